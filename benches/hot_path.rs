@@ -0,0 +1,185 @@
+//! Baselines for the per-event hot path
+//!
+//! Run with `cargo bench`. Everything runs in memory; no database is opened.
+//! `concurrent_accept` is the one to watch when touching shared per-scope
+//! state: its two cases should stay close.
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use geohashed_relay::config::RelayConfig;
+use geohashed_relay::geohash_utils::{extract_geohash_tags, is_geohash_subdomain};
+use geohashed_relay::log_summary::LogSummary;
+use geohashed_relay::pages::render_info_page;
+use geohashed_relay::pow::PowController;
+use geohashed_relay::processor::{ConnectionState, GeohashedEventProcessor};
+use geohashed_relay::scope_counters::ScopeCounterMap;
+use geohashed_relay::test_support::{connection_state, event_context, geohash_tag, raw_geohash_tags, signed_note};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::RwLock;
+use relay_builder::{EventContext, EventProcessor};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn bench_handle_event(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let processor = GeohashedEventProcessor::new();
+    let keys = Keys::generate();
+    let context = event_context(Scope::named("drt2z").unwrap());
+
+    let cases = [
+        ("no_g_tag", signed_note(&keys, vec![])),
+        ("matching_g_tag", signed_note(&keys, vec![geohash_tag("drt2z")])),
+        ("rejecting_g_tag", signed_note(&keys, vec![geohash_tag("9q8yy")])),
+    ];
+
+    let mut group = c.benchmark_group("handle_event");
+    for (name, event) in cases {
+        group.bench_function(name, |b| {
+            b.to_async(&runtime).iter_batched(
+                || (event.clone(), connection_state()),
+                |(event, state)| {
+                    let processor = &processor;
+                    let context = &context;
+                    async move { black_box(processor.handle_event(event, state, context).await) }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_extract_geohash_tags(c: &mut Criterion) {
+    let mut group = c.benchmark_group("extract_geohash_tags");
+    for count in [0, 3, 100] {
+        let tags = raw_geohash_tags(count);
+        group.bench_function(format!("{}_tags", count), |b| {
+            b.iter(|| extract_geohash_tags(black_box(&tags)))
+        });
+    }
+    group.finish();
+}
+
+fn bench_is_geohash_subdomain(c: &mut Criterion) {
+    // "cold" is the uncached path; a cached variant belongs next to it
+    // once lookups are memoized
+    let mut group = c.benchmark_group("is_geohash_subdomain/cold");
+    for subdomain in ["drt2z", "DRT2Z", "team1", "www"] {
+        group.bench_function(subdomain, |b| b.iter(|| is_geohash_subdomain(black_box(subdomain))));
+    }
+    group.finish();
+}
+
+fn bench_info_page(c: &mut Criterion) {
+    let config = RelayConfig::default();
+    c.bench_function("render_info_page/geohash", |b| {
+        b.iter(|| render_info_page(black_box(Some("drt2z")), "example.com", &config, None))
+    });
+}
+
+/// Connections publishing at once in `bench_concurrent_accept`
+const CONNECTIONS: usize = 16;
+const EVENTS_PER_CONNECTION: usize = 64;
+
+/// A connection as it is after opening: scope counters already resolved
+struct Connection {
+    scope: Scope,
+    context: EventContext,
+    state: Arc<RwLock<ConnectionState>>,
+    events: Vec<Event>,
+}
+
+impl Connection {
+    fn open(cell: &str, counters: &ScopeCounterMap) -> Self {
+        let scope = Scope::named(cell).unwrap();
+        let state = connection_state();
+        state.write().scope_counters = Some(counters.resolve(&scope));
+        let keys = Keys::generate();
+        Self {
+            context: event_context(scope.clone()),
+            events: (0..EVENTS_PER_CONNECTION).map(|_| signed_note(&keys, vec![geohash_tag(cell)])).collect(),
+            scope,
+            state,
+        }
+    }
+
+    /// What the rate limiter and processor do for each of its EVENTs
+    async fn publish_all(&self, processor: &GeohashedEventProcessor, counters: &ScopeCounterMap) {
+        for event in &self.events {
+            let admitted = {
+                let state = self.state.read();
+                counters.with_counters(state.scope_counters.as_deref(), &self.scope, |counters| counters.check())
+            };
+            if admitted {
+                let _ = black_box(processor.handle_event(event.clone(), self.state.clone(), &self.context).await);
+            }
+        }
+    }
+}
+
+/// Accept throughput with every connection on one cell versus one cell each
+///
+/// Per-scope state is shared, so one cell should cost little more than
+/// sixteen; a single lock on the way would serialize both.
+fn bench_concurrent_accept(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(CONNECTIONS)
+        .build()
+        .unwrap();
+    // Every event takes from its bucket and counts towards adaptive PoW,
+    // without either ever refusing one
+    let config = Arc::new(RelayConfig {
+        events_per_minute: u32::MAX,
+        pow_max_difficulty: 1,
+        pow_threshold_per_minute: u32::MAX,
+        ..Default::default()
+    });
+    let counters = Arc::new(ScopeCounterMap::new(config.clone()));
+    let processor = Arc::new(
+        GeohashedEventProcessor::with_config(config.clone())
+            .with_pow(Arc::new(PowController::for_config(&config).with_counters(counters.clone())))
+            .with_log_summary(Arc::new(LogSummary::new(Duration::from_secs(3600)))),
+    );
+
+    const CELL_SUFFIXES: &[u8] = b"0123456789bcdefg";
+    let layouts: [(&str, Vec<String>); 2] = [
+        ("one_scope", vec!["drt2z".to_string(); CONNECTIONS]),
+        (
+            "16_scopes",
+            CELL_SUFFIXES.iter().take(CONNECTIONS).map(|suffix| format!("drt2{}", *suffix as char)).collect(),
+        ),
+    ];
+
+    let mut group = c.benchmark_group("concurrent_accept/16_connections");
+    group.throughput(Throughput::Elements((CONNECTIONS * EVENTS_PER_CONNECTION) as u64));
+    for (name, cells) in layouts {
+        let connections: Vec<Arc<Connection>> =
+            cells.iter().map(|cell| Arc::new(Connection::open(cell, &counters))).collect();
+        let (connections, processor, counters) = (&connections, &processor, &counters);
+        group.bench_function(name, |b| {
+            b.to_async(&runtime).iter(|| async move {
+                let tasks: Vec<_> = connections
+                    .iter()
+                    .map(|connection| {
+                        let (connection, processor, counters) = (connection.clone(), processor.clone(), counters.clone());
+                        tokio::spawn(async move { connection.publish_all(&processor, &counters).await })
+                    })
+                    .collect();
+                for task in tasks {
+                    task.await.unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_handle_event,
+    bench_extract_geohash_tags,
+    bench_is_geohash_subdomain,
+    bench_info_page,
+    bench_concurrent_accept
+);
+criterion_main!(benches);
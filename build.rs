@@ -0,0 +1,20 @@
+//! Embeds the git commit the binary was built from
+
+use std::process::Command;
+
+fn main() {
+    // Docker builds have no .git; they can pass the commit in explicitly
+    let commit = std::env::var("GIT_COMMIT").ok().filter(|c| !c.is_empty()).or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|commit| commit.trim().to_string())
+    });
+    println!("cargo:rustc-env=GEOHASHED_RELAY_GIT_COMMIT={}", commit.as_deref().unwrap_or("unknown"));
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
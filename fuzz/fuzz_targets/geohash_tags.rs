@@ -0,0 +1,22 @@
+#![no_main]
+
+use geohashed_relay::geohash_utils::{extract_geohash_tags, is_valid_geohash, normalize_geohash};
+use libfuzzer_sys::fuzz_target;
+
+// Input is a list of tags: tags separated by '\n', values by '\t'
+fuzz_target!(|data: &str| {
+    let tags: Vec<Vec<String>> = data
+        .split('\n')
+        .map(|tag| tag.split('\t').map(str::to_string).collect())
+        .collect();
+
+    for geohash in extract_geohash_tags(&tags) {
+        assert!(is_valid_geohash(&geohash));
+        assert_eq!(normalize_geohash(&geohash).as_deref(), Some(geohash.as_str()));
+    }
+
+    if let Some(normalized) = normalize_geohash(data) {
+        assert!(is_valid_geohash(&normalized));
+        assert_eq!(normalize_geohash(&normalized), Some(normalized.clone()));
+    }
+});
@@ -0,0 +1,20 @@
+#![no_main]
+
+use geohashed_relay::host_parsing::parse_host;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (&str, u8)| {
+    let (host, base_domain_parts) = (input.0, input.1 as usize % 5);
+    let info = parse_host(host, base_domain_parts);
+    if let Some(subdomain) = &info.subdomain {
+        assert!(!subdomain.is_empty());
+        assert!(!subdomain.starts_with('.'));
+        assert!(!subdomain.contains(':'));
+    }
+    // Parsing what we produced gives the same answer
+    let rebuilt = match &info.subdomain {
+        Some(subdomain) => format!("{}.{}", subdomain, info.domain),
+        None => info.domain.clone(),
+    };
+    assert_eq!(parse_host(&rebuilt, base_domain_parts), info);
+});
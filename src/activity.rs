@@ -0,0 +1,221 @@
+//! Recently active geohash cells
+//!
+//! `ScopeActivity` remembers when each cell last accepted an event. A
+//! periodic task forgets cells idle for longer than `ACTIVE_WINDOW_SECS` and
+//! publishes `relay_scope_active{precision}`, the number of cells active in
+//! the window per geohash length, so Prometheus never sees cell names; those
+//! are listed by `/api/scopes` instead.
+//!
+//! At most `MAX_TRACKED_SCOPES` cells are remembered (give or take cells
+//! first seen at the same moment). Past that, new cells are counted in
+//! `relay_scope_activity_overflow_total` and not tracked until older ones
+//! expire.
+
+use dashmap::DashMap;
+use nostr_lmdb::Scope;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::geohash_utils::MAX_GEOHASH_LENGTH;
+
+/// How long a cell counts as active after its last accepted event
+pub const ACTIVE_WINDOW_SECS: u64 = 60 * 60;
+/// Cells remembered at once
+pub const MAX_TRACKED_SCOPES: usize = 100_000;
+/// How often expired cells are dropped and the gauges refreshed
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(60);
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// A cell and when it last accepted an event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ActiveScope {
+    pub scope: String,
+    pub precision: usize,
+    pub last_active: u64,
+}
+
+/// Body of `GET /api/scopes`
+#[derive(Debug, Serialize)]
+pub struct ActivityReport {
+    pub window_secs: u64,
+    pub active_by_precision: BTreeMap<usize, u64>,
+    pub scopes: Vec<ActiveScope>,
+}
+
+/// Last accepted event time per geohash cell
+#[derive(Debug)]
+pub struct ScopeActivity {
+    /// Updated by every accepted event, so sharded rather than behind one lock
+    last_active: DashMap<String, u64>,
+    capacity: usize,
+}
+
+impl Default for ScopeActivity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScopeActivity {
+    pub fn new() -> Self {
+        Self::with_capacity(MAX_TRACKED_SCOPES)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            last_active: DashMap::new(),
+            capacity,
+        }
+    }
+
+    /// Notes an accepted event in `scope`; root isn't tracked
+    pub fn record(&self, scope: &Scope, now: u64) {
+        let Scope::Named { name, .. } = scope else {
+            return;
+        };
+        if let Some(mut at) = self.last_active.get_mut(name) {
+            *at = (*at).max(now);
+        } else if self.last_active.len() < self.capacity {
+            self.last_active.entry(name.clone()).and_modify(|at| *at = (*at).max(now)).or_insert(now);
+        } else {
+            metrics::counter!("relay_scope_activity_overflow_total").increment(1);
+        }
+    }
+
+    /// `record` at the current time
+    pub fn record_now(&self, scope: &Scope) {
+        self.record(scope, now_secs());
+    }
+
+    /// Forgets cells idle for longer than the window
+    pub fn prune(&self, now: u64) {
+        let cutoff = now.saturating_sub(ACTIVE_WINDOW_SECS);
+        self.last_active.retain(|_, at| *at >= cutoff);
+    }
+
+    /// Active cells per precision, with every precision present
+    pub fn active_by_precision(&self, now: u64) -> BTreeMap<usize, u64> {
+        let cutoff = now.saturating_sub(ACTIVE_WINDOW_SECS);
+        let mut counts: BTreeMap<usize, u64> = (1..=MAX_GEOHASH_LENGTH).map(|precision| (precision, 0)).collect();
+        for entry in self.last_active.iter() {
+            if *entry.value() >= cutoff {
+                *counts.entry(entry.key().len().min(MAX_GEOHASH_LENGTH)).or_default() += 1;
+            }
+        }
+        counts
+    }
+
+    /// Up to `limit` active cells, most recently active first
+    pub fn recent(&self, now: u64, limit: usize) -> Vec<ActiveScope> {
+        let cutoff = now.saturating_sub(ACTIVE_WINDOW_SECS);
+        let mut scopes: Vec<ActiveScope> = self
+            .last_active
+            .iter()
+            .filter(|entry| *entry.value() >= cutoff)
+            .map(|entry| ActiveScope {
+                scope: entry.key().clone(),
+                precision: entry.key().len(),
+                last_active: *entry.value(),
+            })
+            .collect();
+        scopes.sort_by(|a, b| b.last_active.cmp(&a.last_active).then_with(|| a.scope.cmp(&b.scope)));
+        scopes.truncate(limit);
+        scopes
+    }
+
+    /// Counts per precision plus the `limit` most recently active cells
+    pub fn report(&self, limit: usize) -> ActivityReport {
+        let now = now_secs();
+        ActivityReport {
+            window_secs: ACTIVE_WINDOW_SECS,
+            active_by_precision: self.active_by_precision(now),
+            scopes: self.recent(now, limit),
+        }
+    }
+
+    /// Drops expired cells and refreshes the gauges
+    pub fn publish(&self, now: u64) {
+        self.prune(now);
+        for (precision, count) in self.active_by_precision(now) {
+            metrics::gauge!("relay_scope_active", "precision" => precision.to_string()).set(count as f64);
+        }
+    }
+}
+
+/// Spawns the periodic prune and gauge refresh
+pub fn spawn_activity_task(activity: Arc<ScopeActivity>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(PUBLISH_INTERVAL);
+        loop {
+            ticker.tick().await;
+            activity.publish(now_secs());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cell(name: &str) -> Scope {
+        Scope::named(name).unwrap()
+    }
+
+    #[test]
+    fn test_active_cells_are_bucketed_by_precision() {
+        let activity = ScopeActivity::new();
+        let now = 10_000;
+        activity.record(&cell("drt2z"), now);
+        activity.record(&cell("9q8yy"), now - 60);
+        activity.record(&cell("drt"), now - ACTIVE_WINDOW_SECS);
+        activity.record(&cell("dr"), now - ACTIVE_WINDOW_SECS - 1);
+        activity.record(&Scope::Default, now);
+
+        let counts = activity.active_by_precision(now);
+        assert_eq!(counts.len(), MAX_GEOHASH_LENGTH);
+        assert_eq!(counts[&5], 2);
+        assert_eq!(counts[&3], 1);
+        assert_eq!(counts[&2], 0);
+        assert_eq!(counts.values().sum::<u64>(), 3);
+    }
+
+    #[test]
+    fn test_prune_and_recent() {
+        let activity = ScopeActivity::new();
+        activity.record(&cell("drt2z"), 100);
+        activity.record(&cell("9q8yy"), 200);
+        activity.record(&cell("drt2z"), 300);
+        activity.record(&cell("drt2z"), 250);
+
+        let recent = activity.recent(300, 10);
+        assert_eq!(
+            recent.iter().map(|s| (s.scope.as_str(), s.last_active)).collect::<Vec<_>>(),
+            vec![("drt2z", 300), ("9q8yy", 200)]
+        );
+        assert_eq!(activity.recent(300, 1).len(), 1);
+
+        activity.prune(250 + ACTIVE_WINDOW_SECS);
+        assert_eq!(activity.recent(250 + ACTIVE_WINDOW_SECS, 10)[0].scope, "drt2z");
+        assert_eq!(activity.last_active.len(), 1);
+    }
+
+    #[test]
+    fn test_capacity_is_bounded() {
+        let activity = ScopeActivity::with_capacity(2);
+        activity.record(&cell("drt2z"), 100);
+        activity.record(&cell("9q8yy"), 100);
+        activity.record(&cell("u4pru"), 100);
+        assert_eq!(activity.last_active.len(), 2);
+
+        // Known cells still update, and expiry makes room again
+        activity.record(&cell("drt2z"), 100 + ACTIVE_WINDOW_SECS + 1);
+        activity.prune(100 + ACTIVE_WINDOW_SECS + 1);
+        activity.record(&cell("u4pru"), 100 + ACTIVE_WINDOW_SECS + 1);
+        let names: Vec<String> = activity.recent(100 + ACTIVE_WINDOW_SECS + 1, 10).into_iter().map(|s| s.scope).collect();
+        assert_eq!(names, vec!["drt2z", "u4pru"]);
+    }
+}
@@ -0,0 +1,139 @@
+//! Paid-write admission list
+//!
+//! Pubkeys that have paid to write to scopes whose write policy is `paid`.
+//! The list lives in memory and is persisted as a JSON array of hex pubkeys
+//! under `database_path`, so an external payment processor can update it
+//! through `POST /api/admissions` and the changes survive restarts.
+
+use anyhow::{Context, Result};
+use nostr_sdk::prelude::*;
+use parking_lot::RwLock;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use crate::config::RelayConfig;
+use crate::keys::parse_pubkey_flexible;
+
+/// File name of the persisted list inside `database_path`
+pub const ADMISSIONS_FILE: &str = "admissions.json";
+
+/// Set of admitted pubkeys, optionally backed by a file
+#[derive(Debug, Default)]
+pub struct AdmissionList {
+    /// `None` keeps the list in memory only
+    path: Option<PathBuf>,
+    pubkeys: RwLock<HashSet<PublicKey>>,
+}
+
+impl AdmissionList {
+    /// Loads the list from `path`, starting empty if the file doesn't exist
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let pubkeys = match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                let hex: Vec<String> = serde_json::from_str(&contents)
+                    .with_context(|| format!("invalid admission list {}", path.display()))?;
+                hex.iter()
+                    .map(|pk| parse_pubkey_flexible(pk).with_context(|| format!("invalid admitted pubkey '{}'", pk)))
+                    .collect::<Result<_>>()?
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        Ok(Self {
+            path: Some(path),
+            pubkeys: RwLock::new(pubkeys),
+        })
+    }
+
+    /// Opens the list kept in the configured database directory
+    pub fn for_config(config: &RelayConfig) -> Result<Self> {
+        Self::open(Path::new(&config.database_path).join(ADMISSIONS_FILE))
+    }
+
+    /// List that is never persisted, for tests and tooling
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    pub fn is_admitted(&self, pubkey: &PublicKey) -> bool {
+        self.pubkeys.read().contains(pubkey)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pubkeys.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds and removes pubkeys, persisting the result; returns the new size
+    ///
+    /// A pubkey in both lists ends up removed.
+    pub fn update(&self, add: &[PublicKey], remove: &[PublicKey]) -> Result<usize> {
+        let mut pubkeys = self.pubkeys.write();
+        let mut updated = pubkeys.clone();
+        updated.extend(add.iter().copied());
+        for pubkey in remove {
+            updated.remove(pubkey);
+        }
+        // Persist before swapping so a failed write leaves memory and disk in step
+        if let Some(path) = &self.path {
+            save(path, &updated)?;
+        }
+        *pubkeys = updated;
+        Ok(pubkeys.len())
+    }
+}
+
+/// Writes the list atomically (temp file, then rename)
+fn save(path: &Path, pubkeys: &HashSet<PublicKey>) -> Result<()> {
+    let mut hex: Vec<String> = pubkeys.iter().map(|pk| pk.to_hex()).collect();
+    hex.sort();
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(&hex)?)
+        .with_context(|| format!("failed to write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_persists_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(ADMISSIONS_FILE);
+        let alice = Keys::generate().public_key();
+        let bob = Keys::generate().public_key();
+
+        let list = AdmissionList::open(&path).unwrap();
+        assert!(list.is_empty());
+        assert_eq!(list.update(&[alice, bob], &[]).unwrap(), 2);
+        assert_eq!(list.update(&[], &[bob]).unwrap(), 1);
+
+        let reopened = AdmissionList::open(&path).unwrap();
+        assert!(reopened.is_admitted(&alice));
+        assert!(!reopened.is_admitted(&bob));
+    }
+
+    #[test]
+    fn test_remove_wins_over_add() {
+        let list = AdmissionList::in_memory();
+        let alice = Keys::generate().public_key();
+        assert_eq!(list.update(&[alice], &[alice]).unwrap(), 0);
+        assert!(!list.is_admitted(&alice));
+    }
+
+    #[test]
+    fn test_corrupt_file_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(ADMISSIONS_FILE);
+        std::fs::write(&path, "not json").unwrap();
+        assert!(AdmissionList::open(&path).is_err());
+
+        std::fs::write(&path, r#"["not-a-pubkey"]"#).unwrap();
+        assert!(AdmissionList::open(&path).is_err());
+    }
+}
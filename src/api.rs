@@ -0,0 +1,1276 @@
+//! JSON API routes
+//!
+//! Everything under `/api` is served here. Handlers resolve the scope from
+//! the Host header the same way the info page does.
+
+use axum::{
+    extract::{ConnectInfo, Path, Query, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::{EventId, Filter};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use crate::activity::ScopeActivity;
+use crate::admissions::AdmissionList;
+use crate::blocklist::Blocklist;
+use crate::cells::{self, DEFAULT_CELLS_LIMIT};
+use crate::config::{BlocklistConfig, RelayConfig};
+use crate::connections::ConnectionRegistry;
+use crate::first_seen::FirstSeen;
+use crate::geoip::GeoIp;
+use crate::geohash_utils::{
+    cell_bbox, encode_latlon, neighborhood_bbox, neighbors, normalize_geohash, GeoBbox, DEFAULT_RESOLVE_PRECISION,
+};
+use crate::host_parsing::{client_ip, host_info};
+use crate::http_cache::if_none_match;
+use crate::ip_filter::IpFilter;
+use crate::keys::{parse_event_id_flexible, parse_pubkey_flexible};
+use crate::maintenance::Maintenance;
+use crate::nip05::Nip05Directory;
+use crate::policy::EffectiveScopePolicy;
+use crate::pow::PowController;
+use crate::replication::{ReplicationFollower, ReplicationLeader};
+use crate::sse::SseFeed;
+use crate::stats::StatsCache;
+use crate::storage::{DiskWatermark, WriteFailures};
+use crate::store::{ScopeStore, ROOT_SCOPE_LABEL};
+use crate::store_admin;
+use crate::tombstones::{Tombstone, Tombstones};
+use crate::trending::{self, TrendingWindow, DEFAULT_TRENDING_LIMIT};
+use crate::wot::WebOfTrust;
+
+/// Shared state for the API routes
+#[derive(Clone)]
+pub struct ApiState {
+    pub config: Arc<RelayConfig>,
+    pub stats: Arc<StatsCache>,
+    pub connections: Arc<ConnectionRegistry>,
+    pub store: Arc<dyn ScopeStore>,
+    pub disk: Arc<DiskWatermark>,
+    /// Recent failed writes, for `/health`
+    pub write_failures: Arc<WriteFailures>,
+    pub admissions: Arc<AdmissionList>,
+    pub nip05: Arc<Nip05Directory>,
+    pub sse: Arc<SseFeed>,
+    /// Serves `/replication/stream` when this relay is a leader
+    pub replication_leader: Option<Arc<ReplicationLeader>>,
+    /// Set when this relay is a follower, for `/health`
+    pub replica: Option<Arc<ReplicationFollower>>,
+    pub maintenance: Arc<Maintenance>,
+    pub activity: Arc<ScopeActivity>,
+    pub pow: Arc<PowController>,
+    pub blocklist: Arc<Blocklist>,
+    pub first_seen: Arc<FirstSeen>,
+    /// Events hidden by kind 5 deletions, in tombstone deletion mode
+    pub tombstones: Arc<Tombstones>,
+    pub wot: Arc<WebOfTrust>,
+    pub ip_filter: Arc<IpFilter>,
+    /// Root page cell suggestions, passed on to the info pages
+    pub geoip: Arc<GeoIp>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScopeQuery {
+    scope: Option<String>,
+}
+
+/// Resolves the scope a request is about.
+///
+/// Geohash hosts always refer to their own cell. On the root domain a
+/// `?scope=` parameter may name another scope ("root" or a geohash).
+fn resolve_scope(headers: &HeaderMap, config: &RelayConfig, requested: Option<&str>) -> Result<Scope, StatusCode> {
+    match host_info(headers, config.base_domain_parts()).subdomain {
+        Some(sub) => {
+            let geohash = normalize_geohash(&sub).ok_or(StatusCode::NOT_FOUND)?;
+            Scope::named(&geohash).map_err(|_| StatusCode::NOT_FOUND)
+        }
+        None => match requested {
+            None => Ok(Scope::Default),
+            Some(label) if label == ROOT_SCOPE_LABEL => Ok(Scope::Default),
+            Some(label) => {
+                let geohash = normalize_geohash(label).ok_or(StatusCode::BAD_REQUEST)?;
+                Scope::named(&geohash).map_err(|_| StatusCode::BAD_REQUEST)
+            }
+        },
+    }
+}
+
+async fn stats_handler(
+    State(state): State<ApiState>,
+    Query(query): Query<ScopeQuery>,
+    headers: HeaderMap,
+) -> Response {
+    match resolve_scope(&headers, &state.config, query.scope.as_deref()) {
+        Ok(scope) => {
+            let mut stats = state.stats.stats_for(&scope, &state.connections);
+            stats.pow_difficulty = state.pow.required(&scope);
+            stats.wot = state.wot.report();
+            Json(stats).into_response()
+        }
+        Err(status) => status.into_response(),
+    }
+}
+
+/// The scope's resolved policy, with its version as the ETag
+async fn policy_handler(
+    State(state): State<ApiState>,
+    Query(query): Query<ScopeQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let scope = match resolve_scope(&headers, &state.config, query.scope.as_deref()) {
+        Ok(scope) => scope,
+        Err(status) => return status.into_response(),
+    };
+    let subdomain = match &scope {
+        Scope::Named { name, .. } => Some(name.as_str()),
+        Scope::Default => None,
+    };
+    let policy = EffectiveScopePolicy::resolve(subdomain, &state.config, state.pow.required(&scope));
+    let etag = format!("\"{}\"", policy.policy_version);
+    if if_none_match(&headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+    ([(header::ETAG, etag)], Json(policy)).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CellsQuery {
+    prefix: Option<String>,
+    min_events: Option<String>,
+    limit: Option<String>,
+}
+
+/// Cells one level below `prefix` that hold events, from any host
+async fn cells_handler(State(state): State<ApiState>, Query(query): Query<CellsQuery>) -> Response {
+    let Some(prefix) = cells::parse_prefix(query.prefix.as_deref().unwrap_or_default()) else {
+        return bad_request("prefix must be a geohash shorter than the longest cell");
+    };
+    let parse = |value: Option<&str>, default: usize, name: &str| match value {
+        None => Ok(default),
+        Some(value) => value
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| bad_request(format!("{} must be a positive integer", name))),
+    };
+    let min_events = match parse(query.min_events.as_deref(), 1, "min_events") {
+        Ok(min_events) => min_events,
+        Err(response) => return response,
+    };
+    let limit = match parse(query.limit.as_deref(), DEFAULT_CELLS_LIMIT, "limit") {
+        Ok(limit) => limit,
+        Err(response) => return response,
+    };
+    Json(cells::child_cells(&state.stats, &state.config, &prefix, min_events, limit)).into_response()
+}
+
+/// Active direct children of a cell, from any host
+async fn children_activity_handler(State(state): State<ApiState>, Path(geohash): Path<String>) -> Response {
+    let Some(geohash) = cells::parse_prefix(&geohash).filter(|geohash| !geohash.is_empty()) else {
+        return bad_request("not a geohash with child cells");
+    };
+    Json(cells::children_activity(&state.stats, &state.config, &geohash)).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TrendingQuery {
+    pub window: Option<String>,
+    pub limit: Option<String>,
+}
+
+/// Busiest cells in the window, from any host
+async fn trending_handler(State(state): State<ApiState>, Query(query): Query<TrendingQuery>) -> Response {
+    let window = match query.window.as_deref().map(str::parse::<TrendingWindow>) {
+        None => TrendingWindow::default(),
+        Some(Ok(window)) => window,
+        Some(Err(e)) => return bad_request(e.to_string()),
+    };
+    let limit = match query.limit.as_deref().map(|limit| limit.trim().parse::<usize>()) {
+        None => DEFAULT_TRENDING_LIMIT,
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => return bad_request("limit must be a positive integer"),
+    };
+    Json(trending::trending(&state.stats, &state.config, window, limit)).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResolveQuery {
+    lat: Option<String>,
+    lon: Option<String>,
+    precision: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CellRelay {
+    geohash: String,
+    relay_url: String,
+}
+
+
+#[derive(Debug, Serialize)]
+struct Resolution {
+    geohash: String,
+    relay_url: String,
+    bbox: GeoBbox,
+    neighbors: Vec<CellRelay>,
+    /// The cell and its neighbors, split at the antimeridian when the
+    /// area crosses it
+    neighborhood: Vec<GeoBbox>,
+}
+
+/// 400 response with a JSON reason
+fn bad_request(reason: impl Into<String>) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(serde_json::json!({ "error": reason.into() })),
+    )
+        .into_response()
+}
+
+fn parse_coordinate(value: Option<&str>, name: &str, limit: f64) -> Result<f64, Response> {
+    let value = value.ok_or_else(|| bad_request(format!("missing {}", name)))?;
+    let parsed: f64 = value
+        .trim()
+        .parse()
+        .map_err(|_| bad_request(format!("{} must be a number", name)))?;
+    if !parsed.is_finite() || parsed < -limit || parsed > limit {
+        return Err(bad_request(format!("{} must be between -{} and {}", name, limit, limit)));
+    }
+    Ok(parsed)
+}
+
+fn resolve(config: &RelayConfig, query: &ResolveQuery) -> Result<Resolution, Response> {
+    let lat = parse_coordinate(query.lat.as_deref(), "lat", 90.0)?;
+    let lon = parse_coordinate(query.lon.as_deref(), "lon", 180.0)?;
+    let precision = match query.precision.as_deref() {
+        Some(p) => p
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| bad_request("precision must be a positive integer"))?,
+        None => DEFAULT_RESOLVE_PRECISION,
+    };
+    let precision = precision.clamp(config.min_geohash_precision, config.max_geohash_precision);
+
+    let geohash = encode_latlon(lat, lon, precision)
+        .ok_or_else(|| bad_request("coordinate could not be encoded"))?;
+    let bbox = cell_bbox(&geohash).ok_or_else(|| bad_request("coordinate could not be encoded"))?;
+    let neighborhood = neighborhood_bbox(&geohash).map(|area| area.split_at_antimeridian()).unwrap_or_default();
+
+    let neighbors = neighbors(&geohash)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|gh| CellRelay {
+            relay_url: config.relay_url_for(Some(&gh)),
+            geohash: gh,
+        })
+        .collect();
+
+    Ok(Resolution {
+        relay_url: config.relay_url_for(Some(&geohash)),
+        bbox,
+        geohash,
+        neighbors,
+        neighborhood,
+    })
+}
+
+/// Maps a coordinate to its cell relay. Works from any host.
+async fn resolve_handler(
+    State(state): State<ApiState>,
+    Query(query): Query<ResolveQuery>,
+) -> Response {
+    match resolve(&state.config, &query) {
+        Ok(resolution) => Json(resolution).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// Number of scopes listed in `/api/db`
+const DB_TOP_SCOPES: usize = 20;
+
+/// Number of cells listed in `/api/scopes`
+const ACTIVE_SCOPES_LISTED: usize = 100;
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Checks the `Authorization: Bearer <ADMIN_TOKEN>` header
+///
+/// Admin routes don't exist (404) unless a token is configured.
+fn require_admin(headers: &HeaderMap, config: &RelayConfig) -> Result<(), StatusCode> {
+    let Some(token) = config.admin_token.as_deref() else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let presented = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if constant_time_eq(presented.trim().as_bytes(), token.as_bytes()) {
+        Ok(())
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+/// LMDB statistics for operators
+async fn db_handler(State(state): State<ApiState>, headers: HeaderMap) -> Response {
+    if let Err(status) = require_admin(&headers, &state.config) {
+        return status.into_response();
+    }
+    match store_admin::db_stats(
+        state.store.as_ref(),
+        std::path::Path::new(&state.config.database_path),
+        state.config.lmdb_map_size as u64,
+        DB_TOP_SCOPES,
+    )
+    .await
+    {
+        Ok(stats) => Json(stats).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+/// Cells active in the last hour, by precision and by name
+///
+/// Admin-only: the names say where people are posting from.
+async fn scopes_handler(State(state): State<ApiState>, headers: HeaderMap) -> Response {
+    if let Err(status) = require_admin(&headers, &state.config) {
+        return status.into_response();
+    }
+    Json(state.activity.report(ACTIVE_SCOPES_LISTED)).into_response()
+}
+
+/// Open websocket connections with their counters
+///
+/// Admin-only: lists client addresses.
+async fn connections_handler(State(state): State<ApiState>, headers: HeaderMap) -> Response {
+    if let Err(status) = require_admin(&headers, &state.config) {
+        return status.into_response();
+    }
+    Json(state.connections.live()).into_response()
+}
+
+/// Body of `POST /api/admissions`; pubkeys as hex or npub
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AdmissionUpdate {
+    add: Vec<String>,
+    remove: Vec<String>,
+}
+
+/// Adds or removes paid-write admissions, e.g. from a payment processor
+async fn admissions_handler(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(update): Json<AdmissionUpdate>,
+) -> Response {
+    if let Err(status) = require_admin(&headers, &state.config) {
+        return status.into_response();
+    }
+    let parse = |pubkeys: &[String]| -> Result<Vec<_>, Response> {
+        pubkeys
+            .iter()
+            .map(|pk| parse_pubkey_flexible(pk).map_err(|e| bad_request(e.to_string())))
+            .collect()
+    };
+    let (add, remove) = match (parse(&update.add), parse(&update.remove)) {
+        (Ok(add), Ok(remove)) => (add, remove),
+        (Err(response), _) | (_, Err(response)) => return response,
+    };
+    match state.admissions.update(&add, &remove) {
+        Ok(admitted) => Json(serde_json::json!({ "admitted": admitted })).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+/// Body of `POST /api/maintenance`
+#[derive(Debug, Deserialize)]
+pub struct MaintenanceUpdate {
+    enabled: bool,
+    message: Option<String>,
+}
+
+/// Ends read-only mode once the operator has checked the data
+async fn clear_read_only_handler(State(state): State<ApiState>, headers: HeaderMap) -> Response {
+    if let Err(status) = require_admin(&headers, &state.config) {
+        return status.into_response();
+    }
+    let was_read_only = state.maintenance.clear_read_only();
+    Json(serde_json::json!({ "read_only": false, "was_read_only": was_read_only })).into_response()
+}
+
+/// Turns maintenance mode on or off without a restart
+async fn maintenance_handler(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(update): Json<MaintenanceUpdate>,
+) -> Response {
+    if let Err(status) = require_admin(&headers, &state.config) {
+        return status.into_response();
+    }
+    match state.maintenance.set(update.enabled, update.message) {
+        Ok(maintenance) => Json(maintenance).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+/// Current content blocklist
+async fn blocklist_handler(State(state): State<ApiState>, headers: HeaderMap) -> Response {
+    if let Err(status) = require_admin(&headers, &state.config) {
+        return status.into_response();
+    }
+    Json(state.blocklist.config()).into_response()
+}
+
+/// Replaces the content blocklist without a restart
+async fn update_blocklist_handler(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(update): Json<BlocklistConfig>,
+) -> Response {
+    if let Err(status) = require_admin(&headers, &state.config) {
+        return status.into_response();
+    }
+    match state.blocklist.set(update) {
+        Ok(blocklist) => Json(blocklist).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": format!("{:#}", e) })),
+        )
+            .into_response(),
+    }
+}
+
+/// Where and when an event first reached the relay
+async fn event_meta_handler(State(state): State<ApiState>, Path(id): Path<String>) -> Response {
+    let id = match parse_event_id_flexible(&id) {
+        Ok(id) => id,
+        Err(e) => return bad_request(e.to_string()),
+    };
+    match state.first_seen.first(&id) {
+        Some(sighting) => Json(serde_json::json!({
+            "scope": sighting.scope,
+            "received_at": sighting.received_at,
+        }))
+        .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Query of `GET /api/scopes/{scope}/export`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ExportQuery {
+    include_deleted: bool,
+}
+
+/// A scope's events as JSONL, each with its first-seen `received_at`
+///
+/// Admin-only, like the other scope listings. `received_at` is null for
+/// events stored before first-seen times were recorded. Events hidden by
+/// tombstones are left out unless `include_deleted=true`, which adds them
+/// with the id of the deletion hiding them as `tombstone`.
+async fn export_handler(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Path(label): Path<String>,
+    Query(query): Query<ExportQuery>,
+) -> Response {
+    if let Err(status) = require_admin(&headers, &state.config) {
+        return status.into_response();
+    }
+    let scope = if label == ROOT_SCOPE_LABEL {
+        Some(Scope::Default)
+    } else {
+        normalize_geohash(&label).and_then(|geohash| Scope::named(&geohash).ok())
+    };
+    let Some(scope) = scope else {
+        return bad_request("scope must be root or a geohash");
+    };
+    let events = match state.store.query(&scope, Filter::new()).await {
+        Ok(events) => events,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({ "error": e.to_string() })),
+            )
+                .into_response();
+        }
+    };
+    let mut body = String::new();
+    for event in events {
+        let tombstone = state.tombstones.hidden_by(&event.id);
+        if tombstone.is_some() && !query.include_deleted {
+            continue;
+        }
+        let received_at = state.first_seen.received_at(&scope, &event.id);
+        let mut line = serde_json::to_value(&event).unwrap_or_default();
+        line["received_at"] = serde_json::json!(received_at);
+        if let Some(tombstone) = tombstone {
+            line["tombstone"] = serde_json::json!(tombstone);
+        }
+        body.push_str(&line.to_string());
+        body.push('\n');
+    }
+    ([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response()
+}
+
+/// Deletion requests recorded in tombstone mode, most recent first
+async fn tombstones_handler(State(state): State<ApiState>, headers: HeaderMap) -> Response {
+    if let Err(status) = require_admin(&headers, &state.config) {
+        return status.into_response();
+    }
+    Json(state.tombstones.list()).into_response()
+}
+
+/// Brings back the events a deletion hid
+async fn restore_tombstone_handler(State(state): State<ApiState>, headers: HeaderMap, Path(id): Path<String>) -> Response {
+    if let Err(status) = require_admin(&headers, &state.config) {
+        return status.into_response();
+    }
+    let id = match parse_event_id_flexible(&id) {
+        Ok(id) => id,
+        Err(e) => return bad_request(e.to_string()),
+    };
+    tombstone_response(Ok(state.tombstones.restore(&id)))
+}
+
+/// Deletes the events a deletion hid for good
+async fn purge_tombstone_handler(State(state): State<ApiState>, headers: HeaderMap, Path(id): Path<String>) -> Response {
+    if let Err(status) = require_admin(&headers, &state.config) {
+        return status.into_response();
+    }
+    let id = match parse_event_id_flexible(&id) {
+        Ok(id) => id,
+        Err(e) => return bad_request(e.to_string()),
+    };
+    tombstone_response(state.tombstones.purge(state.store.as_ref(), &id).await)
+}
+
+/// The updated tombstone, or 404 when there was no hidden one to update
+fn tombstone_response(result: anyhow::Result<Option<Tombstone>>) -> Response {
+    match result {
+        Ok(Some(tombstone)) => Json(tombstone).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": format!("{:#}", e) })),
+        )
+            .into_response(),
+    }
+}
+
+/// Routes for the JSON API
+pub fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/api/stats", get(stats_handler))
+        .route("/api/policy", get(policy_handler))
+        .route("/api/resolve", get(resolve_handler))
+        .route("/api/trending", get(trending_handler))
+        .route("/api/cells", get(cells_handler))
+        .route("/api/geohash/{geohash}/children_activity", get(children_activity_handler))
+        .route("/api/db", get(db_handler))
+        .route("/api/scopes", get(scopes_handler))
+        .route("/api/connections", get(connections_handler))
+        .route("/api/scopes/{scope}/export", get(export_handler))
+        .route("/api/events/{id}/meta", get(event_meta_handler))
+        .route("/api/tombstones", get(tombstones_handler))
+        .route("/api/tombstones/{id}/restore", post(restore_tombstone_handler))
+        .route("/api/tombstones/{id}/purge", post(purge_tombstone_handler))
+        .route("/api/admissions", post(admissions_handler))
+        .route("/api/maintenance", post(maintenance_handler))
+        .route("/api/read-only", delete(clear_read_only_handler))
+        .route("/api/blocklist", get(blocklist_handler).put(update_blocklist_handler))
+        .route_layer(middleware::from_fn_with_state(state.clone(), ip_access))
+        .with_state(state)
+}
+
+/// Applies `ip_allowlist` and `ip_denylist` to the API
+///
+/// Requests without a peer address (only possible in tests) pass.
+async fn ip_access(State(state): State<ApiState>, request: Request, next: Next) -> Response {
+    if let Some(ConnectInfo(peer)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        let ip = client_ip(request.headers(), peer.ip(), &state.config);
+        if !state.ip_filter.admits(ip) {
+            return (StatusCode::FORBIDDEN, "Forbidden").into_response();
+        }
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::live::LiveEvents;
+    use crate::stats;
+    use crate::store::{LmdbStore, MemoryStore, ScopeStore};
+    use axum::{body::{to_bytes, Body}, http::Request};
+    use nostr_sdk::prelude::*;
+    use relay_builder::RelayDatabase;
+    use tower::ServiceExt;
+
+    async fn note(keys: &Keys, kind: u16) -> Event {
+        EventBuilder::new(Kind::from(kind), "api test")
+            .sign(keys)
+            .await
+            .unwrap()
+    }
+
+    async fn get_json(state: ApiState, host: &str, uri: &str) -> (StatusCode, serde_json::Value) {
+        get_json_with(state, Request::builder().uri(uri).header("host", host)).await
+    }
+
+    async fn get_json_with(state: ApiState, request: axum::http::request::Builder) -> (StatusCode, serde_json::Value) {
+        let response = router(state)
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+    }
+
+    fn test_state() -> ApiState {
+        let config = Arc::new(RelayConfig {
+            relay_url: "wss://example.com".to_string(),
+            ..Default::default()
+        });
+        let store: Arc<dyn ScopeStore> = Arc::new(MemoryStore::new());
+        ApiState {
+            nip05: Arc::new(Nip05Directory::new(&config, &Keys::generate().public_key())),
+            sse: Arc::new(SseFeed::new(
+                config.clone(),
+                store.clone(),
+                Arc::new(LiveEvents::new()),
+            )),
+            config,
+            stats: Arc::new(StatsCache::new()),
+            connections: Arc::new(ConnectionRegistry::new()),
+            store,
+            disk: Arc::new(DiskWatermark::disabled()),
+            write_failures: Arc::new(WriteFailures::disabled()),
+            admissions: Arc::new(AdmissionList::in_memory()),
+            replication_leader: None,
+            replica: None,
+            maintenance: Arc::new(Maintenance::disabled()),
+            activity: Arc::new(ScopeActivity::new()),
+            pow: Arc::new(PowController::disabled()),
+            blocklist: Arc::new(Blocklist::disabled()),
+            first_seen: Arc::new(FirstSeen::in_memory()),
+            tombstones: Arc::new(Tombstones::in_memory()),
+            wot: Arc::new(WebOfTrust::disabled()),
+            ip_filter: Arc::new(IpFilter::disabled()),
+            geoip: Arc::new(GeoIp::disabled()),
+        }
+    }
+
+    async fn seeded_state(store: &dyn ScopeStore) -> ApiState {
+        let state = test_state();
+        stats::refresh(store, &state.stats).await.unwrap();
+        state
+    }
+
+    #[tokio::test]
+    async fn test_stats_for_geohash_host() {
+        let store = MemoryStore::new();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let keys = Keys::generate();
+        store.insert(&drt2z, note(&keys, 1).await);
+        store.insert(&drt2z, note(&keys, 1).await);
+        store.insert(&Scope::Default, note(&keys, 0).await);
+
+        let state = seeded_state(&store).await;
+        state.connections.connected(&drt2z);
+
+        let (status, json) = get_json(state, "drt2z.example.com", "/api/stats").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["scope"], "drt2z");
+        assert_eq!(json["stored_events"], 2);
+        assert_eq!(json["events_last_hour"], 2);
+        assert_eq!(json["distinct_pubkeys_24h"], 1);
+        assert_eq!(json["active_connections"], 1);
+        assert_eq!(json["top_kinds"][0]["kind"], 1);
+        assert_eq!(json["top_kinds"][0]["count"], 2);
+        assert_eq!(json["pow_difficulty"], 0);
+        assert!(json["computed_at"].is_u64());
+    }
+
+    #[tokio::test]
+    async fn test_stats_report_raised_pow_difficulty() {
+        let drt2z = Scope::named("drt2z").unwrap();
+        let pow = PowController::new(4, 16, 10);
+        for _ in 0..80 {
+            pow.record(&drt2z);
+        }
+        pow.recompute(std::time::Duration::from_secs(60));
+        let state = ApiState { pow: Arc::new(pow), ..test_state() };
+
+        let (_, json) = get_json(state.clone(), "drt2z.example.com", "/api/stats").await;
+        assert_eq!(json["pow_difficulty"], 7);
+        let (_, json) = get_json(state, "9q8yy.example.com", "/api/stats").await;
+        assert_eq!(json["pow_difficulty"], 4);
+    }
+
+    #[tokio::test]
+    async fn test_trending_lists_busy_cells_only() {
+        let store = MemoryStore::new();
+        let drt2z = Scope::named("drt2z").unwrap();
+        for _ in 0..3 {
+            store.insert(&drt2z, note(&Keys::generate(), 1).await);
+        }
+        // A lone poster's cell stays unlisted however busy it is
+        let alone = Keys::generate();
+        let u33dc = Scope::named("u33dc").unwrap();
+        for _ in 0..5 {
+            store.insert(&u33dc, note(&alone, 1).await);
+        }
+        let state = seeded_state(&store).await;
+
+        let (status, json) = get_json(state.clone(), "drt2z.example.com", "/api/trending?window=1h&limit=5").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["window"], "1h");
+        assert_eq!(json["cells"].as_array().unwrap().len(), 1);
+        assert_eq!(json["cells"][0]["geohash"], "drt2z");
+        assert_eq!(json["cells"][0]["events"], 3);
+        assert_eq!(json["cells"][0]["distinct_pubkeys"], 3);
+
+        let (status, _) = get_json(state.clone(), "example.com", "/api/trending?window=7d").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = get_json(state, "example.com", "/api/trending?limit=many").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_cells_lists_children_with_events() {
+        let store = MemoryStore::new();
+        let keys = Keys::generate();
+        for cell in ["drt2z", "drt2z", "dr5re"] {
+            store.insert(&Scope::named(cell).unwrap(), note(&keys, 1).await);
+        }
+        // A cell whose only event is gone again
+        let gone = note(&keys, 1).await;
+        let drm = Scope::named("drmqq").unwrap();
+        store.insert(&drm, gone.clone());
+        store.delete(&drm, gone.id).await.unwrap();
+        store.insert(&Scope::named("9q8yy").unwrap(), note(&keys, 1).await);
+        let state = seeded_state(&store).await;
+
+        let (status, json) = get_json(state.clone(), "example.com", "/api/cells?prefix=DR&min_events=1&limit=32").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["prefix"], "dr");
+        let cells = json["cells"].as_array().unwrap();
+        assert_eq!(cells.len(), 2);
+        assert_eq!(cells[0]["geohash"], "drt");
+        assert_eq!(cells[0]["events"], 2);
+        assert!(cells[0]["last_event_at"].is_u64());
+        assert_eq!(cells[1]["geohash"], "dr5");
+
+        let (_, json) = get_json(state.clone(), "example.com", "/api/cells?min_events=2").await;
+        assert_eq!(json["cells"].as_array().unwrap().len(), 1);
+        assert_eq!(json["cells"][0]["geohash"], "d");
+
+        let (status, _) = get_json(state, "example.com", "/api/cells?prefix=dra").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_children_activity_skips_quiet_children() {
+        let store = MemoryStore::new();
+        let keys = Keys::generate();
+        for i in 0..5 {
+            let event = EventBuilder::text_note(format!("post {}", i)).sign(&keys).await.unwrap();
+            store.insert(&Scope::named("drt2z").unwrap(), event);
+        }
+        store.insert(&Scope::named("dr5re").unwrap(), note(&keys, 1).await);
+        let state = seeded_state(&store).await;
+
+        let (status, json) = get_json(state.clone(), "example.com", "/api/geohash/DR/children_activity").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["prefix"], "dr");
+        let cells = json["cells"].as_array().unwrap();
+        assert_eq!(cells.len(), 1);
+        assert_eq!(cells[0]["geohash"], "drt");
+        assert_eq!(cells[0]["events"], 5);
+
+        let (status, _) = get_json(state, "example.com", "/api/geohash/dra/children_activity").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_stats_report_web_of_trust_when_enabled() {
+        let (_, json) = get_json(test_state(), "example.com", "/api/stats").await;
+        assert!(json.get("wot").is_none());
+
+        let seed = Keys::generate().public_key();
+        let state = ApiState { wot: Arc::new(WebOfTrust::new(vec![seed], 2)), ..test_state() };
+        let (_, json) = get_json(state, "drt2z.example.com", "/api/stats").await;
+        assert_eq!(json["wot"], serde_json::json!({ "depth": 2, "trusted_pubkeys": 1, "refreshed_at": null }));
+    }
+
+    #[tokio::test]
+    async fn test_root_stats_exclude_named_scopes() {
+        let store = MemoryStore::new();
+        let keys = Keys::generate();
+        store.insert(&Scope::named("drt2z").unwrap(), note(&keys, 1).await);
+        store.insert(&Scope::Default, note(&keys, 0).await);
+
+        let (_, json) = get_json(seeded_state(&store).await, "example.com", "/api/stats").await;
+        assert_eq!(json["scope"], "root");
+        assert_eq!(json["stored_events"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_scope_override_only_on_root_domain() {
+        let store = MemoryStore::new();
+        let keys = Keys::generate();
+        store.insert(&Scope::named("drt2z").unwrap(), note(&keys, 1).await);
+        let state = seeded_state(&store).await;
+
+        let (_, json) = get_json(state.clone(), "example.com", "/api/stats?scope=drt2z").await;
+        assert_eq!(json["scope"], "drt2z");
+        assert_eq!(json["stored_events"], 1);
+
+        // Geohash hosts ignore the override
+        let (_, json) = get_json(state.clone(), "9q8yy.example.com", "/api/stats?scope=drt2z").await;
+        assert_eq!(json["scope"], "9q8yy");
+        assert_eq!(json["stored_events"], 0);
+
+        let (status, _) = get_json(state.clone(), "example.com", "/api/stats?scope=not-a-cell").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = get_json(state, "www.example.com", "/api/stats").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_stats_before_first_computation() {
+        let (status, json) = get_json(test_state(), "drt2z.example.com", "/api/stats").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["stored_events"], 0);
+        assert!(json["computed_at"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_stats_from_lmdb_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = Arc::new(RelayDatabase::new(dir.path()).unwrap());
+        let drt2z = Scope::named("drt2z").unwrap();
+        let keys = Keys::generate();
+        database.save_event(&note(&keys, 1).await, &drt2z).await.unwrap();
+        database.save_event(&note(&keys, 7).await, &drt2z).await.unwrap();
+        database.save_event(&note(&keys, 1).await, &Scope::Default).await.unwrap();
+
+        let store = LmdbStore::new(database);
+        let state = seeded_state(&store).await;
+
+        let (_, json) = get_json(state.clone(), "drt2z.example.com", "/api/stats").await;
+        assert_eq!(json["stored_events"], 2);
+        assert_eq!(json["top_kinds"].as_array().unwrap().len(), 2);
+
+        let (_, json) = get_json(state, "example.com", "/api/stats").await;
+        assert_eq!(json["stored_events"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_coordinate() {
+        let (status, json) = get_json(
+            test_state(),
+            "example.com",
+            "/api/resolve?lat=42.3398&lon=-71.0449&precision=5",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["geohash"], "drt2z");
+        assert_eq!(json["relay_url"], "wss://drt2z.example.com");
+        assert!(json["bbox"]["min_lat"].as_f64().unwrap() <= 42.3398);
+        assert!(json["bbox"]["max_lon"].as_f64().unwrap() >= -71.0449);
+        let neighbors = json["neighbors"].as_array().unwrap();
+        assert_eq!(neighbors.len(), 8);
+        for n in neighbors {
+            let gh = n["geohash"].as_str().unwrap();
+            assert_eq!(n["relay_url"], format!("wss://{}.example.com", gh));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_works_from_any_host() {
+        let (status, json) = get_json(test_state(), "9q8yy.example.com", "/api/resolve?lat=42.3398&lon=-71.0449").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["geohash"], "drt2z");
+    }
+
+    #[tokio::test]
+    async fn test_resolve_boundary_coordinates() {
+        // Poles omit the neighbors beyond them
+        let (status, json) = get_json(test_state(), "example.com", "/api/resolve?lat=90&lon=0").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["neighbors"].as_array().unwrap().len(), 5);
+
+        let (status, json) = get_json(test_state(), "example.com", "/api/resolve?lat=-90&lon=0").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["neighbors"].as_array().unwrap().len(), 5);
+
+        // Both sides of the antimeridian resolve, and neighbors wrap
+        let (status, json) = get_json(test_state(), "example.com", "/api/resolve?lat=0&lon=180").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["neighbors"].as_array().unwrap().len(), 8);
+        assert_eq!(json["bbox"]["crosses_antimeridian"], false);
+        // The area around it is split into boxes either side of the meridian
+        let neighborhood = json["neighborhood"].as_array().unwrap();
+        assert_eq!(neighborhood.len(), 2);
+        assert_eq!(neighborhood[0]["max_lon"], 180.0);
+        assert_eq!(neighborhood[1]["min_lon"], -180.0);
+        let (status, _) = get_json(test_state(), "example.com", "/api/resolve?lat=0&lon=-180").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (_, json) = get_json(test_state(), "example.com", "/api/resolve?lat=42.3398&lon=-71.0449").await;
+        assert_eq!(json["neighborhood"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_rejects_invalid_coordinates() {
+        for uri in [
+            "/api/resolve?lat=90.5&lon=0",
+            "/api/resolve?lat=0&lon=-181",
+            "/api/resolve?lat=abc&lon=0",
+            "/api/resolve?lat=NaN&lon=0",
+            "/api/resolve?lon=0",
+            "/api/resolve?lat=0&lon=0&precision=-1",
+        ] {
+            let (status, json) = get_json(test_state(), "example.com", uri).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", uri);
+            assert!(json["error"].is_string(), "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_clamps_precision() {
+        let mut state = test_state();
+        state.config = Arc::new(RelayConfig {
+            min_geohash_precision: 4,
+            max_geohash_precision: 6,
+            ..(*state.config).clone()
+        });
+
+        let (_, json) = get_json(state.clone(), "example.com", "/api/resolve?lat=42.3398&lon=-71.0449&precision=1").await;
+        assert_eq!(json["geohash"], "drt2");
+
+        let (_, json) = get_json(state.clone(), "example.com", "/api/resolve?lat=42.3398&lon=-71.0449&precision=12").await;
+        assert_eq!(json["geohash"].as_str().unwrap().len(), 6);
+
+        let (_, json) = get_json(state, "example.com", "/api/resolve?lat=42.3398&lon=-71.0449&precision=0").await;
+        assert_eq!(json["geohash"], "drt2");
+    }
+
+    #[tokio::test]
+    async fn test_db_stats_requires_admin_token() {
+        let (status, _) = get_json(test_state(), "example.com", "/api/db").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let mut state = test_state();
+        state.config = Arc::new(RelayConfig {
+            admin_token: Some("s3cret".to_string()),
+            ..(*state.config).clone()
+        });
+        let keys = Keys::generate();
+        let store = MemoryStore::new();
+        store.insert(&Scope::named("drt2z").unwrap(), note(&keys, 1).await);
+        state.store = Arc::new(store);
+
+        let (status, _) = get_json(state.clone(), "example.com", "/api/db").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let request = Request::builder()
+            .uri("/api/db")
+            .header("host", "example.com")
+            .header("authorization", "Bearer wrong");
+        assert_eq!(get_json_with(state.clone(), request).await.0, StatusCode::UNAUTHORIZED);
+
+        let request = Request::builder()
+            .uri("/api/db")
+            .header("host", "example.com")
+            .header("authorization", "Bearer s3cret");
+        let (status, json) = get_json_with(state, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["total_events"], 1);
+        assert_eq!(json["named_scopes"], 1);
+        assert_eq!(json["top_scopes"][0]["scope"], "drt2z");
+    }
+
+    #[tokio::test]
+    async fn test_active_scopes() {
+        let mut state = test_state();
+        state.config = Arc::new(RelayConfig {
+            admin_token: Some("s3cret".to_string()),
+            ..(*state.config).clone()
+        });
+        state.activity.record_now(&Scope::named("drt2z").unwrap());
+        state.activity.record_now(&Scope::named("drt").unwrap());
+        state.activity.record_now(&Scope::Default);
+
+        let (status, _) = get_json(state.clone(), "example.com", "/api/scopes").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let request = Request::builder()
+            .uri("/api/scopes")
+            .header("host", "example.com")
+            .header("authorization", "Bearer s3cret");
+        let (status, json) = get_json_with(state, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["window_secs"], 3600);
+        assert_eq!(json["active_by_precision"]["5"], 1);
+        assert_eq!(json["active_by_precision"]["3"], 1);
+        assert_eq!(json["active_by_precision"]["4"], 0);
+        assert_eq!(json["scopes"].as_array().unwrap().len(), 2);
+    }
+
+    fn post_admissions(token: Option<&str>, body: serde_json::Value) -> Request<Body> {
+        let mut request = Request::builder()
+            .method("POST")
+            .uri("/api/admissions")
+            .header("host", "example.com")
+            .header("content-type", "application/json");
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {}", token));
+        }
+        request.body(Body::from(body.to_string())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_admissions_add_and_remove() {
+        let mut state = test_state();
+        state.config = Arc::new(RelayConfig {
+            admin_token: Some("s3cret".to_string()),
+            ..(*state.config).clone()
+        });
+        let alice = Keys::generate().public_key();
+        let bob = Keys::generate().public_key();
+
+        let body = serde_json::json!({ "add": [alice.to_hex()] });
+        let response = router(state.clone()).oneshot(post_admissions(None, body.clone())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(!state.admissions.is_admitted(&alice));
+
+        let response = router(state.clone()).oneshot(post_admissions(Some("s3cret"), body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(state.admissions.is_admitted(&alice));
+
+        let body = serde_json::json!({ "add": [bob.to_bech32().unwrap()], "remove": [alice.to_hex()] });
+        let response = router(state.clone()).oneshot(post_admissions(Some("s3cret"), body)).await.unwrap();
+        let json: serde_json::Value =
+            serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(json["admitted"], 1);
+        assert!(state.admissions.is_admitted(&bob));
+        assert!(!state.admissions.is_admitted(&alice));
+
+        // One bad pubkey rejects the whole update
+        let body = serde_json::json!({ "add": [alice.to_hex(), "nope"] });
+        let response = router(state.clone()).oneshot(post_admissions(Some("s3cret"), body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(!state.admissions.is_admitted(&alice));
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_toggle() {
+        let mut state = test_state();
+        state.config = Arc::new(RelayConfig {
+            admin_token: Some("s3cret".to_string()),
+            ..(*state.config).clone()
+        });
+        let post = |token: Option<&str>, body: serde_json::Value| {
+            let mut request = post_admissions(token, body);
+            *request.uri_mut() = "/api/maintenance".parse().unwrap();
+            request
+        };
+
+        let body = serde_json::json!({ "enabled": true, "message": "migrating storage" });
+        let response = router(state.clone()).oneshot(post(None, body.clone())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(state.maintenance.message(), None);
+
+        let response = router(state.clone()).oneshot(post(Some("s3cret"), body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let json: serde_json::Value =
+            serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(json, serde_json::json!({ "enabled": true, "message": "migrating storage" }));
+        assert_eq!(state.maintenance.message().as_deref(), Some("migrating storage"));
+
+        let body = serde_json::json!({ "enabled": false });
+        let response = router(state.clone()).oneshot(post(Some("s3cret"), body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.maintenance.message(), None);
+    }
+
+    #[tokio::test]
+    async fn test_clear_read_only() {
+        let mut state = test_state();
+        state.config = Arc::new(RelayConfig {
+            admin_token: Some("s3cret".to_string()),
+            ..(*state.config).clone()
+        });
+        state.maintenance = Arc::new(Maintenance::disabled().with_read_only(true));
+        let clear = |token: Option<&str>| {
+            let mut request = Request::builder().method("DELETE").uri("/api/read-only").header("host", "example.com");
+            if let Some(token) = token {
+                request = request.header("authorization", format!("Bearer {}", token));
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        let response = router(state.clone()).oneshot(clear(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(state.maintenance.is_read_only());
+
+        let response = router(state.clone()).oneshot(clear(Some("s3cret"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let json: serde_json::Value =
+            serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(json, serde_json::json!({ "read_only": false, "was_read_only": true }));
+        assert!(!state.maintenance.is_read_only());
+    }
+
+    #[tokio::test]
+    async fn test_blocklist_update() {
+        let mut state = test_state();
+        state.config = Arc::new(RelayConfig {
+            admin_token: Some("s3cret".to_string()),
+            ..(*state.config).clone()
+        });
+        let put = |body: serde_json::Value| {
+            let mut request = post_admissions(Some("s3cret"), body);
+            *request.method_mut() = axum::http::Method::PUT;
+            *request.uri_mut() = "/api/blocklist".parse().unwrap();
+            request
+        };
+
+        let body = serde_json::json!({ "terms": ["casino"], "scopes": { "u33": { "patterns": ["\\bbeer\\b"] } } });
+        let response = router(state.clone()).oneshot(put(body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(state.blocklist.is_blocked("drt2z", "Online Casino"));
+        assert!(state.blocklist.is_blocked("u33db", "cold beer"));
+
+        // Invalid patterns are refused and the running rules stay
+        let response = router(state.clone()).oneshot(put(serde_json::json!({ "patterns": ["(unclosed"] }))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(state.blocklist.is_blocked("drt2z", "Online Casino"));
+
+        let (status, _) = get_json(state.clone(), "example.com", "/api/blocklist").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_event_meta_and_export_carry_received_at() {
+        let mut state = test_state();
+        state.config = Arc::new(RelayConfig {
+            admin_token: Some("s3cret".to_string()),
+            ..(*state.config).clone()
+        });
+        let drt2z = Scope::named("drt2z").unwrap();
+        let keys = Keys::generate();
+        let seen = note(&keys, 1).await;
+        let unseen = note(&keys, 7).await;
+        state.store.save(&drt2z, seen.clone()).await.unwrap();
+        state.store.save(&drt2z, unseen.clone()).await.unwrap();
+        state.first_seen.record(&drt2z, seen.id, 1_700_000_000);
+
+        let uri = format!("/api/events/{}/meta", seen.id.to_hex());
+        let (status, json) = get_json(state.clone(), "example.com", &uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json, serde_json::json!({ "scope": "drt2z", "received_at": 1_700_000_000 }));
+        let uri = format!("/api/events/{}/meta", nostr::nips::nip19::ToBech32::to_bech32(&seen.id).unwrap());
+        assert_eq!(get_json(state.clone(), "example.com", &uri).await.1["scope"], "drt2z");
+        let uri = format!("/api/events/{}/meta", unseen.id.to_hex());
+        assert_eq!(get_json(state.clone(), "example.com", &uri).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(get_json(state.clone(), "example.com", "/api/events/nope/meta").await.0, StatusCode::BAD_REQUEST);
+
+        let (status, _) = get_json(state.clone(), "example.com", "/api/scopes/drt2z/export").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let request = Request::builder()
+            .uri("/api/scopes/drt2z/export")
+            .header("host", "example.com")
+            .header("authorization", "Bearer s3cret")
+            .body(Body::empty())
+            .unwrap();
+        let response = router(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let lines: Vec<serde_json::Value> = body
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        for line in lines {
+            let expected = if line["id"] == seen.id.to_hex() { serde_json::json!(1_700_000_000) } else { serde_json::Value::Null };
+            assert_eq!(line["received_at"], expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ip_lists_guard_the_api() {
+        async fn status_from(state: ApiState, peer: &str, forwarded_for: Option<&str>) -> StatusCode {
+            let peer: SocketAddr = format!("{}:4000", peer).parse().unwrap();
+            let mut request = Request::builder()
+                .uri("/api/stats")
+                .header("host", "example.com")
+                .extension(ConnectInfo(peer));
+            if let Some(client) = forwarded_for {
+                request = request.header("x-forwarded-for", client);
+            }
+            get_json_with(state, request).await.0
+        }
+        let with_lists = |allow: &str, deny: &str| ApiState {
+            ip_filter: Arc::new(IpFilter::new(
+                crate::ip_filter::parse_cidr_list(allow).unwrap(),
+                crate::ip_filter::parse_cidr_list(deny).unwrap(),
+            )),
+            ..test_state()
+        };
+
+        // Empty lists restrict nothing
+        assert_eq!(status_from(test_state(), "203.0.113.9", None).await, StatusCode::OK);
+
+        let office = with_lists("10.0.0.0/8, fd00::/8", "");
+        assert_eq!(status_from(office.clone(), "10.1.2.3", None).await, StatusCode::OK);
+        assert_eq!(status_from(office.clone(), "fd00::1", None).await, StatusCode::OK);
+        assert_eq!(status_from(office.clone(), "203.0.113.9", None).await, StatusCode::FORBIDDEN);
+
+        let abuse = with_lists("", "203.0.113.0/24");
+        assert_eq!(status_from(abuse.clone(), "203.0.113.9", None).await, StatusCode::FORBIDDEN);
+        assert_eq!(status_from(abuse, "198.51.100.1", None).await, StatusCode::OK);
+
+        // Deny wins over allow
+        let both = with_lists("10.0.0.0/8", "10.6.0.0/16");
+        assert_eq!(status_from(both.clone(), "10.1.2.3", None).await, StatusCode::OK);
+        assert_eq!(status_from(both.clone(), "10.6.0.1", None).await, StatusCode::FORBIDDEN);
+
+        // Behind a local proxy the forwarded client is checked
+        assert_eq!(status_from(office.clone(), "127.0.0.1", Some("203.0.113.9")).await, StatusCode::FORBIDDEN);
+        assert_eq!(status_from(office.clone(), "127.0.0.1", Some("10.1.2.3")).await, StatusCode::OK);
+        // ... but not from anyone else
+        assert_eq!(status_from(office.clone(), "203.0.113.9", Some("10.1.2.3")).await, StatusCode::FORBIDDEN);
+
+        // Reloaded lists apply to the next request
+        office.ip_filter.replace(Vec::new(), Vec::new());
+        assert_eq!(status_from(office, "203.0.113.9", None).await, StatusCode::OK);
+    }
+}
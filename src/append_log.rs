@@ -0,0 +1,220 @@
+//! Keyed records kept in a JSONL file next to the database
+//!
+//! Expirations, requests to vanish and tombstones are small records that
+//! must survive a restart. `AppendLog` keeps them in memory by key and
+//! appends every change to a file under `database_path`; on load a later
+//! line replaces earlier ones with the same key. `compact` rewrites the
+//! file with one line per record, and runs on open so every append starts
+//! on a fresh line, dropping torn ones.
+
+use anyhow::{Context, Result};
+use parking_lot::{Mutex, RwLock, RwLockReadGuard};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::hash::Hash;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+use crate::config::RelayConfig;
+
+/// A record of an `AppendLog`
+pub trait LogEntry: Clone + Serialize + DeserializeOwned {
+    type Key: Copy + Eq + Hash;
+
+    /// Lines with the same key are states of the same record
+    fn key(&self) -> Self::Key;
+}
+
+/// Records by key, optionally backed by a file
+#[derive(Debug)]
+pub struct AppendLog<E: LogEntry> {
+    /// `None` keeps them in memory only
+    path: Option<PathBuf>,
+    /// Held across a rewrite so no append lands in the replaced file
+    file: Mutex<Option<File>>,
+    entries: RwLock<HashMap<E::Key, E>>,
+}
+
+impl<E: LogEntry> Default for AppendLog<E> {
+    fn default() -> Self {
+        Self::in_memory()
+    }
+}
+
+impl<E: LogEntry> AppendLog<E> {
+    /// Loads the log at `path`, creating it if it doesn't exist
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let mut entries = HashMap::new();
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+                    match serde_json::from_str::<E>(line) {
+                        Ok(entry) => {
+                            entries.insert(entry.key(), entry);
+                        }
+                        Err(e) => warn!("Skipping invalid line in {}: {}", path.display(), e),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        }
+        let log = Self {
+            path: Some(path),
+            file: Mutex::new(None),
+            entries: RwLock::new(entries),
+        };
+        log.compact()?;
+        Ok(log)
+    }
+
+    /// Opens `file_name` in the configured database directory
+    pub fn for_config(config: &RelayConfig, file_name: &str) -> Result<Self> {
+        Self::open(Path::new(&config.database_path).join(file_name))
+    }
+
+    /// A log that is never persisted, for tests and tooling
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            file: Mutex::new(None),
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Every record, by key
+    pub fn entries(&self) -> RwLockReadGuard<'_, HashMap<E::Key, E>> {
+        self.entries.read()
+    }
+
+    pub fn get(&self, key: &E::Key) -> Option<E> {
+        self.entries.read().get(key).cloned()
+    }
+
+    /// Stores `entry`, replacing the record with its key
+    pub fn insert(&self, entry: E) {
+        self.update(entry.key(), |_| Some(entry));
+    }
+
+    /// Replaces the record at `key` with what `f` makes of it and returns
+    /// the new record; when `f` returns `None` nothing changes
+    pub fn update(&self, key: E::Key, f: impl FnOnce(Option<&E>) -> Option<E>) -> Option<E> {
+        // File first, like `compact`, so lines land in the order records change
+        let mut file = self.file.lock();
+        let entry = {
+            let mut entries = self.entries.write();
+            let entry = f(entries.get(&key))?;
+            entries.insert(key, entry.clone());
+            entry
+        };
+        if let Some(file) = file.as_mut() {
+            let line = serde_json::to_string(&entry).unwrap_or_default();
+            if let Err(e) = writeln!(file, "{}", line) {
+                warn!("Failed to append to {}: {}", self.path.as_deref().unwrap_or(Path::new("")).display(), e);
+            }
+        }
+        Some(entry)
+    }
+
+    /// Drops the records at `keys` and rewrites the file without them
+    pub fn remove(&self, keys: &[E::Key]) -> Result<()> {
+        {
+            let mut entries = self.entries.write();
+            for key in keys {
+                entries.remove(key);
+            }
+        }
+        self.compact()
+    }
+
+    /// Rewrites the file from memory and reopens it for appending
+    pub fn compact(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut file = self.file.lock();
+        let mut contents = String::new();
+        for entry in self.entries.read().values() {
+            contents.push_str(&serde_json::to_string(entry).unwrap_or_default());
+            contents.push('\n');
+        }
+        let tmp = path.with_extension("jsonl.tmp");
+        std::fs::write(&tmp, contents).with_context(|| format!("failed to write {}", tmp.display()))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("failed to replace {}", path.display()))?;
+        *file = Some(
+            OpenOptions::new()
+                .append(true)
+                .open(path)
+                .with_context(|| format!("failed to open {}", path.display()))?,
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    struct Counter {
+        name: u8,
+        count: u64,
+    }
+
+    impl LogEntry for Counter {
+        type Key = u8;
+
+        fn key(&self) -> u8 {
+            self.name
+        }
+    }
+
+    fn bump(log: &AppendLog<Counter>, name: u8) {
+        log.update(name, |old| Some(Counter { name, count: old.map_or(1, |c| c.count + 1) }));
+    }
+
+    #[test]
+    fn test_later_lines_win_on_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("counters.jsonl");
+        let log = AppendLog::open(&path).unwrap();
+        bump(&log, 1);
+        bump(&log, 1);
+        bump(&log, 2);
+        // Appended, not rewritten
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+        drop(log);
+
+        let reopened: AppendLog<Counter> = AppendLog::open(&path).unwrap();
+        assert_eq!(reopened.get(&1), Some(Counter { name: 1, count: 2 }));
+        assert_eq!(reopened.entries().len(), 2);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn test_torn_lines_are_dropped_and_removals_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("counters.jsonl");
+        std::fs::write(&path, "{\"name\":1,\"count\":5}\n{\"name\":2,\"co").unwrap();
+        let log: AppendLog<Counter> = AppendLog::open(&path).unwrap();
+        assert_eq!(log.entries().len(), 1);
+        bump(&log, 3);
+        log.remove(&[1]).unwrap();
+        drop(log);
+
+        let reopened: AppendLog<Counter> = AppendLog::open(&path).unwrap();
+        assert_eq!(reopened.get(&1), None);
+        assert_eq!(reopened.get(&3), Some(Counter { name: 3, count: 1 }));
+    }
+
+    #[test]
+    fn test_update_can_decline() {
+        let log = AppendLog::in_memory();
+        assert!(log.update(1, |old: Option<&Counter>| old.cloned()).is_none());
+        assert!(log.entries().is_empty());
+    }
+}
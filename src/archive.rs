@@ -0,0 +1,512 @@
+//! Cold storage for evicted events
+//!
+//! Under `QuotaPolicy::Archive`, events evicted from a full cell are first
+//! written as one gzipped JSONL object per batch, named by scope and time
+//! range (`<prefix><scope>/<oldest>-<newest>-<id>.jsonl.gz`), and only
+//! deleted locally once the upload succeeded. `restore` reads those objects
+//! back and re-imports them through `store_admin::import_events`.
+//!
+//! Objects live behind `ObjectStore`: `S3ObjectStore` speaks the S3 REST API
+//! (path-style, SigV4) to any compatible endpoint, `FsObjectStore` keeps
+//! them in a local directory.
+
+use anyhow::{bail, Context, Result};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use futures::future::BoxFuture;
+use nostr::hashes::{hmac::{Hmac, HmacEngine}, sha256, Hash, HashEngine};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::config::RelayConfig;
+use crate::store::{scope_label, ScopeStore};
+use crate::store_admin::{self, ImportReport};
+use crate::syndication::rfc3339;
+
+/// Where archive objects are kept
+pub trait ObjectStore: Send + Sync + 'static {
+    fn put(&self, key: &str, body: Vec<u8>) -> BoxFuture<'_, Result<()>>;
+
+    fn get(&self, key: &str) -> BoxFuture<'_, Result<Vec<u8>>>;
+
+    /// Keys starting with `prefix`, sorted
+    fn list(&self, prefix: &str) -> BoxFuture<'_, Result<Vec<String>>>;
+}
+
+/// Objects as files under a directory, keys mapping to relative paths
+#[derive(Debug, Clone)]
+pub struct FsObjectStore {
+    root: PathBuf,
+}
+
+impl FsObjectStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        if key.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+            bail!("invalid object key '{}'", key);
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+fn walk(dir: &Path, root: &Path, keys: &mut Vec<String>) -> Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", dir.display())),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk(&path, root, keys)?;
+        } else if let Ok(relative) = path.strip_prefix(root) {
+            keys.push(relative.to_string_lossy().replace('\\', "/"));
+        }
+    }
+    Ok(())
+}
+
+impl ObjectStore for FsObjectStore {
+    fn put(&self, key: &str, body: Vec<u8>) -> BoxFuture<'_, Result<()>> {
+        let path = self.path(key);
+        Box::pin(async move {
+            let path = path?;
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            // Write then rename so a partial object is never visible
+            let partial = path.with_extension("partial");
+            std::fs::write(&partial, body)?;
+            std::fs::rename(&partial, &path)?;
+            Ok(())
+        })
+    }
+
+    fn get(&self, key: &str) -> BoxFuture<'_, Result<Vec<u8>>> {
+        let path = self.path(key);
+        Box::pin(async move {
+            let path = path?;
+            std::fs::read(&path).with_context(|| format!("failed to read {}", path.display()))
+        })
+    }
+
+    fn list(&self, prefix: &str) -> BoxFuture<'_, Result<Vec<String>>> {
+        let prefix = prefix.to_string();
+        Box::pin(async move {
+            let mut keys = Vec::new();
+            walk(&self.root, &self.root, &mut keys)?;
+            keys.retain(|key| key.starts_with(&prefix) && !key.ends_with(".partial"));
+            keys.sort();
+            Ok(keys)
+        })
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut engine = HmacEngine::<sha256::Hash>::new(key);
+    engine.input(data);
+    Hmac::<sha256::Hash>::from_engine(engine).to_byte_array()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    sha256::Hash::hash(data).to_string()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// SigV4 signing key for a day, region and service
+pub fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> [u8; 32] {
+    let k_date = hmac(format!("AWS4{}", secret).as_bytes(), date.as_bytes());
+    let k_region = hmac(&k_date, region.as_bytes());
+    let k_service = hmac(&k_region, service.as_bytes());
+    hmac(&k_service, b"aws4_request")
+}
+
+/// Percent-encodes everything but unreserved characters (and `/` if asked)
+fn uri_encode(value: &str, keep_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Values of every `<tag>` element in an XML document
+fn xml_values<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    let mut values = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else { break };
+        values.push(&rest[..end]);
+        rest = &rest[end + close.len()..];
+    }
+    values
+}
+
+fn xml_unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// S3-compatible bucket addressed path-style
+#[derive(Debug, Clone)]
+pub struct S3ObjectStore {
+    client: reqwest::Client,
+    endpoint: url::Url,
+    bucket: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+impl S3ObjectStore {
+    pub fn new(
+        endpoint: &str,
+        bucket: impl Into<String>,
+        region: impl Into<String>,
+        access_key_id: impl Into<String>,
+        secret_access_key: impl Into<String>,
+    ) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .user_agent(concat!("geohashed-relay/", env!("CARGO_PKG_VERSION")))
+            .timeout(Duration::from_secs(60))
+            .build()?;
+        Ok(Self {
+            client,
+            endpoint: url::Url::parse(endpoint).with_context(|| format!("invalid archive endpoint '{}'", endpoint))?,
+            bucket: bucket.into(),
+            region: region.into(),
+            access_key_id: access_key_id.into(),
+            secret_access_key: secret_access_key.into(),
+        })
+    }
+
+    /// The configured bucket, or `bucket` if given (from an `s3://` URL)
+    pub fn for_config(config: &RelayConfig, bucket: Option<&str>) -> Result<Self> {
+        let Some(endpoint) = &config.archive_endpoint else {
+            bail!("ARCHIVE_ENDPOINT is not set");
+        };
+        let Some(bucket) = bucket.or(config.archive_bucket.as_deref()) else {
+            bail!("ARCHIVE_BUCKET is not set");
+        };
+        Self::new(
+            endpoint,
+            bucket,
+            config.archive_region.clone(),
+            config.archive_access_key_id.clone().unwrap_or_default(),
+            config.archive_secret_access_key.clone().unwrap_or_default(),
+        )
+    }
+
+    /// Sends a SigV4-signed request for `key` (empty for the bucket itself)
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response> {
+        let path = if key.is_empty() {
+            format!("/{}", self.bucket)
+        } else {
+            format!("/{}/{}", self.bucket, uri_encode(key, true))
+        };
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(k, v)| (uri_encode(k, false), uri_encode(v, false)))
+            .collect();
+        query.sort();
+        let query = query.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join("&");
+
+        let host = match self.endpoint.port() {
+            Some(port) => format!("{}:{}", self.endpoint.host_str().unwrap_or_default(), port),
+            None => self.endpoint.host_str().unwrap_or_default().to_string(),
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+        let amz_date = rfc3339(now).replace(['-', ':'], "");
+        let date = &amz_date[..8];
+        let payload_hash = sha256_hex(&body);
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+            method, path, query, host, payload_hash, amz_date, payload_hash
+        );
+        let credential_scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            credential_scope,
+            sha256_hex(canonical_request.as_bytes())
+        );
+        let key = signing_key(&self.secret_access_key, date, &self.region, "s3");
+        let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+            self.access_key_id, credential_scope, signature
+        );
+
+        let mut url = format!("{}://{}{}", self.endpoint.scheme(), host, path);
+        if !query.is_empty() {
+            url = format!("{}?{}", url, query);
+        }
+        let response = self
+            .client
+            .request(method.clone(), url)
+            .header("x-amz-date", &amz_date)
+            .header("x-amz-content-sha256", &payload_hash)
+            .header(reqwest::header::AUTHORIZATION, authorization)
+            .body(body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            bail!("{} {} returned {}", method, path, response.status());
+        }
+        Ok(response)
+    }
+}
+
+impl ObjectStore for S3ObjectStore {
+    fn put(&self, key: &str, body: Vec<u8>) -> BoxFuture<'_, Result<()>> {
+        let key = key.to_string();
+        Box::pin(async move {
+            self.send(reqwest::Method::PUT, &key, &[], body).await?;
+            Ok(())
+        })
+    }
+
+    fn get(&self, key: &str) -> BoxFuture<'_, Result<Vec<u8>>> {
+        let key = key.to_string();
+        Box::pin(async move {
+            let response = self.send(reqwest::Method::GET, &key, &[], Vec::new()).await?;
+            Ok(response.bytes().await?.to_vec())
+        })
+    }
+
+    fn list(&self, prefix: &str) -> BoxFuture<'_, Result<Vec<String>>> {
+        let prefix = prefix.to_string();
+        Box::pin(async move {
+            let mut keys = Vec::new();
+            let mut token: Option<String> = None;
+            loop {
+                let mut query = vec![("list-type", "2"), ("prefix", prefix.as_str())];
+                if let Some(token) = &token {
+                    query.push(("continuation-token", token.as_str()));
+                }
+                let response = self.send(reqwest::Method::GET, "", &query, Vec::new()).await?;
+                let xml = response.text().await?;
+                keys.extend(xml_values(&xml, "Key").into_iter().map(xml_unescape));
+                token = xml_values(&xml, "NextContinuationToken").first().map(|t| xml_unescape(t));
+                if token.is_none() {
+                    break;
+                }
+            }
+            keys.sort();
+            Ok(keys)
+        })
+    }
+}
+
+/// Gzipped JSONL of `events`
+pub fn encode(events: &[Event]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    for event in events {
+        encoder.write_all(event.as_json().as_bytes())?;
+        encoder.write_all(b"\n")?;
+    }
+    Ok(encoder.finish()?)
+}
+
+/// Events in a gzipped JSONL object
+pub fn decode(object: &[u8]) -> Result<Vec<Event>> {
+    let mut events = Vec::new();
+    for line in BufReader::new(GzDecoder::new(object)).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        events.push(Event::from_json(&line).context("invalid event in archive object")?);
+    }
+    Ok(events)
+}
+
+/// Writes evicted events to an `ObjectStore`
+#[derive(Clone)]
+pub struct Archiver {
+    objects: Arc<dyn ObjectStore>,
+    prefix: String,
+}
+
+impl std::fmt::Debug for Archiver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Archiver").field("prefix", &self.prefix).finish_non_exhaustive()
+    }
+}
+
+impl Archiver {
+    pub fn new(objects: Arc<dyn ObjectStore>, prefix: impl Into<String>) -> Self {
+        Self { objects, prefix: prefix.into() }
+    }
+
+    /// The configured S3 archive
+    pub fn for_config(config: &RelayConfig) -> Result<Self> {
+        let objects = S3ObjectStore::for_config(config, None)?;
+        Ok(Self::new(Arc::new(objects), config.archive_prefix.clone()))
+    }
+
+    /// Key for a batch; the first event's id keeps equal time ranges apart
+    pub fn object_key(&self, scope: &Scope, events: &[Event]) -> String {
+        let oldest = events.iter().map(|e| e.created_at.as_u64()).min().unwrap_or(0);
+        let newest = events.iter().map(|e| e.created_at.as_u64()).max().unwrap_or(0);
+        let id = events.first().map(|e| e.id.to_hex()).unwrap_or_default();
+        format!(
+            "{}{}/{:010}-{:010}-{}.jsonl.gz",
+            self.prefix,
+            scope_label(scope),
+            oldest,
+            newest,
+            &id[..id.len().min(16)]
+        )
+    }
+
+    /// Uploads `events` as one object
+    pub async fn archive(&self, scope: &Scope, events: &[Event]) -> Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        let key = self.object_key(scope, events);
+        let body = encode(events)?;
+        let bytes = body.len() as u64;
+        if let Err(e) = self.objects.put(&key, body).await {
+            metrics::counter!("relay_archive_failures_total").increment(1);
+            return Err(e.context(format!("failed to archive {}", key)));
+        }
+        metrics::counter!("relay_archived_bytes_total").increment(bytes);
+        metrics::counter!("relay_archived_events_total").increment(events.len() as u64);
+        Ok(())
+    }
+
+    /// Re-imports every archived object for `scope`
+    pub async fn restore(&self, store: &dyn ScopeStore, scope: &Scope) -> Result<ImportReport> {
+        let prefix = format!("{}{}/", self.prefix, scope_label(scope));
+        let mut report = ImportReport::default();
+        for key in self.objects.list(&prefix).await? {
+            let events = decode(&self.objects.get(&key).await?).with_context(|| format!("failed to read {}", key))?;
+            report += store_admin::import_events(store, scope, events).await?;
+        }
+        Ok(report)
+    }
+}
+
+/// Parses a `restore --from` location: `s3://bucket[/prefix]` (using the
+/// configured endpoint and credentials) or `file:///path[/prefix]`
+pub fn archiver_for_location(location: &str, config: &RelayConfig) -> Result<Archiver> {
+    if let Some(rest) = location.strip_prefix("s3://") {
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            bail!("invalid archive location '{}'", location);
+        }
+        let objects = S3ObjectStore::for_config(config, Some(bucket))?;
+        return Ok(Archiver::new(Arc::new(objects), normalize_prefix(prefix)));
+    }
+    if let Some(path) = location.strip_prefix("file://") {
+        return Ok(Archiver::new(Arc::new(FsObjectStore::new(path)), ""));
+    }
+    bail!("archive location must start with s3:// or file://")
+}
+
+fn normalize_prefix(prefix: &str) -> String {
+    match prefix.trim_matches('/') {
+        "" => String::new(),
+        prefix => format!("{}/", prefix),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    async fn notes(count: u64) -> Vec<Event> {
+        let keys = Keys::generate();
+        let mut events = Vec::new();
+        for i in 0..count {
+            events.push(
+                EventBuilder::text_note(format!("archived {}", i))
+                    .custom_created_at(Timestamp::from(1_000 + i))
+                    .sign(&keys)
+                    .await
+                    .unwrap(),
+            );
+        }
+        events
+    }
+
+    #[test]
+    fn test_signing_key_matches_aws_example() {
+        let key = signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215", "us-east-1", "iam");
+        assert_eq!(hex(&key), "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d");
+    }
+
+    #[test]
+    fn test_list_response_parsing() {
+        let xml = "<ListBucketResult><Contents><Key>a/1&amp;2.jsonl.gz</Key></Contents>\
+                   <Contents><Key>a/3.jsonl.gz</Key></Contents>\
+                   <NextContinuationToken>abc</NextContinuationToken></ListBucketResult>";
+        let keys: Vec<String> = xml_values(xml, "Key").into_iter().map(xml_unescape).collect();
+        assert_eq!(keys, vec!["a/1&2.jsonl.gz", "a/3.jsonl.gz"]);
+        assert_eq!(xml_values(xml, "NextContinuationToken"), vec!["abc"]);
+        assert_eq!(uri_encode("drt2z/a b+c", true), "drt2z/a%20b%2Bc");
+    }
+
+    #[tokio::test]
+    async fn test_archive_and_restore_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let archiver = Archiver::new(Arc::new(FsObjectStore::new(dir.path())), "relay/");
+        let drt2z = Scope::named("drt2z").unwrap();
+        let events = notes(3).await;
+
+        archiver.archive(&drt2z, &events).await.unwrap();
+        let keys = FsObjectStore::new(dir.path()).list("relay/drt2z/").await.unwrap();
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0].starts_with("relay/drt2z/0000001000-0000001002-"));
+
+        let store = MemoryStore::new();
+        let report = archiver.restore(&store, &drt2z).await.unwrap();
+        assert_eq!(report.imported, 3);
+        assert_eq!(store.query(&drt2z, Filter::new()).await.unwrap().len(), 3);
+        // Other scopes' objects are not touched
+        let other = archiver.restore(&store, &Scope::named("9q8yy").unwrap()).await.unwrap();
+        assert_eq!(other.imported, 0);
+    }
+
+    #[test]
+    fn test_locations() {
+        let config = RelayConfig {
+            archive_endpoint: Some("http://127.0.0.1:9000".to_string()),
+            ..Default::default()
+        };
+        let archiver = archiver_for_location("s3://cold/relay", &config).unwrap();
+        assert_eq!(archiver.prefix, "relay/");
+        assert!(archiver_for_location("file:///tmp/archive", &config).is_ok());
+        assert!(archiver_for_location("s3://", &config).is_err());
+        assert!(archiver_for_location("ftp://x", &config).is_err());
+        assert!(archiver_for_location("s3://cold", &RelayConfig::default()).is_err());
+    }
+}
@@ -0,0 +1,344 @@
+//! Append-only audit log of processed events
+//!
+//! When `audit_log_dir` is set, every EVENT that reaches `handle_event` is
+//! recorded as one JSON line: when, which event, who, what kind, which
+//! scope, whether it was accepted and the rejection reason code. Records go
+//! through a bounded channel to a writer thread, so the hot path never
+//! waits on the disk; if the writer falls behind, records are dropped and
+//! counted. Files are named `audit-{seq}.jsonl` and rotated by size.
+
+use anyhow::{Context, Result};
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+use crate::config::{AuditFsync, RelayConfig};
+
+/// Records buffered between `handle_event` and the writer thread
+const CHANNEL_CAPACITY: usize = 10_000;
+
+/// Whether an event was stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditDecision {
+    Accepted,
+    Rejected,
+}
+
+/// One line of the audit log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Unix seconds when the event was processed
+    pub ts: u64,
+    pub event_id: String,
+    pub pubkey: String,
+    pub kind: u16,
+    /// Scope label the event was stored in, or posted to if rejected
+    pub scope: String,
+    pub decision: AuditDecision,
+    /// `RejectReason` code for rejected events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(event: &Event, scope: String, decision: AuditDecision, reason: Option<&str>) -> Self {
+        Self {
+            ts: now_secs(),
+            event_id: event.id.to_hex(),
+            pubkey: event.pubkey.to_hex(),
+            kind: event.kind.as_u16(),
+            scope,
+            decision,
+            reason: reason.map(String::from),
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// Handle for queueing records; cheap to clone
+#[derive(Debug, Clone, Default)]
+pub struct AuditLog {
+    /// `None` when auditing is disabled
+    sender: Option<SyncSender<AuditRecord>>,
+}
+
+impl AuditLog {
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Starts the writer for the configured directory, if any
+    pub fn for_config(config: &RelayConfig) -> Result<Self> {
+        match &config.audit_log_dir {
+            Some(dir) => Self::open(dir, config.audit_log_max_bytes, config.audit_fsync),
+            None => Ok(Self::disabled()),
+        }
+    }
+
+    /// Starts a writer thread appending to `dir`
+    pub fn open(dir: impl Into<PathBuf>, max_bytes: u64, fsync: AuditFsync) -> Result<Self> {
+        let mut writer = AuditWriter::open(dir, max_bytes, fsync)?;
+        info!("Audit log: {}", writer.dir.display());
+        let (sender, receiver) = mpsc::sync_channel(CHANNEL_CAPACITY);
+        std::thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || writer.run(receiver))
+            .context("failed to start audit log writer")?;
+        Ok(Self { sender: Some(sender) })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    /// Queues a record without blocking, dropping it if the writer is behind
+    pub fn record(&self, record: AuditRecord) {
+        if let Some(sender) = &self.sender {
+            if sender.try_send(record).is_err() {
+                metrics::counter!("relay_audit_dropped_records_total").increment(1);
+            }
+        }
+    }
+}
+
+/// Appends records to size-rotated files
+pub struct AuditWriter {
+    dir: PathBuf,
+    max_bytes: u64,
+    fsync: AuditFsync,
+    seq: u64,
+    file: BufWriter<File>,
+    size: u64,
+}
+
+impl AuditWriter {
+    /// Opens the newest file in `dir`, creating the directory if needed
+    pub fn open(dir: impl Into<PathBuf>, max_bytes: u64, fsync: AuditFsync) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).with_context(|| format!("failed to create {}", dir.display()))?;
+        let seq = log_files(&dir)?.last().map(|(seq, _)| *seq).unwrap_or(0);
+        let (file, size) = open_segment(&dir, seq)?;
+        Ok(Self { dir, max_bytes, fsync, seq, file, size })
+    }
+
+    pub fn write(&mut self, record: &AuditRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        if self.max_bytes > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+        if self.fsync == AuditFsync::Always {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes buffered records, syncing them to disk unless fsync is off
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.flush()?;
+        if self.fsync != AuditFsync::Never {
+            self.file.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.flush()?;
+        self.seq += 1;
+        let (file, size) = open_segment(&self.dir, self.seq)?;
+        self.file = file;
+        self.size = size;
+        Ok(())
+    }
+
+    /// Writes queued records in batches until every `AuditLog` is dropped
+    fn run(&mut self, receiver: Receiver<AuditRecord>) {
+        while let Ok(record) = receiver.recv() {
+            let mut batch = vec![record];
+            loop {
+                match receiver.try_recv() {
+                    Ok(record) => batch.push(record),
+                    Err(TryRecvError::Empty | TryRecvError::Disconnected) => break,
+                }
+            }
+            let result = batch
+                .iter()
+                .try_for_each(|record| self.write(record))
+                .and_then(|_| self.flush());
+            if let Err(e) = result {
+                warn!("Failed to write audit log: {}", e);
+            }
+        }
+    }
+}
+
+fn segment_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("audit-{:010}.jsonl", seq))
+}
+
+fn open_segment(dir: &Path, seq: u64) -> Result<(BufWriter<File>, u64)> {
+    let path = segment_path(dir, seq);
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let size = file.metadata()?.len();
+    Ok((BufWriter::new(file), size))
+}
+
+/// Audit files in `dir`, oldest first
+pub fn log_files(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        let seq = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("audit-"))
+            .and_then(|name| name.strip_suffix(".jsonl"))
+            .and_then(|seq| seq.parse().ok());
+        if let Some(seq) = seq {
+            files.push((seq, path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Filter for `search`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditQuery {
+    pub pubkey: Option<PublicKey>,
+    /// Unix seconds
+    pub since: Option<u64>,
+}
+
+impl AuditQuery {
+    fn matches(&self, record: &AuditRecord) -> bool {
+        self.pubkey.is_none_or(|pubkey| record.pubkey == pubkey.to_hex())
+            && self.since.is_none_or(|since| record.ts >= since)
+    }
+}
+
+/// Calls `f` with every matching record, oldest first
+///
+/// Unparseable lines (e.g. a torn write at the end of a file) are skipped.
+pub fn search(dir: &Path, query: &AuditQuery, mut f: impl FnMut(&AuditRecord)) -> Result<()> {
+    for (_, path) in log_files(dir)? {
+        let file = File::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+        for line in BufReader::new(file).lines() {
+            let Ok(record) = serde_json::from_str::<AuditRecord>(&line?) else {
+                continue;
+            };
+            if query.matches(&record) {
+                f(&record);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(pubkey: &PublicKey, ts: u64) -> AuditRecord {
+        AuditRecord {
+            ts,
+            event_id: "00".repeat(32),
+            pubkey: pubkey.to_hex(),
+            kind: 1,
+            scope: "drt2z".to_string(),
+            decision: AuditDecision::Rejected,
+            reason: Some("wrong-scope".to_string()),
+        }
+    }
+
+    fn collect(dir: &Path, query: &AuditQuery) -> Vec<AuditRecord> {
+        let mut records = Vec::new();
+        search(dir, query, |record| records.push(record.clone())).unwrap();
+        records
+    }
+
+    #[test]
+    fn test_rotates_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let pubkey = Keys::generate().public_key();
+        let line_len = serde_json::to_vec(&record(&pubkey, 1)).unwrap().len() as u64 + 1;
+
+        let mut writer = AuditWriter::open(dir.path(), line_len * 3, AuditFsync::Never).unwrap();
+        for ts in 0..10 {
+            writer.write(&record(&pubkey, ts)).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let files = log_files(dir.path()).unwrap();
+        assert_eq!(files.len(), 4);
+        for (_, path) in &files {
+            assert!(fs::metadata(path).unwrap().len() <= line_len * 3);
+        }
+        let timestamps: Vec<u64> = collect(dir.path(), &AuditQuery::default()).iter().map(|r| r.ts).collect();
+        assert_eq!(timestamps, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_reopen_appends_to_newest_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let pubkey = Keys::generate().public_key();
+        let mut writer = AuditWriter::open(dir.path(), 0, AuditFsync::Batch).unwrap();
+        writer.write(&record(&pubkey, 1)).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        let mut writer = AuditWriter::open(dir.path(), 0, AuditFsync::Batch).unwrap();
+        writer.write(&record(&pubkey, 2)).unwrap();
+        writer.flush().unwrap();
+
+        assert_eq!(log_files(dir.path()).unwrap().len(), 1);
+        assert_eq!(collect(dir.path(), &AuditQuery::default()).len(), 2);
+    }
+
+    #[test]
+    fn test_search_filters_by_pubkey_and_since() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = Keys::generate().public_key();
+        let bob = Keys::generate().public_key();
+        let mut writer = AuditWriter::open(dir.path(), 0, AuditFsync::Never).unwrap();
+        for (pubkey, ts) in [(&alice, 100), (&bob, 200), (&alice, 300)] {
+            writer.write(&record(pubkey, ts)).unwrap();
+        }
+        writer.flush().unwrap();
+        // A torn trailing line is ignored
+        fs::OpenOptions::new()
+            .append(true)
+            .open(segment_path(dir.path(), 0))
+            .unwrap()
+            .write_all(b"{\"ts\":")
+            .unwrap();
+
+        let query = AuditQuery { pubkey: Some(alice), since: Some(200) };
+        let records = collect(dir.path(), &query);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].ts, 300);
+
+        let query = AuditQuery { pubkey: Some(bob), since: None };
+        assert_eq!(collect(dir.path(), &query).len(), 1);
+    }
+}
@@ -0,0 +1,104 @@
+//! NIP-42 authentication for the scope types that need it
+//!
+//! relay_builder's `Nip42Middleware` answers AUTH messages and records the
+//! connection's authenticated pubkey, which `handle_event` and
+//! `verify_filters` check against `root_write_auth`, `geohash_write_auth`
+//! and `geohash_read_auth`. `ScopedAuthMiddleware` only sends the AUTH
+//! challenge to connections whose scope needs it, so open scopes behave as
+//! before; clients answer an `auth-required:` rejection with that challenge.
+
+use nostr_lmdb::Scope;
+use relay_builder::middlewares::{AuthConfig, Nip42Middleware};
+use relay_builder::{
+    ConnectionContext, DisconnectContext, InboundContext, InboundProcessor, NostrMiddleware, OutboundContext,
+};
+use std::sync::Arc;
+use crate::config::RelayConfig;
+use crate::processor::ConnectionState;
+
+/// Challenges connections to scopes that require auth
+#[derive(Debug, Clone)]
+pub struct ScopedAuthMiddleware {
+    config: Arc<RelayConfig>,
+    inner: Nip42Middleware<ConnectionState>,
+}
+
+impl ScopedAuthMiddleware {
+    pub fn new(config: Arc<RelayConfig>) -> Self {
+        let inner = Nip42Middleware::new(AuthConfig {
+            auth_url: config.relay_url.clone(),
+            base_domain_parts: config.base_domain_parts(),
+            validate_subdomains: true,
+        });
+        Self { config, inner }
+    }
+
+    fn challenges(&self, scope: &Scope) -> bool {
+        let subdomain = match scope {
+            Scope::Named { name, .. } => Some(name.as_str()),
+            Scope::Default => None,
+        };
+        // Root reads cells through geo filters, so it needs the challenge too
+        let reads_cells = subdomain.is_none() && self.config.geo_filter_extension && self.config.geohash_read_auth;
+        self.config.write_auth_for(subdomain) || self.config.read_auth_for(subdomain) || reads_cells
+    }
+}
+
+impl NostrMiddleware<ConnectionState> for ScopedAuthMiddleware {
+    async fn on_connect(&self, ctx: ConnectionContext<'_, ConnectionState>) -> Result<(), anyhow::Error> {
+        let scope = ctx.state.read().subdomain.clone();
+        if self.challenges(&scope) {
+            return self.inner.on_connect(ctx).await;
+        }
+        Ok(())
+    }
+
+    async fn process_inbound<Next>(&self, ctx: InboundContext<'_, ConnectionState, Next>) -> Result<(), anyhow::Error>
+    where
+        Next: InboundProcessor<ConnectionState>,
+    {
+        self.inner.process_inbound(ctx).await
+    }
+
+    async fn process_outbound(&self, ctx: OutboundContext<'_, ConnectionState>) -> Result<(), anyhow::Error> {
+        self.inner.process_outbound(ctx).await
+    }
+
+    async fn on_disconnect(&self, ctx: DisconnectContext<'_, ConnectionState>) -> Result<(), anyhow::Error> {
+        self.inner.on_disconnect(ctx).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_scopes_needing_auth_are_challenged() {
+        let drt2z = Scope::named("drt2z").unwrap();
+        let middleware = ScopedAuthMiddleware::new(Arc::new(RelayConfig::default()));
+        assert!(!middleware.challenges(&Scope::Default));
+        assert!(!middleware.challenges(&drt2z));
+
+        let middleware = ScopedAuthMiddleware::new(Arc::new(RelayConfig {
+            geohash_read_auth: true,
+            ..Default::default()
+        }));
+        assert!(!middleware.challenges(&Scope::Default));
+        assert!(middleware.challenges(&drt2z));
+
+        let middleware = ScopedAuthMiddleware::new(Arc::new(RelayConfig {
+            geohash_read_auth: true,
+            geo_filter_extension: true,
+            ..Default::default()
+        }));
+        assert!(middleware.challenges(&Scope::Default));
+
+        let middleware = ScopedAuthMiddleware::new(Arc::new(RelayConfig {
+            root_write_auth: true,
+            ..Default::default()
+        }));
+        assert!(middleware.challenges(&Scope::Default));
+        assert!(!middleware.challenges(&drt2z));
+    }
+}
@@ -0,0 +1,449 @@
+//! Synthetic load generator
+//!
+//! Opens publisher and subscriber websockets against a relay, publishes
+//! locally signed events at a target rate and reports throughput, OK
+//! latency percentiles and a breakdown of rejections.
+//!
+//! ```text
+//! loadgen --target ws://127.0.0.1:8080 --domain example.com \
+//!         --publishers 20 --subscribers 5 --rate 200 --duration 30 \
+//!         --prefix dr --precision 5 --json results.json
+//! ```
+
+use anyhow::{bail, Context, Result};
+use futures::{SinkExt, StreamExt};
+use geohashed_relay::config::RelayConfig;
+use geohashed_relay::geohash_utils::{normalize_geohash, GEOHASH_ALPHABET, MAX_GEOHASH_LENGTH};
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, Message},
+};
+
+const USAGE: &str = "usage: loadgen [--target URL] [--domain DOMAIN] [--publishers N] [--subscribers N] \
+[--rate EVENTS_PER_SEC] [--duration SECS] [--prefix GEOHASH --precision N | --hot-cell GEOHASH] [--json PATH]";
+
+/// Where publishers and subscribers connect
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Cells {
+    /// Random cells of `precision` under `prefix`
+    Random { prefix: String, precision: usize },
+    /// Everyone on one cell
+    Hot(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Options {
+    target: String,
+    domain: String,
+    publishers: usize,
+    subscribers: usize,
+    rate: f64,
+    duration: Duration,
+    cells: Cells,
+    json: Option<String>,
+}
+
+impl Options {
+    fn parse(args: &[String], config: &RelayConfig) -> Result<Self> {
+        let domain = url::Url::parse(&config.relay_url)
+            .ok()
+            .and_then(|u| u.host_str().map(str::to_string))
+            .unwrap_or_else(|| "localhost".to_string());
+        let mut options = Options {
+            target: config.relay_url.clone(),
+            domain,
+            publishers: 10,
+            subscribers: 5,
+            rate: 100.0,
+            duration: Duration::from_secs(30),
+            cells: Cells::Random { prefix: String::new(), precision: 5 },
+            json: None,
+        };
+
+        let mut prefix = String::new();
+        let mut precision = 5;
+        let mut hot = None;
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{} needs a value\n{}", flag, USAGE));
+            match flag.as_str() {
+                "--target" => options.target = value()?.clone(),
+                "--domain" => options.domain = value()?.clone(),
+                "--publishers" => options.publishers = value()?.parse().context("invalid --publishers")?,
+                "--subscribers" => options.subscribers = value()?.parse().context("invalid --subscribers")?,
+                "--rate" => options.rate = value()?.parse().context("invalid --rate")?,
+                "--duration" => {
+                    options.duration = Duration::from_secs(value()?.parse().context("invalid --duration")?)
+                }
+                "--prefix" => prefix = value()?.clone(),
+                "--precision" => precision = value()?.parse().context("invalid --precision")?,
+                "--hot-cell" => hot = Some(value()?.clone()),
+                "--json" => options.json = Some(value()?.clone()),
+                _ => bail!(USAGE),
+            }
+        }
+
+        if options.publishers == 0 || options.rate.is_nan() || options.rate <= 0.0 {
+            bail!("--publishers and --rate must be positive");
+        }
+        options.cells = match hot {
+            Some(cell) => Cells::Hot(normalize_geohash(&cell).context("--hot-cell must be a geohash")?),
+            None => {
+                if precision == 0 || precision > MAX_GEOHASH_LENGTH || prefix.len() > precision {
+                    bail!("--precision must be between the prefix length and {}", MAX_GEOHASH_LENGTH);
+                }
+                if !prefix.is_empty() {
+                    prefix = normalize_geohash(&prefix).context("--prefix must be a geohash")?;
+                }
+                Cells::Random { prefix, precision }
+            }
+        };
+        Ok(options)
+    }
+}
+
+impl Cells {
+    fn pick(&self, rng: &mut impl Rng) -> String {
+        match self {
+            Cells::Hot(cell) => cell.clone(),
+            Cells::Random { prefix, precision } => {
+                let alphabet = GEOHASH_ALPHABET.as_bytes();
+                let mut cell = prefix.clone();
+                while cell.len() < *precision {
+                    cell.push(alphabet[rng.gen_range(0..alphabet.len())] as char);
+                }
+                cell
+            }
+        }
+    }
+}
+
+/// Builds one event with a rough real-world mix of kinds and tags
+///
+/// Mostly geotagged notes for the connection's cell, plus reactions,
+/// untagged notes, profiles, and a few notes tagged for another cell that
+/// the relay should reject.
+fn realistic_event(keys: &Keys, cell: &str, rng: &mut impl Rng) -> Result<Event> {
+    let g = |gh: &str| Tag::custom(TagKind::Custom("g".into()), vec![gh.to_string()]);
+    let roll: u8 = rng.gen_range(0..100);
+    let builder = match roll {
+        0..=64 => EventBuilder::text_note(format!("loadgen note {}", rng.gen::<u32>())).tags([g(cell)]),
+        65..=79 => EventBuilder::text_note("loadgen untagged note"),
+        80..=89 => EventBuilder::new(Kind::Reaction, "+")
+            .tags([Tag::event(EventId::all_zeros()), g(cell)]),
+        90..=94 => EventBuilder::new(Kind::Metadata, r#"{"name":"loadgen"}"#),
+        _ => {
+            // Tagged for a different cell than the one we're connected to
+            let other = if cell.starts_with('0') { "zzzzz" } else { "00000" };
+            EventBuilder::text_note("loadgen misrouted note").tags([g(other)])
+        }
+    };
+    Ok(builder.sign_with_keys(keys)?)
+}
+
+/// Shared counters for the run
+#[derive(Default)]
+struct Stats {
+    sent: AtomicU64,
+    accepted: AtomicU64,
+    received: AtomicU64,
+    connect_failures: AtomicU64,
+    latencies_us: Mutex<Vec<u64>>,
+    rejections: Mutex<BTreeMap<String, u64>>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+struct Latency {
+    p50_ms: f64,
+    p90_ms: f64,
+    p99_ms: f64,
+    max_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    target: String,
+    duration_secs: f64,
+    publishers: usize,
+    subscribers: usize,
+    target_rate: f64,
+    events_sent: u64,
+    events_accepted: u64,
+    events_rejected: u64,
+    accepted_per_sec: f64,
+    ok_latency: Latency,
+    rejections: BTreeMap<String, u64>,
+    subscriber_events: u64,
+    connect_failures: u64,
+}
+
+/// Nearest-rank percentile of sorted samples
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn latency(samples: &mut [u64]) -> Latency {
+    samples.sort_unstable();
+    let ms = |us: u64| us as f64 / 1000.0;
+    Latency {
+        p50_ms: ms(percentile(samples, 50.0)),
+        p90_ms: ms(percentile(samples, 90.0)),
+        p99_ms: ms(percentile(samples, 99.0)),
+        max_ms: ms(samples.last().copied().unwrap_or(0)),
+    }
+}
+
+/// Groups OK messages by their machine-readable prefix ("rate-limited", ...)
+fn rejection_reason(message: &str) -> String {
+    match message.split_once(':') {
+        Some((prefix, _)) if !prefix.contains(' ') => prefix.to_string(),
+        _ => "other".to_string(),
+    }
+}
+
+async fn connect(target: &str, host: &str) -> Result<tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>> {
+    let mut request = target.into_client_request()?;
+    request.headers_mut().insert("host", host.parse()?);
+    let (socket, _) = connect_async(request).await?;
+    Ok(socket)
+}
+
+async fn run_publisher(options: Arc<Options>, stats: Arc<Stats>, deadline: Instant) -> Result<()> {
+    let mut rng = rand::thread_rng();
+    let cell = options.cells.pick(&mut rng);
+    let host = format!("{}.{}", cell, options.domain);
+    let socket = match connect(&options.target, &host).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            stats.connect_failures.fetch_add(1, Ordering::Relaxed);
+            return Err(e);
+        }
+    };
+    let (mut sink, mut stream) = socket.split();
+    let keys = Keys::generate();
+    let pending: Arc<Mutex<HashMap<EventId, Instant>>> = Arc::default();
+
+    let reader = {
+        let stats = stats.clone();
+        let pending = pending.clone();
+        tokio::spawn(async move {
+            while let Some(Ok(message)) = stream.next().await {
+                let Message::Text(text) = message else { continue };
+                let Ok(value) = serde_json::from_str::<serde_json::Value>(&text) else { continue };
+                if value[0] != "OK" {
+                    continue;
+                }
+                let Some(id) = value[1].as_str().and_then(|id| EventId::from_hex(id).ok()) else { continue };
+                let Some(started) = pending.lock().remove(&id) else { continue };
+                if value[2] == true {
+                    stats.accepted.fetch_add(1, Ordering::Relaxed);
+                    stats.latencies_us.lock().push(started.elapsed().as_micros() as u64);
+                } else {
+                    let reason = rejection_reason(value[3].as_str().unwrap_or_default());
+                    *stats.rejections.lock().entry(reason).or_insert(0) += 1;
+                }
+            }
+        })
+    };
+
+    // Each publisher carries an equal share of the target rate
+    let interval = Duration::from_secs_f64(options.publishers as f64 / options.rate);
+    let mut ticker = tokio::time::interval(interval);
+    while Instant::now() < deadline {
+        ticker.tick().await;
+        let event = realistic_event(&keys, &cell, &mut rng)?;
+        pending.lock().insert(event.id, Instant::now());
+        let message = serde_json::json!(["EVENT", event]).to_string();
+        if sink.send(Message::Text(message.into())).await.is_err() {
+            break;
+        }
+        stats.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Give in-flight OKs a moment to arrive
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    reader.abort();
+    Ok(())
+}
+
+async fn run_subscriber(options: Arc<Options>, stats: Arc<Stats>, deadline: Instant) -> Result<()> {
+    let cell = options.cells.pick(&mut rand::thread_rng());
+    let host = format!("{}.{}", cell, options.domain);
+    let socket = match connect(&options.target, &host).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            stats.connect_failures.fetch_add(1, Ordering::Relaxed);
+            return Err(e);
+        }
+    };
+    let (mut sink, mut stream) = socket.split();
+    let req = serde_json::json!(["REQ", "loadgen", {"since": Timestamp::now().as_u64()}]).to_string();
+    sink.send(Message::Text(req.into())).await?;
+
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    let _ = tokio::time::timeout(remaining, async {
+        while let Some(Ok(message)) = stream.next().await {
+            if let Message::Text(text) = message {
+                if text.starts_with(r#"["EVENT""#) {
+                    stats.received.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    })
+    .await;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv::dotenv().ok();
+    let config = RelayConfig::from_env()?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = Arc::new(Options::parse(&args, &config)?);
+    eprintln!(
+        "loadgen: {} publishers, {} subscribers, {} events/s for {}s against {}",
+        options.publishers,
+        options.subscribers,
+        options.rate,
+        options.duration.as_secs(),
+        options.target
+    );
+
+    let stats = Arc::new(Stats::default());
+    let started = Instant::now();
+    let deadline = started + options.duration;
+
+    let mut tasks = Vec::new();
+    for _ in 0..options.subscribers {
+        tasks.push(tokio::spawn(run_subscriber(options.clone(), stats.clone(), deadline)));
+    }
+    for _ in 0..options.publishers {
+        tasks.push(tokio::spawn(run_publisher(options.clone(), stats.clone(), deadline)));
+    }
+    for task in tasks {
+        if let Ok(Err(e)) = task.await {
+            eprintln!("loadgen: connection failed: {}", e);
+        }
+    }
+
+    let elapsed = started.elapsed().as_secs_f64();
+    let rejections = stats.rejections.lock().clone();
+    let accepted = stats.accepted.load(Ordering::Relaxed);
+    let report = Report {
+        target: options.target.clone(),
+        duration_secs: elapsed,
+        publishers: options.publishers,
+        subscribers: options.subscribers,
+        target_rate: options.rate,
+        events_sent: stats.sent.load(Ordering::Relaxed),
+        events_accepted: accepted,
+        events_rejected: rejections.values().sum(),
+        accepted_per_sec: accepted as f64 / elapsed,
+        ok_latency: latency(&mut stats.latencies_us.lock()),
+        rejections,
+        subscriber_events: stats.received.load(Ordering::Relaxed),
+        connect_failures: stats.connect_failures.load(Ordering::Relaxed),
+    };
+
+    println!(
+        "sent {} | accepted {} ({:.1}/s) | rejected {} | OK latency p50 {:.1}ms p90 {:.1}ms p99 {:.1}ms max {:.1}ms | subscriber events {}",
+        report.events_sent,
+        report.events_accepted,
+        report.accepted_per_sec,
+        report.events_rejected,
+        report.ok_latency.p50_ms,
+        report.ok_latency.p90_ms,
+        report.ok_latency.p99_ms,
+        report.ok_latency.max_ms,
+        report.subscriber_events
+    );
+    for (reason, count) in &report.rejections {
+        println!("  rejected {}: {}", reason, count);
+    }
+
+    if let Some(path) = &options.json {
+        std::fs::write(path, serde_json::to_string_pretty(&report)?)
+            .with_context(|| format!("failed to write {}", path))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn config() -> RelayConfig {
+        RelayConfig {
+            relay_url: "wss://example.com".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_defaults_from_config() {
+        let options = Options::parse(&[], &config()).unwrap();
+        assert_eq!(options.target, "wss://example.com");
+        assert_eq!(options.domain, "example.com");
+        assert_eq!(options.cells, Cells::Random { prefix: String::new(), precision: 5 });
+    }
+
+    #[test]
+    fn test_parse_cells() {
+        let options = Options::parse(&args(&["--hot-cell", "DRT2Z", "--duration", "5"]), &config()).unwrap();
+        assert_eq!(options.cells, Cells::Hot("drt2z".to_string()));
+        assert_eq!(options.duration, Duration::from_secs(5));
+
+        let options = Options::parse(&args(&["--prefix", "dr", "--precision", "4"]), &config()).unwrap();
+        let cell = options.cells.pick(&mut rand::thread_rng());
+        assert!(cell.starts_with("dr"));
+        assert_eq!(normalize_geohash(&cell).unwrap().len(), 4);
+
+        assert!(Options::parse(&args(&["--hot-cell", "team1"]), &config()).is_err());
+        assert!(Options::parse(&args(&["--prefix", "drt2z", "--precision", "3"]), &config()).is_err());
+        assert!(Options::parse(&args(&["--rate", "0"]), &config()).is_err());
+        assert!(Options::parse(&args(&["--rate"]), &config()).is_err());
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut samples = (1..=100).map(|ms| ms * 1000).collect::<Vec<u64>>();
+        let latency = latency(&mut samples);
+        assert_eq!(latency.p50_ms, 50.0);
+        assert_eq!(latency.p99_ms, 99.0);
+        assert_eq!(latency.max_ms, 100.0);
+        assert_eq!(percentile(&[], 50.0), 0);
+    }
+
+    #[test]
+    fn test_rejection_reason() {
+        assert_eq!(rejection_reason("restricted: wrong cell"), "restricted");
+        assert_eq!(rejection_reason("rate-limited: slow down"), "rate-limited");
+        assert_eq!(rejection_reason("something went wrong"), "other");
+    }
+
+    #[test]
+    fn test_events_are_signed_locally() {
+        let keys = Keys::generate();
+        let mut rng = rand::thread_rng();
+        for _ in 0..50 {
+            let event = realistic_event(&keys, "drt2z", &mut rng).unwrap();
+            assert!(event.verify().is_ok());
+        }
+    }
+}
@@ -0,0 +1,288 @@
+//! Content blocklist
+//!
+//! Operators of family-friendly cells refuse events whose content contains
+//! a listed term (case-insensitive substring) or matches a listed regular
+//! expression. `blocklist` in config holds global rules plus per-scope
+//! replacements keyed by scope label prefix. Rejections never echo the
+//! matched term.
+//!
+//! Rules are compiled when the relay starts, so an invalid pattern stops
+//! startup, and again on every `PUT /api/blocklist`, which refuses invalid
+//! rules and leaves the running ones alone. Like maintenance mode, the last
+//! update is persisted under `database_path` and wins over config after a
+//! restart.
+//!
+//! The regex crate matches in linear time, so there's no catastrophic
+//! backtracking; the caps below bound compile time and memory.
+
+use anyhow::{bail, Context, Result};
+use arc_swap::ArcSwap;
+use regex::{RegexSet, RegexSetBuilder};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crate::config::{BlocklistConfig, BlocklistRules, RelayConfig};
+
+/// File name of the persisted rules inside `database_path`
+pub const BLOCKLIST_FILE: &str = "blocklist.json";
+
+/// Most terms and patterns across all rules
+pub const MAX_BLOCKLIST_ENTRIES: usize = 1000;
+
+/// Longest single term or pattern, in characters
+pub const MAX_BLOCKLIST_ENTRY_CHARS: usize = 256;
+
+/// Compiled size limit per rule set's regexes
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+#[derive(Debug)]
+struct CompiledRules {
+    /// Lowercased
+    terms: Vec<String>,
+    patterns: Option<RegexSet>,
+}
+
+impl CompiledRules {
+    fn compile(rules: &BlocklistRules) -> Result<Self> {
+        let patterns = if rules.patterns.is_empty() {
+            None
+        } else {
+            Some(
+                RegexSetBuilder::new(&rules.patterns)
+                    .case_insensitive(true)
+                    .size_limit(REGEX_SIZE_LIMIT)
+                    .dfa_size_limit(REGEX_SIZE_LIMIT)
+                    .build()
+                    .context("invalid blocklist pattern")?,
+            )
+        };
+        Ok(Self {
+            terms: rules
+                .terms
+                .iter()
+                .map(|term| term.to_lowercase())
+                .filter(|term| !term.trim().is_empty())
+                .collect(),
+            patterns,
+        })
+    }
+
+    fn matches(&self, content: &str, lowercased: &str) -> bool {
+        self.terms.iter().any(|term| lowercased.contains(term.as_str()))
+            || self.patterns.as_ref().is_some_and(|patterns| patterns.is_match(content))
+    }
+}
+
+#[derive(Debug)]
+struct Compiled {
+    config: BlocklistConfig,
+    global: CompiledRules,
+    /// Longest prefix first
+    scopes: Vec<(String, CompiledRules)>,
+}
+
+impl Compiled {
+    fn compile(config: BlocklistConfig) -> Result<Self> {
+        let all_rules = std::iter::once(&config.global).chain(config.scopes.values());
+        let entries: Vec<&String> = all_rules.flat_map(|rules| rules.terms.iter().chain(&rules.patterns)).collect();
+        if entries.len() > MAX_BLOCKLIST_ENTRIES {
+            bail!("blocklist has {} entries (max {})", entries.len(), MAX_BLOCKLIST_ENTRIES);
+        }
+        if let Some(entry) = entries.iter().find(|entry| entry.chars().count() > MAX_BLOCKLIST_ENTRY_CHARS) {
+            bail!(
+                "blocklist entry starting '{}' is too long (max {} characters)",
+                entry.chars().take(20).collect::<String>(),
+                MAX_BLOCKLIST_ENTRY_CHARS
+            );
+        }
+
+        let global = CompiledRules::compile(&config.global)?;
+        let mut scopes = config
+            .scopes
+            .iter()
+            .map(|(prefix, rules)| {
+                let compiled = CompiledRules::compile(rules).with_context(|| format!("in rules for '{}'", prefix))?;
+                Ok((prefix.to_lowercase(), compiled))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        scopes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Ok(Self { config, global, scopes })
+    }
+
+    fn rules_for(&self, scope_label: &str) -> &CompiledRules {
+        self.scopes
+            .iter()
+            .find(|(prefix, _)| scope_label.starts_with(prefix.as_str()))
+            .map(|(_, rules)| rules)
+            .unwrap_or(&self.global)
+    }
+}
+
+/// Runtime content rules, optionally backed by a file
+#[derive(Debug)]
+pub struct Blocklist {
+    /// `None` keeps updates in memory only
+    path: Option<PathBuf>,
+    compiled: ArcSwap<Compiled>,
+}
+
+impl Blocklist {
+    /// Compiles `config`, without persisting updates
+    pub fn new(config: BlocklistConfig) -> Result<Self> {
+        Ok(Self {
+            path: None,
+            compiled: ArcSwap::from_pointee(Compiled::compile(config)?),
+        })
+    }
+
+    /// Loads the rules from `path`, falling back to `default` if the file
+    /// doesn't exist
+    pub fn open(path: impl Into<PathBuf>, default: BlocklistConfig) -> Result<Self> {
+        let path = path.into();
+        let config = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("invalid blocklist {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => default,
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        let compiled = Compiled::compile(config).with_context(|| format!("invalid blocklist {}", path.display()))?;
+        Ok(Self {
+            path: Some(path),
+            compiled: ArcSwap::from_pointee(compiled),
+        })
+    }
+
+    /// Opens the rules kept in the configured database directory
+    pub fn for_config(config: &RelayConfig) -> Result<Self> {
+        Self::open(Path::new(&config.database_path).join(BLOCKLIST_FILE), config.blocklist.clone())
+            .context("invalid BLOCKLIST")
+    }
+
+    /// No rules, for tests and tooling
+    pub fn disabled() -> Self {
+        Self::new(BlocklistConfig::default()).expect("empty blocklist compiles")
+    }
+
+    /// The rules as configured
+    pub fn config(&self) -> BlocklistConfig {
+        self.compiled.load().config.clone()
+    }
+
+    /// Whether `content` is refused in the scope labelled `scope_label`
+    pub fn is_blocked(&self, scope_label: &str, content: &str) -> bool {
+        let compiled = self.compiled.load();
+        let rules = compiled.rules_for(scope_label);
+        if rules.terms.is_empty() && rules.patterns.is_none() {
+            return false;
+        }
+        rules.matches(content, &content.to_lowercase())
+    }
+
+    /// Replaces the rules, persisting them; invalid rules change nothing
+    pub fn set(&self, config: BlocklistConfig) -> Result<BlocklistConfig> {
+        let compiled = Compiled::compile(config)?;
+        // Persist before swapping so a failed write leaves memory and disk in step
+        if let Some(path) = &self.path {
+            save(path, &compiled.config)?;
+        }
+        let config = compiled.config.clone();
+        self.compiled.store(Arc::new(compiled));
+        Ok(config)
+    }
+}
+
+/// Writes the rules atomically (temp file, then rename)
+fn save(path: &Path, config: &BlocklistConfig) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(config)?)
+        .with_context(|| format!("failed to write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn rules(terms: &[&str], patterns: &[&str]) -> BlocklistRules {
+        BlocklistRules {
+            terms: terms.iter().map(|t| t.to_string()).collect(),
+            patterns: patterns.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    fn global(terms: &[&str], patterns: &[&str]) -> BlocklistConfig {
+        BlocklistConfig { global: rules(terms, patterns), scopes: BTreeMap::new() }
+    }
+
+    #[test]
+    fn test_substring_match_ignores_case() {
+        let blocklist = Blocklist::new(global(&["casino"], &[])).unwrap();
+        assert!(blocklist.is_blocked("drt2z", "Best CASINO bonuses here"));
+        assert!(blocklist.is_blocked("root", "onlinecasinos"));
+        assert!(!blocklist.is_blocked("drt2z", "Coffee at the square"));
+    }
+
+    #[test]
+    fn test_unicode_case_folding() {
+        let blocklist = Blocklist::new(global(&["Schöner Mist"], &["σκανδαλο"])).unwrap();
+        assert!(blocklist.is_blocked("u33db", "SO EIN SCHÖNER MIST"));
+        assert!(blocklist.is_blocked("sw8zh", "ΤΙ ΣΚΑΝΔΑΛΟ"));
+        // Case folding isn't accent folding
+        assert!(!blocklist.is_blocked("u33db", "so ein schoner mist"));
+    }
+
+    #[test]
+    fn test_regex_match() {
+        let blocklist = Blocklist::new(global(&[], &[r"\bfree\s+(sats|btc)\b", r"t\.me/\w+"])).unwrap();
+        assert!(blocklist.is_blocked("drt2z", "Get FREE   sats now"));
+        assert!(blocklist.is_blocked("drt2z", "join t.me/pumpgroup"));
+        assert!(!blocklist.is_blocked("drt2z", "freesats are a myth"));
+    }
+
+    #[test]
+    fn test_scope_prefix_override() {
+        let mut config = global(&["beer"], &[]);
+        config.scopes.insert("u33".to_string(), rules(&["beer", "wine"], &[]));
+        config.scopes.insert("u33db".to_string(), rules(&[], &[]));
+        let blocklist = Blocklist::new(config).unwrap();
+
+        assert!(blocklist.is_blocked("drt2z", "beer garden"));
+        assert!(!blocklist.is_blocked("drt2z", "wine bar"));
+        assert!(blocklist.is_blocked("u33d8", "wine bar"));
+        // The longest prefix wins, even when it allows everything
+        assert!(!blocklist.is_blocked("u33db", "beer garden"));
+    }
+
+    #[test]
+    fn test_invalid_rules_are_refused() {
+        assert!(Blocklist::new(global(&[], &["(unclosed"])).is_err());
+        assert!(Blocklist::new(global(&[], &[r"(\w{1,500}){1,500}"])).is_err());
+        let too_many: Vec<String> = (0..=MAX_BLOCKLIST_ENTRIES).map(|i| format!("term{}", i)).collect();
+        let too_many: Vec<&str> = too_many.iter().map(String::as_str).collect();
+        assert!(Blocklist::new(global(&too_many, &[])).is_err());
+        assert!(Blocklist::new(global(&[&"x".repeat(MAX_BLOCKLIST_ENTRY_CHARS + 1)], &[])).is_err());
+
+        // A failed update keeps the running rules
+        let blocklist = Blocklist::new(global(&["casino"], &[])).unwrap();
+        assert!(blocklist.set(global(&[], &["(unclosed"])).is_err());
+        assert!(blocklist.is_blocked("drt2z", "casino"));
+    }
+
+    #[test]
+    fn test_update_persists() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(BLOCKLIST_FILE);
+
+        let blocklist = Blocklist::open(&path, global(&["casino"], &[])).unwrap();
+        assert!(blocklist.is_blocked("drt2z", "casino"));
+        blocklist.set(global(&["poker"], &[])).unwrap();
+        assert!(!blocklist.is_blocked("drt2z", "casino"));
+
+        // The saved rules win over the config default after a restart
+        let reopened = Blocklist::open(&path, global(&["casino"], &[])).unwrap();
+        assert!(reopened.is_blocked("drt2z", "poker"));
+        assert!(!reopened.is_blocked("drt2z", "casino"));
+        assert_eq!(reopened.config(), global(&["poker"], &[]));
+    }
+}
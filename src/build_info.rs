@@ -0,0 +1,88 @@
+//! Version, commit and uptime of the running relay
+//!
+//! The commit is embedded by build.rs. Uptime is measured from
+//! `mark_process_start`, which main calls before anything else.
+
+use serde::Serialize;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use crate::replication::ReplicationHealth;
+
+/// Crate version
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Git commit the binary was built from ("unknown" outside a checkout)
+pub const GIT_COMMIT: &str = env!("GEOHASHED_RELAY_GIT_COMMIT");
+
+/// NIP-11 `software` URL
+pub const SOFTWARE: &str = "https://github.com/verse-pbc/geohashed-relay";
+
+static PROCESS_START: OnceLock<Instant> = OnceLock::new();
+
+/// Records the process start time; later calls have no effect
+pub fn mark_process_start() {
+    PROCESS_START.get_or_init(Instant::now);
+}
+
+/// Time since `mark_process_start` (or since the first uptime query)
+pub fn uptime() -> Duration {
+    PROCESS_START.get_or_init(Instant::now).elapsed()
+}
+
+/// `0.1.0 (abc123def456)`
+pub fn version_string() -> String {
+    format!("{} ({})", VERSION, GIT_COMMIT)
+}
+
+/// Publishes `relay_build_info{version, commit} 1`
+pub fn record_build_info_metric() {
+    metrics::gauge!("relay_build_info", "version" => VERSION, "commit" => GIT_COMMIT).set(1.0);
+}
+
+/// Body of `/health`
+#[derive(Debug, Serialize)]
+pub struct Health {
+    pub status: &'static str,
+    pub version: &'static str,
+    pub commit: &'static str,
+    pub uptime_secs: u64,
+    /// Only present on followers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replication: Option<ReplicationHealth>,
+    /// The operator's message while in maintenance mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<String>,
+    /// Whether EVENTs are refused until read-only mode is cleared
+    pub read_only: bool,
+}
+
+pub fn health() -> Health {
+    Health {
+        status: "ok",
+        version: VERSION,
+        commit: GIT_COMMIT,
+        uptime_secs: uptime().as_secs(),
+        replication: None,
+        maintenance: None,
+        read_only: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_string() {
+        assert_eq!(version_string(), format!("{} ({})", env!("CARGO_PKG_VERSION"), GIT_COMMIT));
+        assert!(!GIT_COMMIT.is_empty());
+    }
+
+    #[test]
+    fn test_uptime_counts_from_start() {
+        mark_process_start();
+        let first = uptime();
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(uptime() > first);
+    }
+}
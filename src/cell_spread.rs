@@ -0,0 +1,174 @@
+//! Distinct cells written per client IP
+//!
+//! Scanners walk geohash subdomains and drop one spam event in each cell,
+//! which per-scope and per-connection limits never see. `CellSpreadLimiter`
+//! remembers which cells each IP wrote to in the last hour; once an IP has
+//! used `max_cells_per_ip_per_hour`, writes to further cells are refused
+//! with `RejectReason::TooManyCells`, while the cells it already uses stay
+//! open so someone moving around town isn't cut off.
+//!
+//! At most `MAX_TRACKED_IPS` addresses are remembered; the least recently
+//! seen one is forgotten first.
+
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Window the distinct cells are counted over
+pub const CELL_SPREAD_WINDOW_SECS: u64 = 60 * 60;
+/// Addresses remembered at once
+pub const MAX_TRACKED_IPS: usize = 50_000;
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+#[derive(Debug, Default)]
+struct IpCells {
+    /// Position in `Tracked::recency`
+    touched: u64,
+    /// Cell name to last write
+    cells: HashMap<String, u64>,
+}
+
+#[derive(Debug, Default)]
+struct Tracked {
+    ips: HashMap<IpAddr, IpCells>,
+    /// Least recently seen first
+    recency: BTreeMap<u64, IpAddr>,
+    next_touch: u64,
+}
+
+/// Cells each client IP wrote to recently
+#[derive(Debug)]
+pub struct CellSpreadLimiter {
+    max_cells: usize,
+    capacity: usize,
+    tracked: Mutex<Tracked>,
+}
+
+impl CellSpreadLimiter {
+    /// `max_cells` of 0 disables the limit
+    pub fn new(max_cells: usize) -> Self {
+        Self::with_capacity(max_cells, MAX_TRACKED_IPS)
+    }
+
+    pub fn with_capacity(max_cells: usize, capacity: usize) -> Self {
+        Self {
+            max_cells,
+            capacity: capacity.max(1),
+            tracked: Mutex::new(Tracked::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_cells > 0
+    }
+
+    /// False if `ip` has used up its distinct cells and `cell` isn't one of
+    /// them; otherwise records the write
+    pub fn admit(&self, ip: IpAddr, cell: &str) -> bool {
+        self.admit_at(ip, cell, now_secs())
+    }
+
+    pub fn admit_at(&self, ip: IpAddr, cell: &str, now: u64) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        let mut tracked = self.tracked.lock();
+        let tracked = &mut *tracked;
+
+        if !tracked.ips.contains_key(&ip) && tracked.ips.len() >= self.capacity {
+            if let Some((_, oldest)) = tracked.recency.pop_first() {
+                tracked.ips.remove(&oldest);
+            }
+        }
+        let touch = tracked.next_touch;
+        tracked.next_touch += 1;
+        let entry = tracked.ips.entry(ip).or_default();
+        tracked.recency.remove(&entry.touched);
+        entry.touched = touch;
+        tracked.recency.insert(touch, ip);
+
+        let cutoff = now.saturating_sub(CELL_SPREAD_WINDOW_SECS);
+        entry.cells.retain(|_, at| *at > cutoff);
+        if !entry.cells.contains_key(cell) && entry.cells.len() >= self.max_cells {
+            metrics::counter!("relay_cell_spread_blocked_total").increment(1);
+            return false;
+        }
+        entry.cells.insert(cell.to_string(), now);
+        true
+    }
+
+    /// Addresses currently remembered
+    pub fn tracked_ips(&self) -> usize {
+        self.tracked.lock().ips.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCANNER: &str = "198.51.100.23";
+
+    fn cells(count: usize) -> Vec<String> {
+        // Distinct precision-5 cells around Denver
+        (0..count).map(|i| format!("9xj{:02}", i)).collect()
+    }
+
+    #[test]
+    fn test_scan_across_many_cells_is_cut_off() {
+        let limiter = CellSpreadLimiter::new(10);
+        let scanner: IpAddr = SCANNER.parse().unwrap();
+
+        let admitted: Vec<bool> = cells(50).iter().map(|cell| limiter.admit_at(scanner, cell, 1_000)).collect();
+        assert_eq!(admitted.iter().filter(|ok| **ok).count(), 10);
+        assert!(admitted[..10].iter().all(|ok| *ok));
+
+        // Cells it already uses stay open
+        assert!(limiter.admit_at(scanner, &cells(50)[3], 1_100));
+
+        // Other addresses are unaffected
+        let neighbour: IpAddr = "198.51.100.24".parse().unwrap();
+        assert!(cells(50)[20..30].iter().all(|cell| limiter.admit_at(neighbour, cell, 1_100)));
+    }
+
+    #[test]
+    fn test_window_slides() {
+        let limiter = CellSpreadLimiter::new(2);
+        let ip: IpAddr = SCANNER.parse().unwrap();
+        assert!(limiter.admit_at(ip, "9xj00", 1_000));
+        assert!(limiter.admit_at(ip, "9xj01", 2_000));
+        assert!(!limiter.admit_at(ip, "9xj02", 2_000));
+
+        // The first cell ages out of the hour
+        assert!(limiter.admit_at(ip, "9xj02", 1_000 + CELL_SPREAD_WINDOW_SECS));
+        assert!(!limiter.admit_at(ip, "9xj03", 1_000 + CELL_SPREAD_WINDOW_SECS));
+    }
+
+    #[test]
+    fn test_least_recently_seen_ip_is_forgotten() {
+        let limiter = CellSpreadLimiter::with_capacity(1, 2);
+        let a: IpAddr = "198.51.100.1".parse().unwrap();
+        let b: IpAddr = "198.51.100.2".parse().unwrap();
+        let c: IpAddr = "198.51.100.3".parse().unwrap();
+        assert!(limiter.admit_at(a, "9xj00", 1_000));
+        assert!(limiter.admit_at(b, "9xj00", 1_000));
+        // a is seen again, so b is the one to go
+        assert!(!limiter.admit_at(a, "9xj01", 1_000));
+        assert!(limiter.admit_at(c, "9xj00", 1_000));
+        assert_eq!(limiter.tracked_ips(), 2);
+        assert!(limiter.admit_at(b, "9xj01", 1_000));
+        assert!(!limiter.admit_at(c, "9xj01", 1_000));
+    }
+
+    #[test]
+    fn test_zero_disables() {
+        let limiter = CellSpreadLimiter::new(0);
+        let ip: IpAddr = SCANNER.parse().unwrap();
+        assert!(cells(50).iter().all(|cell| limiter.admit_at(ip, cell, 1_000)));
+        assert_eq!(limiter.tracked_ips(), 0);
+    }
+}
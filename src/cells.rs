@@ -0,0 +1,183 @@
+//! Child cells with content, for drilling down a geohash
+//!
+//! `GET /api/cells?prefix=dr` answers "which cells one level below `dr`
+//! have anything on this relay": every stored scope under the prefix is
+//! folded into its ancestor at `prefix.len() + 1` characters, so `drt`
+//! is listed when only `drt2z` holds events. Counts come from the
+//! aggregates the stats task caches for every scope, never from storage on
+//! request, and lag by up to `stats_interval_secs`.
+//!
+//! The same listing backs the "activity in this area" panel on a cell's
+//! info page and `GET /api/geohash/{gh}/children_activity`. Those never
+//! list a child below `children_activity_min_events`, so a quiet cell
+//! doesn't point at the one person posting in it.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use crate::config::RelayConfig;
+use crate::geohash_utils::{children, normalize_geohash, MAX_GEOHASH_LENGTH};
+use crate::stats::StatsCache;
+
+/// Children listed when no limit is requested (all of one level)
+pub const DEFAULT_CELLS_LIMIT: usize = 32;
+
+/// One child of the prefix
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChildCell {
+    pub geohash: String,
+    pub relay_url: String,
+    /// Events stored in this cell and all cells below it
+    pub events: usize,
+    /// `created_at` of the newest of those events
+    pub last_event_at: Option<u64>,
+}
+
+/// Body of `GET /api/cells`
+#[derive(Debug, Clone, Serialize)]
+pub struct CellListing {
+    pub prefix: String,
+    pub cells: Vec<ChildCell>,
+    /// Unix timestamp of the aggregates, null before the first run
+    pub computed_at: Option<u64>,
+}
+
+/// Lowercased `prefix` if it can start a cell name; "" lists the top level
+pub fn parse_prefix(prefix: &str) -> Option<String> {
+    let prefix = prefix.trim();
+    if prefix.is_empty() {
+        return Some(String::new());
+    }
+    normalize_geohash(prefix).filter(|prefix| prefix.len() < MAX_GEOHASH_LENGTH)
+}
+
+/// Children of `prefix` holding at least `min_events`, busiest first
+pub fn child_cells(
+    stats: &StatsCache,
+    config: &RelayConfig,
+    prefix: &str,
+    min_events: usize,
+    limit: usize,
+) -> CellListing {
+    let depth = prefix.len() + 1;
+    let mut counts: BTreeMap<String, (usize, Option<u64>)> = children(prefix)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|child| (child, (0, None)))
+        .collect();
+    for (label, aggregates) in stats.all() {
+        let Some(geohash) = normalize_geohash(&label) else {
+            continue;
+        };
+        if geohash.len() < depth {
+            continue;
+        }
+        if let Some(child) = counts.get_mut(&geohash[..depth]) {
+            child.0 += aggregates.stored_events;
+            child.1 = child.1.max(aggregates.last_event_at);
+        }
+    }
+
+    let mut cells: Vec<ChildCell> = counts
+        .into_iter()
+        .filter(|(_, (events, _))| *events > 0 && *events >= min_events)
+        .map(|(geohash, (events, last_event_at))| ChildCell {
+            relay_url: config.relay_url_for(Some(&geohash)),
+            geohash,
+            events,
+            last_event_at,
+        })
+        .collect();
+    cells.sort_by(|a, b| b.events.cmp(&a.events).then(a.geohash.cmp(&b.geohash)));
+    cells.truncate(limit.min(DEFAULT_CELLS_LIMIT));
+    CellListing {
+        prefix: prefix.to_string(),
+        cells,
+        computed_at: stats.computed_at(),
+    }
+}
+
+/// Direct children of `geohash` active enough to show to anyone, busiest first
+pub fn children_activity(stats: &StatsCache, config: &RelayConfig, geohash: &str) -> CellListing {
+    child_cells(stats, config, geohash, config.children_activity_min_events, DEFAULT_CELLS_LIMIT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::ScopeAggregates;
+    use std::collections::HashMap;
+
+    fn stored(events: usize, last_event_at: u64) -> ScopeAggregates {
+        ScopeAggregates {
+            stored_events: events,
+            events_last_hour: 0,
+            events_last_24h: 0,
+            distinct_pubkeys_last_hour: 0,
+            distinct_pubkeys_24h: 0,
+            top_kinds: Vec::new(),
+            last_event_at: (events > 0).then_some(last_event_at),
+        }
+    }
+
+    fn seeded() -> StatsCache {
+        let cache = StatsCache::new();
+        cache.replace(
+            HashMap::from([
+                ("root".to_string(), stored(100, 9_000)),
+                ("drt2z".to_string(), stored(5, 1_000)),
+                ("drt2y".to_string(), stored(2, 3_000)),
+                ("drt".to_string(), stored(1, 2_000)),
+                ("dr5re".to_string(), stored(9, 500)),
+                // Emptied by retention
+                ("drm".to_string(), stored(0, 0)),
+                ("9q8yy".to_string(), stored(4, 700)),
+            ]),
+            10_000,
+        );
+        cache
+    }
+
+    #[test]
+    fn test_children_fold_deeper_cells() {
+        let listing = child_cells(&seeded(), &RelayConfig::default(), "dr", 1, DEFAULT_CELLS_LIMIT);
+        let listed: Vec<_> = listing.cells.iter().map(|c| (c.geohash.as_str(), c.events, c.last_event_at)).collect();
+        assert_eq!(listed, [("dr5", 9, Some(500)), ("drt", 8, Some(3_000))]);
+        assert_eq!(listing.computed_at, Some(10_000));
+
+        let listing = child_cells(&seeded(), &RelayConfig::default(), "drt", 3, DEFAULT_CELLS_LIMIT);
+        let listed: Vec<_> = listing.cells.iter().map(|c| c.geohash.as_str()).collect();
+        assert_eq!(listed, ["drt2"]);
+    }
+
+    #[test]
+    fn test_empty_prefix_lists_top_level() {
+        let listing = child_cells(&seeded(), &RelayConfig::default(), "", 1, 1);
+        assert_eq!(listing.cells.len(), 1);
+        assert_eq!(listing.cells[0].geohash, "d");
+        assert_eq!(listing.cells[0].events, 17);
+    }
+
+    #[test]
+    fn test_prefix_validation() {
+        assert_eq!(parse_prefix(" DR "), Some("dr".to_string()));
+        assert_eq!(parse_prefix(""), Some(String::new()));
+        assert_eq!(parse_prefix("dra"), None);
+        assert_eq!(parse_prefix("drt2zbx"), None);
+    }
+
+    #[test]
+    fn test_children_activity_hides_quiet_children() {
+        let config = RelayConfig { children_activity_min_events: 3, ..Default::default() };
+        let listing = children_activity(&seeded(), &config, "drt");
+        let listed: Vec<_> = listing.cells.iter().map(|c| (c.geohash.as_str(), c.events)).collect();
+        assert_eq!(listed, [("drt2", 7)]);
+
+        // dr5 holds 9 events, drt 8; the emptied drm is never listed
+        let listing = children_activity(&seeded(), &config, "dr");
+        let listed: Vec<_> = listing.cells.iter().map(|c| c.geohash.as_str()).collect();
+        assert_eq!(listed, ["dr5", "drt"]);
+
+        let strict = RelayConfig { children_activity_min_events: 10, ..Default::default() };
+        assert!(children_activity(&seeded(), &strict, "dr").cells.is_empty());
+    }
+}
@@ -0,0 +1,328 @@
+//! Command-line subcommands
+//!
+//! With no arguments the binary serves the relay. Maintenance commands run
+//! against the configured database and exit.
+
+use anyhow::{bail, Context, Result};
+use nostr_lmdb::Scope;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::archive::archiver_for_location;
+use crate::audit::{self, AuditQuery};
+use crate::config::RelayConfig;
+use crate::keys::{self, parse_pubkey_flexible};
+use crate::store::{open_database, scope_from_label, scope_label, LmdbStore};
+use crate::store_admin::{self, RescopeOptions};
+
+const USAGE: &str = "usage: geohashed-relay [serve [--read-only] | verify | migrate rescope [--from <scope>] [--dry-run] [--resume] | audit grep [--pubkey <hex|npub>] [--since <unix-secs|30m|24h|7d>] | restore --scope <scope> --from <s3://bucket/prefix|file:///path> | keys generate [--out <file>] | keys show [--file <file>] [--reveal-secret]]";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Serve the relay, refusing EVENTs until cleared with `read_only`
+    Serve {
+        read_only: bool,
+    },
+    /// Re-verify signatures and scope placement of every stored event
+    Verify,
+    /// Move geotagged events out of `from` into their cells
+    Rescope {
+        from: Scope,
+        dry_run: bool,
+        resume: bool,
+    },
+    /// Print audit records matching the query
+    AuditGrep(AuditQuery),
+    /// Re-import archived events of `scope` from an archive location
+    Restore {
+        scope: Scope,
+        from: String,
+    },
+    /// Print a new keypair, optionally saving it to a key file
+    KeysGenerate {
+        out: Option<PathBuf>,
+    },
+    /// Print the public identity of a key file or the configured relay key
+    KeysShow {
+        file: Option<PathBuf>,
+        reveal_secret: bool,
+    },
+}
+
+impl Command {
+    /// Parses arguments after the program name
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        match args.as_slice() {
+            [] | ["serve"] => Ok(Command::Serve { read_only: false }),
+            ["--read-only"] | ["serve", "--read-only"] => Ok(Command::Serve { read_only: true }),
+            ["verify"] => Ok(Command::Verify),
+            ["migrate", "rescope", options @ ..] => parse_rescope(options),
+            ["audit", "grep", options @ ..] => parse_audit_grep(options, now_secs()),
+            ["restore", options @ ..] => parse_restore(options),
+            ["keys", "generate"] => Ok(Command::KeysGenerate { out: None }),
+            ["keys", "generate", "--out", out] => Ok(Command::KeysGenerate { out: Some(PathBuf::from(out)) }),
+            ["keys", "show", options @ ..] => parse_keys_show(options),
+            _ => bail!(USAGE),
+        }
+    }
+}
+
+fn parse_rescope(options: &[&str]) -> Result<Command> {
+    let mut from = Scope::Default;
+    let mut dry_run = false;
+    let mut resume = false;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match *option {
+            "--dry-run" => dry_run = true,
+            "--resume" => resume = true,
+            "--from" => {
+                let Some(label) = options.next() else { bail!(USAGE) };
+                let Some(scope) = scope_from_label(label) else {
+                    bail!("invalid scope '{}'", label)
+                };
+                from = scope;
+            }
+            _ => bail!(USAGE),
+        }
+    }
+    Ok(Command::Rescope { from, dry_run, resume })
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// Parses `--since` as unix seconds or an age like `30m`, `24h` or `7d`
+fn parse_since(value: &str, now: u64) -> Result<u64> {
+    if let Ok(secs) = value.parse() {
+        return Ok(secs);
+    }
+    let unit = match value.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 60 * 60,
+        Some('d') => 24 * 60 * 60,
+        _ => bail!("invalid --since '{}'", value),
+    };
+    let amount: u64 = value[..value.len() - 1]
+        .parse()
+        .with_context(|| format!("invalid --since '{}'", value))?;
+    Ok(now.saturating_sub(amount.saturating_mul(unit)))
+}
+
+fn parse_audit_grep(options: &[&str], now: u64) -> Result<Command> {
+    let mut query = AuditQuery::default();
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let Some(value) = options.next() else { bail!(USAGE) };
+        match *option {
+            "--pubkey" => query.pubkey = Some(parse_pubkey_flexible(value)?),
+            "--since" => query.since = Some(parse_since(value, now)?),
+            _ => bail!(USAGE),
+        }
+    }
+    Ok(Command::AuditGrep(query))
+}
+
+fn parse_restore(options: &[&str]) -> Result<Command> {
+    let mut scope = None;
+    let mut from = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let Some(value) = options.next() else { bail!(USAGE) };
+        match *option {
+            "--scope" => {
+                let Some(parsed) = scope_from_label(value) else {
+                    bail!("invalid scope '{}'", value)
+                };
+                scope = Some(parsed);
+            }
+            "--from" => from = Some(value.to_string()),
+            _ => bail!(USAGE),
+        }
+    }
+    let (Some(scope), Some(from)) = (scope, from) else { bail!(USAGE) };
+    Ok(Command::Restore { scope, from })
+}
+
+fn parse_keys_show(options: &[&str]) -> Result<Command> {
+    let mut file = None;
+    let mut reveal_secret = false;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match *option {
+            "--reveal-secret" => reveal_secret = true,
+            "--file" => {
+                let Some(path) = options.next() else { bail!(USAGE) };
+                file = Some(PathBuf::from(path));
+            }
+            _ => bail!(USAGE),
+        }
+    }
+    Ok(Command::KeysShow { file, reveal_secret })
+}
+
+/// Runs `verify`, returning whether the database is clean
+pub async fn run_verify(config: &RelayConfig) -> Result<bool> {
+    let store = LmdbStore::new(open_database(config)?);
+    let report = store_admin::verify(&store, &config.global_kinds).await?;
+    for anomaly in &report.anomalies {
+        println!("{}", anomaly);
+    }
+    println!(
+        "Checked {} events in {} scopes: {} anomalies",
+        report.events,
+        report.scopes,
+        report.anomalies.len()
+    );
+    Ok(report.is_clean())
+}
+
+/// Runs a maintenance command; `Serve` is handled by the binary
+pub async fn run(command: Command, config: Arc<RelayConfig>) -> Result<()> {
+    match command {
+        Command::Serve { .. } => bail!("serve is not a maintenance command"),
+        Command::Verify => {
+            if !run_verify(&config).await? {
+                bail!("database verification found anomalies");
+            }
+            Ok(())
+        }
+        Command::Rescope { from, dry_run, resume } => {
+            let store = LmdbStore::new(open_database(&config)?);
+            let checkpoint = Path::new(&config.database_path)
+                .join(format!("rescope-{}.checkpoint", scope_label(&from)));
+            let options = RescopeOptions {
+                source: from,
+                dry_run,
+                checkpoint: Some(checkpoint),
+                resume,
+                page_size: store_admin::PAGE_SIZE,
+            };
+            let report = store_admin::rescope(&store, &options, |planned| {
+                if dry_run {
+                    println!("would move {}", planned);
+                }
+            })
+            .await?;
+            println!(
+                "{} {} of {} scanned events",
+                if dry_run { "Would move" } else { "Moved" },
+                report.moved,
+                report.scanned
+            );
+            Ok(())
+        }
+        Command::AuditGrep(query) => {
+            let Some(dir) = &config.audit_log_dir else {
+                bail!("AUDIT_LOG_DIR is not set");
+            };
+            audit::search(Path::new(dir), &query, |record| {
+                println!("{}", serde_json::to_string(record).unwrap_or_default());
+            })
+        }
+        Command::Restore { scope, from } => {
+            let archiver = archiver_for_location(&from, &config)?;
+            let store = LmdbStore::new(open_database(&config)?);
+            let report = archiver.restore(&store, &scope).await?;
+            println!(
+                "Restored {} events into {} ({} with invalid signatures skipped)",
+                report.imported,
+                scope_label(&scope),
+                report.invalid
+            );
+            Ok(())
+        }
+        Command::KeysGenerate { out } => keys::generate(out.as_deref()),
+        Command::KeysShow { file, reveal_secret } => keys::show(file.as_deref(), reveal_secret),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::prelude::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(Command::parse(&args(&[])).unwrap(), Command::Serve { read_only: false });
+        assert_eq!(Command::parse(&args(&["serve"])).unwrap(), Command::Serve { read_only: false });
+        assert_eq!(Command::parse(&args(&["--read-only"])).unwrap(), Command::Serve { read_only: true });
+        assert_eq!(Command::parse(&args(&["serve", "--read-only"])).unwrap(), Command::Serve { read_only: true });
+        assert!(Command::parse(&args(&["verify", "--read-only"])).is_err());
+        assert_eq!(Command::parse(&args(&["verify"])).unwrap(), Command::Verify);
+        assert!(Command::parse(&args(&["verify", "extra"])).is_err());
+        assert!(Command::parse(&args(&["bogus"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_rescope() {
+        assert_eq!(
+            Command::parse(&args(&["migrate", "rescope"])).unwrap(),
+            Command::Rescope { from: Scope::Default, dry_run: false, resume: false }
+        );
+        assert_eq!(
+            Command::parse(&args(&["migrate", "rescope", "--from", "drt2z", "--dry-run", "--resume"])).unwrap(),
+            Command::Rescope { from: Scope::named("drt2z").unwrap(), dry_run: true, resume: true }
+        );
+        assert!(Command::parse(&args(&["migrate", "rescope", "--from"])).is_err());
+        assert!(Command::parse(&args(&["migrate", "rescope", "--from", ""])).is_err());
+        assert!(Command::parse(&args(&["migrate"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_audit_grep() {
+        let pubkey = Keys::generate().public_key();
+        let hex = pubkey.to_hex();
+        assert_eq!(
+            parse_audit_grep(&["--pubkey", &hex, "--since", "2h"], 10_000).unwrap(),
+            Command::AuditGrep(AuditQuery { pubkey: Some(pubkey), since: Some(2_800) })
+        );
+
+        assert_eq!(
+            Command::parse(&args(&["audit", "grep", "--since", "1700000000"])).unwrap(),
+            Command::AuditGrep(AuditQuery { pubkey: None, since: Some(1_700_000_000) })
+        );
+        assert_eq!(Command::parse(&args(&["audit", "grep"])).unwrap(), Command::AuditGrep(AuditQuery::default()));
+        assert!(Command::parse(&args(&["audit", "grep", "--since"])).is_err());
+        assert!(Command::parse(&args(&["audit", "grep", "--since", "soon"])).is_err());
+        assert!(Command::parse(&args(&["audit", "grep", "--pubkey", "nope"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_restore() {
+        assert_eq!(
+            Command::parse(&args(&["restore", "--scope", "drt2z", "--from", "s3://archive/relay"])).unwrap(),
+            Command::Restore { scope: Scope::named("drt2z").unwrap(), from: "s3://archive/relay".into() }
+        );
+        assert!(Command::parse(&args(&["restore", "--scope", "drt2z"])).is_err());
+        assert!(Command::parse(&args(&["restore", "--from", "file:///tmp/archive"])).is_err());
+        assert!(Command::parse(&args(&["restore", "--scope", "", "--from", "file:///tmp/archive"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        assert_eq!(Command::parse(&args(&["keys", "generate"])).unwrap(), Command::KeysGenerate { out: None });
+        assert_eq!(
+            Command::parse(&args(&["keys", "generate", "--out", "relay.key"])).unwrap(),
+            Command::KeysGenerate { out: Some(PathBuf::from("relay.key")) }
+        );
+        assert_eq!(
+            Command::parse(&args(&["keys", "show"])).unwrap(),
+            Command::KeysShow { file: None, reveal_secret: false }
+        );
+        assert_eq!(
+            Command::parse(&args(&["keys", "show", "--reveal-secret", "--file", "relay.key"])).unwrap(),
+            Command::KeysShow { file: Some(PathBuf::from("relay.key")), reveal_secret: true }
+        );
+        assert!(Command::parse(&args(&["keys", "show", "--file"])).is_err());
+        assert!(Command::parse(&args(&["keys", "generate", "--out"])).is_err());
+        assert!(Command::parse(&args(&["keys"])).is_err());
+    }
+}
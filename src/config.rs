@@ -1,30 +1,813 @@
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use crate::geohash_utils::{is_geohash_subdomain, DEFAULT_RESOLVE_PRECISION, MAX_GEOHASH_LENGTH};
+use crate::host_parsing::DEFAULT_BASE_DOMAIN_PARTS;
+use crate::ip_filter::{parse_cidr_list, Cidr};
+use crate::keys::parse_pubkey_flexible;
+use crate::global_kinds::DEFAULT_GLOBAL_KINDS;
+use crate::maintenance::DEFAULT_MAINTENANCE_MESSAGE;
+use crate::nip05::{is_valid_name, DEFAULT_NIP05_RELAY_NAME};
+use crate::receipts::DEFAULT_RECEIPT_KIND;
+use crate::status_notes::DEFAULT_STATUS_TEMPLATE;
+
+/// Where direct messages (kind 4 and kind 1059 gift wraps) are accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DmPolicy {
+    /// Only on the root scope, where recipients' clients look for them
+    #[default]
+    RootOnly,
+    /// Nowhere
+    Reject,
+    /// In every scope
+    Allow,
+}
+
+impl std::str::FromStr for DmPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "root-only" => Ok(DmPolicy::RootOnly),
+            "reject" => Ok(DmPolicy::Reject),
+            "allow" => Ok(DmPolicy::Allow),
+            other => anyhow::bail!("unknown DM policy '{}' (expected root-only, reject or allow)", other),
+        }
+    }
+}
+
+/// Preset for what geohash cells accept and keep
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GeohashProfile {
+    /// Cells accept every kind and keep events until evicted
+    Open,
+    /// Cells are live location chat: notes and kind 20000 only, metadata
+    /// routed to root, a day of retention
+    LocationChat,
+    /// Cells follow the granular options as configured
+    #[default]
+    Custom,
+}
+
+impl GeohashProfile {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GeohashProfile::Open => "open",
+            GeohashProfile::LocationChat => "location-chat",
+            GeohashProfile::Custom => "custom",
+        }
+    }
+}
+
+impl std::fmt::Display for GeohashProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for GeohashProfile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "open" => Ok(GeohashProfile::Open),
+            "location-chat" => Ok(GeohashProfile::LocationChat),
+            "custom" => Ok(GeohashProfile::Custom),
+            other => anyhow::bail!("unknown geohash profile '{}' (expected open, location-chat or custom)", other),
+        }
+    }
+}
+
+/// Kinds `location-chat` cells accept: notes and location chat, plus the
+/// metadata kinds that are routed on to root
+pub const LOCATION_CHAT_KINDS: [u16; 5] = [0, 1, 3, 10002, 20000];
+
+/// How long `location-chat` cells keep events
+pub const LOCATION_CHAT_RETENTION_SECS: u64 = 24 * 60 * 60;
+
+/// Room for the `["EVENT", ...]` envelope and JSON escaping on top of
+/// `max_event_size` in one websocket message
+pub const WEBSOCKET_MESSAGE_OVERHEAD: usize = 4 * 1024;
+
+/// What happens to requests without a Host header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MissingHostPolicy {
+    /// Serve them as the root scope
+    #[default]
+    Root,
+    /// Answer 400 before any upgrade
+    Reject,
+}
+
+impl std::str::FromStr for MissingHostPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "root" => Ok(MissingHostPolicy::Root),
+            "reject" => Ok(MissingHostPolicy::Reject),
+            other => anyhow::bail!("unknown missing Host policy '{}' (expected root or reject)", other),
+        }
+    }
+}
+
+/// How `geohash_max_ttl_days` is enforced
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TtlMode {
+    /// Events are accepted as posted and deleted once the TTL has passed
+    #[default]
+    Lenient,
+    /// Events must carry a NIP-40 expiration within the TTL
+    Strict,
+}
+
+impl std::str::FromStr for TtlMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "lenient" => Ok(TtlMode::Lenient),
+            "strict" => Ok(TtlMode::Strict),
+            other => anyhow::bail!("unknown TTL mode '{}' (expected lenient or strict)", other),
+        }
+    }
+}
+
+/// What happens to g tags at a precision the relay doesn't serve
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TagPrecisionMode {
+    /// Refuse the event
+    #[default]
+    Reject,
+    /// Route it to the nearest served precision, truncating or padding
+    Adjust,
+}
+
+impl std::str::FromStr for TagPrecisionMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "reject" => Ok(TagPrecisionMode::Reject),
+            "adjust" => Ok(TagPrecisionMode::Adjust),
+            other => anyhow::bail!("unknown tag precision mode '{}' (expected reject or adjust)", other),
+        }
+    }
+}
+
+/// What happens to events of a capped kind that target a finer cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PrecisionCapPolicy {
+    /// Refuse the event, naming the cap
+    #[default]
+    Reject,
+    /// Store it in the containing cell at the cap
+    Truncate,
+}
+
+impl std::str::FromStr for PrecisionCapPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "reject" => Ok(PrecisionCapPolicy::Reject),
+            "truncate" => Ok(PrecisionCapPolicy::Truncate),
+            other => anyhow::bail!("unknown precision cap policy '{}' (expected reject or truncate)", other),
+        }
+    }
+}
+
+/// What happens to writes into a geohash cell that is at its quota
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QuotaPolicy {
+    /// Refuse the event; reads keep working
+    #[default]
+    Reject,
+    /// Accept the event and delete the cell's oldest events
+    EvictOldest,
+    /// Like evict-oldest, but upload the events to the archive first
+    Archive,
+}
+
+impl std::str::FromStr for QuotaPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "reject" => Ok(QuotaPolicy::Reject),
+            "evict-oldest" => Ok(QuotaPolicy::EvictOldest),
+            "archive" => Ok(QuotaPolicy::Archive),
+            other => anyhow::bail!("unknown quota policy '{}' (expected reject, evict-oldest or archive)", other),
+        }
+    }
+}
+
+/// Where events are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StorageBackend {
+    /// LMDB under `database_path`
+    #[default]
+    Lmdb,
+    /// Scratch database wiped on every start, keeping only the newest
+    /// `memory_events_per_scope` events of each scope
+    Memory,
+}
+
+impl std::str::FromStr for StorageBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "lmdb" => Ok(StorageBackend::Lmdb),
+            "memory" => Ok(StorageBackend::Memory),
+            other => anyhow::bail!("unknown storage backend '{}' (expected lmdb or memory)", other),
+        }
+    }
+}
+
+/// Scopes opened at startup so their first write doesn't pay for it
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PreloadScopes {
+    #[default]
+    None,
+    /// Every scope already in the store
+    All,
+    /// Scope labels, "root" or geohash cells
+    Listed(Vec<String>),
+}
+
+impl std::str::FromStr for PreloadScopes {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().eq_ignore_ascii_case("all") {
+            return Ok(PreloadScopes::All);
+        }
+        let scopes: Vec<String> = s
+            .split(',')
+            .map(|scope| scope.trim().to_lowercase())
+            .filter(|scope| !scope.is_empty())
+            .collect();
+        if let Some(invalid) = scopes.iter().find(|scope| *scope != "root" && !is_geohash_subdomain(scope)) {
+            anyhow::bail!("invalid scope '{}' in PRELOAD_SCOPES (expected geohashes, root or all)", invalid);
+        }
+        Ok(if scopes.is_empty() { PreloadScopes::None } else { PreloadScopes::Listed(scopes) })
+    }
+}
+
+/// How geohash subdomains answer `/.well-known/nostr.json`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Nip05SubdomainPolicy {
+    /// Serve the root domain's document
+    #[default]
+    Serve,
+    /// Redirect to the root domain (NIP-05 clients don't follow redirects)
+    Redirect,
+}
+
+impl std::str::FromStr for Nip05SubdomainPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "serve" => Ok(Nip05SubdomainPolicy::Serve),
+            "redirect" => Ok(Nip05SubdomainPolicy::Redirect),
+            other => anyhow::bail!("unknown NIP-05 subdomain policy '{}' (expected serve or redirect)", other),
+        }
+    }
+}
+
+/// When the audit log is synced to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditFsync {
+    /// Leave it to the OS
+    Never,
+    /// After each batch the writer drains from its queue
+    #[default]
+    Batch,
+    /// After every record
+    Always,
+}
+
+impl std::str::FromStr for AuditFsync {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "never" => Ok(AuditFsync::Never),
+            "batch" => Ok(AuditFsync::Batch),
+            "always" => Ok(AuditFsync::Always),
+            other => anyhow::bail!("unknown audit fsync policy '{}' (expected never, batch or always)", other),
+        }
+    }
+}
+
+/// Who may write to a kind of scope
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WritePolicy {
+    /// Anyone
+    #[default]
+    Free,
+    /// Only pubkeys on the admission list
+    Paid,
+}
+
+impl std::str::FromStr for WritePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "free" => Ok(WritePolicy::Free),
+            "paid" => Ok(WritePolicy::Paid),
+            other => anyhow::bail!("unknown write policy '{}' (expected free or paid)", other),
+        }
+    }
+}
+
+/// What a NIP-09 deletion request does to the events it names
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeletionMode {
+    /// Storage deletes them
+    #[default]
+    Hard,
+    /// They are hidden and a tombstone records the request
+    Tombstone,
+}
+
+impl std::str::FromStr for DeletionMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "hard" => Ok(DeletionMode::Hard),
+            "tombstone" => Ok(DeletionMode::Tombstone),
+            other => anyhow::bail!("unknown deletion mode '{}' (expected hard or tombstone)", other),
+        }
+    }
+}
+
+/// Content an event may not contain
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlocklistRules {
+    /// Case-insensitive substrings
+    #[serde(default)]
+    pub terms: Vec<String>,
+    /// Regular expressions, matched case-insensitively
+    #[serde(default)]
+    pub patterns: Vec<String>,
+}
+
+/// Global content rules plus replacements for some scopes
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlocklistConfig {
+    #[serde(flatten)]
+    pub global: BlocklistRules,
+    /// Rules replacing the global ones, keyed by scope label prefix ("root"
+    /// or the start of a geohash); the longest matching prefix wins
+    #[serde(default)]
+    pub scopes: BTreeMap<String, BlocklistRules>,
+}
+
+/// A receiver POSTed every newly stored event that matches its filters
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Scope labels ("root" or a geohash); a trailing `*` matches by prefix.
+    /// Empty matches every scope
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Empty matches every kind
+    #[serde(default)]
+    pub kinds: Vec<u16>,
+    /// Key for the `X-Webhook-Signature` HMAC
+    pub secret: String,
+    /// Deliveries per minute; events over the budget are dropped (0 = unlimited)
+    #[serde(default = "default_webhook_events_per_minute")]
+    pub events_per_minute: u32,
+}
+
+fn default_webhook_events_per_minute() -> u32 {
+    60
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RelayConfig {
     // Server settings
     pub host: String,
     pub port: u16,
+    /// Unix socket served instead of host/port, from `LISTEN=unix:<path>`
+    pub unix_socket: Option<String>,
+    /// Permissions the socket file is created with
+    pub unix_socket_mode: u32,
+    /// `(uid, gid)` the socket file is handed to, e.g. the reverse proxy's
+    pub unix_socket_owner: Option<(u32, u32)>,
     pub relay_url: String,
+    /// Domain geohash subdomains hang off (e.g. `relay.mycompany.com`);
+    /// taken from `relay_url` when unset
+    pub base_domain: Option<String>,
+    /// Other base domains the relay is reachable under (e.g. an internal
+    /// name), with as many labels as `base_domain`; rejection hints point
+    /// clients at the one they connected to
+    pub alternate_base_domains: Vec<String>,
     
     // Database
+    pub storage_backend: StorageBackend,
+    /// Ring buffer size per scope for the memory backend
+    pub memory_events_per_scope: usize,
     pub database_path: String,
+    /// LMDB map size in bytes; writes fail once the map is full
+    pub lmdb_map_size: usize,
+    /// Map usage percentage at which the relay stops accepting events (0 disables)
+    pub storage_read_only_percent: u8,
+    /// Filesystem usage percentage above which the relay is read-only until
+    /// space is freed (0 disables)
+    pub disk_watermark_percent: u8,
+    /// Failed writes within `storage_error_window_secs` that report the
+    /// relay as degraded (0 disables)
+    pub storage_error_threshold: u32,
+    pub storage_error_window_secs: u64,
+    pub preload_scopes: PreloadScopes,
+    /// Seconds without writes after which a scope's in-memory state is
+    /// released, rebuilt on its next write (0 disables)
+    pub scope_idle_secs: u64,
+    /// Stored events allowed in one geohash cell (0 disables)
+    pub max_events_per_scope: u64,
+    /// Approximate stored bytes allowed in one geohash cell (0 disables)
+    pub max_bytes_per_scope: u64,
+    pub quota_policy: QuotaPolicy,
+    /// How often cached per-cell usage is recounted from the store
+    pub quota_refresh_secs: u64,
+    
+    // S3-compatible cold storage for evicted events
+    /// e.g. `https://s3.us-east-1.amazonaws.com` or `http://minio:9000`
+    pub archive_endpoint: Option<String>,
+    pub archive_bucket: Option<String>,
+    /// Key prefix for archive objects
+    pub archive_prefix: String,
+    pub archive_region: String,
+    pub archive_access_key_id: Option<String>,
+    pub archive_secret_access_key: Option<String>,
     
     // Limits
+    /// Also caps websocket messages, see `max_websocket_message_size`
     pub max_event_size: usize,
     pub max_subscriptions_per_connection: usize,
+    /// REQs per connection whose stored-event queries may run at once
+    pub max_concurrent_queries_per_connection: usize,
+    /// Cached REQ results across all scopes (0 disables the cache)
+    pub query_cache_size: usize,
+    /// How long a cached REQ result may be served
+    pub query_cache_ttl_secs: u64,
+    /// Cached COUNT results across all scopes (0 disables the cache)
+    pub count_cache_size: usize,
+    /// How long a cached COUNT result may be served
+    pub count_cache_ttl_secs: u64,
     pub max_filters_per_subscription: usize,
     pub max_limit_per_filter: usize,
+    /// Limit given to filters without one on large scopes (0 disables)
+    pub default_filter_limit: usize,
+    /// Stored events above which a scope is large, as of the last stats pass
+    pub default_filter_limit_above_events: usize,
+    /// `#g` values one REQ filter may list (0 disables)
+    pub max_geohashes_per_filter: usize,
+    /// Distinct geohash scopes one REQ may touch across its filters,
+    /// counting coordinates as the cells they stand for (0 disables)
+    pub max_geohash_scopes_per_subscription: usize,
+    /// Global websocket connection cap (0 disables)
+    pub max_connections: usize,
+    /// Extra connections allowed above the cap for localhost and trusted proxies
+    pub reserved_connections: usize,
+    pub trusted_proxies: Vec<std::net::IpAddr>,
+    /// Only clients in these CIDR ranges may connect or use the API (empty
+    /// for everyone). Reloaded on SIGHUP
+    pub ip_allowlist: Vec<Cidr>,
+    /// Clients in these CIDR ranges are refused, even if allowlisted.
+    /// Reloaded on SIGHUP
+    pub ip_denylist: Vec<Cidr>,
+    /// Messages queued for one connection before it is closed as a slow consumer
+    pub max_outbound_messages: usize,
+    /// Approximate bytes queued for one connection before it is closed
+    pub max_outbound_bytes: usize,
     
     // Rate limiting
+    /// Default events per minute for each scope (0 disables)
     pub events_per_minute: u32,
+    /// Per-scope budget by geohash precision, e.g. coarse cells get more
+    pub precision_events_per_minute: BTreeMap<usize, u32>,
+    /// Per-scope budget overrides keyed by scope label ("root" or a geohash)
+    pub scope_events_per_minute: HashMap<String, u32>,
+    /// Budget of each authenticated pubkey, taken instead of its scope's
+    /// (0 = authenticated connections share the scope budget)
+    pub authed_events_per_minute: u32,
+    /// Distinct cells one client IP may write to per hour (0 disables)
+    pub max_cells_per_ip_per_hour: usize,
+    
+    // Web of trust
+    /// Hex pubkeys the web of trust grows from; writes are only gated when
+    /// this is non-empty
+    pub wot_seed_pubkeys: Vec<String>,
+    /// Follow hops from a seed that are still trusted (1 = followed by a seed)
+    pub wot_depth: u8,
+    /// Relays contact lists are fetched from; the local store when empty
+    pub wot_bootstrap_relays: Vec<String>,
+    /// How often the trusted set is rebuilt
+    pub wot_refresh_secs: u64,
+    
+    // Stats command
+    /// Answer a "stats" EVENT with a NOTICE describing the connection
+    pub stats_command: bool,
+    /// Kind of the stats EVENT, which must p-tag the relay's pubkey
+    pub stats_command_kind: u16,
+    /// Least time between two answers on one connection
+    pub stats_command_interval_secs: u64,
+    
+    // Tag limits
+    /// Most `p` tags one event may carry (0 disables)
+    pub max_p_tags_per_event: u32,
+    /// Most tags one event may carry under any other single-letter name (0 disables)
+    pub max_tag_entries_per_letter: u32,
+    /// Per-kind replacement for both limits (0 exempts the kind); contact
+    /// and relay lists are exempt by default
+    pub kind_tag_limits: HashMap<u16, u32>,
+    /// Most `g` tags one event may carry, checked before any is parsed (0
+    /// disables); only the first one routes the event
+    pub max_geohash_tags_per_event: u32,
+    
+    // Proof of work (NIP-13)
+    /// Leading zero bits every event id needs (0 disables proof of work)
+    pub pow_min_difficulty: u8,
+    /// Ceiling for adaptive difficulty; at or below the minimum it's fixed
+    pub pow_max_difficulty: u8,
+    /// Accepted events per minute in a scope above which its difficulty
+    /// rises a bit per doubling (0 disables adaptive difficulty)
+    pub pow_threshold_per_minute: u32,
+    /// How often adaptive difficulty is recomputed
+    pub pow_interval_secs: u64,
+    
+    // Duplicate content filter
+    /// How long each scope remembers content (0 disables the filter)
+    pub duplicate_window_secs: u64,
+    /// Most events each scope remembers
+    pub duplicate_window_events: usize,
+    /// Recent matching events an event may repeat before it's refused...
+    pub duplicate_max_matches: usize,
+    /// ...when they come from more than this many distinct pubkeys
+    pub duplicate_max_pubkeys: usize,
+    /// Kinds never compared, reactions by default
+    pub duplicate_exempt_kinds: Vec<u16>,
+    
+    /// Words and patterns refused in event content; runtime changes through
+    /// `PUT /api/blocklist` are persisted and win after a restart
+    pub blocklist: BlocklistConfig,
     
     // Features
+    /// Hide and refuse events past their NIP-40 expiration tag
     pub enable_nip40_expiration: bool,
+    /// Optional middleware; see `optional_middleware` for the chain order
+    pub enable_logger_middleware: bool,
+    pub enable_error_handling: bool,
+    /// The per-scope rate limit (`events_per_minute` and its overrides)
+    pub enable_rate_limit: bool,
+    /// Seconds between the info-level summaries of stored and rejected
+    /// events per scope; 0 turns them off (see `log_summary`)
+    pub log_summary_interval_secs: u64,
+    pub dm_policy: DmPolicy,
+    /// Kinds always stored in (and readable from) the root scope; empty
+    /// for full per-cell isolation
+    pub global_kinds: Vec<u16>,
+    /// Kinds accepted from connections to the root scope (`None` for all)
+    pub root_allowed_kinds: Option<Vec<u16>>,
+    /// Kinds accepted from connections to geohash cells (`None` for all)
+    pub geohash_allowed_kinds: Option<Vec<u16>>,
+    /// Preset that sets the cell options; `custom` leaves them alone
+    pub geohash_profile: GeohashProfile,
+    /// Age after which events are deleted from geohash cells (0 keeps them)
+    pub geohash_retention_secs: u64,
+    /// Longest an event may live in a geohash cell, in days (0 for no limit)
+    pub geohash_max_ttl_days: u64,
+    /// Whether the TTL is enforced on write or by the retention sweeper
+    pub geohash_ttl_mode: TtlMode,
+    /// Kinds the TTL doesn't apply to, e.g. replaceable metadata
+    pub geohash_ttl_exempt_kinds: Vec<u16>,
+    /// Relay-side expiration for events stored without a NIP-40 tag, in
+    /// seconds after receipt (0 for none)
+    pub default_expiration_secs: u64,
+    /// Overrides of `default_expiration_secs` by scope prefix ("root" for
+    /// root); the longest matching prefix wins
+    pub scope_default_expiration_secs: BTreeMap<String, u64>,
+    /// How long after a NIP-62 request to vanish all new events from its
+    /// author are refused, in seconds (0 only refuses events older than
+    /// the request)
+    pub vanish_block_secs: u64,
+    /// Whether kind 5 deletions delete their targets or hide them behind
+    /// tombstones an admin can restore or purge
+    pub deletion_mode: DeletionMode,
+    
+    // Paid writes
+    /// Write policy for the root scope
+    pub root_write_policy: WritePolicy,
+    /// Write policy for geohash cells
+    pub cell_write_policy: WritePolicy,
+    /// Where users pay for admission (NIP-11 `payments_url`)
+    pub payments_url: Option<String>,
+    /// Admission fee advertised in NIP-11 `fees`
+    pub admission_fee_msats: Option<u64>,
+    
+    // NIP-42 authentication
+    /// Events posted to root need an authenticated connection
+    pub root_write_auth: bool,
+    /// Events posted to geohash cells need an authenticated connection
+    pub geohash_write_auth: bool,
+    /// REQs to geohash cells need an authenticated connection
+    pub geohash_read_auth: bool,
+    
+    /// Serve `/{geohash}` on the root domain as that cell's page instead of
+    /// redirecting to the subdomain
+    pub path_routing: bool,
+    /// Run behind a Tor onion service: listen on 127.0.0.1 only and take
+    /// the scope from the path (`/drt2z`), websockets included, since
+    /// onion addresses have no subdomains. Turns on `path_routing`
+    pub tor_mode: bool,
+    /// Onion address published in NIP-11 and on the info pages
+    pub onion_address: Option<String>,
+    
+    /// Development only: let `?scope={geohash}` on `/` pick the scope for
+    /// websockets and info pages, overriding the Host header
+    pub dev_scope_query_param: bool,
+    /// Requests without a Host header (HTTP/1.0, raw sockets) are served as
+    /// root or refused
+    pub missing_host_policy: MissingHostPolicy,
+    /// Private deployments: serve every connection and info page as this
+    /// geohash (or "root"), whatever the Host header says. Event `g` tags
+    /// are still routed relative to it
+    pub forced_scope: Option<String>,
+    /// Info page requests allowed per client IP per minute (0 for no limit).
+    /// Websocket upgrades, NIP-11 documents and `/health` are never limited
+    pub info_page_requests_per_minute: u32,
+    /// MaxMind City database; when set the root page suggests the
+    /// visitor's cell from their IP address
+    pub geoip_database: Option<String>,
+    /// Precision of suggested cells (the nearest allowed one if
+    /// `allowed_precisions` is set)
+    pub geoip_precision: usize,
+    
+    // Geohash precision bounds for coordinate resolution
+    pub min_geohash_precision: usize,
+    pub max_geohash_precision: usize,
+    /// Cell precisions served (empty serves all); subdomains of other
+    /// lengths are invalid scopes
+    pub allowed_precisions: Vec<usize>,
+    /// Whether g tags at other precisions are rejected or adjusted
+    pub tag_precision_mode: TagPrecisionMode,
+    /// Finest cell precision each listed kind may target, for kinds that
+    /// give away where someone is (e.g. user statuses)
+    pub kind_precision_caps: BTreeMap<u16, usize>,
+    /// Whether events over their kind's cap are rejected or moved up
+    pub precision_cap_policy: PrecisionCapPolicy,
+    /// Resolve `latlon:` values in root REQs' `#g` filters to cells
+    pub geo_filter_extension: bool,
     
     // Monitoring
     pub metrics_enabled: bool,
     pub metrics_port: u16,
+    
+    // Audit log of processed events
+    /// Directory for the JSONL audit files; auditing is off when unset
+    pub audit_log_dir: Option<String>,
+    /// Size at which the current audit file is rotated (0 never rotates)
+    pub audit_log_max_bytes: u64,
+    pub audit_fsync: AuditFsync,
+    
+    /// Receivers notified of newly stored events
+    pub webhooks: Vec<WebhookConfig>,
+    /// Delivery attempts per event before it is dead-lettered
+    pub webhook_max_attempts: u32,
+    
+    /// Receives the daily usage report as JSON
+    pub usage_report_webhook: Option<String>,
+    /// Signs usage report POSTs like event webhooks
+    pub usage_report_secret: Option<String>,
+    /// Publishes the daily usage report into root as a kind 30078 event
+    pub usage_report_publish: bool,
+    /// Seconds after UTC midnight the daily usage report is made
+    pub usage_report_at: u64,
+    
+    /// Store a relay-signed receipt next to every accepted event
+    pub receipts_enabled: bool,
+    /// Kind of the receipts; an ephemeral kind only reaches live listeners
+    pub receipt_kind: u16,
+    /// Receipts issued per author per minute (0 for no limit)
+    pub receipts_per_minute: u32,
+    
+    /// Hours between status notes posted into active cells (0 disables)
+    pub status_notes_interval_hours: u64,
+    /// Kind of the status notes
+    pub status_notes_kind: u16,
+    /// Content of each note; `{cell}`, `{events}`, `{people}` and
+    /// `{stored}` are filled in from the cell's stats
+    pub status_notes_template: String,
+    /// Events in the last 24 hours a cell needs to get a note
+    pub status_notes_min_events: usize,
+    /// Cells posted to per cycle, the busiest first
+    pub status_notes_max_cells: usize,
+    
+    /// `mqtt://` or `mqtts://` broker the MQTT bridge publishes to
+    pub mqtt_broker_url: Option<String>,
+    pub mqtt_username: Option<String>,
+    pub mqtt_password: Option<String>,
+    pub mqtt_client_id: String,
+    /// Topic for each event; `{scope}` and `{kind}` are filled in
+    pub mqtt_topic_template: String,
+    /// Scope labels to bridge, a trailing `*` matching by prefix (empty = all)
+    pub mqtt_scopes: Vec<String>,
+    /// Kinds to bridge (empty = all)
+    pub mqtt_kinds: Vec<u16>,
+    /// 0 (at most once) or 1 (at least once)
+    pub mqtt_qos: u8,
+    
+    /// Stored events replayed when an SSE feed connects
+    pub feed_replay_events: usize,
+    /// Open SSE feeds allowed per client IP (0 = unlimited)
+    pub feed_max_connections_per_ip: usize,
+    
+    // Leader-follower replication
+    /// Bearer token followers present; the replication stream is only
+    /// served when set
+    pub replication_token: Option<String>,
+    /// Leader's HTTP base URL; when set this relay is a read-only follower
+    pub replicate_from: Option<String>,
+    /// SOCKS5 proxy for connections to the leader, e.g.
+    /// `socks5h://127.0.0.1:9050` to reach an onion leader over Tor
+    pub socks_proxy: Option<String>,
+    
+    // Branding and operator info (info page and NIP-11)
+    pub relay_name: Option<String>,
+    pub relay_description: Option<String>,
+    pub relay_icon_url: Option<String>,
+    pub relay_banner_url: Option<String>,
+    pub operator_contact: Option<String>,
+    /// Operator pubkey, normalized to hex at load time
+    pub operator_pubkey: Option<String>,
+    /// Bearer token for admin API routes; admin routes are disabled without one
+    pub admin_token: Option<String>,
+    
+    /// Start in maintenance mode (refusing writes) unless toggled since
+    pub maintenance: bool,
+    /// Message EVENTs are refused with during maintenance
+    pub maintenance_message: String,
+    /// Start refusing EVENTs until an admin clears it (also `serve --read-only`)
+    pub read_only: bool,
+    
+    // NIP-05 identifiers (/.well-known/nostr.json)
+    /// Names and their pubkeys, normalized to hex at load time
+    pub nip05_names: BTreeMap<String, String>,
+    /// Relay hints per name
+    pub nip05_relays: BTreeMap<String, Vec<String>>,
+    /// Name the relay's own pubkey is published under
+    pub nip05_relay_name: String,
+    pub nip05_subdomains: Nip05SubdomainPolicy,
+    
+    // Link previews (og:image for geohash pages)
+    pub preview_enabled: bool,
+    /// Tile server URL template with {z}/{x}/{y} placeholders
+    pub preview_tile_url: String,
+    /// Where rendered previews are cached (defaults to DATABASE_PATH/previews)
+    pub preview_cache_dir: Option<String>,
+    /// Maximum number of uncached previews rendered per minute
+    pub preview_renders_per_minute: u32,
+    
+    /// Greet new connections with a NOTICE describing the scope's rules
+    pub welcome_notice: bool,
+    
+    /// Serve /feed.json and /feed.atom on geohash subdomains
+    pub syndication_feeds: bool,
+    
+    // Self-published discovery events (kind 0 / kind 10002)
+    pub self_publish: bool,
+    /// Also publish a kind 0 describing each geohash cell that has events
+    pub self_publish_cells: bool,
+    
+    // Stats API
+    /// How often the per-scope stats aggregates are recomputed
+    pub stats_interval_secs: u64,
+    /// Distinct authors a cell needs in the window to be listed as trending
+    pub trending_min_pubkeys: usize,
+    /// Stored events a child cell needs to appear in a cell's
+    /// "activity in this area" panel and `children_activity` listing
+    pub children_activity_min_events: usize,
 }
 
 impl Default for RelayConfig {
@@ -32,21 +815,192 @@ impl Default for RelayConfig {
         Self {
             host: "127.0.0.1".to_string(),
             port: 8080,
+            unix_socket: None,
+            unix_socket_mode: 0o660,
+            unix_socket_owner: None,
             relay_url: "ws://localhost:8080".to_string(),
+            base_domain: None,
+            alternate_base_domains: Vec::new(),
+            storage_backend: StorageBackend::Lmdb,
+            memory_events_per_scope: 500,
             database_path: "./data".to_string(),
+            lmdb_map_size: 10 * 1024 * 1024 * 1024, // 10GB
+            storage_read_only_percent: 0,
+            disk_watermark_percent: 95,
+            storage_error_threshold: 5,
+            storage_error_window_secs: 60,
+            preload_scopes: PreloadScopes::None,
+            scope_idle_secs: 3600,
+            max_events_per_scope: 0,
+            max_bytes_per_scope: 0,
+            quota_policy: QuotaPolicy::default(),
+            archive_endpoint: None,
+            archive_bucket: None,
+            archive_prefix: String::new(),
+            archive_region: "us-east-1".to_string(),
+            archive_access_key_id: None,
+            archive_secret_access_key: None,
+            quota_refresh_secs: 300,
             max_event_size: 128 * 1024, // 128KB
             max_subscriptions_per_connection: 20,
+            max_concurrent_queries_per_connection: 2,
+            query_cache_size: 0,
+            query_cache_ttl_secs: 10,
+            count_cache_size: 0,
+            count_cache_ttl_secs: 30,
             max_filters_per_subscription: 10,
             max_limit_per_filter: 5000,
+            default_filter_limit: 500,
+            default_filter_limit_above_events: 10_000,
+            max_geohashes_per_filter: 16,
+            max_geohash_scopes_per_subscription: 64,
+            max_connections: 10_000,
+            reserved_connections: 16,
+            trusted_proxies: Vec::new(),
+            ip_allowlist: Vec::new(),
+            ip_denylist: Vec::new(),
+            max_outbound_messages: 1000,
+            max_outbound_bytes: 4 * 1024 * 1024, // 4MB
             events_per_minute: 30,  // 0.5 per second - reasonable for normal chat
+            precision_events_per_minute: BTreeMap::new(),
+            scope_events_per_minute: HashMap::new(),
+            authed_events_per_minute: 0,
+            max_cells_per_ip_per_hour: 0,
+            wot_seed_pubkeys: Vec::new(),
+            wot_depth: 1,
+            wot_bootstrap_relays: Vec::new(),
+            wot_refresh_secs: 3600,
+            stats_command: false,
+            stats_command_kind: 21059,
+            stats_command_interval_secs: 10,
+            max_p_tags_per_event: 50,
+            max_tag_entries_per_letter: 0,
+            kind_tag_limits: HashMap::from([(3, 0), (10002, 0)]),
+            max_geohash_tags_per_event: 4,
+            pow_min_difficulty: 0,
+            pow_max_difficulty: 0,
+            pow_threshold_per_minute: 0,
+            pow_interval_secs: 60,
+            duplicate_window_secs: 0,
+            duplicate_window_events: 1000,
+            duplicate_max_matches: 2,
+            duplicate_max_pubkeys: 2,
+            duplicate_exempt_kinds: vec![7],
+            blocklist: BlocklistConfig::default(),
             enable_nip40_expiration: true,
+            enable_logger_middleware: true,
+            enable_error_handling: true,
+            enable_rate_limit: true,
+            log_summary_interval_secs: 10,
+            dm_policy: DmPolicy::default(),
+            global_kinds: DEFAULT_GLOBAL_KINDS.to_vec(),
+            root_allowed_kinds: None,
+            geohash_allowed_kinds: None,
+            geohash_profile: GeohashProfile::default(),
+            geohash_retention_secs: 0,
+            geohash_max_ttl_days: 0,
+            geohash_ttl_mode: TtlMode::default(),
+            geohash_ttl_exempt_kinds: Vec::new(),
+            default_expiration_secs: 0,
+            scope_default_expiration_secs: BTreeMap::new(),
+            vanish_block_secs: 0,
+            deletion_mode: DeletionMode::default(),
+            root_write_policy: WritePolicy::default(),
+            root_write_auth: false,
+            geohash_write_auth: false,
+            geohash_read_auth: false,
+            cell_write_policy: WritePolicy::default(),
+            payments_url: None,
+            admission_fee_msats: None,
+            path_routing: false,
+            tor_mode: false,
+            onion_address: None,
+            dev_scope_query_param: false,
+            missing_host_policy: MissingHostPolicy::default(),
+            forced_scope: None,
+            info_page_requests_per_minute: 60,
+            geoip_database: None,
+            geoip_precision: DEFAULT_RESOLVE_PRECISION,
+            min_geohash_precision: 1,
+            max_geohash_precision: MAX_GEOHASH_LENGTH,
+            allowed_precisions: Vec::new(),
+            tag_precision_mode: TagPrecisionMode::default(),
+            kind_precision_caps: BTreeMap::new(),
+            precision_cap_policy: PrecisionCapPolicy::default(),
+            geo_filter_extension: false,
             metrics_enabled: true,
             metrics_port: 9090,
+            audit_log_dir: None,
+            audit_log_max_bytes: 100 * 1024 * 1024,
+            audit_fsync: AuditFsync::default(),
+            webhooks: Vec::new(),
+            webhook_max_attempts: 5,
+            usage_report_webhook: None,
+            usage_report_secret: None,
+            usage_report_publish: false,
+            usage_report_at: 0,
+            receipts_enabled: false,
+            receipt_kind: DEFAULT_RECEIPT_KIND,
+            receipts_per_minute: 60,
+            status_notes_interval_hours: 0,
+            status_notes_kind: 1,
+            status_notes_template: DEFAULT_STATUS_TEMPLATE.to_string(),
+            status_notes_min_events: 10,
+            status_notes_max_cells: 50,
+            feed_replay_events: 20,
+            feed_max_connections_per_ip: 4,
+            mqtt_broker_url: None,
+            mqtt_username: None,
+            mqtt_password: None,
+            mqtt_client_id: "geohashed-relay".to_string(),
+            mqtt_topic_template: "geo/{scope}/{kind}".to_string(),
+            mqtt_scopes: Vec::new(),
+            mqtt_kinds: Vec::new(),
+            mqtt_qos: 0,
+            replication_token: None,
+            replicate_from: None,
+            socks_proxy: None,
+            relay_name: None,
+            relay_description: None,
+            relay_icon_url: None,
+            relay_banner_url: None,
+            operator_contact: None,
+            operator_pubkey: None,
+            admin_token: None,
+            maintenance: false,
+            maintenance_message: DEFAULT_MAINTENANCE_MESSAGE.to_string(),
+            read_only: false,
+            nip05_names: BTreeMap::new(),
+            nip05_relays: BTreeMap::new(),
+            nip05_relay_name: DEFAULT_NIP05_RELAY_NAME.to_string(),
+            nip05_subdomains: Nip05SubdomainPolicy::default(),
+            preview_enabled: true,
+            preview_tile_url: "https://tile.openstreetmap.org/{z}/{x}/{y}.png".to_string(),
+            preview_cache_dir: None,
+            preview_renders_per_minute: 10,
+            welcome_notice: true,
+            syndication_feeds: true,
+            self_publish: true,
+            self_publish_cells: false,
+            stats_interval_secs: 60,
+            trending_min_pubkeys: 3,
+            children_activity_min_events: 5,
         }
     }
 }
 
 impl RelayConfig {
+    /// Returns a fingerprint of the current configuration
+    ///
+    /// Used to invalidate anything derived from config (e.g. cached info
+    /// pages and their ETags) when the relay is restarted with new settings.
+    pub fn revision(&self) -> u64 {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        serde_json::to_string(self).unwrap_or_default().hash(&mut hasher);
+        hasher.finish()
+    }
+
     pub fn from_env() -> anyhow::Result<Self> {
         let mut config = Self::default();
         
@@ -58,22 +1012,1569 @@ impl RelayConfig {
             config.port = port.parse()?;
         }
         
+        if let Ok(listen) = std::env::var("LISTEN") {
+            config.unix_socket = parse_listen(&listen)?;
+        }
+        
+        if let Ok(mode) = std::env::var("UNIX_SOCKET_MODE") {
+            config.unix_socket_mode = parse_socket_mode(&mode)?;
+        }
+        
+        if let Ok(owner) = std::env::var("UNIX_SOCKET_OWNER") {
+            config.unix_socket_owner = parse_socket_owner(&owner)?;
+        }
+        
         if let Ok(url) = std::env::var("RELAY_URL") {
             config.relay_url = url;
         }
         
+        if let Some(domain) = env_opt("BASE_DOMAIN") {
+            let domain = domain.trim_matches('.').to_lowercase();
+            if domain.is_empty() {
+                anyhow::bail!("BASE_DOMAIN must be a domain name");
+            }
+            config.base_domain = Some(domain);
+        }
+        
+        if let Some(domains) = env_opt("ALTERNATE_BASE_DOMAINS") {
+            config.alternate_base_domains = domains
+                .split(',')
+                .map(|domain| domain.trim().trim_matches('.').to_lowercase())
+                .filter(|domain| !domain.is_empty())
+                .collect();
+        }
+        
+        if let Ok(backend) = std::env::var("STORAGE_BACKEND") {
+            config.storage_backend = backend.parse()?;
+        }
+        
+        if let Ok(capacity) = std::env::var("MEMORY_EVENTS_PER_SCOPE") {
+            config.memory_events_per_scope = capacity.parse::<usize>()?.max(1);
+        }
+        
         if let Ok(path) = std::env::var("DATABASE_PATH") {
             config.database_path = path;
         }
         
+        if let Ok(size) = std::env::var("LMDB_MAP_SIZE") {
+            config.lmdb_map_size = size.parse()?;
+        }
+        
+        if let Ok(percent) = std::env::var("STORAGE_READ_ONLY_PERCENT") {
+            config.storage_read_only_percent = percent.parse()?;
+            if config.storage_read_only_percent > 100 {
+                anyhow::bail!("STORAGE_READ_ONLY_PERCENT must be between 0 and 100");
+            }
+        }
+        
+        if let Ok(percent) = std::env::var("DISK_WATERMARK_PERCENT") {
+            config.disk_watermark_percent = percent.parse()?;
+            if config.disk_watermark_percent > 100 {
+                anyhow::bail!("DISK_WATERMARK_PERCENT must be between 0 and 100");
+            }
+        }
+        
+        if let Ok(threshold) = std::env::var("STORAGE_ERROR_THRESHOLD") {
+            config.storage_error_threshold = threshold.parse()?;
+        }
+        
+        if let Ok(secs) = std::env::var("STORAGE_ERROR_WINDOW_SECS") {
+            config.storage_error_window_secs = secs.parse()?;
+        }
+        
+        if let Ok(scopes) = std::env::var("PRELOAD_SCOPES") {
+            config.preload_scopes = scopes.parse()?;
+        }
+        
+        if let Ok(secs) = std::env::var("SCOPE_IDLE_SECS") {
+            config.scope_idle_secs = secs.parse()?;
+            if config.scope_idle_secs > 0 && config.scope_idle_secs < 60 {
+                anyhow::bail!("SCOPE_IDLE_SECS must be 0 or at least 60 (rate-limit buckets refill over a minute)");
+            }
+        }
+        
+        if let Ok(max) = std::env::var("MAX_EVENTS_PER_SCOPE") {
+            config.max_events_per_scope = max.parse()?;
+        }
+        
+        if let Ok(max) = std::env::var("MAX_BYTES_PER_SCOPE") {
+            config.max_bytes_per_scope = max.parse()?;
+        }
+        
+        if let Ok(policy) = std::env::var("QUOTA_POLICY") {
+            config.quota_policy = policy.parse()?;
+        }
+        
+        if let Ok(secs) = std::env::var("QUOTA_REFRESH_SECS") {
+            config.quota_refresh_secs = secs.parse()?;
+        }
+        
+        config.archive_endpoint = env_opt("ARCHIVE_ENDPOINT");
+        config.archive_bucket = env_opt("ARCHIVE_BUCKET");
+        if let Some(prefix) = env_opt("ARCHIVE_PREFIX") {
+            config.archive_prefix = prefix;
+        }
+        if let Some(region) = env_opt("ARCHIVE_REGION") {
+            config.archive_region = region;
+        }
+        config.archive_access_key_id = env_opt("ARCHIVE_ACCESS_KEY_ID");
+        config.archive_secret_access_key = env_opt("ARCHIVE_SECRET_ACCESS_KEY");
+        if config.quota_policy == QuotaPolicy::Archive
+            && (config.archive_endpoint.is_none() || config.archive_bucket.is_none())
+        {
+            anyhow::bail!("ARCHIVE_ENDPOINT and ARCHIVE_BUCKET are required when QUOTA_POLICY is archive");
+        }
+        
         if let Ok(size) = std::env::var("MAX_EVENT_SIZE") {
             config.max_event_size = size.parse()?;
         }
         
+        if let Ok(max) = std::env::var("MAX_SUBSCRIPTIONS_PER_CONNECTION") {
+            config.max_subscriptions_per_connection = max.parse()?;
+        }
+        
+        if let Ok(max) = std::env::var("MAX_CONCURRENT_QUERIES_PER_CONNECTION") {
+            config.max_concurrent_queries_per_connection = max.parse::<usize>()?.max(1);
+        }
+        
+        if let Ok(size) = std::env::var("QUERY_CACHE_SIZE") {
+            config.query_cache_size = size.parse()?;
+        }
+        
+        if let Ok(secs) = std::env::var("QUERY_CACHE_TTL_SECS") {
+            config.query_cache_ttl_secs = secs.parse()?;
+        }
+        
+        if let Ok(size) = std::env::var("COUNT_CACHE_SIZE") {
+            config.count_cache_size = size.parse()?;
+        }
+        
+        if let Ok(secs) = std::env::var("COUNT_CACHE_TTL_SECS") {
+            config.count_cache_ttl_secs = secs.parse()?;
+        }
+        
+        if let Ok(max) = std::env::var("MAX_LIMIT_PER_FILTER") {
+            config.max_limit_per_filter = max.parse()?;
+        }
+        
+        if let Ok(limit) = std::env::var("DEFAULT_FILTER_LIMIT") {
+            config.default_filter_limit = limit.parse()?;
+        }
+        
+        if let Ok(events) = std::env::var("DEFAULT_FILTER_LIMIT_ABOVE_EVENTS") {
+            config.default_filter_limit_above_events = events.parse()?;
+        }
+        
+        if let Ok(max) = std::env::var("MAX_GEOHASHES_PER_FILTER") {
+            config.max_geohashes_per_filter = max.parse()?;
+        }
+        
+        if let Ok(max) = std::env::var("MAX_GEOHASH_SCOPES_PER_SUBSCRIPTION") {
+            config.max_geohash_scopes_per_subscription = max.parse()?;
+        }
+        
+        if let Ok(max) = std::env::var("MAX_CONNECTIONS") {
+            config.max_connections = max.parse()?;
+        }
+        
+        if let Ok(reserved) = std::env::var("RESERVED_CONNECTIONS") {
+            config.reserved_connections = reserved.parse()?;
+        }
+        
+        if let Ok(proxies) = std::env::var("TRUSTED_PROXIES") {
+            config.trusted_proxies = proxies
+                .split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .context("invalid TRUSTED_PROXIES")?;
+        }
+        
+        if let Some(list) = env_opt("IP_ALLOWLIST") {
+            config.ip_allowlist = parse_cidr_list(&list).context("invalid IP_ALLOWLIST")?;
+        }
+        
+        if let Some(list) = env_opt("IP_DENYLIST") {
+            config.ip_denylist = parse_cidr_list(&list).context("invalid IP_DENYLIST")?;
+        }
+        
+        if let Ok(max) = std::env::var("MAX_OUTBOUND_MESSAGES") {
+            config.max_outbound_messages = max.parse()?;
+        }
+        
+        if let Ok(max) = std::env::var("MAX_OUTBOUND_BYTES") {
+            config.max_outbound_bytes = max.parse()?;
+        }
+        
         if let Ok(rate) = std::env::var("EVENTS_PER_MINUTE") {
             config.events_per_minute = rate.parse()?;
         }
         
-        Ok(config)
+        if let Ok(limits) = std::env::var("PRECISION_EVENTS_PER_MINUTE") {
+            config.precision_events_per_minute = parse_limits::<usize, BTreeMap<_, _>>(&limits)
+                .context("invalid PRECISION_EVENTS_PER_MINUTE")?;
+        }
+        
+        if let Ok(limits) = std::env::var("SCOPE_EVENTS_PER_MINUTE") {
+            config.scope_events_per_minute = parse_limits::<String, HashMap<_, _>>(&limits.to_lowercase())
+                .context("invalid SCOPE_EVENTS_PER_MINUTE")?;
+        }
+        
+        if let Ok(rate) = std::env::var("AUTHED_EVENTS_PER_MINUTE") {
+            config.authed_events_per_minute = rate.parse()?;
+        }
+        
+        if let Ok(max) = std::env::var("MAX_CELLS_PER_IP_PER_HOUR") {
+            config.max_cells_per_ip_per_hour = max.parse()?;
+        }
+        
+        if let Some(seeds) = env_opt("WOT_SEED_PUBKEYS") {
+            config.wot_seed_pubkeys = seeds
+                .split(',')
+                .map(str::trim)
+                .filter(|seed| !seed.is_empty())
+                .map(|seed| parse_pubkey_flexible(seed).map(|pubkey| pubkey.to_hex()))
+                .collect::<anyhow::Result<_>>()
+                .context("invalid WOT_SEED_PUBKEYS")?;
+        }
+        
+        if let Ok(depth) = std::env::var("WOT_DEPTH") {
+            config.wot_depth = depth.parse()?;
+            if !(1..=2).contains(&config.wot_depth) {
+                anyhow::bail!("WOT_DEPTH must be 1 or 2");
+            }
+        }
+        
+        if let Some(relays) = env_opt("WOT_BOOTSTRAP_RELAYS") {
+            config.wot_bootstrap_relays = relays
+                .split(',')
+                .map(str::trim)
+                .filter(|relay| !relay.is_empty())
+                .map(|relay| {
+                    url::Url::parse(relay).with_context(|| format!("invalid WOT_BOOTSTRAP_RELAYS entry '{}'", relay))?;
+                    Ok(relay.to_string())
+                })
+                .collect::<anyhow::Result<_>>()?;
+        }
+        
+        if let Ok(secs) = std::env::var("WOT_REFRESH_SECS") {
+            config.wot_refresh_secs = secs.parse()?;
+        }
+        
+        if let Ok(enabled) = std::env::var("STATS_COMMAND") {
+            config.stats_command = enabled.parse()?;
+        }
+        
+        if let Ok(kind) = std::env::var("STATS_COMMAND_KIND") {
+            config.stats_command_kind = kind.parse()?;
+        }
+        
+        if let Ok(secs) = std::env::var("STATS_COMMAND_INTERVAL_SECS") {
+            config.stats_command_interval_secs = secs.parse()?;
+        }
+        
+        if let Ok(max) = std::env::var("MAX_P_TAGS_PER_EVENT") {
+            config.max_p_tags_per_event = max.parse()?;
+        }
+        
+        if let Ok(max) = std::env::var("MAX_TAG_ENTRIES_PER_LETTER") {
+            config.max_tag_entries_per_letter = max.parse()?;
+        }
+        
+        if let Ok(max) = std::env::var("MAX_GEOHASH_TAGS_PER_EVENT") {
+            config.max_geohash_tags_per_event = max.parse()?;
+        }
+        
+        if let Ok(limits) = std::env::var("KIND_TAG_LIMITS") {
+            config.kind_tag_limits = parse_limits::<u16, HashMap<_, _>>(&limits)
+                .context("invalid KIND_TAG_LIMITS")?;
+        }
+        
+        if let Ok(difficulty) = std::env::var("POW_MIN_DIFFICULTY") {
+            config.pow_min_difficulty = difficulty.parse()?;
+        }
+        
+        if let Ok(difficulty) = std::env::var("POW_MAX_DIFFICULTY") {
+            config.pow_max_difficulty = difficulty.parse()?;
+        }
+        
+        if let Ok(rate) = std::env::var("POW_THRESHOLD_PER_MINUTE") {
+            config.pow_threshold_per_minute = rate.parse()?;
+        }
+        
+        if let Ok(secs) = std::env::var("POW_INTERVAL_SECS") {
+            config.pow_interval_secs = secs.parse::<u64>()?.max(1);
+        }
+        
+        if let Ok(secs) = std::env::var("DUPLICATE_WINDOW_SECS") {
+            config.duplicate_window_secs = secs.parse()?;
+        }
+        
+        if let Ok(events) = std::env::var("DUPLICATE_WINDOW_EVENTS") {
+            config.duplicate_window_events = events.parse()?;
+        }
+        
+        if let Ok(matches) = std::env::var("DUPLICATE_MAX_MATCHES") {
+            config.duplicate_max_matches = matches.parse()?;
+        }
+        
+        if let Ok(pubkeys) = std::env::var("DUPLICATE_MAX_PUBKEYS") {
+            config.duplicate_max_pubkeys = pubkeys.parse()?;
+        }
+        
+        if let Ok(kinds) = std::env::var("DUPLICATE_EXEMPT_KINDS") {
+            config.duplicate_exempt_kinds = parse_kinds(&kinds).context("invalid DUPLICATE_EXEMPT_KINDS")?;
+        }
+        
+        if let Some(blocklist) = env_opt("BLOCKLIST") {
+            config.blocklist = serde_json::from_str(&blocklist).context("invalid BLOCKLIST")?;
+        }
+        
+        if let Ok(enabled) = std::env::var("ENABLE_NIP40_EXPIRATION") {
+            config.enable_nip40_expiration = enabled.parse()?;
+        }
+        
+        if let Ok(enabled) = std::env::var("ENABLE_LOGGER_MIDDLEWARE") {
+            config.enable_logger_middleware = enabled.parse()?;
+        }
+        
+        if let Ok(enabled) = std::env::var("ENABLE_ERROR_HANDLING") {
+            config.enable_error_handling = enabled.parse()?;
+        }
+        
+        if let Ok(enabled) = std::env::var("ENABLE_RATE_LIMIT") {
+            config.enable_rate_limit = enabled.parse()?;
+        }
+        
+        if let Ok(secs) = std::env::var("LOG_SUMMARY_INTERVAL_SECS") {
+            config.log_summary_interval_secs = secs.parse()?;
+        }
+        
+        if let Ok(policy) = std::env::var("DM_POLICY") {
+            config.dm_policy = policy.parse()?;
+        }
+        
+        if let Ok(kinds) = std::env::var("GLOBAL_KINDS") {
+            config.global_kinds = parse_kinds(&kinds).context("invalid GLOBAL_KINDS")?;
+        }
+        
+        if let Some(kinds) = env_opt("ROOT_ALLOWED_KINDS") {
+            config.root_allowed_kinds = parse_allowed_kinds(&kinds).context("invalid ROOT_ALLOWED_KINDS")?;
+        }
+        
+        if let Some(kinds) = env_opt("GEOHASH_ALLOWED_KINDS") {
+            config.geohash_allowed_kinds = parse_allowed_kinds(&kinds).context("invalid GEOHASH_ALLOWED_KINDS")?;
+        }
+        
+        if let Ok(secs) = std::env::var("GEOHASH_RETENTION_SECS") {
+            config.geohash_retention_secs = secs.parse()?;
+        }
+        
+        if let Ok(days) = std::env::var("GEOHASH_MAX_TTL_DAYS") {
+            config.geohash_max_ttl_days = days.parse()?;
+        }
+        
+        if let Ok(mode) = std::env::var("GEOHASH_TTL_MODE") {
+            config.geohash_ttl_mode = mode.parse()?;
+        }
+        
+        if let Some(kinds) = env_opt("GEOHASH_TTL_EXEMPT_KINDS") {
+            config.geohash_ttl_exempt_kinds = parse_kinds(&kinds).context("invalid GEOHASH_TTL_EXEMPT_KINDS")?;
+        }
+        
+        if let Ok(secs) = std::env::var("DEFAULT_EXPIRATION_SECS") {
+            config.default_expiration_secs = secs.parse()?;
+        }
+        
+        if let Ok(overrides) = std::env::var("SCOPE_DEFAULT_EXPIRATION_SECS") {
+            config.scope_default_expiration_secs = parse_limits::<String, Vec<_>>(&overrides.to_lowercase())
+                .context("invalid SCOPE_DEFAULT_EXPIRATION_SECS")?
+                .into_iter()
+                .map(|(prefix, secs)| (prefix, u64::from(secs)))
+                .collect();
+        }
+        
+        if let Ok(secs) = std::env::var("VANISH_BLOCK_SECS") {
+            config.vanish_block_secs = secs.parse()?;
+        }
+        
+        if let Ok(mode) = std::env::var("DELETION_MODE") {
+            config.deletion_mode = mode.parse()?;
+        }
+        
+        // After the granular options, which a preset replaces
+        if let Ok(profile) = std::env::var("GEOHASH_PROFILE") {
+            config.geohash_profile = profile.parse()?;
+        }
+        config.apply_geohash_profile();
+        
+        if let Ok(policy) = std::env::var("ROOT_WRITE_POLICY") {
+            config.root_write_policy = policy.parse()?;
+        }
+        
+        if let Ok(policy) = std::env::var("CELL_WRITE_POLICY") {
+            config.cell_write_policy = policy.parse()?;
+        }
+        
+        config.payments_url = env_opt("PAYMENTS_URL");
+        
+        if let Some(fee) = env_opt("ADMISSION_FEE_MSATS") {
+            config.admission_fee_msats = Some(fee.parse()?);
+        }
+        
+        if let Ok(required) = std::env::var("ROOT_WRITE_AUTH") {
+            config.root_write_auth = required.parse()?;
+        }
+        
+        if let Ok(required) = std::env::var("GEOHASH_WRITE_AUTH") {
+            config.geohash_write_auth = required.parse()?;
+        }
+        
+        if let Ok(required) = std::env::var("GEOHASH_READ_AUTH") {
+            config.geohash_read_auth = required.parse()?;
+        }
+        
+        let paid = config.root_write_policy == WritePolicy::Paid || config.cell_write_policy == WritePolicy::Paid;
+        if paid && config.payments_url.is_none() {
+            anyhow::bail!("PAYMENTS_URL is required when ROOT_WRITE_POLICY or CELL_WRITE_POLICY is paid");
+        }
+        
+        if let Ok(enabled) = std::env::var("PATH_ROUTING") {
+            config.path_routing = enabled.parse()?;
+        }
+        
+        if let Ok(enabled) = std::env::var("TOR_MODE") {
+            config.tor_mode = enabled.parse()?;
+            config.path_routing |= config.tor_mode;
+        }
+        
+        if let Some(address) = env_opt("ONION_ADDRESS") {
+            config.onion_address = Some(parse_onion_address(&address)?);
+        }
+        
+        if let Ok(enabled) = std::env::var("DEV_SCOPE_QUERY_PARAM") {
+            config.dev_scope_query_param = enabled.parse()?;
+        }
+        
+        if let Ok(policy) = std::env::var("MISSING_HOST_POLICY") {
+            config.missing_host_policy = policy.parse()?;
+        }
+        
+        if let Some(scope) = env_opt("FORCED_SCOPE") {
+            config.forced_scope = Some(parse_forced_scope(&scope)?);
+        }
+        
+        if let Ok(rate) = std::env::var("INFO_PAGE_REQUESTS_PER_MINUTE") {
+            config.info_page_requests_per_minute = rate.parse()?;
+        }
+        
+        config.geoip_database = env_opt("GEOIP_DATABASE");
+        if let Ok(precision) = std::env::var("GEOIP_PRECISION") {
+            config.geoip_precision = precision.parse()?;
+            if config.geoip_precision == 0 || config.geoip_precision > MAX_GEOHASH_LENGTH {
+                anyhow::bail!("GEOIP_PRECISION must be between 1 and {}", MAX_GEOHASH_LENGTH);
+            }
+        }
+        
+        if let Ok(precision) = std::env::var("MIN_GEOHASH_PRECISION") {
+            config.min_geohash_precision = precision.parse()?;
+        }
+        
+        if let Ok(precision) = std::env::var("MAX_GEOHASH_PRECISION") {
+            config.max_geohash_precision = precision.parse()?;
+        }
+        
+        if let Some(precisions) = env_opt("ALLOWED_PRECISIONS") {
+            config.allowed_precisions = precisions
+                .split(',')
+                .map(|p| p.trim().parse::<usize>())
+                .collect::<Result<_, _>>()
+                .context("invalid ALLOWED_PRECISIONS")?;
+            if config.allowed_precisions.iter().any(|p| *p == 0 || *p > MAX_GEOHASH_LENGTH) {
+                anyhow::bail!("ALLOWED_PRECISIONS must be between 1 and {}", MAX_GEOHASH_LENGTH);
+            }
+            config.allowed_precisions.sort_unstable();
+            config.allowed_precisions.dedup();
+        }
+        
+        if let Ok(mode) = std::env::var("TAG_PRECISION_MODE") {
+            config.tag_precision_mode = mode.parse()?;
+        }
+        
+        if let Ok(caps) = std::env::var("KIND_PRECISION_CAPS") {
+            let caps: BTreeMap<u16, u32> = parse_limits(&caps).context("invalid KIND_PRECISION_CAPS")?;
+            config.kind_precision_caps = caps.into_iter().map(|(kind, precision)| (kind, precision as usize)).collect();
+            if config.kind_precision_caps.values().any(|p| *p == 0 || *p > MAX_GEOHASH_LENGTH) {
+                anyhow::bail!("KIND_PRECISION_CAPS precisions must be between 1 and {}", MAX_GEOHASH_LENGTH);
+            }
+        }
+        
+        if let Ok(policy) = std::env::var("PRECISION_CAP_POLICY") {
+            config.precision_cap_policy = policy.parse()?;
+        }
+        
+        if let Ok(enabled) = std::env::var("GEO_FILTER_EXTENSION") {
+            config.geo_filter_extension = enabled.parse()?;
+        }
+        
+        if config.min_geohash_precision == 0
+            || config.min_geohash_precision > config.max_geohash_precision
+            || config.max_geohash_precision > MAX_GEOHASH_LENGTH
+        {
+            anyhow::bail!(
+                "geohash precision bounds must satisfy 1 <= MIN_GEOHASH_PRECISION <= MAX_GEOHASH_PRECISION <= {}",
+                MAX_GEOHASH_LENGTH
+            );
+        }
+        
+        config.relay_name = env_opt("RELAY_NAME");
+        config.relay_description = env_opt("RELAY_DESCRIPTION");
+        config.relay_icon_url = env_opt("RELAY_ICON_URL");
+        config.relay_banner_url = env_opt("RELAY_BANNER_URL");
+        config.operator_contact = env_opt("OPERATOR_CONTACT");
+        
+        if let Some(pubkey) = env_opt("OPERATOR_PUBKEY") {
+            let pubkey = parse_pubkey_flexible(&pubkey).context("invalid OPERATOR_PUBKEY")?;
+            config.operator_pubkey = Some(pubkey.to_hex());
+        }
+        
+        config.admin_token = env_opt("ADMIN_TOKEN");
+        
+        if let Ok(enabled) = std::env::var("MAINTENANCE") {
+            config.maintenance = enabled.parse()?;
+        }
+        
+        if let Some(message) = env_opt("MAINTENANCE_MESSAGE") {
+            config.maintenance_message = message;
+        }
+        
+        if let Ok(enabled) = std::env::var("READ_ONLY") {
+            config.read_only = enabled.parse()?;
+        }
+        
+        if let Some(names) = env_opt("NIP05_NAMES") {
+            config.nip05_names = parse_nip05_names(&names).context("invalid NIP05_NAMES")?;
+        }
+        
+        if let Some(relays) = env_opt("NIP05_RELAYS") {
+            config.nip05_relays = parse_nip05_relays(&relays).context("invalid NIP05_RELAYS")?;
+        }
+        
+        if let Some(name) = env_opt("NIP05_RELAY_NAME") {
+            let name = name.to_lowercase();
+            if !is_valid_name(&name) {
+                anyhow::bail!("invalid NIP05_RELAY_NAME '{}'", name);
+            }
+            config.nip05_relay_name = name;
+        }
+        
+        if let Ok(policy) = std::env::var("NIP05_SUBDOMAINS") {
+            config.nip05_subdomains = policy.parse()?;
+        }
+        
+        if let Ok(enabled) = std::env::var("PREVIEW_ENABLED") {
+            config.preview_enabled = enabled.parse()?;
+        }
+        
+        if let Some(url) = env_opt("PREVIEW_TILE_URL") {
+            config.preview_tile_url = url;
+        }
+        
+        config.preview_cache_dir = env_opt("PREVIEW_CACHE_DIR");
+        
+        if let Ok(rate) = std::env::var("PREVIEW_RENDERS_PER_MINUTE") {
+            config.preview_renders_per_minute = rate.parse()?;
+        }
+        
+        if let Ok(enabled) = std::env::var("WELCOME_NOTICE") {
+            config.welcome_notice = enabled.parse()?;
+        }
+        
+        if let Ok(enabled) = std::env::var("SYNDICATION_FEEDS") {
+            config.syndication_feeds = enabled.parse()?;
+        }
+        
+        if let Ok(enabled) = std::env::var("SELF_PUBLISH") {
+            config.self_publish = enabled.parse()?;
+        }
+        
+        if let Ok(enabled) = std::env::var("SELF_PUBLISH_CELLS") {
+            config.self_publish_cells = enabled.parse()?;
+        }
+        
+        if let Ok(secs) = std::env::var("STATS_INTERVAL_SECS") {
+            config.stats_interval_secs = secs.parse()?;
+        }
+        
+        if let Ok(min) = std::env::var("TRENDING_MIN_PUBKEYS") {
+            config.trending_min_pubkeys = min.parse()?;
+        }
+        
+        if let Ok(min) = std::env::var("CHILDREN_ACTIVITY_MIN_EVENTS") {
+            config.children_activity_min_events = min.parse()?;
+        }
+        
+        config.audit_log_dir = env_opt("AUDIT_LOG_DIR");
+        
+        if let Ok(bytes) = std::env::var("AUDIT_LOG_MAX_BYTES") {
+            config.audit_log_max_bytes = bytes.parse()?;
+        }
+        
+        if let Ok(policy) = std::env::var("AUDIT_FSYNC") {
+            config.audit_fsync = policy.parse()?;
+        }
+        
+        if let Some(webhooks) = env_opt("WEBHOOKS") {
+            config.webhooks = serde_json::from_str(&webhooks).context("invalid WEBHOOKS")?;
+            for webhook in &config.webhooks {
+                url::Url::parse(&webhook.url).with_context(|| format!("invalid webhook URL '{}'", webhook.url))?;
+            }
+        }
+        
+        if let Ok(attempts) = std::env::var("WEBHOOK_MAX_ATTEMPTS") {
+            config.webhook_max_attempts = attempts.parse()?;
+        }
+        
+        if let Some(url) = env_opt("USAGE_REPORT_WEBHOOK") {
+            url::Url::parse(&url).with_context(|| format!("invalid USAGE_REPORT_WEBHOOK '{}'", url))?;
+            config.usage_report_webhook = Some(url);
+        }
+        
+        config.usage_report_secret = env_opt("USAGE_REPORT_SECRET");
+        
+        if let Ok(enabled) = std::env::var("USAGE_REPORT_PUBLISH") {
+            config.usage_report_publish = enabled.parse()?;
+        }
+        
+        if let Some(at) = env_opt("USAGE_REPORT_AT") {
+            config.usage_report_at = parse_time_of_day(&at).context("invalid USAGE_REPORT_AT")?;
+        }
+        
+        if let Ok(enabled) = std::env::var("RECEIPTS_ENABLED") {
+            config.receipts_enabled = enabled.parse()?;
+        }
+        
+        if let Ok(kind) = std::env::var("RECEIPT_KIND") {
+            config.receipt_kind = kind.parse()?;
+        }
+        
+        if let Ok(rate) = std::env::var("RECEIPTS_PER_MINUTE") {
+            config.receipts_per_minute = rate.parse()?;
+        }
+        
+        if let Ok(hours) = std::env::var("STATUS_NOTES_INTERVAL_HOURS") {
+            config.status_notes_interval_hours = hours.parse()?;
+        }
+        
+        if let Ok(kind) = std::env::var("STATUS_NOTES_KIND") {
+            config.status_notes_kind = kind.parse()?;
+        }
+        
+        if let Some(template) = env_opt("STATUS_NOTES_TEMPLATE") {
+            config.status_notes_template = template;
+        }
+        
+        if let Ok(min) = std::env::var("STATUS_NOTES_MIN_EVENTS") {
+            config.status_notes_min_events = min.parse()?;
+        }
+        
+        if let Ok(max) = std::env::var("STATUS_NOTES_MAX_CELLS") {
+            config.status_notes_max_cells = max.parse()?;
+        }
+        
+        if let Ok(replay) = std::env::var("FEED_REPLAY_EVENTS") {
+            config.feed_replay_events = replay.parse()?;
+        }
+        
+        if let Ok(max) = std::env::var("FEED_MAX_CONNECTIONS_PER_IP") {
+            config.feed_max_connections_per_ip = max.parse()?;
+        }
+        
+        if let Some(url) = env_opt("MQTT_BROKER_URL") {
+            let parsed = url::Url::parse(&url).with_context(|| format!("invalid MQTT_BROKER_URL '{}'", url))?;
+            if !matches!(parsed.scheme(), "mqtt" | "mqtts") || parsed.host_str().is_none() {
+                anyhow::bail!("MQTT_BROKER_URL must be mqtt://host[:port] or mqtts://host[:port]");
+            }
+            config.mqtt_broker_url = Some(url);
+        }
+        
+        config.mqtt_username = env_opt("MQTT_USERNAME");
+        config.mqtt_password = env_opt("MQTT_PASSWORD");
+        
+        if let Some(client_id) = env_opt("MQTT_CLIENT_ID") {
+            config.mqtt_client_id = client_id;
+        }
+        
+        if let Some(template) = env_opt("MQTT_TOPIC_TEMPLATE") {
+            config.mqtt_topic_template = template;
+        }
+        
+        if let Some(scopes) = env_opt("MQTT_SCOPES") {
+            config.mqtt_scopes = scopes
+                .split(',')
+                .map(|scope| scope.trim().to_lowercase())
+                .filter(|scope| !scope.is_empty())
+                .collect();
+        }
+        
+        if let Some(kinds) = env_opt("MQTT_KINDS") {
+            config.mqtt_kinds = parse_kinds(&kinds).context("invalid MQTT_KINDS")?;
+        }
+        
+        if let Ok(qos) = std::env::var("MQTT_QOS") {
+            config.mqtt_qos = qos.parse()?;
+            if config.mqtt_qos > 1 {
+                anyhow::bail!("MQTT_QOS must be 0 or 1");
+            }
+        }
+        
+        config.replication_token = env_opt("REPLICATION_TOKEN");
+        config.replicate_from = env_opt("REPLICATE_FROM");
+        if let Some(leader) = &config.replicate_from {
+            url::Url::parse(leader).with_context(|| format!("invalid REPLICATE_FROM '{}'", leader))?;
+            if config.replication_token.is_none() {
+                anyhow::bail!("REPLICATION_TOKEN is required when REPLICATE_FROM is set");
+            }
+        }
+        
+        config.socks_proxy = env_opt("SOCKS_PROXY");
+        if let Some(proxy) = &config.socks_proxy {
+            let url = url::Url::parse(proxy).with_context(|| format!("invalid SOCKS_PROXY '{}'", proxy))?;
+            if !matches!(url.scheme(), "socks5" | "socks5h") {
+                anyhow::bail!("invalid SOCKS_PROXY '{}' (expected socks5:// or socks5h://)", proxy);
+            }
+        }
+        
+        Ok(config)
+    }
+    
+    /// Events per minute budget for a scope (`None` for root)
+    ///
+    /// An explicit per-scope override wins, then the limit for the cell's
+    /// precision, then `events_per_minute`.
+    pub fn events_per_minute_for(&self, subdomain: Option<&str>) -> u32 {
+        let label = subdomain.map(str::to_lowercase).unwrap_or_else(|| "root".to_string());
+        if let Some(limit) = self.scope_events_per_minute.get(&label) {
+            return *limit;
+        }
+        subdomain
+            .and_then(|sub| self.precision_events_per_minute.get(&sub.len()))
+            .copied()
+            .unwrap_or(self.events_per_minute)
+    }
+    
+    /// Most tags named `letter` an event of `kind` may carry (0 for no limit)
+    ///
+    /// A per-kind override wins over both general limits.
+    pub fn tag_limit_for(&self, kind: u16, letter: char) -> u32 {
+        if let Some(limit) = self.kind_tag_limits.get(&kind) {
+            return *limit;
+        }
+        if letter == 'p' {
+            self.max_p_tags_per_event
+        } else {
+            self.max_tag_entries_per_letter
+        }
+    }
+    
+    /// Write policy for a scope (`None` for root)
+    pub fn write_policy_for(&self, subdomain: Option<&str>) -> WritePolicy {
+        match subdomain {
+            Some(_) => self.cell_write_policy,
+            None => self.root_write_policy,
+        }
+    }
+    
+    /// Cell TTL in seconds for events of `kind` (`None` when no TTL applies)
+    pub fn geohash_ttl_secs_for(&self, kind: u16) -> Option<u64> {
+        if self.geohash_max_ttl_days == 0 || self.geohash_ttl_exempt_kinds.contains(&kind) {
+            return None;
+        }
+        Some(self.geohash_max_ttl_days.saturating_mul(24 * 60 * 60))
+    }
+    
+    /// Default expiration in seconds for events stored in a scope (`None`
+    /// for root); `None` when events there don't expire by default
+    pub fn default_expiration_secs_for(&self, subdomain: Option<&str>) -> Option<u64> {
+        let label = subdomain.map(str::to_lowercase).unwrap_or_else(|| "root".to_string());
+        let secs = self
+            .scope_default_expiration_secs
+            .iter()
+            .filter(|(prefix, _)| match subdomain {
+                Some(_) => *prefix != "root" && label.starts_with(prefix.as_str()),
+                None => *prefix == "root",
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default_expiration_secs, |(_, secs)| *secs);
+        (secs > 0).then_some(secs)
+    }
+    
+    /// Sets the cell options `geohash_profile` stands for
+    pub fn apply_geohash_profile(&mut self) {
+        match self.geohash_profile {
+            GeohashProfile::Open => {
+                self.geohash_allowed_kinds = None;
+                self.geohash_retention_secs = 0;
+            }
+            GeohashProfile::LocationChat => {
+                self.geohash_allowed_kinds = Some(LOCATION_CHAT_KINDS.to_vec());
+                self.geohash_retention_secs = LOCATION_CHAT_RETENTION_SECS;
+                for kind in DEFAULT_GLOBAL_KINDS {
+                    if !self.global_kinds.contains(&kind) {
+                        self.global_kinds.push(kind);
+                    }
+                }
+            }
+            GeohashProfile::Custom => {}
+        }
+    }
+    
+    /// Whether posting to a scope (`None` for root) needs NIP-42 auth
+    pub fn write_auth_for(&self, subdomain: Option<&str>) -> bool {
+        match subdomain {
+            Some(_) => self.geohash_write_auth,
+            None => self.root_write_auth,
+        }
+    }
+    
+    /// Whether reading a scope (`None` for root) needs NIP-42 auth
+    pub fn read_auth_for(&self, subdomain: Option<&str>) -> bool {
+        subdomain.is_some() && self.geohash_read_auth
+    }
+    
+    /// Whether any scope needs NIP-42 auth
+    pub fn auth_enabled(&self) -> bool {
+        self.root_write_auth || self.geohash_write_auth || self.geohash_read_auth
+    }
+    
+    /// Kinds accepted from connections to a scope (`None` for root), `None` for all
+    pub fn allowed_kinds_for(&self, subdomain: Option<&str>) -> Option<&[u16]> {
+        match subdomain {
+            Some(_) => self.geohash_allowed_kinds.as_deref(),
+            None => self.root_allowed_kinds.as_deref(),
+        }
+    }
+    
+    /// Host of `relay_url`, lowercased
+    fn relay_url_host(&self) -> Option<String> {
+        let url = url::Url::parse(&self.relay_url).ok()?;
+        Some(url.host_str()?.to_lowercase())
+    }
+    
+    /// Domain geohash subdomains are under, if known
+    ///
+    /// `base_domain` when configured, otherwise the host of `relay_url`. A
+    /// bare hostname or IP (e.g. `ws://localhost:8080`) says nothing about
+    /// the public domain, so it gives `None`.
+    pub fn base_domain(&self) -> Option<String> {
+        if let Some(domain) = &self.base_domain {
+            return Some(domain.clone());
+        }
+        let host = self.relay_url_host()?;
+        if host.starts_with('[') || host.parse::<std::net::IpAddr>().is_ok() || !host.contains('.') {
+            return None;
+        }
+        Some(host)
+    }
+    
+    /// Number of labels in the base domain, shared by relay_builder's scope
+    /// extraction and the HTTP host parsing
+    pub fn base_domain_parts(&self) -> usize {
+        self.base_domain()
+            .map_or(DEFAULT_BASE_DOMAIN_PARTS, |domain| domain.split('.').count())
+    }
+    
+    /// Warning when a configured `base_domain` doesn't cover `relay_url`
+    pub fn base_domain_mismatch(&self) -> Option<String> {
+        let base = self.base_domain.as_deref()?;
+        let host = self.relay_url_host()?;
+        if host == base || host.ends_with(&format!(".{}", base)) {
+            return None;
+        }
+        Some(format!(
+            "RELAY_URL host '{}' is not under BASE_DOMAIN '{}'; advertised cell URLs will not match the scopes clients connect to",
+            host, base
+        ))
+    }
+    
+    /// Settings worth knowing when debugging a deployment, as label/value
+    /// pairs in the order they are logged at startup
+    ///
+    /// Reads the database directory for its size; everything else comes
+    /// from the config.
+    pub fn summarize(&self) -> Vec<(&'static str, String)> {
+        let mut rate_limit = format!("{} events/min per scope", self.events_per_minute);
+        let mut overrides: Vec<String> = self
+            .precision_events_per_minute
+            .iter()
+            .map(|(precision, limit)| format!("precision {}: {}", precision, limit))
+            .collect();
+        let mut scopes: Vec<String> = self
+            .scope_events_per_minute
+            .iter()
+            .map(|(scope, limit)| format!("{}: {}", scope, limit))
+            .collect();
+        scopes.sort();
+        overrides.extend(scopes);
+        if !overrides.is_empty() {
+            rate_limit = format!("{} ({})", rate_limit, overrides.join(", "));
+        }
+        if self.authed_events_per_minute > 0 {
+            rate_limit = format!("{}, {} events/min per authenticated pubkey", rate_limit, self.authed_events_per_minute);
+        }
+
+        let mut features = Vec::new();
+        if self.enable_nip40_expiration {
+            features.push("nip40".to_string());
+        }
+        if self.auth_enabled() {
+            features.push("nip42".to_string());
+        }
+        if self.metrics_enabled {
+            features.push(format!("metrics (port {})", self.metrics_port));
+        }
+        if self.preview_enabled {
+            features.push("map previews".to_string());
+        }
+        if self.pow_min_difficulty > 0 || self.pow_threshold_per_minute > 0 {
+            features.push("pow".to_string());
+        }
+        if !self.wot_seed_pubkeys.is_empty() {
+            features.push(format!("wot (depth {})", self.wot_depth));
+        }
+        if self.audit_log_dir.is_some() {
+            features.push("audit log".to_string());
+        }
+        if self.replicate_from.is_some() {
+            features.push("replica".to_string());
+        }
+        if self.tor_mode {
+            features.push("tor".to_string());
+        }
+        if features.is_empty() {
+            features.push("none".to_string());
+        }
+
+        let database_size = crate::store_admin::size_on_disk(std::path::Path::new(&self.database_path));
+        vec![
+            (
+                "bind address",
+                match &self.unix_socket {
+                    Some(path) => format!("unix:{}", path),
+                    None => self.bind_addr().to_string(),
+                },
+            ),
+            ("relay url", self.relay_url.clone()),
+            ("public domain", self.base_domain().unwrap_or_else(|| "none (scopes need a domain)".to_string())),
+            ("scope routing", if self.path_routing { "subdomain and path" } else { "subdomain" }.to_string()),
+            ("storage", format!("{:?}", self.storage_backend).to_lowercase()),
+            ("database", format!("{} ({} bytes)", self.database_path, database_size)),
+            ("rate limit", rate_limit),
+            ("write policy", format!("root {:?}, cells {:?}", self.root_write_policy, self.cell_write_policy).to_lowercase()),
+            ("features", features.join(", ")),
+        ]
+    }
+    
+    /// Suspicious combinations of settings, one log line each
+    ///
+    /// None of these stop startup; each is legal but rarely intended.
+    pub fn validate_warnings(&self) -> Vec<String> {
+        let mut warnings: Vec<String> = self.base_domain_mismatch().into_iter().collect();
+        let public = self.base_domain().is_some_and(|domain| domain != "localhost" && !domain.ends_with(".localhost"));
+        if public && self.relay_url.starts_with("ws://") {
+            warnings.push(format!(
+                "RELAY_URL '{}' is plain ws:// on a public domain; clients will connect without TLS (use wss:// behind a TLS proxy)",
+                self.relay_url
+            ));
+        }
+        if self.metrics_enabled && self.metrics_port == self.port {
+            warnings.push(format!(
+                "METRICS_PORT {} is also the relay port; the metrics server will fail to bind",
+                self.metrics_port
+            ));
+        }
+        let overrides = !self.precision_events_per_minute.is_empty() || !self.scope_events_per_minute.is_empty();
+        if !self.enable_rate_limit && overrides {
+            warnings.push(
+                "Per-precision or per-scope rate limits are set but ENABLE_RATE_LIMIT=false; no rate limit applies".to_string(),
+            );
+        }
+        let paid = self.root_write_policy == WritePolicy::Paid || self.cell_write_policy == WritePolicy::Paid;
+        if paid && self.admin_token.is_none() {
+            warnings.push(
+                "A write policy is paid but ADMIN_TOKEN is unset; POST /api/admissions is disabled, so nobody new can be admitted".to_string(),
+            );
+        }
+        warnings
+    }
+    
+    /// Public websocket URL for a scope, derived from `relay_url`
+    ///
+    /// e.g. `wss://example.com` becomes `wss://drt2z.example.com` for the
+    /// drt2z cell. Scheme and port are kept as configured.
+    pub fn relay_url_for(&self, subdomain: Option<&str>) -> String {
+        let Some(sub) = subdomain else {
+            return self.relay_url.clone();
+        };
+        match url::Url::parse(&self.relay_url) {
+            Ok(mut url) => {
+                let host = url.host_str().unwrap_or("localhost").to_string();
+                if url.set_host(Some(&format!("{}.{}", sub, host))).is_err() {
+                    return self.relay_url.clone();
+                }
+                url.as_str().trim_end_matches('/').to_string()
+            }
+            Err(_) => self.relay_url.clone(),
+        }
+    }
+    
+    /// Address the relay listens on; loopback only in `tor_mode`, where the
+    /// onion service is the only way in
+    pub fn bind_addr(&self) -> std::net::SocketAddr {
+        let ip = if self.tor_mode { [127, 0, 0, 1] } else { [0, 0, 0, 0] };
+        std::net::SocketAddr::from((ip, self.port))
+    }
+    
+    /// Largest websocket message (and frame) a client may send; bigger ones
+    /// close the connection before anything is buffered or parsed
+    pub fn max_websocket_message_size(&self) -> usize {
+        self.max_event_size + WEBSOCKET_MESSAGE_OVERHEAD
+    }
+    
+    /// Websocket URL for a scope on the onion service, when `onion_address`
+    /// is set
+    ///
+    /// Onion addresses have no subdomains, so cells are paths:
+    /// `ws://{onion}/drt2z`. Tor already encrypts the connection.
+    pub fn onion_url_for(&self, subdomain: Option<&str>) -> Option<String> {
+        let onion = self.onion_address.as_deref()?;
+        Some(match subdomain {
+            Some(sub) => format!("ws://{}/{}", onion, sub),
+            None => format!("ws://{}", onion),
+        })
+    }
+    
+    /// Directory for cached preview images
+    pub fn preview_cache_path(&self) -> std::path::PathBuf {
+        match &self.preview_cache_dir {
+            Some(dir) => dir.into(),
+            None => std::path::Path::new(&self.database_path).join("previews"),
+        }
+    }
+}
+
+/// Parses a comma-separated list of kinds
+fn parse_kinds(value: &str) -> anyhow::Result<Vec<u16>> {
+    Ok(value
+        .split(',')
+        .map(str::trim)
+        .filter(|k| !k.is_empty())
+        .map(str::parse)
+        .collect::<Result<_, _>>()?)
+}
+
+/// Parses an allowed-kinds list, where "all" means no restriction
+fn parse_allowed_kinds(value: &str) -> anyhow::Result<Option<Vec<u16>>> {
+    if value.trim().eq_ignore_ascii_case("all") {
+        return Ok(None);
+    }
+    parse_kinds(value).map(Some)
+}
+
+/// Parses `key:limit` pairs separated by commas
+fn parse_limits<K, C>(value: &str) -> anyhow::Result<C>
+where
+    K: std::str::FromStr,
+    K::Err: std::error::Error + Send + Sync + 'static,
+    C: FromIterator<(K, u32)>,
+{
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, limit) = pair
+                .split_once(':')
+                .with_context(|| format!("expected key:limit, got '{}'", pair))?;
+            Ok((key.trim().parse()?, limit.trim().parse()?))
+        })
+        .collect()
+}
+
+/// Splits comma-separated `name:value` pairs, checking and lowercasing names
+fn nip05_pairs(value: &str) -> anyhow::Result<Vec<(String, &str)>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair
+                .split_once(':')
+                .with_context(|| format!("expected name:value, got '{}'", pair))?;
+            let name = name.trim().to_lowercase();
+            if !is_valid_name(&name) {
+                anyhow::bail!("invalid NIP-05 name '{}'", name);
+            }
+            Ok((name, value.trim()))
+        })
+        .collect()
+}
+
+/// Parses `name:pubkey` pairs, pubkeys as hex or npub
+fn parse_nip05_names(value: &str) -> anyhow::Result<BTreeMap<String, String>> {
+    nip05_pairs(value)?
+        .into_iter()
+        .map(|(name, pubkey)| Ok((name, parse_pubkey_flexible(pubkey)?.to_hex())))
+        .collect()
+}
+
+/// Parses `name:url|url` pairs
+fn parse_nip05_relays(value: &str) -> anyhow::Result<BTreeMap<String, Vec<String>>> {
+    Ok(nip05_pairs(value)?
+        .into_iter()
+        .map(|(name, urls)| {
+            let urls = urls.split('|').map(str::trim).filter(|u| !u.is_empty()).map(String::from).collect();
+            (name, urls)
+        })
+        .collect())
+}
+
+/// Reads an environment variable, treating empty values as unset
+fn env_opt(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Seconds after midnight for an `HH:MM` time
+pub fn parse_time_of_day(value: &str) -> anyhow::Result<u64> {
+    let (hours, minutes) = value.trim().split_once(':').context("expected HH:MM")?;
+    let (hours, minutes): (u64, u64) = (hours.parse()?, minutes.parse()?);
+    if hours > 23 || minutes > 59 {
+        anyhow::bail!("'{}' is not a time of day", value.trim());
+    }
+    Ok(hours * 3600 + minutes * 60)
+}
+
+/// Lowercased `FORCED_SCOPE`, which must be "root" or a geohash cell
+pub fn parse_forced_scope(value: &str) -> anyhow::Result<String> {
+    let scope = value.trim().to_lowercase();
+    if scope != "root" && !is_geohash_subdomain(&scope) {
+        anyhow::bail!("invalid FORCED_SCOPE '{}' (expected a geohash or root)", value.trim());
+    }
+    Ok(scope)
+}
+
+/// Lowercased onion host from `ONION_ADDRESS`, with any scheme or trailing
+/// slash removed
+pub fn parse_onion_address(value: &str) -> anyhow::Result<String> {
+    let address = value.trim().to_lowercase();
+    let address = address.split_once("://").map_or(address.as_str(), |(_, rest)| rest).trim_end_matches('/');
+    match address.strip_suffix(".onion") {
+        Some(name) if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric()) => Ok(address.to_string()),
+        _ => anyhow::bail!("invalid ONION_ADDRESS '{}' (expected <name>.onion)", value.trim()),
+    }
+}
+
+/// Socket path from `LISTEN`; `None` when empty (serve host/port)
+pub fn parse_listen(value: &str) -> anyhow::Result<Option<String>> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    match value.strip_prefix("unix:") {
+        Some(path) if !path.is_empty() => Ok(Some(path.to_string())),
+        _ => anyhow::bail!("invalid LISTEN '{}' (expected unix:<path>)", value),
+    }
+}
+
+/// Octal file mode from `UNIX_SOCKET_MODE`, e.g. `660` or `0o660`
+pub fn parse_socket_mode(value: &str) -> anyhow::Result<u32> {
+    let value = value.trim();
+    let digits = value.strip_prefix("0o").unwrap_or(value);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => anyhow::bail!("invalid UNIX_SOCKET_MODE '{}' (expected an octal mode like 660)", value),
+    }
+}
+
+/// Numeric `uid:gid` from `UNIX_SOCKET_OWNER`; `None` when empty
+pub fn parse_socket_owner(value: &str) -> anyhow::Result<Option<(u32, u32)>> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let parsed = value
+        .split_once(':')
+        .and_then(|(uid, gid)| Some((uid.trim().parse().ok()?, gid.trim().parse().ok()?)));
+    match parsed {
+        Some(owner) => Ok(Some(owner)),
+        None => anyhow::bail!("invalid UNIX_SOCKET_OWNER '{}' (expected <uid>:<gid>)", value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d";
+    const NPUB: &str = "npub180cvv07tjdrrgpa0j7j7tmnyl2yr6yr7l8j4s3evf6u64th6gkwsyjh6w6";
+
+    #[test]
+    fn test_relay_url_for_scope() {
+        let mut config = RelayConfig::default();
+        config.relay_url = "wss://example.com".to_string();
+        assert_eq!(config.relay_url_for(None), "wss://example.com");
+        assert_eq!(config.relay_url_for(Some("drt2z")), "wss://drt2z.example.com");
+
+        config.relay_url = "ws://localhost:8080".to_string();
+        assert_eq!(config.relay_url_for(Some("drt2z")), "ws://drt2z.localhost:8080");
+    }
+
+    #[test]
+    fn test_base_domain_parts() {
+        let config = |relay_url: &str, base_domain: Option<&str>| RelayConfig {
+            relay_url: relay_url.to_string(),
+            base_domain: base_domain.map(str::to_string),
+            ..Default::default()
+        };
+
+        assert_eq!(config("wss://example.com", None).base_domain_parts(), 2);
+        assert_eq!(config("wss://relay.mycompany.com", None).base_domain_parts(), 3);
+        assert_eq!(config("wss://nostr.example.co.uk:443/", None).base_domain_parts(), 4);
+        assert_eq!(
+            config("wss://nostr.example.co.uk", None).base_domain().as_deref(),
+            Some("nostr.example.co.uk")
+        );
+
+        // Configured base domain wins over relay_url
+        assert_eq!(config("wss://example.com", Some("relay.mycompany.com")).base_domain_parts(), 3);
+
+        // Local and IP relay URLs fall back to the default
+        assert_eq!(config("ws://localhost:8080", None).base_domain_parts(), DEFAULT_BASE_DOMAIN_PARTS);
+        assert_eq!(config("ws://127.0.0.1:8080", None).base_domain_parts(), DEFAULT_BASE_DOMAIN_PARTS);
+        assert_eq!(config("ws://[::1]:8080", None).base_domain_parts(), DEFAULT_BASE_DOMAIN_PARTS);
+    }
+
+    #[test]
+    fn test_base_domain_mismatch() {
+        let config = |relay_url: &str, base_domain: &str| RelayConfig {
+            relay_url: relay_url.to_string(),
+            base_domain: Some(base_domain.to_string()),
+            ..Default::default()
+        };
+        assert!(config("wss://relay.mycompany.com", "relay.mycompany.com").base_domain_mismatch().is_none());
+        assert!(config("wss://relay.mycompany.com", "mycompany.com").base_domain_mismatch().is_none());
+
+        let warning = config("wss://relay.other.com", "mycompany.com").base_domain_mismatch().unwrap();
+        assert!(warning.contains("relay.other.com"));
+        assert!(warning.contains("mycompany.com"));
+        // Suffix matches must be on a label boundary
+        assert!(config("wss://notmycompany.com", "mycompany.com").base_domain_mismatch().is_some());
+
+        // Nothing to check when the base domain comes from relay_url
+        assert!(RelayConfig::default().base_domain_mismatch().is_none());
+    }
+
+    #[test]
+    fn test_summary_lists_overrides_and_features() {
+        let config = RelayConfig {
+            relay_url: "wss://relay.example.com".to_string(),
+            precision_events_per_minute: BTreeMap::from([(4, 120)]),
+            scope_events_per_minute: HashMap::from([("drt2z".to_string(), 300)]),
+            root_write_auth: true,
+            database_path: "/nonexistent/geohashed-relay".to_string(),
+            ..Default::default()
+        };
+        let summary: HashMap<_, _> = config.summarize().into_iter().collect();
+        assert_eq!(summary["public domain"], "relay.example.com");
+        assert_eq!(summary["scope routing"], "subdomain");
+        assert_eq!(summary["rate limit"], "30 events/min per scope (precision 4: 120, drt2z: 300)");
+        assert!(summary["features"].contains("nip42"));
+        assert_eq!(summary["database"], "/nonexistent/geohashed-relay (0 bytes)");
+    }
+
+    #[test]
+    fn test_warnings_for_suspicious_combinations() {
+        let public = RelayConfig { relay_url: "wss://relay.example.com".to_string(), ..Default::default() };
+        assert!(public.validate_warnings().is_empty());
+        assert!(RelayConfig::default().validate_warnings().is_empty());
+
+        let plain = RelayConfig { relay_url: "ws://relay.example.com".to_string(), ..Default::default() };
+        let warnings = plain.validate_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("without TLS"));
+
+        let colliding = RelayConfig { metrics_enabled: true, metrics_port: 8080, port: 8080, ..Default::default() };
+        assert!(colliding.validate_warnings().iter().any(|w| w.contains("METRICS_PORT 8080")));
+
+        let paid = RelayConfig { cell_write_policy: WritePolicy::Paid, ..public.clone() };
+        assert!(paid.validate_warnings().iter().any(|w| w.contains("ADMIN_TOKEN")));
+        let paid = RelayConfig { admin_token: Some("secret".to_string()), ..paid };
+        assert!(paid.validate_warnings().is_empty());
+
+        let mismatched = RelayConfig { base_domain: Some("other.com".to_string()), ..public };
+        assert!(mismatched.validate_warnings()[0].contains("BASE_DOMAIN"));
+    }
+
+    #[test]
+    fn test_default_expiration_for_scope() {
+        let config = RelayConfig {
+            default_expiration_secs: 86_400,
+            scope_default_expiration_secs: BTreeMap::from([
+                ("9q".to_string(), 3_600),
+                ("9q8y".to_string(), 600),
+                ("root".to_string(), 0),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(config.default_expiration_secs_for(Some("drt2z")), Some(86_400));
+        assert_eq!(config.default_expiration_secs_for(Some("9q5cs")), Some(3_600));
+        assert_eq!(config.default_expiration_secs_for(Some("9Q8YY")), Some(600));
+        assert_eq!(config.default_expiration_secs_for(None), None);
+        assert_eq!(RelayConfig::default().default_expiration_secs_for(Some("drt2z")), None);
+    }
+
+    #[test]
+    fn test_events_per_minute_for_scope() {
+        let config = RelayConfig {
+            events_per_minute: 30,
+            precision_events_per_minute: parse_limits::<usize, BTreeMap<_, _>>("2:600, 5:60").unwrap(),
+            scope_events_per_minute: parse_limits::<String, HashMap<_, _>>("root:120,drt2z:10").unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(config.events_per_minute_for(None), 120);
+        assert_eq!(config.events_per_minute_for(Some("DRT2Z")), 10);
+        assert_eq!(config.events_per_minute_for(Some("9q8yy")), 60);
+        assert_eq!(config.events_per_minute_for(Some("dr")), 600);
+        assert_eq!(config.events_per_minute_for(Some("drt2z7")), 30);
+
+        assert!(parse_limits::<usize, BTreeMap<_, _>>("5").is_err());
+        assert!(parse_limits::<usize, BTreeMap<_, _>>("x:5").is_err());
+    }
+
+    #[test]
+    fn test_dm_policy_parsing() {
+        assert_eq!("root-only".parse::<DmPolicy>().unwrap(), DmPolicy::RootOnly);
+        assert_eq!("Reject".parse::<DmPolicy>().unwrap(), DmPolicy::Reject);
+        assert_eq!("allow".parse::<DmPolicy>().unwrap(), DmPolicy::Allow);
+        assert!("sometimes".parse::<DmPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_deletion_mode_parsing() {
+        assert_eq!("hard".parse::<DeletionMode>().unwrap(), DeletionMode::Hard);
+        assert_eq!("Tombstone".parse::<DeletionMode>().unwrap(), DeletionMode::Tombstone);
+        assert!("soft".parse::<DeletionMode>().is_err());
+    }
+
+    #[test]
+    fn test_write_policy_per_scope_type() {
+        let config = RelayConfig {
+            root_write_policy: WritePolicy::Paid,
+            ..Default::default()
+        };
+        assert_eq!(config.write_policy_for(None), WritePolicy::Paid);
+        assert_eq!(config.write_policy_for(Some("drt2z")), WritePolicy::Free);
+        assert_eq!("Paid".parse::<WritePolicy>().unwrap(), WritePolicy::Paid);
+        assert!("premium".parse::<WritePolicy>().is_err());
+    }
+
+    #[test]
+    fn test_geohash_profile_expands_to_granular_options() {
+        assert_eq!("Location-Chat".parse::<GeohashProfile>().unwrap(), GeohashProfile::LocationChat);
+        assert!("chat".parse::<GeohashProfile>().is_err());
+
+        let mut config = RelayConfig {
+            geohash_profile: GeohashProfile::LocationChat,
+            global_kinds: vec![],
+            ..Default::default()
+        };
+        config.apply_geohash_profile();
+        assert_eq!(config.allowed_kinds_for(Some("drt2z")), Some(&[0, 1, 3, 10002, 20000][..]));
+        assert_eq!(config.allowed_kinds_for(None), None);
+        assert_eq!(config.geohash_retention_secs, 86400);
+        assert_eq!(config.global_kinds, vec![0, 3, 10002]);
+
+        // Open clears whatever was set; custom keeps it
+        let mut config = RelayConfig {
+            geohash_profile: GeohashProfile::Open,
+            geohash_allowed_kinds: Some(vec![1]),
+            geohash_retention_secs: 60,
+            ..Default::default()
+        };
+        config.apply_geohash_profile();
+        assert_eq!(config.allowed_kinds_for(Some("drt2z")), None);
+        assert_eq!(config.geohash_retention_secs, 0);
+
+        let mut config = RelayConfig {
+            geohash_allowed_kinds: Some(vec![1]),
+            geohash_retention_secs: 60,
+            ..Default::default()
+        };
+        config.apply_geohash_profile();
+        assert_eq!(config.allowed_kinds_for(Some("drt2z")), Some(&[1][..]));
+        assert_eq!(config.geohash_retention_secs, 60);
+    }
+
+    #[test]
+    fn test_geohash_ttl_exemptions() {
+        assert_eq!(RelayConfig::default().geohash_ttl_secs_for(1), None);
+        assert_eq!(" Strict".parse::<TtlMode>().unwrap(), TtlMode::Strict);
+        assert!("forever".parse::<TtlMode>().is_err());
+
+        let config = RelayConfig {
+            geohash_max_ttl_days: 7,
+            geohash_ttl_exempt_kinds: vec![0, 10002],
+            ..Default::default()
+        };
+        assert_eq!(config.geohash_ttl_secs_for(1), Some(7 * 86400));
+        assert_eq!(config.geohash_ttl_secs_for(0), None);
+        assert_eq!(config.geohash_ttl_secs_for(10002), None);
+    }
+
+    #[test]
+    fn test_auth_per_scope_type() {
+        let open = RelayConfig::default();
+        assert!(!open.auth_enabled());
+        assert!(!open.write_auth_for(None) && !open.write_auth_for(Some("drt2z")));
+
+        let config = RelayConfig {
+            geohash_write_auth: true,
+            geohash_read_auth: true,
+            ..Default::default()
+        };
+        assert!(config.auth_enabled());
+        assert!(!config.write_auth_for(None));
+        assert!(config.write_auth_for(Some("drt2z")));
+        assert!(!config.read_auth_for(None));
+        assert!(config.read_auth_for(Some("drt2z")));
+
+        let config = RelayConfig { root_write_auth: true, ..Default::default() };
+        assert!(config.write_auth_for(None));
+        assert!(!config.write_auth_for(Some("drt2z")));
+        assert!(!config.read_auth_for(Some("drt2z")));
+    }
+
+    #[test]
+    fn test_allowed_kinds_parsing() {
+        assert_eq!(parse_allowed_kinds("all").unwrap(), None);
+        assert_eq!(parse_allowed_kinds(" ALL ").unwrap(), None);
+        assert_eq!(parse_allowed_kinds("1, 20000").unwrap(), Some(vec![1, 20000]));
+        assert!(parse_allowed_kinds("1,chat").is_err());
+
+        let config = RelayConfig {
+            root_allowed_kinds: Some(vec![0, 3, 10002]),
+            ..Default::default()
+        };
+        assert_eq!(config.allowed_kinds_for(None), Some(&[0, 3, 10002][..]));
+        assert_eq!(config.allowed_kinds_for(Some("drt2z")), None);
+    }
+
+    #[test]
+    fn test_quota_policy_parsing() {
+        assert_eq!("reject".parse::<QuotaPolicy>().unwrap(), QuotaPolicy::Reject);
+        assert_eq!(" Evict-Oldest ".parse::<QuotaPolicy>().unwrap(), QuotaPolicy::EvictOldest);
+        assert_eq!("archive".parse::<QuotaPolicy>().unwrap(), QuotaPolicy::Archive);
+        assert!("evict".parse::<QuotaPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_tag_precision_mode_parsing() {
+        assert_eq!("reject".parse::<TagPrecisionMode>().unwrap(), TagPrecisionMode::Reject);
+        assert_eq!(" Adjust".parse::<TagPrecisionMode>().unwrap(), TagPrecisionMode::Adjust);
+        assert!("truncate".parse::<TagPrecisionMode>().is_err());
+    }
+
+    #[test]
+    fn test_precision_cap_policy_parsing() {
+        assert_eq!("reject".parse::<PrecisionCapPolicy>().unwrap(), PrecisionCapPolicy::Reject);
+        assert_eq!("Truncate ".parse::<PrecisionCapPolicy>().unwrap(), PrecisionCapPolicy::Truncate);
+        assert!("adjust".parse::<PrecisionCapPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_storage_backend_parsing() {
+        assert_eq!("lmdb".parse::<StorageBackend>().unwrap(), StorageBackend::Lmdb);
+        assert_eq!(" Memory ".parse::<StorageBackend>().unwrap(), StorageBackend::Memory);
+        assert!("sqlite".parse::<StorageBackend>().is_err());
+    }
+
+    #[test]
+    fn test_preload_scopes_parsing() {
+        assert_eq!(" ALL ".parse::<PreloadScopes>().unwrap(), PreloadScopes::All);
+        assert_eq!("".parse::<PreloadScopes>().unwrap(), PreloadScopes::None);
+        assert_eq!(
+            "root, DRT2Z,,9q8yy".parse::<PreloadScopes>().unwrap(),
+            PreloadScopes::Listed(vec!["root".to_string(), "drt2z".to_string(), "9q8yy".to_string()])
+        );
+        assert!("drt2z,team1".parse::<PreloadScopes>().is_err());
+    }
+
+    #[test]
+    fn test_parse_time_of_day() {
+        assert_eq!(parse_time_of_day("00:00").unwrap(), 0);
+        assert_eq!(parse_time_of_day(" 06:30 ").unwrap(), 6 * 3600 + 30 * 60);
+        assert!(parse_time_of_day("24:00").is_err());
+        assert!(parse_time_of_day("6").is_err());
+    }
+
+    #[test]
+    fn test_parse_forced_scope() {
+        assert_eq!(parse_forced_scope(" DRT2Z ").unwrap(), "drt2z");
+        assert_eq!(parse_forced_scope("Root").unwrap(), "root");
+        assert!(parse_forced_scope("team1").is_err());
+        assert!(parse_forced_scope("drt2zbxyz").is_err());
+    }
+
+    #[test]
+    fn test_listen_and_socket_settings() {
+        assert_eq!(parse_listen("unix:/run/geohashed-relay.sock").unwrap().as_deref(), Some("/run/geohashed-relay.sock"));
+        assert_eq!(parse_listen(" ").unwrap(), None);
+        assert!(parse_listen("unix:").is_err());
+        assert!(parse_listen("0.0.0.0:8080").is_err());
+
+        assert_eq!(parse_socket_mode("660").unwrap(), 0o660);
+        assert_eq!(parse_socket_mode("0o600").unwrap(), 0o600);
+        assert!(parse_socket_mode("999").is_err());
+        assert!(parse_socket_mode("7777").is_err());
+
+        assert_eq!(parse_socket_owner("33:33").unwrap(), Some((33, 33)));
+        assert_eq!(parse_socket_owner("").unwrap(), None);
+        assert!(parse_socket_owner("www-data").is_err());
+    }
+
+    #[test]
+    fn test_tor_mode_binds_loopback_and_publishes_onion() {
+        let config = RelayConfig::default();
+        assert_eq!(config.bind_addr().to_string(), "0.0.0.0:8080");
+        assert_eq!(config.onion_url_for(None), None);
+
+        let config = RelayConfig {
+            tor_mode: true,
+            onion_address: Some(parse_onion_address(" ws://ABCDEF.onion/ ").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(config.bind_addr().to_string(), "127.0.0.1:8080");
+        assert_eq!(config.onion_url_for(None).as_deref(), Some("ws://abcdef.onion"));
+        assert_eq!(config.onion_url_for(Some("drt2z")).as_deref(), Some("ws://abcdef.onion/drt2z"));
+
+        assert!(parse_onion_address("example.com").is_err());
+        assert!(parse_onion_address(".onion").is_err());
+        assert!(parse_onion_address("a.b.onion").is_err());
+    }
+
+    #[test]
+    fn test_parse_nip05_names_and_relays() {
+        let names = parse_nip05_names(&format!("Alice:{}, bob:{}", HEX, NPUB)).unwrap();
+        assert_eq!(names.get("alice").map(String::as_str), Some(HEX));
+        assert_eq!(names.get("bob").map(String::as_str), Some(HEX));
+        assert!(parse_nip05_names(&format!("not valid:{}", HEX)).is_err());
+        assert!(parse_nip05_names("alice:nope").is_err());
+
+        let relays = parse_nip05_relays("alice:wss://a.example.com|wss://b.example.com").unwrap();
+        assert_eq!(relays["alice"], vec!["wss://a.example.com", "wss://b.example.com"]);
     }
 }
\ No newline at end of file
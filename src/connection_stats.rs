@@ -0,0 +1,177 @@
+//! `stats` command: what the relay thinks of a connection
+//!
+//! Rate-limit complaints are easier to debug when users can ask the relay
+//! directly. With `stats_command` on, an EVENT of `stats_command_kind` that
+//! p-tags the relay's pubkey and says "stats" is never stored; the
+//! processor describes the connection instead, and `StatsNoticeMiddleware`
+//! sends that as a NOTICE right after the command's OK. Answers are limited
+//! to one per `stats_command_interval_secs` per connection.
+
+use nostr::nips::nip19::ToBech32;
+use nostr_sdk::prelude::*;
+use relay_builder::{NostrMiddleware, OutboundContext};
+use std::time::{Duration, Instant};
+use crate::processor::ConnectionState;
+
+/// Content of the command EVENT
+pub const STATS_COMMAND_CONTENT: &str = "stats";
+
+/// Period the per-connection counters cover, matching the rate limit's
+pub const COUNTER_WINDOW: Duration = Duration::from_secs(60);
+
+/// Whether `event` asks the relay at `relay_pubkey` for connection stats
+pub fn is_stats_command(event: &Event, kind: u16, relay_pubkey: &PublicKey) -> bool {
+    event.kind.as_u16() == kind
+        && event.content.trim().eq_ignore_ascii_case(STATS_COMMAND_CONTENT)
+        && event.tags.public_keys().any(|pubkey| pubkey == relay_pubkey)
+}
+
+/// Outcomes of a connection's EVENTs in the current window
+#[derive(Debug, Clone, Default)]
+pub struct EventCounters {
+    window_start: Option<Instant>,
+    /// Wall-clock time of `window_start`, for reporting
+    window_started_at: Option<Timestamp>,
+    pub accepted: u64,
+    pub rejected: u64,
+    /// Refused by the scope rate limit before reaching the processor
+    pub rate_limited: u64,
+}
+
+impl EventCounters {
+    /// Starts a new window once the current one is over
+    pub fn roll(&mut self, now: Instant) {
+        if !matches!(self.window_start, Some(start) if now.duration_since(start) < COUNTER_WINDOW) {
+            *self = Self {
+                window_start: Some(now),
+                window_started_at: Some(Timestamp::now()),
+                ..Self::default()
+            };
+        }
+    }
+
+    /// When the current window started, `None` before the first EVENT
+    pub fn window_started_at(&self) -> Option<Timestamp> {
+        self.window_started_at
+    }
+
+    pub fn record(&mut self, accepted: bool) {
+        self.roll(Instant::now());
+        if accepted {
+            self.accepted += 1;
+        } else {
+            self.rejected += 1;
+        }
+    }
+
+    pub fn record_rate_limited(&mut self) {
+        self.roll(Instant::now());
+        self.rate_limited += 1;
+    }
+}
+
+/// A connection as the relay sees it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// "root" or the geohash
+    pub scope: String,
+    pub connected_secs: u64,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub rate_limited: u64,
+    /// Scope budget (0 for none)
+    pub events_per_minute: u32,
+    /// Events the cell may still store, and its quota, when one applies
+    pub quota: Option<(u64, u64)>,
+    pub subscriptions: usize,
+    pub max_subscriptions: usize,
+    pub authed_pubkey: Option<PublicKey>,
+}
+
+impl std::fmt::Display for ConnectionStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "stats: scope {}, connected {}s", self.scope, self.connected_secs)?;
+        write!(
+            f,
+            "; last minute {} accepted, {} rejected, {} rate-limited",
+            self.accepted, self.rejected, self.rate_limited
+        )?;
+        match self.events_per_minute {
+            0 => f.write_str("; no rate limit")?,
+            limit => write!(f, "; limit {} events/min per scope", limit)?,
+        }
+        if let Some((left, max)) = self.quota {
+            write!(f, "; {} of {} cell events left", left, max)?;
+        }
+        write!(f, "; {} of {} subscriptions open", self.subscriptions, self.max_subscriptions)?;
+        match self.authed_pubkey.and_then(|pubkey| pubkey.to_bech32().ok()) {
+            Some(npub) => write!(f, "; authenticated as {}", npub),
+            None => f.write_str("; not authenticated"),
+        }
+    }
+}
+
+/// A stats answer waiting for its command's OK
+#[derive(Debug, Clone)]
+pub struct PendingStatsNotice {
+    pub event_id: EventId,
+    pub notice: String,
+}
+
+/// Sends the stats NOTICE after the command's OK
+#[derive(Debug, Clone)]
+pub struct StatsNoticeMiddleware;
+
+impl NostrMiddleware<ConnectionState> for StatsNoticeMiddleware {
+    async fn process_outbound(&self, ctx: OutboundContext<'_, ConnectionState>) -> Result<(), anyhow::Error> {
+        let Some(RelayMessage::Ok { event_id, .. }) = &ctx.message else {
+            return Ok(());
+        };
+        let pending = {
+            let mut state = ctx.state.write();
+            match &state.custom.stats_notice {
+                Some(pending) if pending.event_id == *event_id => state.custom.stats_notice.take(),
+                _ => None,
+            }
+        };
+        if let Some(pending) = pending {
+            ctx.sender.send_bypass(RelayMessage::notice(pending.notice));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_needs_kind_content_and_relay_tag() {
+        let relay = Keys::generate().public_key();
+        let keys = Keys::generate();
+        let command = |kind: u16, content: &str, to: PublicKey| {
+            EventBuilder::new(Kind::from(kind), content).tag(Tag::public_key(to)).sign_with_keys(&keys).unwrap()
+        };
+
+        assert!(is_stats_command(&command(21059, "stats", relay), 21059, &relay));
+        assert!(is_stats_command(&command(21059, " Stats\n", relay), 21059, &relay));
+        assert!(!is_stats_command(&command(1, "stats", relay), 21059, &relay));
+        assert!(!is_stats_command(&command(21059, "hello", relay), 21059, &relay));
+        assert!(!is_stats_command(&command(21059, "stats", keys.public_key()), 21059, &relay));
+    }
+
+    #[test]
+    fn test_counters_reset_each_window() {
+        let mut counters = EventCounters::default();
+        assert_eq!(counters.window_started_at(), None);
+        counters.record(true);
+        let started_at = counters.window_started_at().expect("window started");
+        assert!(started_at.as_u64().abs_diff(Timestamp::now().as_u64()) <= 1);
+        counters.record(false);
+        counters.record_rate_limited();
+        assert_eq!((counters.accepted, counters.rejected, counters.rate_limited), (1, 1, 1));
+
+        counters.roll(Instant::now() + COUNTER_WINDOW);
+        assert_eq!((counters.accepted, counters.rejected, counters.rate_limited), (0, 0, 0));
+    }
+}
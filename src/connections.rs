@@ -0,0 +1,385 @@
+//! Live connection tracking
+//!
+//! `ConnectionRegistry` keeps a per-scope count of open websocket connections.
+//! It is fed by `ConnectionTrackingMiddleware`'s connect/disconnect hooks and
+//! read by the stats endpoint and the global connection cap
+//! (`ConnectionLimit`). `WelcomeMiddleware` greets new connections with a
+//! NOTICE describing their scope.
+//!
+//! The registry also keeps a `ConnectionView` of every open connection,
+//! refreshed after each message the client sends, for `GET /api/connections`.
+//!
+//! The websocket route also hands each upgrade's `ConnectionOrigin` to the
+//! registry, keyed by peer address, for `ConnectionTrackingMiddleware` to
+//! move into `ConnectionState` once relay_builder opens the connection.
+
+use nostr_lmdb::Scope;
+use parking_lot::RwLock;
+use nostr_sdk::prelude::RelayMessage;
+use parking_lot::Mutex;
+use relay_builder::{ConnectionContext, DisconnectContext, InboundContext, InboundProcessor, NostrMiddleware};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::warn;
+use crate::config::RelayConfig;
+use crate::host_parsing::ConnectionOrigin;
+use crate::policy::welcome_notice;
+use crate::processor::ConnectionState;
+use crate::store::scope_label;
+
+/// Upgrades whose connection hasn't opened after this long are forgotten
+const PENDING_ORIGIN_TTL: Duration = Duration::from_secs(30);
+
+/// One open connection as `GET /api/connections` lists it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConnectionView {
+    /// "root" or the geohash
+    pub scope: String,
+    pub ip: Option<IpAddr>,
+    /// Unix seconds
+    pub connected_at: Option<u64>,
+    /// Unix seconds the counters below started at, `None` before the
+    /// first EVENT; they cover one `COUNTER_WINDOW`
+    pub window_started_at: Option<u64>,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub rate_limited: u64,
+    pub subscriptions: usize,
+}
+
+impl ConnectionView {
+    pub fn of(state: &ConnectionState, scope: &Scope) -> Self {
+        Self {
+            scope: scope_label(scope),
+            ip: state.client_ip,
+            connected_at: state.connected_since.map(|at| at.as_u64()),
+            window_started_at: state.event_counters.window_started_at().map(|at| at.as_u64()),
+            accepted: state.event_counters.accepted,
+            rejected: state.event_counters.rejected,
+            rate_limited: state.event_counters.rate_limited,
+            subscriptions: state.subscriptions.len(),
+        }
+    }
+}
+
+/// Per-scope open connection counts
+#[derive(Debug, Default)]
+pub struct ConnectionRegistry {
+    per_scope: RwLock<HashMap<String, usize>>,
+    /// Open connections by peer address
+    live: RwLock<HashMap<SocketAddr, ConnectionView>>,
+    /// Origins of upgrades waiting for their connection to open
+    pending_origins: Mutex<HashMap<SocketAddr, (ConnectionOrigin, Instant)>>,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn connected(&self, scope: &Scope) {
+        let mut per_scope = self.per_scope.write();
+        *per_scope.entry(scope_label(scope)).or_insert(0) += 1;
+        metrics::gauge!("relay_active_connections").set(per_scope.values().sum::<usize>() as f64);
+    }
+
+    pub fn disconnected(&self, scope: &Scope) {
+        let mut per_scope = self.per_scope.write();
+        let label = scope_label(scope);
+        if let Some(count) = per_scope.get_mut(&label) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                per_scope.remove(&label);
+            }
+        }
+        metrics::gauge!("relay_active_connections").set(per_scope.values().sum::<usize>() as f64);
+    }
+
+    /// Open connections on exactly this scope
+    pub fn active(&self, scope: &Scope) -> usize {
+        self.per_scope.read().get(&scope_label(scope)).copied().unwrap_or(0)
+    }
+
+    /// Open connections across all scopes
+    pub fn total(&self) -> usize {
+        self.per_scope.read().values().sum()
+    }
+
+    /// Records or refreshes the connection from `peer`
+    pub fn track(&self, peer: SocketAddr, view: ConnectionView) {
+        self.live.write().insert(peer, view);
+    }
+
+    pub fn untrack(&self, peer: &SocketAddr) {
+        self.live.write().remove(peer);
+    }
+
+    /// Open connections, oldest first
+    pub fn live(&self) -> Vec<ConnectionView> {
+        let mut views: Vec<ConnectionView> = self.live.read().values().cloned().collect();
+        views.sort_by_key(|view| (view.connected_at, view.ip));
+        views
+    }
+
+    /// Remembers how the upgrade from `peer` reached the relay
+    pub fn expect_origin(&self, peer: SocketAddr, origin: ConnectionOrigin) {
+        let now = Instant::now();
+        let mut pending = self.pending_origins.lock();
+        // Failed upgrades never collect theirs
+        pending.retain(|_, (_, at)| now.duration_since(*at) < PENDING_ORIGIN_TTL);
+        pending.insert(peer, (origin, now));
+    }
+
+    /// The origin `expect_origin` recorded for `peer`, once
+    pub fn take_origin(&self, peer: SocketAddr) -> Option<ConnectionOrigin> {
+        self.pending_origins.lock().remove(&peer).map(|(origin, _)| origin)
+    }
+}
+
+/// Minimum time between "shedding connections" warnings
+const SHED_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Global cap on open websocket connections
+///
+/// Trusted peers (localhost and configured proxies) get `reserved` extra
+/// slots above the cap so health probes keep working while shedding.
+#[derive(Debug)]
+pub struct ConnectionLimit {
+    max: usize,
+    reserved: usize,
+    trusted: Vec<IpAddr>,
+    last_warning: Mutex<Option<Instant>>,
+}
+
+impl ConnectionLimit {
+    /// `max == 0` disables the cap
+    pub fn new(max: usize, reserved: usize, trusted: Vec<IpAddr>) -> Self {
+        Self {
+            max,
+            reserved,
+            trusted,
+            last_warning: Mutex::new(None),
+        }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        ip.is_loopback() || self.trusted.contains(&ip)
+    }
+
+    /// Whether a new connection from `ip` may be accepted right now
+    pub fn admits(&self, registry: &ConnectionRegistry, ip: IpAddr) -> bool {
+        if self.max == 0 {
+            return true;
+        }
+        let limit = if self.is_trusted(ip) { self.max + self.reserved } else { self.max };
+        let open = registry.total();
+        if open < limit {
+            return true;
+        }
+
+        metrics::counter!("relay_connections_shed_total").increment(1);
+        let mut last_warning = self.last_warning.lock();
+        if last_warning.is_none_or(|at| at.elapsed() >= SHED_WARNING_INTERVAL) {
+            *last_warning = Some(Instant::now());
+            warn!("Connection cap reached ({} open, max {}); refusing new connections", open, self.max);
+        }
+        false
+    }
+}
+
+/// Middleware that reports connects/disconnects to a `ConnectionRegistry`
+/// and records the scope, client address and origin in `ConnectionState`
+#[derive(Debug, Clone)]
+pub struct ConnectionTrackingMiddleware {
+    registry: Arc<ConnectionRegistry>,
+}
+
+impl ConnectionTrackingMiddleware {
+    pub fn new(registry: Arc<ConnectionRegistry>) -> Self {
+        Self { registry }
+    }
+}
+
+impl NostrMiddleware<ConnectionState> for ConnectionTrackingMiddleware {
+    async fn on_connect(&self, ctx: ConnectionContext<'_, ConnectionState>) -> Result<(), anyhow::Error> {
+        // websocket_builder names connections after the peer address
+        let client_addr = ctx.connection_id.parse().ok();
+        let origin = client_addr.and_then(|addr| self.registry.take_origin(addr));
+        let (scope, view) = {
+            let mut state = ctx.state.write();
+            let scope = state.subdomain.clone();
+            state.custom.connected(&scope, client_addr, origin);
+            let view = ConnectionView::of(&state.custom, &scope);
+            (scope, view)
+        };
+        self.registry.connected(&scope);
+        if let Some(addr) = client_addr {
+            self.registry.track(addr, view);
+        }
+        Ok(())
+    }
+
+    async fn process_inbound<Next>(&self, ctx: InboundContext<'_, ConnectionState, Next>) -> Result<(), anyhow::Error>
+    where
+        Next: InboundProcessor<ConnectionState>,
+    {
+        let result = ctx.next().await;
+        // Counters and subscriptions change further down the chain
+        let (client_addr, view) = {
+            let state = ctx.state.read();
+            (state.custom.client_addr, ConnectionView::of(&state.custom, &state.subdomain))
+        };
+        if let Some(addr) = client_addr {
+            self.registry.track(addr, view);
+        }
+        result
+    }
+
+    async fn on_disconnect(&self, ctx: DisconnectContext<'_, ConnectionState>) -> Result<(), anyhow::Error> {
+        let (scope, client_addr) = {
+            let state = ctx.state.read();
+            (state.subdomain.clone(), state.custom.client_addr)
+        };
+        self.registry.disconnected(&scope);
+        if let Some(addr) = client_addr {
+            self.registry.untrack(&addr);
+        }
+        Ok(())
+    }
+}
+
+/// Sends the scope's posting rules as a NOTICE when a connection opens
+#[derive(Debug, Clone)]
+pub struct WelcomeMiddleware {
+    config: Arc<RelayConfig>,
+}
+
+impl WelcomeMiddleware {
+    pub fn new(config: Arc<RelayConfig>) -> Self {
+        Self { config }
+    }
+}
+
+impl NostrMiddleware<ConnectionState> for WelcomeMiddleware {
+    async fn on_connect(&self, ctx: ConnectionContext<'_, ConnectionState>) -> Result<(), anyhow::Error> {
+        if !self.config.welcome_notice {
+            return Ok(());
+        }
+        let subdomain = match ctx.state.read().subdomain.as_ref() {
+            Scope::Named { name, .. } => Some(name.clone()),
+            Scope::Default => None,
+        };
+        let notice = welcome_notice(subdomain.as_deref(), &self.config);
+        ctx.send_message(RelayMessage::notice(notice))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_counts_per_scope() {
+        let registry = ConnectionRegistry::new();
+        let drt2z = Scope::named("drt2z").unwrap();
+
+        registry.connected(&drt2z);
+        registry.connected(&drt2z);
+        registry.connected(&Scope::Default);
+        assert_eq!(registry.active(&drt2z), 2);
+        assert_eq!(registry.active(&Scope::Default), 1);
+        assert_eq!(registry.total(), 3);
+
+        registry.disconnected(&drt2z);
+        registry.disconnected(&drt2z);
+        // Extra disconnects never underflow
+        registry.disconnected(&drt2z);
+        assert_eq!(registry.active(&drt2z), 0);
+        assert_eq!(registry.total(), 1);
+    }
+
+    #[test]
+    fn test_connection_limit_with_reserved_headroom() {
+        let registry = ConnectionRegistry::new();
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let stranger: IpAddr = "203.0.113.7".parse().unwrap();
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        let limit = ConnectionLimit::new(2, 1, vec![proxy]);
+
+        registry.connected(&Scope::Default);
+        assert!(limit.admits(&registry, stranger));
+        registry.connected(&Scope::Default);
+
+        // At the cap: only trusted peers get the reserved slot
+        assert!(!limit.admits(&registry, stranger));
+        assert!(limit.admits(&registry, proxy));
+        assert!(limit.admits(&registry, localhost));
+        registry.connected(&Scope::Default);
+        assert!(!limit.admits(&registry, localhost));
+
+        // Slots free up as connections close
+        registry.disconnected(&Scope::Default);
+        registry.disconnected(&Scope::Default);
+        assert!(limit.admits(&registry, stranger));
+    }
+
+    #[test]
+    fn test_connection_limit_disabled() {
+        let registry = ConnectionRegistry::new();
+        for _ in 0..100 {
+            registry.connected(&Scope::Default);
+        }
+        let limit = ConnectionLimit::new(0, 0, vec![]);
+        assert!(limit.admits(&registry, "203.0.113.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_connection_view_shape() {
+        let peer: SocketAddr = "203.0.113.7:52000".parse().unwrap();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let mut state = ConnectionState::default();
+        state.connected(&drt2z, Some(peer), None);
+        state.event_counters.record(true);
+        state.event_counters.record(false);
+        state.subscriptions.open(&nostr_sdk::prelude::SubscriptionId::new("feed"), 20);
+
+        let view = ConnectionView::of(&state, &drt2z);
+        let json = serde_json::to_value(&view).unwrap();
+        assert_eq!(json["scope"], "drt2z");
+        assert_eq!(json["ip"], "203.0.113.7");
+        assert!(json["connected_at"].is_u64());
+        assert!(json["window_started_at"].is_u64());
+        assert_eq!(json["accepted"], 1);
+        assert_eq!(json["rejected"], 1);
+        assert_eq!(json["rate_limited"], 0);
+        assert_eq!(json["subscriptions"], 1);
+
+        // Before any EVENT there is no window yet
+        let fresh = ConnectionView::of(&ConnectionState::default(), &Scope::Default);
+        let json = serde_json::to_value(&fresh).unwrap();
+        assert_eq!(json["scope"], "root");
+        assert!(json["window_started_at"].is_null());
+
+        let registry = ConnectionRegistry::new();
+        registry.track(peer, view.clone());
+        assert_eq!(registry.live(), vec![view]);
+        registry.untrack(&peer);
+        assert!(registry.live().is_empty());
+    }
+
+    #[test]
+    fn test_origin_is_handed_over_once() {
+        let registry = ConnectionRegistry::new();
+        let peer: SocketAddr = "203.0.113.7:52000".parse().unwrap();
+        let origin = ConnectionOrigin { base_domain: Some("relay.corp.internal".to_string()), secure: None, ..Default::default() };
+
+        registry.expect_origin(peer, origin.clone());
+        assert_eq!(registry.take_origin("203.0.113.7:52001".parse().unwrap()), None);
+        assert_eq!(registry.take_origin(peer), Some(origin));
+        assert_eq!(registry.take_origin(peer), None);
+    }
+}
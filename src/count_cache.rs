@@ -0,0 +1,502 @@
+//! NIP-45 COUNT answers, cached per scope
+//!
+//! Map clients COUNT dozens of cells on every pan. `CountMiddleware` answers
+//! COUNT from `CountCache`, keyed by scope and the normalized filter, so a
+//! repeated COUNT doesn't evaluate the filter again. Entries are dropped when
+//! their scope stores an event (via `LiveEvents`) or after
+//! `count_cache_ttl_secs`.
+//!
+//! The empty filter, "everything in this cell", is the common case and is
+//! answered from a per-scope total instead: counted once, then kept up to
+//! date as regular events are stored. Replaceable, addressable and deletion
+//! events change the total by an unknown amount, so they drop it to be
+//! recounted, as does `TOTAL_RECOUNT` passing, which bounds the drift from
+//! background deletions (retention, quotas, expiration).
+//!
+//! Events hidden by tombstones (`deletion_mode = tombstone`) and expired
+//! ones the sweeper hasn't deleted yet are still stored, so they are
+//! subtracted from whatever the cache answers.
+//!
+//! COUNTs never reach relay_builder, so `ReadChecks` runs the processor's
+//! `verify_filters` on them first: read auth and the `#g` limits apply as
+//! for a REQ. COUNTs that could match direct messages are refused, since
+//! who may see those depends on the connection.
+
+use anyhow::Result;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use relay_builder::{InboundContext, InboundProcessor, NostrMiddleware};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+use crate::config::RelayConfig;
+use crate::expirations::Expirations;
+use crate::live::{LiveEvents, StoredEvent};
+use crate::processor::ConnectionState;
+use crate::read_checks::ReadChecks;
+use crate::scope_policy::{DefaultScopePolicy, ScopePolicy};
+use crate::scope_residency::ScopeResources;
+use crate::store::ScopeStore;
+use crate::tombstones::Tombstones;
+
+/// Longest a scope total is trusted before it is recounted
+const TOTAL_RECOUNT: Duration = Duration::from_secs(300);
+
+struct CachedCount {
+    count: usize,
+    stored_at: Instant,
+}
+
+#[derive(Default)]
+struct CountState {
+    entries: HashMap<Scope, HashMap<String, CachedCount>>,
+    len: usize,
+    /// Events per scope, for the empty filter
+    totals: HashMap<Scope, CachedCount>,
+    /// Bumped on invalidation so counts racing a write aren't cached
+    generations: HashMap<Scope, u64>,
+}
+
+/// Event counts per scope and filter
+pub struct CountCache {
+    capacity: usize,
+    ttl: Duration,
+    state: Mutex<CountState>,
+}
+
+/// Whether `filter` counts every event in its scope
+pub fn is_whole_scope(filter: &Filter) -> bool {
+    *filter == Filter::new()
+}
+
+/// Whether `filter` names a DM kind
+pub fn counts_direct_messages(filter: &Filter) -> bool {
+    filter
+        .kinds
+        .as_ref()
+        .is_some_and(|kinds| kinds.contains(&Kind::EncryptedDirectMessage) || kinds.contains(&Kind::GiftWrap))
+}
+
+/// Whether storing `event` changes its scope's total by exactly one
+fn adds_one(event: &Event) -> bool {
+    !(event.kind.is_replaceable() || event.kind.is_addressable() || event.kind == Kind::EventDeletion)
+}
+
+impl CountCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            state: Mutex::new(CountState::default()),
+        }
+    }
+
+    pub fn for_config(config: &RelayConfig) -> Self {
+        Self::new(config.count_cache_size, Duration::from_secs(config.count_cache_ttl_secs))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Events matching `filter` in `scope`
+    ///
+    /// Counts in `store` only on a miss, or always while the cache is disabled.
+    pub async fn count(&self, store: &dyn ScopeStore, scope: &Scope, filter: &Filter) -> Result<usize> {
+        if !self.is_enabled() {
+            return store.count(scope, filter.clone()).await;
+        }
+        if is_whole_scope(filter) {
+            return self.total(store, scope).await;
+        }
+
+        let key = filter.as_json();
+        let generation = {
+            let state = self.state.lock();
+            let fresh = state
+                .entries
+                .get(scope)
+                .and_then(|entries| entries.get(&key))
+                .filter(|cached| cached.stored_at.elapsed() < self.ttl)
+                .map(|cached| cached.count);
+            if let Some(count) = fresh {
+                metrics::counter!("relay_count_cache_hits_total").increment(1);
+                return Ok(count);
+            }
+            state.generations.get(scope).copied().unwrap_or_default()
+        };
+        metrics::counter!("relay_count_cache_misses_total").increment(1);
+
+        let count = store.count(scope, filter.clone()).await?;
+        let mut state = self.state.lock();
+        if state.generations.get(scope).copied().unwrap_or_default() == generation {
+            self.insert(&mut state, scope, key, count);
+        }
+        Ok(count)
+    }
+
+    /// Every event in `scope`, from the maintained total when there is one
+    async fn total(&self, store: &dyn ScopeStore, scope: &Scope) -> Result<usize> {
+        let generation = {
+            let state = self.state.lock();
+            let fresh = state
+                .totals
+                .get(scope)
+                .filter(|total| total.stored_at.elapsed() < TOTAL_RECOUNT)
+                .map(|total| total.count);
+            if let Some(count) = fresh {
+                metrics::counter!("relay_count_cache_hits_total").increment(1);
+                return Ok(count);
+            }
+            state.generations.get(scope).copied().unwrap_or_default()
+        };
+        metrics::counter!("relay_count_cache_misses_total").increment(1);
+
+        let count = store.count(scope, Filter::new()).await?;
+        let mut state = self.state.lock();
+        if state.generations.get(scope).copied().unwrap_or_default() == generation {
+            state.totals.insert(scope.clone(), CachedCount { count, stored_at: Instant::now() });
+        }
+        Ok(count)
+    }
+
+    fn insert(&self, state: &mut CountState, scope: &Scope, key: String, count: usize) {
+        let replaced = state
+            .entries
+            .entry(scope.clone())
+            .or_default()
+            .insert(key, CachedCount { count, stored_at: Instant::now() });
+        if replaced.is_none() {
+            state.len += 1;
+        }
+        while state.len > self.capacity {
+            // Drop the oldest entry; the cache is small enough to scan
+            let oldest = state
+                .entries
+                .iter()
+                .flat_map(|(scope, entries)| entries.iter().map(move |(key, cached)| (scope, key, cached.stored_at)))
+                .min_by_key(|(_, _, stored_at)| *stored_at)
+                .map(|(scope, key, _)| (scope.clone(), key.clone()));
+            let Some((scope, key)) = oldest else { break };
+            if let Some(entries) = state.entries.get_mut(&scope) {
+                entries.remove(&key);
+                if entries.is_empty() {
+                    state.entries.remove(&scope);
+                }
+            }
+            state.len -= 1;
+        }
+    }
+
+    /// Accounts for an event stored in its scope
+    pub fn record(&self, stored: &StoredEvent) {
+        if stored.event.kind.is_ephemeral() {
+            return;
+        }
+        let mut state = self.state.lock();
+        Self::drop_entries(&mut state, &stored.scope);
+        if adds_one(&stored.event) {
+            if let Some(total) = state.totals.get_mut(&stored.scope) {
+                total.count += 1;
+            }
+        } else {
+            state.totals.remove(&stored.scope);
+        }
+    }
+
+    fn drop_entries(state: &mut CountState, scope: &Scope) {
+        *state.generations.entry(scope.clone()).or_default() += 1;
+        if let Some(entries) = state.entries.remove(scope) {
+            state.len -= entries.len();
+        }
+    }
+
+    /// Drops every cached count for `scope`, including its total
+    pub fn invalidate(&self, scope: &Scope) {
+        let mut state = self.state.lock();
+        Self::drop_entries(&mut state, scope);
+        state.totals.remove(scope);
+    }
+
+    /// Cached filter counts, not counting scope totals
+    pub fn len(&self) -> usize {
+        self.state.lock().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl ScopeResources for CountCache {
+    fn release(&self, scope: &Scope) {
+        self.invalidate(scope);
+    }
+}
+
+/// Keeps cached counts in step with stored events
+pub fn spawn_count_invalidation(cache: Arc<CountCache>, live: &LiveEvents) {
+    let mut receiver = live.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(stored) => cache.record(&stored),
+                Err(RecvError::Lagged(skipped)) => {
+                    // Can't tell which scopes changed, so start over
+                    warn!("Count cache missed {} stored events, clearing it", skipped);
+                    let scopes: Vec<Scope> = {
+                        let state = cache.state.lock();
+                        state.entries.keys().chain(state.totals.keys()).cloned().collect()
+                    };
+                    for scope in scopes {
+                        cache.invalidate(&scope);
+                    }
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Answers NIP-45 COUNTs for the connection's scope
+#[derive(Clone)]
+pub struct CountMiddleware<P = DefaultScopePolicy> {
+    cache: Arc<CountCache>,
+    store: Arc<dyn ScopeStore>,
+    /// Runs the filter checks relay_builder would have run on the COUNT
+    read_checks: ReadChecks<P>,
+    tombstones: Arc<Tombstones>,
+    expirations: Arc<Expirations>,
+}
+
+impl<P: ScopePolicy> CountMiddleware<P> {
+    pub fn new(cache: Arc<CountCache>, store: Arc<dyn ScopeStore>, read_checks: ReadChecks<P>) -> Self {
+        Self {
+            cache,
+            store,
+            read_checks,
+            tombstones: Arc::new(Tombstones::disabled()),
+            expirations: Arc::new(Expirations::in_memory()),
+        }
+    }
+
+    /// Leaves events hidden by tombstones out of the counts
+    pub fn with_tombstones(mut self, tombstones: Arc<Tombstones>) -> Self {
+        self.tombstones = tombstones;
+        self
+    }
+
+    /// Leaves expired events the sweeper hasn't deleted yet out of the counts
+    pub fn with_expirations(mut self, expirations: Arc<Expirations>) -> Self {
+        self.expirations = expirations;
+        self
+    }
+
+    /// Events matching `filter` in `scope` that aren't hidden or expired
+    async fn count(&self, scope: &Scope, filter: &Filter) -> Result<usize> {
+        let count = self.cache.count(self.store.as_ref(), scope, filter).await?;
+        let mut hidden: HashSet<EventId> = self.tombstones.hidden_in(scope).into_iter().collect();
+        let now = Timestamp::now().as_u64();
+        hidden.extend(self.expirations.due(now).into_iter().filter(|(due, _)| due == scope).map(|(_, id)| id));
+        if hidden.is_empty() {
+            return Ok(count);
+        }
+        // Still stored, so the cached count includes whichever of them match
+        let stored = self.store.query(scope, Filter::new().ids(hidden)).await?;
+        Ok(count.saturating_sub(stored.iter().filter(|event| filter.match_event(event)).count()))
+    }
+}
+
+impl<P: ScopePolicy + Clone> NostrMiddleware<ConnectionState> for CountMiddleware<P> {
+    async fn process_inbound<Next>(&self, ctx: InboundContext<'_, ConnectionState, Next>) -> Result<(), anyhow::Error>
+    where
+        Next: InboundProcessor<ConnectionState>,
+    {
+        let request = match &ctx.message {
+            Some(ClientMessage::Count { subscription_id, filter }) => {
+                Some((SubscriptionId::clone(subscription_id), Filter::clone(filter)))
+            }
+            _ => None,
+        };
+        let Some((subscription_id, filter)) = request else {
+            return ctx.next().await;
+        };
+
+        if counts_direct_messages(&filter) {
+            let message = "restricted: direct messages can't be counted".to_string();
+            ctx.send_message(RelayMessage::closed(subscription_id, message))?;
+            return Ok(());
+        }
+        let reader = {
+            let state = ctx.state.read();
+            self.read_checks.reader(state.subdomain.clone(), state.authed_pubkey, &state.custom)
+        };
+        // Read auth and the #g limits, as for a REQ
+        if let Err(message) = reader.verify(std::slice::from_ref(&filter)) {
+            ctx.send_message(RelayMessage::closed(subscription_id, message))?;
+            return Ok(());
+        }
+        let scope = reader.scope().clone();
+        match self.count(&scope, &filter).await {
+            Ok(count) => {
+                debug!("COUNT {} in {:?}: {}", subscription_id, scope, count);
+                ctx.send_message(RelayMessage::count(subscription_id, count))?;
+            }
+            Err(e) => {
+                warn!("COUNT {} failed: {}", subscription_id, e);
+                ctx.send_message(RelayMessage::closed(subscription_id, "error: could not count events".to_string()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use futures::future::BoxFuture;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// `MemoryStore` that counts counts
+    #[derive(Default)]
+    struct CountingStore {
+        inner: MemoryStore,
+        counts: AtomicUsize,
+    }
+
+    impl ScopeStore for CountingStore {
+        fn query(&self, scope: &Scope, filter: Filter) -> BoxFuture<'_, Result<Vec<Event>>> {
+            self.inner.query(scope, filter)
+        }
+
+        fn count(&self, scope: &Scope, filter: Filter) -> BoxFuture<'_, Result<usize>> {
+            self.counts.fetch_add(1, Ordering::SeqCst);
+            self.inner.count(scope, filter)
+        }
+
+        fn scopes(&self) -> BoxFuture<'_, Result<Vec<Scope>>> {
+            self.inner.scopes()
+        }
+
+        fn save(&self, scope: &Scope, event: Event) -> BoxFuture<'_, Result<()>> {
+            self.inner.save(scope, event)
+        }
+
+        fn delete(&self, scope: &Scope, id: EventId) -> BoxFuture<'_, Result<()>> {
+            self.inner.delete(scope, id)
+        }
+    }
+
+    fn cache() -> CountCache {
+        CountCache::new(100, Duration::from_secs(60))
+    }
+
+    async fn event(keys: &Keys, kind: u16, content: &str) -> Event {
+        EventBuilder::new(Kind::from(kind), content).sign(keys).await.unwrap()
+    }
+
+    /// Stores `event` and tells the cache, as the invalidation task would
+    fn store_event(store: &CountingStore, cache: &CountCache, scope: &Scope, event: Event) {
+        store.inner.insert(scope, event.clone());
+        cache.record(&StoredEvent { scope: scope.clone(), event });
+    }
+
+    #[tokio::test]
+    async fn test_repeated_counts_hit_the_cache() {
+        let store = CountingStore::default();
+        let drt2z = Scope::named("drt2z").unwrap();
+        store.inner.insert(&drt2z, event(&Keys::generate(), 1, "hi").await);
+        let cache = cache();
+        let filter = Filter::new().kind(Kind::TextNote);
+
+        assert_eq!(cache.count(&store, &drt2z, &filter).await.unwrap(), 1);
+        assert_eq!(cache.count(&store, &drt2z, &filter).await.unwrap(), 1);
+        assert_eq!(store.counts.load(Ordering::SeqCst), 1);
+
+        // Other scopes and filters have their own entries
+        cache.count(&store, &Scope::named("9q8yy").unwrap(), &filter).await.unwrap();
+        cache.count(&store, &drt2z, &Filter::new().kind(Kind::Metadata)).await.unwrap();
+        assert_eq!(store.counts.load(Ordering::SeqCst), 3);
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_stored_event_invalidates_the_scope() {
+        let store = CountingStore::default();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let keys = Keys::generate();
+        store.inner.insert(&drt2z, event(&keys, 1, "first").await);
+        let cache = Arc::new(cache());
+        let live = LiveEvents::new();
+        spawn_count_invalidation(cache.clone(), &live);
+        let filter = Filter::new().kind(Kind::TextNote);
+        assert_eq!(cache.count(&store, &drt2z, &filter).await.unwrap(), 1);
+
+        let second = event(&keys, 1, "second").await;
+        store.inner.insert(&drt2z, second.clone());
+        live.publish(StoredEvent { scope: drt2z.clone(), event: second });
+        for _ in 0..100 {
+            if cache.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(cache.count(&store, &drt2z, &filter).await.unwrap(), 2);
+        assert_eq!(store.counts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_whole_scope_total_matches_brute_force() {
+        let store = CountingStore::default();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let alice = Keys::generate();
+        let bob = Keys::generate();
+        for i in 0..20 {
+            store.inner.insert(&drt2z, event(&alice, 1, &format!("seed {}", i)).await);
+        }
+        store.inner.insert(&Scope::Default, event(&bob, 1, "elsewhere").await);
+        let cache = cache();
+        let brute_force = |store: &CountingStore| store.inner.query(&drt2z, Filter::new());
+
+        assert_eq!(cache.count(&store, &drt2z, &Filter::new()).await.unwrap(), 20);
+
+        // Regular events keep the total without recounting
+        for i in 0..5 {
+            store_event(&store, &cache, &drt2z, event(&bob, 20000, &format!("chat {}", i)).await);
+        }
+        // Ephemeral events are broadcast but never stored
+        cache.record(&StoredEvent { scope: drt2z.clone(), event: event(&bob, 20001, "ephemeral").await });
+        assert_eq!(cache.count(&store, &drt2z, &Filter::new()).await.unwrap(), brute_force(&store).await.unwrap().len());
+        assert_eq!(store.counts.load(Ordering::SeqCst), 1);
+
+        // A replaceable event may replace another, so the next COUNT recounts
+        store_event(&store, &cache, &drt2z, event(&alice, 0, "{}").await);
+        store_event(&store, &cache, &drt2z, event(&alice, 0, "{\"name\":\"alice\"}").await);
+        assert_eq!(cache.count(&store, &drt2z, &Filter::new()).await.unwrap(), brute_force(&store).await.unwrap().len());
+        assert_eq!(store.counts.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.count(&store, &Scope::Default, &Filter::new()).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_cache_always_counts() {
+        let store = CountingStore::default();
+        let cache = CountCache::new(0, Duration::from_secs(60));
+        cache.count(&store, &Scope::Default, &Filter::new()).await.unwrap();
+        cache.count(&store, &Scope::Default, &Filter::new()).await.unwrap();
+        assert_eq!(store.counts.load(Ordering::SeqCst), 2);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_filter_classification() {
+        assert!(is_whole_scope(&Filter::new()));
+        assert!(!is_whole_scope(&Filter::new().kind(Kind::TextNote)));
+        assert!(counts_direct_messages(&Filter::new().kinds([Kind::TextNote, Kind::GiftWrap])));
+        assert!(!counts_direct_messages(&Filter::new()));
+    }
+}
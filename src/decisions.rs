@@ -0,0 +1,108 @@
+//! Structured logs and metrics for the processor's routing decisions
+//!
+//! `handle_event` leaves the `ScopeDecision` it made in
+//! `ConnectionState::last_decision`. `DecisionMiddleware` clears it before an
+//! EVENT goes down the chain and reads it once the processor has run, so
+//! "OK false because of the wrong scope" reaches logs and
+//! `relay_event_decisions_total{outcome, reason, scope_type}` as a reason
+//! code instead of an OK message to parse. EVENTs refused before the
+//! processor (rate limits, auth) leave no decision behind.
+
+use anyhow::Result;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::{InboundContext, InboundProcessor, NostrMiddleware};
+use tracing::debug;
+use crate::processor::ConnectionState;
+use crate::routing::ScopeDecision;
+use crate::store::scope_label;
+
+/// Labels of `relay_event_decisions_total` for one decision
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecisionLabels {
+    /// "stored" or "rejected"
+    pub outcome: &'static str,
+    /// Reject reason code, "none" for stored events
+    pub reason: &'static str,
+    /// "root" or "geohash": where the event went, or where it was refused
+    pub scope_type: &'static str,
+}
+
+impl DecisionLabels {
+    /// Labels for `decision` made on a connection to `connection_scope`
+    pub fn new(decision: &ScopeDecision, connection_scope: &Scope) -> Self {
+        let scope_type = |scope: &Scope| if matches!(scope, Scope::Default) { "root" } else { "geohash" };
+        match decision {
+            ScopeDecision::Store(scope) => Self { outcome: "stored", reason: "none", scope_type: scope_type(scope) },
+            ScopeDecision::Reject(reason) => Self {
+                outcome: "rejected",
+                reason: reason.code(),
+                scope_type: scope_type(connection_scope),
+            },
+        }
+    }
+}
+
+/// Reports each EVENT's routing decision
+#[derive(Debug, Clone, Default)]
+pub struct DecisionMiddleware;
+
+impl NostrMiddleware<ConnectionState> for DecisionMiddleware {
+    async fn process_inbound<Next>(&self, ctx: InboundContext<'_, ConnectionState, Next>) -> Result<(), anyhow::Error>
+    where
+        Next: InboundProcessor<ConnectionState>,
+    {
+        let event_id = match &ctx.message {
+            Some(ClientMessage::Event(event)) => event.id,
+            _ => return ctx.next().await,
+        };
+        // A decision left over from an earlier EVENT mustn't be reported for this one
+        ctx.state.write().custom.last_decision = None;
+        let result = ctx.next().await;
+
+        let (decision, scope) = {
+            let state = ctx.state.read();
+            (state.custom.last_decision.clone(), state.subdomain.as_ref().clone())
+        };
+        if let Some(decision) = decision {
+            let labels = DecisionLabels::new(&decision, &scope);
+            debug!(
+                event_id = %event_id,
+                outcome = labels.outcome,
+                reason = labels.reason,
+                scope = %match &decision {
+                    ScopeDecision::Store(stored) => scope_label(stored),
+                    ScopeDecision::Reject(_) => scope_label(&scope),
+                },
+                "event decision"
+            );
+            metrics::counter!(
+                "relay_event_decisions_total",
+                "outcome" => labels.outcome,
+                "reason" => labels.reason,
+                "scope_type" => labels.scope_type
+            )
+            .increment(1);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reject::RejectReason;
+
+    #[test]
+    fn test_labels_carry_reason_codes() {
+        let cell = Scope::named("drt2z").unwrap();
+        assert_eq!(
+            DecisionLabels::new(&ScopeDecision::Store(Scope::Default), &cell),
+            DecisionLabels { outcome: "stored", reason: "none", scope_type: "root" }
+        );
+        assert_eq!(
+            DecisionLabels::new(&ScopeDecision::Reject(RejectReason::RateLimited), &cell),
+            DecisionLabels { outcome: "rejected", reason: "rate-limited", scope_type: "geohash" }
+        );
+    }
+}
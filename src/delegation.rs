@@ -0,0 +1,203 @@
+//! NIP-26 delegated events
+//!
+//! A shared identity (a venue, a community account) can let other keys post
+//! on its behalf by signing a token over the delegatee's pubkey and a
+//! conditions string, carried as
+//! `["delegation", <delegator>, <conditions>, <token>]`. When the tag is
+//! present and valid the delegator is the effective author for policy
+//! checks such as paid-scope admissions; when it is present and invalid
+//! (bad signature, malformed or unmet conditions) the event is refused.
+
+use nostr::hashes::{sha256, Hash};
+use nostr::secp256k1::{schnorr::Signature, Message, XOnlyPublicKey};
+use nostr_sdk::prelude::*;
+use std::fmt;
+use std::str::FromStr;
+
+/// Name of the NIP-26 tag
+pub const DELEGATION_TAG: &str = "delegation";
+
+/// Why a delegation tag doesn't hold
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DelegationError {
+    /// Wrong arity, or a field that doesn't parse
+    Malformed,
+    /// The token isn't the delegator's signature for this delegatee
+    BadSignature,
+    /// The conditions string has an unknown or malformed clause
+    BadConditions(String),
+    /// The event's kind or created_at is outside the conditions
+    ConditionsNotMet,
+}
+
+impl fmt::Display for DelegationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DelegationError::Malformed => f.write_str("malformed delegation tag"),
+            DelegationError::BadSignature => f.write_str("delegation token signature doesn't verify"),
+            DelegationError::BadConditions(clause) => write!(f, "unsupported delegation condition '{}'", clause),
+            DelegationError::ConditionsNotMet => f.write_str("event is outside the delegation conditions"),
+        }
+    }
+}
+
+/// Parsed `kind=1&created_at>…&created_at<…` string
+///
+/// Kind clauses are alternatives; time bounds are exclusive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Conditions {
+    pub kinds: Vec<u16>,
+    pub created_after: Option<u64>,
+    pub created_before: Option<u64>,
+}
+
+impl FromStr for Conditions {
+    type Err = DelegationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut conditions = Conditions::default();
+        for clause in s.split('&').filter(|clause| !clause.is_empty()) {
+            let bad = || DelegationError::BadConditions(clause.to_string());
+            if let Some(kind) = clause.strip_prefix("kind=") {
+                conditions.kinds.push(kind.parse().map_err(|_| bad())?);
+            } else if let Some(after) = clause.strip_prefix("created_at>") {
+                let after = after.parse().map_err(|_| bad())?;
+                conditions.created_after = Some(conditions.created_after.map_or(after, |t: u64| t.max(after)));
+            } else if let Some(before) = clause.strip_prefix("created_at<") {
+                let before = before.parse().map_err(|_| bad())?;
+                conditions.created_before = Some(conditions.created_before.map_or(before, |t: u64| t.min(before)));
+            } else {
+                return Err(bad());
+            }
+        }
+        Ok(conditions)
+    }
+}
+
+impl Conditions {
+    pub fn allows(&self, kind: u16, created_at: u64) -> bool {
+        (self.kinds.is_empty() || self.kinds.contains(&kind))
+            && !matches!(self.created_after, Some(after) if created_at <= after)
+            && !matches!(self.created_before, Some(before) if created_at >= before)
+    }
+}
+
+/// Digest the delegator signs: `sha256("nostr:delegation:<delegatee>:<conditions>")`
+pub fn token_message(delegatee: &PublicKey, conditions: &str) -> Message {
+    let token = format!("nostr:delegation:{}:{}", delegatee.to_hex(), conditions);
+    Message::from_digest(sha256::Hash::hash(token.as_bytes()).to_byte_array())
+}
+
+/// The delegator an event validly speaks for, `None` without a delegation tag
+pub fn delegator(event: &Event) -> Result<Option<PublicKey>, DelegationError> {
+    let Some(tag) = event
+        .tags
+        .iter()
+        .map(|tag| tag.as_slice())
+        .find(|tag| tag.first().map(String::as_str) == Some(DELEGATION_TAG))
+    else {
+        return Ok(None);
+    };
+    let [_, delegator, conditions, token] = tag else {
+        return Err(DelegationError::Malformed);
+    };
+
+    let delegator = PublicKey::from_hex(delegator).map_err(|_| DelegationError::Malformed)?;
+    let key = XOnlyPublicKey::from_slice(&delegator.to_bytes()).map_err(|_| DelegationError::Malformed)?;
+    let signature = Signature::from_str(token).map_err(|_| DelegationError::Malformed)?;
+    nostr::SECP256K1
+        .verify_schnorr(&signature, &token_message(&event.pubkey, conditions), &key)
+        .map_err(|_| DelegationError::BadSignature)?;
+
+    let conditions: Conditions = conditions.parse()?;
+    if !conditions.allows(event.kind.as_u16(), event.created_at.as_u64()) {
+        return Err(DelegationError::ConditionsNotMet);
+    }
+    Ok(Some(delegator))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONDITIONS: &str = "kind=1&created_at>1700000000&created_at<1800000000";
+
+    /// Event by `delegatee` carrying a delegation from `delegator` signed
+    /// over `signed_conditions` but claiming `conditions`
+    fn delegated(
+        delegator: &Keys,
+        delegatee: &Keys,
+        signed_conditions: &str,
+        conditions: &str,
+        kind: u16,
+        created_at: u64,
+    ) -> Event {
+        let token = delegator.sign_schnorr(&token_message(&delegatee.public_key(), signed_conditions));
+        EventBuilder::new(Kind::from(kind), "posted for the venue")
+            .tag(Tag::parse([
+                DELEGATION_TAG,
+                &delegator.public_key().to_hex(),
+                conditions,
+                &token.to_string(),
+            ]).unwrap())
+            .custom_created_at(Timestamp::from(created_at))
+            .sign_with_keys(delegatee)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_valid_delegation_names_the_delegator() {
+        let venue = Keys::generate();
+        let poster = Keys::generate();
+        let event = delegated(&venue, &poster, CONDITIONS, CONDITIONS, 1, 1_750_000_000);
+        assert_eq!(delegator(&event), Ok(Some(venue.public_key())));
+
+        let plain = EventBuilder::text_note("no delegation").sign_with_keys(&poster).unwrap();
+        assert_eq!(delegator(&plain), Ok(None));
+    }
+
+    #[test]
+    fn test_conditions_are_enforced() {
+        let venue = Keys::generate();
+        let poster = Keys::generate();
+        for (kind, created_at) in [(7, 1_750_000_000), (1, 1_700_000_000), (1, 1_650_000_000), (1, 1_800_000_000)] {
+            let event = delegated(&venue, &poster, CONDITIONS, CONDITIONS, kind, created_at);
+            assert_eq!(delegator(&event), Err(DelegationError::ConditionsNotMet), "{} {}", kind, created_at);
+        }
+
+        // Several kinds are alternatives
+        let either = "kind=1&kind=7";
+        let event = delegated(&venue, &poster, either, either, 7, 1_750_000_000);
+        assert_eq!(delegator(&event), Ok(Some(venue.public_key())));
+    }
+
+    #[test]
+    fn test_forged_or_malformed_delegations_fail() {
+        let venue = Keys::generate();
+        let poster = Keys::generate();
+
+        // Conditions widened after signing
+        let event = delegated(&venue, &poster, CONDITIONS, "kind=1", 1, 1_750_000_000);
+        assert_eq!(delegator(&event), Err(DelegationError::BadSignature));
+
+        // Token issued to someone else
+        let other = Keys::generate();
+        let token = venue.sign_schnorr(&token_message(&other.public_key(), CONDITIONS));
+        let event = EventBuilder::text_note("borrowed token")
+            .tag(Tag::parse([DELEGATION_TAG, &venue.public_key().to_hex(), CONDITIONS, &token.to_string()]).unwrap())
+            .custom_created_at(Timestamp::from(1_750_000_000))
+            .sign_with_keys(&poster)
+            .unwrap();
+        assert_eq!(delegator(&event), Err(DelegationError::BadSignature));
+
+        let event = EventBuilder::text_note("short tag")
+            .tag(Tag::parse([DELEGATION_TAG, &venue.public_key().to_hex()]).unwrap())
+            .sign_with_keys(&poster)
+            .unwrap();
+        assert_eq!(delegator(&event), Err(DelegationError::Malformed));
+
+        let odd = "kind=1&tags=p";
+        let event = delegated(&venue, &poster, odd, odd, 1, 1_750_000_000);
+        assert_eq!(delegator(&event), Err(DelegationError::BadConditions("tags=p".to_string())));
+    }
+}
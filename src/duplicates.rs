@@ -0,0 +1,250 @@
+//! Near-duplicate content filter per scope
+//!
+//! Spam floods post the same text from rotating keys, so per-pubkey limits
+//! never trigger. `DuplicateFilter` remembers each scope's recent events as
+//! an exact content hash plus a 64-bit simhash of its words. An event whose
+//! content matches (exactly, or within `MAX_SIMHASH_DISTANCE` bits) more than
+//! `duplicate_max_matches` of them, posted by more than
+//! `duplicate_max_pubkeys` distinct authors, is refused.
+//!
+//! Each scope keeps at most `duplicate_window_events` entries, none older
+//! than `duplicate_window_secs`. Refused events aren't remembered, so a flood
+//! can't lock a phrase out for longer than the window.
+
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::config::RelayConfig;
+use crate::scope_residency::ScopeResources;
+
+/// Fingerprints this many bits apart still count as the same text
+pub const MAX_SIMHASH_DISTANCE: u32 = 3;
+
+/// Content shorter than this ("+", "gm") is too generic to compare
+const MIN_CONTENT_CHARS: usize = 8;
+
+/// Scopes remembered before stale ones are dropped
+const MAX_TRACKED_SCOPES: usize = 10_000;
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// FNV-1a, stable across runs unlike `DefaultHasher`
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Simhash over lowercased words, so spacing, case and punctuation don't matter
+pub fn simhash(content: &str) -> u64 {
+    let mut weights = [0i32; 64];
+    for word in content.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()) {
+        let hash = fnv1a(word.to_lowercase().as_bytes());
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            *weight += if hash & (1 << bit) != 0 { 1 } else { -1 };
+        }
+    }
+    weights
+        .iter()
+        .enumerate()
+        .filter(|(_, weight)| **weight > 0)
+        .fold(0, |fingerprint, (bit, _)| fingerprint | (1 << bit))
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    at: u64,
+    hash: u64,
+    fingerprint: u64,
+    pubkey: PublicKey,
+}
+
+impl Entry {
+    fn matches(&self, hash: u64, fingerprint: u64) -> bool {
+        self.hash == hash || (self.fingerprint ^ fingerprint).count_ones() <= MAX_SIMHASH_DISTANCE
+    }
+}
+
+/// Recent content per scope
+#[derive(Debug)]
+pub struct DuplicateFilter {
+    window_secs: u64,
+    window_events: usize,
+    max_matches: usize,
+    max_pubkeys: usize,
+    exempt_kinds: HashSet<u16>,
+    scopes: Mutex<HashMap<Scope, VecDeque<Entry>>>,
+}
+
+impl DuplicateFilter {
+    pub fn for_config(config: &RelayConfig) -> Self {
+        Self {
+            window_secs: config.duplicate_window_secs,
+            window_events: config.duplicate_window_events.max(1),
+            max_matches: config.duplicate_max_matches,
+            max_pubkeys: config.duplicate_max_pubkeys,
+            exempt_kinds: config.duplicate_exempt_kinds.iter().copied().collect(),
+            scopes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.window_secs > 0
+    }
+
+    /// False if `event` repeats too much recent content in `scope`;
+    /// otherwise remembers it
+    pub fn admit(&self, scope: &Scope, event: &Event) -> bool {
+        self.admit_at(scope, event, now_secs())
+    }
+
+    pub fn admit_at(&self, scope: &Scope, event: &Event, now: u64) -> bool {
+        if !self.is_enabled()
+            || self.exempt_kinds.contains(&event.kind.as_u16())
+            || event.content.trim().chars().count() < MIN_CONTENT_CHARS
+        {
+            return true;
+        }
+        let hash = fnv1a(event.content.as_bytes());
+        let fingerprint = simhash(&event.content);
+        let cutoff = now.saturating_sub(self.window_secs);
+
+        let mut scopes = self.scopes.lock();
+        if scopes.len() >= MAX_TRACKED_SCOPES && !scopes.contains_key(scope) {
+            scopes.retain(|_, entries| entries.back().is_some_and(|entry| entry.at > cutoff));
+        }
+        let entries = scopes.entry(scope.clone()).or_default();
+        while entries.front().is_some_and(|entry| entry.at <= cutoff) {
+            entries.pop_front();
+        }
+
+        let matching: Vec<&Entry> = entries.iter().filter(|entry| entry.matches(hash, fingerprint)).collect();
+        let pubkeys: HashSet<&PublicKey> = matching.iter().map(|entry| &entry.pubkey).collect();
+        if matching.len() > self.max_matches && pubkeys.len() > self.max_pubkeys {
+            metrics::counter!("relay_duplicate_content_blocked_total").increment(1);
+            return false;
+        }
+
+        entries.push_back(Entry { at: now, hash, fingerprint, pubkey: event.pubkey });
+        if entries.len() > self.window_events {
+            entries.pop_front();
+        }
+        true
+    }
+}
+
+impl ScopeResources for DuplicateFilter {
+    /// Keeps a window that still has entries young enough to match
+    fn release(&self, scope: &Scope) {
+        let cutoff = now_secs().saturating_sub(self.window_secs);
+        let mut scopes = self.scopes.lock();
+        if scopes.get(scope).is_some_and(|entries| !entries.back().is_some_and(|entry| entry.at > cutoff)) {
+            scopes.remove(scope);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter() -> DuplicateFilter {
+        DuplicateFilter::for_config(&RelayConfig {
+            duplicate_window_secs: 600,
+            ..Default::default()
+        })
+    }
+
+    async fn note(content: &str) -> Event {
+        EventBuilder::text_note(content).sign(&Keys::generate()).await.unwrap()
+    }
+
+    const SPAM: &str = "Claim your free sats now at totally-legit.example";
+
+    #[tokio::test]
+    async fn test_same_content_from_many_keys_is_blocked() {
+        let filter = filter();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let mut admitted = Vec::new();
+        for _ in 0..5 {
+            admitted.push(filter.admit_at(&drt2z, &note(SPAM).await, 1000));
+        }
+        assert_eq!(admitted, vec![true, true, true, false, false]);
+
+        // Other scopes keep their own window
+        assert!(filter.admit_at(&Scope::Default, &note(SPAM).await, 1000));
+    }
+
+    #[tokio::test]
+    async fn test_near_duplicates_are_blocked() {
+        let filter = filter();
+        let drt2z = Scope::named("drt2z").unwrap();
+        for _ in 0..3 {
+            assert!(filter.admit_at(&drt2z, &note(SPAM).await, 1000));
+        }
+        let reworded = "CLAIM your free sats now at totally-legit.example!!!";
+        assert!(!filter.admit_at(&drt2z, &note(reworded).await, 1000));
+    }
+
+    #[tokio::test]
+    async fn test_distinct_content_passes() {
+        let filter = filter();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let posts = [
+            "Farmers market on the square is open until noon today",
+            "Anyone know why the tram is stopped at the bridge?",
+            "Lost a grey cat near the library, answers to Pixel",
+            "Free piano lessons for kids this Saturday at the church hall",
+            "Road closed on Elm street for the parade, take the bypass",
+        ];
+        for content in posts {
+            assert!(filter.admit_at(&drt2z, &note(content).await, 1000), "{}", content);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_one_author_repeating_is_not_this_filters_job() {
+        let filter = filter();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let keys = Keys::generate();
+        for _ in 0..10 {
+            let event = EventBuilder::text_note(SPAM).sign(&keys).await.unwrap();
+            assert!(filter.admit_at(&drt2z, &event, 1000));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_window_expires() {
+        let filter = filter();
+        let drt2z = Scope::named("drt2z").unwrap();
+        for _ in 0..3 {
+            assert!(filter.admit_at(&drt2z, &note(SPAM).await, 1000));
+        }
+        assert!(!filter.admit_at(&drt2z, &note(SPAM).await, 1599));
+        assert!(filter.admit_at(&drt2z, &note(SPAM).await, 1600));
+    }
+
+    #[tokio::test]
+    async fn test_exempt_kinds_and_short_content_pass() {
+        let filter = filter();
+        let drt2z = Scope::named("drt2z").unwrap();
+        for _ in 0..10 {
+            let reaction = EventBuilder::new(Kind::Reaction, SPAM).sign(&Keys::generate()).await.unwrap();
+            assert!(filter.admit_at(&drt2z, &reaction, 1000));
+            assert!(filter.admit_at(&drt2z, &note("gm").await, 1000));
+        }
+    }
+
+    #[test]
+    fn test_simhash_ignores_case_and_punctuation() {
+        assert_eq!(simhash("Hello, World"), simhash("hello world!"));
+        assert_ne!(simhash("hello world"), simhash("goodbye moon"));
+    }
+}
@@ -0,0 +1,213 @@
+//! Relay-side default expirations
+//!
+//! With `default_expiration_secs` (or a `scope_default_expiration_secs`
+//! entry for the scope's prefix) set, an event stored without a NIP-40
+//! `expiration` tag still goes away: the relay notes `received + default`
+//! as its effective expiration, hides it from REQs once that passes and
+//! lets the sweeper delete it. The signed event is never touched. Shorter
+//! client expirations stand; longer ones only get an earlier effective
+//! expiration when the cell TTL (`geohash_max_ttl_days`) applies to the
+//! event. With NIP-40 on, a tagged event's own expiration is noted as well,
+//! so the sweeper deletes it and COUNTs leave it out once it passes.
+//!
+//! Effective expirations are kept in an `AppendLog` under `database_path`,
+//! which is rewritten without the swept ones.
+
+use anyhow::Result;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+use crate::append_log::{AppendLog, LogEntry};
+use crate::config::RelayConfig;
+use crate::store::{scope_from_label, scope_label, ScopeStore};
+
+/// Effective expirations, next to the database
+pub const EXPIRATIONS_FILE: &str = "expirations.jsonl";
+
+/// Effective expiration for an event arriving at `now`, or `None` when
+/// the event's own tag (or nothing) decides
+///
+/// `default_secs` is the scope's default, `max_ttl_secs` the cell TTL for
+/// the event's kind.
+pub fn effective_expiration(event: &Event, default_secs: u64, max_ttl_secs: Option<u64>, now: u64) -> Option<u64> {
+    let ttl_limit = max_ttl_secs.map(|ttl| now.saturating_add(ttl));
+    match event.tags.expiration() {
+        None => {
+            let default = now.saturating_add(default_secs);
+            Some(ttl_limit.map_or(default, |limit| default.min(limit)))
+        }
+        Some(requested) => ttl_limit.filter(|limit| requested.as_u64() > *limit),
+    }
+}
+
+/// One line of the log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    id: EventId,
+    /// Scope label ("root" or the geohash)
+    scope: String,
+    expires_at: u64,
+}
+
+impl LogEntry for Entry {
+    type Key = EventId;
+
+    fn key(&self) -> EventId {
+        self.id
+    }
+}
+
+/// Effective expirations of stored events, optionally backed by a file
+#[derive(Debug, Default)]
+pub struct Expirations {
+    log: AppendLog<Entry>,
+}
+
+impl Expirations {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Ok(Self { log: AppendLog::open(path)? })
+    }
+
+    /// Expirations under `database_path`
+    pub fn for_config(config: &RelayConfig) -> Result<Self> {
+        Ok(Self { log: AppendLog::for_config(config, EXPIRATIONS_FILE)? })
+    }
+
+    /// For tests and tooling
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Notes that `id` in `scope` expires at `expires_at`
+    pub fn record(&self, scope: &Scope, id: EventId, expires_at: u64) {
+        self.log.insert(Entry { id, scope: scope_label(scope), expires_at });
+    }
+
+    /// Effective expiration of `id`, if the relay set one
+    pub fn expires_at(&self, id: &EventId) -> Option<u64> {
+        self.log.entries().get(id).map(|entry| entry.expires_at)
+    }
+
+    pub fn is_expired(&self, id: &EventId, now: u64) -> bool {
+        self.expires_at(id).is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Events whose effective expiration has passed, with their scopes
+    pub fn due(&self, now: u64) -> Vec<(Scope, EventId)> {
+        self.log
+            .entries()
+            .values()
+            .filter(|entry| entry.expires_at <= now)
+            .filter_map(|entry| Some((scope_from_label(&entry.scope)?, entry.id)))
+            .collect()
+    }
+
+    /// Drops `ids` and rewrites the log without them
+    pub fn forget(&self, ids: &[EventId]) -> Result<()> {
+        self.log.remove(ids)
+    }
+}
+
+/// Deletes every event whose effective expiration has passed, returning
+/// how many went
+pub async fn sweep(store: &dyn ScopeStore, expirations: &Expirations, now: u64) -> Result<usize> {
+    let due = expirations.due(now);
+    if due.is_empty() {
+        return Ok(0);
+    }
+    let mut deleted = Vec::with_capacity(due.len());
+    for (scope, id) in &due {
+        match store.delete(scope, *id).await {
+            Ok(()) => deleted.push(*id),
+            Err(e) => warn!("Failed to delete expired event {}: {}", id, e),
+        }
+    }
+    expirations.forget(&deleted)?;
+    Ok(deleted.len())
+}
+
+/// Runs `sweep` every `interval`
+pub fn spawn_expiration_task(store: Arc<dyn ScopeStore>, expirations: Arc<Expirations>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match sweep(store.as_ref(), &expirations, Timestamp::now().as_u64()).await {
+                Ok(0) => {}
+                Ok(deleted) => {
+                    info!("Deleted {} events past their default expiration", deleted);
+                    metrics::counter!("relay_retention_deleted_events_total").increment(deleted as u64);
+                }
+                Err(e) => warn!("Failed to sweep default expirations: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    const NOW: u64 = 1_700_000_000;
+    const HOUR: u64 = 3_600;
+
+    fn note(expiration: Option<u64>) -> Event {
+        let mut builder = EventBuilder::text_note("hello");
+        if let Some(at) = expiration {
+            builder = builder.tag(Tag::expiration(Timestamp::from(at)));
+        }
+        builder.sign_with_keys(&Keys::generate()).unwrap()
+    }
+
+    #[test]
+    fn test_absent_expiration_gets_the_default() {
+        assert_eq!(effective_expiration(&note(None), HOUR, None, NOW), Some(NOW + HOUR));
+        // The cell TTL still wins when it is shorter
+        assert_eq!(effective_expiration(&note(None), 24 * HOUR, Some(HOUR), NOW), Some(NOW + HOUR));
+    }
+
+    #[test]
+    fn test_shorter_client_expiration_is_honored() {
+        let event = note(Some(NOW + 60));
+        assert_eq!(effective_expiration(&event, HOUR, None, NOW), None);
+        assert_eq!(effective_expiration(&event, HOUR, Some(HOUR), NOW), None);
+    }
+
+    #[test]
+    fn test_longer_client_expiration_is_clamped_only_by_the_ttl() {
+        let event = note(Some(NOW + 30 * 24 * HOUR));
+        assert_eq!(effective_expiration(&event, HOUR, None, NOW), None);
+        assert_eq!(effective_expiration(&event, HOUR, Some(24 * HOUR), NOW), Some(NOW + 24 * HOUR));
+    }
+
+    #[tokio::test]
+    async fn test_sweep_deletes_due_events_and_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(EXPIRATIONS_FILE);
+        let store = MemoryStore::new();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let (old, fresh) = (note(None), note(None));
+        store.insert(&drt2z, old.clone());
+        store.insert(&drt2z, fresh.clone());
+
+        let expirations = Expirations::open(&path).unwrap();
+        expirations.record(&drt2z, old.id, NOW);
+        expirations.record(&drt2z, fresh.id, NOW + HOUR);
+        assert!(expirations.is_expired(&old.id, NOW));
+        assert!(!expirations.is_expired(&fresh.id, NOW));
+
+        assert_eq!(sweep(&store, &expirations, NOW).await.unwrap(), 1);
+        assert_eq!(store.count(&drt2z, Filter::new()).await.unwrap(), 1);
+        assert_eq!(expirations.expires_at(&old.id), None);
+        drop(expirations);
+
+        let reopened = Expirations::open(&path).unwrap();
+        assert_eq!(reopened.expires_at(&fresh.id), Some(NOW + HOUR));
+        assert_eq!(reopened.expires_at(&old.id), None);
+    }
+}
@@ -0,0 +1,179 @@
+//! Explicit, reported limits on REQ filters
+//!
+//! relay_builder caps every filter's `limit` at `max_limit_per_filter`
+//! without telling the client, so a client asking for 100000 events can't
+//! tell a clamp from an empty scope. `FilterLimitMiddleware` applies the cap
+//! itself, before anything queries the store, and tells the client the
+//! effective limit in a NOTICE.
+//!
+//! Filters without a limit get `default_filter_limit` on scopes that held
+//! more than `default_filter_limit_above_events` events at the last stats
+//! pass, so an accidental `{}` doesn't dump a whole busy cell. Each clamped
+//! REQ gets one NOTICE and counts once per reason in
+//! `relay_filter_limits_clamped_total{reason}`.
+
+use anyhow::Result;
+use nostr_sdk::prelude::*;
+use relay_builder::{InboundContext, InboundProcessor, NostrMiddleware};
+use std::sync::Arc;
+use tracing::debug;
+use crate::config::RelayConfig;
+use crate::processor::ConnectionState;
+use crate::stats::StatsCache;
+
+/// What `FilterLimits::apply` changed in a REQ
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Clamped {
+    /// Some limit was above `max_limit_per_filter`
+    pub over_max: bool,
+    /// Some filter without a limit got `default_filter_limit`
+    pub defaulted: bool,
+}
+
+impl Clamped {
+    pub fn any(&self) -> bool {
+        self.over_max || self.defaulted
+    }
+}
+
+/// The configured limits; 0 disables either
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FilterLimits {
+    pub max: usize,
+    pub default: usize,
+    /// Stored events above which a scope counts as large
+    pub large_scope_events: usize,
+}
+
+impl FilterLimits {
+    pub fn for_config(config: &RelayConfig) -> Self {
+        Self {
+            max: config.max_limit_per_filter,
+            default: config.default_filter_limit,
+            large_scope_events: config.default_filter_limit_above_events,
+        }
+    }
+
+    /// Clamps `filters` for a scope holding `scope_events` (unknown before
+    /// the first stats pass, when no default applies)
+    pub fn apply(&self, filters: &mut [Filter], scope_events: Option<usize>) -> Clamped {
+        let large = self.default > 0 && scope_events.is_some_and(|events| events > self.large_scope_events);
+        let mut clamped = Clamped::default();
+        for filter in filters.iter_mut() {
+            match filter.limit {
+                Some(limit) if self.max > 0 && limit > self.max => {
+                    filter.limit = Some(self.max);
+                    clamped.over_max = true;
+                }
+                None if large => {
+                    filter.limit = Some(self.default);
+                    clamped.defaulted = true;
+                }
+                _ => {}
+            }
+        }
+        clamped
+    }
+
+    /// NOTICE telling the client what `clamped` means for `subscription_id`
+    pub fn notice(&self, subscription_id: &SubscriptionId, clamped: Clamped) -> String {
+        let mut parts = Vec::new();
+        if clamped.over_max {
+            parts.push(format!("limits above {} were lowered to it (max_limit_per_filter)", self.max));
+        }
+        if clamped.defaulted {
+            parts.push(format!(
+                "filters without a limit return at most {} events on this scope (default_filter_limit)",
+                self.default
+            ));
+        }
+        format!("subscription {}: {}", subscription_id, parts.join("; "))
+    }
+}
+
+/// Clamps REQ limits and reports it
+#[derive(Debug, Clone)]
+pub struct FilterLimitMiddleware {
+    limits: FilterLimits,
+    stats: Arc<StatsCache>,
+}
+
+impl FilterLimitMiddleware {
+    pub fn new(limits: FilterLimits, stats: Arc<StatsCache>) -> Self {
+        Self { limits, stats }
+    }
+}
+
+impl NostrMiddleware<ConnectionState> for FilterLimitMiddleware {
+    async fn process_inbound<Next>(&self, mut ctx: InboundContext<'_, ConnectionState, Next>) -> Result<(), anyhow::Error>
+    where
+        Next: InboundProcessor<ConnectionState>,
+    {
+        if !matches!(&ctx.message, Some(ClientMessage::Req { .. })) {
+            return ctx.next().await;
+        }
+        let scope = ctx.state.read().subdomain.as_ref().clone();
+        let scope_events = self.stats.aggregates(&scope).map(|aggregates| aggregates.stored_events);
+        let (subscription_id, clamped) = match ctx.message.as_mut() {
+            Some(ClientMessage::Req { subscription_id, filters }) => {
+                (SubscriptionId::clone(subscription_id), self.limits.apply(filters, scope_events))
+            }
+            _ => return ctx.next().await,
+        };
+        if clamped.any() {
+            debug!("Clamped filter limits of {}: {:?}", subscription_id, clamped);
+            if clamped.over_max {
+                metrics::counter!("relay_filter_limits_clamped_total", "reason" => "max").increment(1);
+            }
+            if clamped.defaulted {
+                metrics::counter!("relay_filter_limits_clamped_total", "reason" => "default").increment(1);
+            }
+            ctx.send_message(RelayMessage::notice(self.limits.notice(&subscription_id, clamped)))?;
+        }
+        ctx.next().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: FilterLimits = FilterLimits { max: 500, default: 100, large_scope_events: 10_000 };
+
+    #[test]
+    fn test_apply_clamps_limits() {
+        let cases: Vec<(&str, Option<usize>, Option<usize>, Option<usize>, Clamped)> = vec![
+            ("under the max", Some(50), None, Some(50), Clamped::default()),
+            ("at the max", Some(500), None, Some(500), Clamped::default()),
+            ("over the max", Some(100_000), None, Some(500), Clamped { over_max: true, defaulted: false }),
+            ("no limit, small scope", None, Some(10_000), None, Clamped::default()),
+            ("no limit, unknown scope", None, None, None, Clamped::default()),
+            ("no limit, large scope", None, Some(10_001), Some(100), Clamped { over_max: false, defaulted: true }),
+            ("over the max, large scope", Some(900), Some(50_000), Some(500), Clamped { over_max: true, defaulted: false }),
+        ];
+        for (name, limit, scope_events, expected, expected_clamped) in cases {
+            let mut filters = vec![Filter { limit, ..Filter::new() }];
+            let clamped = LIMITS.apply(&mut filters, scope_events);
+            assert_eq!(filters[0].limit, expected, "{}", name);
+            assert_eq!(clamped, expected_clamped, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_zero_disables_the_limits() {
+        let limits = FilterLimits { max: 0, default: 0, large_scope_events: 0 };
+        let mut filters = vec![Filter::new().limit(100_000), Filter::new()];
+        assert!(!limits.apply(&mut filters, Some(1_000_000)).any());
+        assert_eq!(filters[0].limit, Some(100_000));
+        assert_eq!(filters[1].limit, None);
+    }
+
+    #[test]
+    fn test_notice_names_the_effective_limits() {
+        let sub = SubscriptionId::new("feed");
+        let notice = LIMITS.notice(&sub, Clamped { over_max: true, defaulted: true });
+        assert!(notice.starts_with("subscription feed: "), "{}", notice);
+        assert!(notice.contains("limits above 500 were lowered to it (max_limit_per_filter)"), "{}", notice);
+        assert!(notice.contains("at most 100 events on this scope (default_filter_limit)"), "{}", notice);
+    }
+}
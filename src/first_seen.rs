@@ -0,0 +1,239 @@
+//! When the relay first saw each event
+//!
+//! `created_at` is whatever the author claims; syncing clients also want to
+//! know when an event actually reached this relay. The processor records a
+//! timestamp the first time it accepts an event into a scope, and later
+//! copies (republishes, duplicates) never move it. Records are kept in an
+//! `AppendLog` under `database_path`, so they survive restarts, and
+//! `prune` periodically forgets events that are no longer stored, so the
+//! log only grows with the store. The websocket protocol is unchanged: the timestamps
+//! are only served by `GET /api/events/{id}/meta` and the scope export.
+
+use anyhow::Result;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+use crate::append_log::{AppendLog, LogEntry};
+use crate::config::RelayConfig;
+use crate::store::{scope_from_label, scope_label, ScopeStore};
+
+/// Sightings, next to the database
+pub const FIRST_SEEN_FILE: &str = "first_seen.jsonl";
+
+/// Ids looked up per store query while pruning
+const PRUNE_BATCH: usize = 500;
+
+/// When an event first reached one scope
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sighting {
+    pub id: EventId,
+    /// Scope label ("root" or the geohash)
+    pub scope: String,
+    pub received_at: u64,
+}
+
+/// One line of the log: an event's sightings, earliest first
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    id: EventId,
+    sightings: Vec<Sighting>,
+}
+
+impl LogEntry for Entry {
+    type Key = EventId;
+
+    fn key(&self) -> EventId {
+        self.id
+    }
+}
+
+/// First-seen timestamps per event and scope, optionally backed by a file
+#[derive(Debug, Default)]
+pub struct FirstSeen {
+    log: AppendLog<Entry>,
+}
+
+impl FirstSeen {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Ok(Self { log: AppendLog::open(path)? })
+    }
+
+    /// Sightings under `database_path`
+    pub fn for_config(config: &RelayConfig) -> Result<Self> {
+        Ok(Self { log: AppendLog::for_config(config, FIRST_SEEN_FILE)? })
+    }
+
+    /// Timestamps kept in memory only
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Records `id` as received in `scope` at `received_at` unless it was
+    /// seen there before; returns whether it was new
+    pub fn record(&self, scope: &Scope, id: EventId, received_at: u64) -> bool {
+        let sighting = Sighting { id, scope: scope_label(scope), received_at };
+        let recorded = self.log.update(id, |old| {
+            let mut sightings = old.map(|entry| entry.sightings.clone()).unwrap_or_default();
+            if sightings.iter().any(|s| s.scope == sighting.scope) {
+                return None;
+            }
+            sightings.push(sighting);
+            sightings.sort_by_key(|s| s.received_at);
+            Some(Entry { id, sightings })
+        });
+        recorded.is_some()
+    }
+
+    /// When `id` first reached `scope`
+    pub fn received_at(&self, scope: &Scope, id: &EventId) -> Option<u64> {
+        let label = scope_label(scope);
+        self.log
+            .entries()
+            .get(id)?
+            .sightings
+            .iter()
+            .find(|s| s.scope == label)
+            .map(|s| s.received_at)
+    }
+
+    /// Where and when `id` first reached the relay
+    pub fn first(&self, id: &EventId) -> Option<Sighting> {
+        self.log.entries().get(id)?.sightings.first().cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.log.entries().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops the sightings of events no longer stored in their scope
+    /// (deleted, expired, evicted) and rewrites the log; returns how many
+    /// sightings went
+    pub async fn prune(&self, store: &dyn ScopeStore) -> Result<usize> {
+        let mut by_scope: HashMap<String, Vec<EventId>> = HashMap::new();
+        for entry in self.log.entries().values() {
+            for sighting in &entry.sightings {
+                by_scope.entry(sighting.scope.clone()).or_default().push(entry.id);
+            }
+        }
+        let mut gone: HashMap<EventId, HashSet<String>> = HashMap::new();
+        for (label, ids) in by_scope {
+            let Some(scope) = scope_from_label(&label) else {
+                continue;
+            };
+            for batch in ids.chunks(PRUNE_BATCH) {
+                let stored: HashSet<EventId> = store
+                    .query(&scope, Filter::new().ids(batch.iter().copied()))
+                    .await?
+                    .into_iter()
+                    .map(|event| event.id)
+                    .collect();
+                for id in batch.iter().filter(|id| !stored.contains(id)) {
+                    gone.entry(*id).or_default().insert(label.clone());
+                }
+            }
+        }
+        if gone.is_empty() {
+            return Ok(0);
+        }
+
+        let mut pruned = 0;
+        let mut emptied = Vec::new();
+        for (id, scopes) in gone {
+            pruned += scopes.len();
+            // An event republished meanwhile keeps its new sighting
+            let kept = self.log.update(id, |old| {
+                let mut sightings = old?.sightings.clone();
+                sightings.retain(|s| !scopes.contains(&s.scope));
+                Some(Entry { id, sightings })
+            });
+            if kept.is_some_and(|entry| entry.sightings.is_empty()) {
+                emptied.push(id);
+            }
+        }
+        // Compacts, so the log keeps one line per remaining event
+        self.log.remove(&emptied)?;
+        Ok(pruned)
+    }
+}
+
+/// Runs `prune` every `interval`
+pub fn spawn_prune_task(store: Arc<dyn ScopeStore>, first_seen: Arc<FirstSeen>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match first_seen.prune(store.as_ref()).await {
+                Ok(0) => {}
+                Ok(pruned) => info!("Forgot first-seen times of {} deleted events", pruned),
+                Err(e) => warn!("Failed to prune first-seen times: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn test_first_sighting_is_kept_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(FIRST_SEEN_FILE);
+        let id = EventId::all_zeros();
+        let drt2z = Scope::named("drt2z").unwrap();
+
+        let first_seen = FirstSeen::open(&path).unwrap();
+        assert!(first_seen.record(&drt2z, id, 1_000));
+        assert!(!first_seen.record(&drt2z, id, 2_000));
+        assert!(first_seen.record(&Scope::Default, id, 3_000));
+        assert_eq!(first_seen.received_at(&drt2z, &id), Some(1_000));
+        drop(first_seen);
+
+        let reopened = FirstSeen::open(&path).unwrap();
+        assert_eq!(reopened.received_at(&drt2z, &id), Some(1_000));
+        assert_eq!(reopened.received_at(&Scope::Default, &id), Some(3_000));
+        assert_eq!(reopened.first(&id).map(|s| s.scope), Some("drt2z".to_string()));
+        assert!(!reopened.record(&drt2z, id, 4_000));
+        assert_eq!(reopened.received_at(&drt2z, &id), Some(1_000));
+    }
+
+    #[tokio::test]
+    async fn test_prune_forgets_events_no_longer_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(FIRST_SEEN_FILE);
+        let store = MemoryStore::new();
+        let keys = Keys::generate();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let kept = EventBuilder::text_note("kept").sign(&keys).await.unwrap();
+        let deleted = EventBuilder::text_note("deleted").sign(&keys).await.unwrap();
+        store.insert(&drt2z, kept.clone());
+        store.insert(&Scope::Default, deleted.clone());
+
+        let first_seen = FirstSeen::open(&path).unwrap();
+        first_seen.record(&drt2z, kept.id, 1_000);
+        first_seen.record(&Scope::Default, kept.id, 2_000);
+        first_seen.record(&Scope::Default, deleted.id, 3_000);
+        store.delete(&Scope::Default, deleted.id).await.unwrap();
+
+        // Gone from root in both cases; kept is still stored in drt2z
+        assert_eq!(first_seen.prune(&store).await.unwrap(), 2);
+        assert_eq!(first_seen.received_at(&drt2z, &kept.id), Some(1_000));
+        assert_eq!(first_seen.received_at(&Scope::Default, &kept.id), None);
+        assert_eq!(first_seen.first(&deleted.id), None);
+        drop(first_seen);
+
+        let reopened = FirstSeen::open(&path).unwrap();
+        assert_eq!(reopened.len(), 1);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+    }
+}
@@ -0,0 +1,457 @@
+//! Coordinates in REQ filters
+//!
+//! With `geo_filter_extension` on, a REQ on the root relay may name a place
+//! instead of a cell: a `#g` value of `latlon:37.77,-122.41` stands for the
+//! cell covering that point at the served precision, and
+//! `latlon:37.77,-122.41,neighbors` for that cell and the eight around it.
+//! `GeoFilterMiddleware` queries those cells with the rest of the filter and
+//! answers with their stored events, EOSE and a CLOSED: the subscription
+//! isn't live, since root never sees events stored in cells. Each cell's
+//! read checks run as if the REQ had been sent there: a cell that needs
+//! auth closes the REQ for an unauthenticated connection, and only events
+//! the connection could see in their cell are sent. Filters of the
+//! same REQ without coordinates are answered from root. Malformed values
+//! close the subscription with a `bad-geohash-filter` rejection.
+//!
+//! Every `#g` value, with or without the extension, is checked by
+//! `check_geohash_values` before it is resolved to a scope.
+
+use anyhow::{bail, Context, Result};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::{InboundContext, InboundProcessor, NostrMiddleware};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tracing::{debug, warn};
+use crate::config::RelayConfig;
+use crate::geohash_utils::{encode_latlon, is_valid_geohash, neighbors, DEFAULT_RESOLVE_PRECISION};
+use crate::processor::ConnectionState;
+use crate::read_checks::{ReadChecks, Reader};
+use crate::reject::RejectReason;
+use crate::scope_policy::{DefaultScopePolicy, ScopePolicy};
+use crate::store::ScopeStore;
+
+/// Prefix marking a `#g` value as coordinates
+pub const LATLON_PREFIX: &str = "latlon:";
+
+/// Suffix asking for the neighboring cells as well
+const NEIGHBORS_SUFFIX: &str = "neighbors";
+
+/// Accepted syntax, as advertised in NIP-11
+pub const LATLON_SYNTAX: &str = "latlon:<lat>,<lon>[,neighbors]";
+
+/// A parsed `latlon:` value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeoPoint {
+    pub lat: f64,
+    pub lon: f64,
+    pub neighbors: bool,
+}
+
+/// Parses a `#g` value; `None` when it isn't a `latlon:` value at all
+pub fn parse_latlon(value: &str) -> Option<Result<GeoPoint>> {
+    let rest = value.strip_prefix(LATLON_PREFIX)?;
+    Some(parse_point(rest).with_context(|| format!("bad geo filter '{}', expected {}", value, LATLON_SYNTAX)))
+}
+
+fn parse_point(rest: &str) -> Result<GeoPoint> {
+    let parts: Vec<&str> = rest.split(',').map(str::trim).collect();
+    let neighbors = match parts.as_slice() {
+        [_, _] => false,
+        [_, _, suffix] if *suffix == NEIGHBORS_SUFFIX => true,
+        _ => bail!("wrong number of fields"),
+    };
+    let lat: f64 = parts[0].parse().context("latitude is not a number")?;
+    let lon: f64 = parts[1].parse().context("longitude is not a number")?;
+    if !lat.is_finite() || !(-90.0..=90.0).contains(&lat) {
+        bail!("latitude out of range");
+    }
+    if !lon.is_finite() || !(-180.0..=180.0).contains(&lon) {
+        bail!("longitude out of range");
+    }
+    Ok(GeoPoint { lat, lon, neighbors })
+}
+
+/// Precision coordinates resolve to: the finest served precision, or the
+/// `/api/resolve` default within the configured bounds
+pub fn resolve_precision(config: &RelayConfig) -> usize {
+    match config.allowed_precisions.iter().max() {
+        Some(precision) => *precision,
+        None => DEFAULT_RESOLVE_PRECISION.clamp(config.min_geohash_precision, config.max_geohash_precision),
+    }
+}
+
+/// Cells a point stands for at `precision`
+pub fn cells_for(point: &GeoPoint, precision: usize) -> Vec<String> {
+    let Some(cell) = encode_latlon(point.lat, point.lon, precision) else {
+        return Vec::new();
+    };
+    let mut cells = vec![cell.clone()];
+    if point.neighbors {
+        cells.extend(neighbors(&cell).unwrap_or_default());
+    }
+    cells
+}
+
+fn g_tag() -> SingleLetterTag {
+    SingleLetterTag::lowercase(Alphabet::G)
+}
+
+/// Whether any filter uses coordinates
+pub fn has_geo_values(filters: &[Filter]) -> bool {
+    filters.iter().any(|filter| {
+        filter
+            .generic_tags
+            .get(&g_tag())
+            .is_some_and(|values| values.iter().any(|value| value.starts_with(LATLON_PREFIX)))
+    })
+}
+
+/// Checks every `latlon:` value in `filters`
+pub fn validate(filters: &[Filter]) -> Result<()> {
+    for filter in filters {
+        for value in filter.generic_tags.get(&g_tag()).into_iter().flatten() {
+            if let Some(Err(e)) = parse_latlon(value) {
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Bounds on the `#g` values of one REQ; 0 disables either cap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GeohashLimits {
+    /// `max_geohashes_per_filter`
+    pub per_filter: usize,
+    /// `max_geohash_scopes_per_subscription`, across all filters
+    pub per_subscription: usize,
+    /// Whether `latlon:` values are accepted, see `geo_filter_extension`
+    pub latlon: bool,
+}
+
+impl GeohashLimits {
+    pub fn for_config(config: &RelayConfig) -> Self {
+        Self {
+            per_filter: config.max_geohashes_per_filter,
+            per_subscription: config.max_geohash_scopes_per_subscription,
+            latlon: config.geo_filter_extension,
+        }
+    }
+}
+
+/// Checks the `#g` values of a REQ before any of them is resolved to a
+/// scope: each must be a geohash (or, with `latlon`, coordinates), and a
+/// filter may name at most `per_filter` of them and the REQ at most
+/// `per_subscription` cells. Coordinates count as the one or nine cells
+/// they stand for.
+pub fn check_geohash_values(filters: &[Filter], limits: GeohashLimits) -> Result<()> {
+    let mut cells: HashSet<String> = HashSet::new();
+    let mut points = 0;
+    for filter in filters {
+        let Some(values) = filter.generic_tags.get(&g_tag()) else {
+            continue;
+        };
+        if limits.per_filter > 0 && values.len() > limits.per_filter {
+            bail!("too many #g values in one filter (max_geohashes_per_filter is {})", limits.per_filter);
+        }
+        for value in values {
+            if limits.latlon {
+                if let Some(point) = parse_latlon(value) {
+                    points += if point?.neighbors { 9 } else { 1 };
+                    continue;
+                }
+            }
+            if value.is_empty() || value.starts_with('*') {
+                bail!("#g wildcards need a geohash prefix");
+            }
+            if !is_valid_geohash(value) {
+                bail!("'{}' is not a geohash", value);
+            }
+            cells.insert(value.to_lowercase());
+        }
+        if limits.per_subscription > 0 && cells.len() + points > limits.per_subscription {
+            bail!(
+                "too many geohash scopes in one subscription (max_geohash_scopes_per_subscription is {})",
+                limits.per_subscription
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Where one filter of a REQ is answered from
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeoQuery {
+    pub scopes: Vec<Scope>,
+    pub filter: Filter,
+}
+
+/// Splits `filter` into the cells its coordinates resolve to and the
+/// filter to run there
+///
+/// The coordinates are dropped from `#g`; plain geohash values stay, and
+/// with none left the cells are queried without a `#g` constraint, since
+/// untagged events belong to the cell they were posted to. Filters without
+/// coordinates are answered from root.
+pub fn translate(filter: &Filter, precision: usize) -> Result<GeoQuery> {
+    let Some(values) = filter.generic_tags.get(&g_tag()) else {
+        return Ok(GeoQuery { scopes: vec![Scope::Default], filter: filter.clone() });
+    };
+    let mut cells: Vec<String> = Vec::new();
+    let mut plain = Vec::new();
+    for value in values {
+        match parse_latlon(value) {
+            Some(point) => {
+                for cell in cells_for(&point?, precision) {
+                    if !cells.contains(&cell) {
+                        cells.push(cell);
+                    }
+                }
+            }
+            None => plain.push(value.clone()),
+        }
+    }
+    if cells.is_empty() {
+        return Ok(GeoQuery { scopes: vec![Scope::Default], filter: filter.clone() });
+    }
+
+    let mut translated = filter.clone();
+    if plain.is_empty() {
+        translated.generic_tags.remove(&g_tag());
+    } else {
+        translated.generic_tags.insert(g_tag(), plain.into_iter().collect());
+    }
+    let scopes = cells.iter().filter_map(|cell| Scope::named(cell).ok()).collect();
+    Ok(GeoQuery { scopes, filter: translated })
+}
+
+/// Stored events for one translated filter that `reader` may see in
+/// their cell, newest first and within its limit across all cells
+pub async fn run<P: ScopePolicy>(store: &dyn ScopeStore, reader: &Reader<'_, P>, query: &GeoQuery) -> Result<Vec<Event>> {
+    let mut seen = HashSet::new();
+    let mut events = Vec::new();
+    for scope in &query.scopes {
+        let cell = reader.at(scope.clone());
+        for event in store.query(scope, query.filter.clone()).await? {
+            if cell.can_see(&event) && seen.insert(event.id) {
+                events.push(event);
+            }
+        }
+    }
+    events.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    if let Some(limit) = query.filter.limit {
+        events.truncate(limit);
+    }
+    Ok(events)
+}
+
+/// Answers REQs with coordinates on the root relay from the cells they
+/// resolve to
+#[derive(Clone)]
+pub struct GeoFilterMiddleware<P = DefaultScopePolicy> {
+    store: Arc<dyn ScopeStore>,
+    /// Runs each cell's read checks, as a REQ on that cell would
+    read_checks: ReadChecks<P>,
+    /// `geo_filter_extension`; off passes every REQ through untouched
+    enabled: bool,
+    precision: usize,
+}
+
+impl<P: ScopePolicy> GeoFilterMiddleware<P> {
+    pub fn new(store: Arc<dyn ScopeStore>, read_checks: ReadChecks<P>, config: &RelayConfig) -> Self {
+        Self {
+            store,
+            read_checks,
+            enabled: config.geo_filter_extension,
+            precision: resolve_precision(config),
+        }
+    }
+}
+
+impl<P: ScopePolicy + Clone> NostrMiddleware<ConnectionState> for GeoFilterMiddleware<P> {
+    async fn process_inbound<Next>(&self, ctx: InboundContext<'_, ConnectionState, Next>) -> Result<(), anyhow::Error>
+    where
+        Next: InboundProcessor<ConnectionState>,
+    {
+        if !self.enabled {
+            return ctx.next().await;
+        }
+        let on_root = matches!(ctx.state.read().subdomain.as_ref(), Scope::Default);
+        let request = match &ctx.message {
+            Some(ClientMessage::Req { subscription_id, filters }) if on_root && has_geo_values(filters) => {
+                Some((SubscriptionId::clone(subscription_id), filters.clone()))
+            }
+            _ => None,
+        };
+        let Some((subscription_id, filters)) = request else {
+            return ctx.next().await;
+        };
+
+        // Never registered with relay_builder, so nothing else closes it
+        let reader = {
+            let mut state = ctx.state.write();
+            state.custom.subscriptions.close(&subscription_id);
+            self.read_checks.reader(state.subdomain.clone(), state.authed_pubkey, &state.custom)
+        };
+        // Root's own checks, including the #g limits
+        if let Err(message) = reader.verify(&filters) {
+            ctx.send_message(RelayMessage::closed(subscription_id, message))?;
+            return Ok(());
+        }
+        let queries = match filters.iter().map(|filter| translate(filter, self.precision)).collect::<Result<Vec<_>>>() {
+            Ok(queries) => queries,
+            Err(e) => {
+                let message = RejectReason::BadGeohashFilter { detail: format!("{:#}", e) }.to_string();
+                ctx.send_message(RelayMessage::closed(subscription_id, message))?;
+                return Ok(());
+            }
+        };
+        // Then each cell's, as if the REQ had been sent there
+        for query in &queries {
+            for scope in &query.scopes {
+                if let Err(message) = reader.at(scope.clone()).verify(std::slice::from_ref(&query.filter)) {
+                    ctx.send_message(RelayMessage::closed(subscription_id, message))?;
+                    return Ok(());
+                }
+            }
+        }
+
+        let mut sent = HashSet::new();
+        for query in &queries {
+            match run(self.store.as_ref(), &reader, query).await {
+                Ok(events) => {
+                    for event in events.into_iter().filter(|event| sent.insert(event.id)) {
+                        ctx.send_message(RelayMessage::event(subscription_id.clone(), event))?;
+                    }
+                }
+                Err(e) => warn!("Failed to answer geo filter for {}: {}", subscription_id, e),
+            }
+        }
+        debug!("Answered geo filter {} with {} events", subscription_id, sent.len());
+        ctx.send_message(RelayMessage::eose(subscription_id.clone()))?;
+        ctx.send_message(RelayMessage::closed(subscription_id, "geo filters only return stored events".to_string()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::GeohashedEventProcessor;
+    use crate::store::MemoryStore;
+
+    fn geo_filter(values: &[&str]) -> Filter {
+        Filter::new().custom_tags(g_tag(), values.iter().map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn test_parse_latlon() {
+        assert_eq!(
+            parse_latlon("latlon:37.77,-122.41").unwrap().unwrap(),
+            GeoPoint { lat: 37.77, lon: -122.41, neighbors: false }
+        );
+        assert!(parse_latlon("latlon: 37.77 , -122.41 ,neighbors").unwrap().unwrap().neighbors);
+        assert!(parse_latlon("9q8yy").is_none());
+
+        for bad in ["latlon:", "latlon:37.77", "latlon:91,0", "latlon:0,181", "latlon:NaN,0", "latlon:1,2,3", "latlon:a,b"] {
+            let err = parse_latlon(bad).unwrap().unwrap_err();
+            assert!(format!("{:#}", err).contains(LATLON_SYNTAX), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_translation_near_cell_boundaries() {
+        // Either side of the edge between drt2y and drt2z to its east
+        let (drt2z, lon_err, _) = geohash::decode("drt2z").unwrap();
+        let edge = drt2z.x - lon_err;
+        let east = translate(&geo_filter(&[&format!("latlon:{},{}", drt2z.y, edge + 1e-6)]), 5).unwrap();
+        let west = translate(&geo_filter(&[&format!("latlon:{},{}", drt2z.y, edge - 1e-6)]), 5).unwrap();
+        assert_eq!(east.scopes, [Scope::named("drt2z").unwrap()]);
+        assert_eq!(west.scopes, [Scope::named("drt2y").unwrap()]);
+        assert!(east.filter.generic_tags.is_empty());
+
+        // Neighbors add the eight cells around, once each
+        let around = translate(&geo_filter(&[&format!("latlon:{},{},neighbors", drt2z.y, drt2z.x)]), 5).unwrap();
+        assert_eq!(around.scopes.len(), 9);
+        assert_eq!(around.scopes[0], Scope::named("drt2z").unwrap());
+
+        // Plain geohashes stay in the filter
+        let mixed = translate(&geo_filter(&["latlon:37.77,-122.41", "drt2z"]), 5).unwrap();
+        assert_eq!(mixed.scopes, [Scope::named("9q8yy").unwrap()]);
+        assert_eq!(mixed.filter.generic_tags[&g_tag()].len(), 1);
+    }
+
+    #[test]
+    fn test_filters_without_coordinates_stay_on_root() {
+        let filter = Filter::new().kind(Kind::TextNote);
+        assert!(!has_geo_values(&[filter.clone()]));
+        assert_eq!(translate(&filter, 5).unwrap().scopes, [Scope::Default]);
+        assert!(validate(&[geo_filter(&["drt2z"])]).is_ok());
+        assert!(validate(&[geo_filter(&["latlon:200,0"])]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_queries_resolved_cells() {
+        let store = MemoryStore::new();
+        let keys = Keys::generate();
+        let here = EventBuilder::text_note("here").sign(&keys).await.unwrap();
+        let elsewhere = EventBuilder::text_note("elsewhere").sign(&keys).await.unwrap();
+        store.insert(&Scope::named("9q8yy").unwrap(), here.clone());
+        store.insert(&Scope::named("drt2z").unwrap(), elsewhere);
+
+        let checks = ReadChecks::new(GeohashedEventProcessor::new(), Keys::generate().public_key());
+        let reader = checks.reader(Arc::new(Scope::Default), None, &ConnectionState::default());
+        let query = translate(&geo_filter(&["latlon:37.77,-122.41"]), 5).unwrap();
+        let events = run(&store, &reader, &query).await.unwrap();
+        assert_eq!(events.iter().map(|e| e.id).collect::<Vec<_>>(), [here.id]);
+    }
+
+    #[test]
+    fn test_geohash_value_limits() {
+        let limits = GeohashLimits { per_filter: 4, per_subscription: 10, latlon: true };
+        let cells = |n: usize| geo_filter(&["drt2z", "drt2y", "drt2w", "drt2x", "9q8yy", "9q8yz"][..n]);
+        let cases: Vec<(&str, Vec<Filter>, Option<&str>)> = vec![
+            ("at the filter limit", vec![cells(4)], None),
+            ("over the filter limit", vec![cells(5)], Some("max_geohashes_per_filter is 4")),
+            (
+                "cells and coordinates at the subscription limit",
+                vec![cells(4), geo_filter(&["u4pru", "u4prv"]), geo_filter(&["latlon:1,1", "latlon:2,2", "latlon:3,3", "latlon:4,4"])],
+                None,
+            ),
+            ("repeated cells count once", vec![cells(4), cells(4), cells(4)], None),
+            (
+                "over the subscription limit",
+                vec![cells(4), geo_filter(&["latlon:0,0,neighbors"])],
+                Some("max_geohash_scopes_per_subscription is 10"),
+            ),
+            ("bare wildcard", vec![geo_filter(&["*"])], Some("need a geohash prefix")),
+            ("empty value", vec![geo_filter(&[""])], Some("need a geohash prefix")),
+            ("not a geohash", vec![geo_filter(&["drt2a"])], Some("'drt2a' is not a geohash")),
+            ("prefixed wildcard", vec![geo_filter(&["drt*"])], Some("'drt*' is not a geohash")),
+            ("malformed coordinates", vec![geo_filter(&["latlon:91,0"])], Some(LATLON_SYNTAX)),
+        ];
+        for (name, filters, expected) in cases {
+            let result = check_geohash_values(&filters, limits);
+            match expected {
+                None => assert!(result.is_ok(), "{}: {:?}", name, result),
+                Some(message) => {
+                    let err = format!("{:#}", result.unwrap_err());
+                    assert!(err.contains(message), "{}: {}", name, err);
+                }
+            }
+        }
+
+        // Without the extension coordinates are just bad values, and 0 lifts the caps
+        let plain = GeohashLimits { per_filter: 0, per_subscription: 0, latlon: false };
+        assert!(check_geohash_values(&[geo_filter(&["latlon:1,1"])], plain).is_err());
+        assert!(check_geohash_values(&[cells(6), cells(6)], plain).is_ok());
+    }
+
+    #[test]
+    fn test_resolve_precision_follows_served_cells() {
+        assert_eq!(resolve_precision(&RelayConfig::default()), DEFAULT_RESOLVE_PRECISION);
+        let config = RelayConfig { allowed_precisions: vec![4, 6], ..RelayConfig::default() };
+        assert_eq!(resolve_precision(&config), 6);
+    }
+}
@@ -3,12 +3,21 @@
 //! This module provides validation and normalization for geohash strings
 //! used in location-based event routing. Events are routed to exact geohash
 //! scopes only - no hierarchical propagation.
+//!
+//! The geometry helpers (neighbors, grids, bounds) handle the edges of the
+//! map explicitly: nothing lies beyond a pole, and east/west wrap across the
+//! antimeridian.
+
+use serde::Serialize;
 
 /// Maximum allowed geohash precision (7 characters = ~152m)
 pub const MAX_GEOHASH_LENGTH: usize = 7;
 
+/// Precision coordinates resolve to when none is requested
+pub const DEFAULT_RESOLVE_PRECISION: usize = 5;
+
 /// Valid characters in a geohash string
-const VALID_GEOHASH_CHARS: &str = "0123456789bcdefghjkmnpqrstuvwxyz";
+pub const GEOHASH_ALPHABET: &str = "0123456789bcdefghjkmnpqrstuvwxyz";
 
 /// Validates a geohash string
 /// 
@@ -22,7 +31,79 @@ pub fn is_valid_geohash(gh: &str) -> bool {
     }
     
     // Check all characters are valid
-    gh.chars().all(|c| VALID_GEOHASH_CHARS.contains(c.to_ascii_lowercase()))
+    gh.chars().all(|c| GEOHASH_ALPHABET.contains(c.to_ascii_lowercase()))
+}
+
+/// Validates a geohash using the georust library's decoder
+/// This provides additional validation beyond character checking,
+/// ensuring the geohash represents a valid geographic location
+pub fn is_valid_geohash_strict(gh: &str) -> bool {
+    if !is_valid_geohash(gh) {
+        return false;
+    }
+    
+    // Try to decode - if it fails, the geohash is invalid
+    geohash::decode(&gh.to_lowercase()).is_ok()
+}
+
+/// Checks if a subdomain string is a valid geohash
+/// Used to determine if a subdomain should be treated as a geohash scope
+/// or a regular team/group name
+pub fn is_geohash_subdomain(subdomain: &str) -> bool {
+    // Must be valid geohash and use strict validation to ensure it's geographic
+    is_valid_geohash_strict(subdomain)
+}
+
+/// Whether cells of `precision` are served; an empty `allowed` set serves
+/// every precision
+pub fn is_allowed_precision(precision: usize, allowed: &[usize]) -> bool {
+    allowed.is_empty() || allowed.contains(&precision)
+}
+
+/// `is_geohash_subdomain` for a relay that only serves the `allowed`
+/// precisions
+pub fn is_served_geohash_subdomain(subdomain: &str, allowed: &[usize]) -> bool {
+    is_geohash_subdomain(subdomain) && is_allowed_precision(subdomain.len(), allowed)
+}
+
+/// Allowed precision closest to `precision`, the coarser one on a tie
+pub fn nearest_allowed_precision(precision: usize, allowed: &[usize]) -> Option<usize> {
+    allowed
+        .iter()
+        .copied()
+        .min_by_key(|candidate| (candidate.abs_diff(precision), *candidate))
+}
+
+/// The cell of `precision` containing `gh`'s center
+///
+/// Coarser precisions truncate; finer ones pad with the sub-cell at the
+/// center, since a coarse tag says nothing about where inside it the
+/// event is.
+pub fn to_precision(gh: &str, precision: usize) -> Option<String> {
+    let gh = normalize_geohash(gh)?;
+    if precision <= gh.len() {
+        return (precision > 0).then(|| gh[..precision].to_string());
+    }
+    let (center, _, _) = geohash::decode(&gh).ok()?;
+    encode_latlon(center.y, center.x, precision)
+}
+
+/// The cell of at most `precision` containing `gh`: its prefix if finer,
+/// `gh` itself otherwise; never pads
+pub fn truncate_to_precision(gh: &str, precision: usize) -> Option<String> {
+    let gh = normalize_geohash(gh)?;
+    match precision {
+        0 => None,
+        p if p < gh.len() => Some(gh[..p].to_string()),
+        _ => Some(gh),
+    }
+}
+
+/// Finest allowed cell that contains `gh`, for pointing visitors of a
+/// cell that isn't served at one that is
+pub fn containing_allowed_cell(gh: &str, allowed: &[usize]) -> Option<String> {
+    let precision = allowed.iter().copied().filter(|p| *p > 0 && *p < gh.len()).max()?;
+    to_precision(gh, precision)
 }
 
 /// Normalizes a geohash string to lowercase
@@ -51,59 +132,227 @@ pub fn extract_geohash_tags(tags: &[Vec<String>]) -> Vec<String> {
         .collect()
 }
 
-/// Get all 8 neighbors of a geohash plus the center geohash itself
-/// Returns a 3x3 grid with the center geohash and its 8 neighbors
-/// Order: [NW, N, NE, W, Center, E, SW, S, SE]
+/// `extract_geohash_tags` for events allowed at most `max` g tags (0 for
+/// no limit)
+///
+/// Returns `None` as soon as one tag too many is seen, without validating
+/// the rest. Invalid g tags count towards the limit.
+pub fn extract_geohash_tags_capped(tags: &[Vec<String>], max: usize) -> Option<Vec<String>> {
+    let mut seen = 0;
+    let mut geohashes = Vec::new();
+    for tag in tags.iter().filter(|tag| tag.len() >= 2 && tag[0] == "g") {
+        seen += 1;
+        if max > 0 && seen > max {
+            return None;
+        }
+        geohashes.extend(normalize_geohash(&tag[1]));
+    }
+    Some(geohashes)
+}
+
+/// The 3x3 grid around a geohash: its neighbors and the center itself
+///
+/// Order: [NW, N, NE, W, Center, E, SW, S, SE], leaving out the directions
+/// `neighbor` has no cell for.
 pub fn get_geohash_grid(center: &str) -> Option<Vec<String>> {
-    use geohash::{Direction, neighbor};
-    
-    if !is_valid_geohash(center) {
+    use geohash::Direction::*;
+
+    let center = normalize_geohash(center)?;
+    let mut grid = Vec::with_capacity(9);
+    for direction in [NW, N, NE, W] {
+        grid.extend(neighbor(&center, direction));
+    }
+    grid.push(center.clone());
+    for direction in [E, SW, S, SE] {
+        grid.extend(neighbor(&center, direction));
+    }
+    Some(grid)
+}
+
+/// Encodes a coordinate as a geohash of the given precision
+///
+/// Returns None for out-of-range coordinates or precisions.
+pub fn encode_latlon(lat: f64, lon: f64, precision: usize) -> Option<String> {
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
         return None;
     }
-    
-    let center_lower = center.to_lowercase();
-    
-    // Calculate all neighbors
-    let nw = neighbor(&center_lower, Direction::NW).ok()?;
-    let n = neighbor(&center_lower, Direction::N).ok()?;
-    let ne = neighbor(&center_lower, Direction::NE).ok()?;
-    let w = neighbor(&center_lower, Direction::W).ok()?;
-    let e = neighbor(&center_lower, Direction::E).ok()?;
-    let sw = neighbor(&center_lower, Direction::SW).ok()?;
-    let s = neighbor(&center_lower, Direction::S).ok()?;
-    let se = neighbor(&center_lower, Direction::SE).ok()?;
-    
-    Some(vec![
-        nw, n, ne,
-        w, center_lower.clone(), e,
-        sw, s, se
-    ])
+    if precision == 0 || precision > MAX_GEOHASH_LENGTH {
+        return None;
+    }
+    geohash::encode(geohash::Coord { x: lon, y: lat }, precision).ok()
+}
+
+/// The adjacent cell of a geohash in `direction`, at the same precision
+///
+/// `None` for an invalid geohash, or when the cell touches a pole and
+/// `direction` would cross it. East and west wrap across the antimeridian.
+pub fn neighbor(gh: &str, direction: geohash::Direction) -> Option<String> {
+    use geohash::Direction::*;
+
+    let gh = normalize_geohash(gh)?;
+    let (center, lon_err, lat_err) = geohash::decode(&gh).ok()?;
+    let (dlat, dlon) = match direction {
+        N => (1, 0),
+        NE => (1, 1),
+        E => (0, 1),
+        SE => (-1, 1),
+        S => (-1, 0),
+        SW => (-1, -1),
+        W => (0, -1),
+        NW => (1, -1),
+    };
+    let lat = center.y + 2.0 * lat_err * f64::from(dlat);
+    if !(-90.0..=90.0).contains(&lat) {
+        return None;
+    }
+    let mut lon = center.x + 2.0 * lon_err * f64::from(dlon);
+    if lon > 180.0 {
+        lon -= 360.0;
+    } else if lon < -180.0 {
+        lon += 360.0;
+    }
+    encode_latlon(lat, lon, gh.len())
+}
+
+/// Adjacent cells of a geohash at the same precision
+///
+/// Order: [NW, N, NE, W, E, SW, S, SE]. Cells touching a pole omit the
+/// directions that would cross it, and east/west neighbors wrap across the
+/// antimeridian.
+pub fn neighbors(gh: &str) -> Option<Vec<String>> {
+    use geohash::Direction::*;
+
+    let gh = normalize_geohash(gh)?;
+    Some([NW, N, NE, W, E, SW, S, SE].into_iter().filter_map(|d| neighbor(&gh, d)).collect())
+}
+
+/// Bounds of a geohash area, in degrees
+///
+/// An area crossing the antimeridian runs east from `min_lon` over ±180° to
+/// `max_lon`, so its `min_lon` is greater than its `max_lon` (as in GeoJSON).
+/// Anything drawing it should use `split_at_antimeridian`; a polygon through
+/// those corners spans the rest of the world instead.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct GeoBbox {
+    pub min_lat: f64,
+    pub min_lon: f64,
+    pub max_lat: f64,
+    pub max_lon: f64,
+    pub crosses_antimeridian: bool,
+}
+
+impl GeoBbox {
+    /// The area as boxes that don't cross the antimeridian: itself, or its
+    /// parts east and west of it
+    pub fn split_at_antimeridian(&self) -> Vec<GeoBbox> {
+        if !self.crosses_antimeridian {
+            return vec![*self];
+        }
+        vec![
+            GeoBbox { max_lon: 180.0, crosses_antimeridian: false, ..*self },
+            GeoBbox { min_lon: -180.0, crosses_antimeridian: false, ..*self },
+        ]
+    }
+}
+
+/// Bounds of one cell, which never crosses the antimeridian
+pub fn cell_bbox(gh: &str) -> Option<GeoBbox> {
+    let rect = geohash::decode_bbox(&normalize_geohash(gh)?).ok()?;
+    Some(GeoBbox {
+        min_lat: rect.min().y,
+        min_lon: rect.min().x,
+        max_lat: rect.max().y,
+        max_lon: rect.max().x,
+        crosses_antimeridian: false,
+    })
+}
+
+/// Smallest box covering every cell, crossing the antimeridian when that
+/// is narrower than going around
+///
+/// `None` when `cells` is empty or holds an invalid geohash.
+pub fn cells_bbox(cells: &[String]) -> Option<GeoBbox> {
+    let boxes = cells.iter().map(|gh| cell_bbox(gh)).collect::<Option<Vec<_>>>()?;
+    let min_lat = boxes.iter().map(|b| b.min_lat).reduce(f64::min)?;
+    let max_lat = boxes.iter().map(|b| b.max_lat).reduce(f64::max)?;
+
+    // Longitude spans merged in order; the box leaves out the widest gap
+    let mut spans: Vec<(f64, f64)> = boxes.iter().map(|b| (b.min_lon, b.max_lon)).collect();
+    spans.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut merged: Vec<(f64, f64)> = Vec::with_capacity(spans.len());
+    for (min, max) in spans {
+        match merged.last_mut() {
+            Some(last) if min <= last.1 => last.1 = last.1.max(max),
+            _ => merged.push((min, max)),
+        }
+    }
+    let (first, last) = (merged[0], merged[merged.len() - 1]);
+    let mut widest = (first.0 + 360.0 - last.1, first.0, last.1);
+    for pair in merged.windows(2) {
+        let gap = pair[1].0 - pair[0].1;
+        if gap > widest.0 {
+            widest = (gap, pair[1].0, pair[0].1);
+        }
+    }
+    let (_, min_lon, max_lon) = widest;
+    Some(GeoBbox {
+        min_lat,
+        min_lon,
+        max_lat,
+        max_lon,
+        crosses_antimeridian: min_lon > max_lon,
+    })
+}
+
+/// Bounds of a cell and its neighbors
+pub fn neighborhood_bbox(gh: &str) -> Option<GeoBbox> {
+    let mut cells = neighbors(gh)?;
+    cells.push(normalize_geohash(gh)?);
+    cells_bbox(&cells)
+}
+
+/// The 32 cells one character below a geohash, in alphabet order
+///
+/// An empty `gh` gives the top-level cells. `None` for an invalid geohash
+/// or one already at `MAX_GEOHASH_LENGTH`.
+pub fn children(gh: &str) -> Option<Vec<String>> {
+    let gh = if gh.is_empty() { String::new() } else { normalize_geohash(gh)? };
+    if gh.len() >= MAX_GEOHASH_LENGTH {
+        return None;
+    }
+    Some(GEOHASH_ALPHABET.chars().map(|c| format!("{}{}", gh, c)).collect())
+}
+
+/// Approximate cell dimensions (width × height) for each precision
+const CELL_SIZES: [&str; MAX_GEOHASH_LENGTH] = [
+    "5,000km × 5,000km",
+    "1,250km × 625km",
+    "156km × 156km",
+    "39km × 19.5km",
+    "4.9km × 4.9km",
+    "1.2km × 0.6km",
+    "153m × 153m",
+];
+
+/// Human-readable description of the area covered by a geohash cell
+///
+/// e.g. "Geohash cell drt2z (~4.9km × 4.9km) centered at 42.35, -71.04"
+pub fn describe_cell(gh: &str) -> Option<String> {
+    let gh = normalize_geohash(gh)?;
+    let (center, _, _) = geohash::decode(&gh).ok()?;
+    Some(format!(
+        "Geohash cell {} (~{}) centered at {:.2}, {:.2}",
+        gh,
+        CELL_SIZES[gh.len() - 1],
+        center.y,
+        center.x
+    ))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use geohash::decode;
-    
-    /// Validates a geohash using the georust library's decoder
-    /// This provides additional validation beyond character checking,
-    /// ensuring the geohash represents a valid geographic location
-    fn is_valid_geohash_strict(gh: &str) -> bool {
-        if !is_valid_geohash(gh) {
-            return false;
-        }
-        
-        // Try to decode - if it fails, the geohash is invalid
-        decode(gh).is_ok()
-    }
-    
-    /// Checks if a subdomain string is a valid geohash
-    /// Used to determine if a subdomain should be treated as a geohash scope
-    /// or a regular team/group name
-    fn is_geohash_subdomain(subdomain: &str) -> bool {
-        // Must be valid geohash and use strict validation to ensure it's geographic
-        is_valid_geohash_strict(subdomain)
-    }
 
     #[test]
     fn test_valid_geohash_basic() {
@@ -156,6 +405,20 @@ mod tests {
         assert!(!is_valid_geohash("dr 2z"));  // Space invalid
     }
 
+    #[test]
+    fn test_truncate_to_precision() {
+        assert_eq!(truncate_to_precision("drt2zb", 4), Some("drt2".to_string()));
+        assert_eq!(truncate_to_precision("DRT2ZB", 4), Some("drt2".to_string()));
+        // Already at or under the cap
+        assert_eq!(truncate_to_precision("drt2", 4), Some("drt2".to_string()));
+        assert_eq!(truncate_to_precision("dr", 4), Some("dr".to_string()));
+        // Precision 1 is the coarsest cell
+        assert_eq!(truncate_to_precision("drt2zby", 1), Some("d".to_string()));
+        assert_eq!(truncate_to_precision("d", 1), Some("d".to_string()));
+        assert_eq!(truncate_to_precision("drt2z", 0), None);
+        assert_eq!(truncate_to_precision("dr!2z", 3), None);
+    }
+
     #[test]
     fn test_normalize_geohash() {
         // Valid geohashes get normalized to lowercase
@@ -258,6 +521,83 @@ mod tests {
         assert!(extracted.contains(&"gbsuv".to_string()));
     }
 
+    #[test]
+    fn test_describe_cell() {
+        let description = describe_cell("DRT2Z").unwrap();
+        assert!(description.starts_with("Geohash cell drt2z (~4.9km × 4.9km) centered at 42."));
+        assert!(describe_cell("u").unwrap().contains("5,000km"));
+        assert!(describe_cell("u09tunq").unwrap().contains("153m"));
+        assert_eq!(describe_cell("invalid!"), None);
+    }
+
+    #[test]
+    fn test_encode_latlon_round_trip() {
+        let (center, _, _) = decode("drt2z").unwrap();
+        assert_eq!(encode_latlon(center.y, center.x, 5), Some("drt2z".to_string()));
+        assert_eq!(encode_latlon(center.y, center.x, 3), Some("drt".to_string()));
+    }
+
+    #[test]
+    fn test_encode_latlon_ranges() {
+        assert!(encode_latlon(90.0, 180.0, 5).is_some());
+        assert!(encode_latlon(-90.0, -180.0, 5).is_some());
+        assert_eq!(encode_latlon(90.1, 0.0, 5), None);
+        assert_eq!(encode_latlon(0.0, -180.1, 5), None);
+        assert_eq!(encode_latlon(f64::NAN, 0.0, 5), None);
+        assert_eq!(encode_latlon(0.0, 0.0, 0), None);
+        assert_eq!(encode_latlon(0.0, 0.0, MAX_GEOHASH_LENGTH + 1), None);
+    }
+
+    #[test]
+    fn test_neighbors_interior_cell() {
+        let result = neighbors("drt2z").unwrap();
+        let grid = get_geohash_grid("drt2z").unwrap();
+        assert_eq!(result.len(), 8);
+        for n in &result {
+            assert!(grid.contains(n));
+            assert_ne!(n, "drt2z");
+        }
+    }
+
+    #[test]
+    fn test_children() {
+        let result = children("DR").unwrap();
+        assert_eq!(result.len(), 32);
+        assert_eq!(result.first().map(String::as_str), Some("dr0"));
+        assert_eq!(result.last().map(String::as_str), Some("drz"));
+        assert_eq!(children("").unwrap()[0], "0");
+        assert_eq!(children("drt2zbx"), None);
+        assert_eq!(children("dra"), None);
+    }
+
+    #[test]
+    fn test_neighbors_at_poles() {
+        let north = encode_latlon(90.0, 10.0, 5).unwrap();
+        let result = neighbors(&north).unwrap();
+        // NW, N, NE are beyond the pole
+        assert_eq!(result.len(), 5);
+        for n in &result {
+            assert!(decode(n).unwrap().0.y < 90.0);
+        }
+
+        let south = encode_latlon(-90.0, 10.0, 5).unwrap();
+        assert_eq!(neighbors(&south).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_neighbors_wrap_antimeridian() {
+        let east_edge = encode_latlon(0.0, 179.99, 5).unwrap();
+        let result = neighbors(&east_edge).unwrap();
+        assert_eq!(result.len(), 8);
+        // The east neighbor (index 4) is on the other side of the meridian
+        let (east, _, _) = decode(&result[4]).unwrap();
+        assert!(east.x < -179.0);
+
+        let west_edge = encode_latlon(0.0, -179.99, 5).unwrap();
+        let (west, _, _) = decode(&neighbors(&west_edge).unwrap()[3]).unwrap();
+        assert!(west.x > 179.0);
+    }
+
     #[test]
     fn test_is_geohash_subdomain() {
         // Valid geohash subdomains
@@ -275,4 +615,173 @@ mod tests {
         // This is why we use strict validation
         assert!(is_geohash_subdomain("d"));  // Valid geohash
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_extract_geohash_tags_capped() {
+        let tags: Vec<Vec<String>> = ["drt2z", "invalid!", "9q8yy", "gbsuv"]
+            .iter()
+            .map(|g| vec!["g".to_string(), g.to_string()])
+            .collect();
+        assert_eq!(extract_geohash_tags_capped(&tags, 4), Some(extract_geohash_tags(&tags)));
+        assert_eq!(extract_geohash_tags_capped(&tags, 3), None);
+        assert_eq!(extract_geohash_tags_capped(&tags, 0).map(|g| g.len()), Some(3));
+    }
+
+    #[test]
+    fn test_no_neighbor_beyond_a_pole() {
+        use geohash::Direction::*;
+
+        let north = encode_latlon(90.0, 10.0, 3).unwrap();
+        for direction in [NW, N, NE] {
+            assert_eq!(neighbor(&north, direction), None);
+        }
+        assert!(neighbor(&north, E).is_some());
+        assert!(neighbor(&north, S).is_some());
+        let grid = get_geohash_grid(&north).unwrap();
+        assert_eq!(grid.len(), 6);
+        assert_eq!(grid[1], north);
+
+        let south = encode_latlon(-90.0, 10.0, 3).unwrap();
+        assert_eq!(neighbor(&south, S), None);
+        assert_eq!(get_geohash_grid(&south).unwrap().len(), 6);
+        assert_eq!(neighbor("dra", N), None);
+    }
+
+    #[test]
+    fn test_neighbor_wraps_antimeridian() {
+        use geohash::Direction::*;
+
+        let east_edge = encode_latlon(0.0, 179.99, 4).unwrap();
+        let west_edge = encode_latlon(0.0, -179.99, 4).unwrap();
+        assert_eq!(neighbor(&east_edge, E), Some(west_edge.clone()));
+        assert_eq!(neighbor(&west_edge, W), Some(east_edge));
+    }
+
+    #[test]
+    fn test_cell_bbox() {
+        let bbox = cell_bbox("drt2z").unwrap();
+        assert!(bbox.min_lat < 42.35 && 42.35 < bbox.max_lat);
+        assert!(bbox.min_lon < -71.04 && -71.04 < bbox.max_lon);
+        assert!(!bbox.crosses_antimeridian);
+        assert_eq!(bbox.split_at_antimeridian(), vec![bbox]);
+        assert_eq!(cell_bbox("dra"), None);
+    }
+
+    #[test]
+    fn test_neighborhood_across_antimeridian() {
+        let east_edge = encode_latlon(0.0, 179.99, 5).unwrap();
+        let bbox = neighborhood_bbox(&east_edge).unwrap();
+        assert!(bbox.crosses_antimeridian);
+        assert!(bbox.min_lon > 179.0 && bbox.max_lon < -179.0, "{:?}", bbox);
+
+        let parts = bbox.split_at_antimeridian();
+        assert_eq!(parts.len(), 2);
+        assert_eq!((parts[0].min_lon, parts[0].max_lon), (bbox.min_lon, 180.0));
+        assert_eq!((parts[1].min_lon, parts[1].max_lon), (-180.0, bbox.max_lon));
+        assert!(parts.iter().all(|part| !part.crosses_antimeridian && part.min_lat == bbox.min_lat));
+
+        let inland = neighborhood_bbox("drt2z").unwrap();
+        assert!(!inland.crosses_antimeridian);
+        assert!(inland.min_lon < inland.max_lon);
+    }
+
+    #[test]
+    fn test_neighborhood_at_pole_stops_at_the_pole() {
+        let north = encode_latlon(90.0, 10.0, 2).unwrap();
+        let bbox = neighborhood_bbox(&north).unwrap();
+        assert_eq!(bbox.max_lat, 90.0);
+        assert!(!bbox.crosses_antimeridian);
+        assert_eq!(cells_bbox(&[]), None);
+        // Every longitude covered: no gap to leave out
+        let band: Vec<String> = ["0", "1", "4", "5", "h", "j", "n", "p"].iter().map(|c| c.to_string()).collect();
+        let bbox = cells_bbox(&band).unwrap();
+        assert_eq!((bbox.min_lon, bbox.max_lon, bbox.crosses_antimeridian), (-180.0, 180.0, false));
+    }
+
+    /// Whether two cells share an edge or a corner, across the antimeridian
+    fn touches(a: &GeoBbox, b: &GeoBbox) -> bool {
+        const EPS: f64 = 1e-9;
+        let lat_touch = a.min_lat <= b.max_lat + EPS && b.min_lat <= a.max_lat + EPS;
+        let lon_touch = [-360.0, 0.0, 360.0]
+            .iter()
+            .any(|shift| a.min_lon <= b.max_lon + shift + EPS && b.min_lon + shift <= a.max_lon + EPS);
+        lat_touch && lon_touch
+    }
+
+    /// Whether `bbox` covers `cell`
+    fn covers(bbox: &GeoBbox, cell: &GeoBbox) -> bool {
+        let lat = bbox.min_lat <= cell.min_lat && cell.max_lat <= bbox.max_lat;
+        let lon = bbox
+            .split_at_antimeridian()
+            .iter()
+            .any(|part| part.min_lon <= cell.min_lon && cell.max_lon <= part.max_lon);
+        lat && lon
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_neighbors_are_valid_and_adjacent(gh in "[0-9b-hjkmnp-z]{1,7}") {
+            let center = cell_bbox(&gh).unwrap();
+            let result = neighbors(&gh).unwrap();
+            proptest::prop_assert!(result.len() == 8 || result.len() == 5);
+            for n in &result {
+                proptest::prop_assert!(is_valid_geohash(n));
+                proptest::prop_assert_eq!(n.len(), gh.len());
+                proptest::prop_assert_ne!(n, &gh);
+                proptest::prop_assert!(touches(&center, &cell_bbox(n).unwrap()), "{} does not touch {}", n, gh);
+            }
+        }
+
+        #[test]
+        fn prop_neighborhood_bbox_covers_every_cell(gh in "[0-9b-hjkmnp-z]{1,7}") {
+            let bbox = neighborhood_bbox(&gh).unwrap();
+            for cell in get_geohash_grid(&gh).unwrap() {
+                proptest::prop_assert!(covers(&bbox, &cell_bbox(&cell).unwrap()), "{:?} misses {}", bbox, cell);
+            }
+            for part in bbox.split_at_antimeridian() {
+                proptest::prop_assert!(part.min_lon <= part.max_lon);
+            }
+        }
+
+        #[test]
+        fn prop_normalize_geohash_is_idempotent(input in "\\PC{0,12}") {
+            if let Some(normalized) = normalize_geohash(&input) {
+                proptest::prop_assert!(is_valid_geohash(&normalized));
+                proptest::prop_assert_eq!(normalize_geohash(&normalized), Some(normalized.clone()));
+            }
+        }
+
+        #[test]
+        fn prop_extracted_tags_are_valid(tags in proptest::collection::vec(proptest::collection::vec("\\PC{0,10}", 0..4), 0..20)) {
+            for geohash in extract_geohash_tags(&tags) {
+                proptest::prop_assert!(is_valid_geohash(&geohash));
+                proptest::prop_assert_eq!(geohash.to_lowercase(), geohash);
+            }
+        }
+    }
+
+    #[test]
+    fn test_allowed_precisions() {
+        assert!(is_allowed_precision(3, &[]));
+        assert!(is_served_geohash_subdomain("drt2z", &[5]));
+        for sub in ["d", "dr", "drt", "drt2", "drt2zb", "drt2zby"] {
+            assert!(is_geohash_subdomain(sub));
+            assert!(!is_served_geohash_subdomain(sub, &[5]), "{} should not be served", sub);
+        }
+        assert_eq!(nearest_allowed_precision(7, &[5]), Some(5));
+        assert_eq!(nearest_allowed_precision(4, &[3, 5]), Some(3));
+        assert_eq!(nearest_allowed_precision(4, &[]), None);
+    }
+
+    #[test]
+    fn test_to_precision_truncates_and_pads() {
+        assert_eq!(to_precision("drt2zby", 5), Some("drt2z".to_string()));
+        let padded = to_precision("drt", 5).unwrap();
+        assert_eq!(padded.len(), 5);
+        assert!(padded.starts_with("drt"));
+        assert_eq!(to_precision("drt", 0), None);
+
+        assert_eq!(containing_allowed_cell("drt2zby", &[3, 5]), Some("drt2z".to_string()));
+        assert_eq!(containing_allowed_cell("drt", &[5]), None);
+    }
+}
@@ -0,0 +1,111 @@
+//! GeoIP cell suggestions for the root landing page
+//!
+//! With `geoip_database` pointing at a MaxMind City database, the root info
+//! page looks up the visitor's address and suggests the cell containing the
+//! located coordinates, at `geoip_precision` (or the nearest allowed one).
+//! Nothing is shown when the database isn't configured, the address isn't
+//! in it, or the record has no coordinates. Lookups and their results are
+//! logged at debug level at most, and never stored.
+
+use anyhow::{Context, Result};
+use maxminddb::{geoip2, Reader};
+use std::net::IpAddr;
+use tracing::debug;
+use crate::config::RelayConfig;
+use crate::geohash_utils::{encode_latlon, nearest_allowed_precision};
+
+#[derive(Default)]
+pub struct GeoIp {
+    /// `None` when no database is configured
+    reader: Option<Reader<Vec<u8>>>,
+    precision: usize,
+}
+
+impl GeoIp {
+    /// Opens the database at `path`, suggesting cells of `precision`
+    pub fn open(path: &str, precision: usize) -> Result<Self> {
+        let reader = Reader::open_readfile(path).with_context(|| format!("failed to open GeoIP database {}", path))?;
+        Ok(Self {
+            reader: Some(reader),
+            precision,
+        })
+    }
+
+    /// The configured database, or no suggestions without one
+    pub fn for_config(config: &RelayConfig) -> Result<Self> {
+        let Some(path) = &config.geoip_database else {
+            return Ok(Self::disabled());
+        };
+        let precision = nearest_allowed_precision(config.geoip_precision, &config.allowed_precisions)
+            .unwrap_or(config.geoip_precision);
+        Self::open(path, precision)
+    }
+
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Cell the visitor at `ip` is probably in
+    pub fn suggest_cell(&self, ip: IpAddr) -> Option<String> {
+        let reader = self.reader.as_ref()?;
+        let city: geoip2::City = match reader.lookup(ip) {
+            Ok(city) => city,
+            Err(e) => {
+                debug!("GeoIP lookup missed: {}", e);
+                return None;
+            }
+        };
+        let location = city.location?;
+        let cell = encode_latlon(location.latitude?, location.longitude?, self.precision);
+        debug!("GeoIP suggests {:?}", cell);
+        cell
+    }
+}
+
+impl std::fmt::Debug for GeoIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GeoIp")
+            .field("enabled", &self.reader.is_some())
+            .field("precision", &self.precision)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One network, 81.2.69.0/24, in Boston (see the fixture's generator)
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/geoip-test.mmdb");
+
+    #[test]
+    fn test_hit_suggests_cell() {
+        let geoip = GeoIp::open(FIXTURE, 5).unwrap();
+        assert_eq!(geoip.suggest_cell("81.2.69.160".parse().unwrap()).as_deref(), Some("drt2z"));
+
+        let coarse = GeoIp::open(FIXTURE, 3).unwrap();
+        assert_eq!(coarse.suggest_cell("81.2.69.1".parse().unwrap()).as_deref(), Some("drt"));
+    }
+
+    #[test]
+    fn test_miss_suggests_nothing() {
+        let geoip = GeoIp::open(FIXTURE, 5).unwrap();
+        assert_eq!(geoip.suggest_cell("81.2.70.1".parse().unwrap()), None);
+        assert_eq!(geoip.suggest_cell("2001:db8::1".parse().unwrap()), None);
+        assert_eq!(GeoIp::disabled().suggest_cell("81.2.69.160".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_precision_follows_allowed_precisions() {
+        let config = RelayConfig {
+            geoip_database: Some(FIXTURE.to_string()),
+            allowed_precisions: vec![4, 6],
+            ..Default::default()
+        };
+        let geoip = GeoIp::for_config(&config).unwrap();
+        assert_eq!(geoip.suggest_cell("81.2.69.160".parse().unwrap()).map(|cell| cell.len()), Some(4));
+
+        let missing = RelayConfig { geoip_database: Some("/nonexistent.mmdb".to_string()), ..Default::default() };
+        assert!(GeoIp::for_config(&missing).is_err());
+    }
+}
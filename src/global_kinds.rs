@@ -0,0 +1,289 @@
+//! Kinds shared by every scope
+//!
+//! Profiles (kind 0), contact lists (kind 3) and relay lists (kind 10002)
+//! describe a person rather than a place. `handle_event` stores them in the
+//! root scope regardless of which cell they were published on, and
+//! `GlobalKindsMiddleware` answers REQs on geohash scopes with the matching
+//! root events for those kinds in addition to the cell's own results.
+//!
+//! A filter naming its kinds, none of them DMs, is answered here in full:
+//! the cell and root results are merged newest first, without duplicates
+//! (and only the newest of a replaceable event), within the filter's
+//! limit, and relay_builder gets the filter with limit 0 so it only opens
+//! the live subscription. Other filters get root's global-kind events
+//! before relay_builder's own results, each capped at the limit on its own.
+//! Either way the stored events are sent before relay_builder's EOSE.
+//!
+//! `ReadChecks` runs the processor's `verify_filters` on the REQ before
+//! anything is sent, so a REQ relay_builder will refuse (read auth, `#g`
+//! limits) gets no events, and `can_see_event` on every event sent, so
+//! tombstoned and expired events stay hidden.
+//!
+//! Only stored events are unioned; live events published to root are not
+//! broadcast to subscribers on other scopes.
+
+use anyhow::Result;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::{InboundContext, InboundProcessor, NostrMiddleware};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+use tracing::{debug, warn};
+use crate::config::RelayConfig;
+use crate::processor::ConnectionState;
+use crate::query_cache::cacheable;
+use crate::read_checks::ReadChecks;
+use crate::scope_policy::{DefaultScopePolicy, ScopePolicy};
+use crate::store::ScopeStore;
+
+/// Default kinds routed to and read from the root scope
+pub const DEFAULT_GLOBAL_KINDS: [u16; 3] = [0, 3, 10002];
+
+/// Whether `handle_event` stores events of `kind` in root
+///
+/// Deletion requests never move, even if listed: storage applies them to
+/// the scope they're stored in, and one sent to a cell must not reach
+/// root's copy of the same addressable event.
+pub fn is_stored_in_root(kind: Kind, global_kinds: &[u16]) -> bool {
+    kind != Kind::EventDeletion && global_kinds.contains(&kind.as_u16())
+}
+
+/// Restricts a filter to the global kinds, or None if it can't match any
+///
+/// Filters without a kinds constraint are narrowed to all global kinds.
+pub fn root_filter(filter: &Filter, global_kinds: &[Kind]) -> Option<Filter> {
+    let kinds: BTreeSet<Kind> = match &filter.kinds {
+        Some(requested) => requested
+            .iter()
+            .filter(|kind| global_kinds.contains(kind))
+            .copied()
+            .collect(),
+        None => global_kinds.iter().copied().collect(),
+    };
+    if kinds.is_empty() {
+        return None;
+    }
+    let mut narrowed = filter.clone();
+    narrowed.kinds = Some(kinds);
+    Some(narrowed)
+}
+
+/// Root-scope events for the global kinds matching any of `filters`
+pub async fn query_global(
+    store: &dyn ScopeStore,
+    filters: &[Filter],
+    global_kinds: &[Kind],
+) -> Result<Vec<Event>> {
+    let mut seen = HashSet::new();
+    let mut events = Vec::new();
+    for filter in filters.iter().filter_map(|f| root_filter(f, global_kinds)) {
+        for event in store.query(&Scope::Default, filter).await? {
+            if seen.insert(event.id) {
+                events.push(event);
+            }
+        }
+    }
+    Ok(events)
+}
+
+/// Whether `filter` can be answered from the cell and root together:
+/// it names its kinds, none of them DMs, whose visibility depends on the
+/// connection
+pub fn mergeable(filter: &Filter) -> bool {
+    cacheable(std::slice::from_ref(filter))
+}
+
+/// `filter`'s stored events in `cell` merged with root's for the global
+/// kinds, newest first and within the filter's limit
+///
+/// Events in both scopes appear once, and of a replaceable event only the
+/// newest version is kept.
+pub async fn query_merged(store: &dyn ScopeStore, cell: &Scope, filter: &Filter, global_kinds: &[Kind]) -> Result<Vec<Event>> {
+    let mut events = store.query(cell, filter.clone()).await?;
+    if let Some(root) = root_filter(filter, global_kinds) {
+        events.extend(store.query(&Scope::Default, root).await?);
+    }
+    events.sort_by(|a, b| b.created_at.cmp(&a.created_at).then_with(|| a.id.cmp(&b.id)));
+
+    let mut seen = HashSet::new();
+    let mut newest: HashMap<(Kind, PublicKey), EventId> = HashMap::new();
+    events.retain(|event| {
+        if !seen.insert(event.id) {
+            return false;
+        }
+        if event.kind.is_replaceable() {
+            return *newest.entry((event.kind, event.pubkey)).or_insert(event.id) == event.id;
+        }
+        true
+    });
+    if let Some(limit) = filter.limit {
+        events.truncate(limit);
+    }
+    Ok(events)
+}
+
+/// Adds root-scope results for the global kinds to REQs on geohash scopes
+#[derive(Clone)]
+pub struct GlobalKindsMiddleware<P = DefaultScopePolicy> {
+    store: Arc<dyn ScopeStore>,
+    /// Runs the read checks relay_builder would have run on the REQ and
+    /// each event
+    read_checks: ReadChecks<P>,
+    global_kinds: Vec<Kind>,
+}
+
+impl<P: ScopePolicy> GlobalKindsMiddleware<P> {
+    pub fn new(store: Arc<dyn ScopeStore>, read_checks: ReadChecks<P>, config: &RelayConfig) -> Self {
+        Self {
+            store,
+            read_checks,
+            global_kinds: config.global_kinds.iter().map(|k| Kind::from(*k)).collect(),
+        }
+    }
+}
+
+impl<P: ScopePolicy + Clone> NostrMiddleware<ConnectionState> for GlobalKindsMiddleware<P> {
+    async fn process_inbound<Next>(&self, mut ctx: InboundContext<'_, ConnectionState, Next>) -> Result<(), anyhow::Error>
+    where
+        Next: InboundProcessor<ConnectionState>,
+    {
+        let cell = ctx.state.read().subdomain.as_ref().clone();
+        if matches!(cell, Scope::Default) || self.global_kinds.is_empty() {
+            return ctx.next().await;
+        }
+        let request = match &ctx.message {
+            Some(ClientMessage::Req { subscription_id, filters }) => {
+                Some((SubscriptionId::clone(subscription_id), filters.clone()))
+            }
+            _ => None,
+        };
+        let Some((subscription_id, filters)) = request else {
+            return ctx.next().await;
+        };
+        let reader = {
+            let state = ctx.state.read();
+            self.read_checks.reader(state.subdomain.clone(), state.authed_pubkey, &state.custom)
+        };
+        // Nothing is sent for a REQ relay_builder is about to refuse
+        if reader.verify(&filters).is_err() {
+            return ctx.next().await;
+        }
+
+        // Sent before the relay's own results so everything lands before EOSE
+        let mut sent = HashSet::new();
+        let mut answered = Vec::new();
+        for (index, filter) in filters.iter().enumerate() {
+            let Some(root) = root_filter(filter, &self.global_kinds) else {
+                continue;
+            };
+            let merge = mergeable(filter);
+            let events = if merge {
+                query_merged(self.store.as_ref(), &cell, filter, &self.global_kinds).await
+            } else {
+                self.store.query(&Scope::Default, root).await
+            };
+            match events {
+                Ok(events) => {
+                    if merge {
+                        answered.push(index);
+                    }
+                    let visible = events.into_iter().filter(|event| reader.can_see(event));
+                    for event in visible.filter(|event| sent.insert(event.id)) {
+                        ctx.send_message(RelayMessage::event(subscription_id.clone(), event))?;
+                    }
+                }
+                Err(e) => warn!("Failed to query global kinds for {}: {}", subscription_id, e),
+            }
+        }
+        debug!("Added {} merged or root events to {}", sent.len(), subscription_id);
+
+        // Answered filters only open the live subscription
+        if let Some(ClientMessage::Req { filters, .. }) = ctx.message.as_mut() {
+            for index in answered {
+                filters[index].limit = Some(0);
+            }
+        }
+        ctx.next().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn global() -> Vec<Kind> {
+        DEFAULT_GLOBAL_KINDS.iter().map(|k| Kind::from(*k)).collect()
+    }
+
+    #[test]
+    fn test_root_filter_narrows_kinds() {
+        let filter = Filter::new().kinds([Kind::Metadata, Kind::TextNote]);
+        let narrowed = root_filter(&filter, &global()).unwrap();
+        assert_eq!(narrowed.kinds, Some(BTreeSet::from([Kind::Metadata])));
+
+        assert!(root_filter(&Filter::new().kind(Kind::TextNote), &global()).is_none());
+        assert!(root_filter(&filter, &[]).is_none());
+
+        // Unconstrained filters ask root for every global kind
+        let keys = Keys::generate();
+        let narrowed = root_filter(&Filter::new().author(keys.public_key()), &global()).unwrap();
+        assert_eq!(narrowed.kinds.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_profile_readable_from_any_scope() {
+        let store = MemoryStore::new();
+        let keys = Keys::generate();
+        let profile = EventBuilder::new(Kind::Metadata, r#"{"name":"alice"}"#)
+            .sign(&keys)
+            .await
+            .unwrap();
+        let note = EventBuilder::text_note("root note").sign(&keys).await.unwrap();
+        store.insert(&Scope::Default, profile.clone());
+        store.insert(&Scope::Default, note);
+
+        // From another cell: only the global kind comes through
+        let filters = vec![Filter::new().author(keys.public_key())];
+        let events = query_global(&store, &filters, &global()).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].id, profile.id);
+
+        // Overlapping filters don't produce duplicates
+        let filters = vec![Filter::new().kind(Kind::Metadata), Filter::new().author(keys.public_key())];
+        assert_eq!(query_global(&store, &filters, &global()).await.unwrap().len(), 1);
+
+        // Disabled: nothing is unioned
+        assert!(query_global(&store, &filters, &[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_merged_query_dedupes_and_keeps_the_limit() {
+        let store = MemoryStore::new();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let keys = Keys::generate();
+        let profile = |name: &str, at: u64| {
+            EventBuilder::new(Kind::Metadata, format!(r#"{{"name":"{}"}}"#, name)).custom_created_at(Timestamp::from(at))
+        };
+        // An old copy left in the cell, the current one in root
+        let stale = profile("old", 1_000).sign(&keys).await.unwrap();
+        let current = profile("new", 2_000).sign(&keys).await.unwrap();
+        let note = EventBuilder::text_note("cell note").custom_created_at(Timestamp::from(1_500)).sign(&keys).await.unwrap();
+        let root_note = EventBuilder::text_note("root note").sign(&keys).await.unwrap();
+        store.insert(&drt2z, stale);
+        store.insert(&drt2z, note.clone());
+        store.insert(&Scope::Default, current.clone());
+        store.insert(&Scope::Default, root_note);
+
+        let filter = Filter::new().kinds([Kind::Metadata, Kind::TextNote]).author(keys.public_key());
+        let events = query_merged(&store, &drt2z, &filter, &global()).await.unwrap();
+        assert_eq!(events.iter().map(|e| e.id).collect::<Vec<_>>(), [current.id, note.id]);
+
+        let events = query_merged(&store, &drt2z, &filter.clone().limit(1), &global()).await.unwrap();
+        assert_eq!(events.iter().map(|e| e.id).collect::<Vec<_>>(), [current.id]);
+
+        assert!(mergeable(&filter));
+        assert!(!mergeable(&Filter::new().author(keys.public_key())));
+        assert!(!mergeable(&Filter::new().kinds([Kind::Metadata, Kind::GiftWrap])));
+    }
+}
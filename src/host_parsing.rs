@@ -0,0 +1,378 @@
+//! Host header parsing for the HTTP routes and websocket upgrades
+//!
+//! Splits the Host header into an optional subdomain and the base domain the
+//! request was made against. `resolve_scope` turns that into the `Scope` a
+//! websocket connection is served in; `server::ScopedHandlerFactory` makes
+//! relay_builder use it rather than its own Host parsing, so HTTP routes
+//! and websocket connections can't disagree on the scope.
+//!
+//! `connection_origin` keeps what relay_builder doesn't see: the base domain
+//! and scheme a websocket client actually used, so rejection hints can point
+//! it at `wss://{geohash}.{that domain}`.
+
+use axum::http::HeaderMap;
+use nostr_lmdb::Scope;
+use std::net::IpAddr;
+use crate::config::RelayConfig;
+use crate::geohash_utils::is_valid_geohash;
+
+/// Labels in the base domain when none is known (`example.com`)
+pub const DEFAULT_BASE_DOMAIN_PARTS: usize = 2;
+
+/// Host that resolves to the root scope, for requests that came without one
+pub const ROOT_HOST: &str = "localhost";
+
+/// Subdomain and domain extracted from a Host header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostInfo {
+    pub subdomain: Option<String>,
+    pub domain: String,
+}
+
+/// Strips the port from a Host value, keeping bracketed IPv6 literals whole
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        };
+    }
+    // A bare IPv6 address has colons but no port
+    if host.parse::<IpAddr>().is_ok() {
+        return host;
+    }
+    host.split(':').next().unwrap_or(host)
+}
+
+/// Parses a raw Host value (with optional port) into subdomain + domain
+///
+/// The last `base_domain_parts` labels are the domain and everything before
+/// them is the subdomain.
+pub fn parse_host(host: &str, base_domain_parts: usize) -> HostInfo {
+    // Strip port and any leading/trailing dots (`example.com.` is the same host)
+    let host_without_port = strip_port(host).trim_matches('.');
+
+    // IP addresses never have subdomains
+    let is_ip = host_without_port.starts_with('[')
+        || host_without_port.parse::<IpAddr>().is_ok();
+
+    let base_domain_parts = base_domain_parts.max(1);
+    let parts: Vec<&str> = host_without_port.split('.').collect();
+    if !is_ip && parts.len() > base_domain_parts {
+        // Definitely has subdomain (e.g., test.example.com)
+        let split = parts.len() - base_domain_parts;
+        HostInfo {
+            subdomain: Some(parts[..split].join(".")),
+            domain: parts[split..].join("."),
+        }
+    } else if !is_ip && parts.len() == 2 && is_valid_geohash(parts[0]) {
+        // Two parts where the first is a geohash (e.g., drt2z.localhost)
+        HostInfo {
+            subdomain: Some(parts[0].to_string()),
+            domain: parts[1].to_string(),
+        }
+    } else {
+        // No subdomain (localhost, example.local, etc.)
+        HostInfo {
+            subdomain: None,
+            domain: host_without_port.to_string(),
+        }
+    }
+}
+
+/// Extracts subdomain + domain from request headers, defaulting to localhost
+pub fn host_info(headers: &HeaderMap, base_domain_parts: usize) -> HostInfo {
+    let host = headers
+        .get("host")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or(ROOT_HOST);
+    parse_host(host, base_domain_parts)
+}
+
+/// Scope for a request's Host header
+///
+/// Subdomains are lowercased, so `DRT2Z.example.com` is the `drt2z` cell.
+/// Subdomains that aren't geohashes still get a named scope; the processor
+/// rejects writes to them with `RejectReason::InvalidSubdomain`.
+pub fn resolve_scope(headers: &HeaderMap, base_domain_parts: usize) -> Scope {
+    match host_info(headers, base_domain_parts).subdomain {
+        Some(subdomain) => Scope::named(&subdomain.to_lowercase()).unwrap_or(Scope::Default),
+        None => Scope::Default,
+    }
+}
+
+/// Base domain, scheme and address a websocket client connected with
+///
+/// Either of the first two is `None` when the request didn't establish it,
+/// in which case the configured one applies.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionOrigin {
+    pub base_domain: Option<String>,
+    /// From `X-Forwarded-Proto`
+    pub secure: Option<bool>,
+    /// From `client_ip`, so the client behind a trusted proxy
+    pub client_ip: Option<IpAddr>,
+}
+
+/// Origin of a websocket request from `peer`
+///
+/// The Host's base domain only counts if it's the configured one or one of
+/// `alternate_base_domains`, so a forged Host can't put arbitrary URLs in
+/// rejection messages. `X-Forwarded-Proto` is only believed from localhost
+/// and `trusted_proxies`.
+pub fn connection_origin(headers: &HeaderMap, peer: IpAddr, config: &RelayConfig) -> ConnectionOrigin {
+    let domain = headers
+        .get("host")
+        .and_then(|h| h.to_str().ok())
+        .map(|host| parse_host(host, config.base_domain_parts()).domain.to_lowercase());
+    let base_domain = domain.filter(|domain| {
+        config.base_domain().as_deref() == Some(domain.as_str()) || config.alternate_base_domains.contains(domain)
+    });
+
+    let trusted_peer = peer.is_loopback() || config.trusted_proxies.contains(&peer);
+    let secure = headers
+        .get("x-forwarded-proto")
+        .and_then(|proto| proto.to_str().ok())
+        .filter(|_| trusted_peer)
+        .and_then(|proto| match proto.trim().to_ascii_lowercase().as_str() {
+            "https" => Some(true),
+            "http" => Some(false),
+            _ => None,
+        });
+    ConnectionOrigin { base_domain, secure, client_ip: Some(client_ip(headers, peer, config)) }
+}
+
+/// Address of the client behind `peer`
+///
+/// Localhost and `trusted_proxies` are believed about the last address they
+/// appended to `X-Forwarded-For`; anyone else is the client themselves.
+pub fn client_ip(headers: &HeaderMap, peer: IpAddr, config: &RelayConfig) -> IpAddr {
+    if !peer.is_loopback() && !config.trusted_proxies.contains(&peer) {
+        return peer;
+    }
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .last()
+        .and_then(|last| last.trim().parse().ok())
+        .unwrap_or(peer)
+}
+
+/// Host value that resolves to `subdomain` with `base_domain_parts`
+///
+/// Used when the scope comes from somewhere other than the Host header (see
+/// `RelayConfig::dev_scope_query_param`); the base domain is a placeholder
+/// under `localhost`.
+pub fn host_for_scope(subdomain: &str, base_domain_parts: usize) -> String {
+    let mut labels = vec!["dev"; base_domain_parts.max(1) - 1];
+    labels.push("localhost");
+    format!("{}.{}", subdomain, labels.join("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_host(host: &str) -> HostInfo {
+        super::parse_host(host, DEFAULT_BASE_DOMAIN_PARTS)
+    }
+
+    #[test]
+    fn test_root_domain() {
+        assert_eq!(
+            parse_host("example.com"),
+            HostInfo { subdomain: None, domain: "example.com".to_string() }
+        );
+        assert_eq!(
+            parse_host("localhost:8080"),
+            HostInfo { subdomain: None, domain: "localhost".to_string() }
+        );
+    }
+
+    #[test]
+    fn test_subdomain_with_port() {
+        assert_eq!(
+            parse_host("drt2z.example.com:443"),
+            HostInfo { subdomain: Some("drt2z".to_string()), domain: "example.com".to_string() }
+        );
+    }
+
+    #[test]
+    fn test_two_part_geohash_host() {
+        assert_eq!(
+            parse_host("drt2z.localhost"),
+            HostInfo { subdomain: Some("drt2z".to_string()), domain: "localhost".to_string() }
+        );
+    }
+
+    #[test]
+    fn test_host_for_scope_round_trips() {
+        for base_domain_parts in 1..=3 {
+            let host = host_for_scope("drt2z", base_domain_parts);
+            let info = super::parse_host(&host, base_domain_parts);
+            assert_eq!(info.subdomain.as_deref(), Some("drt2z"), "{}", host);
+        }
+    }
+
+    #[test]
+    fn test_missing_host_header() {
+        assert_eq!(host_info(&HeaderMap::new(), DEFAULT_BASE_DOMAIN_PARTS).domain, "localhost");
+    }
+
+    #[test]
+    fn test_longer_base_domains() {
+        assert_eq!(
+            super::parse_host("drt2z.relay.mycompany.com", 3),
+            HostInfo { subdomain: Some("drt2z".to_string()), domain: "relay.mycompany.com".to_string() }
+        );
+        assert_eq!(
+            super::parse_host("relay.mycompany.com", 3),
+            HostInfo { subdomain: None, domain: "relay.mycompany.com".to_string() }
+        );
+        assert_eq!(
+            super::parse_host("drt2z.nostr.example.co.uk:443", 4),
+            HostInfo { subdomain: Some("drt2z".to_string()), domain: "nostr.example.co.uk".to_string() }
+        );
+        // Everything in front of the base domain is the subdomain
+        assert_eq!(
+            super::parse_host("www.drt2z.example.com", 2),
+            HostInfo { subdomain: Some("www.drt2z".to_string()), domain: "example.com".to_string() }
+        );
+    }
+
+    #[test]
+    fn test_ip_hosts_have_no_subdomain() {
+        for (host, domain) in [
+            ("127.0.0.1", "127.0.0.1"),
+            ("10.0.0.5:8080", "10.0.0.5"),
+            ("[::1]:8080", "[::1]"),
+            ("::1", "::1"),
+        ] {
+            assert_eq!(parse_host(host), HostInfo { subdomain: None, domain: domain.to_string() });
+        }
+    }
+
+    #[test]
+    fn test_resolve_scope_matrix() {
+        let named = |name: &str| Scope::named(name).unwrap();
+        let cases = [
+            (Some("drt2z.example.com"), named("drt2z")),
+            (Some("DRT2Z.Example.COM"), named("drt2z")),
+            (Some("drt2z.example.com:8443"), named("drt2z")),
+            (Some("DrT2z.example.com.:443"), named("drt2z")),
+            (Some("example.com"), Scope::Default),
+            (Some("example.com:8080"), Scope::Default),
+            (Some("127.0.0.1:8080"), Scope::Default),
+            (Some("[::1]:8080"), Scope::Default),
+            // Reserved and invalid names keep their scope so writes are rejected
+            (Some("www.example.com"), named("www")),
+            (Some("api.example.com"), named("api")),
+            (Some("drt2zzzz.example.com"), named("drt2zzzz")),
+            (Some("www.drt2z.example.com"), named("www.drt2z")),
+            (None, Scope::Default),
+        ];
+        for (host, expected) in cases {
+            let mut headers = HeaderMap::new();
+            if let Some(host) = host {
+                headers.insert("host", host.parse().unwrap());
+            }
+            assert_eq!(resolve_scope(&headers, DEFAULT_BASE_DOMAIN_PARTS), expected, "{:?}", host);
+        }
+    }
+
+    #[test]
+    fn test_resolve_scope_round_trips_through_host_for_scope() {
+        for base_domain_parts in 1..=3 {
+            let mut headers = HeaderMap::new();
+            headers.insert("host", host_for_scope("drt2z", base_domain_parts).parse().unwrap());
+            assert_eq!(resolve_scope(&headers, base_domain_parts), Scope::named("drt2z").unwrap());
+        }
+    }
+
+    #[test]
+    fn test_trailing_dot() {
+        assert_eq!(
+            parse_host("drt2z.example.com.:443"),
+            HostInfo { subdomain: Some("drt2z".to_string()), domain: "example.com".to_string() }
+        );
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_parse_host_invariants(host in "\\PC{0,40}", base_domain_parts in 1usize..5) {
+            let info = super::parse_host(&host, base_domain_parts);
+            if let Some(subdomain) = &info.subdomain {
+                proptest::prop_assert!(!subdomain.is_empty());
+                proptest::prop_assert!(!subdomain.starts_with('.'));
+                proptest::prop_assert!(!subdomain.contains(':'));
+            }
+            let rebuilt = match &info.subdomain {
+                Some(subdomain) => format!("{}.{}", subdomain, info.domain),
+                None => info.domain.clone(),
+            };
+            proptest::prop_assert_eq!(super::parse_host(&rebuilt, base_domain_parts), info);
+        }
+
+        #[test]
+        fn prop_hostnames_split_on_first_label(sub in "[a-z0-9]{1,10}", domain in "[a-z]{1,10}\\.[a-z]{2,5}", port in proptest::option::of(1u16..)) {
+            let host = match port {
+                Some(port) => format!("{}.{}:{}", sub, domain, port),
+                None => format!("{}.{}", sub, domain),
+            };
+            proptest::prop_assert_eq!(parse_host(&host), HostInfo { subdomain: Some(sub), domain });
+        }
+    }
+
+    #[test]
+    fn test_connection_origin_trusts_known_domains_and_proxies() {
+        let config = RelayConfig {
+            relay_url: "wss://relay.example.com".to_string(),
+            alternate_base_domains: vec!["relay.corp.internal".to_string()],
+            trusted_proxies: vec!["10.0.0.2".parse().unwrap()],
+            ..Default::default()
+        };
+        let headers = |host: &'static str, proto: Option<&'static str>| {
+            let mut headers = HeaderMap::new();
+            headers.insert("host", host.parse().unwrap());
+            if let Some(proto) = proto {
+                headers.insert("x-forwarded-proto", proto.parse().unwrap());
+            }
+            headers
+        };
+        let proxy: IpAddr = "10.0.0.2".parse().unwrap();
+        let client: IpAddr = "203.0.113.9".parse().unwrap();
+
+        let origin = connection_origin(&headers("drt2z.relay.corp.internal:8080", Some("http")), proxy, &config);
+        assert_eq!(origin.base_domain.as_deref(), Some("relay.corp.internal"));
+        assert_eq!(origin.secure, Some(false));
+
+        let origin = connection_origin(&headers("DRT2Z.Relay.Example.com", None), client, &config);
+        assert_eq!(origin.base_domain.as_deref(), Some("relay.example.com"));
+        assert_eq!(origin.secure, None);
+
+        // Unknown domains and proxy headers from anyone else are ignored
+        let origin = connection_origin(&headers("drt2z.evil.test", Some("http")), client, &config);
+        assert_eq!(origin, ConnectionOrigin { client_ip: Some(client), ..Default::default() });
+
+        // The proxy's client, not the proxy
+        let mut forwarded = headers("drt2z.relay.example.com", None);
+        forwarded.insert("x-forwarded-for", "198.51.100.7".parse().unwrap());
+        assert_eq!(connection_origin(&forwarded, proxy, &config).client_ip, Some("198.51.100.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_client_ip_only_forwarded_by_trusted_proxies() {
+        let config = RelayConfig { trusted_proxies: vec!["10.0.0.2".parse().unwrap()], ..Default::default() };
+        let mut headers = HeaderMap::new();
+        headers.append("x-forwarded-for", "1.1.1.1".parse().unwrap());
+        headers.append("x-forwarded-for", "192.0.2.9, 198.51.100.7".parse().unwrap());
+        let ip = |peer: &str| client_ip(&headers, peer.parse().unwrap(), &config).to_string();
+
+        assert_eq!(ip("10.0.0.2"), "198.51.100.7");
+        assert_eq!(ip("127.0.0.1"), "198.51.100.7");
+        assert_eq!(ip("203.0.113.5"), "203.0.113.5");
+        assert_eq!(client_ip(&HeaderMap::new(), "10.0.0.2".parse().unwrap(), &config).to_string(), "10.0.0.2");
+    }
+}
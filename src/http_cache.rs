@@ -0,0 +1,354 @@
+//! HTTP caching helpers for the relay info page
+//!
+//! The info page is fully determined by the scope (subdomain), the domain it
+//! was requested on, and the running configuration. That lets us compute the
+//! ETag from those inputs alone and answer `If-None-Match` with a 304 before
+//! rendering anything. Rendered pages are kept in a small bounded map with a
+//! short TTL so repeated visits to a busy cell don't rebuild the HTML.
+
+use axum::{
+    body::{Body, HttpBody},
+    http::{header, HeaderMap, HeaderValue, Response, StatusCode},
+};
+use parking_lot::Mutex;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tower_http::compression::{predicate::DefaultPredicate, CompressionLayer, Predicate};
+
+/// How long a rendered info page stays in the cache
+pub const DEFAULT_PAGE_TTL: Duration = Duration::from_secs(60);
+
+/// Maximum number of scopes kept in the page cache
+pub const DEFAULT_PAGE_CACHE_CAPACITY: usize = 1024;
+
+/// A rendered page together with its validator
+#[derive(Debug, Clone)]
+pub struct CachedPage {
+    pub html: Arc<str>,
+    pub etag: String,
+    rendered_at: Instant,
+}
+
+/// Bounded, TTL-based cache of rendered info pages keyed by scope + domain
+#[derive(Debug)]
+pub struct PageCache {
+    entries: Mutex<HashMap<String, CachedPage>>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl Default for PageCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_PAGE_TTL, DEFAULT_PAGE_CACHE_CAPACITY)
+    }
+}
+
+impl PageCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            ttl,
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Returns the cached page for `key` if it is still fresh and was rendered
+    /// for the same `etag`, otherwise renders it with `render` and stores it.
+    pub fn get_or_render(&self, key: &str, etag: &str, render: impl FnOnce() -> String) -> CachedPage {
+        let now = Instant::now();
+
+        if let Some(page) = self.entries.lock().get(key) {
+            if page.etag == etag && now.duration_since(page.rendered_at) < self.ttl {
+                return page.clone();
+            }
+        }
+
+        // Render outside the lock so a slow render doesn't block other scopes
+        let page = CachedPage {
+            html: Arc::from(render()),
+            etag: etag.to_string(),
+            rendered_at: now,
+        };
+
+        let mut entries = self.entries.lock();
+        if entries.len() >= self.capacity && !entries.contains_key(key) {
+            // Drop expired pages first, then the oldest one if still full
+            entries.retain(|_, p| now.duration_since(p.rendered_at) < self.ttl);
+            if entries.len() >= self.capacity {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, p)| p.rendered_at)
+                    .map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(key.to_string(), page.clone());
+
+        page
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().is_empty()
+    }
+}
+
+/// Computes a strong ETag from the inputs that determine the info page
+pub fn etag_for(subdomain: Option<&str>, domain: &str, config_revision: u64) -> String {
+    let mut hasher = DefaultHasher::new();
+    subdomain.hash(&mut hasher);
+    domain.hash(&mut hasher);
+    config_revision.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// Returns true if the request's `If-None-Match` header matches `etag`
+pub fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim())
+        .any(|candidate| {
+            candidate == "*" || candidate == etag || candidate.strip_prefix("W/") == Some(etag)
+        })
+}
+
+fn cache_headers(etag: &str, ttl: Duration) -> axum::http::response::Builder {
+    Response::builder()
+        .header(header::ETAG, etag)
+        .header(header::CACHE_CONTROL, format!("public, max-age={}", ttl.as_secs()))
+        // The page depends on the Host header (and NIP-11 on Accept), so shared
+        // caches must key on both
+        .header(header::VARY, HeaderValue::from_static("host, accept"))
+}
+
+/// Empty 304 response carrying the validator
+pub fn not_modified(etag: &str, ttl: Duration) -> Response<Body> {
+    cache_headers(etag, ttl)
+        .status(StatusCode::NOT_MODIFIED)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Serves a cached page, answering with 304 when the client already has it
+pub fn html_response(headers: &HeaderMap, page: &CachedPage, ttl: Duration) -> Response<Body> {
+    if if_none_match(headers, &page.etag) {
+        return not_modified(&page.etag, ttl);
+    }
+
+    cache_headers(&page.etag, ttl)
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(Body::from(page.html.to_string()))
+        .unwrap()
+}
+
+/// Compression predicate that never touches websocket upgrade responses
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NotForUpgrades;
+
+impl Predicate for NotForUpgrades {
+    fn should_compress<B>(&self, response: &Response<B>) -> bool
+    where
+        B: HttpBody,
+    {
+        response.status() != StatusCode::SWITCHING_PROTOCOLS
+            && !response.headers().contains_key(header::UPGRADE)
+    }
+}
+
+/// Gzip/deflate compression for HTTP responses, leaving upgrades untouched
+pub fn compression_layer() -> CompressionLayer<impl Predicate> {
+    CompressionLayer::new()
+        .gzip(true)
+        .deflate(true)
+        .compress_when(DefaultPredicate::new().and(NotForUpgrades))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::to_bytes, http::Request, routing::get, Router};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use tower::ServiceExt;
+
+    fn sample_html() -> String {
+        // Large enough to clear the compression size threshold
+        format!("<!DOCTYPE html><html><body>{}</body></html>", "<p>drt2z</p>".repeat(200))
+    }
+
+    fn test_router(cache: Arc<PageCache>) -> Router {
+        Router::new()
+            .route(
+                "/",
+                get(move |headers: HeaderMap| {
+                    let cache = cache.clone();
+                    async move {
+                        let etag = etag_for(Some("drt2z"), "example.com", 1);
+                        let page = cache.get_or_render("drt2z.example.com", &etag, sample_html);
+                        html_response(&headers, &page, DEFAULT_PAGE_TTL)
+                    }
+                }),
+            )
+            .layer(compression_layer())
+    }
+
+    #[test]
+    fn test_etag_depends_on_inputs() {
+        let base = etag_for(Some("drt2z"), "example.com", 1);
+        assert_eq!(base, etag_for(Some("drt2z"), "example.com", 1));
+        assert_ne!(base, etag_for(Some("9q8yy"), "example.com", 1));
+        assert_ne!(base, etag_for(None, "example.com", 1));
+        assert_ne!(base, etag_for(Some("drt2z"), "example.org", 1));
+        assert_ne!(base, etag_for(Some("drt2z"), "example.com", 2));
+        assert!(base.starts_with('"') && base.ends_with('"'));
+    }
+
+    #[test]
+    fn test_if_none_match_parsing() {
+        let etag = "\"abc\"";
+        let mut headers = HeaderMap::new();
+        assert!(!if_none_match(&headers, etag));
+
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"other\", \"abc\""));
+        assert!(if_none_match(&headers, etag));
+
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("W/\"abc\""));
+        assert!(if_none_match(&headers, etag));
+
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+        assert!(!if_none_match(&headers, etag));
+    }
+
+    #[test]
+    fn test_cache_reuses_fresh_pages() {
+        let cache = PageCache::new(Duration::from_secs(60), 8);
+        let mut renders = 0;
+
+        cache.get_or_render("drt2z", "\"a\"", || { renders += 1; "one".into() });
+        let page = cache.get_or_render("drt2z", "\"a\"", || { renders += 1; "two".into() });
+        assert_eq!(renders, 1);
+        assert_eq!(&*page.html, "one");
+
+        // A different validator (e.g. config changed) forces a re-render
+        let page = cache.get_or_render("drt2z", "\"b\"", || { renders += 1; "three".into() });
+        assert_eq!(renders, 2);
+        assert_eq!(&*page.html, "three");
+    }
+
+    #[test]
+    fn test_cache_expires_after_ttl() {
+        let cache = PageCache::new(Duration::ZERO, 8);
+        let mut renders = 0;
+        cache.get_or_render("drt2z", "\"a\"", || { renders += 1; String::new() });
+        cache.get_or_render("drt2z", "\"a\"", || { renders += 1; String::new() });
+        assert_eq!(renders, 2);
+    }
+
+    #[test]
+    fn test_cache_is_bounded() {
+        let cache = PageCache::new(Duration::from_secs(60), 3);
+        for key in ["a", "b", "c", "d", "e"] {
+            cache.get_or_render(key, "\"x\"", String::new);
+        }
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_matching_etag_returns_304() {
+        let router = test_router(Arc::new(PageCache::default()));
+
+        let response = router
+            .clone()
+            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers().get(header::ETAG).unwrap().clone();
+        assert!(response.headers().get(header::CACHE_CONTROL).is_some());
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .header(header::IF_NONE_MATCH, etag.clone())
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers().get(header::ETAG), Some(&etag));
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_gzip_round_trips_to_identical_html() {
+        let router = test_router(Arc::new(PageCache::default()));
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .header(header::ACCEPT_ENCODING, "gzip")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+
+        let compressed = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let mut decoded = String::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, sample_html());
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_responses_are_not_compressed() {
+        let router = Router::new()
+            .route(
+                "/",
+                get(|| async {
+                    Response::builder()
+                        .status(StatusCode::SWITCHING_PROTOCOLS)
+                        .header(header::UPGRADE, "websocket")
+                        .header(header::CONNECTION, "upgrade")
+                        .body(Body::from(sample_html()))
+                        .unwrap()
+                }),
+            )
+            .layer(compression_layer());
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .header(header::ACCEPT_ENCODING, "gzip")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+    }
+}
@@ -0,0 +1,289 @@
+//! IP allowlist and denylist
+//!
+//! `ip_allowlist` and `ip_denylist` hold CIDR ranges (IPv4 and IPv6; a bare
+//! address is a single host). A client is refused with 403 when its address,
+//! as resolved through `trusted_proxies`, is in the denylist, or when the
+//! allowlist is non-empty and doesn't contain it. Deny wins over allow and
+//! empty lists restrict nothing. Websocket upgrades are checked before the
+//! upgrade, and the same rules guard the `/api/` routes.
+//!
+//! On SIGHUP both lists are read again from `.env` (falling back to the
+//! environment the relay started with). A list that fails to parse leaves
+//! the running rules alone.
+
+use anyhow::{bail, Context, Result};
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::{info, warn};
+use crate::config::RelayConfig;
+
+/// File SIGHUP re-reads the lists from
+pub const RELOAD_ENV_FILE: &str = ".env";
+
+/// A network in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    /// With the host bits cleared
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (network, width) = bits(self.network);
+        let (ip, ip_width) = bits(ip.to_canonical());
+        width == ip_width && mask(ip, width, self.prefix) == network
+    }
+}
+
+/// Address as an integer, with its width in bits
+fn bits(ip: IpAddr) -> (u128, u8) {
+    match ip {
+        IpAddr::V4(ip) => (u32::from(ip).into(), 32),
+        IpAddr::V6(ip) => (u128::from(ip), 128),
+    }
+}
+
+/// `bits` with everything after the first `prefix` of `width` cleared
+fn mask(bits: u128, width: u8, prefix: u8) -> u128 {
+    if prefix == 0 {
+        return 0;
+    }
+    let ones = if width == 128 { u128::MAX } else { (1u128 << width) - 1 };
+    bits & (ones << (width - prefix)) & ones
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let value = value.trim();
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .with_context(|| format!("invalid address in '{}'", value))?;
+        let width = bits(address).1;
+        let prefix = match prefix {
+            None => width,
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= width => prefix,
+                _ => bail!("invalid prefix length in '{}' (expected 0 to {})", value, width),
+            },
+        };
+        // Clients are matched by their canonical address, so ranges of
+        // mapped IPv4 addresses are written as IPv4 too
+        let (address, prefix) = match address.to_canonical() {
+            IpAddr::V4(ip) if address.is_ipv6() && prefix >= 96 => (IpAddr::V4(ip), prefix - 96),
+            _ => (address, prefix),
+        };
+        let (address_bits, width) = bits(address);
+        let masked = mask(address_bits, width, prefix);
+        let network = match address {
+            IpAddr::V4(_) => IpAddr::V4((masked as u32).into()),
+            IpAddr::V6(_) => IpAddr::V6(masked.into()),
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+/// Parses a comma-separated list of CIDR ranges
+pub fn parse_cidr_list(value: &str) -> Result<Vec<Cidr>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+#[derive(Debug, Default)]
+struct Rules {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+/// The running lists, swapped on reload
+#[derive(Debug, Default)]
+pub struct IpFilter {
+    rules: ArcSwap<Rules>,
+}
+
+impl IpFilter {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        Self {
+            rules: ArcSwap::from_pointee(Rules { allow, deny }),
+        }
+    }
+
+    pub fn for_config(config: &RelayConfig) -> Self {
+        Self::new(config.ip_allowlist.clone(), config.ip_denylist.clone())
+    }
+
+    /// No restriction, for tests and tooling
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    pub fn admits(&self, ip: IpAddr) -> bool {
+        let rules = self.rules.load();
+        if rules.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        rules.allow.is_empty() || rules.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Replaces both lists
+    pub fn replace(&self, allow: Vec<Cidr>, deny: Vec<Cidr>) {
+        self.rules.store(Arc::new(Rules { allow, deny }));
+    }
+
+    /// Reads both lists again, keeping the running ones if either is invalid
+    pub fn reload(&self) -> Result<()> {
+        let contents = std::fs::read_to_string(RELOAD_ENV_FILE).unwrap_or_default();
+        let list = |name: &str| -> Result<Vec<Cidr>> {
+            let value = env_file_var(&contents, name).or_else(|| std::env::var(name).ok()).unwrap_or_default();
+            parse_cidr_list(&value).with_context(|| format!("invalid {}", name))
+        };
+        let (allow, deny) = (list("IP_ALLOWLIST")?, list("IP_DENYLIST")?);
+        info!("Reloaded IP lists: {} allowed, {} denied ranges", allow.len(), deny.len());
+        self.replace(allow, deny);
+        Ok(())
+    }
+}
+
+/// Value of `name` in the contents of a `.env` file
+fn env_file_var(contents: &str, name: &str) -> Option<String> {
+    contents.lines().find_map(|line| {
+        let line = line.trim();
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line.split_once('=')?;
+        (key.trim() == name).then(|| value.trim().trim_matches(['"', '\'']).to_string())
+    })
+}
+
+/// Reloads `filter` on every SIGHUP
+#[cfg(unix)]
+pub fn spawn_reload_on_sighup(filter: Arc<IpFilter>) {
+    tokio::spawn(async move {
+        let mut hangups = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+            Ok(signal) => signal,
+            Err(e) => {
+                warn!("Failed to install SIGHUP handler, IP lists won't reload: {}", e);
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            if let Err(e) = filter.reload() {
+                warn!("Keeping the running IP lists: {:#}", e);
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn spawn_reload_on_sighup(_filter: Arc<IpFilter>) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(value: &str) -> Cidr {
+        value.parse().unwrap()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_parsing_edge_cases() {
+        // Host bits are cleared and bare addresses are single hosts
+        assert_eq!(cidr("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr(" 192.0.2.7 ").to_string(), "192.0.2.7/32");
+        assert_eq!(cidr("2001:db8::1").to_string(), "2001:db8::1/128");
+        assert_eq!(cidr("2001:db8:abcd::/32").to_string(), "2001:db8::/32");
+        assert_eq!(cidr("0.0.0.0/0").to_string(), "0.0.0.0/0");
+        assert_eq!(cidr("::/0").to_string(), "::/0");
+        assert_eq!(cidr("::ffff:10.0.0.1/128").to_string(), "10.0.0.1/32");
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/-1".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+
+        assert_eq!(parse_cidr_list(" 10.0.0.0/8, ,fd00::/8 ").unwrap().len(), 2);
+        assert!(parse_cidr_list("").unwrap().is_empty());
+        assert!(parse_cidr_list("10.0.0.0/8,nope").is_err());
+    }
+
+    #[test]
+    fn test_cidr_matching() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.255.0.1")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::1")));
+        // Clients behind dual-stack listeners show up as mapped addresses
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.0.0.1")));
+    }
+
+    #[test]
+    fn test_deny_wins_over_allow() {
+        let open = IpFilter::disabled();
+        assert!(open.admits(ip("203.0.113.9")));
+
+        let office = IpFilter::new(vec![cidr("10.0.0.0/8")], Vec::new());
+        assert!(office.admits(ip("10.1.2.3")));
+        assert!(!office.admits(ip("203.0.113.9")));
+
+        let abuse = IpFilter::new(Vec::new(), vec![cidr("203.0.113.0/24")]);
+        assert!(!abuse.admits(ip("203.0.113.9")));
+        assert!(abuse.admits(ip("198.51.100.1")));
+
+        let both = IpFilter::new(vec![cidr("10.0.0.0/8")], vec![cidr("10.6.0.0/16")]);
+        assert!(both.admits(ip("10.1.2.3")));
+        assert!(!both.admits(ip("10.6.0.1")));
+
+        both.replace(Vec::new(), Vec::new());
+        assert!(both.admits(ip("10.6.0.1")));
+    }
+
+    #[test]
+    fn test_env_file_var() {
+        let contents = "# comment\nIP_ALLOWLIST=10.0.0.0/8\nexport IP_DENYLIST=\"10.6.0.0/16\"\n";
+        assert_eq!(env_file_var(contents, "IP_ALLOWLIST").as_deref(), Some("10.0.0.0/8"));
+        assert_eq!(env_file_var(contents, "IP_DENYLIST").as_deref(), Some("10.6.0.0/16"));
+        assert_eq!(env_file_var(contents, "RELAY_URL"), None);
+    }
+}
@@ -0,0 +1,249 @@
+//! Relay key loading and the `keys` subcommands
+//!
+//! The relay signs its self-published events and NIP-42 challenges with
+//! the key in `RELAY_PRIVATE_KEY` (hex or nsec), or the first line of the
+//! file named by `RELAY_PRIVATE_KEY_FILE`. `serve`, `keys show` and
+//! anything else that needs the key go through `relay_keys_from_env`, so
+//! the same parsing rules apply everywhere. Secrets never appear in error
+//! messages.
+//!
+//! The `*_flexible` parsers here are the one place keys and event ids are
+//! read from operators, in config, the admin API and the CLI alike: hex
+//! or the matching bech32 form. Pasting the wrong kind of bech32 value
+//! (an nsec where a pubkey belongs, say) gets an error naming the mix-up.
+
+use anyhow::{bail, Context, Result};
+use nostr::nips::nip19::{FromBech32, ToBech32};
+use nostr_sdk::prelude::*;
+use std::io::Write;
+use std::path::Path;
+
+/// What a bech32 value's prefix says it is, for errors about mixing them up
+fn bech32_kind(value: &str) -> Option<&'static str> {
+    [("npub1", "a public key (npub)"), ("nsec1", "a secret key (nsec)"), ("note1", "an event id (note)")]
+        .into_iter()
+        .find(|(prefix, _)| value.starts_with(prefix))
+        .map(|(_, kind)| kind)
+}
+
+/// Parses a public key given as hex or npub
+pub fn parse_pubkey_flexible(value: &str) -> Result<PublicKey> {
+    let value = value.trim();
+    if value.starts_with("npub1") {
+        return PublicKey::from_bech32(value).map_err(|e| anyhow::anyhow!("invalid npub '{}': {}", value, e));
+    }
+    if value.starts_with("nsec1") {
+        bail!("got a secret key (nsec) where a public key is expected; give its npub or hex public key, and keep the nsec private");
+    }
+    if let Some(kind) = bech32_kind(value) {
+        bail!("got {} where a public key is expected (hex or npub)", kind);
+    }
+    PublicKey::from_hex(value).map_err(|e| anyhow::anyhow!("expected a hex or npub public key, got '{}': {}", value, e))
+}
+
+/// Parses a secret key given as hex or nsec
+pub fn parse_seckey_flexible(value: &str) -> Result<SecretKey> {
+    let value = value.trim();
+    if value.starts_with("nsec1") {
+        return SecretKey::from_bech32(value).map_err(|_| anyhow::anyhow!("invalid nsec secret key"));
+    }
+    if let Some(kind) = bech32_kind(value) {
+        bail!("got {} where a secret key is expected (hex or nsec)", kind);
+    }
+    SecretKey::from_hex(value).map_err(|_| anyhow::anyhow!("expected a hex or nsec secret key"))
+}
+
+/// Parses an event id given as hex or note
+pub fn parse_event_id_flexible(value: &str) -> Result<EventId> {
+    let value = value.trim();
+    if value.starts_with("note1") {
+        return EventId::from_bech32(value).map_err(|e| anyhow::anyhow!("invalid note '{}': {}", value, e));
+    }
+    if let Some(kind) = bech32_kind(value) {
+        bail!("got {} where an event id is expected (hex or note)", kind);
+    }
+    EventId::from_hex(value).map_err(|_| anyhow::anyhow!("expected a hex or note event id, got '{}'", value))
+}
+
+/// Reads the secret key on the first line of `path`
+pub fn read_key_file(path: &Path) -> Result<SecretKey> {
+    let contents = std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let line = contents.lines().next().unwrap_or_default();
+    parse_seckey_flexible(line).with_context(|| format!("invalid key in {}", path.display()))
+}
+
+/// Writes `keys` as an nsec to a new file readable only by its owner
+///
+/// An existing file is never overwritten.
+pub fn write_key_file(path: &Path, keys: &Keys) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path).with_context(|| format!("failed to create {}", path.display()))?;
+    let nsec = keys.secret_key().to_bech32().context("failed to encode nsec")?;
+    writeln!(file, "{}", nsec).with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Keys from `RELAY_PRIVATE_KEY`, else `RELAY_PRIVATE_KEY_FILE`; `None` when
+/// neither is set
+pub fn relay_keys_from_env() -> Result<Option<Keys>> {
+    if let Ok(value) = std::env::var("RELAY_PRIVATE_KEY") {
+        let secret = parse_seckey_flexible(&value).context("invalid RELAY_PRIVATE_KEY")?;
+        return Ok(Some(Keys::new(secret)));
+    }
+    if let Ok(path) = std::env::var("RELAY_PRIVATE_KEY_FILE") {
+        return Ok(Some(Keys::new(read_key_file(Path::new(&path))?)));
+    }
+    Ok(None)
+}
+
+/// Lines printed by `keys generate` and `keys show`
+///
+/// The secret lines are only included with `reveal_secret`.
+pub fn describe(keys: &Keys, reveal_secret: bool) -> Result<Vec<String>> {
+    let mut lines = Vec::new();
+    if reveal_secret {
+        lines.push(format!("secret (hex): {}", keys.secret_key().to_secret_hex()));
+        lines.push(format!("nsec:         {}", keys.secret_key().to_bech32()?));
+    }
+    lines.push(format!("pubkey (hex): {}", keys.public_key().to_hex()));
+    lines.push(format!("npub:         {}", keys.public_key().to_bech32()?));
+    Ok(lines)
+}
+
+/// Runs `keys generate`, optionally saving the new key to `out`
+pub fn generate(out: Option<&Path>) -> Result<()> {
+    let keys = Keys::generate();
+    if let Some(path) = out {
+        write_key_file(path, &keys)?;
+        println!("Wrote nsec to {}", path.display());
+    }
+    for line in describe(&keys, true)? {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Runs `keys show` for the key in `file`, or the relay's configured key
+pub fn show(file: Option<&Path>, reveal_secret: bool) -> Result<()> {
+    let keys = match file {
+        Some(path) => Keys::new(read_key_file(path)?),
+        None => match relay_keys_from_env()? {
+            Some(keys) => keys,
+            None => bail!("neither RELAY_PRIVATE_KEY nor RELAY_PRIVATE_KEY_FILE is set"),
+        },
+    };
+    for line in describe(&keys, reveal_secret)? {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_and_nsec_parse_to_the_same_key() {
+        let keys = Keys::generate();
+        let hex = keys.secret_key().to_secret_hex();
+        let nsec = keys.secret_key().to_bech32().unwrap();
+
+        assert_eq!(&parse_seckey_flexible(&hex).unwrap(), keys.secret_key());
+        assert_eq!(&parse_seckey_flexible(&format!(" {}\n", nsec)).unwrap(), keys.secret_key());
+        assert!(parse_seckey_flexible("nsec1nope").is_err());
+
+        // The rejected value is never echoed back
+        let err = parse_seckey_flexible(&hex[1..]).unwrap_err().to_string();
+        assert!(!err.contains(&hex[1..]));
+    }
+
+    const HEX: &str = "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d";
+    const NPUB: &str = "npub180cvv07tjdrrgpa0j7j7tmnyl2yr6yr7l8j4s3evf6u64th6gkwsyjh6w6";
+
+    #[test]
+    fn test_parse_pubkey_hex_and_npub() {
+        let from_hex = parse_pubkey_flexible(HEX).unwrap();
+        let from_npub = parse_pubkey_flexible(&format!(" {}\n", NPUB)).unwrap();
+        assert_eq!(from_hex, from_npub);
+        assert_eq!(from_npub.to_hex(), HEX);
+    }
+
+    #[test]
+    fn test_parse_pubkey_rejects_garbage() {
+        for value in ["", "not-a-key", "npub1invalid", &HEX[..60]] {
+            let err = parse_pubkey_flexible(value).unwrap_err().to_string();
+            assert!(err.contains("npub"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_nsec_where_npub_expected_is_named_and_not_echoed() {
+        let keys = Keys::generate();
+        let nsec = keys.secret_key().to_bech32().unwrap();
+        let err = parse_pubkey_flexible(&nsec).unwrap_err().to_string();
+        assert!(err.starts_with("got a secret key (nsec) where a public key is expected"), "{}", err);
+        assert!(!err.contains(&nsec));
+
+        let note = EventId::all_zeros().to_bech32().unwrap();
+        let err = parse_pubkey_flexible(&note).unwrap_err().to_string();
+        assert_eq!(err, "got an event id (note) where a public key is expected (hex or npub)");
+    }
+
+    #[test]
+    fn test_npub_where_nsec_expected() {
+        let err = parse_seckey_flexible(NPUB).unwrap_err().to_string();
+        assert_eq!(err, "got a public key (npub) where a secret key is expected (hex or nsec)");
+    }
+
+    #[test]
+    fn test_parse_event_id_hex_and_note() {
+        let id = EventId::from_hex(HEX).unwrap();
+        let note = id.to_bech32().unwrap();
+        assert_eq!(parse_event_id_flexible(HEX).unwrap(), id);
+        assert_eq!(parse_event_id_flexible(&note).unwrap(), id);
+        assert!(parse_event_id_flexible("note1invalid").is_err());
+        assert!(parse_event_id_flexible(&HEX[..60]).unwrap_err().to_string().contains("hex or note"));
+        let err = parse_event_id_flexible(NPUB).unwrap_err().to_string();
+        assert_eq!(err, "got a public key (npub) where an event id is expected (hex or note)");
+    }
+
+    #[test]
+    fn test_describe_hides_secret_unless_revealed() {
+        let keys = Keys::generate();
+        let hex = keys.secret_key().to_secret_hex();
+        let hidden = describe(&keys, false).unwrap().join("\n");
+        assert!(hidden.contains(&keys.public_key().to_hex()));
+        assert!(!hidden.contains(&hex) && !hidden.contains("nsec1"));
+
+        let revealed = describe(&keys, true).unwrap().join("\n");
+        assert!(revealed.contains(&hex) && revealed.contains("nsec1"));
+    }
+
+    #[test]
+    fn test_key_file_round_trips_and_is_never_overwritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("relay.key");
+        let keys = Keys::generate();
+
+        write_key_file(&path, &keys).unwrap();
+        assert_eq!(&read_key_file(&path).unwrap(), keys.secret_key());
+        assert!(write_key_file(&path, &Keys::generate()).is_err());
+        assert_eq!(&read_key_file(&path).unwrap(), keys.secret_key());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_key_file_is_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("relay.key");
+        write_key_file(&path, &Keys::generate()).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,119 @@
+//! `duplicate:` OKs for events the scope already stores
+//!
+//! Clients republish the same signed event all the time (retries, outbox
+//! fan-out). The processor looks the id up in the target scope before the
+//! scope's proof of work, duplicate-content and quota checks see it; a hit
+//! takes nothing from them and is neither written again nor counted as
+//! accepted. It is still a success, so `DuplicateOkMiddleware` turns the
+//! plain OK that follows into
+//! `["OK", id, true, "duplicate: already have this event"]`.
+
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::{NostrMiddleware, OutboundContext};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tracing::warn;
+use crate::processor::ConnectionState;
+use crate::store::ScopeStore;
+
+/// Message of the OK sent for a known event
+pub const DUPLICATE_MESSAGE: &str = "duplicate: already have this event";
+
+/// Existence checks against the store, or none without one
+#[derive(Clone, Default)]
+pub struct KnownEvents {
+    store: Option<Arc<dyn ScopeStore>>,
+}
+
+impl std::fmt::Debug for KnownEvents {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KnownEvents").field("enabled", &self.store.is_some()).finish()
+    }
+}
+
+impl KnownEvents {
+    pub fn new(store: Arc<dyn ScopeStore>) -> Self {
+        Self { store: Some(store) }
+    }
+
+    /// The store looked in, if any
+    pub fn store(&self) -> Option<&Arc<dyn ScopeStore>> {
+        self.store.as_ref()
+    }
+
+    /// Whether `scope` already stores `id`; a failed lookup counts as no
+    pub async fn contains(&self, scope: &Scope, id: EventId) -> bool {
+        let Some(store) = &self.store else {
+            return false;
+        };
+        match store.count(scope, Filter::new().id(id)).await {
+            Ok(count) => count > 0,
+            Err(e) => {
+                warn!("Could not check for event {} in {:?}: {:#}", id, scope, e);
+                false
+            }
+        }
+    }
+}
+
+/// Known events of a connection waiting for their OK
+#[derive(Debug, Clone, Default)]
+pub struct PendingDuplicates {
+    ids: HashSet<EventId>,
+}
+
+impl PendingDuplicates {
+    pub fn insert(&mut self, id: EventId) {
+        self.ids.insert(id);
+    }
+
+    pub fn remove(&mut self, id: &EventId) -> bool {
+        self.ids.remove(id)
+    }
+}
+
+/// Rewrites the OK for a known event to carry the `duplicate:` prefix
+#[derive(Debug, Clone)]
+pub struct DuplicateOkMiddleware;
+
+impl NostrMiddleware<ConnectionState> for DuplicateOkMiddleware {
+    async fn process_outbound(&self, mut ctx: OutboundContext<'_, ConnectionState>) -> Result<(), anyhow::Error> {
+        let Some(RelayMessage::Ok { event_id, status: true, .. }) = ctx.message.as_ref() else {
+            return Ok(());
+        };
+        let event_id = *event_id;
+        if ctx.state.write().custom.duplicates.remove(&event_id) {
+            ctx.message.replace(RelayMessage::ok(event_id, true, DUPLICATE_MESSAGE));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_known_events_are_per_scope() {
+        let event = EventBuilder::text_note("again").sign_with_keys(&Keys::generate()).unwrap();
+        let store = Arc::new(crate::store::MemoryStore::new());
+        let drt2z = Scope::named("drt2z").unwrap();
+        store.insert(&drt2z, event.clone());
+        let known = KnownEvents::new(store);
+
+        assert!(known.contains(&drt2z, event.id).await);
+        assert!(!known.contains(&Scope::Default, event.id).await);
+        assert!(!KnownEvents::default().contains(&drt2z, event.id).await);
+    }
+
+    #[test]
+    fn test_pending_duplicate_is_taken_once() {
+        let id = EventId::all_zeros();
+        let mut pending = PendingDuplicates::default();
+        assert!(!pending.remove(&id));
+        pending.insert(id);
+        assert!(pending.remove(&id));
+        assert!(!pending.remove(&id));
+    }
+}
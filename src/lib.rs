@@ -1,3 +1,83 @@
+#![recursion_limit = "256"]
+
+pub mod activity;
+pub mod admissions;
+pub mod append_log;
+pub mod archive;
+pub mod audit;
+pub mod auth;
+pub mod blocklist;
+pub mod build_info;
+pub mod cell_spread;
+pub mod cells;
 pub mod config;
+pub mod decisions;
+pub mod delegation;
+pub mod connection_stats;
+pub mod count_cache;
+pub mod duplicates;
+pub mod expirations;
+pub mod filter_limits;
+pub mod first_seen;
+pub mod geo_filter;
+pub mod geoip;
 pub mod processor;
-pub mod geohash_utils;
\ No newline at end of file
+pub mod geohash_utils;
+pub mod host_parsing;
+pub mod http_cache;
+pub mod ip_filter;
+pub mod keys;
+pub mod known_events;
+pub mod pages;
+pub mod nip05;
+pub mod nip11;
+pub mod page_limit;
+pub mod preview;
+pub mod store;
+pub mod store_admin;
+pub mod subscriptions;
+pub mod syndication;
+pub mod storage;
+pub mod connections;
+pub mod slow_consumer;
+pub mod sse;
+pub mod stats;
+pub mod status_notes;
+pub mod api;
+pub mod server;
+
+pub mod self_publish;
+pub mod global_kinds;
+pub mod live;
+pub mod log_summary;
+pub mod maintenance;
+pub mod memory_backend;
+pub mod mqtt;
+pub mod optional_middleware;
+pub mod policy;
+pub mod pow;
+pub mod prometheus;
+pub mod precision_caps;
+pub mod query_cache;
+pub mod quota;
+pub mod rate_limit;
+pub mod read_checks;
+pub mod receipts;
+pub mod reject;
+pub mod relay;
+pub mod replication;
+pub mod retention;
+pub mod routing;
+pub mod scope_counters;
+pub mod scope_policy;
+pub mod scope_residency;
+pub mod tombstones;
+pub mod trending;
+#[cfg(unix)]
+pub mod unix_socket;
+pub mod usage_report;
+pub mod vanish;
+pub mod webhooks;
+pub mod wot;
+pub mod cli;
+pub mod test_support;
@@ -0,0 +1,139 @@
+//! Broadcast of newly stored events
+//!
+//! relay_builder fans stored events out to websocket subscriptions but
+//! doesn't expose that to the rest of the relay. `LiveEventsMiddleware`
+//! republishes every event the relay acknowledged with `OK true` on a
+//! `LiveEvents` broadcast channel, which webhooks and the SSE feed
+//! subscribe to instead of polling storage.
+//!
+//! Inbound EVENTs are held in `ConnectionState` until their OK goes out, and
+//! only while something is subscribed.
+
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::{InboundContext, InboundProcessor, NostrMiddleware, OutboundContext};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use crate::global_kinds::is_stored_in_root;
+use crate::processor::ConnectionState;
+
+/// Events buffered for subscribers that fall behind
+const CHANNEL_CAPACITY: usize = 1_024;
+/// Events per connection awaiting their OK; more are not tracked
+const MAX_PENDING_PER_CONNECTION: usize = 256;
+
+/// An event and the scope it was stored in
+#[derive(Debug, Clone)]
+pub struct StoredEvent {
+    pub scope: Scope,
+    pub event: Event,
+}
+
+/// Events a connection has published, waiting for the relay's OK
+pub type PendingEvents = HashMap<EventId, StoredEvent>;
+
+/// Sender side of the stored-event broadcast
+#[derive(Debug, Clone)]
+pub struct LiveEvents {
+    sender: broadcast::Sender<Arc<StoredEvent>>,
+}
+
+impl Default for LiveEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LiveEvents {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<StoredEvent>> {
+        self.sender.subscribe()
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub fn publish(&self, stored: StoredEvent) {
+        // Err only means nobody is listening
+        let _ = self.sender.send(Arc::new(stored));
+    }
+}
+
+/// Publishes events to `LiveEvents` once the relay has accepted them
+#[derive(Clone)]
+pub struct LiveEventsMiddleware {
+    live: Arc<LiveEvents>,
+    global_kinds: Vec<u16>,
+}
+
+impl LiveEventsMiddleware {
+    pub fn new(live: Arc<LiveEvents>, global_kinds: &[u16]) -> Self {
+        Self {
+            live,
+            global_kinds: global_kinds.to_vec(),
+        }
+    }
+}
+
+impl NostrMiddleware<ConnectionState> for LiveEventsMiddleware {
+    async fn process_inbound<Next>(&self, ctx: InboundContext<'_, ConnectionState, Next>) -> Result<(), anyhow::Error>
+    where
+        Next: InboundProcessor<ConnectionState>,
+    {
+        if let Some(ClientMessage::Event(event)) = &ctx.message {
+            if self.live.has_subscribers() {
+                // Global kinds are stored in root whichever scope they arrive on
+                let scope = if is_stored_in_root(event.kind, &self.global_kinds) {
+                    Scope::Default
+                } else {
+                    ctx.state.read().subdomain.as_ref().clone()
+                };
+                let mut state = ctx.state.write();
+                if state.custom.pending_events.len() < MAX_PENDING_PER_CONNECTION {
+                    let stored = StoredEvent { scope, event: event.as_ref().clone() };
+                    state.custom.pending_events.insert(event.id, stored);
+                }
+            }
+        }
+        ctx.next().await
+    }
+
+    async fn process_outbound(&self, ctx: OutboundContext<'_, ConnectionState>) -> Result<(), anyhow::Error> {
+        let Some(RelayMessage::Ok { event_id, status, message }) = &ctx.message else {
+            return Ok(());
+        };
+        let Some(stored) = ctx.state.write().custom.pending_events.remove(event_id) else {
+            return Ok(());
+        };
+        if *status && !message.starts_with("duplicate:") {
+            self.live.publish(stored);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_subscribers_receive_published_events() {
+        let live = LiveEvents::new();
+        assert!(!live.has_subscribers());
+        let mut receiver = live.subscribe();
+        assert!(live.has_subscribers());
+
+        let event = EventBuilder::text_note("live").sign(&Keys::generate()).await.unwrap();
+        live.publish(StoredEvent { scope: Scope::named("drt2z").unwrap(), event: event.clone() });
+
+        let stored = receiver.recv().await.unwrap();
+        assert_eq!(stored.event.id, event.id);
+        assert_eq!(stored.scope, Scope::named("drt2z").unwrap());
+    }
+}
@@ -0,0 +1,222 @@
+//! Maintenance mode
+//!
+//! While maintenance is on, every EVENT is refused with
+//! `RejectReason::Maintenance` carrying the operator's message; REQs and open
+//! subscriptions keep working. The flag starts from `maintenance` and
+//! `maintenance_message` in config and can be flipped at runtime through
+//! `POST /api/maintenance`. The last toggle is persisted under
+//! `database_path` and wins over the config default after a restart.
+//!
+//! Read-only mode (`read_only`, or `serve --read-only`) is the verification
+//! window after a crash or restore: EVENTs are refused with
+//! `RejectReason::ReadOnly` until the operator clears it through
+//! `DELETE /api/read-only`. It is never persisted, so every start with the
+//! flag begins read-only again. Maintenance wins while both are on.
+
+use anyhow::{Context, Result};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::config::RelayConfig;
+
+/// File name of the persisted state inside `database_path`
+pub const MAINTENANCE_FILE: &str = "maintenance.json";
+
+/// Message shown when the operator doesn't supply one
+pub const DEFAULT_MAINTENANCE_MESSAGE: &str = "the relay is undergoing maintenance, try again later";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceState {
+    pub enabled: bool,
+    pub message: String,
+}
+
+/// Runtime maintenance flag, optionally backed by a file
+#[derive(Debug)]
+pub struct Maintenance {
+    /// `None` keeps the state in memory only
+    path: Option<PathBuf>,
+    state: RwLock<MaintenanceState>,
+    read_only: AtomicBool,
+    /// Bumped on every change, for info page ETags
+    revision: AtomicU64,
+}
+
+impl Maintenance {
+    /// Loads the state from `path`, falling back to `default` if the file
+    /// doesn't exist
+    pub fn open(path: impl Into<PathBuf>, default: MaintenanceState) -> Result<Self> {
+        let path = path.into();
+        let state = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("invalid maintenance state {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => default,
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        Ok(Self {
+            path: Some(path),
+            state: RwLock::new(state),
+            read_only: AtomicBool::new(false),
+            revision: AtomicU64::new(0),
+        })
+    }
+
+    /// Opens the state kept in the configured database directory, read-only
+    /// if configured
+    pub fn for_config(config: &RelayConfig) -> Result<Self> {
+        let maintenance = Self::open(
+            Path::new(&config.database_path).join(MAINTENANCE_FILE),
+            MaintenanceState {
+                enabled: config.maintenance,
+                message: config.maintenance_message.clone(),
+            },
+        )?;
+        Ok(maintenance.with_read_only(config.read_only))
+    }
+
+    pub fn with_read_only(self, read_only: bool) -> Self {
+        self.read_only.store(read_only, Ordering::Relaxed);
+        metrics::gauge!("relay_read_only").set(if read_only { 1.0 } else { 0.0 });
+        self
+    }
+
+    /// Maintenance off and never persisted, for tests and tooling
+    pub fn disabled() -> Self {
+        Self {
+            path: None,
+            state: RwLock::new(MaintenanceState {
+                enabled: false,
+                message: DEFAULT_MAINTENANCE_MESSAGE.to_string(),
+            }),
+            read_only: AtomicBool::new(false),
+            revision: AtomicU64::new(0),
+        }
+    }
+
+    pub fn state(&self) -> MaintenanceState {
+        self.state.read().clone()
+    }
+
+    /// The operator's message while maintenance is on
+    pub fn message(&self) -> Option<String> {
+        let state = self.state.read();
+        state.enabled.then(|| state.message.clone())
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Ends read-only mode, returning whether it was on
+    pub fn clear_read_only(&self) -> bool {
+        let was_read_only = self.read_only.swap(false, Ordering::Relaxed);
+        if was_read_only {
+            self.revision.fetch_add(1, Ordering::Relaxed);
+            metrics::gauge!("relay_read_only").set(0.0);
+        }
+        was_read_only
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision.load(Ordering::Relaxed)
+    }
+
+    /// Turns maintenance on or off, persisting the result
+    ///
+    /// Without a (non-blank) `message` the previous one is kept.
+    pub fn set(&self, enabled: bool, message: Option<String>) -> Result<MaintenanceState> {
+        let mut state = self.state.write();
+        let mut updated = MaintenanceState { enabled, message: state.message.clone() };
+        if let Some(message) = message.filter(|message| !message.trim().is_empty()) {
+            updated.message = message;
+        }
+        // Persist before swapping so a failed write leaves memory and disk in step
+        if let Some(path) = &self.path {
+            save(path, &updated)?;
+        }
+        *state = updated.clone();
+        self.revision.fetch_add(1, Ordering::Relaxed);
+        metrics::gauge!("relay_maintenance").set(if enabled { 1.0 } else { 0.0 });
+        Ok(updated)
+    }
+}
+
+/// Writes the state atomically (temp file, then rename)
+fn save(path: &Path, state: &MaintenanceState) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(state)?)
+        .with_context(|| format!("failed to write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn off() -> MaintenanceState {
+        MaintenanceState { enabled: false, message: DEFAULT_MAINTENANCE_MESSAGE.to_string() }
+    }
+
+    #[test]
+    fn test_toggle_persists_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(MAINTENANCE_FILE);
+
+        let maintenance = Maintenance::open(&path, off()).unwrap();
+        assert_eq!(maintenance.message(), None);
+        maintenance.set(true, Some("migrating storage".to_string())).unwrap();
+        assert_eq!(maintenance.message().as_deref(), Some("migrating storage"));
+        assert_eq!(maintenance.revision(), 1);
+
+        // The persisted state wins over the config default
+        let reopened = Maintenance::open(&path, off()).unwrap();
+        assert_eq!(reopened.message().as_deref(), Some("migrating storage"));
+
+        // Turning it off keeps the message for next time
+        let state = reopened.set(false, None).unwrap();
+        assert_eq!(state, MaintenanceState { enabled: false, message: "migrating storage".to_string() });
+        assert_eq!(Maintenance::open(&path, off()).unwrap().message(), None);
+    }
+
+    #[test]
+    fn test_config_default_applies_without_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = RelayConfig {
+            database_path: dir.path().to_string_lossy().to_string(),
+            maintenance: true,
+            maintenance_message: "back at noon".to_string(),
+            ..Default::default()
+        };
+        let maintenance = Maintenance::for_config(&config).unwrap();
+        assert_eq!(maintenance.message().as_deref(), Some("back at noon"));
+
+        // Blank messages don't replace the current one
+        maintenance.set(true, Some("  ".to_string())).unwrap();
+        assert_eq!(maintenance.message().as_deref(), Some("back at noon"));
+    }
+
+    #[test]
+    fn test_read_only_is_cleared_and_not_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = RelayConfig {
+            database_path: dir.path().to_string_lossy().to_string(),
+            read_only: true,
+            ..Default::default()
+        };
+        let maintenance = Maintenance::for_config(&config).unwrap();
+        assert!(maintenance.is_read_only());
+        assert_eq!(maintenance.message(), None);
+
+        assert!(maintenance.clear_read_only());
+        assert!(!maintenance.is_read_only());
+        assert_eq!(maintenance.revision(), 1);
+        assert!(!maintenance.clear_read_only());
+        assert_eq!(maintenance.revision(), 1);
+
+        // Every start with the flag is read-only again
+        assert!(Maintenance::for_config(&config).unwrap().is_read_only());
+        assert!(!Maintenance::for_config(&RelayConfig { read_only: false, ..config }).unwrap().is_read_only());
+    }
+}
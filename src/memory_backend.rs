@@ -0,0 +1,165 @@
+//! Ring buffers for the memory storage backend
+//!
+//! Pop-up relays (conferences, festivals) run with `storage_backend =
+//! memory`: the database starts empty on every start (see
+//! `store::open_storage`) and each scope keeps only its newest
+//! `memory_events_per_scope` events. `RingBuffers` remembers the ids of each
+//! scope's stored events in arrival order; once a ring wraps, the event
+//! that fell out is deleted from the store.
+//!
+//! Ephemeral kinds are never stored, so they don't take a slot. A replaced
+//! replaceable event keeps its slot until it falls out, which only makes
+//! the scope hold slightly fewer events.
+//!
+//! The rings follow `LiveEvents`, so eviction happens just after a store.
+//! If the task falls behind the broadcast it trims every scope from the
+//! store itself, newest `created_at` first, and starts the rings over from
+//! what is left.
+
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+use crate::live::LiveEvents;
+use crate::store::{scope_label, ScopeStore};
+
+/// Newest stored event ids per scope
+#[derive(Debug)]
+pub struct RingBuffers {
+    capacity: usize,
+    rings: Mutex<HashMap<Scope, VecDeque<EventId>>>,
+}
+
+impl RingBuffers {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            rings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds `id` to `scope`'s ring, returning the id that fell out
+    pub fn push(&self, scope: &Scope, id: EventId) -> Option<EventId> {
+        let mut rings = self.rings.lock();
+        let ring = rings.entry(scope.clone()).or_default();
+        if ring.contains(&id) {
+            return None;
+        }
+        ring.push_back(id);
+        if ring.len() > self.capacity {
+            ring.pop_front()
+        } else {
+            None
+        }
+    }
+
+    /// Restarts `scope`'s ring with `ids`, oldest first
+    fn reset(&self, scope: &Scope, ids: impl IntoIterator<Item = EventId>) {
+        self.rings.lock().insert(scope.clone(), ids.into_iter().collect());
+    }
+}
+
+/// Deletes all but the newest `capacity` events of every scope and restarts
+/// the rings from what is left, returning how many events went
+pub async fn trim(rings: &RingBuffers, store: &dyn ScopeStore) -> anyhow::Result<usize> {
+    let mut evicted = 0;
+    for scope in store.scopes().await? {
+        // Newest first
+        let events = store.query(&scope, Filter::new()).await?;
+        for event in events.iter().skip(rings.capacity) {
+            store.delete(&scope, event.id).await?;
+            evicted += 1;
+        }
+        rings.reset(&scope, events.iter().take(rings.capacity).rev().map(|event| event.id));
+    }
+    Ok(evicted)
+}
+
+/// Deletes events as they fall out of their scope's ring
+pub fn spawn_ring_buffers(rings: Arc<RingBuffers>, store: Arc<dyn ScopeStore>, live: &LiveEvents) {
+    let mut receiver = live.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(stored) => {
+                    if stored.event.kind.is_ephemeral() {
+                        continue;
+                    }
+                    let Some(evicted) = rings.push(&stored.scope, stored.event.id) else {
+                        continue;
+                    };
+                    if let Err(e) = store.delete(&stored.scope, evicted).await {
+                        warn!("Failed to drop event {} from {}: {}", evicted, scope_label(&stored.scope), e);
+                    } else {
+                        metrics::counter!("relay_memory_evicted_events_total").increment(1);
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => match trim(&rings, store.as_ref()).await {
+                    Ok(evicted) => {
+                        info!("Memory backend missed {} stored events; trimmed {} from the store", skipped, evicted);
+                        metrics::counter!("relay_memory_evicted_events_total").increment(evicted as u64);
+                    }
+                    Err(e) => warn!("Memory backend missed {} stored events and failed to trim: {}", skipped, e),
+                },
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u8) -> EventId {
+        EventId::from_byte_array([n; 32])
+    }
+
+    #[test]
+    fn test_ring_wraps_per_scope() {
+        let rings = RingBuffers::new(2);
+        let drt2z = Scope::named("drt2z").unwrap();
+
+        assert_eq!(rings.push(&drt2z, id(1)), None);
+        assert_eq!(rings.push(&drt2z, id(2)), None);
+        // Duplicates don't take a slot
+        assert_eq!(rings.push(&drt2z, id(2)), None);
+        assert_eq!(rings.push(&drt2z, id(3)), Some(id(1)));
+        assert_eq!(rings.push(&drt2z, id(4)), Some(id(2)));
+
+        // Other scopes have their own ring
+        assert_eq!(rings.push(&Scope::Default, id(5)), None);
+    }
+
+    #[tokio::test]
+    async fn test_trim_catches_up_on_missed_events() {
+        use crate::store::MemoryStore;
+
+        let store = MemoryStore::new();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let keys = Keys::generate();
+        let notes: Vec<Event> = (0..4u64)
+            .map(|i| {
+                EventBuilder::text_note(format!("note {}", i))
+                    .custom_created_at(Timestamp::from(1_700_000_000 + i))
+                    .sign_with_keys(&keys)
+                    .unwrap()
+            })
+            .collect();
+        // Stored without the rings seeing them, as after a lag
+        for note in &notes {
+            store.insert(&drt2z, note.clone());
+        }
+
+        let rings = RingBuffers::new(2);
+        assert_eq!(trim(&rings, &store).await.unwrap(), 2);
+        let kept: Vec<EventId> = store.query(&drt2z, Filter::new()).await.unwrap().iter().map(|e| e.id).collect();
+        assert_eq!(kept, vec![notes[3].id, notes[2].id]);
+
+        // The ring picks up from the survivors, oldest first
+        assert_eq!(rings.push(&drt2z, id(9)), Some(notes[2].id));
+    }
+}
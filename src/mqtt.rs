@@ -0,0 +1,225 @@
+//! MQTT bridge for newly stored events
+//!
+//! With `mqtt_broker_url` set, every event published on `LiveEvents` (stored
+//! events, and ephemeral ones as they're broadcast) whose scope and kind
+//! match `mqtt_scopes`/`mqtt_kinds` is published as JSON to the topic
+//! rendered from `mqtt_topic_template`, e.g. `geo/drt2z/20000`.
+//!
+//! The websocket path never waits on the broker: events go through a
+//! bounded queue to a forwarding task, and are dropped and counted in
+//! `relay_mqtt_dropped_total` when it's full. rumqttc reconnects on the
+//! next poll after a connection error; the bridge backs off between
+//! attempts and counts them in `relay_mqtt_connection_errors_total`.
+
+use anyhow::{Context, Result};
+use nostr_sdk::prelude::*;
+use rumqttc::{AsyncClient, Event as MqttEvent, EventLoop, MqttOptions, Packet, QoS, Transport};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, info, warn};
+use crate::config::RelayConfig;
+use crate::live::{LiveEvents, StoredEvent};
+use crate::store::scope_label;
+use crate::webhooks::scope_matches;
+
+/// Events waiting for the broker connection
+const QUEUE_CAPACITY: usize = 1_000;
+/// Requests rumqttc buffers between the client and its event loop
+const CLIENT_CAPACITY: usize = 100;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// One event on its way to the broker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttMessage {
+    pub topic: String,
+    pub payload: String,
+}
+
+/// `template` with `{scope}` and `{kind}` filled in
+pub fn render_topic(template: &str, scope: &str, kind: u16) -> String {
+    template.replace("{scope}", scope).replace("{kind}", &kind.to_string())
+}
+
+/// Picks the events to bridge and queues them for the broker
+#[derive(Debug)]
+pub struct MqttBridge {
+    template: String,
+    scopes: Vec<String>,
+    kinds: Vec<u16>,
+    sender: mpsc::Sender<MqttMessage>,
+}
+
+impl MqttBridge {
+    /// Bridge configured like `config`, with the receiving end of its queue
+    pub fn channel(config: &RelayConfig, capacity: usize) -> (Self, mpsc::Receiver<MqttMessage>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let bridge = Self {
+            template: config.mqtt_topic_template.clone(),
+            scopes: config.mqtt_scopes.clone(),
+            kinds: config.mqtt_kinds.clone(),
+            sender,
+        };
+        (bridge, receiver)
+    }
+
+    /// Connects to the configured broker; `None` when the bridge is off.
+    /// Must be called inside a tokio runtime
+    pub fn for_config(config: &RelayConfig) -> Result<Option<Self>> {
+        let Some(url) = &config.mqtt_broker_url else {
+            return Ok(None);
+        };
+        let (client, eventloop) = connect(config, url)?;
+        let qos = if config.mqtt_qos == 0 { QoS::AtMostOnce } else { QoS::AtLeastOnce };
+        let (bridge, receiver) = Self::channel(config, QUEUE_CAPACITY);
+        tokio::spawn(forward(client, receiver, qos));
+        tokio::spawn(drive(eventloop, url.clone()));
+        info!("Bridging events to MQTT broker {}", url);
+        Ok(Some(bridge))
+    }
+
+    /// Queues `stored` if it matches, without waiting
+    pub fn dispatch(&self, stored: &StoredEvent) {
+        let scope = scope_label(&stored.scope);
+        let kind = stored.event.kind.as_u16();
+        if !scope_matches(&self.scopes, &scope) || !(self.kinds.is_empty() || self.kinds.contains(&kind)) {
+            return;
+        }
+        let message = MqttMessage {
+            topic: render_topic(&self.template, &scope, kind),
+            payload: stored.event.as_json(),
+        };
+        if self.sender.try_send(message).is_err() {
+            debug!("MQTT queue full, dropping {}", stored.event.id);
+            metrics::counter!("relay_mqtt_dropped_total").increment(1);
+        }
+    }
+}
+
+fn connect(config: &RelayConfig, url: &str) -> Result<(AsyncClient, EventLoop)> {
+    let parsed = url::Url::parse(url).with_context(|| format!("invalid MQTT broker URL '{}'", url))?;
+    let host = parsed.host_str().context("MQTT broker URL has no host")?;
+    let tls = parsed.scheme() == "mqtts";
+    let port = parsed.port().unwrap_or(if tls { 8883 } else { 1883 });
+    let mut options = MqttOptions::new(&config.mqtt_client_id, host, port);
+    options.set_keep_alive(KEEP_ALIVE);
+    if let Some(username) = &config.mqtt_username {
+        options.set_credentials(username, config.mqtt_password.as_deref().unwrap_or_default());
+    }
+    if tls {
+        options.set_transport(Transport::tls_with_default_config());
+    }
+    Ok(AsyncClient::new(options, CLIENT_CAPACITY))
+}
+
+/// Hands queued events to the client
+async fn forward(client: AsyncClient, mut receiver: mpsc::Receiver<MqttMessage>, qos: QoS) {
+    while let Some(message) = receiver.recv().await {
+        if let Err(e) = client.publish(message.topic, qos, false, message.payload).await {
+            warn!("MQTT client stopped: {}", e);
+            metrics::counter!("relay_mqtt_dropped_total").increment(1);
+            return;
+        }
+        metrics::counter!("relay_mqtt_published_total").increment(1);
+    }
+}
+
+/// Runs the connection, backing off between failed attempts
+async fn drive(mut eventloop: EventLoop, url: String) {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match eventloop.poll().await {
+            Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker {}", url);
+                backoff = INITIAL_BACKOFF;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("MQTT connection to {} failed, retrying in {:?}: {}", url, backoff, e);
+                metrics::counter!("relay_mqtt_connection_errors_total").increment(1);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Bridges every event published on `live` until the relay shuts down
+pub fn spawn_listener(bridge: Arc<MqttBridge>, live: &LiveEvents) {
+    let mut receiver = live.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(stored) => bridge.dispatch(&stored),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("MQTT bridge fell behind, dropped {} events", missed);
+                    metrics::counter!("relay_mqtt_dropped_total").increment(missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_lmdb::Scope;
+
+    async fn stored(scope: Scope, kind: u16) -> StoredEvent {
+        let event = EventBuilder::new(Kind::from(kind), "hello").sign(&Keys::generate()).await.unwrap();
+        StoredEvent { scope, event }
+    }
+
+    #[test]
+    fn test_render_topic() {
+        assert_eq!(render_topic("geo/{scope}/{kind}", "drt2z", 20000), "geo/drt2z/20000");
+        assert_eq!(render_topic("nostr/{scope}", "root", 1), "nostr/root");
+    }
+
+    #[tokio::test]
+    async fn test_matching_events_are_queued() {
+        let config = RelayConfig {
+            mqtt_scopes: vec!["drt*".to_string()],
+            mqtt_kinds: vec![20000],
+            ..Default::default()
+        };
+        let (bridge, mut receiver) = MqttBridge::channel(&config, 10);
+
+        let chat = stored(Scope::named("drt2z").unwrap(), 20000).await;
+        bridge.dispatch(&chat);
+        bridge.dispatch(&stored(Scope::named("drt2z").unwrap(), 1).await);
+        bridge.dispatch(&stored(Scope::named("9q8yy").unwrap(), 20000).await);
+        bridge.dispatch(&stored(Scope::Default, 20000).await);
+
+        let message = receiver.try_recv().unwrap();
+        assert_eq!(message.topic, "geo/drt2z/20000");
+        assert_eq!(Event::from_json(&message.payload).unwrap(), chat.event);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_drops_instead_of_waiting() {
+        let (bridge, mut receiver) = MqttBridge::channel(&RelayConfig::default(), 1);
+        let first = stored(Scope::Default, 1).await;
+        bridge.dispatch(&first);
+        bridge.dispatch(&stored(Scope::Default, 1).await);
+
+        assert_eq!(receiver.try_recv().unwrap().topic, "geo/root/1");
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_live_events_reach_the_queue() {
+        let (bridge, mut receiver) = MqttBridge::channel(&RelayConfig::default(), 10);
+        let live = LiveEvents::new();
+        spawn_listener(Arc::new(bridge), &live);
+
+        let event = stored(Scope::named("drt2z").unwrap(), 20000).await;
+        live.publish(event.clone());
+        let message = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+        assert_eq!(message.topic, "geo/drt2z/20000");
+    }
+}
@@ -0,0 +1,228 @@
+//! NIP-05 identifiers
+//!
+//! Serves `/.well-known/nostr.json` so `name@example.com` identifiers resolve
+//! to configured pubkeys. The relay's own pubkey is always listed, under
+//! `nip05_relay_name`, with the relay URL as its relay hint. Identifiers
+//! belong to the root domain; geohash subdomains serve the same document or
+//! redirect to it depending on `nip05_subdomains`.
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use crate::config::{Nip05SubdomainPolicy, RelayConfig};
+use crate::host_parsing::host_info;
+
+/// Name the relay's pubkey is listed under unless configured otherwise
+pub const DEFAULT_NIP05_RELAY_NAME: &str = "_";
+
+/// Whether `name` is a valid NIP-05 local part (`a-z0-9-_.`)
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '_' | '.'))
+}
+
+/// The `nostr.json` document
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Nip05Document {
+    pub names: BTreeMap<String, String>,
+    /// Relay hints keyed by hex pubkey
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub relays: BTreeMap<String, Vec<String>>,
+}
+
+/// Every configured identifier, with the relay's own merged in
+#[derive(Debug, Clone)]
+pub struct Nip05Directory {
+    document: Nip05Document,
+    subdomains: Nip05SubdomainPolicy,
+    base_domain_parts: usize,
+    https: bool,
+}
+
+impl Nip05Directory {
+    pub fn new(config: &RelayConfig, relay_pubkey: &PublicKey) -> Self {
+        let mut names = config.nip05_names.clone();
+        names.insert(config.nip05_relay_name.clone(), relay_pubkey.to_hex());
+
+        let mut relays: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (name, urls) in &config.nip05_relays {
+            if let Some(pubkey) = names.get(name) {
+                relays.entry(pubkey.clone()).or_default().extend(urls.iter().cloned());
+            }
+        }
+        let relay_hints = relays.entry(relay_pubkey.to_hex()).or_default();
+        if !relay_hints.contains(&config.relay_url) {
+            relay_hints.push(config.relay_url.clone());
+        }
+
+        Self {
+            document: Nip05Document { names, relays },
+            subdomains: config.nip05_subdomains,
+            base_domain_parts: config.base_domain_parts(),
+            https: !config.relay_url.starts_with("ws://"),
+        }
+    }
+
+    /// The document, narrowed to `name` when one is given
+    pub fn document(&self, name: Option<&str>) -> Nip05Document {
+        let Some(name) = name else {
+            return self.document.clone();
+        };
+        let name = name.to_lowercase();
+        let Some(pubkey) = self.document.names.get(&name) else {
+            return Nip05Document::default();
+        };
+        Nip05Document {
+            names: BTreeMap::from([(name, pubkey.clone())]),
+            relays: self
+                .document
+                .relays
+                .get(pubkey)
+                .map(|urls| BTreeMap::from([(pubkey.clone(), urls.clone())]))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NameQuery {
+    name: Option<String>,
+}
+
+async fn nostr_json_handler(
+    State(directory): State<Arc<Nip05Directory>>,
+    Query(query): Query<NameQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let host = host_info(&headers, directory.base_domain_parts);
+    if host.subdomain.is_some() && directory.subdomains == Nip05SubdomainPolicy::Redirect {
+        let scheme = if directory.https { "https" } else { "http" };
+        let port = headers
+            .get(header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.split_once(':'))
+            .map(|(_, port)| format!(":{}", port))
+            .unwrap_or_default();
+        let query = query
+            .name
+            .map(|name| name.to_lowercase())
+            .filter(|name| is_valid_name(name))
+            .map(|name| format!("?name={}", name))
+            .unwrap_or_default();
+        let location = format!("{}://{}{}/.well-known/nostr.json{}", scheme, host.domain, port, query);
+        return (StatusCode::MOVED_PERMANENTLY, [(header::LOCATION, location)]).into_response();
+    }
+
+    // Web clients fetch this cross-origin, so CORS is required by NIP-05
+    (
+        [(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")],
+        Json(directory.document(query.name.as_deref())),
+    )
+        .into_response()
+}
+
+pub fn router(directory: Arc<Nip05Directory>) -> Router {
+    Router::new()
+        .route("/.well-known/nostr.json", get(nostr_json_handler))
+        .with_state(directory)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::{to_bytes, Body}, http::Request};
+    use tower::ServiceExt;
+
+    const ALICE: &str = "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d";
+
+    fn test_config() -> RelayConfig {
+        let mut config = RelayConfig {
+            relay_url: "wss://example.com".to_string(),
+            ..Default::default()
+        };
+        config.nip05_names.insert("alice".to_string(), ALICE.to_string());
+        config
+            .nip05_relays
+            .insert("alice".to_string(), vec!["wss://alice.example.net".to_string()]);
+        config
+    }
+
+    async fn get(config: &RelayConfig, relay_pubkey: &PublicKey, host: &str, uri: &str) -> Response {
+        router(Arc::new(Nip05Directory::new(config, relay_pubkey)))
+            .oneshot(Request::builder().uri(uri).header("host", host).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn body_json(response: Response) -> serde_json::Value {
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_lists_configured_names_and_relay() {
+        let relay = Keys::generate().public_key();
+        let response = get(&test_config(), &relay, "example.com", "/.well-known/nostr.json").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+
+        let doc = body_json(response).await;
+        assert_eq!(doc["names"]["alice"], ALICE);
+        assert_eq!(doc["names"]["_"], relay.to_hex());
+        assert_eq!(doc["relays"][ALICE][0], "wss://alice.example.net");
+        assert_eq!(doc["relays"][relay.to_hex()][0], "wss://example.com");
+    }
+
+    #[tokio::test]
+    async fn test_name_query_filters() {
+        let relay = Keys::generate().public_key();
+        let doc = body_json(get(&test_config(), &relay, "example.com", "/.well-known/nostr.json?name=ALICE").await).await;
+        assert_eq!(doc["names"].as_object().unwrap().len(), 1);
+        assert_eq!(doc["names"]["alice"], ALICE);
+        assert_eq!(doc["relays"].as_object().unwrap().len(), 1);
+
+        let response = get(&test_config(), &relay, "example.com", "/.well-known/nostr.json?name=nobody").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_json(response).await, serde_json::json!({ "names": {} }));
+    }
+
+    #[tokio::test]
+    async fn test_relay_name_is_configurable() {
+        let relay = Keys::generate().public_key();
+        let config = RelayConfig {
+            nip05_relay_name: "relay".to_string(),
+            ..test_config()
+        };
+        let doc = body_json(get(&config, &relay, "example.com", "/.well-known/nostr.json?name=relay").await).await;
+        assert_eq!(doc["names"]["relay"], relay.to_hex());
+    }
+
+    #[tokio::test]
+    async fn test_subdomains_serve_or_redirect() {
+        let relay = Keys::generate().public_key();
+        let response = get(&test_config(), &relay, "drt2z.example.com", "/.well-known/nostr.json").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_json(response).await["names"]["alice"], ALICE);
+
+        let config = RelayConfig {
+            nip05_subdomains: Nip05SubdomainPolicy::Redirect,
+            ..test_config()
+        };
+        let response = get(&config, &relay, "drt2z.example.com:8443", "/.well-known/nostr.json?name=alice").await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://example.com:8443/.well-known/nostr.json?name=alice"
+        );
+    }
+}
@@ -0,0 +1,291 @@
+//! NIP-11 relay information document
+//!
+//! Served from `/` when the client sends `Accept: application/nostr+json`.
+//! Geohash scopes get the operator branding combined with cell-specific text.
+
+use serde::Serialize;
+use crate::build_info;
+use crate::config::RelayConfig;
+use crate::geo_filter::{resolve_precision, LATLON_SYNTAX};
+use crate::geohash_utils::is_valid_geohash;
+use crate::policy::EffectiveScopePolicy;
+
+/// Default relay name when no branding is configured
+pub const DEFAULT_RELAY_NAME: &str = "Geohashed Nostr Relay";
+
+/// Media type clients use to request the NIP-11 document
+pub const NIP11_CONTENT_TYPE: &str = "application/nostr+json";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RelayInformation {
+    pub name: String,
+    pub description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub banner: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contact: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<String>,
+    pub supported_nips: Vec<u16>,
+    pub software: String,
+    pub version: String,
+    /// Only present on scopes whose write policy is paid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payments_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fees: Option<Fees>,
+    pub limitation: Limitation,
+    /// Root only, with `geo_filter_extension` on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub geo_filter: Option<GeoFilter>,
+    /// This scope's websocket URL on the onion service, with
+    /// `onion_address` set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub onion_url: Option<String>,
+}
+
+/// NIP-11 `limitation` object, for the scope being described
+#[derive(Debug, Clone, Serialize)]
+pub struct Limitation {
+    pub max_message_length: usize,
+    pub max_subscriptions: usize,
+    pub auth_required: bool,
+    pub payment_required: bool,
+}
+
+impl From<&EffectiveScopePolicy> for Limitation {
+    fn from(policy: &EffectiveScopePolicy) -> Self {
+        Self {
+            max_message_length: policy.limits.max_event_size,
+            max_subscriptions: policy.limits.max_subscriptions,
+            auth_required: policy.auth.read || policy.auth.write,
+            payment_required: policy.writes.payment_required,
+        }
+    }
+}
+
+/// Custom field describing coordinates accepted in `#g` filters
+#[derive(Debug, Clone, Serialize)]
+pub struct GeoFilter {
+    /// Filter field the values go in
+    pub tag: &'static str,
+    pub syntax: &'static str,
+    /// Precision of the cells coordinates resolve to
+    pub precision: usize,
+    /// Geo REQs get stored events only, then CLOSED
+    pub live: bool,
+}
+
+/// NIP-11 `fees` object
+#[derive(Debug, Clone, Serialize)]
+pub struct Fees {
+    pub admission: Vec<Fee>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Fee {
+    pub amount: u64,
+    pub unit: &'static str,
+}
+
+/// Builds the NIP-11 document for the given scope
+pub fn relay_information(config: &RelayConfig, subdomain: Option<&str>) -> RelayInformation {
+    let base_name = config.relay_name.as_deref().unwrap_or(DEFAULT_RELAY_NAME);
+    let base_description = config.relay_description.as_deref().unwrap_or(
+        "A Nostr relay with geohash-based data isolation. Events tagged with a geohash must be posted to the matching subdomain.",
+    );
+
+    let (name, description) = match subdomain {
+        Some(sub) if is_valid_geohash(sub) => (
+            format!("{} [{}]", base_name, sub),
+            format!(
+                "{} This endpoint serves the '{}' geohash cell: events tagged with [\"g\", \"{}\"] and untagged events posted here are stored in this cell only.",
+                base_description, sub, sub
+            ),
+        ),
+        _ => (base_name.to_string(), base_description.to_string()),
+    };
+
+    let mut supported_nips = vec![1, 11, 45];
+    if config.enable_nip40_expiration {
+        supported_nips.push(40);
+    }
+    if config.auth_enabled() {
+        supported_nips.push(42);
+    }
+    supported_nips.sort_unstable();
+
+    let policy = EffectiveScopePolicy::resolve(subdomain, config, config.pow_min_difficulty);
+    let paid = policy.writes.payment_required;
+    let fees = config.admission_fee_msats.filter(|_| paid).map(|amount| Fees {
+        admission: vec![Fee { amount, unit: "msats" }],
+    });
+
+    RelayInformation {
+        name,
+        description,
+        icon: config.relay_icon_url.clone(),
+        banner: config.relay_banner_url.clone(),
+        contact: config.operator_contact.clone(),
+        pubkey: config.operator_pubkey.clone(),
+        supported_nips,
+        software: build_info::SOFTWARE.to_string(),
+        version: build_info::version_string(),
+        payments_url: config.payments_url.clone().filter(|_| paid),
+        fees,
+        limitation: Limitation::from(&policy),
+        geo_filter: (config.geo_filter_extension && subdomain.is_none()).then(|| GeoFilter {
+            tag: "#g",
+            syntax: LATLON_SYNTAX,
+            precision: resolve_precision(config),
+            live: false,
+        }),
+        onion_url: config.onion_url_for(subdomain.filter(|sub| is_valid_geohash(sub))),
+    }
+}
+
+/// Returns true if the request asks for the NIP-11 document
+pub fn wants_relay_information(headers: &axum::http::HeaderMap) -> bool {
+    headers
+        .get(axum::http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.contains(NIP11_CONTENT_TYPE))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WritePolicy;
+
+    fn branded_config() -> RelayConfig {
+        RelayConfig {
+            relay_name: Some("Hashstr".to_string()),
+            relay_description: Some("Location chat for everyone.".to_string()),
+            relay_icon_url: Some("https://example.com/icon.png".to_string()),
+            relay_banner_url: Some("https://example.com/banner.png".to_string()),
+            operator_contact: Some("mailto:ops@example.com".to_string()),
+            operator_pubkey: Some("3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d".to_string()),
+            ..RelayConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_unbranded_defaults() {
+        let info = relay_information(&RelayConfig::default(), None);
+        assert_eq!(info.name, DEFAULT_RELAY_NAME);
+        assert!(info.icon.is_none());
+        assert!(info.contact.is_none());
+        assert!(info.pubkey.is_none());
+        assert!(info.supported_nips.contains(&11));
+        assert!(info.supported_nips.contains(&45));
+    }
+
+    #[test]
+    fn test_root_uses_operator_branding() {
+        let info = relay_information(&branded_config(), None);
+        assert_eq!(info.name, "Hashstr");
+        assert_eq!(info.description, "Location chat for everyone.");
+        assert_eq!(info.icon.as_deref(), Some("https://example.com/icon.png"));
+        assert_eq!(info.contact.as_deref(), Some("mailto:ops@example.com"));
+        assert_eq!(
+            info.pubkey.as_deref(),
+            Some("3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d")
+        );
+    }
+
+    #[test]
+    fn test_geohash_scope_combines_branding_with_cell() {
+        let info = relay_information(&branded_config(), Some("drt2z"));
+        assert_eq!(info.name, "Hashstr [drt2z]");
+        assert!(info.description.starts_with("Location chat for everyone."));
+        assert!(info.description.contains("'drt2z' geohash cell"));
+    }
+
+    #[test]
+    fn test_serialized_field_names() {
+        let json = serde_json::to_value(relay_information(&branded_config(), None)).unwrap();
+        for field in ["name", "description", "icon", "banner", "contact", "pubkey", "supported_nips", "software", "version"] {
+            assert!(json.get(field).is_some(), "missing field {}", field);
+        }
+
+        // Unset optional fields are omitted rather than null
+        let json = serde_json::to_value(relay_information(&RelayConfig::default(), None)).unwrap();
+        assert!(json.get("icon").is_none());
+        assert_eq!(json["limitation"]["max_message_length"], RelayConfig::default().max_event_size);
+    }
+
+    #[test]
+    fn test_payment_fields_only_on_paid_scopes() {
+        let config = RelayConfig {
+            root_write_policy: WritePolicy::Paid,
+            payments_url: Some("https://pay.example.com".to_string()),
+            admission_fee_msats: Some(21_000),
+            ..RelayConfig::default()
+        };
+
+        let json = serde_json::to_value(relay_information(&config, None)).unwrap();
+        assert_eq!(json["payments_url"], "https://pay.example.com");
+        assert_eq!(json["fees"]["admission"][0]["amount"], 21_000);
+        assert_eq!(json["fees"]["admission"][0]["unit"], "msats");
+
+        let json = serde_json::to_value(relay_information(&config, Some("drt2z"))).unwrap();
+        assert!(json.get("payments_url").is_none());
+        assert!(json.get("fees").is_none());
+    }
+
+    #[test]
+    fn test_auth_required_per_scope() {
+        let json = serde_json::to_value(relay_information(&RelayConfig::default(), Some("drt2z"))).unwrap();
+        assert_eq!(json["limitation"]["auth_required"], false);
+        assert!(!json["supported_nips"].as_array().unwrap().contains(&42.into()));
+
+        let config = RelayConfig {
+            geohash_write_auth: true,
+            ..RelayConfig::default()
+        };
+        let json = serde_json::to_value(relay_information(&config, Some("drt2z"))).unwrap();
+        assert_eq!(json["limitation"]["auth_required"], true);
+        assert!(json["supported_nips"].as_array().unwrap().contains(&42.into()));
+        let json = serde_json::to_value(relay_information(&config, None)).unwrap();
+        assert_eq!(json["limitation"]["auth_required"], false);
+
+        let config = RelayConfig {
+            root_write_auth: true,
+            ..RelayConfig::default()
+        };
+        assert!(relay_information(&config, None).limitation.auth_required);
+        assert!(!relay_information(&config, Some("drt2z")).limitation.auth_required);
+    }
+
+    #[test]
+    fn test_software_and_version() {
+        let info = relay_information(&RelayConfig::default(), Some("drt2z"));
+        assert_eq!(info.software, build_info::SOFTWARE);
+        assert!(info.version.starts_with(build_info::VERSION));
+        assert!(info.version.contains(build_info::GIT_COMMIT));
+    }
+
+    #[test]
+    fn test_geo_filter_advertised_on_root_only() {
+        assert!(relay_information(&RelayConfig::default(), None).geo_filter.is_none());
+
+        let config = RelayConfig { geo_filter_extension: true, ..RelayConfig::default() };
+        let info = serde_json::to_value(relay_information(&config, None)).unwrap();
+        assert_eq!(info["geo_filter"]["syntax"], "latlon:<lat>,<lon>[,neighbors]");
+        assert_eq!(info["geo_filter"]["precision"], 5);
+        assert!(relay_information(&config, Some("drt2z")).geo_filter.is_none());
+    }
+
+    #[test]
+    fn test_onion_url_published_when_configured() {
+        let info = serde_json::to_value(relay_information(&RelayConfig::default(), Some("drt2z"))).unwrap();
+        assert!(info.get("onion_url").is_none());
+
+        let config = RelayConfig { onion_address: Some("abcdef.onion".to_string()), ..RelayConfig::default() };
+        assert_eq!(relay_information(&config, None).onion_url.as_deref(), Some("ws://abcdef.onion"));
+        assert_eq!(relay_information(&config, Some("drt2z")).onion_url.as_deref(), Some("ws://abcdef.onion/drt2z"));
+    }
+}
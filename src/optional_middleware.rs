@@ -0,0 +1,294 @@
+//! Switching built-in middleware off
+//!
+//! The chain `build_relay` assembles has one fixed order, listed outermost
+//! first in `CHAIN`:
+//!
+//! 1. `NostrLoggerMiddleware` (optional), so it sees every message
+//! 2. Decision and OK-rewriting middleware, which read what the processor
+//!    decided once the rest of the chain has returned
+//! 3. Stats notices, scoped auth and proof-of-work notices
+//! 4. Live events, slow consumers, connection tracking, welcome,
+//!    subscription and filter limits
+//! 5. Query answering: global kinds, geo filters, COUNT and the query cache
+//! 6. `ErrorHandlingMiddleware` (optional), wrapping everything that
+//!    touches the store
+//! 7. Storage-full handling
+//! 8. `Nip40ExpirationMiddleware` (optional)
+//! 9. `ScopeRateLimitMiddleware` (optional), innermost: EVENTs refused by
+//!    auth, NIP-40 or a full store don't spend a scope's budget, but ones
+//!    the processor refuses afterwards (proof of work, quotas, routing) do
+//!
+//! The chain's type is fixed at compile time, so a part that is switched
+//! off stays in place as an `Optional` pass-through. `validate` refuses
+//! combinations that can't work. `check_built` reads the order back from
+//! the type of the chain `build_relay` actually built, and refuses to start
+//! when it differs from `CHAIN` or puts a part outside one it must wrap.
+
+use anyhow::{bail, Result};
+use relay_builder::{
+    ConnectionContext, DisconnectContext, InboundContext, InboundProcessor, NostrMiddleware, OutboundContext,
+};
+use crate::config::{RelayConfig, TtlMode};
+use crate::processor::ConnectionState;
+
+/// `inner` when enabled, a pass-through otherwise
+#[derive(Debug, Clone)]
+pub struct Optional<M> {
+    inner: M,
+    enabled: bool,
+}
+
+impl<M> Optional<M> {
+    pub fn new(inner: M, enabled: bool) -> Self {
+        Self { inner, enabled }
+    }
+}
+
+impl<M: NostrMiddleware<ConnectionState>> NostrMiddleware<ConnectionState> for Optional<M> {
+    async fn process_inbound<Next>(&self, ctx: InboundContext<'_, ConnectionState, Next>) -> Result<(), anyhow::Error>
+    where
+        Next: InboundProcessor<ConnectionState>,
+    {
+        if self.enabled {
+            self.inner.process_inbound(ctx).await
+        } else {
+            ctx.next().await
+        }
+    }
+
+    async fn process_outbound(&self, ctx: OutboundContext<'_, ConnectionState>) -> Result<(), anyhow::Error> {
+        if self.enabled {
+            self.inner.process_outbound(ctx).await
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn on_connect(&self, ctx: ConnectionContext<'_, ConnectionState>) -> Result<(), anyhow::Error> {
+        if self.enabled {
+            self.inner.on_connect(ctx).await
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn on_disconnect(&self, ctx: DisconnectContext<'_, ConnectionState>) -> Result<(), anyhow::Error> {
+        if self.enabled {
+            self.inner.on_disconnect(ctx).await
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The middleware `build_relay` assembles, outermost first
+pub const CHAIN: &[&str] = &[
+    "logger",
+    "decision",
+    "truncated ok",
+    "duplicate ok",
+    "stats notice",
+    "auth",
+    "pow notice",
+    "live events",
+    "slow consumer",
+    "connection tracking",
+    "welcome",
+    "subscription limit",
+    "filter limit",
+    "geo filter",
+    "global kinds",
+    "count",
+    "query cache",
+    "error handling",
+    "storage full",
+    "nip40",
+    "rate limit",
+];
+
+/// Type each part of `CHAIN` is built from, as it appears in the chain's
+/// type name
+const TYPES: &[(&str, &str)] = &[
+    ("logger", "NostrLoggerMiddleware"),
+    ("decision", "DecisionMiddleware"),
+    ("truncated ok", "TruncatedOkMiddleware"),
+    ("duplicate ok", "DuplicateOkMiddleware"),
+    ("stats notice", "StatsNoticeMiddleware"),
+    ("auth", "ScopedAuthMiddleware"),
+    ("pow notice", "PowNoticeMiddleware"),
+    ("live events", "LiveEventsMiddleware"),
+    ("slow consumer", "SlowConsumerMiddleware"),
+    ("connection tracking", "ConnectionTrackingMiddleware"),
+    ("welcome", "WelcomeMiddleware"),
+    ("subscription limit", "SubscriptionLimitMiddleware"),
+    ("filter limit", "FilterLimitMiddleware"),
+    ("geo filter", "GeoFilterMiddleware"),
+    ("global kinds", "GlobalKindsMiddleware"),
+    ("count", "CountMiddleware"),
+    ("query cache", "QueryCacheMiddleware"),
+    ("error handling", "ErrorHandlingMiddleware"),
+    ("storage full", "StorageFullMiddleware"),
+    ("nip40", "Nip40ExpirationMiddleware"),
+    ("rate limit", "ScopeRateLimitMiddleware"),
+];
+
+/// relay_builder's own innermost middleware, which runs the processor
+const INNERMOST_TYPE: &str = "RelayMiddleware";
+
+/// Parts that must wrap others, as (outer, inner)
+const REQUIRED_ORDER: &[(&str, &str)] = &[
+    // OK rewrites apply to what the decision middleware reports
+    ("decision", "truncated ok"),
+    ("decision", "duplicate ok"),
+    // Refused events must not spend a scope's budget
+    ("auth", "rate limit"),
+    ("nip40", "rate limit"),
+    ("storage full", "rate limit"),
+    // Store errors surface as NOTICEs rather than dropped connections
+    ("error handling", "storage full"),
+    ("error handling", "query cache"),
+    ("error handling", "count"),
+];
+
+/// Refuses switched-off middleware that other settings rely on
+pub fn validate(config: &RelayConfig) -> Result<()> {
+    let strict_ttl = config.geohash_ttl_mode == TtlMode::Strict && config.geohash_max_ttl_days > 0;
+    if strict_ttl && !config.enable_nip40_expiration {
+        bail!("GEOHASH_TTL_MODE=strict relies on NIP-40 to hide expired events; set ENABLE_NIP40_EXPIRATION=true");
+    }
+    Ok(())
+}
+
+/// Parts of the chain whose type is named `type_name`, outermost first
+pub fn built_order(type_name: &str) -> Vec<&'static str> {
+    let position = |name: &str| type_name.find(&format!("::{}", name));
+    let mut found: Vec<(usize, &'static str)> =
+        TYPES.iter().filter_map(|&(part, name)| Some((position(name)?, part))).collect();
+    found.sort_unstable();
+    let mut order: Vec<&'static str> = found.into_iter().map(|(_, part)| part).collect();
+    // Whichever way the type nests, relay_builder's own middleware is innermost
+    let innermost = position(INNERMOST_TYPE);
+    if innermost.is_some() && innermost < type_name.find(&format!("::{}", TYPES[0].1)) {
+        order.reverse();
+    }
+    order
+}
+
+/// Refuses a built chain, given its type name, that doesn't match `CHAIN`
+/// or breaks the order its parts rely on
+pub fn check_built(type_name: &str) -> Result<()> {
+    let order = built_order(type_name);
+    if order != CHAIN {
+        bail!("middleware chain is built as [{}], not as listed in CHAIN", order.join(", "));
+    }
+    check_order(&order)
+}
+
+/// Refuses a chain where the logger isn't outermost, the rate limiter
+/// isn't innermost or a part doesn't wrap one it must
+fn check_order(chain: &[&str]) -> Result<()> {
+    let position = |name: &str| chain.iter().position(|part| *part == name);
+    if chain.first() != Some(&"logger") {
+        bail!("the logger must be the outermost middleware");
+    }
+    if chain.last() != Some(&"rate limit") {
+        bail!("the rate limiter must be the innermost middleware");
+    }
+    for &(outer, inner) in REQUIRED_ORDER {
+        match (position(outer), position(inner)) {
+            (Some(o), Some(i)) if o < i => {}
+            (Some(_), Some(_)) => bail!("{} middleware must run before {}", outer, inner),
+            _ => bail!("middleware chain is missing {} or {}", outer, inner),
+        }
+    }
+    Ok(())
+}
+
+/// Names of the switched-off parts, for the startup log
+pub fn disabled(config: &RelayConfig) -> Vec<&'static str> {
+    [
+        ("logger", config.enable_logger_middleware),
+        ("error handling", config.enable_error_handling),
+        ("nip40", config.enable_nip40_expiration),
+        ("rate limit", config.enable_rate_limit),
+    ]
+    .into_iter()
+    .filter(|(_, enabled)| !enabled)
+    .map(|(name, _)| name)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strict_ttl_needs_nip40() {
+        let strict = RelayConfig {
+            geohash_max_ttl_days: 7,
+            geohash_ttl_mode: TtlMode::Strict,
+            enable_nip40_expiration: false,
+            ..Default::default()
+        };
+        let err = validate(&strict).unwrap_err().to_string();
+        assert!(err.contains("ENABLE_NIP40_EXPIRATION"), "{}", err);
+        assert!(validate(&RelayConfig { enable_nip40_expiration: true, ..strict.clone() }).is_ok());
+        assert!(validate(&RelayConfig { geohash_ttl_mode: TtlMode::Lenient, ..strict }).is_ok());
+    }
+
+    /// Roughly what `type_name_of_val` reports for a chain of `parts`,
+    /// outermost first
+    fn chain_type(parts: &[&str]) -> String {
+        let mut name = "relay_builder::middleware::RelayMiddleware<geohashed_relay::processor::GeohashedEventProcessor>".to_string();
+        for part in parts.iter().rev() {
+            let ty = TYPES.iter().find(|(p, _)| p == part).unwrap().1;
+            name = format!("relay_builder::Chain<geohashed_relay::x::{}, {}>", ty, name);
+        }
+        name
+    }
+
+    #[test]
+    fn test_order_is_read_from_the_built_chain() {
+        assert_eq!(built_order(&chain_type(CHAIN)), CHAIN);
+        assert!(check_built(&chain_type(CHAIN)).is_ok());
+
+        // Nesting the other way round reads the same
+        let mut inside_out = "relay_builder::middleware::RelayMiddleware<P>".to_string();
+        for &(_, ty) in TYPES.iter().rev() {
+            inside_out = format!("relay_builder::Chain<{}, geohashed_relay::x::{}>", inside_out, ty);
+        }
+        assert_eq!(built_order(&inside_out), CHAIN);
+
+        let mut swapped = CHAIN.to_vec();
+        swapped.swap(1, 2);
+        let err = check_built(&chain_type(&swapped)).unwrap_err().to_string();
+        assert!(err.contains("not as listed in CHAIN"), "{}", err);
+
+        let missing = &CHAIN[..CHAIN.len() - 1];
+        assert!(check_built(&chain_type(missing)).is_err());
+    }
+
+    #[test]
+    fn test_chain_order() {
+        assert!(check_order(CHAIN).is_ok());
+
+        let mut limited_first = CHAIN.to_vec();
+        limited_first.retain(|part| *part != "rate limit");
+        limited_first.insert(1, "rate limit");
+        let err = check_order(&limited_first).unwrap_err().to_string();
+        assert!(err.contains("innermost"), "{}", err);
+
+        let mut unwrapped = CHAIN.to_vec();
+        unwrapped.retain(|part| *part != "error handling");
+        unwrapped.insert(unwrapped.len() - 1, "error handling");
+        let err = check_order(&unwrapped).unwrap_err().to_string();
+        assert!(err.contains("error handling middleware must run before"), "{}", err);
+    }
+
+    #[test]
+    fn test_disabled_parts_are_listed() {
+        assert!(disabled(&RelayConfig::default()).is_empty());
+        let config = RelayConfig { enable_logger_middleware: false, enable_rate_limit: false, ..Default::default() };
+        assert_eq!(disabled(&config), ["logger", "rate limit"]);
+    }
+}
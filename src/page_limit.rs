@@ -0,0 +1,70 @@
+//! Per-IP rate limit for the HTML info pages
+//!
+//! The info page is unauthenticated and backed by storage queries on a
+//! cache miss, so a plain HTTP flood would otherwise turn into LMDB load.
+//! Each client IP gets `info_page_requests_per_minute`; the render cache in
+//! `http_cache` keeps the allowed traffic off storage most of the time.
+
+use governor::{clock::{Clock, DefaultClock}, DefaultKeyedRateLimiter, Quota, RateLimiter};
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+/// Clients tracked before idle ones are dropped
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Requests per minute per client IP, or no limit
+pub struct PageRateLimiter {
+    limiter: Option<DefaultKeyedRateLimiter<IpAddr>>,
+}
+
+impl PageRateLimiter {
+    /// `requests_per_minute` of 0 disables the limit
+    pub fn new(requests_per_minute: u32) -> Self {
+        Self {
+            limiter: NonZeroU32::new(requests_per_minute).map(|rate| RateLimiter::keyed(Quota::per_minute(rate))),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self::new(0)
+    }
+
+    /// Counts a request from `ip`, or returns how long it must wait
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let Some(limiter) = &self.limiter else {
+            return Ok(());
+        };
+        if limiter.len() > MAX_TRACKED_CLIENTS {
+            limiter.retain_recent();
+        }
+        limiter
+            .check_key(&ip)
+            .map_err(|not_until| not_until.wait_time_from(DefaultClock::default().now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_is_per_client() {
+        let limiter = PageRateLimiter::new(2);
+        let a: IpAddr = "203.0.113.1".parse().unwrap();
+        let b: IpAddr = "203.0.113.2".parse().unwrap();
+
+        assert!(limiter.check(a).is_ok());
+        assert!(limiter.check(a).is_ok());
+        let wait = limiter.check(a).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(30));
+        assert!(limiter.check(b).is_ok());
+    }
+
+    #[test]
+    fn test_disabled_never_limits() {
+        let limiter = PageRateLimiter::disabled();
+        let ip: IpAddr = "203.0.113.1".parse().unwrap();
+        assert!((0..1000).all(|_| limiter.check(ip).is_ok()));
+    }
+}
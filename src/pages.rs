@@ -0,0 +1,694 @@
+//! HTML pages served on plain HTTP requests
+//!
+//! The subdomain and domain both come from the Host header and are therefore
+//! attacker-controlled. Templates escape every interpolated value, and data
+//! needed by the map scripts is passed as a JSON blob rather than spliced into
+//! JavaScript source.
+
+use askama::Template;
+use nostr::nips::nip19::ToBech32;
+use nostr::PublicKey;
+use serde::Serialize;
+use crate::cells::CellListing;
+use crate::config::RelayConfig;
+use crate::geohash_utils::{
+    containing_allowed_cell, describe_cell, is_allowed_precision, is_valid_geohash, neighborhood_bbox, GeoBbox,
+    MAX_GEOHASH_LENGTH,
+};
+use crate::nip11::DEFAULT_RELAY_NAME;
+use crate::policy::scope_rules;
+use crate::trending::TrendingReport;
+
+/// The relay info page shown when a browser hits `/`
+#[derive(Template)]
+#[template(path = "info.html")]
+struct InfoPage<'a> {
+    title: String,
+    heading: String,
+    relay_description: Option<&'a str>,
+    icon_url: Option<&'a str>,
+    banner_url: Option<&'a str>,
+    contact: Option<&'a str>,
+    operator_npub: Option<String>,
+    og_description: String,
+    og_url: String,
+    og_image: Option<String>,
+    /// "root", "geohash" or "invalid"
+    kind: &'static str,
+    /// Subdomain as requested (empty for root)
+    sub: &'a str,
+    domain: &'a str,
+    show_map: bool,
+    page_data: String,
+    accepted_rules: Vec<String>,
+    rejected_rules: Vec<String>,
+    /// Operator message while the relay is in maintenance mode
+    maintenance: Option<&'a str>,
+    /// Writes are refused until the operator clears read-only mode
+    read_only: bool,
+    /// The subdomain is a geohash at a precision that isn't served
+    unserved: bool,
+    /// The served cell containing an unserved one, if any
+    served_cell: Option<String>,
+    /// Websocket URL of the cell in the examples
+    cell_url: String,
+    /// This scope on the onion service, with `onion_address` set
+    onion_url: Option<String>,
+    /// Root only: the visitor's probable cell from GeoIP
+    suggested_cell: Option<&'a str>,
+    /// Geohash only: active child cells, busiest first
+    area_activity: Vec<AreaChild>,
+}
+
+/// One child cell in the "activity in this area" panel
+struct AreaChild {
+    geohash: String,
+    events: usize,
+    /// 1 to 4 by share of the busiest child's events
+    size: usize,
+}
+
+/// 404 page for root-domain paths that are not geohashes
+#[derive(Template)]
+#[template(path = "not_found.html")]
+struct NotFoundPage<'a> {
+    segment: &'a str,
+    domain: &'a str,
+    max_length: usize,
+}
+
+/// `/trending` on the root domain
+#[derive(Template)]
+#[template(path = "trending.html")]
+struct TrendingPage<'a> {
+    domain: &'a str,
+    window: &'a str,
+    /// "hour" or "24 hours"
+    window_label: &'static str,
+    min_pubkeys: usize,
+    cells: Vec<TrendingRow>,
+}
+
+struct TrendingRow {
+    geohash: String,
+    /// Cell center, rounded
+    location: String,
+    events: usize,
+    distinct_pubkeys: usize,
+}
+
+/// Data consumed by the map scripts
+#[derive(Serialize)]
+struct MapData<'a> {
+    domain: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    geohash: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lat: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lon: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    zoom: Option<u8>,
+    /// The cell and its neighbors as boxes that don't cross the antimeridian
+    #[serde(skip_serializing_if = "Option::is_none")]
+    neighborhood: Option<Vec<GeoBbox>>,
+}
+
+/// Map zoom level that roughly fits a cell of the given precision
+fn zoom_for_precision(precision: usize) -> u8 {
+    match precision {
+        1 => 2,
+        2 => 4,
+        3 => 7,
+        4 => 10,
+        5 => 12,
+        6 => 14,
+        7 => 18,
+        _ => 16,
+    }
+}
+
+/// Serializes a value as JSON that is safe to embed inside a `<script>` element
+///
+/// `<`, `>` and `&` are emitted as unicode escapes so the payload can never
+/// close the script element or open a comment.
+fn json_for_script<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value)
+        .unwrap_or_else(|_| "{}".to_string())
+        .replace('<', "\\u003c")
+        .replace('>', "\\u003e")
+        .replace('&', "\\u0026")
+}
+
+/// Renders the 404 page for a path segment that is not a geohash
+pub fn render_not_found(segment: &str, domain: &str) -> String {
+    NotFoundPage {
+        segment,
+        domain,
+        max_length: MAX_GEOHASH_LENGTH,
+    }
+    .render()
+    .expect("not found template rendering is infallible")
+}
+
+/// Renders the trending cells page
+pub fn render_trending_page(report: &TrendingReport, domain: &str) -> String {
+    TrendingPage {
+        domain,
+        window: report.window,
+        window_label: if report.window == "1h" { "hour" } else { "24 hours" },
+        min_pubkeys: report.min_pubkeys,
+        cells: report
+            .cells
+            .iter()
+            .map(|cell| TrendingRow {
+                geohash: cell.geohash.clone(),
+                location: format!("{:.2}, {:.2}", cell.lat, cell.lon),
+                events: cell.events,
+                distinct_pubkeys: cell.distinct_pubkeys,
+            })
+            .collect(),
+    }
+    .render()
+    .expect("trending template rendering is infallible")
+}
+
+/// Why the relay is refusing writes, for the banner atop the info page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WritesRefused<'a> {
+    /// Maintenance mode, with the operator's message
+    Maintenance(&'a str),
+    /// Read-only mode after startup
+    ReadOnly,
+}
+
+/// Renders the info page for the given scope
+///
+/// `subdomain` is `None` on the root domain. Invalid subdomains get the root
+/// page with a note explaining why the subdomain is not a geohash scope;
+/// cells at a precision the relay doesn't serve link to the served cell
+/// containing them.
+/// Operator branding from `config` is combined with the cell-specific text,
+/// and a banner tops the page while writes are `refused`.
+pub fn render_info_page(subdomain: Option<&str>, domain: &str, config: &RelayConfig, refused: Option<WritesRefused<'_>>) -> String {
+    info_page(subdomain, domain, config, refused)
+        .render()
+        .expect("info page template rendering is infallible")
+}
+
+/// Cell page with an "activity in this area" panel over `children`
+pub fn render_cell_page(
+    cell: &str,
+    domain: &str,
+    config: &RelayConfig,
+    refused: Option<WritesRefused<'_>>,
+    children: &CellListing,
+) -> String {
+    let mut page = info_page(Some(cell), domain, config, refused);
+    if page.kind == "geohash" {
+        let busiest = children.cells.iter().map(|child| child.events).max().unwrap_or_default().max(1);
+        page.area_activity = children
+            .cells
+            .iter()
+            .map(|child| AreaChild {
+                geohash: child.geohash.clone(),
+                events: child.events,
+                size: 1 + child.events * 3 / busiest,
+            })
+            .collect();
+    }
+    page.render()
+        .expect("info page template rendering is infallible")
+}
+
+/// Root page suggesting `cell` as the visitor's own (see `geoip`)
+///
+/// The suggestion is per visitor, so these pages are never cached.
+pub fn render_root_page_near(cell: &str, domain: &str, config: &RelayConfig, refused: Option<WritesRefused<'_>>) -> String {
+    let mut page = info_page(None, domain, config, refused);
+    page.suggested_cell = Some(cell);
+    page.render()
+        .expect("info page template rendering is infallible")
+}
+
+fn info_page<'a>(
+    subdomain: Option<&'a str>,
+    domain: &'a str,
+    config: &'a RelayConfig,
+    refused: Option<WritesRefused<'a>>,
+) -> InfoPage<'a> {
+    let relay_name = config.relay_name.as_deref();
+    let operator_npub = config
+        .operator_pubkey
+        .as_deref()
+        .and_then(|hex| PublicKey::from_hex(hex).ok())
+        .and_then(|pk| pk.to_bech32().ok());
+
+    let mut page = match subdomain {
+        Some(sub) if is_valid_geohash(sub) && is_allowed_precision(sub.len(), &config.allowed_precisions) => {
+            // Get center coordinates for the map
+            let center = geohash::decode(sub).ok().map(|(coord, _, _)| coord);
+            InfoPage {
+                title: match relay_name {
+                    Some(name) => format!("{} · {}", sub, name),
+                    None => format!("{} Nostr Relay", sub),
+                },
+                heading: relay_name.unwrap_or("Nostr Relay").to_string(),
+                relay_description: None,
+                icon_url: None,
+                banner_url: None,
+                contact: None,
+                operator_npub: None,
+                og_description: describe_cell(sub).unwrap_or_default(),
+                og_url: format!("https://{}.{}/", sub, domain),
+                og_image: config
+                    .preview_enabled
+                    .then(|| format!("https://{}.{}/preview.png", sub, domain)),
+                kind: "geohash",
+                sub,
+                domain,
+                show_map: center.is_some(),
+                page_data: json_for_script(&MapData {
+                    domain,
+                    geohash: Some(sub),
+                    lat: center.map(|c| c.y),
+                    lon: center.map(|c| c.x),
+                    zoom: Some(zoom_for_precision(sub.len())),
+                    neighborhood: neighborhood_bbox(sub).map(|area| area.split_at_antimeridian()),
+                }),
+                accepted_rules: Vec::new(),
+                rejected_rules: Vec::new(),
+                maintenance: None,
+                read_only: false,
+                unserved: false,
+                served_cell: None,
+                // Onion addresses have no subdomains, so cells are paths
+                cell_url: if config.tor_mode {
+                    format!("ws://{}/{}", domain, sub)
+                } else {
+                    format!("wss://{}.{}", sub, domain)
+                },
+                onion_url: config.onion_url_for(Some(sub)),
+                suggested_cell: None,
+                area_activity: Vec::new(),
+            }
+        }
+        Some(sub) => InfoPage {
+            title: relay_name.unwrap_or(DEFAULT_RELAY_NAME).to_string(),
+            heading: relay_name.unwrap_or(DEFAULT_RELAY_NAME).to_string(),
+            relay_description: None,
+            icon_url: None,
+            banner_url: None,
+            contact: None,
+            operator_npub: None,
+            og_description: String::new(),
+            og_url: format!("https://{}/", domain),
+            og_image: None,
+            kind: "invalid",
+            sub,
+            domain,
+            show_map: false,
+            page_data: String::new(),
+            accepted_rules: Vec::new(),
+            rejected_rules: Vec::new(),
+            maintenance: None,
+            read_only: false,
+            unserved: is_valid_geohash(sub),
+            served_cell: is_valid_geohash(sub)
+                .then(|| containing_allowed_cell(&sub.to_lowercase(), &config.allowed_precisions))
+                .flatten(),
+            cell_url: String::new(),
+            onion_url: None,
+            suggested_cell: None,
+            area_activity: Vec::new(),
+        },
+        None => InfoPage {
+            title: relay_name.unwrap_or(DEFAULT_RELAY_NAME).to_string(),
+            heading: relay_name.unwrap_or(DEFAULT_RELAY_NAME).to_string(),
+            relay_description: None,
+            icon_url: None,
+            banner_url: None,
+            contact: None,
+            operator_npub: None,
+            og_description: String::new(),
+            og_url: format!("https://{}/", domain),
+            og_image: None,
+            kind: "root",
+            sub: "",
+            domain,
+            show_map: true,
+            page_data: json_for_script(&MapData {
+                domain,
+                geohash: None,
+                lat: None,
+                lon: None,
+                zoom: None,
+                neighborhood: None,
+            }),
+            accepted_rules: Vec::new(),
+            rejected_rules: Vec::new(),
+            maintenance: None,
+            read_only: false,
+            unserved: false,
+            served_cell: None,
+            cell_url: String::new(),
+            onion_url: config.onion_url_for(None),
+            suggested_cell: None,
+            area_activity: Vec::new(),
+        },
+    };
+
+    let rules = scope_rules(subdomain, config);
+    page.accepted_rules = rules.accepted;
+    page.rejected_rules = rules.rejected;
+    
+    // Operator branding is shared by every scope
+    page.relay_description = config.relay_description.as_deref();
+    page.icon_url = config.relay_icon_url.as_deref();
+    page.banner_url = config.relay_banner_url.as_deref();
+    page.contact = config.operator_contact.as_deref();
+    page.operator_npub = operator_npub;
+    match refused {
+        Some(WritesRefused::Maintenance(message)) => page.maintenance = Some(message),
+        Some(WritesRefused::ReadOnly) => page.read_only = true,
+        None => {}
+    }
+    if page.og_description.is_empty() {
+        page.og_description = config.relay_description.clone().unwrap_or_else(|| {
+            "A Nostr relay with geohash-based data isolation".to_string()
+        });
+    }
+
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page_data_of(html: &str) -> serde_json::Value {
+        let start_tag = r#"<script type="application/json" id="relay-page-data">"#;
+        let start = html.find(start_tag).expect("page data present") + start_tag.len();
+        let end = start + html[start..].find("</script>").unwrap();
+        serde_json::from_str(&html[start..end]).unwrap()
+    }
+
+    #[test]
+    fn test_root_page() {
+        let html = render_info_page(None, "example.com", &RelayConfig::default(), None);
+
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<title>Geohashed Nostr Relay</title>"));
+        assert!(html.contains("Global Geohash Grid"));
+        assert!(html.contains("This root relay only accepts events <strong>without</strong> geohash tags"));
+        assert!(html.contains("nak req -l 10 wss://example.com"));
+        assert!(html.contains("<li>Events without geohash tags</li>"));
+        assert!(html.contains("<li>Must be posted to matching subdomain</li>"));
+
+        let data = page_data_of(&html);
+        assert_eq!(data["domain"], "example.com");
+        assert!(data.get("geohash").is_none());
+    }
+
+    #[test]
+    fn test_geohash_page() {
+        let html = render_info_page(Some("drt2z"), "example.com", &RelayConfig::default(), None);
+
+        assert!(html.contains("<title>drt2z Nostr Relay</title>"));
+        assert!(html.contains(r#"Nostr Relay <span style="color: #4ade80; font-weight: 600;">[drt2z]</span>"#));
+        assert!(html.contains("Geohash Grid Map"));
+        assert!(html.contains(r#"nak event -k 20000 -c "Hello from drt2z!" -t g=drt2z"#));
+        assert!(html.contains("wss://drt2z.example.com"));
+        assert!(html.contains("<li>Events without any geohash tag</li>"));
+        assert!(html.contains("<li>Events with different geohash tags</li>"));
+
+        let data = page_data_of(&html);
+        assert_eq!(data["geohash"], "drt2z");
+        assert_eq!(data["domain"], "example.com");
+        assert_eq!(data["zoom"], 12);
+        assert!(data["lat"].as_f64().is_some());
+        assert!(data["lon"].as_f64().is_some());
+        assert_eq!(data["neighborhood"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_map_data_splits_at_antimeridian() {
+        let html = render_info_page(Some("xbpbp"), "example.com", &RelayConfig::default(), None);
+        let neighborhood = page_data_of(&html)["neighborhood"].as_array().unwrap().clone();
+        assert_eq!(neighborhood.len(), 2);
+        for part in neighborhood {
+            assert_eq!(part["crosses_antimeridian"], false);
+            assert!(part["min_lon"].as_f64().unwrap() < part["max_lon"].as_f64().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_invalid_subdomain_page() {
+        let html = render_info_page(Some("foobar"), "example.com", &RelayConfig::default(), None);
+
+        assert!(html.contains("<title>Geohashed Nostr Relay</title>"));
+        assert!(html.contains("is not a valid geohash subdomain"));
+        assert!(html.contains("foobar"));
+        // No map and no page data for invalid scopes
+        assert!(!html.contains("relay-page-data"));
+        assert!(!html.contains("Geohash Grid"));
+    }
+
+    #[test]
+    fn test_unserved_precision_links_to_served_cell() {
+        let config = RelayConfig { allowed_precisions: vec![5], ..Default::default() };
+        let html = render_info_page(Some("drt2zby"), "example.com", &config, None);
+        assert!(html.contains("is not served at this precision"));
+        assert!(html.contains(r#"href="https://drt2z.example.com/""#));
+        assert!(!html.contains("relay-page-data"));
+
+        // Nothing served contains a coarser cell
+        let html = render_info_page(Some("drt"), "example.com", &config, None);
+        assert!(html.contains("is not served at this precision"));
+        assert!(!html.contains("drt2z.example.com"));
+    }
+
+    #[test]
+    fn test_hostile_subdomain_cannot_inject_markup() {
+        let hostile = r#"drt2z"><script>alert(1)</script>"#;
+        let html = render_info_page(Some(hostile), "example.com", &RelayConfig::default(), None);
+
+        assert!(!html.contains("<script>alert(1)</script>"));
+        assert!(!html.contains(r#""><script>"#));
+        assert!(html.contains("&lt;script&gt;"));
+    }
+
+    #[test]
+    fn test_not_found_page_escapes_segment() {
+        let html = render_not_found("<b>team</b>", "example.com");
+        assert!(html.contains("&lt;b&gt;team&lt;/b&gt;"));
+        assert!(!html.contains("<b>team</b>"));
+        assert!(html.contains("example.com/drt2z"));
+    }
+
+    #[test]
+    fn test_trending_page_links_cells() {
+        use crate::trending::TrendingCell;
+        let report = TrendingReport {
+            window: "24h",
+            min_pubkeys: 3,
+            cells: vec![TrendingCell {
+                geohash: "9q8yy".to_string(),
+                relay_url: "wss://9q8yy.example.com".to_string(),
+                events: 42,
+                distinct_pubkeys: 7,
+                lat: 37.77,
+                lon: -122.41,
+            }],
+            computed_at: None,
+        };
+        let html = render_trending_page(&report, "example.com");
+        assert!(html.contains("Trending cells in the last 24 hours"));
+        assert!(html.contains(r#"<a href="/9q8yy"><code>9q8yy</code></a>"#));
+        assert!(html.contains("<td>37.77, -122.41</td>"));
+        assert!(html.contains("<td>42</td>"));
+
+        let empty = TrendingReport { cells: Vec::new(), ..report };
+        assert!(render_trending_page(&empty, "example.com").contains("No cell has had at least 3 people"));
+    }
+
+    #[test]
+    fn test_hostile_domain_cannot_break_out_of_scripts() {
+        let hostile = r#"example.com</script><script>alert(1)</script>"#;
+
+        for subdomain in [None, Some("drt2z")] {
+            let html = render_info_page(subdomain, hostile, &RelayConfig::default(), None);
+            assert!(!html.contains("<script>alert(1)</script>"));
+
+            // The JSON blob still round-trips to the original value
+            let data = page_data_of(&html);
+            assert_eq!(data["domain"], hostile);
+        }
+    }
+
+    #[test]
+    fn test_json_for_script_escapes_html_significant_chars() {
+        let encoded = json_for_script(&"</script><!--&");
+        assert!(!encoded.contains('<'));
+        assert!(!encoded.contains('>'));
+        assert!(!encoded.contains('&'));
+        let decoded: String = serde_json::from_str(&encoded).unwrap();
+        assert_eq!(decoded, "</script><!--&");
+    }
+
+    fn branded_config() -> RelayConfig {
+        RelayConfig {
+            relay_name: Some("Hashstr".to_string()),
+            relay_description: Some("Location chat for everyone.".to_string()),
+            relay_icon_url: Some("https://example.com/icon.png".to_string()),
+            relay_banner_url: Some("https://example.com/banner.png".to_string()),
+            operator_contact: Some("ops@example.com".to_string()),
+            operator_pubkey: Some("3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d".to_string()),
+            ..RelayConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_unbranded_page_has_no_footer() {
+        let html = render_info_page(None, "example.com", &RelayConfig::default(), None);
+        assert!(!html.contains(r#"class="footer""#));
+        assert!(!html.contains(r#"class="banner""#));
+        assert!(!html.contains(r#"class="relay-icon""#));
+    }
+
+    #[test]
+    fn test_root_page_with_branding() {
+        let html = render_info_page(None, "example.com", &branded_config(), None);
+
+        assert!(html.contains("<title>Hashstr</title>"));
+        assert!(html.contains(r#"<img class="relay-icon" src="https:&#x2f;&#x2f;example.com&#x2f;icon.png" alt="">"#));
+        assert!(html.contains(r#"<img class="banner""#));
+        assert!(html.contains(r#"<div class="description">Location chat for everyone.</div>"#));
+        assert!(html.contains("Operator contact: ops@example.com"));
+        assert!(html.contains("npub180cvv07tjdrrgpa0j7j7tmnyl2yr6yr7l8j4s3evf6u64th6gkwsyjh6w6"));
+        // The standard root explanation is still there
+        assert!(html.contains("This root relay only accepts events"));
+    }
+
+    #[test]
+    fn test_geohash_page_combines_branding_with_cell_text() {
+        let html = render_info_page(Some("drt2z"), "example.com", &branded_config(), None);
+
+        assert!(html.contains("<title>drt2z · Hashstr</title>"));
+        assert!(html.contains(r#"Hashstr <span style="color: #4ade80; font-weight: 600;">[drt2z]</span>"#));
+        assert!(html.contains("Location chat for everyone."));
+        assert!(html.contains("represents a distinct geographic cell"));
+        assert!(html.contains("Operator contact: ops@example.com"));
+    }
+
+    #[test]
+    fn test_branding_values_are_escaped() {
+        let config = RelayConfig {
+            relay_name: Some("<b>evil</b>".to_string()),
+            operator_contact: Some(r#""><script>alert(1)</script>"#.to_string()),
+            ..RelayConfig::default()
+        };
+        let html = render_info_page(None, "example.com", &config, None);
+        assert!(!html.contains("<b>evil</b>"));
+        assert!(!html.contains("<script>alert(1)</script>"));
+    }
+
+    #[test]
+    fn test_geohash_page_open_graph_tags() {
+        let html = render_info_page(Some("drt2z"), "example.com", &RelayConfig::default(), None);
+
+        assert!(html.contains(r#"<meta property="og:title" content="drt2z Nostr Relay">"#));
+        assert!(html.contains("Geohash cell drt2z (~4.9km × 4.9km)"));
+        assert!(html.contains(r#"<meta property="og:image""#));
+        assert!(html.contains("preview.png"));
+        assert!(html.contains("summary_large_image"));
+    }
+
+    #[test]
+    fn test_no_preview_image_when_disabled() {
+        let config = RelayConfig {
+            preview_enabled: false,
+            ..RelayConfig::default()
+        };
+        let html = render_info_page(Some("drt2z"), "example.com", &config, None);
+        assert!(!html.contains("og:image"));
+        assert!(html.contains(r#"<meta name="twitter:card" content="summary">"#));
+
+        // Root never has a preview image
+        let html = render_info_page(None, "example.com", &RelayConfig::default(), None);
+        assert!(!html.contains("og:image"));
+    }
+
+    #[test]
+    fn test_maintenance_banner() {
+        let refused = Some(WritesRefused::Maintenance("migrating <storage>"));
+        let html = render_info_page(Some("drt2z"), "example.com", &RelayConfig::default(), refused);
+        assert!(html.contains(r#"<div class="maintenance" role="alert">"#));
+        assert!(html.contains("migrating &lt;storage&gt;"));
+        assert!(!html.contains("Read-only"));
+
+        let html = render_info_page(Some("drt2z"), "example.com", &RelayConfig::default(), None);
+        assert!(!html.contains(r#"class="maintenance""#));
+    }
+
+    #[test]
+    fn test_read_only_banner() {
+        let html = render_info_page(Some("drt2z"), "example.com", &RelayConfig::default(), Some(WritesRefused::ReadOnly));
+        assert!(html.contains(r#"<div class="maintenance" role="alert"><strong>Read-only:</strong>"#));
+        assert!(!html.contains("Down for maintenance"));
+    }
+
+    #[test]
+    fn test_tor_mode_page_uses_path_urls_and_shows_onion() {
+        let config = RelayConfig {
+            tor_mode: true,
+            onion_address: Some("abcdef.onion".to_string()),
+            ..Default::default()
+        };
+        let html = render_info_page(Some("drt2z"), "abcdef.onion", &config, None);
+        // URLs in variables may have their slashes escaped
+        let html = html.replace("&#x2f;", "/");
+        assert!(html.contains("nak req -l 10 ws://abcdef.onion/drt2z"));
+        assert!(!html.contains("drt2z.abcdef.onion"));
+        assert!(html.contains("Onion service: <code>ws://abcdef.onion/drt2z</code>"));
+    }
+
+    #[test]
+    fn test_root_page_suggests_local_cell() {
+        let html = render_root_page_near("drt2z", "example.com", &RelayConfig::default(), None);
+        assert!(html.contains("Your local cell is probably <strong>drt2z</strong>"));
+        assert!(html.contains(r#"href="https://drt2z.example.com/""#));
+        assert!(html.contains("Guessed from your IP address"));
+
+        let html = render_info_page(None, "example.com", &RelayConfig::default(), None);
+        assert!(!html.contains("Your local cell"));
+        assert!(!html.contains(r#"class="suggestion""#));
+    }
+
+    #[test]
+    fn test_cell_page_lists_area_activity() {
+        use crate::cells::ChildCell;
+
+        let child = |geohash: &str, events| ChildCell {
+            geohash: geohash.to_string(),
+            relay_url: format!("wss://{}.example.com", geohash),
+            events,
+            last_event_at: None,
+        };
+        let listing = CellListing {
+            prefix: "dr".to_string(),
+            cells: vec![child("drt", 40), child("dr5", 10)],
+            computed_at: Some(1_000),
+        };
+        let html = render_cell_page("dr", "example.com", &RelayConfig::default(), None, &listing);
+        assert!(html.contains("Activity in this area"));
+        let busiest = html.find(r#"<a class="area-child size-4" href="https://drt.example.com/">drt"#).unwrap();
+        let quieter = html.find(r#"<a class="area-child size-1" href="https://dr5.example.com/">dr5"#).unwrap();
+        assert!(busiest < quieter);
+
+        let empty = CellListing { cells: Vec::new(), ..listing };
+        let html = render_cell_page("dr", "example.com", &RelayConfig::default(), None, &empty);
+        assert!(!html.contains("Activity in this area"));
+        assert!(!render_info_page(Some("dr"), "example.com", &RelayConfig::default(), None).contains("Activity in this area"));
+    }
+}
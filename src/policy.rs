@@ -0,0 +1,474 @@
+//! Posting rules for a scope
+//!
+//! The info page and the welcome NOTICE both describe what a scope accepts;
+//! they take their wording from here so the two never disagree with each
+//! other or with `handle_event`. `EffectiveScopePolicy` is the same rules in
+//! machine-readable form, served at `/api/policy` and behind the NIP-11
+//! `limitation` object.
+
+use nostr::hashes::{sha256, Hash};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use crate::config::{DmPolicy, PrecisionCapPolicy, RelayConfig, TagPrecisionMode, TtlMode, WritePolicy};
+use crate::geohash_utils::{is_allowed_precision, is_valid_geohash, truncate_to_precision};
+
+/// Accepted/rejected event rules for one scope
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScopeRules {
+    pub accepted: Vec<String>,
+    pub rejected: Vec<String>,
+}
+
+/// Kinds joined for display, e.g. "0, 3, 10002"
+pub fn kinds_list(kinds: &[u16]) -> String {
+    kinds.iter().map(|k| k.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// Retention window for display, e.g. "1 day", "7 days", "6 hours"
+fn retention_window(secs: u64) -> String {
+    let (count, unit) = match secs {
+        s if s % 86_400 == 0 => (s / 86_400, "day"),
+        s if s % 3_600 == 0 => (s / 3_600, "hour"),
+        s if s % 60 == 0 => (s / 60, "minute"),
+        s => (s, "second"),
+    };
+    format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
+
+/// Whether `sub` is a geohash cell at a served precision
+fn is_served_cell(sub: &str, config: &RelayConfig) -> bool {
+    is_valid_geohash(sub) && is_allowed_precision(sub.len(), &config.allowed_precisions)
+}
+
+/// Rules for the scope at `subdomain` (`None` for root)
+pub fn scope_rules(subdomain: Option<&str>, config: &RelayConfig) -> ScopeRules {
+    let served = |sub: &str| is_served_cell(sub, config);
+    let mut rules = match subdomain {
+        Some(sub) if served(sub) => ScopeRules {
+            accepted: vec![
+                format!(r#"Events with ["g", "{}"] tag"#, sub),
+                "Events without any geohash tag".to_string(),
+            ],
+            rejected: vec!["Events with different geohash tags".to_string()],
+        },
+        // Root, and invalid or unserved subdomains shown with the root rules
+        _ => ScopeRules {
+            accepted: vec!["Events without geohash tags".to_string()],
+            rejected: vec![
+                r#"Events with ["g", "geohash"] tags"#.to_string(),
+                "Must be posted to matching subdomain".to_string(),
+            ],
+        },
+    };
+
+    let on_cell = subdomain.is_some_and(served);
+    if on_cell && !config.allowed_precisions.is_empty() {
+        let precisions = config.allowed_precisions.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", ");
+        match config.tag_precision_mode {
+            TagPrecisionMode::Reject => {
+                rules.rejected.push(format!("Geohash tags at precisions other than {}", precisions))
+            }
+            TagPrecisionMode::Adjust => rules.accepted.push(format!(
+                "Geohash tags at other precisions, moved to the nearest served precision ({})",
+                precisions
+            )),
+        }
+    }
+    if let Some(sub) = subdomain.filter(|_| on_cell) {
+        // Kinds capped coarser than this cell, grouped by cap
+        let mut capped: BTreeMap<usize, Vec<u16>> = BTreeMap::new();
+        for (kind, max) in config.kind_precision_caps.iter().filter(|(_, max)| **max < sub.len()) {
+            capped.entry(*max).or_default().push(*kind);
+        }
+        for (max, kinds) in capped {
+            let cell = truncate_to_precision(sub, max).unwrap_or_default();
+            match config.precision_cap_policy {
+                PrecisionCapPolicy::Reject => rules.rejected.push(format!(
+                    "Kinds {} (capped at precision {}; post them to {})",
+                    kinds_list(&kinds),
+                    max,
+                    cell
+                )),
+                PrecisionCapPolicy::Truncate => rules.accepted.push(format!(
+                    "Kinds {} are stored in {} (capped at precision {})",
+                    kinds_list(&kinds),
+                    cell,
+                    max
+                )),
+            }
+        }
+    }
+    if on_cell && !config.global_kinds.is_empty() {
+        rules.accepted.push(format!(
+            "Kinds {} are stored in the root scope and readable from every cell",
+            kinds_list(&config.global_kinds)
+        ));
+    }
+    if let Some(allowed) = config.allowed_kinds_for(subdomain) {
+        rules.rejected.push(format!("Kinds other than {}", kinds_list(allowed)));
+    }
+    if on_cell && config.geohash_retention_secs > 0 {
+        rules.accepted.push(format!(
+            "Events are deleted after {}",
+            retention_window(config.geohash_retention_secs)
+        ));
+    }
+    if on_cell && config.geohash_max_ttl_days > 0 {
+        let window = retention_window(config.geohash_max_ttl_days.saturating_mul(86_400));
+        let exempt = match config.geohash_ttl_exempt_kinds.as_slice() {
+            [] => String::new(),
+            kinds => format!(" (kinds {} exempt)", kinds_list(kinds)),
+        };
+        match config.geohash_ttl_mode {
+            TtlMode::Lenient => rules.accepted.push(format!("Events are deleted after {}{}", window, exempt)),
+            TtlMode::Strict => rules.rejected.push(format!(
+                "Events without a NIP-40 expiration within {}{}",
+                window, exempt
+            )),
+        }
+    }
+    match (config.dm_policy, on_cell) {
+        (DmPolicy::RootOnly, true) => {
+            rules.rejected.push("Direct messages (kinds 4, 1059); send them to the root relay".to_string());
+        }
+        (DmPolicy::Reject, _) => {
+            rules.rejected.push("Direct messages (kinds 4, 1059)".to_string());
+        }
+        _ => {}
+    }
+
+    rules
+}
+
+/// Everything a scope enforces, resolved from the config
+///
+/// Serializes to canonical JSON: fields in declaration order, maps sorted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EffectiveScopePolicy {
+    /// "root" or the cell's geohash
+    pub scope: String,
+    /// False for subdomains the relay doesn't serve; every event sent there
+    /// is rejected
+    pub accepts_events: bool,
+    pub kinds: KindRules,
+    pub precision: PrecisionRules,
+    pub writes: WriteRules,
+    pub auth: AuthRules,
+    pub retention: RetentionRules,
+    pub limits: SizeLimits,
+    /// Hash of everything above; changes whenever any of it does
+    pub policy_version: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KindRules {
+    /// `None` accepts every kind
+    pub allowed: Option<Vec<u16>>,
+    /// Stored in root and readable from every cell
+    pub global: Vec<u16>,
+    pub dm_policy: DmPolicy,
+    /// Whether this scope stores kinds 4 and 1059
+    pub dms_accepted: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PrecisionRules {
+    /// Served cell precisions; empty serves every precision
+    pub allowed: Vec<usize>,
+    pub tag_mode: TagPrecisionMode,
+    pub max_geohash_tags: u32,
+    /// Kinds capped coarser than this cell, by kind; empty on root
+    pub kind_caps: BTreeMap<u16, usize>,
+    pub cap_policy: PrecisionCapPolicy,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WriteRules {
+    /// 0 for no limit
+    pub events_per_minute: u32,
+    /// Leading zero bits event ids need, 0 for none
+    pub pow_difficulty: u8,
+    pub write_policy: WritePolicy,
+    pub payment_required: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuthRules {
+    pub read: bool,
+    pub write: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RetentionRules {
+    /// Events are deleted this long after they are stored
+    pub retention_secs: Option<u64>,
+    /// NIP-40 expiration given to events that don't carry one
+    pub default_expiration_secs: Option<u64>,
+    /// Longest events are kept, or may ask to be kept under strict mode
+    pub max_ttl_secs: Option<u64>,
+    /// Present when `max_ttl_secs` is
+    pub ttl_mode: Option<TtlMode>,
+    pub ttl_exempt_kinds: Vec<u16>,
+    /// Whether expired events are hidden and refused
+    pub nip40_expiration: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SizeLimits {
+    pub max_event_size: usize,
+    pub max_subscriptions: usize,
+    pub max_limit_per_filter: usize,
+    pub max_p_tags_per_event: u32,
+    pub max_tag_entries_per_letter: u32,
+    /// Per-kind tag limits replacing the two above
+    pub kind_tag_limits: BTreeMap<u16, u32>,
+}
+
+impl EffectiveScopePolicy {
+    /// Policy for the scope at `subdomain` (`None` for root), with the PoW
+    /// difficulty the scope currently requires
+    ///
+    /// Every field comes from the config accessors `handle_event` and the
+    /// middleware enforce with.
+    pub fn resolve(subdomain: Option<&str>, config: &RelayConfig, pow_difficulty: u8) -> Self {
+        let on_cell = subdomain.is_some_and(|sub| is_served_cell(sub, config));
+        let cell = subdomain.filter(|_| on_cell);
+        let max_ttl_secs = cell
+            .filter(|_| config.geohash_max_ttl_days > 0)
+            .map(|_| config.geohash_max_ttl_days.saturating_mul(86_400));
+        let write_policy = config.write_policy_for(subdomain);
+
+        let mut policy = Self {
+            scope: subdomain.map_or_else(|| "root".to_string(), str::to_lowercase),
+            accepts_events: subdomain.is_none() || on_cell,
+            kinds: KindRules {
+                allowed: config.allowed_kinds_for(subdomain).map(<[u16]>::to_vec),
+                global: config.global_kinds.clone(),
+                dm_policy: config.dm_policy,
+                dms_accepted: match config.dm_policy {
+                    DmPolicy::Allow => true,
+                    DmPolicy::RootOnly => !on_cell,
+                    DmPolicy::Reject => false,
+                },
+            },
+            precision: PrecisionRules {
+                allowed: config.allowed_precisions.clone(),
+                tag_mode: config.tag_precision_mode,
+                max_geohash_tags: config.max_geohash_tags_per_event,
+                kind_caps: cell
+                    .map(|sub| {
+                        config
+                            .kind_precision_caps
+                            .iter()
+                            .filter(|(_, max)| **max < sub.len())
+                            .map(|(kind, max)| (*kind, *max))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                cap_policy: config.precision_cap_policy,
+            },
+            writes: WriteRules {
+                events_per_minute: if config.enable_rate_limit { config.events_per_minute_for(subdomain) } else { 0 },
+                pow_difficulty,
+                write_policy,
+                payment_required: write_policy == WritePolicy::Paid,
+            },
+            auth: AuthRules {
+                read: config.read_auth_for(subdomain),
+                write: config.write_auth_for(subdomain),
+            },
+            retention: RetentionRules {
+                retention_secs: cell.map(|_| config.geohash_retention_secs).filter(|secs| *secs > 0),
+                default_expiration_secs: config.default_expiration_secs_for(subdomain),
+                max_ttl_secs,
+                ttl_mode: max_ttl_secs.map(|_| config.geohash_ttl_mode),
+                ttl_exempt_kinds: if max_ttl_secs.is_some() { config.geohash_ttl_exempt_kinds.clone() } else { Vec::new() },
+                nip40_expiration: config.enable_nip40_expiration,
+            },
+            limits: SizeLimits {
+                max_event_size: config.max_event_size,
+                max_subscriptions: config.max_subscriptions_per_connection,
+                max_limit_per_filter: config.max_limit_per_filter,
+                max_p_tags_per_event: config.max_p_tags_per_event,
+                max_tag_entries_per_letter: config.max_tag_entries_per_letter,
+                kind_tag_limits: config.kind_tag_limits.iter().map(|(kind, limit)| (*kind, *limit)).collect(),
+            },
+            policy_version: String::new(),
+        };
+        let canonical = serde_json::to_vec(&policy).expect("policy serializes");
+        policy.policy_version = sha256::Hash::hash(&canonical).to_string()[..16].to_string();
+        policy
+    }
+}
+
+/// NOTICE sent to new connections summarizing the scope's rules
+pub fn welcome_notice(subdomain: Option<&str>, config: &RelayConfig) -> String {
+    let limit = match config.events_per_minute_for(subdomain) {
+        0 => "no rate limit".to_string(),
+        per_minute => format!("{} events/min limit", per_minute),
+    };
+    match subdomain {
+        Some(sub) if is_valid_geohash(sub) => format!(
+            r#"Connected to {} scope — geotagged events must carry ["g","{}"]; untagged events are stored in this cell; {}"#,
+            sub, sub, limit
+        ),
+        Some(sub) => format!(
+            "Connected to '{}', which is not a geohash scope — events posted here are rejected",
+            sub
+        ),
+        None => format!(
+            "Connected to root scope — geotagged events must be posted to their geohash subdomain; untagged events are stored in root; {}",
+            limit
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_geohash_rules() {
+        let rules = scope_rules(Some("drt2z"), &RelayConfig::default());
+        assert_eq!(rules.accepted[0], r#"Events with ["g", "drt2z"] tag"#);
+        assert!(rules.accepted.iter().any(|r| r.contains("0, 3, 10002")));
+        assert!(rules.rejected.iter().any(|r| r.contains("Direct messages")));
+    }
+
+    #[test]
+    fn test_rules_follow_config() {
+        let config = RelayConfig {
+            global_kinds: vec![],
+            dm_policy: DmPolicy::Allow,
+            ..Default::default()
+        };
+        let rules = scope_rules(Some("drt2z"), &config);
+        assert_eq!(rules.accepted.len(), 2);
+        assert_eq!(rules.rejected.len(), 1);
+
+        // Root accepts DMs under the default policy
+        let rules = scope_rules(None, &RelayConfig::default());
+        assert!(!rules.rejected.iter().any(|r| r.contains("Direct messages")));
+    }
+
+    #[test]
+    fn test_unserved_precision_gets_root_rules() {
+        let config = RelayConfig { allowed_precisions: vec![5], ..Default::default() };
+        let rules = scope_rules(Some("drt2"), &config);
+        assert_eq!(rules.accepted[0], "Events without geohash tags");
+
+        let rules = scope_rules(Some("drt2z"), &config);
+        assert!(rules.rejected.iter().any(|r| r == "Geohash tags at precisions other than 5"));
+    }
+
+    #[test]
+    fn test_capped_kinds_for_the_cell_precision() {
+        let config = RelayConfig {
+            kind_precision_caps: BTreeMap::from([(30315, 4), (1311, 4), (7, 6)]),
+            ..Default::default()
+        };
+        let rules = scope_rules(Some("drt2z"), &config);
+        assert!(rules.rejected.contains(&"Kinds 1311, 30315 (capped at precision 4; post them to drt2)".to_string()));
+        assert!(!rules.rejected.iter().any(|r| r.starts_with("Kinds 7")));
+        // At or under every cap, and on root, nothing is capped
+        assert!(!scope_rules(Some("drt2"), &config).rejected.iter().any(|r| r.contains("capped")));
+        assert!(!scope_rules(None, &config).rejected.iter().any(|r| r.contains("capped")));
+
+        let config = RelayConfig { precision_cap_policy: PrecisionCapPolicy::Truncate, ..config };
+        let rules = scope_rules(Some("drt2zby"), &config);
+        assert!(rules.accepted.contains(&"Kinds 1311, 30315 are stored in drt2 (capped at precision 4)".to_string()));
+        assert!(rules.accepted.contains(&"Kinds 7 are stored in drt2zb (capped at precision 6)".to_string()));
+    }
+
+    #[test]
+    fn test_allowed_kinds_per_scope_type() {
+        let config = RelayConfig {
+            root_allowed_kinds: Some(vec![0, 3, 10002]),
+            geohash_allowed_kinds: Some(vec![1, 20000]),
+            ..Default::default()
+        };
+        assert!(scope_rules(None, &config).rejected.contains(&"Kinds other than 0, 3, 10002".to_string()));
+        assert!(scope_rules(Some("drt2z"), &config).rejected.contains(&"Kinds other than 1, 20000".to_string()));
+    }
+
+    #[test]
+    fn test_cell_retention_window() {
+        let config = RelayConfig {
+            geohash_retention_secs: 86_400,
+            geohash_max_ttl_days: 7,
+            geohash_ttl_exempt_kinds: vec![0, 10002],
+            ..Default::default()
+        };
+        let rules = scope_rules(Some("drt2z"), &config);
+        assert!(rules.accepted.contains(&"Events are deleted after 1 day".to_string()));
+        assert!(rules.accepted.contains(&"Events are deleted after 7 days (kinds 0, 10002 exempt)".to_string()));
+        assert!(!scope_rules(None, &config).accepted.iter().any(|r| r.contains("deleted")));
+
+        let config = RelayConfig {
+            geohash_max_ttl_days: 1,
+            geohash_ttl_mode: TtlMode::Strict,
+            ..Default::default()
+        };
+        let rules = scope_rules(Some("drt2z"), &config);
+        assert!(rules.rejected.contains(&"Events without a NIP-40 expiration within 1 day".to_string()));
+        assert_eq!(retention_window(6 * 3_600), "6 hours");
+    }
+
+    #[test]
+    fn test_effective_policy_per_scope() {
+        let config = RelayConfig {
+            geohash_allowed_kinds: Some(vec![1, 7]),
+            geohash_write_auth: true,
+            geohash_max_ttl_days: 7,
+            kind_precision_caps: BTreeMap::from([(30315, 4), (7, 6)]),
+            ..Default::default()
+        };
+        let cell = EffectiveScopePolicy::resolve(Some("drt2z"), &config, 8);
+        assert!(cell.accepts_events);
+        assert_eq!(cell.kinds.allowed.as_deref(), Some(&[1, 7][..]));
+        assert!(!cell.kinds.dms_accepted);
+        assert_eq!(cell.precision.kind_caps, BTreeMap::from([(30315, 4)]));
+        assert_eq!(cell.writes.pow_difficulty, 8);
+        assert!(cell.auth.write);
+        assert_eq!(cell.retention.max_ttl_secs, Some(7 * 86_400));
+        assert_eq!(cell.retention.ttl_mode, Some(TtlMode::Lenient));
+
+        let root = EffectiveScopePolicy::resolve(None, &config, 0);
+        assert_eq!(root.scope, "root");
+        assert!(root.kinds.allowed.is_none());
+        assert!(root.kinds.dms_accepted);
+        assert!(root.precision.kind_caps.is_empty());
+        assert!(!root.auth.write);
+        assert!(root.retention.max_ttl_secs.is_none());
+        assert_ne!(root.policy_version, cell.policy_version);
+    }
+
+    #[test]
+    fn test_effective_policy_serialization() {
+        let config = RelayConfig { allowed_precisions: vec![5], enable_rate_limit: false, ..Default::default() };
+        let json = serde_json::to_value(EffectiveScopePolicy::resolve(Some("drt2"), &config, 0)).unwrap();
+        assert_eq!(json["accepts_events"], false);
+        assert_eq!(json["writes"]["events_per_minute"], 0);
+        assert_eq!(json["kinds"]["dm_policy"], "root-only");
+        assert_eq!(json["policy_version"].as_str().unwrap().len(), 16);
+    }
+
+    #[test]
+    fn test_policy_version_is_stable() {
+        let config = RelayConfig::default();
+        let version = |config: &RelayConfig| EffectiveScopePolicy::resolve(Some("drt2z"), config, 0).policy_version;
+        assert_eq!(version(&config), version(&config.clone()));
+        assert_ne!(version(&config), version(&RelayConfig { max_event_size: 1024, ..config.clone() }));
+    }
+
+    #[test]
+    fn test_welcome_notice() {
+        let config = RelayConfig::default();
+        assert_eq!(
+            welcome_notice(Some("drt2z"), &config),
+            r#"Connected to drt2z scope — geotagged events must carry ["g","drt2z"]; untagged events are stored in this cell; 30 events/min limit"#
+        );
+        assert!(welcome_notice(None, &config).starts_with("Connected to root scope"));
+        assert!(welcome_notice(Some("team1"), &config).contains("not a geohash scope"));
+    }
+}
@@ -0,0 +1,235 @@
+//! NIP-13 proof of work, adaptive per scope
+//!
+//! Events must have an id with at least the scope's current difficulty in
+//! leading zero bits. With only `pow_min_difficulty` set that's a fixed
+//! requirement. With `pow_threshold_per_minute` and a higher
+//! `pow_max_difficulty`, `PowController` counts accepted events per scope
+//! and, every `pow_interval_secs`, raises a scope's difficulty one bit for
+//! each doubling of its rate above the threshold, clamped to the bounds.
+//!
+//! The difficulty is in the rejection message (and a NOTICE, sent by
+//! `PowNoticeMiddleware`) and in `/api/stats`, so clients can mine enough
+//! up front.
+
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::{Mutex, RwLock};
+use relay_builder::{NostrMiddleware, OutboundContext};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::config::RelayConfig;
+use crate::processor::ConnectionState;
+use crate::reject::reason_code;
+use crate::scope_counters::{ScopeCounterMap, ScopeCounters};
+
+/// `RejectReason::InsufficientPow`'s code
+const POW_REQUIRED_CODE: &str = "pow-required";
+
+/// Leading zero bits of an event id
+pub fn leading_zero_bits(id: &EventId) -> u8 {
+    let mut bits = 0u32;
+    for byte in id.as_bytes() {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits.min(u32::from(u8::MAX)) as u8
+}
+
+/// Difficulty for a scope accepting `rate` events per minute
+///
+/// `min` up to the threshold, then one more bit per doubling, at most `max`.
+pub fn difficulty_for_rate(rate: f64, min: u8, max: u8, threshold: u32) -> u8 {
+    if threshold == 0 || rate <= f64::from(threshold) {
+        return min;
+    }
+    let doublings = (rate / f64::from(threshold)).log2().floor() as u32;
+    (u32::from(min) + doublings).min(u32::from(max.max(min))) as u8
+}
+
+/// Required difficulty per scope
+#[derive(Debug)]
+pub struct PowController {
+    min: u8,
+    max: u8,
+    threshold: u32,
+    /// Accepted events per scope since the last recompute
+    counters: Arc<ScopeCounterMap>,
+    /// Scopes above the minimum
+    difficulty: RwLock<HashMap<Scope, u8>>,
+    last_recompute: Mutex<Instant>,
+}
+
+impl PowController {
+    pub fn new(min: u8, max: u8, threshold: u32) -> Self {
+        Self {
+            min,
+            max: max.max(min),
+            threshold,
+            counters: Arc::new(ScopeCounterMap::unlimited()),
+            difficulty: RwLock::new(HashMap::new()),
+            last_recompute: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn for_config(config: &RelayConfig) -> Self {
+        Self::new(config.pow_min_difficulty, config.pow_max_difficulty, config.pow_threshold_per_minute)
+    }
+
+    /// Counts accepted events in `counters`, shared with the rate limiter
+    /// so connections reach both through the counters they cached
+    pub fn with_counters(mut self, counters: Arc<ScopeCounterMap>) -> Self {
+        self.counters = counters;
+        self
+    }
+
+    /// No proof of work is ever required
+    pub fn disabled() -> Self {
+        Self::new(0, 0, 0)
+    }
+
+    fn is_adaptive(&self) -> bool {
+        self.threshold > 0 && self.max > self.min
+    }
+
+    /// Difficulty events in `scope` must meet
+    pub fn required(&self, scope: &Scope) -> u8 {
+        self.difficulty.read().get(scope).copied().unwrap_or(self.min)
+    }
+
+    /// Counts an accepted event towards `scope`'s rate
+    pub fn record(&self, scope: &Scope) {
+        self.record_in(scope, None);
+    }
+
+    /// `record`, through a connection's cached counters when they're for
+    /// `scope`
+    pub fn record_in(&self, scope: &Scope, cached: Option<&ScopeCounters>) {
+        if self.is_adaptive() {
+            self.counters.with_counters(cached, scope, |counters| counters.record_accepted());
+        }
+    }
+
+    /// Sets every scope's difficulty from its rate over `elapsed`
+    pub fn recompute(&self, elapsed: Duration) {
+        let accepted = self.counters.take_accepted();
+        let minutes = elapsed.as_secs_f64().max(1.0) / 60.0;
+        let difficulty: HashMap<Scope, u8> = accepted
+            .into_iter()
+            .map(|(scope, count)| {
+                let rate = count as f64 / minutes;
+                (scope, difficulty_for_rate(rate, self.min, self.max, self.threshold))
+            })
+            .filter(|(_, difficulty)| *difficulty > self.min)
+            .collect();
+        metrics::gauge!("relay_pow_raised_scopes").set(difficulty.len() as f64);
+        *self.difficulty.write() = difficulty;
+    }
+
+    /// `recompute` over the time since the previous call
+    pub fn tick(&self) {
+        let elapsed = std::mem::replace(&mut *self.last_recompute.lock(), Instant::now()).elapsed();
+        self.recompute(elapsed);
+    }
+}
+
+/// Recomputes difficulty every `interval` while adaptive PoW is on
+pub fn spawn_pow_controller(pow: Arc<PowController>, interval: Duration) {
+    if !pow.is_adaptive() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            pow.tick();
+        }
+    });
+}
+
+/// Follows PoW rejections with a NOTICE stating the current difficulty
+#[derive(Debug, Clone)]
+pub struct PowNoticeMiddleware;
+
+impl NostrMiddleware<ConnectionState> for PowNoticeMiddleware {
+    async fn process_outbound(&self, ctx: OutboundContext<'_, ConnectionState>) -> Result<(), anyhow::Error> {
+        if let Some(RelayMessage::Ok { status: false, message, .. }) = &ctx.message {
+            if reason_code(message) == Some(POW_REQUIRED_CODE) {
+                ctx.sender.send_bypass(RelayMessage::notice(message.to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reject::RejectReason;
+
+    #[test]
+    fn test_difficulty_staircase() {
+        let staircase: Vec<u8> = [0.0, 60.0, 119.0, 120.0, 240.0, 480.0, 960.0]
+            .into_iter()
+            .map(|rate| difficulty_for_rate(rate, 8, 24, 60))
+            .collect();
+        assert_eq!(staircase, vec![8, 8, 8, 9, 10, 11, 12]);
+    }
+
+    #[test]
+    fn test_difficulty_is_clamped() {
+        assert_eq!(difficulty_for_rate(1e9, 8, 12, 60), 12);
+        assert_eq!(difficulty_for_rate(1e9, 8, 4, 60), 8);
+        // No threshold: fixed at the minimum
+        assert_eq!(difficulty_for_rate(1e9, 8, 24, 0), 8);
+    }
+
+    #[test]
+    fn test_controller_recomputes_per_scope() {
+        let pow = PowController::new(4, 16, 10);
+        let hot = Scope::named("drt2z").unwrap();
+        let quiet = Scope::named("9q8yy").unwrap();
+        for _ in 0..80 {
+            pow.record(&hot);
+        }
+        for _ in 0..5 {
+            pow.record(&quiet);
+        }
+
+        // 80 events in a minute is three doublings over 10
+        pow.recompute(Duration::from_secs(60));
+        assert_eq!(pow.required(&hot), 7);
+        assert_eq!(pow.required(&quiet), 4);
+        assert_eq!(pow.required(&Scope::Default), 4);
+
+        // The same count over four minutes is only 20 a minute
+        for _ in 0..80 {
+            pow.record(&hot);
+        }
+        pow.recompute(Duration::from_secs(240));
+        assert_eq!(pow.required(&hot), 5);
+
+        // A quiet interval drops back to the minimum
+        pow.recompute(Duration::from_secs(60));
+        assert_eq!(pow.required(&hot), 4);
+    }
+
+    #[test]
+    fn test_notice_matches_rejection_code() {
+        let reason = RejectReason::InsufficientPow { scope: "drt2z".to_string(), difficulty: 16 };
+        assert_eq!(reason.code(), POW_REQUIRED_CODE);
+    }
+
+    #[test]
+    fn test_leading_zero_bits() {
+        let mut bytes = [0xffu8; 32];
+        assert_eq!(leading_zero_bits(&EventId::from_byte_array(bytes)), 0);
+        bytes[0] = 0;
+        bytes[1] = 0x1f;
+        assert_eq!(leading_zero_bits(&EventId::from_byte_array(bytes)), 11);
+        assert_eq!(leading_zero_bits(&EventId::from_byte_array([0; 32])), 255);
+    }
+}
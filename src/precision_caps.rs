@@ -0,0 +1,76 @@
+//! Precision caps for privacy-sensitive kinds
+//!
+//! Some kinds say where a person is right now (user statuses, check-ins);
+//! in a 7-character cell that is a street corner. `kind_precision_caps`
+//! names the finest precision each such kind may target. An event of a
+//! capped kind headed for a finer cell is refused with a message naming
+//! the cap, or under `PrecisionCapPolicy::Truncate` stored in the
+//! containing cell at the cap instead. The signed g tag is left as is.
+//!
+//! A truncated event's OK says where it went:
+//! `["OK", id, true, "truncated: stored in drt2 (kind 30315 is capped at precision 4)"]`.
+
+use nostr_sdk::prelude::*;
+use relay_builder::{NostrMiddleware, OutboundContext};
+use std::collections::HashMap;
+use crate::processor::ConnectionState;
+
+/// Message of the OK sent for an event stored at its kind's cap
+pub fn truncated_message(cell: &str, kind: u16, max: usize) -> String {
+    format!("truncated: stored in {} (kind {} is capped at precision {})", cell, kind, max)
+}
+
+/// Truncated events of a connection waiting for their OK
+#[derive(Debug, Clone, Default)]
+pub struct PendingTruncations {
+    messages: HashMap<EventId, String>,
+}
+
+impl PendingTruncations {
+    pub fn insert(&mut self, id: EventId, message: String) {
+        self.messages.insert(id, message);
+    }
+
+    pub fn remove(&mut self, id: &EventId) -> Option<String> {
+        self.messages.remove(id)
+    }
+}
+
+/// Rewrites the OK for a truncated event to name the cell it was stored in
+#[derive(Debug, Clone)]
+pub struct TruncatedOkMiddleware;
+
+impl NostrMiddleware<ConnectionState> for TruncatedOkMiddleware {
+    async fn process_outbound(&self, mut ctx: OutboundContext<'_, ConnectionState>) -> Result<(), anyhow::Error> {
+        let Some(RelayMessage::Ok { event_id, status, message }) = ctx.message.as_ref() else {
+            return Ok(());
+        };
+        let (event_id, rewrite) = (*event_id, *status && message.is_empty());
+        // Taken on any OK, so a later refusal in the store leaves nothing behind
+        let Some(truncated) = ctx.state.write().custom.truncations.remove(&event_id) else {
+            return Ok(());
+        };
+        if rewrite {
+            ctx.message.replace(RelayMessage::ok(event_id, true, truncated));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_truncation_is_taken_once() {
+        let id = EventId::all_zeros();
+        let mut pending = PendingTruncations::default();
+        assert_eq!(pending.remove(&id), None);
+        pending.insert(id, truncated_message("drt2", 30315, 4));
+        assert_eq!(
+            pending.remove(&id).as_deref(),
+            Some("truncated: stored in drt2 (kind 30315 is capped at precision 4)")
+        );
+        assert_eq!(pending.remove(&id), None);
+    }
+}
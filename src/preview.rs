@@ -0,0 +1,363 @@
+//! Static map preview images for geohash cells
+//!
+//! `GET /preview.png` on a geohash subdomain returns a single OSM tile with the
+//! cell outlined, used as the `og:image` for link unfurls. Rendering requires
+//! an outbound tile fetch, so results are cached on disk per geohash and cache
+//! misses go through a strict global rate limit.
+
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use image::{ImageFormat, Rgba, RgbaImage};
+use std::future::Future;
+use std::io::Cursor;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{debug, warn};
+use crate::geohash_utils::normalize_geohash;
+use crate::host_parsing::{host_info, DEFAULT_BASE_DOMAIN_PARTS};
+
+/// Size of a slippy map tile in pixels
+const TILE_SIZE: u32 = 256;
+
+/// Largest cell size (in pixels) we try to fit into the tile
+const MAX_CELL_PIXELS: f64 = 200.0;
+
+/// Outline color for the cell rectangle
+const OUTLINE: Rgba<u8> = Rgba([74, 222, 128, 255]);
+
+/// A slippy map tile address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileCoord {
+    pub z: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+/// Source of raw map tiles (PNG bytes)
+pub trait TileFetcher: Send + Sync + 'static {
+    fn fetch_tile(&self, tile: TileCoord) -> impl Future<Output = Result<Vec<u8>>> + Send;
+}
+
+/// Fetches tiles over HTTP from an OSM-compatible tile server
+#[derive(Debug, Clone)]
+pub struct HttpTileFetcher {
+    client: reqwest::Client,
+    url_template: String,
+}
+
+impl HttpTileFetcher {
+    /// `url_template` uses `{z}`, `{x}` and `{y}` placeholders
+    pub fn new(url_template: impl Into<String>) -> Self {
+        let client = reqwest::Client::builder()
+            .user_agent(concat!("geohashed-relay/", env!("CARGO_PKG_VERSION")))
+            .timeout(std::time::Duration::from_secs(10))
+            .build()
+            .expect("failed to build HTTP client");
+        Self {
+            client,
+            url_template: url_template.into(),
+        }
+    }
+}
+
+impl TileFetcher for HttpTileFetcher {
+    async fn fetch_tile(&self, tile: TileCoord) -> Result<Vec<u8>> {
+        let url = self
+            .url_template
+            .replace("{z}", &tile.z.to_string())
+            .replace("{x}", &tile.x.to_string())
+            .replace("{y}", &tile.y.to_string());
+        let bytes = self
+            .client
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        Ok(bytes.to_vec())
+    }
+}
+
+/// Renders and caches cell preview images
+pub struct PreviewService<F> {
+    fetcher: F,
+    cache_dir: PathBuf,
+    limiter: DefaultDirectRateLimiter,
+    base_domain_parts: usize,
+}
+
+impl<F: TileFetcher> PreviewService<F> {
+    pub fn new(fetcher: F, cache_dir: impl Into<PathBuf>, renders_per_minute: u32) -> Self {
+        let quota = Quota::per_minute(NonZeroU32::new(renders_per_minute.max(1)).unwrap());
+        Self {
+            fetcher,
+            cache_dir: cache_dir.into(),
+            limiter: RateLimiter::direct(quota),
+            base_domain_parts: DEFAULT_BASE_DOMAIN_PARTS,
+        }
+    }
+
+    /// Labels in the base domain, for finding the cell in the Host header
+    pub fn with_base_domain_parts(mut self, base_domain_parts: usize) -> Self {
+        self.base_domain_parts = base_domain_parts;
+        self
+    }
+
+    fn cache_path(&self, geohash: &str) -> PathBuf {
+        self.cache_dir.join(format!("{}.png", geohash))
+    }
+
+    /// Returns the PNG for a geohash, rendering it on a cache miss
+    ///
+    /// Returns `Ok(None)` when the render would exceed the rate limit.
+    pub async fn preview(&self, geohash: &str) -> Result<Option<Vec<u8>>> {
+        let geohash = normalize_geohash(geohash).ok_or_else(|| anyhow!("invalid geohash"))?;
+        let path = self.cache_path(&geohash);
+
+        if let Ok(bytes) = tokio::fs::read(&path).await {
+            return Ok(Some(bytes));
+        }
+
+        // Only cache misses make outbound requests, so only they are limited
+        if self.limiter.check().is_err() {
+            return Ok(None);
+        }
+
+        let (tile, rect) = tile_for_geohash(&geohash)?;
+        let tile_png = self.fetcher.fetch_tile(tile).await.context("tile fetch failed")?;
+        let png = render_preview(&tile_png, rect)?;
+
+        if let Err(e) = tokio::fs::create_dir_all(&self.cache_dir).await {
+            warn!("Failed to create preview cache dir {:?}: {}", self.cache_dir, e);
+        } else if let Err(e) = tokio::fs::write(&path, &png).await {
+            warn!("Failed to cache preview for {}: {}", geohash, e);
+        }
+
+        debug!("Rendered preview for {} from tile {:?}", geohash, tile);
+        Ok(Some(png))
+    }
+}
+
+/// Converts lon/lat to global pixel coordinates at the given zoom
+fn lonlat_to_pixels(lon: f64, lat: f64, zoom: u32) -> (f64, f64) {
+    let world = f64::from(TILE_SIZE) * 2f64.powi(zoom as i32);
+    // Web Mercator is undefined at the poles
+    let lat = lat.clamp(-85.0511, 85.0511).to_radians();
+    let x = (lon + 180.0) / 360.0 * world;
+    let y = (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / std::f64::consts::PI) / 2.0 * world;
+    (x, y)
+}
+
+/// Picks the tile and the cell rectangle (in tile pixels) for a geohash
+///
+/// Uses the highest zoom where the cell still fits comfortably in one tile.
+/// Returns the tile plus `(x0, y0, x1, y1)` pixel bounds relative to it.
+pub fn tile_for_geohash(geohash: &str) -> Result<(TileCoord, (f64, f64, f64, f64))> {
+    let bbox = geohash::decode_bbox(geohash).map_err(|e| anyhow!("{}", e))?;
+    let (min, max) = (bbox.min(), bbox.max());
+    let center = ((min.x + max.x) / 2.0, (min.y + max.y) / 2.0);
+
+    let mut zoom = 0;
+    for z in 0..=18 {
+        let (x0, y0) = lonlat_to_pixels(min.x, max.y, z);
+        let (x1, y1) = lonlat_to_pixels(max.x, min.y, z);
+        if x1 - x0 > MAX_CELL_PIXELS || y1 - y0 > MAX_CELL_PIXELS {
+            break;
+        }
+        zoom = z;
+    }
+
+    let (cx, cy) = lonlat_to_pixels(center.0, center.1, zoom);
+    let tiles = 2u32.pow(zoom);
+    let tile = TileCoord {
+        z: zoom,
+        x: ((cx / f64::from(TILE_SIZE)) as u32).min(tiles - 1),
+        y: ((cy / f64::from(TILE_SIZE)) as u32).min(tiles - 1),
+    };
+
+    let origin = (f64::from(tile.x * TILE_SIZE), f64::from(tile.y * TILE_SIZE));
+    let (x0, y0) = lonlat_to_pixels(min.x, max.y, zoom);
+    let (x1, y1) = lonlat_to_pixels(max.x, min.y, zoom);
+    Ok((tile, (x0 - origin.0, y0 - origin.1, x1 - origin.0, y1 - origin.1)))
+}
+
+/// Draws the cell outline onto a tile and encodes it as PNG
+pub fn render_preview(tile_png: &[u8], rect: (f64, f64, f64, f64)) -> Result<Vec<u8>> {
+    let mut img: RgbaImage = image::load_from_memory(tile_png)
+        .context("tile is not a valid image")?
+        .to_rgba8();
+    let (w, h) = img.dimensions();
+
+    // Clamp to the image; cells crossing a tile edge are clipped
+    let clamp_x = |v: f64| (v.round().max(0.0) as u32).min(w - 1);
+    let clamp_y = |v: f64| (v.round().max(0.0) as u32).min(h - 1);
+    let (x0, y0, x1, y1) = (clamp_x(rect.0), clamp_y(rect.1), clamp_x(rect.2), clamp_y(rect.3));
+
+    for thickness in 0..3u32 {
+        for x in x0..=x1 {
+            img.put_pixel(x, (y0 + thickness).min(h - 1), OUTLINE);
+            img.put_pixel(x, y1.saturating_sub(thickness), OUTLINE);
+        }
+        for y in y0..=y1 {
+            img.put_pixel((x0 + thickness).min(w - 1), y, OUTLINE);
+            img.put_pixel(x1.saturating_sub(thickness), y, OUTLINE);
+        }
+    }
+
+    let mut out = Cursor::new(Vec::new());
+    img.write_to(&mut out, ImageFormat::Png)?;
+    Ok(out.into_inner())
+}
+
+async fn preview_handler<F: TileFetcher>(
+    State(service): State<Arc<PreviewService<F>>>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(geohash) = host_info(&headers, service.base_domain_parts).subdomain.and_then(|s| normalize_geohash(&s)) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    match service.preview(&geohash).await {
+        Ok(Some(png)) => (
+            [
+                (header::CONTENT_TYPE, "image/png"),
+                (header::CACHE_CONTROL, "public, max-age=86400"),
+            ],
+            png,
+        )
+            .into_response(),
+        Ok(None) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, "60")],
+        )
+            .into_response(),
+        Err(e) => {
+            warn!("Preview rendering failed for {}: {}", geohash, e);
+            StatusCode::BAD_GATEWAY.into_response()
+        }
+    }
+}
+
+/// Routes for the preview endpoint
+pub fn router<F: TileFetcher>(service: Arc<PreviewService<F>>) -> Router {
+    Router::new()
+        .route("/preview.png", get(preview_handler::<F>))
+        .with_state(service)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::{to_bytes, Body}, http::Request};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::ServiceExt;
+
+    /// Returns a blank tile and counts how often it was asked for one
+    #[derive(Clone, Default)]
+    struct StubFetcher {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl TileFetcher for StubFetcher {
+        async fn fetch_tile(&self, _tile: TileCoord) -> Result<Vec<u8>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let tile = RgbaImage::from_pixel(TILE_SIZE, TILE_SIZE, Rgba([255, 255, 255, 255]));
+            let mut out = Cursor::new(Vec::new());
+            tile.write_to(&mut out, ImageFormat::Png)?;
+            Ok(out.into_inner())
+        }
+    }
+
+    #[test]
+    fn test_tile_selection_fits_cell() {
+        let (tile, (x0, y0, x1, y1)) = tile_for_geohash("drt2z").unwrap();
+        assert!(tile.z > 0 && tile.z <= 18);
+        assert!(x1 > x0 && y1 > y0);
+        assert!(x1 - x0 <= MAX_CELL_PIXELS && y1 - y0 <= MAX_CELL_PIXELS);
+
+        // Coarser cells use lower zoom levels
+        let (coarse, _) = tile_for_geohash("dr").unwrap();
+        assert!(coarse.z < tile.z);
+    }
+
+    #[tokio::test]
+    async fn test_renders_png_with_cell_outline() {
+        let fetcher = StubFetcher::default();
+        let (_, rect) = tile_for_geohash("drt2z").unwrap();
+        let tile = fetcher.fetch_tile(TileCoord { z: 0, x: 0, y: 0 }).await.unwrap();
+
+        let png = render_preview(&tile, rect).unwrap();
+        let img = image::load_from_memory(&png).unwrap().to_rgba8();
+        assert_eq!(img.dimensions(), (TILE_SIZE, TILE_SIZE));
+
+        let x0 = (rect.0.round().max(0.0) as u32).min(TILE_SIZE - 1);
+        let y0 = (rect.1.round().max(0.0) as u32).min(TILE_SIZE - 1);
+        assert_eq!(*img.get_pixel(x0, y0), OUTLINE);
+    }
+
+    #[tokio::test]
+    async fn test_preview_is_cached_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let fetcher = StubFetcher::default();
+        let service = PreviewService::new(fetcher.clone(), dir.path(), 10);
+
+        let first = service.preview("drt2z").await.unwrap().unwrap();
+        let second = service.preview("DRT2Z").await.unwrap().unwrap();
+        assert_eq!(first, second);
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 1);
+        assert!(dir.path().join("drt2z.png").exists());
+    }
+
+    #[tokio::test]
+    async fn test_cache_misses_are_rate_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = PreviewService::new(StubFetcher::default(), dir.path(), 1);
+
+        assert!(service.preview("drt2z").await.unwrap().is_some());
+        assert!(service.preview("9q8yy").await.unwrap().is_none());
+        // Cached cells are still served
+        assert!(service.preview("drt2z").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_handler_serves_png_on_geohash_host_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = Arc::new(PreviewService::new(StubFetcher::default(), dir.path(), 10));
+
+        let response = router(service.clone())
+            .oneshot(
+                Request::builder()
+                    .uri("/preview.png")
+                    .header("host", "drt2z.example.com")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/png");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(image::load_from_memory(&body).is_ok());
+
+        let response = router(service)
+            .oneshot(
+                Request::builder()
+                    .uri("/preview.png")
+                    .header("host", "example.com")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -2,59 +2,543 @@ use anyhow::Result;
 use nostr_sdk::prelude::*;
 use parking_lot::RwLock;
 use relay_builder::{EventContext, EventProcessor, StoreCommand, Error as RelayError};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::Instant;
-use tracing::{debug, info};
-use crate::geohash_utils::extract_geohash_tags;
+use tracing::{debug, info, warn};
+use crate::activity::ScopeActivity;
+use crate::admissions::AdmissionList;
+use crate::audit::{AuditDecision, AuditLog, AuditRecord};
+use crate::blocklist::Blocklist;
+use crate::cell_spread::CellSpreadLimiter;
+use crate::connection_stats::{is_stats_command, ConnectionStats, EventCounters, PendingStatsNotice};
+use crate::delegation::delegator;
+use crate::config::{DmPolicy, GeohashProfile, PrecisionCapPolicy, RelayConfig, TtlMode, WritePolicy};
+use crate::geohash_utils::{extract_geohash_tags_capped, is_allowed_precision, truncate_to_precision};
+use crate::global_kinds::is_stored_in_root;
+use crate::host_parsing::ConnectionOrigin;
+use crate::known_events::{KnownEvents, PendingDuplicates};
+use crate::live::PendingEvents;
+use crate::log_summary::{event_debug, LogSummary};
+use crate::duplicates::DuplicateFilter;
+use crate::expirations::{effective_expiration, Expirations};
+use crate::first_seen::FirstSeen;
+use crate::maintenance::Maintenance;
+use crate::pow::{leading_zero_bits, PowController};
+use crate::precision_caps::{truncated_message, PendingTruncations};
+use crate::quota::ScopeQuota;
+use crate::reject::RejectReason;
+use crate::routing::{self, ScopeDecision};
+use crate::scope_policy::{DefaultScopePolicy, ScopeClass, ScopePolicy};
+use crate::scope_counters::ScopeCounters;
+use crate::scope_residency::ScopeResidency;
+use crate::slow_consumer::OutboundSizes;
+use crate::storage::{DiskWatermark, StorageMonitor};
+use crate::store::{scope_label, ScopeStore, ROOT_SCOPE_LABEL};
+use crate::subscriptions::OpenSubscriptions;
+use crate::tombstones::{self, Tombstones};
+use crate::usage_report::UsageTally;
+use crate::vanish::{names_this_relay, VanishList, REQUEST_TO_VANISH_KIND};
+use crate::wot::WebOfTrust;
 
 /// Per-connection state for tracking
 #[derive(Debug, Clone, Default)]
 pub struct ConnectionState {
     pub events_sent: u64,
-    pub first_event_time: Option<Instant>,
+    /// Set by `connected` when the connection opens
+    pub connected_at: Option<Instant>,
+    /// Wall-clock time of `connected_at`, for reporting
+    pub connected_since: Option<Timestamp>,
+    /// Geohash the connection was opened to, `None` for root
     pub subdomain_info: Option<String>,
+    /// Peer address, which names the connection in `ConnectionRegistry`;
+    /// the proxy's when there is one
+    pub client_addr: Option<SocketAddr>,
+    /// The client's address, from `X-Forwarded-For` behind a trusted proxy
+    pub client_ip: Option<IpAddr>,
+    /// Domain and scheme the client connected with, for rejection hints
+    pub origin: Option<ConnectionOrigin>,
+    pub outbound_sizes: OutboundSizes,
+    /// Events waiting for their OK before `LiveEvents` is notified
+    pub pending_events: PendingEvents,
+    /// Open subscription ids, for the per-connection cap
+    pub subscriptions: OpenSubscriptions,
+    /// EVENT outcomes in the current minute, for the stats command
+    pub event_counters: EventCounters,
+    pub last_stats_notice: Option<Instant>,
+    /// Stats answer to send once the command's OK goes out
+    pub stats_notice: Option<PendingStatsNotice>,
+    /// Already stored events whose OK gets the `duplicate:` prefix
+    pub duplicates: PendingDuplicates,
+    /// Events stored at their kind's precision cap, whose OK says where
+    pub truncations: PendingTruncations,
+    /// Set only by `publish_internal`; websocket connections start from
+    /// `Default` and have no way to set it
+    pub internal: bool,
+    /// What `handle_event` decided for the latest EVENT, for middleware
+    /// further out in the chain (see `decisions`)
+    pub last_decision: Option<ScopeDecision>,
+    /// The scope's shared counters, resolved when the connection opens so
+    /// events don't look them up (see `scope_counters`)
+    pub scope_counters: Option<Arc<ScopeCounters>>,
 }
 
+impl ConnectionState {
+    /// Records where a connection that just opened points and comes from
+    pub fn connected(&mut self, scope: &nostr_lmdb::Scope, client_addr: Option<SocketAddr>, origin: Option<ConnectionOrigin>) {
+        self.connected_at = Some(Instant::now());
+        self.connected_since = Some(Timestamp::now());
+        self.subdomain_info = match scope {
+            nostr_lmdb::Scope::Named { name, .. } => Some(name.clone()),
+            nostr_lmdb::Scope::Default => None,
+        };
+        self.client_addr = client_addr;
+        self.client_ip = origin.as_ref().and_then(|origin| origin.client_ip).or(client_addr.map(|addr| addr.ip()));
+        self.origin = origin;
+    }
+}
+
+/// The routing decision behind `result`, if it stores or refuses an event
+fn decision_of(result: &Result<Vec<StoreCommand>, RejectReason>) -> Option<ScopeDecision> {
+    match result {
+        Ok(commands) => match commands.first() {
+            Some(StoreCommand::SaveSignedEvent(_, scope, _)) => Some(ScopeDecision::Store(scope.clone())),
+            _ => None,
+        },
+        Err(reason) => Some(ScopeDecision::Reject(reason.clone())),
+    }
+}
+
+/// Kinds carrying direct messages: legacy NIP-04 DMs and NIP-59 gift wraps
+pub(crate) fn is_dm_kind(kind: Kind) -> bool {
+    kind == Kind::EncryptedDirectMessage || kind == Kind::GiftWrap
+}
+
+/// First single-letter tag that `kind` carries more of than `config` allows
+fn excess_tags(tags: &[Vec<String>], kind: u16, config: &RelayConfig) -> Option<RejectReason> {
+    let mut counts: BTreeMap<char, u32> = BTreeMap::new();
+    for name in tags.iter().filter_map(|tag| tag.first()) {
+        let mut chars = name.chars();
+        if let (Some(letter), None) = (chars.next(), chars.next()) {
+            if letter.is_ascii_alphabetic() {
+                *counts.entry(letter).or_default() += 1;
+            }
+        }
+    }
+    counts.into_iter().find_map(|(letter, count)| {
+        let max = config.tag_limit_for(kind, letter);
+        (max > 0 && count > max).then_some(RejectReason::TooManyTags { letter, max })
+    })
+}
 
 /// Multi-tenant event processor with geohash-based location routing
+///
+/// What scopes exist and where events go is up to `P`; see `scope_policy`.
 #[derive(Debug, Clone)]
-pub struct GeohashedEventProcessor {
+pub struct GeohashedEventProcessor<P = DefaultScopePolicy> {
+    config: Arc<RelayConfig>,
+    policy: P,
+    routing: routing::ScopePolicy,
+    storage: Arc<StorageMonitor>,
+    disk: Arc<DiskWatermark>,
+    quota: Arc<ScopeQuota>,
+    admissions: Arc<AdmissionList>,
+    audit: AuditLog,
+    maintenance: Arc<Maintenance>,
+    activity: Arc<ScopeActivity>,
+    pow: Arc<PowController>,
+    duplicates: Arc<DuplicateFilter>,
+    blocklist: Arc<Blocklist>,
+    cell_spread: Arc<CellSpreadLimiter>,
+    known: KnownEvents,
+    first_seen: Arc<FirstSeen>,
+    wot: Arc<WebOfTrust>,
+    expirations: Arc<Expirations>,
+    residency: Arc<ScopeResidency>,
+    usage: Arc<UsageTally>,
+    log_summary: Arc<LogSummary>,
+    vanished: Arc<VanishList>,
+    tombstones: Arc<Tombstones>,
 }
 
 impl GeohashedEventProcessor {
     pub fn new() -> Self {
-        Self {
-        }
+        Self::with_config(Arc::new(RelayConfig::default()))
+    }
+    
+    pub fn with_config(config: Arc<RelayConfig>) -> Self {
+        Self::with_policy(config, DefaultScopePolicy)
     }
 }
 
-impl EventProcessor<ConnectionState> for GeohashedEventProcessor {
-    async fn handle_event(
+impl<P: ScopePolicy> GeohashedEventProcessor<P> {
+    /// A processor whose scopes are decided by `policy`
+    pub fn with_policy(config: Arc<RelayConfig>, policy: P) -> Self {
+        Self {
+            quota: Arc::new(ScopeQuota::new(&config)),
+            policy,
+            routing: routing::ScopePolicy::from_config(&config),
+            storage: Arc::new(StorageMonitor::disabled()),
+            disk: Arc::new(DiskWatermark::disabled()),
+            admissions: Arc::new(AdmissionList::in_memory()),
+            audit: AuditLog::disabled(),
+            maintenance: Arc::new(Maintenance::disabled()),
+            activity: Arc::new(ScopeActivity::new()),
+            pow: Arc::new(PowController::for_config(&config)),
+            duplicates: Arc::new(DuplicateFilter::for_config(&config)),
+            blocklist: Arc::new(Blocklist::new(config.blocklist.clone()).unwrap_or_else(|e| {
+                warn!("Ignoring invalid blocklist: {:#}", e);
+                Blocklist::disabled()
+            })),
+            cell_spread: Arc::new(CellSpreadLimiter::new(config.max_cells_per_ip_per_hour)),
+            known: KnownEvents::default(),
+            first_seen: Arc::new(FirstSeen::in_memory()),
+            wot: Arc::new(WebOfTrust::for_config(&config)),
+            expirations: Arc::new(Expirations::in_memory()),
+            residency: Arc::new(ScopeResidency::disabled()),
+            usage: Arc::new(UsageTally::disabled()),
+            log_summary: Arc::new(LogSummary::disabled()),
+            vanished: Arc::new(VanishList::in_memory(config.vanish_block_secs)),
+            tombstones: Arc::new(Tombstones::disabled()),
+            config,
+        }
+    }
+    
+    /// Shares the storage monitor so events are refused once storage is full
+    pub fn with_storage(mut self, storage: Arc<StorageMonitor>) -> Self {
+        self.storage = storage;
+        self
+    }
+    
+    /// Shares the disk watermark so events are refused under storage pressure
+    pub fn with_disk_watermark(mut self, disk: Arc<DiskWatermark>) -> Self {
+        self.disk = disk;
+        self
+    }
+    
+    /// Shares the per-cell quota with the task that refreshes and evicts it
+    pub fn with_quota(mut self, quota: Arc<ScopeQuota>) -> Self {
+        self.quota = quota;
+        self
+    }
+    
+    /// Shares the list of pubkeys allowed to write to paid scopes
+    pub fn with_admissions(mut self, admissions: Arc<AdmissionList>) -> Self {
+        self.admissions = admissions;
+        self
+    }
+    
+    /// Records every processed event in the audit log
+    pub fn with_audit(mut self, audit: AuditLog) -> Self {
+        self.audit = audit;
+        self
+    }
+    
+    /// Shares the maintenance and read-only flags toggled through the admin API
+    pub fn with_maintenance(mut self, maintenance: Arc<Maintenance>) -> Self {
+        self.maintenance = maintenance;
+        self
+    }
+    
+    /// Shares the recently active cells listed by `/api/scopes`
+    pub fn with_activity(mut self, activity: Arc<ScopeActivity>) -> Self {
+        self.activity = activity;
+        self
+    }
+    
+    /// Shares the per-scope proof-of-work difficulty with its controller task
+    pub fn with_pow(mut self, pow: Arc<PowController>) -> Self {
+        self.pow = pow;
+        self
+    }
+    
+    /// Shares the content blocklist updated through the admin API
+    pub fn with_blocklist(mut self, blocklist: Arc<Blocklist>) -> Self {
+        self.blocklist = blocklist;
+        self
+    }
+    
+    /// Looks events up in the store so republished ones aren't written again
+    pub fn with_store(mut self, store: Arc<dyn ScopeStore>) -> Self {
+        self.known = KnownEvents::new(store);
+        self
+    }
+    
+    /// Shares the first-seen timestamps served by the API
+    pub fn with_first_seen(mut self, first_seen: Arc<FirstSeen>) -> Self {
+        self.first_seen = first_seen;
+        self
+    }
+    
+    /// Shares the trusted set rebuilt by the web-of-trust task
+    pub fn with_wot(mut self, wot: Arc<WebOfTrust>) -> Self {
+        self.wot = wot;
+        self
+    }
+    
+    /// Shares the requests to vanish carried out by the vanish task
+    pub fn with_vanished(mut self, vanished: Arc<VanishList>) -> Self {
+        self.vanished = vanished;
+        self
+    }
+    
+    /// Shares the tombstones restored and purged through the admin API
+    pub fn with_tombstones(mut self, tombstones: Arc<Tombstones>) -> Self {
+        self.tombstones = tombstones;
+        self
+    }
+    
+    /// Shares the effective expirations swept by the expiration task
+    pub fn with_expirations(mut self, expirations: Arc<Expirations>) -> Self {
+        self.expirations = expirations;
+        self
+    }
+    
+    /// Tracks written scopes, releasing the duplicate windows of idle ones
+    pub fn with_residency(mut self, residency: Arc<ScopeResidency>) -> Self {
+        residency.register(self.duplicates.clone());
+        self.residency = residency;
+        self
+    }
+    
+    /// Counts accepted and rejected events for the daily usage report
+    pub fn with_usage(mut self, usage: Arc<UsageTally>) -> Self {
+        self.usage = usage;
+        self
+    }
+    
+    /// Counts stored and rejected events for the periodic log summary
+    pub fn with_log_summary(mut self, log_summary: Arc<LogSummary>) -> Self {
+        self.log_summary = log_summary;
+        self
+    }
+    
+    /// Notes the expiration of an event about to be stored in `scope`: a
+    /// relay-side one when the scope has a default expiration, else its
+    /// own NIP-40 tag, so the sweeper and COUNTs see it too
+    fn record_expiration(&self, event: &Event, scope: &nostr_lmdb::Scope, now: u64) {
+        let subdomain = match scope {
+            nostr_lmdb::Scope::Named { name, .. } => Some(name.as_str()),
+            nostr_lmdb::Scope::Default => None,
+        };
+        let relay_side = self.config.default_expiration_secs_for(subdomain).and_then(|default_secs| {
+            let max_ttl_secs = subdomain.and_then(|_| self.config.geohash_ttl_secs_for(event.kind.as_u16()));
+            effective_expiration(event, default_secs, max_ttl_secs, now)
+        });
+        let tagged = || {
+            let tag = event.tags.expiration().filter(|_| self.config.enable_nip40_expiration);
+            tag.map(|expiration| expiration.as_u64())
+        };
+        if let Some(expires_at) = relay_side.or_else(tagged) {
+            self.expirations.record(scope, event.id, expires_at);
+        }
+    }
+    
+    /// Runs the relay's own `event` through `handle_event` for `scope` as
+    /// an internal submission, so it skips the write policies clients are
+    /// held to but is still routed like any other event
+    pub async fn publish_internal(
         &self,
         event: Event,
-        custom_state: Arc<RwLock<ConnectionState>>,
-        context: &EventContext,
+        relay_pubkey: PublicKey,
+        scope: nostr_lmdb::Scope,
     ) -> Result<Vec<StoreCommand>, RelayError> {
-        // Initialize connection state if needed
+        let state = Arc::new(RwLock::new(ConnectionState { internal: true, ..Default::default() }));
+        let context = EventContext {
+            relay_pubkey,
+            subdomain: Arc::new(scope),
+            authed_pubkey: None,
+        };
+        self.handle_event(event, state, &context).await
+    }
+    
+    /// Whether `event` is the relay's own and came through
+    /// `publish_internal`; a client replaying a relay-signed event doesn't
+    /// qualify
+    fn is_self_published(&self, event: &Event, custom_state: &RwLock<ConnectionState>, context: &EventContext) -> bool {
+        custom_state.read().internal && event.pubkey == context.relay_pubkey && event.verify().is_ok()
+    }
+    
+    /// Error for a rejection, counted by reason
+    fn reject(&self, reason: RejectReason) -> RelayError {
+        metrics::counter!("relay_events_rejected_total", "reason" => reason.code()).increment(1);
+        RelayError::restricted(reason.to_string())
+    }
+    
+    /// Whether `scope` already stores `event`, which is then marked for its
+    /// `duplicate:` OK instead of being saved again
+    async fn already_stored(&self, scope: &nostr_lmdb::Scope, event: &Event, custom_state: &RwLock<ConnectionState>) -> bool {
+        if !self.known.contains(scope, event.id).await {
+            return false;
+        }
+        debug!("Event {} is already stored in {:?}", event.id, scope);
+        let scope_type = if matches!(scope, nostr_lmdb::Scope::Default) { "root" } else { "geohash" };
+        metrics::counter!("relay_events_duplicate_total", "scope_type" => scope_type).increment(1);
         let mut state = custom_state.write();
+        state.duplicates.insert(event.id);
+        state.pending_events.remove(&event.id);
+        true
+    }
+    
+    /// Saves `event` into `scope` unless the scope already stores it, it
+    /// lacks the scope's proof of work, repeats content many authors just
+    /// posted there, or the cell is at its quota
+    async fn save_in(
+        &self,
+        event: Event,
+        scope: nostr_lmdb::Scope,
+        custom_state: &RwLock<ConnectionState>,
+    ) -> Result<Vec<StoreCommand>, RejectReason> {
+        // A resend is answered before it takes anything from the scope's limits
+        if self.already_stored(&scope, &event, custom_state).await {
+            return Ok(Vec::new());
+        }
+        self.residency.touch(&scope);
+        let difficulty = self.pow.required(&scope);
+        if difficulty > 0 && leading_zero_bits(&event.id) < difficulty {
+            return Err(RejectReason::InsufficientPow { scope: scope_label(&scope), difficulty });
+        }
+        if !self.duplicates.admit(&scope, &event) {
+            event_debug!("Rejecting event {}: duplicate content in scope {:?}", event.id, scope);
+            return Err(RejectReason::DuplicateContent);
+        }
+        if self.quota.is_enabled() && !self.quota.admit(&scope, event.as_json().len() as u64) {
+            event_debug!("Rejecting event {}: scope {:?} is at its quota", event.id, scope);
+            return Err(RejectReason::ScopeFull);
+        }
+        Ok(vec![StoreCommand::SaveSignedEvent(Box::new(event), scope, None)])
+    }
+    
+    /// Applies `kind_precision_caps` to where `event` is headed: a cell
+    /// finer than its kind's cap refuses it, or under the truncate policy
+    /// gives way to the containing cell at the cap
+    fn cap_precision(
+        &self,
+        event: &Event,
+        decision: ScopeDecision,
+        custom_state: &RwLock<ConnectionState>,
+    ) -> Result<ScopeDecision, RejectReason> {
+        let kind = event.kind.as_u16();
+        let (Some(&max), ScopeDecision::Store(nostr_lmdb::Scope::Named { name, .. })) =
+            (self.config.kind_precision_caps.get(&kind), &decision)
+        else {
+            return Ok(decision);
+        };
+        if name.len() <= max {
+            return Ok(decision);
+        }
+        let capped = RejectReason::PrecisionCapped { kind, geohash: name.clone(), max };
+        if self.config.precision_cap_policy == PrecisionCapPolicy::Reject {
+            event_debug!("Rejecting event {}: kind {} is capped at precision {}", event.id, kind, max);
+            return Err(capped);
+        }
+        // A cap at a precision the relay doesn't serve leaves nowhere to go
+        let Some(cell) = truncate_to_precision(name, max)
+            .filter(|cell| is_allowed_precision(cell.len(), &self.config.allowed_precisions))
+        else {
+            return Err(capped);
+        };
+        let scope = nostr_lmdb::Scope::named(&cell).map_err(|_| capped)?;
+        event_debug!("Moving event {} from {} to {}: kind {} is capped at precision {}", event.id, name, cell, kind, max);
+        metrics::counter!("relay_precision_capped_events_total").increment(1);
+        custom_state.write().truncations.insert(event.id, truncated_message(&cell, kind, max));
+        Ok(ScopeDecision::Store(scope))
+    }
+    
+    /// Strict TTL mode: whether a cell event's NIP-40 expiration is within
+    /// the TTL; always true otherwise
+    fn expires_within_ttl(&self, event: &Event) -> bool {
+        if self.config.geohash_ttl_mode != TtlMode::Strict {
+            return true;
+        }
+        let Some(ttl) = self.config.geohash_ttl_secs_for(event.kind.as_u16()) else {
+            return true;
+        };
+        let latest = Timestamp::now().as_u64().saturating_add(ttl);
+        event.tags.expiration().is_some_and(|expiration| expiration.as_u64() <= latest)
+    }
+    
+    /// Describes the connection in reply to a stats command; nothing is stored
+    fn answer_stats(
+        &self,
+        event: &Event,
+        custom_state: &Arc<RwLock<ConnectionState>>,
+        context: &EventContext,
+    ) -> Result<Vec<StoreCommand>, RejectReason> {
         let now = Instant::now();
-        
-        if state.first_event_time.is_none() {
-            state.first_event_time = Some(now);
-            state.subdomain_info = match context.subdomain.as_ref() {
-                nostr_lmdb::Scope::Named { name, .. } => Some(name.clone()),
-                nostr_lmdb::Scope::Default => None,
-            };
+        let interval = std::time::Duration::from_secs(self.config.stats_command_interval_secs);
+        let mut state = custom_state.write();
+        if let Some(last) = state.last_stats_notice {
+            let elapsed = now.duration_since(last);
+            if elapsed < interval {
+                return Err(RejectReason::StatsTooSoon { retry_secs: (interval - elapsed).as_secs().max(1) });
+            }
         }
+        state.last_stats_notice = Some(now);
+        state.event_counters.roll(now);
         
+        let subdomain = match context.subdomain.as_ref() {
+            nostr_lmdb::Scope::Named { name, .. } => Some(name.as_str()),
+            nostr_lmdb::Scope::Default => None,
+        };
+        let quota = match (subdomain, self.config.max_events_per_scope) {
+            (Some(_), max) if max > 0 => Some((max.saturating_sub(self.quota.usage(&context.subdomain).events), max)),
+            _ => None,
+        };
+        let stats = ConnectionStats {
+            scope: scope_label(&context.subdomain),
+            connected_secs: state.connected_at.map(|at| now.duration_since(at).as_secs()).unwrap_or_default(),
+            accepted: state.event_counters.accepted,
+            rejected: state.event_counters.rejected,
+            rate_limited: state.event_counters.rate_limited,
+            events_per_minute: self.policy.rate_limit_for(&context.subdomain, &self.config),
+            quota,
+            subscriptions: state.subscriptions.len(),
+            max_subscriptions: self.config.max_subscriptions_per_connection,
+            authed_pubkey: context.authed_pubkey,
+        };
+        state.stats_notice = Some(PendingStatsNotice { event_id: event.id, notice: stats.to_string() });
+        // Not an event anyone else should see
+        state.pending_events.remove(&event.id);
+        Ok(Vec::new())
+    }
+    
+    /// Hands a NIP-62 request to vanish addressed to this relay to the
+    /// vanish task; the request itself isn't stored
+    fn request_vanish(&self, event: &Event, custom_state: &RwLock<ConnectionState>) -> Result<Vec<StoreCommand>, RejectReason> {
+        if !names_this_relay(event, &self.config) {
+            return Err(RejectReason::VanishElsewhere);
+        }
+        info!("{} asked to vanish with {}", event.pubkey, event.id);
+        metrics::counter!("relay_vanish_requests_total").increment(1);
+        self.vanished.record(event.pubkey, event.created_at.as_u64(), Timestamp::now().as_u64());
+        custom_state.write().pending_events.remove(&event.id);
+        Ok(Vec::new())
+    }
+    
+    /// Applies every write policy and picks the scope to store `event` in
+    async fn route_event(
+        &self,
+        event: Event,
+        custom_state: &RwLock<ConnectionState>,
+        context: &EventContext,
+    ) -> Result<Vec<StoreCommand>, RejectReason> {
         // Track events sent
-        state.events_sent += 1;
+        let client_ip = {
+            let mut state = custom_state.write();
+            state.events_sent += 1;
+            state.client_ip
+        };
         
         // Check for geohash tags and determine target scope
         let tags: Vec<Vec<String>> = event.tags.iter()
             .map(|tag| tag.clone().to_vec())
             .collect();
-        let geohash_tags = extract_geohash_tags(&tags);
+        let max_geohash_tags = self.config.max_geohash_tags_per_event;
+        let Some(geohash_tags) = extract_geohash_tags_capped(&tags, max_geohash_tags as usize) else {
+            return Err(RejectReason::TooManyGeohashTags { max: max_geohash_tags });
+        };
         
         // Extract the current subdomain name
         let current_subdomain = match context.subdomain.as_ref() {
@@ -62,91 +546,307 @@ impl EventProcessor<ConnectionState> for GeohashedEventProcessor {
             nostr_lmdb::Scope::Default => None,
         };
         
-        // If we're on a subdomain that's not a valid geohash, reject all events
-        if let Some(subdomain) = current_subdomain {
-            if !crate::geohash_utils::is_valid_geohash(subdomain) {
-                return Err(RelayError::restricted(format!(
-                    "restricted: '{}' is not a valid geohash subdomain",
-                    subdomain
-                )));
+        // The relay's own events skip every write policy and limit below,
+        // but still go where their g tag and scope put them. Kinds kept in
+        // root aren't moved, so a cell's own profile stays in the cell
+        if self.is_self_published(&event, custom_state, context) {
+            return match self.policy.route_event(&geohash_tags, &context.subdomain, &self.routing) {
+                ScopeDecision::Store(scope) => {
+                    if self.already_stored(&scope, &event, custom_state).await {
+                        return Ok(Vec::new());
+                    }
+                    event_debug!("Storing self-published event {} in scope {:?}", event.id, scope);
+                    Ok(vec![StoreCommand::SaveSignedEvent(Box::new(event), scope, None)])
+                }
+                ScopeDecision::Reject(reason) => Err(reason),
+            };
+        }
+        
+        if let Some(message) = self.maintenance.message() {
+            return Err(RejectReason::Maintenance { message });
+        }
+        
+        if self.maintenance.is_read_only() {
+            return Err(RejectReason::ReadOnly);
+        }
+        
+        if self.config.replicate_from.is_some() {
+            return Err(RejectReason::ReadOnlyReplica);
+        }
+        
+        // NIP-62: deleting frees space, so this goes ahead of the storage checks
+        if event.kind.as_u16() == REQUEST_TO_VANISH_KIND {
+            return self.request_vanish(&event, custom_state);
+        }
+        
+        if self.vanished.refuses(&event.pubkey, event.created_at.as_u64(), Timestamp::now().as_u64()) {
+            return Err(RejectReason::Vanished);
+        }
+        
+        if self.storage.is_read_only() {
+            return Err(RejectReason::StorageFull);
+        }
+        
+        if self.disk.is_read_only() {
+            return Err(RejectReason::StoragePressure);
+        }
+        
+        // Routing is decided up front; a subdomain the policy doesn't serve
+        // (by default, anything but a served geohash cell) rejects all
+        // events before any other policy applies
+        let connection_rules = custom_state.read().origin.as_ref().map(|origin| self.routing.for_origin(origin));
+        let rules = connection_rules.as_ref().unwrap_or(&self.routing);
+        if let ScopeClass::Refused(reason) = self.policy.classify_subdomain(&context.subdomain, rules) {
+            return Err(reason);
+        }
+        let decision = self.policy.route_event(&geohash_tags, &context.subdomain, rules);
+        
+        // NIP-26: a delegated event speaks for its delegator, if the
+        // delegation holds
+        let author = match delegator(&event) {
+            Ok(delegator) => delegator.unwrap_or(event.pubkey),
+            Err(e) => {
+                event_debug!("Rejecting event {}: {}", event.id, e);
+                return Err(RejectReason::BadDelegation);
+            }
+        };
+        
+        // Throwaway keys are outside everyone's follows
+        if !self.wot.allows(&author) {
+            event_debug!("Rejecting event {}: {} is not in the web of trust", event.id, author);
+            return Err(RejectReason::NotInWebOfTrust);
+        }
+        
+        // Some deployments tie posts to NIP-42 identities for moderation
+        if self.config.write_auth_for(current_subdomain) && context.authed_pubkey.is_none() {
+            return Err(RejectReason::AuthRequired { write: true });
+        }
+        
+        // Hellthreads: hundreds of mentions are spam and a notification bomb
+        if let Some(reason) = excess_tags(&tags, event.kind.as_u16(), &self.config) {
+            return Err(reason);
+        }
+        
+        // Operator content rules; the matched term is never echoed
+        if self.blocklist.is_blocked(current_subdomain.unwrap_or(ROOT_SCOPE_LABEL), &event.content) {
+            event_debug!("Rejecting event {}: content matches the blocklist", event.id);
+            return Err(RejectReason::ContentBlocked);
+        }
+        
+        // Scanners drop one event in each of many cells
+        if let (Some(ip), Some(cell)) = (client_ip, current_subdomain) {
+            if !self.cell_spread.admit(ip, cell) {
+                event_debug!("Rejecting event {}: {} wrote to too many cells", event.id, ip);
+                return Err(RejectReason::TooManyCells);
             }
         }
         
-        // Check if event has a geohash tag
-        if let Some(first_geohash) = geohash_tags.first() {
-            // Event has a geohash tag - check if we're on the correct subdomain
-            let is_correct_scope = match current_subdomain {
-                Some(subdomain) => subdomain == first_geohash,
-                None => false, // Root domain never accepts geotagged events
-            };
-            
-            if is_correct_scope {
-                // We're on the correct subdomain - store the event
-                info!(
-                    "Storing event {} with matching geohash '{}'",
+        // Paid scopes only take events from admitted authors
+        if self.config.write_policy_for(current_subdomain) == WritePolicy::Paid
+            && !self.admissions.is_admitted(&author)
+        {
+            return Err(RejectReason::PaymentRequired {
+                payments_url: self.config.payments_url.clone(),
+            });
+        }
+        
+        // Each scope type can be limited to the kinds it's meant for
+        if let Some(allowed) = self.config.allowed_kinds_for(current_subdomain) {
+            if !allowed.contains(&event.kind.as_u16()) {
+                return Err(RejectReason::KindNotAllowed {
+                    kind: event.kind.as_u16(),
+                    on_root: current_subdomain.is_none(),
+                    allowed: allowed.to_vec(),
+                    profile: current_subdomain
+                        .map(|_| self.config.geohash_profile)
+                        .filter(|profile| *profile != GeohashProfile::Custom),
+                });
+            }
+        }
+        
+        // Direct messages don't belong in public cells
+        if is_dm_kind(event.kind) {
+            match (self.config.dm_policy, current_subdomain) {
+                (DmPolicy::Allow, _) | (DmPolicy::RootOnly, None) => {}
+                (DmPolicy::RootOnly, Some(_)) => {
+                    return Err(RejectReason::DmRootOnly { kind: event.kind.as_u16() });
+                }
+                (DmPolicy::Reject, _) => {
+                    return Err(RejectReason::DmNotAccepted { kind: event.kind.as_u16() });
+                }
+            }
+        }
+        
+        // Profiles, contacts etc. describe people, not places: keep them in
+        // root so every cell can read them
+        if is_stored_in_root(event.kind, &self.config.global_kinds) {
+            event_debug!(
+                "Storing global kind {} event {} in root scope",
+                event.kind.as_u16(),
+                event.id
+            );
+            return self.save_in(event, nostr_lmdb::Scope::Default, custom_state).await;
+        }
+        
+        let decision = self.cap_precision(&event, decision, custom_state)?;
+        match decision {
+            ScopeDecision::Store(nostr_lmdb::Scope::Named { .. }) if !self.expires_within_ttl(&event) => {
+                event_debug!("Rejecting event {}: no expiration within the cell TTL", event.id);
+                Err(RejectReason::ExpirationRequired {
+                    max_days: self.config.geohash_max_ttl_days,
+                })
+            }
+            ScopeDecision::Store(scope) => {
+                event_debug!(
+                    "Storing event {} (geohash {:?}) in scope {:?}",
                     event.id,
-                    first_geohash
+                    geohash_tags.first(),
+                    scope
                 );
-                Ok(vec![StoreCommand::SaveSignedEvent(
-                    Box::new(event),
-                    (*context.subdomain).clone(),
-                    None,
-                )])
-            } else {
-                // Wrong subdomain - reject with helpful error message
-                let message = if current_subdomain.is_none() {
-                    format!(
-                        "restricted: root relay does not accept geotagged events; use wss://{}.hashstr.com",
-                        first_geohash
-                    )
-                } else {
-                    format!(
-                        "restricted: events with geohash '{}' must be posted to wss://{}.hashstr.com",
-                        first_geohash,
-                        first_geohash
-                    )
-                };
-                
-                info!(
-                    "Rejecting event {} with geohash '{}' (posted to {:?})",
+                self.save_in(event, scope, custom_state).await
+            }
+            ScopeDecision::Reject(reason) => {
+                event_debug!(
+                    "Rejecting event {} with geohash {:?} (posted to {:?}): {}",
                     event.id,
-                    first_geohash,
-                    context.subdomain
+                    geohash_tags.first(),
+                    context.subdomain,
+                    reason.code()
                 );
-                
-                Err(RelayError::restricted(message))
+                Err(reason)
             }
-        } else {
-            // No geohash tag - store in current scope
-            info!(
-                "Storing event {} without geohash in scope {:?}",
-                event.id,
-                context.subdomain
-            );
-            Ok(vec![StoreCommand::SaveSignedEvent(
-                Box::new(event),
-                (*context.subdomain).clone(),
-                None,
-            )])
         }
     }
+}
+
+impl Default for GeohashedEventProcessor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: ScopePolicy> EventProcessor<ConnectionState> for GeohashedEventProcessor<P> {
+    async fn handle_event(
+        &self,
+        event: Event,
+        custom_state: Arc<RwLock<ConnectionState>>,
+        context: &EventContext,
+    ) -> Result<Vec<StoreCommand>, RelayError> {
+        if self.config.stats_command
+            && is_stats_command(&event, self.config.stats_command_kind, &context.relay_pubkey)
+        {
+            let answer = self.answer_stats(&event, &custom_state, context);
+            custom_state.write().last_decision = decision_of(&answer);
+            return answer.map_err(|reason| self.reject(reason));
+        }
+        
+        // Everything the audit record needs is taken before the event moves
+        let audit = self.audit.is_enabled().then(|| {
+            AuditRecord::new(&event, scope_label(&context.subdomain), AuditDecision::Accepted, None)
+        });
+        let kind = event.kind.as_u16();
+        let mut result = self.route_event(event, &custom_state, context).await;
+        custom_state.write().last_decision = decision_of(&result);
+        if let Ok([StoreCommand::SaveSignedEvent(event, scope, _)]) = result.as_deref() {
+            // Storage would delete the targets for good, so the request
+            // only hides them and waits in its tombstone until purged
+            if event.kind == Kind::EventDeletion && self.tombstones.is_enabled() {
+                let hidden = match self.known.store() {
+                    Some(store) => tombstones::targets(store.as_ref(), scope, event).await.unwrap_or_else(|e| {
+                        warn!("Could not resolve the targets of deletion {}: {:#}", event.id, e);
+                        Vec::new()
+                    }),
+                    None => Vec::new(),
+                };
+                debug!("Deletion {} hides {} events in {:?}", event.id, hidden.len(), scope);
+                self.tombstones.record(scope, event, hidden, Timestamp::now().as_u64());
+                custom_state.write().pending_events.remove(&event.id);
+                result = Ok(Vec::new());
+            }
+        }
+        custom_state.write().event_counters.record(result.is_ok());
+        if let Ok(commands) = &result {
+            if let Some(StoreCommand::SaveSignedEvent(event, scope, _)) = commands.first() {
+                let now = Timestamp::now().as_u64();
+                self.first_seen.record(scope, event.id, now);
+                self.record_expiration(event, scope, now);
+                let scope_type = if matches!(scope, nostr_lmdb::Scope::Default) { "root" } else { "geohash" };
+                metrics::counter!("relay_events_accepted_total", "kind" => kind.to_string(), "scope_type" => scope_type)
+                    .increment(1);
+                self.activity.record_now(scope);
+                self.pow.record_in(scope, custom_state.read().scope_counters.as_deref());
+                self.log_summary.stored(scope);
+                // The relay's own events (like the usage report) aren't usage
+                if self.usage.is_enabled() && !custom_state.read().internal {
+                    self.usage.record_accepted(scope, event.as_json().len() as u64);
+                }
+            }
+        }
+        if let Err(reason) = &result {
+            self.usage.record_rejected(&context.subdomain, reason.code());
+            self.log_summary.rejected(&context.subdomain, reason.code());
+        }
+        if let Some(mut record) = audit {
+            match &result {
+                Ok(commands) => {
+                    if let Some(StoreCommand::SaveSignedEvent(_, scope, _)) = commands.first() {
+                        record.scope = scope_label(scope);
+                    }
+                }
+                Err(reason) => {
+                    record.decision = AuditDecision::Rejected;
+                    record.reason = Some(reason.code().to_string());
+                }
+            }
+            self.audit.record(record);
+        }
+        result.map_err(|reason| self.reject(reason))
+    }
     
     fn can_see_event(
         &self,
-        _event: &Event,
+        event: &Event,
         _custom_state: Arc<RwLock<ConnectionState>>,
-        _context: &EventContext,
+        context: &EventContext,
     ) -> Result<bool, RelayError> {
-        // Event is visible to all
-        Ok(true)
+        // Past its relay-side expiration, even before the sweeper runs
+        if self.expirations.is_expired(&event.id, Timestamp::now().as_u64()) {
+            return Ok(false);
+        }
+        // Hidden by a deletion in tombstone mode
+        if self.tombstones.is_hidden(&event.id) {
+            return Ok(false);
+        }
+        // NIP-40, for events answered before relay_builder's middleware sees them
+        if self.config.enable_nip40_expiration && event.is_expired() {
+            return Ok(false);
+        }
+        
+        Ok(self.policy.visibility(event, &context.subdomain, context.authed_pubkey))
     }
     
     fn verify_filters(
         &self,
         filters: &[Filter],
         _custom_state: Arc<RwLock<ConnectionState>>,
-        _context: &EventContext,
+        context: &EventContext,
     ) -> Result<(), RelayError> {
+        let subdomain = match context.subdomain.as_ref() {
+            nostr_lmdb::Scope::Named { name, .. } => Some(name.as_str()),
+            nostr_lmdb::Scope::Default => None,
+        };
+        if self.config.read_auth_for(subdomain) && context.authed_pubkey.is_none() {
+            return Err(RelayError::restricted(RejectReason::AuthRequired { write: false }.to_string()));
+        }
+        
+        // Bounded and well-formed #g values before anything resolves them;
+        // coordinates are answered on root by GeoFilterMiddleware, and
+        // malformed ones never reach storage on any scope
+        let limits = crate::geo_filter::GeohashLimits::for_config(&self.config);
+        if let Err(e) = crate::geo_filter::check_geohash_values(filters, limits) {
+            return Err(RelayError::restricted(RejectReason::BadGeohashFilter { detail: format!("{:#}", e) }.to_string()));
+        }
+        
         // Basic filter validation
         for filter in filters {
             // You can add custom filter validation here
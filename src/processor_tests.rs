@@ -52,6 +52,7 @@ mod tests {
             let error_msg = e.to_string();
             assert!(error_msg.contains("restricted"));
             assert!(error_msg.contains("root relay does not accept geotagged events"));
+            assert!(error_msg.contains("[root-rejects-geotagged]"));
             assert!(error_msg.contains("drt2z.hashstr.com"));
         }
     }
@@ -172,6 +173,7 @@ mod tests {
             let error_msg = e.to_string();
             assert!(error_msg.contains("restricted"));
             assert!(error_msg.contains("events with geohash 'drt2z' must be posted to wss://drt2z.hashstr.com"));
+            assert!(error_msg.contains("[wrong-scope]"));
         }
     }
     
@@ -193,6 +195,7 @@ mod tests {
         if let Err(e) = result {
             let error_msg = e.to_string();
             assert!(error_msg.contains("'foobar' is not a valid geohash subdomain"));
+            assert!(error_msg.contains("[invalid-subdomain]"));
         }
     }
 
@@ -233,4 +236,1112 @@ mod tests {
             _ => panic!("Expected SaveSignedEvent command"),
         }
     }
-}
\ No newline at end of file
+
+    fn processor_with_dm_policy(dm_policy: crate::config::DmPolicy) -> GeohashedEventProcessor {
+        GeohashedEventProcessor::with_config(Arc::new(crate::config::RelayConfig {
+            dm_policy,
+            ..Default::default()
+        }))
+    }
+
+    async fn create_dm(kind: Kind, recipient: PublicKey) -> Event {
+        EventBuilder::new(kind, "ciphertext")
+            .tags(vec![Tag::public_key(recipient)])
+            .sign(&Keys::generate())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_dm_policy_root_only() {
+        let processor = processor_with_dm_policy(crate::config::DmPolicy::RootOnly);
+        let recipient = Keys::generate().public_key();
+
+        for kind in [Kind::EncryptedDirectMessage, Kind::GiftWrap] {
+            let state = Arc::new(RwLock::new(ConnectionState::default()));
+            let root = create_test_context(nostr_lmdb::Scope::Default);
+            let result = processor.handle_event(create_dm(kind, recipient).await, state.clone(), &root).await;
+            assert!(result.is_ok(), "kind {} should be accepted on root", kind);
+
+            let cell = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+            let result = processor.handle_event(create_dm(kind, recipient).await, state, &cell).await;
+            let error_msg = result.unwrap_err().to_string();
+            assert!(error_msg.contains("restricted"));
+            assert!(error_msg.contains("only accepted on the root relay"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dm_policy_reject() {
+        let processor = processor_with_dm_policy(crate::config::DmPolicy::Reject);
+        let recipient = Keys::generate().public_key();
+
+        for scope in [nostr_lmdb::Scope::Default, nostr_lmdb::Scope::named("drt2z").unwrap()] {
+            for kind in [Kind::EncryptedDirectMessage, Kind::GiftWrap] {
+                let state = Arc::new(RwLock::new(ConnectionState::default()));
+                let context = create_test_context(scope.clone());
+                let result = processor.handle_event(create_dm(kind, recipient).await, state, &context).await;
+                assert!(result.unwrap_err().to_string().contains("not accepted by this relay"));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dm_policy_allow() {
+        let processor = processor_with_dm_policy(crate::config::DmPolicy::Allow);
+        let recipient = Keys::generate().public_key();
+
+        for scope in [nostr_lmdb::Scope::Default, nostr_lmdb::Scope::named("drt2z").unwrap()] {
+            for kind in [Kind::EncryptedDirectMessage, Kind::GiftWrap] {
+                let state = Arc::new(RwLock::new(ConnectionState::default()));
+                let context = create_test_context(scope.clone());
+                let result = processor.handle_event(create_dm(kind, recipient).await, state, &context).await;
+                assert!(result.is_ok());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dm_visibility_limited_to_participants_when_authed() {
+        let processor = create_test_processor();
+        let recipient = Keys::generate();
+        let stranger = Keys::generate();
+        let dm = create_dm(Kind::GiftWrap, recipient.public_key()).await;
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+
+        let mut context = create_test_context(nostr_lmdb::Scope::Default);
+        assert!(processor.can_see_event(&dm, state.clone(), &context).unwrap());
+
+        context.authed_pubkey = Some(recipient.public_key());
+        assert!(processor.can_see_event(&dm, state.clone(), &context).unwrap());
+
+        context.authed_pubkey = Some(dm.pubkey);
+        assert!(processor.can_see_event(&dm, state.clone(), &context).unwrap());
+
+        context.authed_pubkey = Some(stranger.public_key());
+        assert!(!processor.can_see_event(&dm, state.clone(), &context).unwrap());
+
+        // Other kinds are unaffected
+        let note = create_event_without_geohash().await;
+        assert!(processor.can_see_event(&note, state, &context).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_global_kinds_stored_in_root_from_cell() {
+        let processor = create_test_processor();
+        let keys = Keys::generate();
+        let profile = EventBuilder::new(Kind::Metadata, r#"{"name":"alice"}"#)
+            .sign(&keys)
+            .await
+            .unwrap();
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let context = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+
+        let commands = processor.handle_event(profile, state, &context).await.unwrap();
+        match &commands[0] {
+            StoreCommand::SaveSignedEvent(_, scope, _) => {
+                assert_eq!(*scope, nostr_lmdb::Scope::Default);
+            }
+            _ => panic!("Expected SaveSignedEvent command"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_global_kinds_disabled_keeps_isolation() {
+        let processor = GeohashedEventProcessor::with_config(Arc::new(crate::config::RelayConfig {
+            global_kinds: vec![],
+            ..Default::default()
+        }));
+        let keys = Keys::generate();
+        let contacts = EventBuilder::new(Kind::ContactList, "")
+            .sign(&keys)
+            .await
+            .unwrap();
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let context = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+
+        let commands = processor.handle_event(contacts, state, &context).await.unwrap();
+        match &commands[0] {
+            StoreCommand::SaveSignedEvent(_, scope, _) => {
+                assert_eq!(*scope, nostr_lmdb::Scope::named("drt2z").unwrap());
+            }
+            _ => panic!("Expected SaveSignedEvent command"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_only_storage_rejects_events() {
+        let storage = Arc::new(crate::storage::StorageMonitor::disabled());
+        let processor = create_test_processor().with_storage(storage.clone());
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let context = create_test_context(nostr_lmdb::Scope::Default);
+
+        let event = create_event_without_geohash().await;
+        assert!(processor.handle_event(event, state.clone(), &context).await.is_ok());
+
+        storage.enter_read_only("test");
+        let event = create_event_without_geohash().await;
+        let err = processor.handle_event(event, state, &context).await.unwrap_err();
+        assert!(err.to_string().contains("relay storage full"));
+    }
+
+    #[tokio::test]
+    async fn test_disk_pressure_rejects_events_until_cleared() {
+        let config = crate::config::RelayConfig {
+            disk_watermark_percent: 90,
+            ..Default::default()
+        };
+        let disk = Arc::new(crate::storage::DiskWatermark::with_provider(&config, crate::storage::FsDiskUsage));
+        let processor = create_test_processor().with_disk_watermark(disk.clone());
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let context = create_test_context(nostr_lmdb::Scope::Default);
+
+        disk.observe(95);
+        let event = create_event_without_geohash().await;
+        let err = processor.handle_event(event, state.clone(), &context).await.unwrap_err();
+        assert!(err.to_string().contains("temporarily read-only (storage pressure)"));
+
+        disk.observe(50);
+        let event = create_event_without_geohash().await;
+        assert!(processor.handle_event(event, state, &context).await.is_ok());
+    }
+
+    fn paid_root_processor(admissions: Arc<crate::admissions::AdmissionList>) -> GeohashedEventProcessor {
+        GeohashedEventProcessor::with_config(Arc::new(crate::config::RelayConfig {
+            root_write_policy: crate::config::WritePolicy::Paid,
+            payments_url: Some("https://pay.example.com".to_string()),
+            ..Default::default()
+        }))
+        .with_admissions(admissions)
+    }
+
+    #[tokio::test]
+    async fn test_paid_scope_rejects_unadmitted_authors() {
+        let processor = paid_root_processor(Arc::new(crate::admissions::AdmissionList::in_memory()));
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let context = create_test_context(nostr_lmdb::Scope::Default);
+
+        let event = create_event_without_geohash().await;
+        let err = processor.handle_event(event, state, &context).await.unwrap_err();
+        assert!(err.to_string().contains("restricted: payment required, see https://pay.example.com [payment-required]"));
+    }
+
+    #[tokio::test]
+    async fn test_paid_scope_accepts_admitted_authors() {
+        let admissions = Arc::new(crate::admissions::AdmissionList::in_memory());
+        let processor = paid_root_processor(admissions.clone());
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let context = create_test_context(nostr_lmdb::Scope::Default);
+
+        let keys = Keys::generate();
+        admissions.update(&[keys.public_key()], &[]).unwrap();
+        let event = EventBuilder::text_note("paid").sign(&keys).await.unwrap();
+        assert!(processor.handle_event(event, state, &context).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_free_scopes_ignore_admissions() {
+        // Cells stay free while root is paid
+        let processor = paid_root_processor(Arc::new(crate::admissions::AdmissionList::in_memory()));
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let context = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+        let event = create_event_with_geohash("drt2z").await;
+        assert!(processor.handle_event(event, state.clone(), &context).await.is_ok());
+
+        // And with no paid policy at all nothing is checked
+        let context = create_test_context(nostr_lmdb::Scope::Default);
+        let event = create_event_without_geohash().await;
+        assert!(create_test_processor().handle_event(event, state, &context).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_allowed_kinds_depend_on_scope_type() {
+        let processor = GeohashedEventProcessor::with_config(Arc::new(crate::config::RelayConfig {
+            root_allowed_kinds: Some(vec![0, 3, 10002]),
+            geohash_allowed_kinds: Some(vec![1, 20000]),
+            global_kinds: vec![],
+            ..Default::default()
+        }));
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let root = create_test_context(nostr_lmdb::Scope::Default);
+        let cell = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+        let keys = Keys::generate();
+
+        // A text note belongs in a cell, not in root
+        let note = EventBuilder::text_note("hi").sign(&keys).await.unwrap();
+        assert!(processor.handle_event(note.clone(), state.clone(), &cell).await.is_ok());
+        let err = processor.handle_event(note, state.clone(), &root).await.unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("kind 1 is not accepted on the root relay"));
+        assert!(message.contains("allowed kinds: 0, 3, 10002"));
+
+        // A relay list is the other way round
+        let relay_list = EventBuilder::new(Kind::RelayList, "").sign(&keys).await.unwrap();
+        assert!(processor.handle_event(relay_list.clone(), state.clone(), &root).await.is_ok());
+        let err = processor.handle_event(relay_list, state, &cell).await.unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("kind 10002 is not accepted on geohash cells"));
+        assert!(message.contains("allowed kinds: 1, 20000"));
+    }
+
+    #[tokio::test]
+    async fn test_full_cell_rejects_events() {
+        let config = Arc::new(crate::config::RelayConfig {
+            max_events_per_scope: 1,
+            ..Default::default()
+        });
+        let processor = GeohashedEventProcessor::with_config(config);
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let context = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+
+        let event = create_event_with_geohash("drt2z").await;
+        assert!(processor.handle_event(event, state.clone(), &context).await.is_ok());
+
+        let event = create_event_without_geohash().await;
+        let err = processor.handle_event(event, state.clone(), &context).await.unwrap_err();
+        assert!(err.to_string().contains("this geohash cell is full"));
+
+        // Root isn't subject to cell quotas
+        let root = create_test_context(nostr_lmdb::Scope::Default);
+        let event = create_event_without_geohash().await;
+        assert!(processor.handle_event(event, state, &root).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_records_decisions_and_reasons() {
+        use crate::audit::{search, AuditDecision, AuditLog, AuditQuery};
+
+        let dir = tempfile::tempdir().unwrap();
+        let audit = AuditLog::open(dir.path(), 0, crate::config::AuditFsync::Never).unwrap();
+        let processor = create_test_processor().with_audit(audit);
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+
+        let accepted = create_event_with_geohash("drt2z").await;
+        let context = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+        assert!(processor.handle_event(accepted.clone(), state.clone(), &context).await.is_ok());
+        let wrong_cell = create_event_with_geohash("9q8yy").await;
+        assert!(processor.handle_event(wrong_cell.clone(), state.clone(), &context).await.is_err());
+        let geotagged_at_root = create_event_with_geohash("drt2z").await;
+        let root = create_test_context(nostr_lmdb::Scope::Default);
+        assert!(processor.handle_event(geotagged_at_root.clone(), state, &root).await.is_err());
+
+        // The writer thread catches up asynchronously
+        let mut records = Vec::new();
+        for _ in 0..100 {
+            records.clear();
+            search(dir.path(), &AuditQuery::default(), |record| records.push(record.clone())).unwrap();
+            if records.len() == 3 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(records.len(), 3);
+
+        assert_eq!(records[0].event_id, accepted.id.to_hex());
+        assert_eq!(records[0].decision, AuditDecision::Accepted);
+        assert_eq!(records[0].scope, "drt2z");
+        assert_eq!(records[0].reason, None);
+
+        assert_eq!(records[1].event_id, wrong_cell.id.to_hex());
+        assert_eq!(records[1].decision, AuditDecision::Rejected);
+        assert_eq!(records[1].reason.as_deref(), Some("wrong-scope"));
+        assert_eq!(records[1].pubkey, wrong_cell.pubkey.to_hex());
+
+        assert_eq!(records[2].scope, "root");
+        assert_eq!(records[2].reason.as_deref(), Some("root-rejects-geotagged"));
+    }
+
+    async fn event_with_p_tags(kind: Kind, count: usize) -> Event {
+        let tags: Vec<Tag> = (0..count).map(|_| Tag::public_key(Keys::generate().public_key())).collect();
+        EventBuilder::new(kind, "").tags(tags).sign(&Keys::generate()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_hellthread_rejected() {
+        let processor = create_test_processor();
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let context = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+
+        let at_limit = event_with_p_tags(Kind::TextNote, 50).await;
+        assert!(processor.handle_event(at_limit, state.clone(), &context).await.is_ok());
+
+        let hellthread = event_with_p_tags(Kind::TextNote, 51).await;
+        let err = processor.handle_event(hellthread, state, &context).await.unwrap_err();
+        assert!(err.to_string().contains("invalid: too many p tags (max 50)"));
+    }
+
+    #[tokio::test]
+    async fn test_contact_lists_exempt_from_tag_limits() {
+        let processor = create_test_processor();
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let root = create_test_context(nostr_lmdb::Scope::Default);
+
+        let contacts = event_with_p_tags(Kind::ContactList, 500).await;
+        assert!(processor.handle_event(contacts, state, &root).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_per_kind_tag_limit_override() {
+        let processor = GeohashedEventProcessor::with_config(Arc::new(crate::config::RelayConfig {
+            max_tag_entries_per_letter: 5,
+            kind_tag_limits: std::collections::HashMap::from([(1, 100), (7, 2)]),
+            ..Default::default()
+        }));
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let context = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+
+        // Kind 1 may mention more people than the default allows
+        let note = event_with_p_tags(Kind::TextNote, 80).await;
+        assert!(processor.handle_event(note, state.clone(), &context).await.is_ok());
+
+        // Kind 7 is held to fewer
+        let reaction = event_with_p_tags(Kind::Reaction, 3).await;
+        let err = processor.handle_event(reaction, state.clone(), &context).await.unwrap_err();
+        assert!(err.to_string().contains("too many p tags (max 2)"));
+
+        // Other letters fall back to the general limit
+        let hashtags: Vec<Tag> = (0..6).map(|i| Tag::hashtag(format!("tag{}", i))).collect();
+        let tagged = EventBuilder::new(Kind::from(1111), "").tags(hashtags).sign(&Keys::generate()).await.unwrap();
+        let err = processor.handle_event(tagged, state, &context).await.unwrap_err();
+        assert!(err.to_string().contains("too many t tags (max 5)"));
+    }
+
+    #[tokio::test]
+    async fn test_insufficient_pow_rejected() {
+        let processor = GeohashedEventProcessor::with_config(Arc::new(crate::config::RelayConfig {
+            pow_min_difficulty: 8,
+            ..Default::default()
+        }));
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let context = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+        let keys = Keys::generate();
+
+        // Regenerate until the id is unlucky enough to need mining
+        let plain = loop {
+            let event = EventBuilder::text_note("no work").sign(&keys).await.unwrap();
+            if crate::pow::leading_zero_bits(&event.id) < 8 {
+                break event;
+            }
+        };
+        let err = processor.handle_event(plain, state.clone(), &context).await.unwrap_err();
+        assert!(err.to_string().contains("pow: current difficulty for drt2z is 8 bits"));
+
+        let mined = EventBuilder::text_note("mined").pow(8).sign(&keys).await.unwrap();
+        assert!(processor.handle_event(mined, state, &context).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_content_blocked() {
+        let processor = GeohashedEventProcessor::with_config(Arc::new(crate::config::RelayConfig {
+            duplicate_window_secs: 600,
+            ..Default::default()
+        }));
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let context = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+
+        for _ in 0..3 {
+            let spam = EventBuilder::text_note("Claim your free sats now").sign(&Keys::generate()).await.unwrap();
+            assert!(processor.handle_event(spam, state.clone(), &context).await.is_ok());
+        }
+        let spam = EventBuilder::text_note("Claim your free sats now").sign(&Keys::generate()).await.unwrap();
+        let err = processor.handle_event(spam, state, &context).await.unwrap_err();
+        assert!(err.to_string().contains("blocked: duplicate content detected"));
+    }
+
+    #[tokio::test]
+    async fn test_auth_policy_per_scope_type() {
+        let drt2z = nostr_lmdb::Scope::named("drt2z").unwrap();
+        let keys = Keys::generate();
+        // (root_write_auth, geohash_write_auth, geohash_read_auth)
+        for (root_write, geohash_write, geohash_read) in
+            [(false, false, false), (true, false, false), (false, true, false), (false, false, true), (true, true, true)]
+        {
+            let processor = GeohashedEventProcessor::with_config(Arc::new(crate::config::RelayConfig {
+                root_write_auth: root_write,
+                geohash_write_auth: geohash_write,
+                geohash_read_auth: geohash_read,
+                ..Default::default()
+            }));
+            let state = Arc::new(RwLock::new(ConnectionState::default()));
+            let combination = format!("root_write={} geohash_write={} geohash_read={}", root_write, geohash_write, geohash_read);
+
+            for (scope, write_required, read_required) in
+                [(nostr_lmdb::Scope::Default, root_write, false), (drt2z.clone(), geohash_write, geohash_read)]
+            {
+                let mut context = create_test_context(scope);
+
+                let note = EventBuilder::text_note("hello").sign(&keys).await.unwrap();
+                let written = processor.handle_event(note, state.clone(), &context).await;
+                let read = processor.verify_filters(&[Filter::new().kind(Kind::TextNote)], state.clone(), &context);
+                assert_eq!(written.is_err(), write_required, "{}", combination);
+                assert_eq!(read.is_err(), read_required, "{}", combination);
+                if let Err(e) = written {
+                    assert!(e.to_string().contains("auth-required: authenticate to post to this scope"));
+                }
+                if let Err(e) = read {
+                    assert!(e.to_string().contains("auth-required: authenticate to read this scope"));
+                }
+
+                // Authenticated connections get through every combination
+                context.authed_pubkey = Some(keys.public_key());
+                let note = EventBuilder::text_note("hello again").sign(&keys).await.unwrap();
+                assert!(processor.handle_event(note, state.clone(), &context).await.is_ok(), "{}", combination);
+                assert!(
+                    processor.verify_filters(&[Filter::new().kind(Kind::TextNote)], state.clone(), &context).is_ok(),
+                    "{}",
+                    combination
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_location_chat_profile_rejects_other_kinds_on_cells() {
+        let mut config = crate::config::RelayConfig {
+            geohash_profile: crate::config::GeohashProfile::LocationChat,
+            ..Default::default()
+        };
+        config.apply_geohash_profile();
+        let processor = GeohashedEventProcessor::with_config(Arc::new(config));
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let cell = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+        let keys = Keys::generate();
+
+        let chat = EventBuilder::new(Kind::from(20000), "hi").sign(&keys).await.unwrap();
+        assert!(processor.handle_event(chat, state.clone(), &cell).await.is_ok());
+
+        // Profiles are routed on to root
+        let profile = EventBuilder::metadata(&Metadata::new().name("alice")).sign(&keys).await.unwrap();
+        match &processor.handle_event(profile, state.clone(), &cell).await.unwrap()[0] {
+            StoreCommand::SaveSignedEvent(_, scope, _) => assert_eq!(*scope, nostr_lmdb::Scope::Default),
+            _ => panic!("Expected SaveSignedEvent"),
+        }
+
+        let article = EventBuilder::new(Kind::LongFormTextNote, "essay").sign(&keys).await.unwrap();
+        let err = processor.handle_event(article, state.clone(), &cell).await.unwrap_err();
+        assert!(err.to_string().contains("kind 30023 is not accepted on geohash cells"));
+        assert!(err.to_string().contains("under the location-chat profile"));
+
+        // Root is unaffected
+        let root = create_test_context(nostr_lmdb::Scope::Default);
+        let article = EventBuilder::new(Kind::LongFormTextNote, "essay").sign(&keys).await.unwrap();
+        assert!(processor.handle_event(article, state, &root).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_events_leave_connection_scope_alone() {
+        let processor = create_test_processor();
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        state.write().connected(&nostr_lmdb::Scope::Default, None, None);
+
+        // Posting a geotagged event from root doesn't repoint the connection
+        let event = create_event_with_geohash("drt2z").await;
+        let _ = processor.handle_event(event, state.clone(), &create_test_context(nostr_lmdb::Scope::Default)).await;
+        assert_eq!(state.read().subdomain_info, None);
+        assert_eq!(state.read().events_sent, 1);
+    }
+
+    #[tokio::test]
+    async fn test_cell_scan_from_one_address_rejected() {
+        let processor = GeohashedEventProcessor::with_config(Arc::new(crate::config::RelayConfig {
+            max_cells_per_ip_per_hour: 3,
+            ..Default::default()
+        }));
+        let addr: std::net::SocketAddr = "198.51.100.23:40000".parse().unwrap();
+        let cells = ["9xj64", "9xj65", "9xj66", "9xj67"];
+
+        for (i, cell) in cells.iter().enumerate() {
+            let scope = nostr_lmdb::Scope::named(cell).unwrap();
+            let state = Arc::new(RwLock::new(ConnectionState::default()));
+            state.write().connected(&scope, Some(addr), None);
+            let result = processor.handle_event(create_event_without_geohash().await, state, &create_test_context(scope)).await;
+            if i < 3 {
+                assert!(result.is_ok());
+            } else {
+                assert!(result.unwrap_err().to_string().contains("[too-many-cells]"));
+            }
+        }
+
+        // A cell already written to stays open
+        let scope = nostr_lmdb::Scope::named("9xj64").unwrap();
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        state.write().connected(&scope, Some(addr), None);
+        let result = processor.handle_event(create_event_without_geohash().await, state, &create_test_context(scope)).await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_strict_ttl_requires_expiration_on_cells() {
+        let ttl_config = |mode| {
+            Arc::new(crate::config::RelayConfig {
+                geohash_max_ttl_days: 7,
+                geohash_ttl_mode: mode,
+                geohash_ttl_exempt_kinds: vec![30023],
+                ..Default::default()
+            })
+        };
+        let processor = GeohashedEventProcessor::with_config(ttl_config(crate::config::TtlMode::Strict));
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let cell = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+        let keys = Keys::generate();
+        let note_expiring_in = |secs: Option<u64>| {
+            let mut builder = EventBuilder::text_note("coffee at the square?");
+            if let Some(secs) = secs {
+                builder = builder.tag(Tag::expiration(Timestamp::from(Timestamp::now().as_u64() + secs)));
+            }
+            builder.sign(&keys)
+        };
+
+        let err = processor.handle_event(note_expiring_in(None).await.unwrap(), state.clone(), &cell).await.unwrap_err();
+        assert!(err.to_string().contains("[expiration-required]"));
+        let err = processor
+            .handle_event(note_expiring_in(Some(30 * 86400)).await.unwrap(), state.clone(), &cell)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("at most 7 days ahead"));
+        let event = note_expiring_in(Some(86400)).await.unwrap();
+        assert!(processor.handle_event(event, state.clone(), &cell).await.is_ok());
+
+        // Exempt kinds and root don't need one
+        let article = EventBuilder::new(Kind::LongFormTextNote, "essay").sign(&keys).await.unwrap();
+        assert!(processor.handle_event(article, state.clone(), &cell).await.is_ok());
+        let root = create_test_context(nostr_lmdb::Scope::Default);
+        assert!(processor.handle_event(note_expiring_in(None).await.unwrap(), state.clone(), &root).await.is_ok());
+
+        // Lenient mode leaves it to the retention sweeper
+        let processor = GeohashedEventProcessor::with_config(ttl_config(crate::config::TtlMode::Lenient));
+        assert!(processor.handle_event(note_expiring_in(None).await.unwrap(), state, &cell).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_stats_command_answers_without_storing() {
+        let processor = GeohashedEventProcessor::with_config(Arc::new(crate::config::RelayConfig {
+            stats_command: true,
+            events_per_minute: 30,
+            ..Default::default()
+        }));
+        let drt2z = nostr_lmdb::Scope::named("drt2z").unwrap();
+        let context = create_test_context(drt2z.clone());
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        state.write().connected(&drt2z, None, None);
+        let keys = Keys::generate();
+        let command = || {
+            EventBuilder::new(Kind::from(21059), "stats")
+                .tag(Tag::public_key(context.relay_pubkey))
+                .sign(&keys)
+        };
+
+        // One accepted, one rejected event first
+        assert!(processor.handle_event(create_event_without_geohash().await, state.clone(), &context).await.is_ok());
+        let elsewhere = create_event_with_geohash("9q8yy").await;
+        assert!(processor.handle_event(elsewhere, state.clone(), &context).await.is_err());
+
+        let event = command().await.unwrap();
+        let commands = processor.handle_event(event.clone(), state.clone(), &context).await.unwrap();
+        assert!(commands.is_empty());
+        let pending = state.read().stats_notice.clone().expect("stats notice queued");
+        assert_eq!(pending.event_id, event.id);
+        assert!(pending.notice.starts_with("stats: scope drt2z, connected 0s"));
+        assert!(pending.notice.contains("last minute 1 accepted, 1 rejected, 0 rate-limited"));
+        assert!(pending.notice.contains("limit 30 events/min per scope"));
+        assert!(pending.notice.contains("0 of 20 subscriptions open"));
+        assert!(pending.notice.ends_with("not authenticated"));
+
+        // Answers are rate limited per connection
+        let err = processor.handle_event(command().await.unwrap(), state.clone(), &context).await.unwrap_err();
+        assert!(err.to_string().contains("[stats-too-soon]"));
+
+        // Off by default: the same event is an ordinary event
+        let processor = create_test_processor();
+        let commands = processor.handle_event(command().await.unwrap(), state, &context).await.unwrap();
+        assert_eq!(commands.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_suggested_url_follows_connection_host() {
+        let processor = GeohashedEventProcessor::with_config(Arc::new(crate::config::RelayConfig {
+            relay_url: "wss://relay.example.com".to_string(),
+            alternate_base_domains: vec!["relay.corp.internal".to_string()],
+            ..Default::default()
+        }));
+        let origin = |domain: Option<&str>, secure: Option<bool>| crate::host_parsing::ConnectionOrigin {
+            base_domain: domain.map(str::to_string),
+            secure,
+            ..Default::default()
+        };
+        let cases = [
+            (Some(origin(Some("relay.corp.internal"), Some(false))), "ws://drt2z.relay.corp.internal"),
+            (Some(origin(Some("relay.example.com"), None)), "wss://drt2z.relay.example.com"),
+            // No trusted Host: the configured domain
+            (Some(origin(None, None)), "wss://drt2z.relay.example.com"),
+            (None, "wss://drt2z.relay.example.com"),
+        ];
+
+        for (origin, expected) in cases {
+            let state = Arc::new(RwLock::new(ConnectionState::default()));
+            state.write().origin = origin;
+            let event = create_event_with_geohash("drt2z").await;
+            let root = create_test_context(nostr_lmdb::Scope::Default);
+            let err = processor.handle_event(event, state, &root).await.unwrap_err();
+            assert!(err.to_string().contains(expected), "{} should suggest {}", err, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deletions_stay_in_connection_scope_even_if_global() {
+        let processor = GeohashedEventProcessor::with_config(Arc::new(crate::config::RelayConfig {
+            global_kinds: vec![0, 3, 5, 10002],
+            ..Default::default()
+        }));
+        let keys = Keys::generate();
+        let coordinate = Coordinate::new(Kind::LongFormTextNote, keys.public_key()).identifier("meetup");
+        let deletion = EventBuilder::delete(EventDeletionRequest::new().coordinate(coordinate))
+            .sign(&keys)
+            .await
+            .unwrap();
+
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let cell = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+        match &processor.handle_event(deletion, state, &cell).await.unwrap()[0] {
+            StoreCommand::SaveSignedEvent(_, scope, _) => {
+                assert_eq!(*scope, nostr_lmdb::Scope::named("drt2z").unwrap())
+            }
+            _ => panic!("Expected SaveSignedEvent"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_already_stored_event_is_not_saved_again() {
+        let store = Arc::new(crate::store::MemoryStore::new());
+        let processor = create_test_processor().with_store(store.clone());
+        let drt2z = nostr_lmdb::Scope::named("drt2z").unwrap();
+        let context = create_test_context(drt2z.clone());
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let event = create_event_with_geohash("drt2z").await;
+
+        let commands = processor.handle_event(event.clone(), state.clone(), &context).await.unwrap();
+        assert_eq!(commands.len(), 1);
+        assert!(!state.write().duplicates.remove(&event.id));
+
+        store.insert(&drt2z, event.clone());
+        let commands = processor.handle_event(event.clone(), state.clone(), &context).await.unwrap();
+        assert!(commands.is_empty());
+        assert!(state.write().duplicates.remove(&event.id));
+
+        // Known in one cell says nothing about another
+        let context = create_test_context(nostr_lmdb::Scope::named("9q8yy").unwrap());
+        let elsewhere = create_event_with_geohash("9q8yy").await;
+        store.insert(&drt2z, elsewhere.clone());
+        let commands = processor.handle_event(elsewhere, state, &context).await.unwrap();
+        assert_eq!(commands.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_resend_at_quota_leaves_usage_alone() {
+        let config = Arc::new(crate::config::RelayConfig {
+            max_events_per_scope: 1,
+            quota_policy: crate::config::QuotaPolicy::Reject,
+            ..Default::default()
+        });
+        let store = Arc::new(crate::store::MemoryStore::new());
+        let quota = Arc::new(crate::quota::ScopeQuota::new(&config));
+        let processor = GeohashedEventProcessor::with_config(config).with_store(store.clone()).with_quota(quota.clone());
+        let drt2z = nostr_lmdb::Scope::named("drt2z").unwrap();
+        let context = create_test_context(drt2z.clone());
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let event = create_event_with_geohash("drt2z").await;
+
+        assert_eq!(processor.handle_event(event.clone(), state.clone(), &context).await.unwrap().len(), 1);
+        store.insert(&drt2z, event.clone());
+        let usage = quota.usage(&drt2z);
+        assert_eq!(usage.events, 1);
+
+        // The cell is full, but the resend is a duplicate, not a new write
+        let commands = processor.handle_event(event.clone(), state.clone(), &context).await.unwrap();
+        assert!(commands.is_empty());
+        assert!(state.write().duplicates.remove(&event.id));
+        assert_eq!(quota.usage(&drt2z), usage);
+    }
+
+    #[tokio::test]
+    async fn test_tombstone_mode_hides_instead_of_deleting() {
+        let store = Arc::new(crate::store::MemoryStore::new());
+        let tombstones = Arc::new(crate::tombstones::Tombstones::in_memory());
+        let processor = create_test_processor().with_store(store.clone()).with_tombstones(tombstones.clone());
+        let drt2z = nostr_lmdb::Scope::named("drt2z").unwrap();
+        let context = create_test_context(drt2z.clone());
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let keys = Keys::generate();
+        let note = EventBuilder::text_note("oops")
+            .tag(Tag::custom(TagKind::Custom("g".into()), ["drt2z"]))
+            .sign(&keys)
+            .await
+            .unwrap();
+        store.insert(&drt2z, note.clone());
+        assert!(processor.can_see_event(&note, state.clone(), &context).unwrap());
+
+        let deletion = EventBuilder::delete(EventDeletionRequest::new().id(note.id)).sign(&keys).await.unwrap();
+        let commands = processor.handle_event(deletion.clone(), state.clone(), &context).await.unwrap();
+        assert!(commands.is_empty(), "the request never reaches storage");
+        assert_eq!(tombstones.hidden_by(&note.id), Some(deletion.id));
+        assert!(!processor.can_see_event(&note, state.clone(), &context).unwrap());
+
+        tombstones.restore(&deletion.id).unwrap();
+        assert!(processor.can_see_event(&note, state, &context).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_delegated_events_count_as_their_delegator() {
+        let admissions = Arc::new(crate::admissions::AdmissionList::in_memory());
+        let processor = paid_root_processor(admissions.clone());
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let context = create_test_context(nostr_lmdb::Scope::Default);
+        let venue = Keys::generate();
+        let poster = Keys::generate();
+        admissions.update(&[venue.public_key()], &[]).unwrap();
+
+        let delegated = |conditions: &str| {
+            let token = venue.sign_schnorr(&crate::delegation::token_message(&poster.public_key(), conditions));
+            EventBuilder::text_note("tonight at the venue")
+                .tag(Tag::parse(["delegation", &venue.public_key().to_hex(), conditions, &token.to_string()]).unwrap())
+                .sign_with_keys(&poster)
+                .unwrap()
+        };
+
+        // The poster isn't admitted, the venue is
+        let event = delegated("kind=1");
+        assert!(processor.handle_event(event, state.clone(), &context).await.is_ok());
+
+        let event = delegated("kind=7");
+        let err = processor.handle_event(event, state, &context).await.unwrap_err();
+        assert!(err.to_string().contains("invalid: bad delegation [bad-delegation]"));
+    }
+
+    #[tokio::test]
+    async fn test_web_of_trust_gates_writes() {
+        let seed = Keys::generate();
+        let processor = create_test_processor()
+            .with_wot(Arc::new(crate::wot::WebOfTrust::new(vec![seed.public_key()], 1)));
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let context = create_test_context(nostr_lmdb::Scope::Default);
+
+        let trusted = EventBuilder::text_note("from the seed").sign(&seed).await.unwrap();
+        assert!(processor.handle_event(trusted, state.clone(), &context).await.is_ok());
+
+        let event = create_event_without_geohash().await;
+        let err = processor.handle_event(event, state, &context).await.unwrap_err();
+        assert!(err.to_string().contains("restricted: not in relay web of trust [not-in-wot]"));
+    }
+
+    #[tokio::test]
+    async fn test_default_expiration_is_recorded_not_signed() {
+        let expirations = Arc::new(crate::expirations::Expirations::in_memory());
+        let processor = GeohashedEventProcessor::with_config(Arc::new(crate::config::RelayConfig {
+            default_expiration_secs: 3_600,
+            ..Default::default()
+        }))
+        .with_expirations(expirations.clone());
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let cell = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+        let keys = Keys::generate();
+
+        // No tag: the relay sets one, the stored event stays as signed
+        let before = Timestamp::now().as_u64();
+        let untagged = EventBuilder::text_note("gone in an hour").sign(&keys).await.unwrap();
+        let commands = processor.handle_event(untagged.clone(), state.clone(), &cell).await.unwrap();
+        assert!(matches!(&commands[..], [StoreCommand::SaveSignedEvent(stored, _, _)] if **stored == untagged));
+        let expires_at = expirations.expires_at(&untagged.id).unwrap();
+        assert!((before + 3_600..=Timestamp::now().as_u64() + 3_600).contains(&expires_at));
+        assert!(processor.can_see_event(&untagged, state.clone(), &cell).unwrap());
+
+        // A shorter client expiration stands, and is noted for the sweeper
+        let expiration = Timestamp::now().as_u64() + 60;
+        let short = EventBuilder::text_note("gone in a minute")
+            .tag(Tag::expiration(Timestamp::from(expiration)))
+            .sign(&keys)
+            .await
+            .unwrap();
+        assert!(processor.handle_event(short.clone(), state.clone(), &cell).await.is_ok());
+        assert_eq!(expirations.expires_at(&short.id), Some(expiration));
+
+        // Once the effective expiration passes the event is hidden
+        expirations.record(&nostr_lmdb::Scope::named("drt2z").unwrap(), untagged.id, before - 1);
+        assert!(!processor.can_see_event(&untagged, state, &cell).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_allowed_precisions_gate_subdomains_and_tags() {
+        let config = |mode| crate::config::RelayConfig {
+            allowed_precisions: vec![5],
+            tag_precision_mode: mode,
+            ..Default::default()
+        };
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+
+        let processor = GeohashedEventProcessor::with_config(Arc::new(config(crate::config::TagPrecisionMode::Reject)));
+        let coarse = create_test_context(nostr_lmdb::Scope::named("drt2").unwrap());
+        let err = processor.handle_event(create_event_without_geohash().await, state.clone(), &coarse).await.unwrap_err();
+        assert!(err.to_string().contains("only serves precision 5"), "{}", err);
+
+        let cell = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+        let fine = create_event_with_geohash("drt2zby").await;
+        let err = processor.handle_event(fine.clone(), state.clone(), &cell).await.unwrap_err();
+        assert!(err.to_string().contains("precision-not-allowed"), "{}", err);
+
+        // Adjusting truncates the tag to the cell it falls in
+        let processor = GeohashedEventProcessor::with_config(Arc::new(config(crate::config::TagPrecisionMode::Adjust)));
+        let commands = processor.handle_event(fine, state, &cell).await.unwrap();
+        assert!(matches!(&commands[..], [StoreCommand::SaveSignedEvent(_, scope, _)] if *scope == nostr_lmdb::Scope::named("drt2z").unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_geo_filter_rejected() {
+        let processor = GeohashedEventProcessor::with_config(Arc::new(crate::config::RelayConfig {
+            geo_filter_extension: true,
+            ..Default::default()
+        }));
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let root = create_test_context(nostr_lmdb::Scope::Default);
+        let g = SingleLetterTag::lowercase(Alphabet::G);
+
+        let good = vec![Filter::new().custom_tag(g, "latlon:37.77,-122.41")];
+        assert!(processor.verify_filters(&good, state.clone(), &root).is_ok());
+
+        let bad = vec![Filter::new().custom_tag(g, "latlon:37.77")];
+        let err = processor.verify_filters(&bad, state.clone(), &root).unwrap_err();
+        assert!(err.to_string().contains("latlon:<lat>,<lon>[,neighbors]"), "{}", err);
+
+        // Off: it isn't a geohash either
+        let err = create_test_processor().verify_filters(&bad, state, &root).unwrap_err();
+        assert!(err.to_string().contains("is not a geohash"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_geohash_values_capped_per_filter() {
+        let processor = create_test_processor();
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let context = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+        let g = SingleLetterTag::lowercase(Alphabet::G);
+        let cells = |n: usize| {
+            let values: Vec<String> = crate::geohash_utils::children("drt2").unwrap().into_iter().take(n).collect();
+            vec![Filter::new().custom_tags(g, values)]
+        };
+
+        assert!(processor.verify_filters(&cells(16), state.clone(), &context).is_ok());
+        let err = processor.verify_filters(&cells(17), state.clone(), &context).unwrap_err();
+        assert!(err.to_string().starts_with("invalid:"), "{}", err);
+        assert!(err.to_string().contains("max_geohashes_per_filter is 16"), "{}", err);
+        assert_eq!(crate::reject::reason_code(&err.to_string()), Some("bad-geohash-filter"));
+
+        let wildcard = vec![Filter::new().custom_tag(g, "*")];
+        assert!(processor.verify_filters(&wildcard, state, &context).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_self_published_events_skip_policy_but_not_routing() {
+        let processor = GeohashedEventProcessor::with_config(Arc::new(crate::config::RelayConfig {
+            geohash_allowed_kinds: Some(vec![20000]),
+            ..Default::default()
+        }));
+        let relay_keys = Keys::generate();
+        let drt2z = nostr_lmdb::Scope::named("drt2z").unwrap();
+        let announcement = EventBuilder::text_note("relay maintenance tonight").sign(&relay_keys).await.unwrap();
+
+        let commands = processor
+            .publish_internal(announcement.clone(), relay_keys.public_key(), drt2z.clone())
+            .await
+            .unwrap();
+        assert!(matches!(&commands[..], [StoreCommand::SaveSignedEvent(_, scope, _)] if *scope == drt2z));
+
+        // Routing still applies: a g tag for another cell is refused
+        let misrouted = EventBuilder::text_note("elsewhere")
+            .tag(Tag::custom(TagKind::Custom("g".into()), vec!["9q8yy".to_string()]))
+            .sign(&relay_keys)
+            .await
+            .unwrap();
+        let err = processor.publish_internal(misrouted, relay_keys.public_key(), drt2z.clone()).await.unwrap_err();
+        assert!(err.to_string().contains("[wrong-scope]"), "{}", err);
+
+        // The same relay-signed event from a client connection gets no bypass
+        let context = EventContext {
+            relay_pubkey: relay_keys.public_key(),
+            subdomain: Arc::new(drt2z),
+            authed_pubkey: None,
+        };
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let err = processor.handle_event(announcement, state, &context).await.unwrap_err();
+        assert!(err.to_string().contains("[kind-not-allowed]"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_geohash_tag_cap_in_both_ttl_modes() {
+        let g_tags = |count: usize| -> Vec<Tag> {
+            (0..count).map(|_| Tag::custom(TagKind::Custom("g".into()), vec!["drt2z".to_string()])).collect()
+        };
+        let context = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+        let keys = Keys::generate();
+
+        for mode in [crate::config::TtlMode::Lenient, crate::config::TtlMode::Strict] {
+            let processor = GeohashedEventProcessor::with_config(Arc::new(crate::config::RelayConfig {
+                geohash_max_ttl_days: 1,
+                geohash_ttl_mode: mode,
+                ..Default::default()
+            }));
+            let state = Arc::new(RwLock::new(ConnectionState::default()));
+            let expiration = Tag::expiration(Timestamp::from(Timestamp::now().as_u64() + 3_600));
+
+            let at_cap = EventBuilder::text_note("four").tags(g_tags(4)).tag(expiration.clone()).sign(&keys).await.unwrap();
+            assert!(processor.handle_event(at_cap, state.clone(), &context).await.is_ok(), "{:?}", mode);
+
+            // Over the cap is reported before anything else about the event
+            let over = EventBuilder::text_note("five").tags(g_tags(5)).sign(&keys).await.unwrap();
+            let err = processor.handle_event(over, state, &context).await.unwrap_err();
+            assert!(err.to_string().contains("invalid: too many geohash tags (max 4)"), "{:?}: {}", mode, err);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_preloaded_scope_first_write_is_warm() {
+        use crate::config::PreloadScopes;
+        use crate::scope_residency::ScopeResidency;
+
+        let store = crate::store::MemoryStore::new();
+        let residency = Arc::new(ScopeResidency::disabled());
+        residency
+            .preload_all(&store, &PreloadScopes::Listed(vec!["drt2z".to_string()]))
+            .await
+            .unwrap();
+        let processor = create_test_processor().with_residency(residency.clone());
+
+        let context = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        processor.handle_event(create_event_with_geohash("drt2z").await, state, &context).await.unwrap();
+        assert_eq!(residency.cold_opens(), 0);
+
+        let context = create_test_context(nostr_lmdb::Scope::named("9q8yy").unwrap());
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        processor.handle_event(create_event_with_geohash("9q8yy").await, state, &context).await.unwrap();
+        assert_eq!(residency.cold_opens(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_decision_is_left_in_connection_state() {
+        use crate::routing::ScopeDecision;
+
+        let processor = create_test_processor();
+        let context = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+
+        processor.handle_event(create_event_with_geohash("drt2z").await, state.clone(), &context).await.unwrap();
+        assert_eq!(
+            state.read().last_decision,
+            Some(ScopeDecision::Store(nostr_lmdb::Scope::named("drt2z").unwrap()))
+        );
+
+        // The next EVENT's decision replaces it, carrying the typed reason
+        processor.handle_event(create_event_with_geohash("9q8yy").await, state.clone(), &context).await.unwrap_err();
+        let decision = state.read().last_decision.clone();
+        let Some(ScopeDecision::Reject(reason)) = decision else {
+            panic!("expected a rejection, got {:?}", decision);
+        };
+        assert_eq!(reason.code(), "wrong-scope");
+    }
+
+    #[tokio::test]
+    async fn test_request_to_vanish_clears_every_scope() {
+        use crate::vanish::{sweep, VanishList, ALL_RELAYS, REQUEST_TO_VANISH_KIND};
+
+        let store = crate::store::MemoryStore::new();
+        let (leaving, staying) = (Keys::generate(), Keys::generate());
+        let scopes = [
+            nostr_lmdb::Scope::Default,
+            nostr_lmdb::Scope::named("drt2z").unwrap(),
+            nostr_lmdb::Scope::named("9q8yy").unwrap(),
+        ];
+        let earlier = Timestamp::from(Timestamp::now().as_u64() - 60);
+        for scope in &scopes {
+            for keys in [&leaving, &staying] {
+                let note = EventBuilder::text_note("here").custom_created_at(earlier).sign(keys).await.unwrap();
+                store.insert(scope, note);
+            }
+        }
+        let vanished = Arc::new(VanishList::in_memory(0));
+        let processor = create_test_processor().with_vanished(vanished.clone());
+        let context = create_test_context(nostr_lmdb::Scope::named("drt2z").unwrap());
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+
+        // Addressed to some other relay: refused, nothing recorded
+        let elsewhere = EventBuilder::new(Kind::from(REQUEST_TO_VANISH_KIND), "")
+            .tag(Tag::parse(["relay", "wss://relay.example.com"]).unwrap())
+            .sign(&leaving)
+            .await
+            .unwrap();
+        let err = processor.handle_event(elsewhere, state.clone(), &context).await.unwrap_err();
+        assert!(err.to_string().contains("[vanish-elsewhere]"), "{}", err);
+        assert!(vanished.pending().is_empty());
+
+        let request = EventBuilder::new(Kind::from(REQUEST_TO_VANISH_KIND), "")
+            .tag(Tag::parse(["relay", ALL_RELAYS]).unwrap())
+            .sign(&leaving)
+            .await
+            .unwrap();
+        let commands = processor.handle_event(request, state.clone(), &context).await.unwrap();
+        assert!(commands.is_empty());
+        assert_eq!(sweep(&store, &vanished).await, 3);
+        for scope in &scopes {
+            let remaining = store.query(scope, Filter::new()).await.unwrap();
+            assert_eq!(remaining.len(), 1, "{:?}", scope);
+            assert_eq!(remaining[0].pubkey, staying.public_key());
+        }
+
+        // The deleted events can't be published again
+        let replayed = EventBuilder::text_note("here").custom_created_at(earlier).sign(&leaving).await.unwrap();
+        let err = processor.handle_event(replayed, state, &context).await.unwrap_err();
+        assert!(err.to_string().contains("[vanished]"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_kind_precision_caps() {
+        let config = |policy| crate::config::RelayConfig {
+            kind_precision_caps: BTreeMap::from([(1, 4)]),
+            precision_cap_policy: policy,
+            ..Default::default()
+        };
+        let state = Arc::new(RwLock::new(ConnectionState::default()));
+        let fine = create_test_context(nostr_lmdb::Scope::named("drt2zb").unwrap());
+        let event = create_event_with_geohash("drt2zb").await;
+
+        let processor = GeohashedEventProcessor::with_config(Arc::new(config(PrecisionCapPolicy::Reject)));
+        let err = processor.handle_event(event.clone(), state.clone(), &fine).await.unwrap_err();
+        assert!(err.to_string().contains("finer than precision 4; post to 'drt2'"), "{}", err);
+        assert!(err.to_string().contains("[precision-capped]"), "{}", err);
+
+        // Already at the cap, and uncapped kinds, go through as usual
+        let at_cap = create_test_context(nostr_lmdb::Scope::named("drt2").unwrap());
+        let commands = processor.handle_event(create_event_with_geohash("drt2").await, state.clone(), &at_cap).await.unwrap();
+        assert!(matches!(&commands[..], [StoreCommand::SaveSignedEvent(_, scope, _)] if *scope == nostr_lmdb::Scope::named("drt2").unwrap()));
+        let reaction = EventBuilder::new(Kind::Reaction, "+")
+            .tag(Tag::custom(TagKind::Custom("g".into()), ["drt2zb"]))
+            .sign(&Keys::generate())
+            .await
+            .unwrap();
+        assert!(processor.handle_event(reaction, state.clone(), &fine).await.is_ok());
+
+        // Truncating stores in the parent cell and leaves the OK message
+        let processor = GeohashedEventProcessor::with_config(Arc::new(config(PrecisionCapPolicy::Truncate)));
+        let commands = processor.handle_event(event.clone(), state.clone(), &fine).await.unwrap();
+        assert!(matches!(&commands[..], [StoreCommand::SaveSignedEvent(_, scope, _)] if *scope == nostr_lmdb::Scope::named("drt2").unwrap()));
+        assert_eq!(
+            state.write().truncations.remove(&event.id).as_deref(),
+            Some("truncated: stored in drt2 (kind 1 is capped at precision 4)")
+        );
+
+        // A cap at an unserved precision has no cell to move to
+        let processor = GeohashedEventProcessor::with_config(Arc::new(crate::config::RelayConfig {
+            allowed_precisions: vec![6],
+            ..config(PrecisionCapPolicy::Truncate)
+        }));
+        let err = processor.handle_event(event, state, &fine).await.unwrap_err();
+        assert!(err.to_string().contains("[precision-capped]"), "{}", err);
+    }
+}
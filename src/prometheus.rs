@@ -0,0 +1,30 @@
+//! Prometheus exposition of the relay's `metrics`
+//!
+//! Counters, gauges and histograms are recorded through the `metrics`
+//! macros throughout the crate; they go nowhere until `install` sets the
+//! global recorder. `/metrics` renders what it has collected.
+
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use std::sync::OnceLock;
+use tracing::warn;
+
+static HANDLE: OnceLock<Option<PrometheusHandle>> = OnceLock::new();
+
+/// Installs the global recorder; later calls are no-ops
+///
+/// Only the first recorder installed in a process takes effect, so this
+/// logs and leaves `/metrics` empty when something else installed one.
+pub fn install() {
+    HANDLE.get_or_init(|| match PrometheusBuilder::new().install_recorder() {
+        Ok(handle) => Some(handle),
+        Err(e) => {
+            warn!("Failed to install the metrics recorder: {}", e);
+            None
+        }
+    });
+}
+
+/// Prometheus text for everything recorded since `install`, empty before
+pub fn render() -> String {
+    HANDLE.get().and_then(Option::as_ref).map(PrometheusHandle::render).unwrap_or_default()
+}
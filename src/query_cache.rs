@@ -0,0 +1,401 @@
+//! Result cache for repeated REQs
+//!
+//! Kiosk-style clients in a busy cell poll with the same REQ every few
+//! seconds. With `query_cache_size` set, `QueryCacheMiddleware` answers a
+//! REQ's stored events from `QueryCache`, keyed by scope and the normalized
+//! filter set, then forwards it to relay_builder with every filter's limit
+//! at 0, so the subscription still goes live without a second LMDB scan.
+//!
+//! An entry is dropped when any event is stored in its scope (via
+//! `LiveEvents`) or after `query_cache_ttl_secs`. REQs whose filters could
+//! match direct messages bypass the cache, since who may see those depends
+//! on the connection. `ReadChecks` runs the processor's `verify_filters`
+//! before a REQ is answered from the cache, and `can_see_event` on every
+//! event served, so a warm cache skips none of the read auth, visibility, expiration and
+//! tombstone checks a stored query gets.
+
+use anyhow::Result;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use relay_builder::{InboundContext, InboundProcessor, NostrMiddleware};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+use crate::config::RelayConfig;
+use crate::live::LiveEvents;
+use crate::processor::ConnectionState;
+use crate::read_checks::ReadChecks;
+use crate::scope_policy::{DefaultScopePolicy, ScopePolicy};
+use crate::scope_residency::ScopeResources;
+use crate::store::ScopeStore;
+
+struct CachedResult {
+    events: Arc<Vec<Event>>,
+    stored_at: Instant,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<Scope, HashMap<String, CachedResult>>,
+    len: usize,
+    /// Bumped on invalidation so queries racing a write aren't cached
+    generations: HashMap<Scope, u64>,
+}
+
+/// Stored events per scope and filter set
+pub struct QueryCache {
+    capacity: usize,
+    ttl: Duration,
+    max_limit: usize,
+    state: Mutex<CacheState>,
+}
+
+/// Cache key for `filters`: the same filters in any order give the same key
+fn normalize(filters: &[Filter]) -> String {
+    let mut parts: Vec<String> = filters.iter().map(|filter| filter.as_json()).collect();
+    parts.sort();
+    parts.dedup();
+    parts.join("\n")
+}
+
+/// Whether `filters` can be served from the cache
+///
+/// Every filter must name its kinds, and none of them may be a DM kind.
+pub fn cacheable(filters: &[Filter]) -> bool {
+    !filters.is_empty()
+        && filters.iter().all(|filter| match &filter.kinds {
+            Some(kinds) => kinds
+                .iter()
+                .all(|kind| *kind != Kind::EncryptedDirectMessage && *kind != Kind::GiftWrap),
+            None => false,
+        })
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize, ttl: Duration, max_limit: usize) -> Self {
+        Self {
+            capacity,
+            ttl,
+            max_limit,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    pub fn for_config(config: &RelayConfig) -> Self {
+        Self::new(
+            config.query_cache_size,
+            Duration::from_secs(config.query_cache_ttl_secs),
+            config.max_limit_per_filter,
+        )
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Events matching any of `filters` in `scope`, newest first
+    ///
+    /// Queries `store` only on a miss.
+    pub async fn query(&self, store: &dyn ScopeStore, scope: &Scope, filters: &[Filter]) -> Result<Arc<Vec<Event>>> {
+        let key = normalize(filters);
+        let generation = {
+            let mut state = self.state.lock();
+            let fresh = state
+                .entries
+                .get(scope)
+                .and_then(|entries| entries.get(&key))
+                .filter(|cached| cached.stored_at.elapsed() < self.ttl)
+                .map(|cached| cached.events.clone());
+            if let Some(events) = fresh {
+                metrics::counter!("relay_query_cache_hits_total").increment(1);
+                return Ok(events);
+            }
+            state.generations.get(scope).copied().unwrap_or_default()
+        };
+        metrics::counter!("relay_query_cache_misses_total").increment(1);
+
+        let events = Arc::new(self.query_store(store, scope, filters).await?);
+        let mut state = self.state.lock();
+        if state.generations.get(scope).copied().unwrap_or_default() == generation {
+            self.insert(&mut state, scope, key, events.clone());
+        }
+        Ok(events)
+    }
+
+    async fn query_store(&self, store: &dyn ScopeStore, scope: &Scope, filters: &[Filter]) -> Result<Vec<Event>> {
+        let mut seen = HashSet::new();
+        let mut events = Vec::new();
+        for filter in filters {
+            // relay_builder clamps limits the same way
+            let mut filter = filter.clone();
+            filter.limit = Some(filter.limit.map_or(self.max_limit, |limit| limit.min(self.max_limit)));
+            for event in store.query(scope, filter).await? {
+                if seen.insert(event.id) {
+                    events.push(event);
+                }
+            }
+        }
+        events.sort_by(|a, b| b.created_at.cmp(&a.created_at).then_with(|| a.id.cmp(&b.id)));
+        Ok(events)
+    }
+
+    fn insert(&self, state: &mut CacheState, scope: &Scope, key: String, events: Arc<Vec<Event>>) {
+        let replaced = state
+            .entries
+            .entry(scope.clone())
+            .or_default()
+            .insert(key, CachedResult { events, stored_at: Instant::now() });
+        if replaced.is_none() {
+            state.len += 1;
+        }
+        while state.len > self.capacity {
+            // Drop the oldest entry; the cache is small enough to scan
+            let oldest = state
+                .entries
+                .iter()
+                .flat_map(|(scope, entries)| entries.iter().map(move |(key, cached)| (scope, key, cached.stored_at)))
+                .min_by_key(|(_, _, stored_at)| *stored_at)
+                .map(|(scope, key, _)| (scope.clone(), key.clone()));
+            let Some((scope, key)) = oldest else { break };
+            if let Some(entries) = state.entries.get_mut(&scope) {
+                entries.remove(&key);
+                if entries.is_empty() {
+                    state.entries.remove(&scope);
+                }
+            }
+            state.len -= 1;
+        }
+    }
+
+    /// Drops every cached result for `scope`
+    pub fn invalidate(&self, scope: &Scope) {
+        let mut state = self.state.lock();
+        *state.generations.entry(scope.clone()).or_default() += 1;
+        if let Some(entries) = state.entries.remove(scope) {
+            state.len -= entries.len();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl ScopeResources for QueryCache {
+    fn release(&self, scope: &Scope) {
+        self.invalidate(scope);
+    }
+}
+
+/// Invalidates cached results as events are stored
+pub fn spawn_invalidation(cache: Arc<QueryCache>, live: &LiveEvents) {
+    let mut receiver = live.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(stored) => cache.invalidate(&stored.scope),
+                Err(RecvError::Lagged(skipped)) => {
+                    // Can't tell which scopes changed, so start over
+                    warn!("Query cache missed {} stored events, clearing it", skipped);
+                    let scopes: Vec<Scope> = cache.state.lock().entries.keys().cloned().collect();
+                    for scope in scopes {
+                        cache.invalidate(&scope);
+                    }
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Serves REQs' stored events from the query cache
+#[derive(Clone)]
+pub struct QueryCacheMiddleware<P = DefaultScopePolicy> {
+    cache: Arc<QueryCache>,
+    store: Arc<dyn ScopeStore>,
+    /// Runs the read checks relay_builder would have run on each event
+    read_checks: ReadChecks<P>,
+}
+
+impl<P: ScopePolicy + Clone> QueryCacheMiddleware<P> {
+    pub fn new(cache: Arc<QueryCache>, store: Arc<dyn ScopeStore>, read_checks: ReadChecks<P>) -> Self {
+        Self { cache, store, read_checks }
+    }
+}
+
+impl<P: ScopePolicy + Clone> NostrMiddleware<ConnectionState> for QueryCacheMiddleware<P> {
+    async fn process_inbound<Next>(&self, mut ctx: InboundContext<'_, ConnectionState, Next>) -> Result<(), anyhow::Error>
+    where
+        Next: InboundProcessor<ConnectionState>,
+    {
+        if !self.cache.is_enabled() {
+            return ctx.next().await;
+        }
+        let request = match &ctx.message {
+            Some(ClientMessage::Req { subscription_id, filters }) if cacheable(filters) => {
+                Some((SubscriptionId::clone(subscription_id), filters.clone()))
+            }
+            _ => None,
+        };
+        let Some((subscription_id, filters)) = request else {
+            return ctx.next().await;
+        };
+
+        let reader = {
+            let state = ctx.state.read();
+            self.read_checks.reader(state.subdomain.clone(), state.authed_pubkey, &state.custom)
+        };
+        // Read auth and malformed filters are relay_builder's to refuse
+        if reader.verify(&filters).is_err() {
+            return ctx.next().await;
+        }
+
+        match self.cache.query(self.store.as_ref(), reader.scope(), &filters).await {
+            Ok(events) => {
+                debug!("Serving {} cached events to {}", events.len(), subscription_id);
+                for event in events.iter() {
+                    // Visibility, expirations and tombstones, as for a stored query
+                    if !reader.can_see(event) {
+                        continue;
+                    }
+                    ctx.send_message(RelayMessage::event(subscription_id.clone(), event.clone()))?;
+                }
+                // Stored events are sent; relay_builder only opens the live subscription
+                if let Some(ClientMessage::Req { filters, .. }) = ctx.message.as_mut() {
+                    for filter in filters.iter_mut() {
+                        filter.limit = Some(0);
+                    }
+                }
+            }
+            Err(e) => warn!("Query cache lookup failed for {}, querying directly: {}", subscription_id, e),
+        }
+        ctx.next().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use futures::future::BoxFuture;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// `MemoryStore` that counts queries
+    #[derive(Default)]
+    struct CountingStore {
+        inner: MemoryStore,
+        queries: AtomicUsize,
+    }
+
+    impl ScopeStore for CountingStore {
+        fn query(&self, scope: &Scope, filter: Filter) -> BoxFuture<'_, Result<Vec<Event>>> {
+            self.queries.fetch_add(1, Ordering::SeqCst);
+            self.inner.query(scope, filter)
+        }
+
+        fn count(&self, scope: &Scope, filter: Filter) -> BoxFuture<'_, Result<usize>> {
+            self.inner.count(scope, filter)
+        }
+
+        fn scopes(&self) -> BoxFuture<'_, Result<Vec<Scope>>> {
+            self.inner.scopes()
+        }
+
+        fn save(&self, scope: &Scope, event: Event) -> BoxFuture<'_, Result<()>> {
+            self.inner.save(scope, event)
+        }
+
+        fn delete(&self, scope: &Scope, id: EventId) -> BoxFuture<'_, Result<()>> {
+            self.inner.delete(scope, id)
+        }
+    }
+
+    async fn note(keys: &Keys, content: &str) -> Event {
+        EventBuilder::new(Kind::from(20000), content).sign(keys).await.unwrap()
+    }
+
+    fn cache(capacity: usize) -> QueryCache {
+        QueryCache::new(capacity, Duration::from_secs(60), 500)
+    }
+
+    #[tokio::test]
+    async fn test_identical_queries_hit_the_store_once() {
+        let store = CountingStore::default();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let keys = Keys::generate();
+        store.inner.insert(&drt2z, note(&keys, "first").await);
+        let cache = cache(10);
+        let filters = vec![Filter::new().kind(Kind::from(20000)).limit(50)];
+
+        assert_eq!(cache.query(&store, &drt2z, &filters).await.unwrap().len(), 1);
+        assert_eq!(cache.query(&store, &drt2z, &filters).await.unwrap().len(), 1);
+        assert_eq!(store.queries.load(Ordering::SeqCst), 1);
+
+        // Other scopes have their own entries
+        cache.query(&store, &Scope::named("9q8yy").unwrap(), &filters).await.unwrap();
+        assert_eq!(store.queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_new_event_invalidates_the_scope() {
+        let store = CountingStore::default();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let keys = Keys::generate();
+        store.inner.insert(&drt2z, note(&keys, "first").await);
+        let cache = Arc::new(cache(10));
+        let live = LiveEvents::new();
+        spawn_invalidation(cache.clone(), &live);
+        let filters = vec![Filter::new().kind(Kind::from(20000)).limit(50)];
+        cache.query(&store, &drt2z, &filters).await.unwrap();
+
+        let second = note(&keys, "second").await;
+        store.inner.insert(&drt2z, second.clone());
+        live.publish(crate::live::StoredEvent { scope: drt2z.clone(), event: second });
+        for _ in 0..100 {
+            if cache.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let events = cache.query(&store, &drt2z, &filters).await.unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(store.queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_size_cap_and_ttl() {
+        let store = CountingStore::default();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let cache = cache(2);
+        for kind in [20000, 20001, 20002] {
+            cache.query(&store, &drt2z, &[Filter::new().kind(Kind::from(kind))]).await.unwrap();
+        }
+        assert_eq!(cache.len(), 2);
+
+        let expiring = QueryCache::new(10, Duration::ZERO, 500);
+        let filters = [Filter::new().kind(Kind::from(20000))];
+        expiring.query(&store, &drt2z, &filters).await.unwrap();
+        expiring.query(&store, &drt2z, &filters).await.unwrap();
+        assert_eq!(store.queries.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn test_cacheable_filters() {
+        assert!(cacheable(&[Filter::new().kind(Kind::from(20000)).limit(50)]));
+        assert!(!cacheable(&[]));
+        assert!(!cacheable(&[Filter::new().limit(50)]));
+        assert!(!cacheable(&[Filter::new().kinds([Kind::TextNote, Kind::GiftWrap])]));
+        assert_eq!(
+            normalize(&[Filter::new().kind(Kind::TextNote), Filter::new().kind(Kind::Metadata)]),
+            normalize(&[Filter::new().kind(Kind::Metadata), Filter::new().kind(Kind::TextNote)])
+        );
+    }
+}
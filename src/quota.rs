@@ -0,0 +1,412 @@
+//! Per-cell stored-event quotas
+//!
+//! Keeps one busy geohash cell from eating the whole LMDB map. Usage is
+//! cached per scope: `handle_event` bumps it on every accepted write and a
+//! background task periodically recounts it from the store. Once a cell is
+//! at `max_events_per_scope` or `max_bytes_per_scope`, writes are either
+//! refused with `RejectReason::ScopeFull` or accepted while the
+//! cell's oldest events are deleted, depending on `quota_policy`. Reads are
+//! never affected. Root is only bounded by the storage monitor.
+//!
+//! Under `archive`, evicted events are uploaded by an `Archiver` before they
+//! are deleted, and a failed upload leaves them in place. Archiving cells are
+//! evicted down to `ARCHIVE_LOW_WATERMARK` of their quota so each upload is a
+//! reasonably sized batch rather than one object per write.
+
+use anyhow::Result;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::{Mutex, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+use crate::archive::Archiver;
+use crate::config::{QuotaPolicy, RelayConfig};
+use crate::store::{scope_label, ScopeStore};
+use crate::store_admin::{self, PAGE_SIZE};
+
+/// Share of the quota an archiving cell is evicted down to, in percent
+const ARCHIVE_LOW_WATERMARK: u64 = 90;
+
+/// Stored events and approximate bytes (serialized JSON) in one scope
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScopeUsage {
+    pub events: u64,
+    pub bytes: u64,
+}
+
+/// Cached per-scope usage and the configured limits
+#[derive(Debug)]
+pub struct ScopeQuota {
+    /// 0 disables the event limit
+    max_events: u64,
+    /// 0 disables the byte limit
+    max_bytes: u64,
+    policy: QuotaPolicy,
+    usage: RwLock<HashMap<Scope, ScopeUsage>>,
+    /// Cells waiting for the eviction task
+    pending_evictions: Mutex<HashSet<Scope>>,
+    evict: Notify,
+    /// Where evicted events go first under `QuotaPolicy::Archive`
+    archiver: Option<Archiver>,
+}
+
+impl ScopeQuota {
+    pub fn new(config: &RelayConfig) -> Self {
+        Self {
+            max_events: config.max_events_per_scope,
+            max_bytes: config.max_bytes_per_scope,
+            policy: config.quota_policy,
+            usage: RwLock::new(HashMap::new()),
+            pending_evictions: Mutex::new(HashSet::new()),
+            evict: Notify::new(),
+            archiver: None,
+        }
+    }
+
+    pub fn with_archiver(mut self, archiver: Archiver) -> Self {
+        self.archiver = Some(archiver);
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_events > 0 || self.max_bytes > 0
+    }
+
+    pub fn usage(&self, scope: &Scope) -> ScopeUsage {
+        self.usage.read().get(scope).copied().unwrap_or_default()
+    }
+
+    /// Whether `usage` leaves no room for another `bytes`-sized event
+    fn is_full(&self, usage: &ScopeUsage, bytes: u64) -> bool {
+        (self.max_events > 0 && usage.events >= self.max_events)
+            || (self.max_bytes > 0 && usage.bytes + bytes > self.max_bytes)
+    }
+
+    fn is_over(&self, usage: &ScopeUsage) -> bool {
+        exceeds(usage, &ScopeUsage { events: self.max_events, bytes: self.max_bytes })
+    }
+
+    /// Usage eviction brings an over-quota cell back down to
+    fn eviction_target(&self) -> ScopeUsage {
+        let scale = |limit: u64| match self.policy {
+            QuotaPolicy::Archive if limit > 0 => (limit * ARCHIVE_LOW_WATERMARK / 100).max(1),
+            _ => limit,
+        };
+        ScopeUsage { events: scale(self.max_events), bytes: scale(self.max_bytes) }
+    }
+
+    /// Accounts for a write of `bytes` into `scope`, false if it must be refused
+    ///
+    /// Under `evict-oldest` and `archive` a full cell still accepts the write and is
+    /// queued for eviction instead.
+    pub fn admit(&self, scope: &Scope, bytes: u64) -> bool {
+        if !self.is_enabled() || *scope == Scope::Default {
+            return true;
+        }
+        let mut usage = self.usage.write();
+        let entry = usage.entry(scope.clone()).or_default();
+        let full = self.is_full(entry, bytes);
+        if full && self.policy == QuotaPolicy::Reject {
+            metrics::counter!("relay_quota_rejected_events_total").increment(1);
+            return false;
+        }
+        entry.events += 1;
+        entry.bytes += bytes;
+        drop(usage);
+
+        if full {
+            self.pending_evictions.lock().insert(scope.clone());
+            self.evict.notify_one();
+        }
+        true
+    }
+
+    /// Replaces the cached usage with a fresh count
+    pub fn replace(&self, usage: HashMap<Scope, ScopeUsage>) {
+        let at_quota = usage.values().filter(|u| self.is_full(u, 0)).count();
+        metrics::gauge!("relay_scopes_at_quota").set(at_quota as f64);
+        *self.usage.write() = usage;
+    }
+
+    /// Recounts every named scope from the store
+    pub async fn refresh(&self, store: &dyn ScopeStore) -> Result<()> {
+        let mut usage = HashMap::new();
+        for scope in store.scopes().await? {
+            if scope == Scope::Default {
+                continue;
+            }
+            let events = store.count(&scope, Filter::new()).await? as u64;
+            // Byte totals need a full scan, so only pay for it when limited
+            let mut bytes = 0;
+            if self.max_bytes > 0 {
+                store_admin::for_each_event(store, &scope, PAGE_SIZE, |event| {
+                    bytes += event.as_json().len() as u64;
+                    Ok(())
+                })
+                .await?;
+            }
+            usage.insert(scope, ScopeUsage { events, bytes });
+        }
+        self.replace(usage);
+        Ok(())
+    }
+
+    /// Deletes a cell's oldest events until it is back within its quota
+    ///
+    /// The event that triggered the eviction may still be in flight, so a
+    /// cell can sit one event over quota until its next write or refresh.
+    /// With an archiver, each batch is uploaded before it is deleted; if the
+    /// upload fails nothing more is deleted and the error is returned.
+    pub async fn evict_oldest(&self, store: &dyn ScopeStore, scope: &Scope) -> Result<u64> {
+        let mut usage = ScopeUsage {
+            events: store.count(scope, Filter::new()).await? as u64,
+            bytes: self.usage(scope).bytes,
+        };
+        let target = self.eviction_target();
+        let mut evicted = 0;
+        while self.is_over(&usage) || (evicted > 0 && exceeds(&usage, &target)) {
+            let oldest = store_admin::oldest_first(store, scope, batch_size(&usage, &target)).await?;
+            if oldest.is_empty() {
+                break;
+            }
+            let mut victims = Vec::new();
+            for event in oldest {
+                if !exceeds(&usage, &target) {
+                    break;
+                }
+                usage.events = usage.events.saturating_sub(1);
+                usage.bytes = usage.bytes.saturating_sub(event.as_json().len() as u64);
+                victims.push(event);
+            }
+            if let Some(archiver) = &self.archiver {
+                archiver.archive(scope, &victims).await?;
+            }
+            for event in &victims {
+                store.delete(scope, event.id).await?;
+            }
+            evicted += victims.len() as u64;
+        }
+        if evicted > 0 {
+            debug!("Evicted {} events from {}", evicted, scope_label(scope));
+            metrics::counter!("relay_quota_evicted_events_total").increment(evicted);
+        }
+        self.usage.write().insert(scope.clone(), usage);
+        Ok(evicted)
+    }
+
+    /// Evicts every cell queued by `admit`
+    async fn evict_pending(&self, store: &dyn ScopeStore) {
+        let pending: Vec<Scope> = self.pending_evictions.lock().drain().collect();
+        for scope in pending {
+            if let Err(e) = self.evict_oldest(store, &scope).await {
+                warn!("Failed to evict events from {}: {}", scope_label(&scope), e);
+            }
+        }
+    }
+
+    /// Evicts from every cell the last refresh found over quota
+    async fn evict_over_quota(&self, store: &dyn ScopeStore) {
+        let over: Vec<Scope> = self
+            .usage
+            .read()
+            .iter()
+            .filter(|(_, usage)| self.is_over(usage))
+            .map(|(scope, _)| scope.clone())
+            .collect();
+        self.pending_evictions.lock().extend(over);
+        self.evict_pending(store).await;
+    }
+}
+
+/// Whether `usage` is above `limits`, where a zero limit is unlimited
+fn exceeds(usage: &ScopeUsage, limits: &ScopeUsage) -> bool {
+    (limits.events > 0 && usage.events > limits.events) || (limits.bytes > 0 && usage.bytes > limits.bytes)
+}
+
+/// Oldest events to fetch to bring `usage` down to `target`
+///
+/// Byte limits are converted to an event count using the average size.
+fn batch_size(usage: &ScopeUsage, target: &ScopeUsage) -> usize {
+    let by_events = if target.events > 0 { usage.events.saturating_sub(target.events) } else { 0 };
+    let by_bytes = match usage.bytes.checked_div(usage.events) {
+        Some(average) if target.bytes > 0 && average > 0 => usage.bytes.saturating_sub(target.bytes).div_ceil(average),
+        _ => 0,
+    };
+    by_events.max(by_bytes).clamp(1, PAGE_SIZE as u64) as usize
+}
+
+/// Spawns the periodic recount and, under `evict-oldest` or `archive`, the
+/// eviction loop
+pub fn spawn_quota_task(quota: Arc<ScopeQuota>, store: Arc<dyn ScopeStore>, interval: Duration) {
+    if !quota.is_enabled() {
+        return;
+    }
+    info!(
+        "Per-cell quota: {} events, {} bytes ({:?})",
+        quota.max_events, quota.max_bytes, quota.policy
+    );
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    if let Err(e) = quota.refresh(store.as_ref()).await {
+                        warn!("Failed to refresh scope quotas: {}", e);
+                        continue;
+                    }
+                    if quota.policy != QuotaPolicy::Reject {
+                        quota.evict_over_quota(store.as_ref()).await;
+                    }
+                }
+                _ = quota.evict.notified() => quota.evict_pending(store.as_ref()).await,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{self, FsObjectStore, ObjectStore};
+    use crate::store::MemoryStore;
+    use futures::future::BoxFuture;
+
+    /// An archive that is always down
+    struct UnreachableStore;
+
+    impl ObjectStore for UnreachableStore {
+        fn put(&self, _key: &str, _body: Vec<u8>) -> BoxFuture<'_, Result<()>> {
+            Box::pin(async { anyhow::bail!("connection refused") })
+        }
+
+        fn get(&self, _key: &str) -> BoxFuture<'_, Result<Vec<u8>>> {
+            Box::pin(async { anyhow::bail!("connection refused") })
+        }
+
+        fn list(&self, _prefix: &str) -> BoxFuture<'_, Result<Vec<String>>> {
+            Box::pin(async { anyhow::bail!("connection refused") })
+        }
+    }
+
+    fn quota(max_events: u64, max_bytes: u64, policy: QuotaPolicy) -> ScopeQuota {
+        ScopeQuota::new(&RelayConfig {
+            max_events_per_scope: max_events,
+            max_bytes_per_scope: max_bytes,
+            quota_policy: policy,
+            ..Default::default()
+        })
+    }
+
+    fn note_at(keys: &Keys, created_at: u64) -> Event {
+        EventBuilder::text_note(format!("note {}", created_at))
+            .custom_created_at(Timestamp::from(created_at))
+            .sign_with_keys(keys)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_reject_policy_refuses_full_cells() {
+        let quota = quota(2, 0, QuotaPolicy::Reject);
+        let drt2z = Scope::named("drt2z").unwrap();
+        assert!(quota.admit(&drt2z, 100));
+        assert!(quota.admit(&drt2z, 100));
+        assert!(!quota.admit(&drt2z, 100));
+        assert_eq!(quota.usage(&drt2z), ScopeUsage { events: 2, bytes: 200 });
+
+        // Other cells and root are unaffected
+        assert!(quota.admit(&Scope::named("9q8yy").unwrap(), 100));
+        assert!((0..10).all(|_| quota.admit(&Scope::Default, 100)));
+    }
+
+    #[test]
+    fn test_byte_limit() {
+        let quota = quota(0, 250, QuotaPolicy::Reject);
+        let drt2z = Scope::named("drt2z").unwrap();
+        assert!(quota.admit(&drt2z, 200));
+        assert!(!quota.admit(&drt2z, 100));
+        assert!(quota.admit(&drt2z, 50));
+    }
+
+    #[test]
+    fn test_disabled_quota_admits_everything() {
+        let quota = quota(0, 0, QuotaPolicy::Reject);
+        let drt2z = Scope::named("drt2z").unwrap();
+        assert!((0..100).all(|_| quota.admit(&drt2z, 1000)));
+    }
+
+    #[tokio::test]
+    async fn test_evict_oldest_keeps_newest_events() {
+        let quota = quota(3, 0, QuotaPolicy::EvictOldest);
+        let store = MemoryStore::new();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let keys = Keys::generate();
+        for created_at in 1..=5 {
+            let event = note_at(&keys, created_at * 1000);
+            assert!(quota.admit(&drt2z, event.as_json().len() as u64));
+            store.insert(&drt2z, event);
+        }
+        assert!(quota.pending_evictions.lock().contains(&drt2z));
+
+        quota.evict_pending(&store).await;
+
+        let remaining = store.query(&drt2z, Filter::new()).await.unwrap();
+        let timestamps: Vec<u64> = remaining.iter().map(|e| e.created_at.as_u64()).collect();
+        assert_eq!(timestamps, vec![5000, 4000, 3000]);
+        assert_eq!(quota.usage(&drt2z).events, 3);
+    }
+
+    #[tokio::test]
+    async fn test_archive_uploads_before_evicting_to_low_watermark() {
+        let dir = tempfile::tempdir().unwrap();
+        let objects = Arc::new(FsObjectStore::new(dir.path()));
+        let quota = quota(10, 0, QuotaPolicy::Archive).with_archiver(Archiver::new(objects.clone(), ""));
+        let store = MemoryStore::new();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let keys = Keys::generate();
+        for created_at in 1..=11 {
+            store.insert(&drt2z, note_at(&keys, created_at * 1000));
+        }
+
+        assert_eq!(quota.evict_oldest(&store, &drt2z).await.unwrap(), 2);
+
+        assert_eq!(store.count(&drt2z, Filter::new()).await.unwrap(), 9);
+        let keys = objects.list("drt2z/").await.unwrap();
+        assert_eq!(keys.len(), 1);
+        let archived = archive::decode(&objects.get(&keys[0]).await.unwrap()).unwrap();
+        let timestamps: Vec<u64> = archived.iter().map(|e| e.created_at.as_u64()).collect();
+        assert_eq!(timestamps, vec![1000, 2000]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_archive_keeps_events() {
+        let quota = quota(3, 0, QuotaPolicy::Archive).with_archiver(Archiver::new(Arc::new(UnreachableStore), ""));
+        let store = MemoryStore::new();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let keys = Keys::generate();
+        for created_at in 1..=5 {
+            store.insert(&drt2z, note_at(&keys, created_at * 1000));
+        }
+
+        assert!(quota.evict_oldest(&store, &drt2z).await.is_err());
+        assert_eq!(store.count(&drt2z, Filter::new()).await.unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_recounts_named_scopes() {
+        let quota = quota(10, 0, QuotaPolicy::Reject);
+        let store = MemoryStore::new();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let keys = Keys::generate();
+        store.insert(&drt2z, note_at(&keys, 1000));
+        store.insert(&drt2z, note_at(&keys, 2000));
+        store.insert(&Scope::Default, note_at(&keys, 3000));
+
+        quota.refresh(&store).await.unwrap();
+
+        assert_eq!(quota.usage(&drt2z).events, 2);
+        assert_eq!(quota.usage(&Scope::Default), ScopeUsage::default());
+    }
+}
@@ -0,0 +1,163 @@
+//! Per-scope event rate limiting
+//!
+//! Every scope (each geohash cell and root) gets its own token bucket, so
+//! a burst in one busy cell can't throttle the rest of the relay. Budgets
+//! come from the scope policy's `rate_limit_for`, by default
+//! `RelayConfig::events_per_minute_for`. The buckets live in
+//! `ScopeCounterMap`; each connection resolves its scope's when it opens.
+//!
+//! With `authed_events_per_minute` set, a connection that has authenticated
+//! takes from its pubkey's own bucket instead, relay-wide and ahead of the
+//! scope policy, so known writers aren't throttled by a crowded cell. The
+//! limiter runs after `ScopedAuthMiddleware`, which is what lets it see who
+//! authenticated.
+
+use governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
+use nostr_sdk::prelude::*;
+use relay_builder::{ConnectionContext, InboundContext, InboundProcessor, NostrMiddleware};
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use tracing::debug;
+use crate::log_summary::LogSummary;
+use crate::processor::ConnectionState;
+use crate::reject::RejectReason;
+use crate::scope_counters::ScopeCounterMap;
+use crate::store::scope_label;
+
+/// Pubkey buckets kept before idle ones are dropped
+const AUTHED_BUCKETS_SOFT_LIMIT: usize = 10_000;
+
+/// Rejects EVENTs once the connection's scope, or its authenticated
+/// pubkey, is over budget
+#[derive(Clone)]
+pub struct ScopeRateLimitMiddleware {
+    counters: Arc<ScopeCounterMap>,
+    /// Per-pubkey buckets for authenticated connections, if they get their own
+    authed: Option<Arc<DefaultKeyedRateLimiter<PublicKey>>>,
+    log_summary: Arc<LogSummary>,
+}
+
+impl ScopeRateLimitMiddleware {
+    pub fn new(counters: Arc<ScopeCounterMap>) -> Self {
+        Self { counters, authed: None, log_summary: Arc::new(LogSummary::disabled()) }
+    }
+
+    /// Gives each authenticated pubkey `events_per_minute` of its own; 0
+    /// leaves authenticated connections on their scope's budget
+    pub fn with_authed_limit(mut self, events_per_minute: u32) -> Self {
+        self.authed = NonZeroU32::new(events_per_minute)
+            .map(|limit| Arc::new(RateLimiter::keyed(Quota::per_minute(limit))));
+        self
+    }
+
+    fn admit_authed(authed: &DefaultKeyedRateLimiter<PublicKey>, pubkey: &PublicKey) -> bool {
+        if authed.len() > AUTHED_BUCKETS_SOFT_LIMIT {
+            authed.retain_recent();
+        }
+        authed.check_key(pubkey).is_ok()
+    }
+
+    /// Counts rate-limited events for the periodic log summary
+    pub fn with_log_summary(mut self, log_summary: Arc<LogSummary>) -> Self {
+        self.log_summary = log_summary;
+        self
+    }
+}
+
+impl NostrMiddleware<ConnectionState> for ScopeRateLimitMiddleware {
+    async fn on_connect(&self, ctx: ConnectionContext<'_, ConnectionState>) -> Result<(), anyhow::Error> {
+        let mut state = ctx.state.write();
+        let counters = self.counters.resolve(&state.subdomain);
+        state.custom.scope_counters = Some(counters);
+        Ok(())
+    }
+
+    async fn process_inbound<Next>(&self, ctx: InboundContext<'_, ConnectionState, Next>) -> Result<(), anyhow::Error>
+    where
+        Next: InboundProcessor<ConnectionState>,
+    {
+        let event_id = match &ctx.message {
+            Some(ClientMessage::Event(event)) => Some(event.id),
+            _ => None,
+        };
+        if let Some(event_id) = event_id {
+            let admitted = {
+                let state = ctx.state.read();
+                match (&self.authed, &state.authed_pubkey) {
+                    (Some(authed), Some(pubkey)) => Self::admit_authed(authed, pubkey),
+                    _ => self.counters.with_counters(state.custom.scope_counters.as_deref(), &state.subdomain, |counters| {
+                        counters.check()
+                    }),
+                }
+            };
+            if !admitted {
+                let scope = ctx.state.read().subdomain.as_ref().clone();
+                debug!("Rate limited event {} in {}", event_id, scope_label(&scope));
+                metrics::counter!("relay_rate_limited_events_total").increment(1);
+                self.log_summary.rejected(&scope, RejectReason::RateLimited.code());
+                ctx.state.write().custom.event_counters.record_rate_limited();
+                ctx.send_message(RelayMessage::ok(event_id, false, RejectReason::RateLimited.to_string()))?;
+                return Ok(());
+            }
+        }
+        ctx.next().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RelayConfig;
+    use nostr_lmdb::Scope;
+
+    #[test]
+    fn test_scopes_have_independent_budgets() {
+        let config = RelayConfig {
+            events_per_minute: 2,
+            ..Default::default()
+        };
+        let limiter = ScopeCounterMap::new(Arc::new(config));
+        let drt2z = Scope::named("drt2z").unwrap();
+        let other = Scope::named("9q8yy").unwrap();
+
+        assert!(limiter.check(&drt2z));
+        assert!(limiter.check(&drt2z));
+        assert!(!limiter.check(&drt2z));
+
+        assert!(limiter.check(&other));
+        assert!(limiter.check(&Scope::Default));
+    }
+
+    #[test]
+    fn test_overrides_and_unlimited_scopes() {
+        let mut config = RelayConfig {
+            events_per_minute: 1,
+            ..Default::default()
+        };
+        config.scope_events_per_minute.insert("root".to_string(), 0);
+        config.precision_events_per_minute.insert(2, 3);
+        let limiter = ScopeCounterMap::new(Arc::new(config));
+
+        for _ in 0..100 {
+            assert!(limiter.check(&Scope::Default));
+        }
+        let coarse = Scope::named("dr").unwrap();
+        assert!((0..3).all(|_| limiter.check(&coarse)));
+        assert!(!limiter.check(&coarse));
+    }
+
+    #[test]
+    fn test_authed_pubkeys_have_their_own_budget() {
+        let counters = Arc::new(ScopeCounterMap::new(Arc::new(RelayConfig::default())));
+        let middleware = ScopeRateLimitMiddleware::new(counters.clone()).with_authed_limit(2);
+        let authed = middleware.authed.as_deref().unwrap();
+        let (alice, bob) = (Keys::generate().public_key(), Keys::generate().public_key());
+
+        assert!(ScopeRateLimitMiddleware::admit_authed(authed, &alice));
+        assert!(ScopeRateLimitMiddleware::admit_authed(authed, &alice));
+        assert!(!ScopeRateLimitMiddleware::admit_authed(authed, &alice));
+        assert!(ScopeRateLimitMiddleware::admit_authed(authed, &bob));
+
+        assert!(ScopeRateLimitMiddleware::new(counters).with_authed_limit(0).authed.is_none());
+    }
+}
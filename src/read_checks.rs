@@ -0,0 +1,88 @@
+//! The processor's read checks, for middleware that answers REQs itself
+//!
+//! The query cache, geo filters, global kinds and COUNT answer from the
+//! store without handing the request to relay_builder, which would
+//! otherwise run `verify_filters` on it and `can_see_event` on every event.
+//! `ReadChecks` runs the same two checks for them, in the scope they
+//! actually read from, so none of them skips read auth, the DM participant
+//! check, tombstones or expirations.
+
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::RwLock;
+use relay_builder::{EventContext, EventProcessor};
+use std::sync::Arc;
+use crate::processor::{ConnectionState, GeohashedEventProcessor};
+use crate::scope_policy::{DefaultScopePolicy, ScopePolicy};
+
+/// The processor and relay key read checks run with
+#[derive(Clone)]
+pub struct ReadChecks<P = DefaultScopePolicy> {
+    processor: GeohashedEventProcessor<P>,
+    relay_pubkey: PublicKey,
+}
+
+impl<P: ScopePolicy> ReadChecks<P> {
+    pub fn new(processor: GeohashedEventProcessor<P>, relay_pubkey: PublicKey) -> Self {
+        Self { processor, relay_pubkey }
+    }
+
+    /// Checks for one connection reading `scope`
+    pub fn reader(&self, scope: Arc<Scope>, authed_pubkey: Option<PublicKey>, custom: &ConnectionState) -> Reader<'_, P> {
+        Reader {
+            checks: self,
+            scope,
+            authed_pubkey,
+            custom_state: Arc::new(RwLock::new(custom.clone())),
+        }
+    }
+}
+
+/// A connection reading one scope
+pub struct Reader<'a, P = DefaultScopePolicy> {
+    checks: &'a ReadChecks<P>,
+    scope: Arc<Scope>,
+    authed_pubkey: Option<PublicKey>,
+    custom_state: Arc<RwLock<ConnectionState>>,
+}
+
+impl<P: ScopePolicy> Reader<'_, P> {
+    pub fn scope(&self) -> &Scope {
+        &self.scope
+    }
+
+    /// The same connection reading another scope, as for `#g` lookups
+    /// answered on root from cells
+    pub fn at(&self, scope: Scope) -> Self {
+        Self {
+            checks: self.checks,
+            scope: Arc::new(scope),
+            authed_pubkey: self.authed_pubkey,
+            custom_state: self.custom_state.clone(),
+        }
+    }
+
+    fn context(&self) -> EventContext {
+        EventContext {
+            relay_pubkey: self.checks.relay_pubkey,
+            subdomain: self.scope.clone(),
+            authed_pubkey: self.authed_pubkey,
+        }
+    }
+
+    /// `verify_filters`, with the message to close the subscription with
+    pub fn verify(&self, filters: &[Filter]) -> Result<(), String> {
+        self.checks
+            .processor
+            .verify_filters(filters, self.custom_state.clone(), &self.context())
+            .map_err(|e| e.to_string())
+    }
+
+    /// `can_see_event`; an error hides the event
+    pub fn can_see(&self, event: &Event) -> bool {
+        self.checks
+            .processor
+            .can_see_event(event, self.custom_state.clone(), &self.context())
+            .unwrap_or(false)
+    }
+}
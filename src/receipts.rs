@@ -0,0 +1,261 @@
+//! Relay-signed receipts for accepted events
+//!
+//! With `receipts_enabled`, every event the relay stores gets a receipt:
+//! an event of `receipt_kind` signed with the relay key and saved in the
+//! same scope, carrying
+//!
+//! - `["e", <accepted id>]` and `["p", <author>]`, so authors can REQ
+//!   `{"kinds": [receipt_kind], "#p": [<their pubkey>]}` for their receipts
+//! - `["scope", <scope label>]`
+//! - `["received_at", <unix seconds>]`, also the receipt's `created_at`
+//!
+//! An ephemeral `receipt_kind` isn't stored; it only reaches live
+//! listeners (SSE feeds, webhooks, MQTT). Receipts are issued from
+//! `LiveEvents` after the OK, so they never delay it, and each author gets
+//! at most `receipts_per_minute`. The relay's own events, receipts
+//! included, never get one.
+
+use anyhow::Result;
+use governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
+use nostr_sdk::prelude::*;
+use relay_builder::StoreCommand;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+use crate::config::RelayConfig;
+use crate::live::{LiveEvents, StoredEvent};
+use crate::processor::GeohashedEventProcessor;
+use crate::scope_policy::{DefaultScopePolicy, ScopePolicy};
+use crate::store::{scope_label, ScopeStore};
+
+/// Default `receipt_kind`
+pub const DEFAULT_RECEIPT_KIND: u16 = 9062;
+
+/// Authors tracked by the rate limit before idle ones are dropped
+const MAX_TRACKED_AUTHORS: usize = 10_000;
+
+/// Unsigned receipt for `stored`, received at `received_at`
+pub fn receipt_builder(kind: Kind, stored: &StoredEvent, received_at: Timestamp) -> EventBuilder {
+    EventBuilder::new(kind, "")
+        .tag(Tag::event(stored.event.id))
+        .tag(Tag::public_key(stored.event.pubkey))
+        .tag(Tag::custom(TagKind::Custom("scope".into()), [scope_label(&stored.scope)]))
+        .tag(Tag::custom(TagKind::Custom("received_at".into()), [received_at.as_u64().to_string()]))
+        .custom_created_at(received_at)
+}
+
+/// Signs, stores and announces receipts
+pub struct ReceiptIssuer<P = DefaultScopePolicy> {
+    processor: GeohashedEventProcessor<P>,
+    keys: Keys,
+    store: Arc<dyn ScopeStore>,
+    live: Arc<LiveEvents>,
+    kind: Kind,
+    /// Receipts per author per minute, `None` for no limit
+    limiter: Option<DefaultKeyedRateLimiter<PublicKey>>,
+}
+
+impl<P: ScopePolicy> ReceiptIssuer<P> {
+    pub fn new(
+        processor: GeohashedEventProcessor<P>,
+        keys: Keys,
+        store: Arc<dyn ScopeStore>,
+        live: Arc<LiveEvents>,
+        kind: u16,
+        per_minute: u32,
+    ) -> Self {
+        Self {
+            processor,
+            keys,
+            store,
+            live,
+            kind: Kind::from(kind),
+            limiter: NonZeroU32::new(per_minute).map(|rate| RateLimiter::keyed(Quota::per_minute(rate))),
+        }
+    }
+
+    /// The configured issuer; `None` unless `receipts_enabled`, or on a
+    /// replica, which gets the leader's receipts
+    pub fn for_config(
+        config: &RelayConfig,
+        processor: GeohashedEventProcessor<P>,
+        keys: Keys,
+        store: Arc<dyn ScopeStore>,
+        live: Arc<LiveEvents>,
+    ) -> Option<Self> {
+        (config.receipts_enabled && config.replicate_from.is_none())
+            .then(|| Self::new(processor, keys, store, live, config.receipt_kind, config.receipts_per_minute))
+    }
+
+    /// Whether `stored` is a client's stored event; receipts and the
+    /// relay's other events don't get one, nor do unstored ephemeral events
+    pub fn wants_receipt(&self, stored: &StoredEvent) -> bool {
+        stored.event.pubkey != self.keys.public_key()
+            && stored.event.kind != self.kind
+            && !stored.event.kind.is_ephemeral()
+    }
+
+    fn admit(&self, author: &PublicKey) -> bool {
+        let Some(limiter) = &self.limiter else {
+            return true;
+        };
+        if limiter.len() > MAX_TRACKED_AUTHORS {
+            limiter.retain_recent();
+        }
+        limiter.check_key(author).is_ok()
+    }
+
+    /// Issues the receipt for `stored`, if it gets one
+    pub async fn issue(&self, stored: &StoredEvent, received_at: Timestamp) -> Result<Option<Event>> {
+        if !self.wants_receipt(stored) {
+            return Ok(None);
+        }
+        if !self.admit(&stored.event.pubkey) {
+            debug!("Skipping receipt for {}: author over the receipt rate", stored.event.id);
+            metrics::counter!("relay_receipts_skipped_total").increment(1);
+            return Ok(None);
+        }
+        let receipt = receipt_builder(self.kind, stored, received_at).sign(&self.keys).await?;
+        if !self.kind.is_ephemeral() {
+            let commands = self
+                .processor
+                .publish_internal(receipt.clone(), self.keys.public_key(), stored.scope.clone())
+                .await
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+            for command in commands {
+                if let StoreCommand::SaveSignedEvent(event, target, _) = command {
+                    self.store.save(&target, *event).await?;
+                }
+            }
+        }
+        self.live.publish(StoredEvent { scope: stored.scope.clone(), event: receipt.clone() });
+        metrics::counter!("relay_receipts_issued_total").increment(1);
+        Ok(Some(receipt))
+    }
+}
+
+/// Issues receipts for every event published on `live` until the relay
+/// shuts down
+pub fn spawn_listener(issuer: Arc<ReceiptIssuer>, live: &LiveEvents) {
+    let mut receiver = live.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(stored) => {
+                    if let Err(e) = issuer.issue(&stored, Timestamp::now()).await {
+                        warn!("Failed to issue receipt for {}: {:#}", stored.event.id, e);
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Receipts fell behind, skipped {} events", missed);
+                    metrics::counter!("relay_receipts_skipped_total").increment(missed);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use nostr_lmdb::Scope;
+
+    fn issuer(store: Arc<MemoryStore>, keys: &Keys, kind: u16, per_minute: u32) -> ReceiptIssuer {
+        ReceiptIssuer::new(
+            GeohashedEventProcessor::new(),
+            keys.clone(),
+            store,
+            Arc::new(LiveEvents::new()),
+            kind,
+            per_minute,
+        )
+    }
+
+    async fn stored(scope: &Scope, content: &str) -> StoredEvent {
+        let event = EventBuilder::text_note(content).sign(&Keys::generate()).await.unwrap();
+        StoredEvent { scope: scope.clone(), event }
+    }
+
+    #[tokio::test]
+    async fn test_receipt_lands_in_the_same_scope_signed_by_the_relay() {
+        let store = Arc::new(MemoryStore::new());
+        let relay_keys = Keys::generate();
+        let issuer = issuer(store.clone(), &relay_keys, DEFAULT_RECEIPT_KIND, 0);
+        let drt2z = Scope::named("drt2z").unwrap();
+        let accepted = stored(&drt2z, "hello").await;
+        let received_at = Timestamp::from(1_700_000_000);
+
+        let receipt = issuer.issue(&accepted, received_at).await.unwrap().unwrap();
+        assert_eq!(receipt.pubkey, relay_keys.public_key());
+        assert!(receipt.verify().is_ok());
+        assert_eq!(receipt.tags.event_ids().collect::<Vec<_>>(), vec![&accepted.event.id]);
+        assert_eq!(receipt.tags.public_keys().collect::<Vec<_>>(), vec![&accepted.event.pubkey]);
+        let tag = |name: &str| {
+            receipt
+                .tags
+                .iter()
+                .find(|tag| tag.as_slice().first().map(String::as_str) == Some(name))
+                .and_then(|tag| tag.content())
+                .map(str::to_string)
+        };
+        assert_eq!(tag("scope").as_deref(), Some("drt2z"));
+        assert_eq!(tag("received_at").as_deref(), Some("1700000000"));
+
+        let filter = Filter::new().kind(Kind::from(DEFAULT_RECEIPT_KIND)).pubkey(accepted.event.pubkey);
+        assert_eq!(store.query(&drt2z, filter.clone()).await.unwrap(), vec![receipt]);
+        assert!(store.query(&Scope::Default, filter).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_receipts_and_relay_events_get_no_receipt() {
+        let store = Arc::new(MemoryStore::new());
+        let relay_keys = Keys::generate();
+        let issuer = issuer(store, &relay_keys, DEFAULT_RECEIPT_KIND, 0);
+        let drt2z = Scope::named("drt2z").unwrap();
+
+        let receipt = issuer.issue(&stored(&drt2z, "hello").await, Timestamp::now()).await.unwrap().unwrap();
+        let as_stored = StoredEvent { scope: drt2z.clone(), event: receipt };
+        assert!(issuer.issue(&as_stored, Timestamp::now()).await.unwrap().is_none());
+
+        let own = EventBuilder::text_note("relay note").sign(&relay_keys).await.unwrap();
+        let own = StoredEvent { scope: drt2z.clone(), event: own };
+        assert!(issuer.issue(&own, Timestamp::now()).await.unwrap().is_none());
+
+        let chat = EventBuilder::new(Kind::from(20000), "hi").sign(&Keys::generate()).await.unwrap();
+        assert!(!issuer.wants_receipt(&StoredEvent { scope: drt2z, event: chat }));
+    }
+
+    #[tokio::test]
+    async fn test_receipts_are_rate_limited_per_author() {
+        let store = Arc::new(MemoryStore::new());
+        let issuer = issuer(store, &Keys::generate(), DEFAULT_RECEIPT_KIND, 2);
+        let drt2z = Scope::named("drt2z").unwrap();
+        let author = Keys::generate();
+        let mut issued = 0;
+        for i in 0..4 {
+            let event = EventBuilder::text_note(format!("note {}", i)).sign(&author).await.unwrap();
+            let accepted = StoredEvent { scope: drt2z.clone(), event };
+            if issuer.issue(&accepted, Timestamp::now()).await.unwrap().is_some() {
+                issued += 1;
+            }
+        }
+        assert_eq!(issued, 2);
+        // Someone else still gets theirs
+        assert!(issuer.issue(&stored(&drt2z, "other").await, Timestamp::now()).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_receipts_are_not_stored() {
+        let store = Arc::new(MemoryStore::new());
+        let issuer = issuer(store.clone(), &Keys::generate(), 20062, 0);
+        let drt2z = Scope::named("drt2z").unwrap();
+        let mut live = issuer.live.subscribe();
+
+        let receipt = issuer.issue(&stored(&drt2z, "hello").await, Timestamp::now()).await.unwrap().unwrap();
+        assert_eq!(store.count(&drt2z, Filter::new()).await.unwrap(), 0);
+        assert_eq!(live.recv().await.unwrap().event, receipt);
+    }
+}
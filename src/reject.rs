@@ -0,0 +1,444 @@
+//! Rejection reasons and their messages
+//!
+//! Every event rejection the relay sends (and the NOTICE before a forced
+//! disconnect) is built from `RejectReason`. Messages start with a NIP-01
+//! prefix that tells clients whether retrying can help and end with a stable
+//! `[code]` token naming the exact reason, e.g.
+//! `restricted: events with geohash 'drt2z' must be posted to wss://drt2z.hashstr.com [wrong-scope]`.
+
+use std::fmt;
+use crate::config::GeohashProfile;
+use crate::policy::kinds_list;
+
+/// Machine-readable message prefix from NIP-01
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Prefix {
+    /// Throttled; retry later
+    RateLimited,
+    /// Malformed event or tags; don't retry unchanged
+    Invalid,
+    /// Scope or policy violation; retrying the same relay won't help
+    Restricted,
+    /// Needs more NIP-13 proof of work
+    Pow,
+    /// Needs NIP-42 authentication first
+    AuthRequired,
+    /// Author or connection is banned
+    Blocked,
+    /// Relay-side failure, usually storage; retry later
+    Error,
+}
+
+impl Prefix {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Prefix::RateLimited => "rate-limited",
+            Prefix::Invalid => "invalid",
+            Prefix::Restricted => "restricted",
+            Prefix::Pow => "pow",
+            Prefix::AuthRequired => "auth-required",
+            Prefix::Blocked => "blocked",
+            Prefix::Error => "error",
+        }
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Why the relay refused an event or dropped a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The connection's subdomain isn't a geohash
+    InvalidSubdomain { subdomain: String },
+    /// The subdomain or g tag is a cell at a precision the relay doesn't serve
+    PrecisionNotAllowed { geohash: String, allowed: Vec<usize> },
+    /// A geotagged event was posted to the root scope
+    RootRejectsGeotagged { suggested_url: String },
+    /// A geotagged event was posted to a different cell
+    WrongScope { geohash: String, suggested_url: String },
+    /// The scope is paid and the author isn't admitted
+    PaymentRequired { payments_url: Option<String> },
+    /// The kind isn't in the scope type's allowed list
+    KindNotAllowed { kind: u16, on_root: bool, allowed: Vec<u16>, profile: Option<GeohashProfile> },
+    /// The kind may not target cells finer than its precision cap
+    PrecisionCapped { kind: u16, geohash: String, max: usize },
+    /// Direct messages are only accepted on root
+    DmRootOnly { kind: u16 },
+    /// Direct messages aren't accepted at all
+    DmNotAccepted { kind: u16 },
+    /// The scope's event budget is used up
+    RateLimited,
+    /// The cell is at its stored-event quota
+    ScopeFull,
+    /// The LMDB map is full
+    StorageFull,
+    /// The database filesystem is over its usage watermark
+    StoragePressure,
+    /// A write failed in the store for any reason other than a full map
+    StorageFailure,
+    /// This relay is a follower and only takes writes from its leader
+    ReadOnlyReplica,
+    /// The relay started read-only and the operator hasn't cleared it yet
+    ReadOnly,
+    /// The operator has put the relay in maintenance mode
+    Maintenance { message: String },
+    /// The connection isn't reading its messages
+    SlowConsumer,
+    /// A REQ would exceed the connection's open subscriptions
+    TooManySubscriptions { max: usize },
+    /// The event carries more tags of one name than its kind allows
+    TooManyTags { letter: char, max: u32 },
+    /// The event carries more g tags than the relay allows
+    TooManyGeohashTags { max: u32 },
+    /// A REQ's #g values are malformed or over the relay's limits
+    BadGeohashFilter { detail: String },
+    /// The event id has fewer leading zero bits than the scope requires
+    InsufficientPow { scope: String, difficulty: u8 },
+    /// Many authors just posted the same content to the scope
+    DuplicateContent,
+    /// The content matches the scope's blocklist
+    ContentBlocked,
+    /// The scope needs a NIP-42 authenticated connection to post (or read)
+    AuthRequired { write: bool },
+    /// The client's address already wrote to its share of distinct cells
+    TooManyCells,
+    /// Strict TTL mode: a cell event lacks an expiration within the limit
+    ExpirationRequired { max_days: u64 },
+    /// The connection asked for stats too recently
+    StatsTooSoon { retry_secs: u64 },
+    /// A NIP-26 delegation tag doesn't verify or its conditions aren't met
+    BadDelegation,
+    /// Web-of-trust mode is on and the author isn't within reach of a seed
+    NotInWebOfTrust,
+    /// A NIP-62 request to vanish names other relays, not this one
+    VanishElsewhere,
+    /// The author asked to vanish; older events, or any within the block period, are refused
+    Vanished,
+}
+
+impl RejectReason {
+    pub fn prefix(&self) -> Prefix {
+        match self {
+            RejectReason::RateLimited | RejectReason::TooManyCells | RejectReason::StatsTooSoon { .. } => Prefix::RateLimited,
+            RejectReason::TooManyTags { .. }
+            | RejectReason::TooManyGeohashTags { .. }
+            | RejectReason::BadGeohashFilter { .. }
+            | RejectReason::ExpirationRequired { .. }
+            | RejectReason::BadDelegation
+            | RejectReason::VanishElsewhere => Prefix::Invalid,
+            RejectReason::InsufficientPow { .. } => Prefix::Pow,
+            RejectReason::DuplicateContent | RejectReason::ContentBlocked => Prefix::Blocked,
+            RejectReason::AuthRequired { .. } => Prefix::AuthRequired,
+            RejectReason::InvalidSubdomain { .. }
+            | RejectReason::PrecisionNotAllowed { .. }
+            | RejectReason::RootRejectsGeotagged { .. }
+            | RejectReason::WrongScope { .. }
+            | RejectReason::PaymentRequired { .. }
+            | RejectReason::KindNotAllowed { .. }
+            | RejectReason::PrecisionCapped { .. }
+            | RejectReason::DmRootOnly { .. }
+            | RejectReason::DmNotAccepted { .. }
+            | RejectReason::TooManySubscriptions { .. }
+            | RejectReason::Vanished
+            | RejectReason::NotInWebOfTrust => Prefix::Restricted,
+            RejectReason::ScopeFull
+            | RejectReason::StorageFull
+            | RejectReason::StoragePressure
+            | RejectReason::StorageFailure
+            | RejectReason::ReadOnlyReplica
+            | RejectReason::ReadOnly
+            | RejectReason::Maintenance { .. }
+            | RejectReason::SlowConsumer => Prefix::Error,
+        }
+    }
+
+    /// Stable identifier, appended to messages and used as a metrics label
+    pub fn code(&self) -> &'static str {
+        match self {
+            RejectReason::InvalidSubdomain { .. } => "invalid-subdomain",
+            RejectReason::PrecisionNotAllowed { .. } => "precision-not-allowed",
+            RejectReason::RootRejectsGeotagged { .. } => "root-rejects-geotagged",
+            RejectReason::WrongScope { .. } => "wrong-scope",
+            RejectReason::PaymentRequired { .. } => "payment-required",
+            RejectReason::KindNotAllowed { .. } => "kind-not-allowed",
+            RejectReason::PrecisionCapped { .. } => "precision-capped",
+            RejectReason::DmRootOnly { .. } => "dm-root-only",
+            RejectReason::DmNotAccepted { .. } => "dm-not-accepted",
+            RejectReason::RateLimited => "rate-limited",
+            RejectReason::ScopeFull => "scope-full",
+            RejectReason::StorageFull => "storage-full",
+            RejectReason::StoragePressure => "storage-pressure",
+            RejectReason::StorageFailure => "storage-failure",
+            RejectReason::ReadOnlyReplica => "read-only-replica",
+            RejectReason::ReadOnly => "read-only",
+            RejectReason::Maintenance { .. } => "maintenance",
+            RejectReason::SlowConsumer => "slow-consumer",
+            RejectReason::TooManySubscriptions { .. } => "too-many-subscriptions",
+            RejectReason::TooManyTags { .. } => "too-many-tags",
+            RejectReason::TooManyGeohashTags { .. } => "too-many-geohash-tags",
+            RejectReason::BadGeohashFilter { .. } => "bad-geohash-filter",
+            RejectReason::InsufficientPow { .. } => "pow-required",
+            RejectReason::DuplicateContent => "duplicate-content",
+            RejectReason::ContentBlocked => "content-blocked",
+            RejectReason::AuthRequired { .. } => "auth-required",
+            RejectReason::TooManyCells => "too-many-cells",
+            RejectReason::ExpirationRequired { .. } => "expiration-required",
+            RejectReason::StatsTooSoon { .. } => "stats-too-soon",
+            RejectReason::BadDelegation => "bad-delegation",
+            RejectReason::NotInWebOfTrust => "not-in-wot",
+            RejectReason::VanishElsewhere => "vanish-elsewhere",
+            RejectReason::Vanished => "vanished",
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.prefix())?;
+        match self {
+            RejectReason::InvalidSubdomain { subdomain } => {
+                write!(f, "'{}' is not a valid geohash subdomain", subdomain)?
+            }
+            RejectReason::PrecisionNotAllowed { geohash, allowed } => {
+                let allowed: Vec<String> = allowed.iter().map(|p| p.to_string()).collect();
+                write!(
+                    f,
+                    "geohash '{}' has precision {}; this relay only serves precision {}",
+                    geohash,
+                    geohash.len(),
+                    allowed.join(", ")
+                )?
+            }
+            RejectReason::RootRejectsGeotagged { suggested_url } => {
+                write!(f, "root relay does not accept geotagged events; use {}", suggested_url)?
+            }
+            RejectReason::WrongScope { geohash, suggested_url } => {
+                write!(f, "events with geohash '{}' must be posted to {}", geohash, suggested_url)?
+            }
+            RejectReason::PaymentRequired { payments_url: Some(url) } => {
+                write!(f, "payment required, see {}", url)?
+            }
+            RejectReason::PaymentRequired { payments_url: None } => f.write_str("payment required")?,
+            RejectReason::KindNotAllowed { kind, on_root, allowed, profile } => {
+                write!(
+                    f,
+                    "kind {} is not accepted on {} (allowed kinds: {}",
+                    kind,
+                    if *on_root { "the root relay" } else { "geohash cells" },
+                    kinds_list(allowed)
+                )?;
+                match profile {
+                    Some(profile) => write!(f, " under the {} profile)", profile)?,
+                    None => f.write_str(")")?,
+                }
+            }
+            RejectReason::PrecisionCapped { kind, geohash, max } => write!(
+                f,
+                "kind {} events may not target cells finer than precision {}; post to '{}' instead of '{}'",
+                kind,
+                max,
+                &geohash[..(*max).min(geohash.len())],
+                geohash
+            )?,
+            RejectReason::DmRootOnly { kind } => write!(
+                f,
+                "direct messages (kind {}) are only accepted on the root relay; geohash cells are public",
+                kind
+            )?,
+            RejectReason::DmNotAccepted { kind } => {
+                write!(f, "direct messages (kind {}) are not accepted by this relay", kind)?
+            }
+            RejectReason::RateLimited => f.write_str("too many events in this scope, slow down")?,
+            RejectReason::ScopeFull => f.write_str("this geohash cell is full")?,
+            RejectReason::StorageFull => f.write_str("relay storage full")?,
+            RejectReason::StoragePressure => f.write_str("relay is temporarily read-only (storage pressure)")?,
+            RejectReason::StorageFailure => f.write_str("internal storage failure")?,
+            RejectReason::ReadOnlyReplica => f.write_str("this relay is a read-only replica")?,
+            RejectReason::ReadOnly => f.write_str("relay is read-only")?,
+            RejectReason::Maintenance { message } => write!(f, "maintenance — {}", message)?,
+            RejectReason::SlowConsumer => {
+                f.write_str("connection closed because it is not reading messages fast enough")?
+            }
+            RejectReason::TooManySubscriptions { max } => write!(f, "too many subscriptions (max {})", max)?,
+            RejectReason::TooManyTags { letter, max } => write!(f, "too many {} tags (max {})", letter, max)?,
+            RejectReason::TooManyGeohashTags { max } => write!(f, "too many geohash tags (max {})", max)?,
+            RejectReason::BadGeohashFilter { detail } => f.write_str(detail)?,
+            RejectReason::InsufficientPow { scope, difficulty } => {
+                write!(f, "current difficulty for {} is {} bits", scope, difficulty)?
+            }
+            RejectReason::DuplicateContent => f.write_str("duplicate content detected")?,
+            RejectReason::ContentBlocked => f.write_str("content policy violation")?,
+            RejectReason::AuthRequired { write: true } => f.write_str("authenticate to post to this scope")?,
+            RejectReason::AuthRequired { write: false } => f.write_str("authenticate to read this scope")?,
+            RejectReason::TooManyCells => f.write_str("too many distinct cells from your address")?,
+            RejectReason::ExpirationRequired { max_days } => write!(
+                f,
+                "events in geohash cells need a NIP-40 expiration tag at most {} days ahead",
+                max_days
+            )?,
+            RejectReason::StatsTooSoon { retry_secs } => write!(f, "stats were just sent, retry in {}s", retry_secs)?,
+            RejectReason::BadDelegation => f.write_str("bad delegation")?,
+            RejectReason::NotInWebOfTrust => f.write_str("not in relay web of trust")?,
+            RejectReason::VanishElsewhere => f.write_str("request to vanish does not name this relay")?,
+            RejectReason::Vanished => f.write_str("author has requested to vanish from this relay")?,
+        }
+        write!(f, " [{}]", self.code())
+    }
+}
+
+/// Reason code token at the end of a relay message, if it has one
+pub fn reason_code(message: &str) -> Option<&str> {
+    let rest = message.strip_suffix(']')?;
+    let start = rest.rfind(" [")?;
+    Some(&rest[start + 2..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_reasons() -> Vec<(RejectReason, Prefix)> {
+        vec![
+            (RejectReason::InvalidSubdomain { subdomain: "foobar".to_string() }, Prefix::Restricted),
+            (
+                RejectReason::PrecisionNotAllowed { geohash: "drt2zby".to_string(), allowed: vec![5] },
+                Prefix::Restricted,
+            ),
+            (
+                RejectReason::RootRejectsGeotagged { suggested_url: "wss://drt2z.hashstr.com".to_string() },
+                Prefix::Restricted,
+            ),
+            (
+                RejectReason::WrongScope {
+                    geohash: "drt2z".to_string(),
+                    suggested_url: "wss://drt2z.hashstr.com".to_string(),
+                },
+                Prefix::Restricted,
+            ),
+            (RejectReason::PaymentRequired { payments_url: None }, Prefix::Restricted),
+            (
+                RejectReason::KindNotAllowed { kind: 1, on_root: true, allowed: vec![0, 3], profile: None },
+                Prefix::Restricted,
+            ),
+            (
+                RejectReason::PrecisionCapped { kind: 30315, geohash: "drt2zb".to_string(), max: 4 },
+                Prefix::Restricted,
+            ),
+            (RejectReason::DmRootOnly { kind: 4 }, Prefix::Restricted),
+            (RejectReason::DmNotAccepted { kind: 1059 }, Prefix::Restricted),
+            (RejectReason::RateLimited, Prefix::RateLimited),
+            (RejectReason::ScopeFull, Prefix::Error),
+            (RejectReason::StorageFull, Prefix::Error),
+            (RejectReason::StoragePressure, Prefix::Error),
+            (RejectReason::StorageFailure, Prefix::Error),
+            (RejectReason::ReadOnlyReplica, Prefix::Error),
+            (RejectReason::ReadOnly, Prefix::Error),
+            (RejectReason::Maintenance { message: "back soon".to_string() }, Prefix::Error),
+            (RejectReason::SlowConsumer, Prefix::Error),
+            (RejectReason::TooManySubscriptions { max: 20 }, Prefix::Restricted),
+            (RejectReason::TooManyTags { letter: 'p', max: 50 }, Prefix::Invalid),
+            (RejectReason::TooManyGeohashTags { max: 4 }, Prefix::Invalid),
+            (RejectReason::BadGeohashFilter { detail: "'drt2a' is not a geohash".to_string() }, Prefix::Invalid),
+            (RejectReason::InsufficientPow { scope: "drt2z".to_string(), difficulty: 16 }, Prefix::Pow),
+            (RejectReason::DuplicateContent, Prefix::Blocked),
+            (RejectReason::ContentBlocked, Prefix::Blocked),
+            (RejectReason::AuthRequired { write: true }, Prefix::AuthRequired),
+            (RejectReason::TooManyCells, Prefix::RateLimited),
+            (RejectReason::ExpirationRequired { max_days: 7 }, Prefix::Invalid),
+            (RejectReason::StatsTooSoon { retry_secs: 10 }, Prefix::RateLimited),
+            (RejectReason::BadDelegation, Prefix::Invalid),
+            (RejectReason::NotInWebOfTrust, Prefix::Restricted),
+            (RejectReason::VanishElsewhere, Prefix::Invalid),
+            (RejectReason::Vanished, Prefix::Restricted),
+        ]
+    }
+
+    #[test]
+    fn test_every_reason_has_its_prefix_and_code() {
+        for (reason, prefix) in all_reasons() {
+            assert_eq!(reason.prefix(), prefix, "{:?}", reason);
+            let message = reason.to_string();
+            assert!(message.starts_with(&format!("{}: ", prefix)), "{}", message);
+            assert_eq!(reason_code(&message), Some(reason.code()), "{}", message);
+        }
+    }
+
+    #[test]
+    fn test_codes_are_distinct() {
+        let reasons = all_reasons();
+        let codes: std::collections::HashSet<_> = reasons.iter().map(|(reason, _)| reason.code()).collect();
+        assert_eq!(codes.len(), reasons.len());
+    }
+
+    #[test]
+    fn test_messages() {
+        assert_eq!(
+            RejectReason::WrongScope {
+                geohash: "drt2z".to_string(),
+                suggested_url: "wss://drt2z.hashstr.com".to_string(),
+            }
+            .to_string(),
+            "restricted: events with geohash 'drt2z' must be posted to wss://drt2z.hashstr.com [wrong-scope]"
+        );
+        assert_eq!(
+            RejectReason::PaymentRequired { payments_url: Some("https://pay.example.com".to_string()) }.to_string(),
+            "restricted: payment required, see https://pay.example.com [payment-required]"
+        );
+        assert_eq!(
+            RejectReason::KindNotAllowed { kind: 10002, on_root: false, allowed: vec![1], profile: None }.to_string(),
+            "restricted: kind 10002 is not accepted on geohash cells (allowed kinds: 1) [kind-not-allowed]"
+        );
+        assert_eq!(
+            RejectReason::KindNotAllowed {
+                kind: 7,
+                on_root: false,
+                allowed: vec![1, 20000],
+                profile: Some(GeohashProfile::LocationChat),
+            }
+            .to_string(),
+            "restricted: kind 7 is not accepted on geohash cells (allowed kinds: 1, 20000 under the location-chat profile) [kind-not-allowed]"
+        );
+        assert_eq!(
+            RejectReason::PrecisionNotAllowed { geohash: "drt".to_string(), allowed: vec![5, 6] }.to_string(),
+            "restricted: geohash 'drt' has precision 3; this relay only serves precision 5, 6 [precision-not-allowed]"
+        );
+        assert_eq!(
+            RejectReason::PrecisionCapped { kind: 30315, geohash: "drt2zb".to_string(), max: 4 }.to_string(),
+            "restricted: kind 30315 events may not target cells finer than precision 4; post to 'drt2' instead of 'drt2zb' [precision-capped]"
+        );
+        assert_eq!(RejectReason::StorageFull.to_string(), "error: relay storage full [storage-full]");
+        assert_eq!(RejectReason::StorageFailure.to_string(), "error: internal storage failure [storage-failure]");
+        assert_eq!(RejectReason::ReadOnly.to_string(), "error: relay is read-only [read-only]");
+        assert_eq!(
+            RejectReason::Maintenance { message: "migrating storage".to_string() }.to_string(),
+            "error: maintenance — migrating storage [maintenance]"
+        );
+        assert_eq!(
+            RejectReason::TooManySubscriptions { max: 20 }.to_string(),
+            "restricted: too many subscriptions (max 20) [too-many-subscriptions]"
+        );
+        assert_eq!(
+            RejectReason::TooManyTags { letter: 'p', max: 50 }.to_string(),
+            "invalid: too many p tags (max 50) [too-many-tags]"
+        );
+        assert_eq!(
+            RejectReason::ExpirationRequired { max_days: 7 }.to_string(),
+            "invalid: events in geohash cells need a NIP-40 expiration tag at most 7 days ahead [expiration-required]"
+        );
+        assert_eq!(
+            RejectReason::InsufficientPow { scope: "drt2z".to_string(), difficulty: 16 }.to_string(),
+            "pow: current difficulty for drt2z is 16 bits [pow-required]"
+        );
+    }
+
+    #[test]
+    fn test_reason_code_parsing() {
+        assert_eq!(reason_code("error: this geohash cell is full [scope-full]"), Some("scope-full"));
+        assert_eq!(reason_code("restricted: no code here"), None);
+        assert_eq!(reason_code(""), None);
+    }
+}
@@ -0,0 +1,476 @@
+//! Relay assembly
+//!
+//! `build_relay` wires the event processor, middleware chain, storage and
+//! background tasks together and returns the HTTP app. The binary serves the
+//! result; integration tests serve it on an ephemeral port. Applications
+//! embedding the relay with their own scope rules call
+//! `build_relay_with_policy`.
+
+use anyhow::Result;
+use axum::Router;
+use nostr_sdk::prelude::*;
+use relay_builder::{
+    middlewares::{ErrorHandlingMiddleware, Nip40ExpirationMiddleware, NostrLoggerMiddleware},
+    RelayBuilder, RelayConfig as BuilderConfig, ScopeConfig,
+};
+use std::{sync::Arc, time::Duration};
+use tracing::{info, warn};
+use crate::activity::{spawn_activity_task, ScopeActivity};
+use crate::admissions::AdmissionList;
+use crate::archive::Archiver;
+use crate::api::ApiState;
+use crate::audit::AuditLog;
+use crate::auth::ScopedAuthMiddleware;
+use crate::blocklist::Blocklist;
+use crate::config::{QuotaPolicy, RelayConfig, StorageBackend};
+use crate::connection_stats::StatsNoticeMiddleware;
+use crate::decisions::DecisionMiddleware;
+use crate::count_cache::{spawn_count_invalidation, CountCache, CountMiddleware};
+use crate::connections::{ConnectionRegistry, ConnectionTrackingMiddleware, WelcomeMiddleware};
+use crate::expirations::{spawn_expiration_task, Expirations};
+use crate::vanish::{spawn_vanish_task, VanishList};
+use crate::tombstones::Tombstones;
+use crate::first_seen::{spawn_prune_task, FirstSeen};
+use crate::filter_limits::{FilterLimitMiddleware, FilterLimits};
+use crate::geo_filter::GeoFilterMiddleware;
+use crate::geoip::GeoIp;
+use crate::global_kinds::GlobalKindsMiddleware;
+use crate::ip_filter::{spawn_reload_on_sighup, IpFilter};
+use crate::known_events::DuplicateOkMiddleware;
+use crate::optional_middleware::{self, Optional};
+use crate::precision_caps::TruncatedOkMiddleware;
+use crate::nip05::Nip05Directory;
+use crate::processor::{ConnectionState, GeohashedEventProcessor};
+use crate::read_checks::ReadChecks;
+use crate::pow::{spawn_pow_controller, PowController, PowNoticeMiddleware};
+use crate::query_cache::{spawn_invalidation, QueryCache, QueryCacheMiddleware};
+use crate::quota::{spawn_quota_task, ScopeQuota};
+use crate::rate_limit::ScopeRateLimitMiddleware;
+use crate::scope_counters::ScopeCounterMap;
+use crate::scope_policy::{DefaultScopePolicy, ScopePolicy};
+use crate::scope_residency::{spawn_scope_eviction, ScopeResidency};
+use crate::replication::{spawn_follower, ReplicationFollower, ReplicationLeader};
+use crate::retention::{spawn_retention_task, RetentionPolicy};
+use crate::self_publish;
+use crate::storage::{
+    spawn_disk_watermark, spawn_storage_monitor, DiskWatermark, StorageFullMiddleware, StorageMonitor, WriteFailures,
+};
+use crate::slow_consumer::{OutboundBudget, SlowConsumerMiddleware};
+use crate::live::{LiveEvents, LiveEventsMiddleware};
+use crate::log_summary::{spawn_log_summary, LogSummary};
+use crate::receipts::{self, ReceiptIssuer};
+use crate::maintenance::Maintenance;
+use crate::memory_backend::{spawn_ring_buffers, RingBuffers};
+use crate::mqtt::{self, MqttBridge};
+use crate::sse::SseFeed;
+use crate::webhooks::{self, WebhookDispatcher};
+use crate::wot::{spawn_wot_task, RelayFollows, StoreFollows, WebOfTrust};
+use crate::server::create_app;
+use crate::stats::{self, StatsCache};
+use crate::status_notes::{spawn_status_notes, StatusNotes};
+use crate::store::{open_storage, LmdbStore, ScopeStore};
+use crate::subscriptions::SubscriptionLimitMiddleware;
+use crate::usage_report::{spawn_usage_reports, UsageReporter, UsageTally};
+
+/// How often LMDB map and disk usage are sampled
+const STORAGE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often expired cell events are deleted
+const RETENTION_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// A fully built relay, ready to be served
+pub struct BuiltRelay {
+    pub app: Router,
+    pub store: Arc<dyn ScopeStore>,
+    pub connections: Arc<ConnectionRegistry>,
+    pub stats: Arc<StatsCache>,
+}
+
+/// Builds the relay described by `config`, signing with `keys`
+pub async fn build_relay(config: &RelayConfig, keys: Keys) -> Result<BuiltRelay> {
+    build_relay_with_policy(config, keys, DefaultScopePolicy).await
+}
+
+/// Builds the relay described by `config` with its scopes decided by `policy`
+pub async fn build_relay_with_policy<P>(config: &RelayConfig, keys: Keys, policy: P) -> Result<BuiltRelay>
+where
+    P: ScopePolicy + Clone,
+{
+    let shared_config = Arc::new(config.clone());
+    let shared_policy: Arc<dyn ScopePolicy> = Arc::new(policy.clone());
+
+    // Watch the LMDB map so a full disk degrades to read-only instead of crashing
+    let storage = Arc::new(StorageMonitor::new(config));
+
+    // Go read-only while the volume itself is nearly full
+    let disk = Arc::new(DiskWatermark::new(config));
+
+    // Answer other failed writes with OK false and degrade /health if they repeat
+    let write_failures = Arc::new(WriteFailures::new(config));
+
+    // Keep any one cell from filling the map, archiving what's evicted if asked
+    let mut quota = ScopeQuota::new(config);
+    if config.quota_policy == QuotaPolicy::Archive {
+        quota = quota.with_archiver(Archiver::for_config(config)?);
+    }
+    let quota = Arc::new(quota);
+
+    // Open the database up front so the HTTP API can read from it too
+    let database = open_storage(config)?;
+    let store: Arc<dyn ScopeStore> = Arc::new(LmdbStore::new(database.clone()));
+
+    // Pubkeys allowed to write to paid scopes, kept next to the database
+    let admissions = Arc::new(AdmissionList::for_config(config)?);
+
+    // Maintenance flag, toggled through the admin API and kept next to the database
+    let maintenance = Arc::new(Maintenance::for_config(config)?);
+    if let Some(message) = maintenance.message() {
+        warn!("Starting in maintenance mode, refusing events: {}", message);
+    }
+    if maintenance.is_read_only() {
+        warn!("Starting read-only, refusing events until cleared with DELETE /api/read-only");
+    }
+
+    // Content blocklist, updated through the admin API and kept next to the database
+    let blocklist = Arc::new(Blocklist::for_config(config)?);
+
+    // Root page cell suggestions; a configured database must open
+    let geoip = Arc::new(GeoIp::for_config(config)?);
+
+    // Client IP allow/deny lists, re-read on SIGHUP
+    let ip_filter = Arc::new(IpFilter::for_config(config));
+    spawn_reload_on_sighup(ip_filter.clone());
+
+    // When each event first reached the relay, kept next to the database
+    let first_seen = Arc::new(FirstSeen::for_config(config)?);
+
+    // Relay-side and NIP-40 expirations of stored events
+    let expirations = Arc::new(Expirations::for_config(config)?);
+
+    // Events hidden by kind 5 deletions, in tombstone deletion mode
+    let tombstones = Arc::new(Tombstones::for_config(config)?);
+
+    // NIP-62 requests to vanish, kept next to the database
+    let vanished = Arc::new(VanishList::for_config(config)?);
+
+    // Keys within reach of the seeds' follows, when write gating is on
+    let wot = Arc::new(WebOfTrust::for_config(config));
+    let wot_interval = Duration::from_secs(config.wot_refresh_secs);
+    if config.wot_bootstrap_relays.is_empty() {
+        spawn_wot_task(wot.clone(), StoreFollows::new(store.clone()), wot_interval);
+    } else {
+        spawn_wot_task(wot.clone(), RelayFollows::new(config.wot_bootstrap_relays.clone()), wot_interval);
+    }
+
+    // Recently active cells, for the scope_active gauges and /api/scopes
+    let activity = Arc::new(ScopeActivity::new());
+    spawn_activity_task(activity.clone());
+
+    // Rate-limit buckets and accepted counts, shared by every connection
+    // to a scope
+    let scope_counters = Arc::new(ScopeCounterMap::with_policy(shared_config.clone(), shared_policy.clone()));
+    // Proof-of-work difficulty per scope, raised for busy cells if adaptive
+    let pow = Arc::new(PowController::for_config(config).with_counters(scope_counters.clone()));
+    spawn_pow_controller(pow.clone(), Duration::from_secs(config.pow_interval_secs));
+
+    // Durable record of accepted and rejected events, if configured
+    let audit = AuditLog::for_config(config)?;
+
+    // Newly stored events, for webhooks, MQTT and the SSE feed
+    let live = Arc::new(LiveEvents::new());
+    webhooks::spawn_listener(Arc::new(WebhookDispatcher::for_config(config)), &live);
+    if let Some(bridge) = MqttBridge::for_config(config)? {
+        mqtt::spawn_listener(Arc::new(bridge), &live);
+    }
+
+    // Results of repeated REQs and COUNTs, dropped as their scope changes
+    let query_cache = Arc::new(QueryCache::for_config(config));
+    if query_cache.is_enabled() {
+        spawn_invalidation(query_cache.clone(), &live);
+    }
+    let count_cache = Arc::new(CountCache::for_config(config));
+    if count_cache.is_enabled() {
+        spawn_count_invalidation(count_cache.clone(), &live);
+    }
+
+    // Open hot scopes up front and release the state of idle ones
+    let residency = Arc::new(ScopeResidency::for_config(config));
+    residency.register(query_cache.clone());
+    residency.register(count_cache.clone());
+    residency.register(scope_counters.clone());
+    let preloaded = residency.preload_all(store.as_ref(), &config.preload_scopes).await?;
+    if preloaded > 0 {
+        info!("Preloaded {} scopes", preloaded);
+    }
+    spawn_scope_eviction(residency.clone());
+
+    // Per-scope counts for the daily usage report
+    let usage = Arc::new(UsageTally::for_config(config));
+
+    // Periodic per-scope counts in place of a log line per event
+    let log_summary = Arc::new(LogSummary::for_config(config));
+    spawn_log_summary(log_summary.clone());
+
+    // Create the event processor (rate limiting now handled by middleware)
+    let processor = GeohashedEventProcessor::with_policy(shared_config.clone(), policy)
+        .with_storage(storage.clone())
+        .with_disk_watermark(disk.clone())
+        .with_quota(quota.clone())
+        .with_admissions(admissions.clone())
+        .with_maintenance(maintenance.clone())
+        .with_activity(activity.clone())
+        .with_pow(pow.clone())
+        .with_blocklist(blocklist.clone())
+        .with_store(store.clone())
+        .with_first_seen(first_seen.clone())
+        .with_wot(wot.clone())
+        .with_expirations(expirations.clone())
+        .with_tombstones(tombstones.clone())
+        .with_vanished(vanished.clone())
+        .with_residency(residency)
+        .with_usage(usage.clone())
+        .with_log_summary(log_summary.clone())
+        .with_audit(audit);
+
+    storage.check();
+    spawn_storage_monitor(storage.clone(), STORAGE_CHECK_INTERVAL);
+    disk.check();
+    spawn_disk_watermark(disk.clone(), STORAGE_CHECK_INTERVAL);
+
+    // Configure the relay with subdomain support
+    let mut relay_config = BuilderConfig::new(
+        &config.relay_url,
+        database.clone(),
+        keys.clone(),
+    );
+
+    if config.auth_enabled() {
+        info!(
+            "NIP-42 auth required for root writes: {}, cell writes: {}, cell reads: {}",
+            config.root_write_auth, config.geohash_write_auth, config.geohash_read_auth
+        );
+    }
+
+    if config.dev_scope_query_param {
+        warn!("DEV_SCOPE_QUERY_PARAM is enabled: any client can pick its scope with ?scope=. Do not run this in production");
+    }
+
+    // Configure subdomain support - extract subdomains from host header. This
+    // must agree with the HTTP routes' host parsing
+    relay_config.scope_config = ScopeConfig::Subdomain {
+        base_domain_parts: config.base_domain_parts(), // e.g., "example.com" has 2 parts
+    };
+
+    // Set limits on the config
+    relay_config.max_subscriptions = config.max_subscriptions_per_connection;
+    relay_config.max_limit = config.max_limit_per_filter;
+    // max_event_size caps websocket messages in server::upgrade
+
+    // Build the relay with middleware
+    let builder = RelayBuilder::<ConnectionState>::new(relay_config)
+        .custom_state::<ConnectionState>()
+        .event_processor(processor.clone())
+        .without_defaults(); // We'll add middleware manually
+
+    // Build with middleware
+    info!("Building relay with middleware...");
+    optional_middleware::validate(config)?;
+    if config.enable_nip40_expiration {
+        info!("- NIP-40 expiration checking enabled");
+    }
+    let disabled = optional_middleware::disabled(config);
+    if !disabled.is_empty() {
+        info!("- Middleware switched off: {}", disabled.join(", "));
+    }
+
+    let connections = Arc::new(ConnectionRegistry::new());
+
+    // Per-scope aggregates for /api/stats, also sizing scopes for default filter limits
+    let stats_cache = Arc::new(StatsCache::new());
+
+    // Pop-up relays keep only each scope's newest events
+    if config.storage_backend == StorageBackend::Memory {
+        info!("Memory storage: keeping the newest {} events per scope", config.memory_events_per_scope);
+        spawn_ring_buffers(Arc::new(RingBuffers::new(config.memory_events_per_scope)), store.clone(), &live);
+    }
+
+    // Read checks for the middleware that answers REQs and COUNTs itself
+    let read_checks = ReadChecks::new(processor.clone(), keys.public_key());
+
+    // Checked against optional_middleware::CHAIN once built
+    let mut chain_type = "";
+    let handler = builder.build_with(|chain| {
+        // Debug: Print the type of the base chain (should have RelayMiddleware as innermost)
+        let chain_step1 = chain
+            .with(Optional::new(
+                ScopeRateLimitMiddleware::new(scope_counters.clone())
+                    .with_log_summary(log_summary.clone())
+                    .with_authed_limit(config.authed_events_per_minute),
+                config.enable_rate_limit,
+            ));
+
+        // At this point, chain is: ScopeRateLimitMiddleware -> RelayMiddleware -> End
+        let chain_step2 = chain_step1.with(Optional::new(Nip40ExpirationMiddleware, config.enable_nip40_expiration));
+        // Now: Nip40ExpirationMiddleware -> ScopeRateLimitMiddleware -> RelayMiddleware -> End
+
+        let chain_step3 = chain_step2.with(StorageFullMiddleware::new(storage.clone(), write_failures.clone()));
+        // Now: StorageFullMiddleware -> Nip40ExpirationMiddleware -> ScopeRateLimitMiddleware -> RelayMiddleware -> End
+
+        let chain_step4 = chain_step3.with(Optional::new(ErrorHandlingMiddleware::new(), config.enable_error_handling));
+        // Now: ErrorHandlingMiddleware -> StorageFullMiddleware -> Nip40ExpirationMiddleware -> ... -> End
+
+        let chain_step5 = chain_step4
+            .with(QueryCacheMiddleware::new(query_cache.clone(), store.clone(), read_checks.clone()))
+            .with(
+                CountMiddleware::new(count_cache.clone(), store.clone(), read_checks.clone())
+                    .with_tombstones(tombstones.clone())
+                    .with_expirations(expirations.clone()),
+            );
+        // Now: CountMiddleware -> QueryCacheMiddleware -> ErrorHandlingMiddleware -> ... -> End
+
+        let chain_step6 = chain_step5
+            .with(GlobalKindsMiddleware::new(store.clone(), read_checks.clone(), &config))
+            .with(GeoFilterMiddleware::new(store.clone(), read_checks.clone(), &config))
+            .with(FilterLimitMiddleware::new(FilterLimits::for_config(config), stats_cache.clone()));
+        // Now: FilterLimitMiddleware -> GeoFilterMiddleware -> GlobalKindsMiddleware -> CountMiddleware -> ... -> End
+
+        let chain_step7 = chain_step6.with(SubscriptionLimitMiddleware::new(
+            config.max_subscriptions_per_connection,
+            config.max_concurrent_queries_per_connection,
+        ));
+        // Now: SubscriptionLimitMiddleware -> FilterLimitMiddleware -> ... -> End
+
+        let chain_step8 = chain_step7.with(WelcomeMiddleware::new(shared_config.clone()));
+        // Now: WelcomeMiddleware -> SubscriptionLimitMiddleware -> ... -> End
+
+        let chain_step9 = chain_step8.with(ConnectionTrackingMiddleware::new(connections.clone()));
+        // Now: ConnectionTrackingMiddleware -> WelcomeMiddleware -> ... -> End
+
+        let chain_step10 = chain_step9.with(SlowConsumerMiddleware::new(OutboundBudget {
+            max_messages: config.max_outbound_messages,
+            max_bytes: config.max_outbound_bytes,
+        }));
+        // Now: SlowConsumerMiddleware -> ConnectionTrackingMiddleware -> ... -> End
+
+        let chain_step11 = chain_step10.with(LiveEventsMiddleware::new(live.clone(), &config.global_kinds));
+        // Now: LiveEventsMiddleware -> SlowConsumerMiddleware -> ... -> End
+
+        let chain_step12 = chain_step11.with(PowNoticeMiddleware);
+        // Now: PowNoticeMiddleware -> LiveEventsMiddleware -> ... -> End
+
+        let chain_step13 = chain_step12.with(ScopedAuthMiddleware::new(shared_config.clone()));
+        // Now: ScopedAuthMiddleware -> PowNoticeMiddleware -> ... -> End
+
+        let chain_step14 = chain_step13.with(StatsNoticeMiddleware);
+        // Now: StatsNoticeMiddleware -> ScopedAuthMiddleware -> ... -> End
+
+        let chain_step15 = chain_step14.with(DuplicateOkMiddleware);
+        // Now: DuplicateOkMiddleware -> StatsNoticeMiddleware -> ... -> End
+
+        let chain_step16 = chain_step15.with(TruncatedOkMiddleware);
+        // Now: TruncatedOkMiddleware -> DuplicateOkMiddleware -> ... -> End
+
+        let chain_step17 = chain_step16.with(DecisionMiddleware);
+        // Now: DecisionMiddleware -> TruncatedOkMiddleware -> ... -> End
+
+        let final_chain = chain_step17.with(Optional::new(NostrLoggerMiddleware::new(), config.enable_logger_middleware));
+        // Final: NostrLoggerMiddleware -> DecisionMiddleware -> TruncatedOkMiddleware -> DuplicateOkMiddleware -> StatsNoticeMiddleware -> ScopedAuthMiddleware -> PowNoticeMiddleware -> LiveEventsMiddleware -> SlowConsumerMiddleware -> ConnectionTrackingMiddleware -> WelcomeMiddleware -> SubscriptionLimitMiddleware -> FilterLimitMiddleware -> GeoFilterMiddleware -> GlobalKindsMiddleware -> CountMiddleware -> QueryCacheMiddleware -> ErrorHandlingMiddleware -> StorageFullMiddleware -> Nip40ExpirationMiddleware -> ScopeRateLimitMiddleware -> RelayMiddleware -> End
+
+        // Print the type name (this will be very long!)
+        chain_type = std::any::type_name_of_val(&final_chain);
+        info!("Middleware chain type: {}", chain_type);
+
+        final_chain
+    }).await?;
+    optional_middleware::check_built(chain_type)?;
+
+    // Publish the relay's own profile and relay list now that storage is up
+    if config.self_publish {
+        if let Err(e) = self_publish::publish_all(store.as_ref(), &processor, &keys, config).await {
+            warn!("Failed to publish relay events: {}", e);
+        }
+    }
+
+    // Recount cell usage and evict from over-quota cells
+    spawn_quota_task(quota, store.clone(), Duration::from_secs(config.quota_refresh_secs));
+
+    // Delete cell events past their retention
+    spawn_retention_task(store.clone(), RetentionPolicy::for_config(config), RETENTION_INTERVAL);
+    spawn_expiration_task(store.clone(), expirations, RETENTION_INTERVAL);
+    spawn_prune_task(store.clone(), first_seen.clone(), RETENTION_INTERVAL);
+
+    // Delete the events of authors who asked to vanish, across every scope
+    spawn_vanish_task(store.clone(), vanished.clone());
+
+    // Periodically aggregate per-scope stats for /api/stats
+    stats::spawn_stats_task(
+        store.clone(),
+        stats_cache.clone(),
+        Duration::from_secs(config.stats_interval_secs),
+    );
+
+    // Daily per-scope digest for operators without Prometheus
+    if let Some(mut reporter) = UsageReporter::for_config(config, usage, stats_cache.clone()) {
+        if config.usage_report_publish {
+            reporter = reporter.with_publisher(processor.clone(), keys.clone(), store.clone());
+        }
+        spawn_usage_reports(Arc::new(reporter));
+    }
+
+    // Relay-signed receipts for accepted events, if enabled
+    if let Some(issuer) = ReceiptIssuer::for_config(config, processor.clone(), keys.clone(), store.clone(), live.clone()) {
+        receipts::spawn_listener(Arc::new(issuer), &live);
+    }
+
+    // Heartbeat notes in active cells, if enabled
+    if let Some(notes) = StatusNotes::for_config(config, processor.clone(), keys.clone(), store.clone(), stats_cache.clone()) {
+        spawn_status_notes(Arc::new(notes));
+    }
+
+    // Followers apply the leader's stream; leaders serve it
+    let replica = ReplicationFollower::for_config(config, store.clone())?
+        .map(|follower| Arc::new(follower.with_vanished(vanished.clone())));
+    if let Some(replica) = &replica {
+        info!("Read-only replica of {}", config.replicate_from.as_deref().unwrap_or_default());
+        spawn_follower(replica.clone());
+    }
+    let replication_leader = match (&config.replication_token, &replica) {
+        (Some(token), None) => Some(Arc::new(
+            ReplicationLeader::new(token.clone(), store.clone(), live.clone()).with_vanished(vanished),
+        )),
+        _ => None,
+    };
+
+    let api_state = ApiState {
+        config: shared_config.clone(),
+        stats: stats_cache.clone(),
+        connections: connections.clone(),
+        store: store.clone(),
+        disk,
+        write_failures,
+        admissions,
+        nip05: Arc::new(Nip05Directory::new(config, &keys.public_key())),
+        sse: Arc::new(SseFeed::new(shared_config.clone(), store.clone(), live).with_policy(shared_policy)),
+        replication_leader,
+        replica,
+        maintenance,
+        activity,
+        pow,
+        blocklist,
+        first_seen,
+        tombstones,
+        wot,
+        ip_filter,
+        geoip,
+    };
+
+    // Create the Axum app
+    let app = create_app(handler, config, api_state);
+
+    Ok(BuiltRelay {
+        app,
+        store,
+        connections,
+        stats: stats_cache,
+    })
+}
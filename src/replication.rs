@@ -0,0 +1,578 @@
+//! Leader-follower replication
+//!
+//! A leader with `replication_token` set serves `POST /replication/stream`.
+//! A follower posts its per-scope cursors (`{"since": {"drt2z": 1700000000}}`)
+//! and gets back an NDJSON body that never ends: every stored event in each
+//! scope since its cursor (less `CATCH_UP_OVERLAP_SECS`; everything for
+//! scopes it doesn't know), a `caught_up` line, then every event published
+//! on `LiveEvents` as it is stored, with heartbeats while idle.
+//!
+//! A follower (`replicate_from` set) refuses client writes with
+//! `RejectReason::ReadOnlyReplica` and saves streamed events through
+//! `store_admin::import_events`. Its cursors start at the newest event of
+//! each local scope and advance as events are applied, so after a dropped
+//! connection it reconnects with backoff and only catches up on what it
+//! missed. Events replayed by the overlap are saved again, which is a no-op.
+//!
+//! Requests to vanish are never stored, so they travel as their own lines:
+//! every recorded request ahead of catch-up, then each new one as the
+//! leader's `VanishList` announces it. A follower records them in its own
+//! list, which has its vanish task clear its copy, and skips streamed
+//! events a request covers.
+//!
+//! Lag is how long ago, by the leader's clock, the follower was last known
+//! to be caught up, to within a heartbeat.
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use futures::channel::mpsc;
+use futures::SinkExt;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, info, warn};
+use crate::api::constant_time_eq;
+use crate::config::RelayConfig;
+use crate::live::{LiveEvents, StoredEvent};
+use crate::store::{scope_from_label, scope_label, ScopeStore};
+use crate::store_admin::{self, ScopePager, PAGE_SIZE};
+use crate::vanish::VanishList;
+
+/// How far before a follower's cursor catch-up starts, for events stored
+/// out of `created_at` order around the time it disconnected
+pub const CATCH_UP_OVERLAP_SECS: u64 = 60;
+/// Idle time after which the leader sends a heartbeat
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// Silence after which a follower gives up on the connection
+const LEADER_TIMEOUT: Duration = Duration::from_secs(20);
+/// Lines buffered per follower before the leader waits on it
+const STREAM_BUFFER: usize = 256;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// One line of the replication stream
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReplicationMessage {
+    Event { scope: String, event: Box<Event> },
+    /// `pubkey` asked to vanish, up to `until`
+    Vanish { pubkey: PublicKey, until: u64 },
+    /// Catch-up is done; everything after this is live
+    CaughtUp { at: u64 },
+    Heartbeat { at: u64 },
+}
+
+impl ReplicationMessage {
+    fn line(&self) -> Bytes {
+        let mut line = serde_json::to_vec(self).unwrap_or_default();
+        line.push(b'\n');
+        Bytes::from(line)
+    }
+}
+
+/// Body of `POST /replication/stream`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StreamRequest {
+    /// Newest `created_at` the follower has per scope label
+    #[serde(default)]
+    pub since: HashMap<String, u64>,
+}
+
+type LineSender = mpsc::Sender<Result<Bytes, Infallible>>;
+
+/// Serves the replication stream to followers
+pub struct ReplicationLeader {
+    token: String,
+    store: Arc<dyn ScopeStore>,
+    live: Arc<LiveEvents>,
+    vanished: Arc<VanishList>,
+}
+
+impl ReplicationLeader {
+    pub fn new(token: impl Into<String>, store: Arc<dyn ScopeStore>, live: Arc<LiveEvents>) -> Self {
+        Self {
+            token: token.into(),
+            store,
+            live,
+            vanished: Arc::new(VanishList::in_memory(0)),
+        }
+    }
+
+    /// Streams the requests to vanish recorded in `vanished`
+    pub fn with_vanished(mut self, vanished: Arc<VanishList>) -> Self {
+        self.vanished = vanished;
+        self
+    }
+
+    fn authorized(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get(header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .is_some_and(|presented| constant_time_eq(presented.trim().as_bytes(), self.token.as_bytes()))
+    }
+
+    /// Catch-up then live events until the follower goes away or falls
+    /// too far behind
+    async fn stream(
+        &self,
+        request: &StreamRequest,
+        live: &mut broadcast::Receiver<Arc<StoredEvent>>,
+        vanishing: &mut broadcast::Receiver<(PublicKey, u64)>,
+        sender: &mut LineSender,
+    ) -> Result<()> {
+        // Ahead of the events, so the follower skips the ones they cover
+        for (pubkey, until) in self.vanished.requests() {
+            send(sender, &ReplicationMessage::Vanish { pubkey, until }).await?;
+        }
+        for scope in self.store.scopes().await? {
+            let since = request
+                .since
+                .get(&scope_label(&scope))
+                .map_or(0, |since| since.saturating_sub(CATCH_UP_OVERLAP_SECS));
+            let mut pager = ScopePager::new(scope.clone(), PAGE_SIZE).since(Timestamp::from(since));
+            while let Some(page) = pager.next_page(self.store.as_ref()).await? {
+                for event in page {
+                    let message = ReplicationMessage::Event { scope: scope_label(&scope), event: Box::new(event) };
+                    send(sender, &message).await?;
+                }
+            }
+        }
+        send(sender, &ReplicationMessage::CaughtUp { at: now_secs() }).await?;
+
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            tokio::select! {
+                received = live.recv() => match received {
+                    Ok(stored) => {
+                        let message = ReplicationMessage::Event {
+                            scope: scope_label(&stored.scope),
+                            event: Box::new(stored.event.clone()),
+                        };
+                        send(sender, &message).await?;
+                        heartbeat.reset();
+                    }
+                    // The follower reconnects and catches up from its cursors
+                    Err(RecvError::Lagged(missed)) => bail!("follower fell {} events behind", missed),
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                received = vanishing.recv() => match received {
+                    Ok((pubkey, until)) => {
+                        send(sender, &ReplicationMessage::Vanish { pubkey, until }).await?;
+                        heartbeat.reset();
+                    }
+                    Err(RecvError::Lagged(missed)) => bail!("follower fell {} requests to vanish behind", missed),
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                _ = heartbeat.tick() => send(sender, &ReplicationMessage::Heartbeat { at: now_secs() }).await?,
+            }
+        }
+    }
+}
+
+async fn send(sender: &mut LineSender, message: &ReplicationMessage) -> Result<()> {
+    sender.send(Ok(message.line())).await.map_err(|_| anyhow!("follower disconnected"))
+}
+
+async fn stream_handler(
+    State(leader): State<Arc<ReplicationLeader>>,
+    headers: HeaderMap,
+    Json(request): Json<StreamRequest>,
+) -> Response {
+    if !leader.authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    // Subscribe before catching up so nothing stored in between is missed
+    let mut live = leader.live.subscribe();
+    let mut vanishing = leader.vanished.subscribe();
+    let (mut sender, receiver) = mpsc::channel(STREAM_BUFFER);
+    tokio::spawn(async move {
+        metrics::gauge!("relay_replication_followers").increment(1.0);
+        if let Err(e) = leader.stream(&request, &mut live, &mut vanishing, &mut sender).await {
+            debug!("Replication stream ended: {}", e);
+        }
+        metrics::gauge!("relay_replication_followers").decrement(1.0);
+    });
+    ([(header::CONTENT_TYPE, "application/x-ndjson")], Body::from_stream(receiver)).into_response()
+}
+
+pub fn router(leader: Arc<ReplicationLeader>) -> Router {
+    Router::new().route("/replication/stream", post(stream_handler)).with_state(leader)
+}
+
+/// Replication state reported in `/health`
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplicationHealth {
+    pub leader: String,
+    pub connected: bool,
+    /// None until the first catch-up completes
+    pub lag_secs: Option<u64>,
+    pub applied_events: u64,
+}
+
+/// Applies a leader's stream to the local store
+pub struct ReplicationFollower {
+    leader: String,
+    token: String,
+    store: Arc<dyn ScopeStore>,
+    vanished: Arc<VanishList>,
+    client: reqwest::Client,
+    /// Newest applied `created_at` per scope label
+    cursors: Mutex<HashMap<String, u64>>,
+    cursors_loaded: AtomicBool,
+    connected: AtomicBool,
+    caught_up: AtomicBool,
+    /// Leader time the follower was last caught up at (0 = never)
+    synced_at: AtomicU64,
+    applied: AtomicU64,
+}
+
+impl ReplicationFollower {
+    pub fn new(leader: impl Into<String>, token: impl Into<String>, store: Arc<dyn ScopeStore>) -> Result<Self> {
+        let client = follower_client(None)?;
+        Ok(Self {
+            leader: leader.into().trim_end_matches('/').to_string(),
+            token: token.into(),
+            store,
+            vanished: Arc::new(VanishList::in_memory(0)),
+            client,
+            cursors: Mutex::new(HashMap::new()),
+            cursors_loaded: AtomicBool::new(false),
+            connected: AtomicBool::new(false),
+            caught_up: AtomicBool::new(false),
+            synced_at: AtomicU64::new(0),
+            applied: AtomicU64::new(0),
+        })
+    }
+
+    /// Follower for `replicate_from`, if this relay is one
+    pub fn for_config(config: &RelayConfig, store: Arc<dyn ScopeStore>) -> Result<Option<Self>> {
+        let Some(leader) = &config.replicate_from else {
+            return Ok(None);
+        };
+        let Some(token) = &config.replication_token else {
+            bail!("REPLICATION_TOKEN is required when REPLICATE_FROM is set");
+        };
+        let follower = Self::new(leader.clone(), token.clone(), store)?;
+        match &config.socks_proxy {
+            Some(proxy) => follower.with_socks_proxy(proxy).map(Some),
+            None => Ok(Some(follower)),
+        }
+    }
+
+    /// Connects to the leader through the SOCKS5 proxy at `proxy`
+    ///
+    /// `socks5h://` resolves the leader's name on the proxy, which Tor
+    /// needs for onion addresses.
+    pub fn with_socks_proxy(mut self, proxy: &str) -> Result<Self> {
+        self.client = follower_client(Some(proxy))?;
+        Ok(self)
+    }
+
+    /// Records the leader's requests to vanish in `vanished`, whose vanish
+    /// task clears them locally
+    pub fn with_vanished(mut self, vanished: Arc<VanishList>) -> Self {
+        self.vanished = vanished;
+        self
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    pub fn lag_secs(&self) -> Option<u64> {
+        match self.synced_at.load(Ordering::Relaxed) {
+            0 => None,
+            at => Some(now_secs().saturating_sub(at)),
+        }
+    }
+
+    pub fn health(&self) -> ReplicationHealth {
+        ReplicationHealth {
+            leader: self.leader.clone(),
+            connected: self.is_connected(),
+            lag_secs: self.lag_secs(),
+            applied_events: self.applied.load(Ordering::Relaxed),
+        }
+    }
+
+    fn advance(&self, scope: String, created_at: u64) {
+        // A future-dated event must not push the cursor past now
+        let created_at = created_at.min(now_secs());
+        let mut cursors = self.cursors.lock();
+        let cursor = cursors.entry(scope).or_default();
+        *cursor = (*cursor).max(created_at);
+    }
+
+    /// Seeds cursors from the newest event in each local scope
+    async fn load_cursors(&self) -> Result<()> {
+        if self.cursors_loaded.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        for scope in self.store.scopes().await? {
+            if let Some(newest) = store_admin::newest_created_at(self.store.as_ref(), &scope).await? {
+                self.advance(scope_label(&scope), newest);
+            }
+        }
+        self.cursors_loaded.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn apply(&self, message: ReplicationMessage) -> Result<()> {
+        match message {
+            ReplicationMessage::Event { scope, event } => {
+                let Some(parsed) = scope_from_label(&scope) else {
+                    bail!("leader sent an event for invalid scope '{}'", scope);
+                };
+                let created_at = event.created_at.as_u64();
+                if self.vanished.erases(&event) {
+                    self.advance(scope, created_at);
+                    return Ok(());
+                }
+                let report = store_admin::import_events(self.store.as_ref(), &parsed, vec![*event]).await?;
+                if report.invalid > 0 {
+                    warn!("Leader sent an event with an invalid signature for {}", scope);
+                }
+                self.applied.fetch_add(report.imported as u64, Ordering::Relaxed);
+                metrics::counter!("relay_replication_applied_events_total").increment(report.imported as u64);
+                self.advance(scope, created_at);
+            }
+            ReplicationMessage::Vanish { pubkey, until } => {
+                // Catch-up resends every request; only new ones need a sweep
+                if !self.vanished.covers(&pubkey, until) {
+                    info!("Leader {} reports that {} asked to vanish", self.leader, pubkey);
+                    self.vanished.record(pubkey, until, now_secs());
+                }
+            }
+            ReplicationMessage::CaughtUp { at } => {
+                info!("Caught up with leader {}", self.leader);
+                self.caught_up.store(true, Ordering::Relaxed);
+                self.synced_at.store(at, Ordering::Relaxed);
+            }
+            ReplicationMessage::Heartbeat { at } => {
+                if self.caught_up.load(Ordering::Relaxed) {
+                    self.synced_at.store(at, Ordering::Relaxed);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Streams from the leader until the connection drops
+    async fn follow(&self) -> Result<()> {
+        self.load_cursors().await?;
+        let request = StreamRequest { since: self.cursors.lock().clone() };
+        let mut response = self
+            .client
+            .post(format!("{}/replication/stream", self.leader))
+            .bearer_auth(&self.token)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&request)?)
+            .send()
+            .await?
+            .error_for_status()?;
+        info!("Replicating from {}", self.leader);
+        self.connected.store(true, Ordering::Relaxed);
+        self.caught_up.store(false, Ordering::Relaxed);
+        metrics::gauge!("relay_replication_connected").set(1.0);
+
+        let mut buffer = Vec::new();
+        loop {
+            let chunk = tokio::time::timeout(LEADER_TIMEOUT, response.chunk())
+                .await
+                .context("leader stopped sending heartbeats")??;
+            let Some(chunk) = chunk else {
+                return Ok(());
+            };
+            buffer.extend_from_slice(&chunk);
+            while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                let message = serde_json::from_slice(&line).context("invalid replication message")?;
+                self.apply(message).await?;
+            }
+        }
+    }
+}
+
+/// HTTP client for the leader connection, optionally through a proxy
+fn follower_client(proxy: Option<&str>) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(concat!("geohashed-relay/", env!("CARGO_PKG_VERSION")))
+        .connect_timeout(Duration::from_secs(10));
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy).with_context(|| format!("invalid SOCKS proxy '{}'", proxy))?);
+    }
+    Ok(builder.build()?)
+}
+
+/// Spawns the follower's connect/backoff loop and lag gauge
+pub fn spawn_follower(follower: Arc<ReplicationFollower>) {
+    let gauge = follower.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Some(lag) = gauge.lag_secs() {
+                metrics::gauge!("relay_replication_lag_seconds").set(lag as f64);
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            match follower.follow().await {
+                Ok(()) => info!("Leader {} closed the replication stream", follower.leader),
+                Err(e) => warn!("Replication from {} failed: {:#}", follower.leader, e),
+            }
+            if follower.connected.swap(false, Ordering::Relaxed) {
+                backoff = INITIAL_BACKOFF;
+            }
+            metrics::gauge!("relay_replication_connected").set(0.0);
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use futures::StreamExt;
+    use nostr_lmdb::Scope;
+
+    fn note_at(keys: &Keys, created_at: u64) -> Event {
+        EventBuilder::text_note(format!("note {}", created_at))
+            .custom_created_at(Timestamp::from(created_at))
+            .sign_with_keys(keys)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_message_lines() {
+        let event = note_at(&Keys::generate(), 1000);
+        let message = ReplicationMessage::Event { scope: "drt2z".to_string(), event: Box::new(event) };
+        let line = message.line();
+        assert!(line.ends_with(b"\n"));
+        assert_eq!(serde_json::from_slice::<ReplicationMessage>(&line).unwrap(), message);
+
+        let heartbeat: serde_json::Value = serde_json::from_slice(&ReplicationMessage::Heartbeat { at: 5 }.line()).unwrap();
+        assert_eq!(heartbeat, serde_json::json!({"type": "heartbeat", "at": 5}));
+    }
+
+    #[tokio::test]
+    async fn test_catch_up_starts_at_each_scope_cursor() {
+        let store = Arc::new(MemoryStore::new());
+        let keys = Keys::generate();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let other = Scope::named("9q8yy").unwrap();
+        store.insert(&drt2z, note_at(&keys, 1_000));
+        let recent = note_at(&keys, 5_000);
+        store.insert(&drt2z, recent.clone());
+        let unknown = note_at(&keys, 1_000);
+        store.insert(&other, unknown.clone());
+
+        let live = Arc::new(LiveEvents::new());
+        let leader = ReplicationLeader::new("s3cret", store, live.clone());
+        let request = StreamRequest { since: HashMap::from([("drt2z".to_string(), 4_000)]) };
+        let (mut sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        let mut subscription = live.subscribe();
+        let mut vanishing = leader.vanished.subscribe();
+        tokio::spawn(async move { leader.stream(&request, &mut subscription, &mut vanishing, &mut sender).await });
+
+        let mut lines = receiver.map(|line| serde_json::from_slice::<ReplicationMessage>(&line.unwrap()).unwrap());
+        let mut caught_up = Vec::new();
+        loop {
+            match lines.next().await.unwrap() {
+                ReplicationMessage::Event { event, .. } => caught_up.push(event.id),
+                ReplicationMessage::CaughtUp { .. } => break,
+                other => panic!("{:?} before catch-up finished", other),
+            }
+        }
+        caught_up.sort();
+        let mut expected = vec![recent.id, unknown.id];
+        expected.sort();
+        assert_eq!(caught_up, expected);
+
+        let stored = note_at(&keys, 6_000);
+        live.publish(StoredEvent { scope: drt2z, event: stored.clone() });
+        loop {
+            if let ReplicationMessage::Event { scope, event } = lines.next().await.unwrap() {
+                assert_eq!(scope, "drt2z");
+                assert_eq!(event.id, stored.id);
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_follower_applies_events_and_advances_cursors() {
+        let store = Arc::new(MemoryStore::new());
+        let keys = Keys::generate();
+        let follower = ReplicationFollower::new("http://leader.example.com/", "s3cret", store.clone()).unwrap();
+        assert_eq!(follower.leader, "http://leader.example.com");
+        assert_eq!(follower.lag_secs(), None);
+
+        let event = note_at(&keys, 2_000);
+        follower
+            .apply(ReplicationMessage::Event { scope: "drt2z".to_string(), event: Box::new(event.clone()) })
+            .await
+            .unwrap();
+        let drt2z = Scope::named("drt2z").unwrap();
+        assert_eq!(store.query(&drt2z, Filter::new()).await.unwrap(), vec![event]);
+        assert_eq!(follower.cursors.lock()["drt2z"], 2_000);
+
+        // Future-dated events only advance the cursor to now
+        let future = note_at(&keys, now_secs() + 86_400);
+        follower
+            .apply(ReplicationMessage::Event { scope: "drt2z".to_string(), event: Box::new(future) })
+            .await
+            .unwrap();
+        assert!(follower.cursors.lock()["drt2z"] <= now_secs());
+
+        // Heartbeats only count once caught up
+        follower.apply(ReplicationMessage::Heartbeat { at: now_secs() }).await.unwrap();
+        assert_eq!(follower.lag_secs(), None);
+        follower.apply(ReplicationMessage::CaughtUp { at: now_secs() - 3 }).await.unwrap();
+        assert!(follower.lag_secs().unwrap() >= 3);
+        assert_eq!(follower.health().applied_events, 2);
+
+        assert!(follower
+            .apply(ReplicationMessage::Event { scope: String::new(), event: Box::new(note_at(&keys, 1)) })
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_follower_uses_socks_proxy() {
+        let config = RelayConfig {
+            replicate_from: Some("http://leaderabc.onion".to_string()),
+            replication_token: Some("s3cret".to_string()),
+            socks_proxy: Some("socks5h://127.0.0.1:9050".to_string()),
+            ..Default::default()
+        };
+        assert!(ReplicationFollower::for_config(&config, Arc::new(MemoryStore::new())).unwrap().is_some());
+        assert!(follower_client(Some("not a proxy")).is_err());
+    }
+}
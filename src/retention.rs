@@ -0,0 +1,188 @@
+//! Age-based retention for geohash cells
+//!
+//! With `geohash_retention_secs` set (the `location-chat` profile sets a
+//! day), a background task periodically deletes cell events older than that,
+//! oldest first, in pages. `geohash_max_ttl_days` is applied the same way,
+//! as an implicit expiration for every kind not in
+//! `geohash_ttl_exempt_kinds`; in strict mode it also catches events stored
+//! before the mode was turned on. Root is never touched.
+
+use anyhow::Result;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+use crate::config::RelayConfig;
+use crate::store::ScopeStore;
+
+/// Events deleted per query
+const PAGE_SIZE: usize = 500;
+
+/// What the sweeper deletes from geohash cells
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Every event older than this goes (0 keeps them)
+    pub max_age_secs: u64,
+    /// Events older than this go unless their kind is exempt (0 keeps them)
+    pub ttl_secs: u64,
+    pub ttl_exempt_kinds: Vec<u16>,
+}
+
+impl RetentionPolicy {
+    pub fn for_config(config: &RelayConfig) -> Self {
+        Self {
+            max_age_secs: config.geohash_retention_secs,
+            ttl_secs: config.geohash_max_ttl_days.saturating_mul(24 * 60 * 60),
+            ttl_exempt_kinds: config.geohash_ttl_exempt_kinds.clone(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_age_secs > 0 || self.ttl_secs > 0
+    }
+}
+
+/// Deletes the events `policy` has aged out of every geohash cell,
+/// returning how many went
+pub async fn expire_cells(store: &dyn ScopeStore, policy: &RetentionPolicy, now: Timestamp) -> Result<usize> {
+    let cutoff = |age: u64| Timestamp::from(now.as_u64().saturating_sub(age));
+    let mut expired = 0;
+    for scope in store.scopes().await? {
+        if matches!(scope, Scope::Default) {
+            continue;
+        }
+        if policy.max_age_secs > 0 {
+            expired += expire_before(store, &scope, cutoff(policy.max_age_secs), &[]).await?;
+        }
+        if policy.ttl_secs > 0 {
+            expired += expire_before(store, &scope, cutoff(policy.ttl_secs), &policy.ttl_exempt_kinds).await?;
+        }
+    }
+    Ok(expired)
+}
+
+/// Deletes `scope`'s events up to `cutoff` whose kind isn't in `exempt`
+async fn expire_before(store: &dyn ScopeStore, scope: &Scope, cutoff: Timestamp, exempt: &[u16]) -> Result<usize> {
+    let mut until = cutoff;
+    let mut expired = 0;
+    loop {
+        let page = store.query(scope, Filter::new().until(until).limit(PAGE_SIZE)).await?;
+        for event in page.iter().filter(|event| !exempt.contains(&event.kind.as_u16())) {
+            store.delete(scope, event.id).await?;
+            expired += 1;
+        }
+        if page.len() < PAGE_SIZE {
+            break;
+        }
+        // Exempt events stay, so page past them; anything sharing the
+        // oldest second that didn't fit waits for the next sweep
+        if !exempt.is_empty() {
+            match page.iter().map(|event| event.created_at.as_u64()).min() {
+                Some(oldest) if oldest > 0 => until = Timestamp::from(oldest - 1),
+                _ => break,
+            }
+        }
+    }
+    Ok(expired)
+}
+
+/// Runs `expire_cells` every `interval` while retention is on
+pub fn spawn_retention_task(store: Arc<dyn ScopeStore>, policy: RetentionPolicy, interval: Duration) {
+    if !policy.is_enabled() {
+        return;
+    }
+    info!(
+        "Geohash cells keep events for {}s, and for {}s unless exempt (0 = no limit)",
+        policy.max_age_secs, policy.ttl_secs
+    );
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match expire_cells(store.as_ref(), &policy, Timestamp::now()).await {
+                Ok(0) => {}
+                Ok(expired) => {
+                    info!("Deleted {} expired cell events", expired);
+                    metrics::counter!("relay_retention_deleted_events_total").increment(expired as u64);
+                }
+                Err(e) => warn!("Failed to expire cell events: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    async fn event_at(kind: u16, created_at: u64) -> Event {
+        EventBuilder::new(Kind::from(kind), format!("at {}", created_at))
+            .custom_created_at(Timestamp::from(created_at))
+            .sign(&Keys::generate())
+            .await
+            .unwrap()
+    }
+
+    async fn note_at(created_at: u64) -> Event {
+        event_at(1, created_at).await
+    }
+
+    #[tokio::test]
+    async fn test_only_old_cell_events_expire() {
+        let store = MemoryStore::new();
+        let drt2z = Scope::named("drt2z").unwrap();
+        store.insert(&drt2z, note_at(1_000).await);
+        store.insert(&drt2z, note_at(90_000).await);
+        store.insert(&Scope::Default, note_at(1_000).await);
+
+        let policy = RetentionPolicy { max_age_secs: 86_400, ..Default::default() };
+        let expired = expire_cells(&store, &policy, Timestamp::from(100_000)).await.unwrap();
+        assert_eq!(expired, 1);
+        assert_eq!(store.count(&drt2z, Filter::new()).await.unwrap(), 1);
+        // Root keeps everything
+        assert_eq!(store.count(&Scope::Default, Filter::new()).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_ttl_spares_exempt_kinds() {
+        let store = MemoryStore::new();
+        let drt2z = Scope::named("drt2z").unwrap();
+        store.insert(&drt2z, note_at(1_000).await);
+        store.insert(&drt2z, event_at(30023, 1_000).await);
+        store.insert(&drt2z, note_at(90_000).await);
+
+        let policy = RetentionPolicy {
+            ttl_secs: 86_400,
+            ttl_exempt_kinds: vec![30023],
+            ..Default::default()
+        };
+        let expired = expire_cells(&store, &policy, Timestamp::from(100_000)).await.unwrap();
+        assert_eq!(expired, 1);
+        assert_eq!(store.count(&drt2z, Filter::new().kind(Kind::from(30023))).await.unwrap(), 1);
+        assert_eq!(store.count(&drt2z, Filter::new().kind(Kind::TextNote)).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_ttl_pages_past_exempt_events() {
+        let store = MemoryStore::new();
+        let drt2z = Scope::named("drt2z").unwrap();
+        // A full page of newer exempt events hides the expired notes behind it
+        for i in 0..PAGE_SIZE as u64 {
+            store.insert(&drt2z, event_at(30023, 10_000 + i).await);
+        }
+        for i in 0..3 {
+            store.insert(&drt2z, note_at(1_000 + i).await);
+        }
+
+        let policy = RetentionPolicy {
+            ttl_secs: 86_400,
+            ttl_exempt_kinds: vec![30023],
+            ..Default::default()
+        };
+        let expired = expire_cells(&store, &policy, Timestamp::from(200_000)).await.unwrap();
+        assert_eq!(expired, 3);
+        assert_eq!(store.count(&drt2z, Filter::new()).await.unwrap(), PAGE_SIZE);
+    }
+}
@@ -0,0 +1,278 @@
+//! Scope routing decisions
+//!
+//! `decide_scope` holds the rules for where an event may be stored, given
+//! its geohash tags and the scope the client connected to. It is pure so the
+//! processor, the HTTP pages and offline tooling can share it and so the
+//! rules can be tested without building events or connections.
+
+use nostr_lmdb::Scope;
+use crate::config::{RelayConfig, TagPrecisionMode};
+use crate::geohash_utils::{is_allowed_precision, is_valid_geohash, nearest_allowed_precision, to_precision};
+use crate::host_parsing::ConnectionOrigin;
+use crate::reject::RejectReason;
+
+/// Domain used in suggested cell URLs when the relay URL doesn't name one
+const FALLBACK_CELL_DOMAIN: &str = "hashstr.com";
+
+/// Outcome of `decide_scope`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScopeDecision {
+    /// Store the event in this scope
+    Store(Scope),
+    /// Refuse the event
+    Reject(RejectReason),
+}
+
+/// Configuration the routing rules depend on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScopePolicy {
+    /// Domain suggested cell URLs are built on, `wss://{geohash}.{domain}`
+    pub cell_domain: String,
+    /// "wss", or "ws" behind a proxy that reports plain HTTP
+    pub cell_scheme: &'static str,
+    /// Cell precisions served; empty serves all
+    pub allowed_precisions: Vec<usize>,
+    /// Whether g tags at other precisions are rejected or adjusted
+    pub tag_precision_mode: TagPrecisionMode,
+}
+
+impl ScopePolicy {
+    pub fn from_config(config: &RelayConfig) -> Self {
+        Self {
+            cell_domain: config
+                .base_domain()
+                .unwrap_or_else(|| FALLBACK_CELL_DOMAIN.to_string()),
+            cell_scheme: "wss",
+            allowed_precisions: config.allowed_precisions.clone(),
+            tag_precision_mode: config.tag_precision_mode,
+        }
+    }
+
+    /// This policy with cell URLs on the domain and scheme a connection used
+    pub fn for_origin(&self, origin: &ConnectionOrigin) -> Self {
+        Self {
+            cell_domain: origin.base_domain.clone().unwrap_or_else(|| self.cell_domain.clone()),
+            cell_scheme: match origin.secure {
+                Some(true) => "wss",
+                Some(false) => "ws",
+                None => self.cell_scheme,
+            },
+            allowed_precisions: self.allowed_precisions.clone(),
+            tag_precision_mode: self.tag_precision_mode,
+        }
+    }
+
+    /// Websocket URL of a geohash cell
+    pub fn cell_url(&self, geohash: &str) -> String {
+        format!("{}://{}.{}", self.cell_scheme, geohash, self.cell_domain)
+    }
+
+    /// The served cell a g tag routes to, or `None` when its precision
+    /// isn't served and tags aren't adjusted
+    fn served_geohash(&self, geohash: &str) -> Option<String> {
+        if is_allowed_precision(geohash.len(), &self.allowed_precisions) {
+            return Some(geohash.to_string());
+        }
+        match self.tag_precision_mode {
+            TagPrecisionMode::Reject => None,
+            TagPrecisionMode::Adjust => {
+                to_precision(geohash, nearest_allowed_precision(geohash.len(), &self.allowed_precisions)?)
+            }
+        }
+    }
+
+    fn precision_not_allowed(&self, geohash: &str) -> RejectReason {
+        RejectReason::PrecisionNotAllowed {
+            geohash: geohash.to_string(),
+            allowed: self.allowed_precisions.clone(),
+        }
+    }
+}
+
+impl Default for ScopePolicy {
+    fn default() -> Self {
+        Self::from_config(&RelayConfig::default())
+    }
+}
+
+/// Decides where an event belongs
+///
+/// `event_geohashes` are the event's valid, normalized g tags in order (see
+/// `extract_geohash_tags`); only the first one counts. Untagged events are
+/// stored in the connection's scope. Geotagged events are only accepted on
+/// the cell they name. Connections to subdomains that aren't geohashes, or
+/// are cells at a precision the policy doesn't serve, can't store anything.
+/// Tags at such a precision are refused or moved to the nearest served one.
+pub fn decide_scope(event_geohashes: &[String], connection_scope: &Scope, policy: &ScopePolicy) -> ScopeDecision {
+    let subdomain = match connection_scope {
+        Scope::Named { name, .. } => Some(name.as_str()),
+        Scope::Default => None,
+    };
+    if let Some(sub) = subdomain.filter(|sub| !is_valid_geohash(sub)) {
+        return ScopeDecision::Reject(RejectReason::InvalidSubdomain {
+            subdomain: sub.to_string(),
+        });
+    }
+    if let Some(sub) = subdomain.filter(|sub| !is_allowed_precision(sub.len(), &policy.allowed_precisions)) {
+        return ScopeDecision::Reject(policy.precision_not_allowed(sub));
+    }
+
+    let Some(tagged) = event_geohashes.first() else {
+        return ScopeDecision::Store(connection_scope.clone());
+    };
+    let Some(geohash) = policy.served_geohash(tagged) else {
+        return ScopeDecision::Reject(policy.precision_not_allowed(tagged));
+    };
+    match subdomain {
+        Some(sub) if sub == geohash => ScopeDecision::Store(connection_scope.clone()),
+        Some(_) => ScopeDecision::Reject(RejectReason::WrongScope {
+            suggested_url: policy.cell_url(&geohash),
+            geohash,
+        }),
+        None => ScopeDecision::Reject(RejectReason::RootRejectsGeotagged {
+            suggested_url: policy.cell_url(&geohash),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn geohashes(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|t| t.to_string()).collect()
+    }
+
+    fn cell(name: &str) -> Scope {
+        Scope::named(name).unwrap()
+    }
+
+    fn decide(tags: &[&str], scope: &Scope) -> ScopeDecision {
+        decide_scope(&geohashes(tags), scope, &ScopePolicy::default())
+    }
+
+    #[test]
+    fn test_geohash_rejected_at_root() {
+        assert_eq!(
+            decide(&["drt2z"], &Scope::Default),
+            ScopeDecision::Reject(RejectReason::RootRejectsGeotagged {
+                suggested_url: "wss://drt2z.hashstr.com".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_geohash_correct_scope_stores() {
+        assert_eq!(decide(&["drt2z"], &cell("drt2z")), ScopeDecision::Store(cell("drt2z")));
+    }
+
+    #[test]
+    fn test_event_without_geohash_uses_connection_scope() {
+        assert_eq!(decide(&[], &cell("gbsuv")), ScopeDecision::Store(cell("gbsuv")));
+        assert_eq!(decide(&[], &Scope::Default), ScopeDecision::Store(Scope::Default));
+    }
+
+    #[test]
+    fn test_multiple_geohash_tags_uses_first() {
+        assert_eq!(decide(&["drt2z", "9q8yy"], &cell("drt2z")), ScopeDecision::Store(cell("drt2z")));
+        assert!(matches!(
+            decide(&["9q8yy", "drt2z"], &cell("drt2z")),
+            ScopeDecision::Reject(RejectReason::WrongScope { .. })
+        ));
+    }
+
+    #[test]
+    fn test_wrong_geohash_subdomain_rejected() {
+        assert_eq!(
+            decide(&["drt2z"], &cell("9q8yy")),
+            ScopeDecision::Reject(RejectReason::WrongScope {
+                geohash: "drt2z".to_string(),
+                suggested_url: "wss://drt2z.hashstr.com".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_subdomain_rejected() {
+        // Even untagged events can't be stored under a non-geohash subdomain
+        for tags in [&[][..], &["drt2z"][..]] {
+            assert_eq!(
+                decide(tags, &cell("foobar")),
+                ScopeDecision::Reject(RejectReason::InvalidSubdomain { subdomain: "foobar".to_string() })
+            );
+        }
+    }
+
+    #[test]
+    fn test_suggested_urls_use_configured_domain() {
+        let config = RelayConfig {
+            relay_url: "wss://relay.mycompany.com".to_string(),
+            ..Default::default()
+        };
+        let policy = ScopePolicy::from_config(&config);
+        assert_eq!(
+            decide_scope(&geohashes(&["drt2z"]), &Scope::Default, &policy),
+            ScopeDecision::Reject(RejectReason::RootRejectsGeotagged {
+                suggested_url: "wss://drt2z.relay.mycompany.com".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_suggested_urls_follow_connection_origin() {
+        let policy = ScopePolicy::from_config(&RelayConfig {
+            relay_url: "wss://relay.mycompany.com".to_string(),
+            ..Default::default()
+        });
+        let internal = policy.for_origin(&ConnectionOrigin {
+            base_domain: Some("relay.corp.internal".to_string()),
+            secure: Some(false),
+            ..Default::default()
+        });
+        assert_eq!(internal.cell_url("drt2z"), "ws://drt2z.relay.corp.internal");
+        assert_eq!(policy.for_origin(&ConnectionOrigin::default()), policy);
+    }
+
+    fn five_only(mode: TagPrecisionMode) -> ScopePolicy {
+        ScopePolicy {
+            allowed_precisions: vec![5],
+            tag_precision_mode: mode,
+            ..ScopePolicy::default()
+        }
+    }
+
+    #[test]
+    fn test_subdomains_outside_allowed_precisions_rejected() {
+        let policy = five_only(TagPrecisionMode::Reject);
+        assert_eq!(decide_scope(&[], &cell("drt2z"), &policy), ScopeDecision::Store(cell("drt2z")));
+        for sub in ["d", "dr", "drt", "drt2", "drt2zb", "drt2zby"] {
+            assert_eq!(
+                decide_scope(&[], &cell(sub), &policy),
+                ScopeDecision::Reject(RejectReason::PrecisionNotAllowed { geohash: sub.to_string(), allowed: vec![5] })
+            );
+        }
+        // Root is not a cell
+        assert_eq!(decide_scope(&[], &Scope::Default, &policy), ScopeDecision::Store(Scope::Default));
+    }
+
+    #[test]
+    fn test_tags_outside_allowed_precisions() {
+        let policy = five_only(TagPrecisionMode::Reject);
+        assert_eq!(
+            decide_scope(&geohashes(&["drt2zby"]), &cell("drt2z"), &policy),
+            ScopeDecision::Reject(RejectReason::PrecisionNotAllowed { geohash: "drt2zby".to_string(), allowed: vec![5] })
+        );
+
+        let policy = five_only(TagPrecisionMode::Adjust);
+        assert_eq!(decide_scope(&geohashes(&["drt2zby"]), &cell("drt2z"), &policy), ScopeDecision::Store(cell("drt2z")));
+        // A coarse tag is padded to the cell at its center
+        let padded = to_precision("drt", 5).unwrap();
+        assert_eq!(decide_scope(&geohashes(&["drt"]), &cell(&padded), &policy), ScopeDecision::Store(cell(&padded)));
+        assert_eq!(
+            decide_scope(&geohashes(&["drt2zby"]), &Scope::Default, &policy),
+            ScopeDecision::Reject(RejectReason::RootRejectsGeotagged {
+                suggested_url: "wss://drt2z.hashstr.com".to_string(),
+            })
+        );
+    }
+}
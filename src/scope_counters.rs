@@ -0,0 +1,283 @@
+//! Per-scope counters shared by every connection to a scope
+//!
+//! Each EVENT takes a token from its scope's rate-limit bucket and, once
+//! accepted, counts towards the scope's proof-of-work rate. Keeping those in
+//! one `Mutex<HashMap>` made every event on the relay wait for the same
+//! lock, so a hot cell slowed down all the others.
+//!
+//! `ScopeCounterMap` shards scopes across a `DashMap` of `Arc<ScopeCounters>`,
+//! and the counters themselves are lock-free (governor's bucket is a single
+//! atomic). `ScopeRateLimitMiddleware` resolves a connection's counters when
+//! it opens and keeps them in `ConnectionState`, so the map is only read for
+//! events routed to another scope. Connections to the same cell still share
+//! its atomics; they no longer share a lock with the rest of the relay.
+//!
+//! Idle scopes are released through `ScopeResidency`, but only when no
+//! connection holds their counters; otherwise a connection would keep
+//! taking from a bucket that new connections no longer see.
+
+use dashmap::DashMap;
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use nostr_lmdb::Scope;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use crate::config::RelayConfig;
+use crate::scope_policy::{DefaultScopePolicy, ScopePolicy};
+use crate::scope_residency::ScopeResources;
+
+/// Events per minute allowed in a scope, 0 for no limit
+type Budget = dyn Fn(&Scope) -> u32 + Send + Sync;
+
+/// Rate-limit bucket and accepted-event count of one scope
+pub struct ScopeCounters {
+    scope: Scope,
+    /// `None` for scopes without a limit
+    limiter: Option<DefaultDirectRateLimiter>,
+    /// Accepted events since `take_accepted` last ran
+    accepted: AtomicU64,
+}
+
+impl std::fmt::Debug for ScopeCounters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScopeCounters")
+            .field("scope", &self.scope)
+            .field("limited", &self.limiter.is_some())
+            .field("accepted", &self.accepted.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl ScopeCounters {
+    fn new(scope: Scope, events_per_minute: u32) -> Self {
+        Self {
+            scope,
+            limiter: NonZeroU32::new(events_per_minute).map(|limit| RateLimiter::direct(Quota::per_minute(limit))),
+            accepted: AtomicU64::new(0),
+        }
+    }
+
+    pub fn scope(&self) -> &Scope {
+        &self.scope
+    }
+
+    /// Takes one event from the scope's budget, false if it's exhausted
+    pub fn check(&self) -> bool {
+        self.limiter.as_ref().is_none_or(|limiter| limiter.check().is_ok())
+    }
+
+    /// Counts an accepted event
+    pub fn record_accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Accepted events since the last call, starting a fresh count
+    pub fn take_accepted(&self) -> u64 {
+        self.accepted.swap(0, Ordering::Relaxed)
+    }
+}
+
+/// Counters keyed by scope, created on first use
+pub struct ScopeCounterMap {
+    budget: Box<Budget>,
+    counters: DashMap<Scope, Arc<ScopeCounters>>,
+}
+
+impl std::fmt::Debug for ScopeCounterMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScopeCounterMap").field("scopes", &self.counters.len()).finish()
+    }
+}
+
+impl Default for ScopeCounterMap {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
+
+impl ScopeCounterMap {
+    /// Budgets from `RelayConfig::events_per_minute_for`
+    pub fn new(config: Arc<RelayConfig>) -> Self {
+        Self::with_policy(config, Arc::new(DefaultScopePolicy))
+    }
+
+    /// Budgets from `policy`'s `rate_limit_for`
+    pub fn with_policy(config: Arc<RelayConfig>, policy: Arc<dyn ScopePolicy>) -> Self {
+        Self::with_budget(move |scope| policy.rate_limit_for(scope, &config))
+    }
+
+    /// Counts without limiting any scope
+    pub fn unlimited() -> Self {
+        Self::with_budget(|_| 0)
+    }
+
+    fn with_budget(budget: impl Fn(&Scope) -> u32 + Send + Sync + 'static) -> Self {
+        Self {
+            budget: Box::new(budget),
+            counters: DashMap::new(),
+        }
+    }
+
+    /// `scope`'s counters, created with its budget if it has none yet
+    pub fn resolve(&self, scope: &Scope) -> Arc<ScopeCounters> {
+        if let Some(counters) = self.counters.get(scope) {
+            return counters.clone();
+        }
+        // Outside the shard lock; a racing resolve may have inserted since
+        let events_per_minute = (self.budget)(scope);
+        self.counters
+            .entry(scope.clone())
+            .or_insert_with(|| Arc::new(ScopeCounters::new(scope.clone(), events_per_minute)))
+            .clone()
+    }
+
+    /// Runs `f` on `cached` if it belongs to `scope`, else on the map's
+    /// counters for `scope`
+    ///
+    /// `cached` is a connection's counters, which must come from this map.
+    pub fn with_counters<R>(&self, cached: Option<&ScopeCounters>, scope: &Scope, f: impl FnOnce(&ScopeCounters) -> R) -> R {
+        match cached {
+            Some(counters) if counters.scope() == scope => f(counters),
+            _ => f(&self.resolve(scope)),
+        }
+    }
+
+    /// Takes one event from `scope`'s budget, false if it's exhausted
+    pub fn check(&self, scope: &Scope) -> bool {
+        self.resolve(scope).check()
+    }
+
+    /// Accepted events per scope since the last call, leaving out scopes
+    /// that accepted none
+    pub fn take_accepted(&self) -> Vec<(Scope, u64)> {
+        self.counters
+            .iter()
+            .filter_map(|entry| {
+                let accepted = entry.value().take_accepted();
+                (accepted > 0).then(|| (entry.key().clone(), accepted))
+            })
+            .collect()
+    }
+
+    /// Scopes with counters
+    pub fn len(&self) -> usize {
+        self.counters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+    }
+}
+
+impl ScopeResources for ScopeCounterMap {
+    /// Only released after a minute or more idle, when the bucket is full
+    /// again anyway, and never while a connection holds the counters
+    fn release(&self, scope: &Scope) {
+        self.counters.remove_if(scope, |_, counters| Arc::strong_count(counters) == 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+    use std::time::Instant;
+
+    const THREADS: usize = 16;
+
+    fn cell(name: &str) -> Scope {
+        Scope::named(name).unwrap()
+    }
+
+    fn limited(events_per_minute: u32) -> ScopeCounterMap {
+        ScopeCounterMap::new(Arc::new(RelayConfig { events_per_minute, ..Default::default() }))
+    }
+
+    /// Runs `f` on `THREADS` threads released at the same moment
+    fn race<R: Send>(f: impl Fn(usize) -> R + Sync) -> Vec<R> {
+        let barrier = Barrier::new(THREADS);
+        std::thread::scope(|threads| {
+            let handles: Vec<_> = (0..THREADS)
+                .map(|i| {
+                    let (barrier, f) = (&barrier, &f);
+                    threads.spawn(move || {
+                        barrier.wait();
+                        f(i)
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        })
+    }
+
+    #[test]
+    fn test_racing_resolves_share_one_counter() {
+        let map = limited(60);
+        let resolved = race(|_| map.resolve(&cell("drt2z")));
+        assert!(resolved.iter().all(|counters| Arc::ptr_eq(counters, &resolved[0])));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn test_racing_connections_never_exceed_the_budget() {
+        let map = limited(60);
+        let started = Instant::now();
+        let admitted: usize = race(|_| {
+            let counters = map.resolve(&cell("drt2z"));
+            (0..20).filter(|_| counters.check()).count()
+        })
+        .into_iter()
+        .sum();
+        // The full burst, plus at most one token per second refilled
+        let refilled = started.elapsed().as_secs() as usize + 1;
+        assert!((60..=60 + refilled).contains(&admitted), "admitted {}", admitted);
+    }
+
+    #[test]
+    fn test_no_accepted_event_is_lost_to_a_concurrent_take() {
+        let map = ScopeCounterMap::unlimited();
+        let scopes = [cell("drt2z"), Scope::Default];
+        let taken = AtomicU64::new(0);
+        race(|i| {
+            if i == 0 {
+                for _ in 0..1_000 {
+                    let accepted: u64 = map.take_accepted().iter().map(|(_, count)| count).sum();
+                    taken.fetch_add(accepted, Ordering::Relaxed);
+                }
+            } else {
+                let counters = map.resolve(&scopes[i % 2]);
+                for _ in 0..1_000 {
+                    counters.record_accepted();
+                }
+            }
+        });
+        let remaining: u64 = map.take_accepted().iter().map(|(_, count)| count).sum();
+        assert_eq!(taken.load(Ordering::Relaxed) + remaining, (THREADS as u64 - 1) * 1_000);
+    }
+
+    #[test]
+    fn test_held_counters_are_not_released() {
+        let map = limited(2);
+        let drt2z = cell("drt2z");
+        let held = map.resolve(&drt2z);
+        assert!(held.check() && held.check());
+        map.release(&drt2z);
+        // Still the same bucket, so reconnecting doesn't reset the budget
+        assert!(Arc::ptr_eq(&held, &map.resolve(&drt2z)));
+        assert!(!map.check(&drt2z));
+
+        drop(held);
+        map.release(&drt2z);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_cached_counters_only_serve_their_scope() {
+        let map = ScopeCounterMap::unlimited();
+        let drt2z = map.resolve(&cell("drt2z"));
+        map.with_counters(Some(&drt2z), &cell("drt2z"), |counters| counters.record_accepted());
+        map.with_counters(Some(&drt2z), &Scope::Default, |counters| counters.record_accepted());
+        let accepted: std::collections::HashMap<Scope, u64> = map.take_accepted().into_iter().collect();
+        assert_eq!(accepted, [(Scope::Default, 1), (cell("drt2z"), 1)].into());
+    }
+}
@@ -0,0 +1,232 @@
+//! Scope rules for applications embedding the relay
+//!
+//! Every decision about what a scope is goes through a `ScopePolicy`: what
+//! the subdomain a connection opened is, where an event is stored, how many
+//! events a scope takes per minute and who may see an event.
+//! `DefaultScopePolicy` is this relay: root plus geohash cells at the served
+//! precisions. An application passes its own policy to
+//! `build_relay_with_policy`; methods it doesn't override keep the default
+//! behavior, so a policy only spells out what it changes.
+//!
+//! Policies are handed the connection's routing settings
+//! (`routing::ScopePolicy`), so suggested cell URLs keep following the
+//! domain and scheme the client used.
+//!
+//! `TeamScopePolicy` is a worked example that serves team names as
+//! subdomains alongside the cells.
+
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use std::collections::BTreeSet;
+use crate::config::RelayConfig;
+use crate::geohash_utils::{is_allowed_precision, is_valid_geohash};
+use crate::processor::is_dm_kind;
+use crate::reject::RejectReason;
+use crate::routing::{self, decide_scope, ScopeDecision};
+
+/// What the scope a connection opened is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScopeClass {
+    Root,
+    /// A geohash cell at a served precision
+    Cell,
+    /// A scope the policy serves that isn't a cell
+    Named,
+    /// A scope that can't store anything
+    Refused(RejectReason),
+}
+
+/// The relay's scope rules; every method defaults to `DefaultScopePolicy`
+pub trait ScopePolicy: std::fmt::Debug + Send + Sync + 'static {
+    /// Classifies a connection's scope; events sent to a `Refused` one are
+    /// rejected before any other policy applies
+    fn classify_subdomain(&self, scope: &Scope, rules: &routing::ScopePolicy) -> ScopeClass {
+        match scope {
+            Scope::Default => ScopeClass::Root,
+            Scope::Named { name, .. } if !is_valid_geohash(name) => {
+                ScopeClass::Refused(RejectReason::InvalidSubdomain { subdomain: name.clone() })
+            }
+            Scope::Named { name, .. } if !is_allowed_precision(name.len(), &rules.allowed_precisions) => {
+                ScopeClass::Refused(RejectReason::PrecisionNotAllowed {
+                    geohash: name.clone(),
+                    allowed: rules.allowed_precisions.clone(),
+                })
+            }
+            Scope::Named { .. } => ScopeClass::Cell,
+        }
+    }
+
+    /// Where an event sent to `scope` with the g tags `geohashes` (valid,
+    /// normalized, in order) is stored
+    fn route_event(&self, geohashes: &[String], scope: &Scope, rules: &routing::ScopePolicy) -> ScopeDecision {
+        decide_scope(geohashes, scope, rules)
+    }
+
+    /// Events per minute `scope` accepts, 0 for no limit
+    fn rate_limit_for(&self, scope: &Scope, config: &RelayConfig) -> u32 {
+        let subdomain = match scope {
+            Scope::Named { name, .. } => Some(name.as_str()),
+            Scope::Default => None,
+        };
+        config.events_per_minute_for(subdomain)
+    }
+
+    /// Whether a reader of `_scope`, authenticated as `authed_pubkey`, may
+    /// see `event`
+    ///
+    /// Authenticated readers only see DMs they sent or received.
+    /// Unauthenticated ones keep seeing them (the payloads are encrypted),
+    /// since the relay doesn't require auth to read.
+    fn visibility(&self, event: &Event, _scope: &Scope, authed_pubkey: Option<PublicKey>) -> bool {
+        match authed_pubkey {
+            Some(authed) if is_dm_kind(event.kind) => {
+                let authed_hex = authed.to_hex();
+                let is_recipient = event.tags.iter().any(|tag| {
+                    let tag = tag.as_slice();
+                    tag.len() >= 2 && tag[0] == "p" && tag[1] == authed_hex
+                });
+                event.pubkey == authed || is_recipient
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Root and geohash cells
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultScopePolicy;
+
+impl ScopePolicy for DefaultScopePolicy {}
+
+/// Serves a fixed set of team names as subdomains alongside the cells
+///
+/// `acme.example.com` is the acme team's scope. Untagged events sent there
+/// are stored there; geotagged ones are pointed at their cell, as on root.
+/// Team scopes take `events_per_minute` unless `scope_events_per_minute`
+/// names them. Settings the config applies to every named scope (write
+/// auth, allowed kinds, cell TTLs) apply to team scopes as well. Everything
+/// else is the default policy.
+#[derive(Debug, Clone)]
+pub struct TeamScopePolicy {
+    teams: BTreeSet<String>,
+    events_per_minute: u32,
+}
+
+impl TeamScopePolicy {
+    pub fn new<I, S>(teams: I, events_per_minute: u32) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Self {
+            teams: teams.into_iter().map(|team| team.as_ref().to_lowercase()).collect(),
+            events_per_minute,
+        }
+    }
+
+    fn is_team(&self, scope: &Scope) -> bool {
+        matches!(scope, Scope::Named { name, .. } if self.teams.contains(&name.to_lowercase()))
+    }
+}
+
+impl ScopePolicy for TeamScopePolicy {
+    fn classify_subdomain(&self, scope: &Scope, rules: &routing::ScopePolicy) -> ScopeClass {
+        if self.is_team(scope) {
+            return ScopeClass::Named;
+        }
+        DefaultScopePolicy.classify_subdomain(scope, rules)
+    }
+
+    fn route_event(&self, geohashes: &[String], scope: &Scope, rules: &routing::ScopePolicy) -> ScopeDecision {
+        if !self.is_team(scope) {
+            return DefaultScopePolicy.route_event(geohashes, scope, rules);
+        }
+        // Routed as if sent to root, then kept in the team's scope
+        match decide_scope(geohashes, &Scope::Default, rules) {
+            ScopeDecision::Store(_) => ScopeDecision::Store(scope.clone()),
+            reject => reject,
+        }
+    }
+
+    fn rate_limit_for(&self, scope: &Scope, config: &RelayConfig) -> u32 {
+        match scope {
+            Scope::Named { name, .. } if self.is_team(scope) => config
+                .scope_events_per_minute
+                .get(&name.to_lowercase())
+                .copied()
+                .unwrap_or(self.events_per_minute),
+            _ => DefaultScopePolicy.rate_limit_for(scope, config),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named(name: &str) -> Scope {
+        Scope::named(name).unwrap()
+    }
+
+    fn geohashes(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn test_default_classification() {
+        let rules = routing::ScopePolicy::from_config(&RelayConfig {
+            allowed_precisions: vec![5],
+            ..Default::default()
+        });
+        assert_eq!(DefaultScopePolicy.classify_subdomain(&Scope::Default, &rules), ScopeClass::Root);
+        assert_eq!(DefaultScopePolicy.classify_subdomain(&named("drt2z"), &rules), ScopeClass::Cell);
+        assert_eq!(
+            DefaultScopePolicy.classify_subdomain(&named("acme"), &rules),
+            ScopeClass::Refused(RejectReason::InvalidSubdomain { subdomain: "acme".to_string() })
+        );
+        assert!(matches!(
+            DefaultScopePolicy.classify_subdomain(&named("drt2"), &rules),
+            ScopeClass::Refused(RejectReason::PrecisionNotAllowed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_team_scopes_store_untagged_events() {
+        let teams = TeamScopePolicy::new(["Acme"], 30);
+        let rules = routing::ScopePolicy::default();
+        assert_eq!(teams.classify_subdomain(&named("acme"), &rules), ScopeClass::Named);
+        assert_eq!(teams.route_event(&[], &named("acme"), &rules), ScopeDecision::Store(named("acme")));
+        assert_eq!(
+            teams.route_event(&geohashes(&["drt2z"]), &named("acme"), &rules),
+            ScopeDecision::Reject(RejectReason::RootRejectsGeotagged {
+                suggested_url: "wss://drt2z.hashstr.com".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_team_policy_keeps_the_defaults_elsewhere() {
+        let teams = TeamScopePolicy::new(["acme"], 30);
+        let rules = routing::ScopePolicy::default();
+        assert_eq!(teams.classify_subdomain(&named("drt2z"), &rules), ScopeClass::Cell);
+        assert!(matches!(
+            teams.classify_subdomain(&named("globex"), &rules),
+            ScopeClass::Refused(RejectReason::InvalidSubdomain { .. })
+        ));
+        assert_eq!(
+            teams.route_event(&geohashes(&["drt2z"]), &named("drt2z"), &rules),
+            ScopeDecision::Store(named("drt2z"))
+        );
+    }
+
+    #[test]
+    fn test_team_rate_limits() {
+        let mut config = RelayConfig { events_per_minute: 120, ..Default::default() };
+        let teams = TeamScopePolicy::new(["acme", "globex"], 30);
+        config.scope_events_per_minute.insert("globex".to_string(), 5);
+        assert_eq!(teams.rate_limit_for(&named("acme"), &config), 30);
+        assert_eq!(teams.rate_limit_for(&named("globex"), &config), 5);
+        assert_eq!(teams.rate_limit_for(&Scope::Default, &config), 120);
+        assert_eq!(DefaultScopePolicy.rate_limit_for(&named("acme"), &config), 120);
+    }
+}
@@ -0,0 +1,278 @@
+//! Scope warm-up and idle eviction
+//!
+//! The first access to a named scope opens it in LMDB, which a client
+//! notices as a slow first write. Scopes in `preload_scopes` (or every
+//! stored scope, with `all`) are read once at startup so hot cells are
+//! already open when their first event arrives.
+//!
+//! The other way round, a relay that has seen thousands of one-event cells
+//! keeps per-scope state for all of them. `ScopeResidency` remembers when
+//! each scope was last written to; scopes idle for `scope_idle_secs` are
+//! evicted, releasing what registered `ScopeResources` hold for them (rate
+//! limit buckets, duplicate windows, cached query results). All of it is
+//! rebuilt on the scope's next write.
+//!
+//! `relay_scopes_open` is the number of resident scopes,
+//! `relay_scope_cold_opens_total` counts writes to scopes that weren't, and
+//! `relay_scope_evictions_total` counts evictions.
+
+use anyhow::Result;
+use dashmap::DashMap;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+use crate::config::{PreloadScopes, RelayConfig};
+use crate::store::{scope_from_label, scope_label, ScopeStore};
+
+/// Longest pause between idle checks
+const MAX_EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Per-scope state that can be dropped while the scope is idle
+pub trait ScopeResources: Send + Sync + 'static {
+    /// Forgets everything held for `scope`
+    fn release(&self, scope: &Scope);
+}
+
+/// Scopes with live in-memory state and when they were last written to
+pub struct ScopeResidency {
+    /// Zero never evicts
+    idle: Duration,
+    /// Touched by every write, so sharded rather than behind one lock
+    last_used: DashMap<Scope, Instant>,
+    resources: Mutex<Vec<Arc<dyn ScopeResources>>>,
+    cold_opens: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl std::fmt::Debug for ScopeResidency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScopeResidency")
+            .field("idle", &self.idle)
+            .field("open", &self.len())
+            .field("resources", &self.resources.lock().len())
+            .finish()
+    }
+}
+
+impl Default for ScopeResidency {
+    fn default() -> Self {
+        Self::new(Duration::ZERO)
+    }
+}
+
+impl ScopeResidency {
+    pub fn new(idle: Duration) -> Self {
+        Self {
+            idle,
+            last_used: DashMap::new(),
+            resources: Mutex::new(Vec::new()),
+            cold_opens: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn for_config(config: &RelayConfig) -> Self {
+        Self::new(Duration::from_secs(config.scope_idle_secs))
+    }
+
+    /// Tracks scopes without ever evicting them, for tests and tooling
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Releases `resources` along with every evicted scope
+    pub fn register(&self, resources: Arc<dyn ScopeResources>) {
+        self.resources.lock().push(resources);
+    }
+
+    /// Notes a write to `scope`, returning false if it wasn't open
+    pub fn touch(&self, scope: &Scope) -> bool {
+        self.touch_at(scope, Instant::now())
+    }
+
+    fn touch_at(&self, scope: &Scope, now: Instant) -> bool {
+        if let Some(mut used) = self.last_used.get_mut(scope) {
+            *used = now;
+            return true;
+        }
+        let was_open = self.last_used.insert(scope.clone(), now).is_some();
+        if !was_open {
+            self.cold_opens.fetch_add(1, Ordering::Relaxed);
+            metrics::counter!("relay_scope_cold_opens_total").increment(1);
+            metrics::gauge!("relay_scopes_open").set(self.last_used.len() as f64);
+        }
+        was_open
+    }
+
+    /// Writes that found their scope closed
+    pub fn cold_opens(&self) -> u64 {
+        self.cold_opens.load(Ordering::Relaxed)
+    }
+
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    /// Scopes currently open
+    pub fn len(&self) -> usize {
+        self.last_used.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Opens `scope` by reading from it, without counting a cold open
+    pub async fn preload(&self, store: &dyn ScopeStore, scope: &Scope) -> Result<()> {
+        store.query(scope, Filter::new().limit(1)).await?;
+        self.last_used.insert(scope.clone(), Instant::now());
+        metrics::gauge!("relay_scopes_open").set(self.last_used.len() as f64);
+        Ok(())
+    }
+
+    /// Preloads the scopes `preload` names, returning how many were opened
+    pub async fn preload_all(&self, store: &dyn ScopeStore, preload: &PreloadScopes) -> Result<usize> {
+        let scopes = match preload {
+            PreloadScopes::None => return Ok(0),
+            PreloadScopes::All => store.scopes().await?,
+            PreloadScopes::Listed(labels) => labels.iter().filter_map(|label| scope_from_label(label)).collect(),
+        };
+        let mut opened = 0;
+        for scope in &scopes {
+            match self.preload(store, scope).await {
+                Ok(()) => opened += 1,
+                Err(e) => warn!("Failed to preload {}: {:#}", scope_label(scope), e),
+            }
+        }
+        Ok(opened)
+    }
+
+    /// Evicts scopes idle for longer than the idle period
+    pub fn evict_idle(&self) -> usize {
+        self.evict_idle_at(Instant::now())
+    }
+
+    fn evict_idle_at(&self, now: Instant) -> usize {
+        if self.idle.is_zero() {
+            return 0;
+        }
+        let mut evicted: Vec<Scope> = Vec::new();
+        self.last_used.retain(|scope, used| {
+            let idle = now.saturating_duration_since(*used) > self.idle;
+            if idle {
+                evicted.push(scope.clone());
+            }
+            !idle
+        });
+        metrics::gauge!("relay_scopes_open").set(self.last_used.len() as f64);
+        if evicted.is_empty() {
+            return 0;
+        }
+        let resources = self.resources.lock().clone();
+        for scope in &evicted {
+            debug!("Evicting idle scope {}", scope_label(scope));
+            for resources in &resources {
+                resources.release(scope);
+            }
+        }
+        self.evictions.fetch_add(evicted.len() as u64, Ordering::Relaxed);
+        metrics::counter!("relay_scope_evictions_total").increment(evicted.len() as u64);
+        evicted.len()
+    }
+}
+
+/// Spawns the periodic idle check; does nothing when eviction is disabled
+pub fn spawn_scope_eviction(residency: Arc<ScopeResidency>) {
+    if residency.idle.is_zero() {
+        return;
+    }
+    let interval = residency.idle.min(MAX_EVICTION_INTERVAL);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            residency.evict_idle();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[derive(Default)]
+    struct Released(Mutex<Vec<Scope>>);
+
+    impl ScopeResources for Released {
+        fn release(&self, scope: &Scope) {
+            self.0.lock().push(scope.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_preloaded_scope_skips_cold_open() {
+        let store = MemoryStore::new();
+        let residency = ScopeResidency::disabled();
+        let preload = PreloadScopes::Listed(vec!["root".to_string(), "drt2z".to_string()]);
+        assert_eq!(residency.preload_all(&store, &preload).await.unwrap(), 2);
+        assert_eq!(residency.cold_opens(), 0);
+
+        assert!(residency.touch(&Scope::named("drt2z").unwrap()));
+        assert!(residency.touch(&Scope::Default));
+        assert_eq!(residency.cold_opens(), 0);
+
+        assert!(!residency.touch(&Scope::named("9q8yy").unwrap()));
+        assert_eq!(residency.cold_opens(), 1);
+        assert_eq!(residency.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_preload_all_opens_stored_scopes() {
+        let store = MemoryStore::new();
+        let drt2z = Scope::named("drt2z").unwrap();
+        store.save(&drt2z, EventBuilder::text_note("hi").sign_with_keys(&Keys::generate()).unwrap()).await.unwrap();
+
+        let residency = ScopeResidency::disabled();
+        assert_eq!(residency.preload_all(&store, &PreloadScopes::All).await.unwrap(), 2);
+        assert!(residency.touch(&drt2z));
+        assert_eq!(residency.preload_all(&store, &PreloadScopes::None).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_idle_scopes_are_evicted_and_reopened() {
+        let residency = ScopeResidency::new(Duration::from_secs(600));
+        let released = Arc::new(Released::default());
+        residency.register(released.clone());
+
+        let start = Instant::now();
+        let quiet = Scope::named("drt2z").unwrap();
+        let busy = Scope::named("9q8yy").unwrap();
+        residency.touch_at(&quiet, start);
+        residency.touch_at(&busy, start);
+        residency.touch_at(&busy, start + Duration::from_secs(500));
+
+        assert_eq!(residency.evict_idle_at(start + Duration::from_secs(600)), 0);
+        assert_eq!(residency.evict_idle_at(start + Duration::from_secs(601)), 1);
+        assert_eq!(*released.0.lock(), vec![quiet.clone()]);
+        assert_eq!(residency.evictions(), 1);
+        assert_eq!(residency.len(), 1);
+
+        // The next write opens it again
+        assert!(!residency.touch_at(&quiet, start + Duration::from_secs(700)));
+        assert_eq!(residency.cold_opens(), 3);
+    }
+
+    #[test]
+    fn test_disabled_never_evicts() {
+        let residency = ScopeResidency::disabled();
+        let start = Instant::now();
+        residency.touch_at(&Scope::Default, start);
+        assert_eq!(residency.evict_idle_at(start + Duration::from_secs(86_400)), 0);
+        assert_eq!(residency.len(), 1);
+    }
+}
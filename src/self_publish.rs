@@ -0,0 +1,257 @@
+//! Events the relay publishes about itself
+//!
+//! At startup the relay signs a kind 0 profile (from the operator branding)
+//! and a NIP-65 kind 10002 relay list into the root scope. When enabled, each
+//! geohash scope that already has events also gets a small kind 0 describing
+//! the cell. Events are only re-signed when their content differs from what
+//! is stored, so restarts don't churn replaceable events.
+//!
+//! Signed events go through `GeohashedEventProcessor::publish_internal`,
+//! which routes them like any other event but skips the kind lists, proof
+//! of work, rate limits and other write policies meant for clients.
+
+use anyhow::Result;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::StoreCommand;
+use tracing::{debug, info, warn};
+use crate::config::RelayConfig;
+use crate::geohash_utils::describe_cell;
+use crate::nip11::DEFAULT_RELAY_NAME;
+use crate::processor::GeohashedEventProcessor;
+use crate::scope_policy::ScopePolicy;
+use crate::store::{scope_label, ScopeStore};
+
+/// Content and tags of an event the relay wants to have stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DesiredEvent {
+    pub kind: Kind,
+    pub content: String,
+    pub tags: Vec<Vec<String>>,
+}
+
+impl DesiredEvent {
+    fn matches(&self, event: &Event) -> bool {
+        let tags: Vec<Vec<String>> = event.tags.iter().map(|tag| tag.clone().to_vec()).collect();
+        event.kind == self.kind && event.content == self.content && tags == self.tags
+    }
+
+    async fn sign(&self, keys: &Keys) -> Result<Event> {
+        let tags = self
+            .tags
+            .iter()
+            .map(|tag| Tag::parse(tag.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(EventBuilder::new(self.kind, &self.content).tags(tags).sign(keys).await?)
+    }
+}
+
+/// Kind 0 profile for the relay itself, built from the operator branding
+pub fn relay_metadata(config: &RelayConfig) -> DesiredEvent {
+    let mut metadata = serde_json::Map::new();
+    metadata.insert(
+        "name".to_string(),
+        config.relay_name.as_deref().unwrap_or(DEFAULT_RELAY_NAME).into(),
+    );
+    if let Some(about) = &config.relay_description {
+        metadata.insert("about".to_string(), about.as_str().into());
+    }
+    if let Some(picture) = &config.relay_icon_url {
+        metadata.insert("picture".to_string(), picture.as_str().into());
+    }
+    if let Some(banner) = &config.relay_banner_url {
+        metadata.insert("banner".to_string(), banner.as_str().into());
+    }
+    DesiredEvent {
+        kind: Kind::Metadata,
+        content: serde_json::Value::Object(metadata).to_string(),
+        tags: Vec::new(),
+    }
+}
+
+/// NIP-65 relay list pointing at the relay's own URL
+pub fn relay_list(config: &RelayConfig) -> DesiredEvent {
+    DesiredEvent {
+        kind: Kind::RelayList,
+        content: String::new(),
+        tags: vec![vec!["r".to_string(), config.relay_url.clone()]],
+    }
+}
+
+/// Kind 0 describing a geohash cell
+pub fn cell_metadata(config: &RelayConfig, geohash: &str) -> DesiredEvent {
+    let name = config.relay_name.as_deref().unwrap_or(DEFAULT_RELAY_NAME);
+    let metadata = serde_json::json!({
+        "name": format!("{} [{}]", name, geohash),
+        "about": describe_cell(geohash).unwrap_or_default(),
+        "website": config.relay_url_for(Some(geohash)),
+    });
+    DesiredEvent {
+        kind: Kind::Metadata,
+        content: metadata.to_string(),
+        tags: Vec::new(),
+    }
+}
+
+/// Publishes `desired` into `scope` unless an identical event is stored
+///
+/// Returns whether a new event was signed and saved.
+pub async fn publish_if_changed<P: ScopePolicy>(
+    store: &dyn ScopeStore,
+    processor: &GeohashedEventProcessor<P>,
+    keys: &Keys,
+    scope: &Scope,
+    desired: &DesiredEvent,
+) -> Result<bool> {
+    let filter = Filter::new()
+        .author(keys.public_key())
+        .kind(desired.kind)
+        .limit(1);
+    let current = store.query(scope, filter).await?;
+    if current.first().is_some_and(|event| desired.matches(event)) {
+        debug!("Kind {} in {} is up to date", desired.kind, scope_label(scope));
+        return Ok(false);
+    }
+
+    let event = desired.sign(keys).await?;
+    let commands = processor
+        .publish_internal(event, keys.public_key(), scope.clone())
+        .await
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+    for command in commands {
+        if let StoreCommand::SaveSignedEvent(event, target, _) = command {
+            store.save(&target, *event).await?;
+            info!("Published kind {} to {}", desired.kind, scope_label(&target));
+        }
+    }
+    Ok(true)
+}
+
+/// Publishes the relay's own events; called once the relay is built
+pub async fn publish_all<P: ScopePolicy>(
+    store: &dyn ScopeStore,
+    processor: &GeohashedEventProcessor<P>,
+    keys: &Keys,
+    config: &RelayConfig,
+) -> Result<()> {
+    publish_if_changed(store, processor, keys, &Scope::Default, &relay_metadata(config)).await?;
+    publish_if_changed(store, processor, keys, &Scope::Default, &relay_list(config)).await?;
+
+    if config.self_publish_cells {
+        for scope in store.scopes().await? {
+            if let Scope::Named { name, .. } = &scope {
+                let desired = cell_metadata(config, name);
+                if let Err(e) = publish_if_changed(store, processor, keys, &scope, &desired).await {
+                    warn!("Failed to publish cell metadata for {}: {}", name, e);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{LmdbStore, MemoryStore};
+    use relay_builder::RelayDatabase;
+    use std::sync::Arc;
+
+    async fn stored(store: &dyn ScopeStore, keys: &Keys, scope: &Scope, kind: Kind) -> Vec<Event> {
+        store
+            .query(scope, Filter::new().author(keys.public_key()).kind(kind))
+            .await
+            .unwrap()
+    }
+
+    fn processor() -> GeohashedEventProcessor {
+        GeohashedEventProcessor::new()
+    }
+
+    fn branded_config() -> RelayConfig {
+        RelayConfig {
+            relay_url: "wss://example.com".to_string(),
+            relay_name: Some("Hashstr".to_string()),
+            relay_description: Some("Local chat".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publishes_metadata_and_relay_list_to_root() {
+        let store = MemoryStore::new();
+        let keys = Keys::generate();
+        publish_all(&store, &processor(), &keys, &branded_config()).await.unwrap();
+
+        let metadata = stored(&store, &keys, &Scope::Default, Kind::Metadata).await;
+        assert_eq!(metadata.len(), 1);
+        let content: serde_json::Value = serde_json::from_str(&metadata[0].content).unwrap();
+        assert_eq!(content["name"], "Hashstr");
+        assert_eq!(content["about"], "Local chat");
+
+        let relay_list = stored(&store, &keys, &Scope::Default, Kind::RelayList).await;
+        assert_eq!(relay_list.len(), 1);
+        assert_eq!(relay_list[0].tags.iter().next().unwrap().clone().to_vec(), vec!["r", "wss://example.com"]);
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_content_is_not_republished() {
+        let store = MemoryStore::new();
+        let keys = Keys::generate();
+        let desired = relay_metadata(&branded_config());
+
+        assert!(publish_if_changed(&store, &processor(), &keys, &Scope::Default, &desired).await.unwrap());
+        assert!(!publish_if_changed(&store, &processor(), &keys, &Scope::Default, &desired).await.unwrap());
+
+        // Changed branding replaces rather than duplicates
+        let mut config = branded_config();
+        config.relay_name = Some("Renamed".to_string());
+        let changed = relay_metadata(&config);
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        assert!(publish_if_changed(&store, &processor(), &keys, &Scope::Default, &changed).await.unwrap());
+
+        let metadata = stored(&store, &keys, &Scope::Default, Kind::Metadata).await;
+        assert_eq!(metadata.len(), 1);
+        assert!(metadata[0].content.contains("Renamed"));
+    }
+
+    #[tokio::test]
+    async fn test_cell_metadata_only_when_enabled() {
+        let store = MemoryStore::new();
+        let keys = Keys::generate();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let note = EventBuilder::text_note("hi").sign(&Keys::generate()).await.unwrap();
+        store.insert(&drt2z, note);
+
+        publish_all(&store, &processor(), &keys, &branded_config()).await.unwrap();
+        assert!(stored(&store, &keys, &drt2z, Kind::Metadata).await.is_empty());
+
+        let config = RelayConfig {
+            self_publish_cells: true,
+            ..branded_config()
+        };
+        publish_all(&store, &processor(), &keys, &config).await.unwrap();
+        let metadata = stored(&store, &keys, &drt2z, Kind::Metadata).await;
+        assert_eq!(metadata.len(), 1);
+        assert!(metadata[0].content.contains("Hashstr [drt2z]"));
+        assert!(metadata[0].content.contains("wss://drt2z.example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_restart_does_not_duplicate_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = Keys::generate();
+        let config = branded_config();
+
+        for _ in 0..2 {
+            // Each iteration opens the database fresh, like a relay restart
+            let database = Arc::new(RelayDatabase::new(dir.path()).unwrap());
+            let store = LmdbStore::new(database);
+            publish_all(&store, &processor(), &keys, &config).await.unwrap();
+
+            assert_eq!(stored(&store, &keys, &Scope::Default, Kind::Metadata).await.len(), 1);
+            assert_eq!(stored(&store, &keys, &Scope::Default, Kind::RelayList).await.len(), 1);
+        }
+    }
+}
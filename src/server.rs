@@ -0,0 +1,1128 @@
+//! HTTP application: websocket upgrades, info pages and auxiliary routes
+//!
+//! `create_app` assembles the full router served by the binary. Everything
+//! except the websocket route at `/` (and `/{segment}` in `tor_mode`) is
+//! built by `routes`, which lets the HTTP behavior be tested without a relay
+//! handler.
+
+use axum::{
+    extract::{ConnectInfo, Path, Query, RawQuery, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use nostr_lmdb::Scope;
+use relay_builder::{handle_upgrade, HandlerFactory, WebSocketUpgrade};
+use std::{net::{IpAddr, SocketAddr}, sync::Arc};
+use tower::ServiceBuilder;
+use tower_http::{
+    cors::CorsLayer,
+    trace::{DefaultMakeSpan, DefaultOnRequest, DefaultOnResponse, TraceLayer},
+};
+use tracing::Level;
+use crate::api::{self, ApiState};
+use crate::build_info;
+use crate::cells;
+use crate::config::{MissingHostPolicy, RelayConfig};
+use crate::connections::{ConnectionLimit, ConnectionRegistry};
+use crate::geoip::GeoIp;
+use crate::geohash_utils::{is_geohash_subdomain, is_served_geohash_subdomain, normalize_geohash};
+use crate::host_parsing::{client_ip, connection_origin, host_for_scope, host_info, resolve_scope, HostInfo, ROOT_HOST};
+use crate::http_cache::{self, PageCache};
+use crate::ip_filter::IpFilter;
+use crate::maintenance::Maintenance;
+use crate::page_limit::PageRateLimiter;
+use crate::preview::{self, HttpTileFetcher, PreviewService};
+use crate::prometheus;
+use crate::stats::StatsCache;
+use crate::syndication::{self, SyndicationFeeds};
+use crate::trending::{self, TrendingWindow, DEFAULT_TRENDING_LIMIT};
+use crate::{nip05, nip11, pages, replication, sse};
+
+/// Rendering state for info pages
+pub struct InfoPages {
+    config: Arc<RelayConfig>,
+    cache: PageCache,
+    config_revision: u64,
+    base_domain_parts: usize,
+    maintenance: Arc<Maintenance>,
+    limiter: PageRateLimiter,
+    geoip: Arc<GeoIp>,
+    /// Aggregates behind a cell page's "activity in this area" panel
+    stats: Arc<StatsCache>,
+}
+
+impl InfoPages {
+    pub fn new(config: &RelayConfig) -> Self {
+        Self {
+            config: Arc::new(config.clone()),
+            cache: PageCache::default(),
+            config_revision: config.revision(),
+            base_domain_parts: config.base_domain_parts(),
+            maintenance: Arc::new(Maintenance::disabled()),
+            limiter: PageRateLimiter::new(config.info_page_requests_per_minute),
+            geoip: Arc::new(GeoIp::disabled()),
+            stats: Arc::new(StatsCache::new()),
+        }
+    }
+
+    /// Shows the maintenance banner while the shared flag is on
+    pub fn with_maintenance(mut self, maintenance: Arc<Maintenance>) -> Self {
+        self.maintenance = maintenance;
+        self
+    }
+
+    /// Suggests a cell to visitors of the root page
+    pub fn with_geoip(mut self, geoip: Arc<GeoIp>) -> Self {
+        self.geoip = geoip;
+        self
+    }
+
+    /// Lists a cell's active children on its page
+    pub fn with_stats(mut self, stats: Arc<StatsCache>) -> Self {
+        self.stats = stats;
+        self
+    }
+}
+
+/// Seconds clients are told to wait when the connection cap is reached
+const SHED_RETRY_AFTER_SECS: &str = "30";
+
+/// relay_builder's handler factory, with the scope decided by this crate
+///
+/// relay_builder derives a connection's scope from the Host header with its
+/// own parser. Before delegating, the Host is replaced by one that can only
+/// parse to `host_parsing::resolve_scope`'s answer (or the dev scope), so
+/// the websocket path and the HTTP routes always agree.
+pub struct ScopedHandlerFactory<H> {
+    inner: H,
+    base_domain_parts: usize,
+}
+
+impl<H> ScopedHandlerFactory<H> {
+    pub fn new(inner: H, base_domain_parts: usize) -> Self {
+        Self { inner, base_domain_parts }
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    /// Scope a connection with these headers is served in
+    pub fn resolve_scope(&self, headers: &HeaderMap, dev_scope: Option<&str>) -> Scope {
+        match dev_scope.and_then(|scope| Scope::named(scope).ok()) {
+            Some(scope) => scope,
+            None => resolve_scope(headers, self.base_domain_parts),
+        }
+    }
+
+    /// `headers` with the Host rewritten to the canonical one for its scope
+    pub fn scoped_headers(&self, headers: &HeaderMap, dev_scope: Option<&str>) -> HeaderMap {
+        let mut headers = headers.clone();
+        if let Scope::Named { name, .. } = self.resolve_scope(&headers, dev_scope) {
+            if let Ok(host) = HeaderValue::from_str(&host_for_scope(&name, self.base_domain_parts)) {
+                headers.insert(header::HOST, host);
+            }
+        }
+        headers
+    }
+}
+
+/// Shared state for the websocket/info page route
+struct AppState<H> {
+    handler: Arc<ScopedHandlerFactory<H>>,
+    pages: Arc<InfoPages>,
+    connections: Arc<ConnectionRegistry>,
+    limit: Arc<ConnectionLimit>,
+    ip_filter: Arc<IpFilter>,
+}
+
+impl<H> Clone for AppState<H> {
+    fn clone(&self) -> Self {
+        Self {
+            handler: self.handler.clone(),
+            pages: self.pages.clone(),
+            connections: self.connections.clone(),
+            limit: self.limit.clone(),
+            ip_filter: self.ip_filter.clone(),
+        }
+    }
+}
+
+/// Builds the full application router
+pub fn create_app(handler: impl HandlerFactory + Send + Sync + 'static, config: &RelayConfig, api_state: ApiState) -> Router
+{
+    let pages = Arc::new(
+        InfoPages::new(config)
+            .with_maintenance(api_state.maintenance.clone())
+            .with_geoip(api_state.geoip.clone())
+            .with_stats(api_state.stats.clone()),
+    );
+    let state = AppState {
+        handler: Arc::new(ScopedHandlerFactory::new(handler, config.base_domain_parts())),
+        pages: pages.clone(),
+        connections: api_state.connections.clone(),
+        limit: Arc::new(ConnectionLimit::new(
+            config.max_connections,
+            config.reserved_connections,
+            config.trusted_proxies.clone(),
+        )),
+        ip_filter: api_state.ip_filter.clone(),
+    };
+
+    let mut app = Router::new().route("/", get(websocket_handler));
+    if config.tor_mode {
+        app = app.route("/{segment}", get(path_scoped_handler));
+    }
+    let mut app = app
+        .route_layer(middleware::from_fn_with_state(pages.clone(), info_page_limit))
+        .with_state(state)
+        .merge(routes(config, pages, api_state))
+        .layer(middleware::from_fn_with_state(config.missing_host_policy, missing_host));
+    if let Some(host) = forced_host(config) {
+        app = app.layer(middleware::from_fn_with_state(host, force_host));
+    }
+
+    app.layer(
+            ServiceBuilder::new()
+                .layer(
+                    TraceLayer::new_for_http()
+                        .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+                        .on_request(DefaultOnRequest::new().level(Level::DEBUG))
+                        .on_response(DefaultOnResponse::new().level(Level::DEBUG)),
+                )
+                .layer(CorsLayer::permissive())
+                // Upgrade responses are excluded by the predicate, so the
+                // websocket path is unaffected
+                .layer(http_cache::compression_layer()),
+        )
+}
+
+/// All HTTP routes except the websocket/info page at `/`
+///
+/// Static routes always win over the `/{segment}` capture in axum, so
+/// `/health`, `/version`, `/trending`, `/metrics`, `/preview.png`, `/feed`,
+/// `/feed.json`, `/feed.atom`, `/.well-known/nostr.json` and anything under
+/// `/api/` or `/replication/` are never treated as geohash paths.
+pub fn routes(config: &RelayConfig, pages: Arc<InfoPages>, api_state: ApiState) -> Router {
+    let feeds = config
+        .syndication_feeds
+        .then(|| Arc::new(SyndicationFeeds::new(config, api_state.store.clone())));
+
+    let mut app = Router::new();
+    // In tor_mode create_app serves it, so websockets can connect there too
+    if !config.tor_mode {
+        app = app
+            .route("/{segment}", get(segment_handler))
+            .route_layer(middleware::from_fn_with_state(pages.clone(), info_page_limit));
+    }
+    let mut app = app
+        .route("/health", get(health_check).with_state(api_state.clone()))
+        .route("/version", get(version_handler))
+        .route("/trending", get(trending_page).with_state(api_state.clone()))
+        .with_state(pages)
+        .merge(nip05::router(api_state.nip05.clone()))
+        .merge(sse::router(api_state.sse.clone()))
+        .merge(api::router(api_state.clone()));
+
+    if let Some(leader) = api_state.replication_leader {
+        app = app.merge(replication::router(leader));
+    }
+
+    if config.preview_enabled {
+        let previews = PreviewService::new(
+            HttpTileFetcher::new(config.preview_tile_url.clone()),
+            config.preview_cache_path(),
+            config.preview_renders_per_minute,
+        )
+        .with_base_domain_parts(config.base_domain_parts());
+        app = app.merge(preview::router(Arc::new(previews)));
+    }
+
+    if let Some(feeds) = feeds {
+        app = app.merge(syndication::router(feeds));
+    }
+
+    if config.metrics_enabled {
+        app = app.route("/metrics", get(metrics_handler));
+    }
+
+    app
+}
+
+/// Applies `missing_host_policy` before any route, upgrades included
+///
+/// HTTP/2 requests carry the host in the URI authority instead, which is
+/// copied into the header so scope resolution sees it. Otherwise the request
+/// is refused with 400 or pinned to the root scope, never left to whatever
+/// an extractor would default to.
+async fn missing_host(State(policy): State<MissingHostPolicy>, mut request: Request, next: Next) -> Response {
+    if request.headers().contains_key(header::HOST) {
+        return next.run(request).await;
+    }
+    let authority = request
+        .uri()
+        .authority()
+        .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok());
+    if let Some(host) = authority {
+        request.headers_mut().insert(header::HOST, host);
+        return next.run(request).await;
+    }
+
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    match policy {
+        MissingHostPolicy::Reject => {
+            tracing::info!("Refusing {} {} from {}: no Host header", request.method(), request.uri(), peer);
+            (StatusCode::BAD_REQUEST, "Host header required").into_response()
+        },
+        MissingHostPolicy::Root => {
+            tracing::info!("No Host header on {} {} from {}, serving root", request.method(), request.uri(), peer);
+            request.headers_mut().insert(header::HOST, HeaderValue::from_static(ROOT_HOST));
+            next.run(request).await
+        },
+    }
+}
+
+/// Host value that resolves to `forced_scope`, if one is configured
+///
+/// The base domain comes from `relay_url` (or `base_domain`) so pages link
+/// to the public host; without one it falls back to a `localhost` host.
+fn forced_host(config: &RelayConfig) -> Option<HeaderValue> {
+    let scope = config.forced_scope.as_deref()?;
+    let host = match (scope, config.base_domain()) {
+        ("root", Some(domain)) => domain,
+        ("root", None) => ROOT_HOST.to_string(),
+        (cell, Some(domain)) => format!("{}.{}", cell, domain),
+        (cell, None) => host_for_scope(cell, config.base_domain_parts()),
+    };
+    HeaderValue::from_str(&host).ok()
+}
+
+/// Pins every request to `forced_scope` by replacing its Host header
+///
+/// Runs before `missing_host`, so requests without a Host are served too.
+async fn force_host(State(host): State<HeaderValue>, mut request: Request, next: Next) -> Response {
+    request.headers_mut().insert(header::HOST, host);
+    next.run(request).await
+}
+
+/// Applies `info_page_requests_per_minute` to the info page routes
+///
+/// Websocket upgrades and NIP-11 documents share the routes but not the
+/// limit. Requests without a peer address (only possible in tests) pass.
+async fn info_page_limit(State(pages): State<Arc<InfoPages>>, request: Request, next: Next) -> Response {
+    let headers = request.headers();
+    if headers.contains_key(header::UPGRADE) || nip11::wants_relay_information(headers) {
+        return next.run(request).await;
+    }
+    let Some(ConnectInfo(peer)) = request.extensions().get::<ConnectInfo<SocketAddr>>() else {
+        return next.run(request).await;
+    };
+    let ip = client_ip(headers, peer.ip(), &pages.config);
+    match pages.limiter.check(ip) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, wait.as_secs().max(1).to_string())],
+            "Too many requests, try again later",
+        )
+            .into_response(),
+    }
+}
+
+async fn websocket_handler<H>(
+    ws: Option<WebSocketUpgrade>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    State(state): State<AppState<H>>,
+) -> Response
+where
+    H: HandlerFactory + Send + Sync + 'static,
+{
+    let scope = match dev_scope(&state.pages.config, query.as_deref()) {
+        Ok(scope) => scope,
+        Err(response) => return response,
+    };
+
+    match ws {
+        Some(ws) => upgrade(&state, ws, addr, &headers, scope.as_deref()).await,
+        None => {
+            let client = client_ip(&headers, addr.ip(), &state.pages.config);
+            root_info_page(&state.pages, &headers, scope.as_deref(), Some(client))
+        },
+    }
+}
+
+/// `/{segment}` in `tor_mode`: websockets to `/{geohash}` connect to that
+/// cell, anything else gets the path-routed page
+async fn path_scoped_handler<H>(
+    ws: Option<WebSocketUpgrade>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(segment): Path<String>,
+    headers: HeaderMap,
+    State(state): State<AppState<H>>,
+) -> Response
+where
+    H: HandlerFactory + Send + Sync + 'static,
+{
+    match ws {
+        Some(ws) if is_served_geohash_subdomain(&segment, &state.pages.config.allowed_precisions) => {
+            upgrade(&state, ws, addr, &headers, Some(&segment.to_lowercase())).await
+        },
+        _ => segment_handler(Path(segment), headers, State(state.pages.clone())).await,
+    }
+}
+
+/// Accepts a websocket in `scope`, or the Host header's scope when `None`
+async fn upgrade<H>(
+    state: &AppState<H>,
+    ws: WebSocketUpgrade,
+    addr: SocketAddr,
+    headers: &HeaderMap,
+    scope: Option<&str>,
+) -> Response
+where
+    H: HandlerFactory + Send + Sync + 'static,
+{
+    let ip = client_ip(headers, addr.ip(), &state.pages.config);
+    if !state.ip_filter.admits(ip) {
+        tracing::info!("Refusing websocket from {}: not allowed by the IP lists", ip);
+        return (StatusCode::FORBIDDEN, "Forbidden").into_response();
+    }
+    // Shed load before accepting rather than starving open connections
+    if !state.limit.admits(&state.connections, addr.ip()) {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, SHED_RETRY_AFTER_SECS)],
+            "Relay is at capacity, try again later",
+        )
+            .into_response();
+    }
+    // Oversized frames, or fragments adding up to too much, close the
+    // connection at the transport instead of being buffered and parsed
+    let max_size = state.pages.config.max_websocket_message_size();
+    let ws = ws.max_frame_size(max_size).max_message_size(max_size);
+    let origin = connection_origin(headers, addr.ip(), &state.pages.config);
+    state.connections.expect_origin(addr, origin);
+    let headers = state.handler.scoped_headers(headers, scope);
+    let h = state.handler.inner().create(&headers);
+    handle_upgrade(ws, addr, h).await
+}
+
+/// Scope requested with `?scope=` when `dev_scope_query_param` is set
+///
+/// `Ok(None)` when the flag is off, `forced_scope` is set or no scope was
+/// given; a 400 response when the requested scope isn't a served geohash
+/// cell.
+fn dev_scope(config: &RelayConfig, query: Option<&str>) -> Result<Option<String>, Response> {
+    if !config.dev_scope_query_param || config.forced_scope.is_some() {
+        return Ok(None);
+    }
+    let requested = query
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find_map(|(key, value)| (key == "scope").then_some(value));
+    match requested {
+        None => Ok(None),
+        Some(scope) if is_served_geohash_subdomain(scope, &config.allowed_precisions) => Ok(Some(scope.to_lowercase())),
+        Some(_) => Err((StatusCode::BAD_REQUEST, "scope must be a geohash").into_response()),
+    }
+}
+
+/// Info page for `/`, for the dev scope if one was requested, otherwise for
+/// the Host header's subdomain
+///
+/// On the root domain the page suggests `client`'s cell when GeoIP finds
+/// one; that page is the visitor's alone, so it skips every cache.
+fn root_info_page(pages: &InfoPages, headers: &HeaderMap, dev_scope: Option<&str>, client: Option<IpAddr>) -> Response {
+    let HostInfo { subdomain, domain } = host_info(headers, pages.base_domain_parts);
+    let subdomain = dev_scope.or(subdomain.as_deref());
+    if subdomain.is_none() && !nip11::wants_relay_information(headers) {
+        if let Some(cell) = client.and_then(|ip| pages.geoip.suggest_cell(ip)) {
+            let message = pages.maintenance.message();
+            let page = pages::render_root_page_near(&cell, &domain, &pages.config, writes_refused(&pages.maintenance, &message));
+            return ([(header::CACHE_CONTROL, "private, no-store")], Html(page)).into_response();
+        }
+    }
+    info_page_response(pages, headers, subdomain, &domain)
+}
+
+/// Banner for the info page; maintenance wins over read-only mode
+fn writes_refused<'a>(maintenance: &Maintenance, message: &'a Option<String>) -> Option<pages::WritesRefused<'a>> {
+    match message {
+        Some(message) => Some(pages::WritesRefused::Maintenance(message)),
+        None => maintenance.is_read_only().then_some(pages::WritesRefused::ReadOnly),
+    }
+}
+
+/// Serves the info page (or NIP-11 document) for a scope
+fn info_page_response(pages: &InfoPages, headers: &HeaderMap, subdomain: Option<&str>, domain: &str) -> Response {
+    // NIP-11 clients get the relay information document instead
+    if nip11::wants_relay_information(headers) {
+        let info = nip11::relay_information(&pages.config, subdomain);
+        return Response::builder()
+            .status(200)
+            .header("content-type", nip11::NIP11_CONTENT_TYPE)
+            .body(serde_json::to_string(&info).unwrap_or_default().into())
+            .unwrap();
+    }
+
+    // The ETag only depends on the page inputs, so a matching
+    // If-None-Match is answered without rendering anything. Cell pages
+    // change with every stats run
+    let revision = pages
+        .config_revision
+        .wrapping_add(pages.maintenance.revision())
+        .wrapping_add(pages.stats.computed_at().unwrap_or_default());
+    let etag = http_cache::etag_for(subdomain, domain, revision);
+    if http_cache::if_none_match(headers, &etag) {
+        return http_cache::not_modified(&etag, http_cache::DEFAULT_PAGE_TTL);
+    }
+
+    let cache_key = format!("{}|{}", subdomain.unwrap_or(""), domain);
+    let page = pages.cache.get_or_render(&cache_key, &etag, || {
+        // Generate informative HTML based on current scope
+        let message = pages.maintenance.message();
+        let refused = writes_refused(&pages.maintenance, &message);
+        match subdomain.and_then(normalize_geohash) {
+            Some(cell) => {
+                let children = cells::children_activity(&pages.stats, &pages.config, &cell);
+                pages::render_cell_page(&cell, domain, &pages.config, refused, &children)
+            }
+            None => pages::render_info_page(subdomain, domain, &pages.config, refused),
+        }
+    });
+    http_cache::html_response(headers, &page, http_cache::DEFAULT_PAGE_TTL)
+}
+
+/// `example.com/drt2z` → `https://drt2z.example.com/`
+///
+/// Only applies on the root domain. In path-routing mode the cell page is
+/// served directly instead of redirecting.
+async fn segment_handler(
+    Path(segment): Path<String>,
+    headers: HeaderMap,
+    State(pages): State<Arc<InfoPages>>,
+) -> Response {
+    let HostInfo { subdomain, domain } = host_info(&headers, pages.base_domain_parts);
+    if subdomain.is_some() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    if !is_geohash_subdomain(&segment) {
+        return (StatusCode::NOT_FOUND, Html(pages::render_not_found(&segment, &domain))).into_response();
+    }
+
+    let geohash = segment.to_lowercase();
+    if pages.config.path_routing {
+        return info_page_response(&pages, &headers, Some(&geohash), &domain);
+    }
+
+    let scheme = if pages.config.relay_url.starts_with("ws://") { "http" } else { "https" };
+    let port = headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.split_once(':'))
+        .map(|(_, port)| format!(":{}", port))
+        .unwrap_or_default();
+    let location = format!("{}://{}.{}{}/", scheme, geohash, domain, port);
+    (StatusCode::MOVED_PERMANENTLY, [(header::LOCATION, location)]).into_response()
+}
+
+/// Busiest cells as a page on the root domain
+async fn trending_page(
+    State(state): State<ApiState>,
+    Query(query): Query<api::TrendingQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let HostInfo { subdomain, domain } = host_info(&headers, state.config.base_domain_parts());
+    if subdomain.is_some() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let window = match query.window.as_deref().map(str::parse::<TrendingWindow>) {
+        None => TrendingWindow::default(),
+        Some(Ok(window)) => window,
+        Some(Err(e)) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    let report = trending::trending(&state.stats, &state.config, window, DEFAULT_TRENDING_LIMIT);
+    Html(pages::render_trending_page(&report, &domain)).into_response()
+}
+
+/// Build info, with status "degraded" while storage pressure, maintenance or
+/// read-only mode refuses writes, or a follower is cut off from its leader
+pub async fn health_check(State(state): State<ApiState>) -> Json<build_info::Health> {
+    let mut health = build_info::health();
+    if state.disk.is_read_only() || state.write_failures.is_degraded() {
+        health.status = "degraded";
+    }
+    if let Some(message) = state.maintenance.message() {
+        health.status = "degraded";
+        health.maintenance = Some(message);
+    }
+    if state.maintenance.is_read_only() {
+        health.status = "degraded";
+        health.read_only = true;
+    }
+    if let Some(replica) = &state.replica {
+        if !replica.is_connected() {
+            health.status = "degraded";
+        }
+        health.replication = Some(replica.health());
+    }
+    Json(health)
+}
+
+/// Plain-text version for simple probes
+pub async fn version_handler() -> String {
+    build_info::version_string()
+}
+
+/// Everything recorded through `metrics`, in Prometheus text format
+pub async fn metrics_handler() -> String {
+    prometheus::render()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity::ScopeActivity;
+    use crate::connections::ConnectionRegistry;
+    use crate::storage::DiskWatermark;
+    use axum::{body::{to_bytes, Body}, http::Request};
+    use tower::ServiceExt;
+
+    fn test_config() -> RelayConfig {
+        RelayConfig {
+            relay_url: "wss://example.com".to_string(),
+            preview_enabled: false,
+            ..Default::default()
+        }
+    }
+
+    fn test_routes(config: RelayConfig) -> Router {
+        test_routes_with_disk(config, DiskWatermark::disabled())
+    }
+
+    fn test_routes_with_disk(config: RelayConfig, disk: DiskWatermark) -> Router {
+        test_routes_with(config, disk, Arc::new(Maintenance::disabled()))
+    }
+
+    fn test_routes_with(config: RelayConfig, disk: DiskWatermark, maintenance: Arc<Maintenance>) -> Router {
+        let api_state = test_api_state(&config, disk, maintenance.clone());
+        routes(&config, Arc::new(InfoPages::new(&config).with_maintenance(maintenance)), api_state)
+    }
+
+    fn test_api_state(config: &RelayConfig, disk: DiskWatermark, maintenance: Arc<Maintenance>) -> ApiState {
+        let store: Arc<dyn crate::store::ScopeStore> = Arc::new(crate::store::MemoryStore::new());
+        ApiState {
+            config: Arc::new(config.clone()),
+            stats: Arc::new(StatsCache::new()),
+            connections: Arc::new(ConnectionRegistry::new()),
+            sse: Arc::new(sse::SseFeed::new(
+                Arc::new(config.clone()),
+                store.clone(),
+                Arc::new(crate::live::LiveEvents::new()),
+            )),
+            store,
+            disk: Arc::new(disk),
+            write_failures: Arc::new(crate::storage::WriteFailures::disabled()),
+            admissions: Arc::new(crate::admissions::AdmissionList::in_memory()),
+            nip05: Arc::new(nip05::Nip05Directory::new(config, &nostr_sdk::prelude::Keys::generate().public_key())),
+            replication_leader: None,
+            replica: None,
+            maintenance,
+            activity: Arc::new(ScopeActivity::new()),
+            pow: Arc::new(crate::pow::PowController::disabled()),
+            blocklist: Arc::new(crate::blocklist::Blocklist::disabled()),
+            first_seen: Arc::new(crate::first_seen::FirstSeen::in_memory()),
+            tombstones: Arc::new(crate::tombstones::Tombstones::disabled()),
+            wot: Arc::new(crate::wot::WebOfTrust::disabled()),
+            ip_filter: Arc::new(crate::ip_filter::IpFilter::disabled()),
+            geoip: Arc::new(GeoIp::disabled()),
+        }
+    }
+
+    async fn get(app: Router, host: &str, uri: &str) -> Response {
+        app.oneshot(
+            Request::builder()
+                .uri(uri)
+                .header("host", host)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn get_without_host(app: Router, policy: MissingHostPolicy, uri: &str) -> Response {
+        app.layer(middleware::from_fn_with_state(policy, missing_host))
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn body_string(response: Response) -> String {
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_geohash_path_redirects_to_subdomain() {
+        let response = get(test_routes(test_config()), "example.com", "/drt2z").await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()[header::LOCATION], "https://drt2z.example.com/");
+
+        // Normalized to lowercase, port preserved
+        let response = get(test_routes(test_config()), "example.com:8443", "/DRT2Z").await;
+        assert_eq!(response.headers()[header::LOCATION], "https://drt2z.example.com:8443/");
+    }
+
+    #[tokio::test]
+    async fn test_plain_ws_relay_redirects_to_http() {
+        let config = RelayConfig {
+            relay_url: "ws://localhost:8080".to_string(),
+            ..test_config()
+        };
+        let response = get(test_routes(config), "localhost:8080", "/drt2z").await;
+        assert_eq!(response.headers()[header::LOCATION], "http://drt2z.localhost:8080/");
+    }
+
+    #[tokio::test]
+    async fn test_non_geohash_path_is_404_with_hint() {
+        let response = get(test_routes(test_config()), "example.com", "/team1").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let html = body_string(response).await;
+        assert!(html.contains("is not a geohash"));
+        assert!(html.contains("example.com/drt2z"));
+    }
+
+    #[tokio::test]
+    async fn test_geohash_path_on_subdomain_is_404() {
+        let response = get(test_routes(test_config()), "9q8yy.example.com", "/drt2z").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_path_routing_serves_cell_page() {
+        let config = RelayConfig {
+            path_routing: true,
+            ..test_config()
+        };
+        let response = get(test_routes(config), "example.com", "/drt2z").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let html = body_string(response).await;
+        assert!(html.contains("drt2z Nostr Relay"));
+    }
+
+    #[test]
+    fn test_dev_scope_ignored_unless_enabled() {
+        assert_eq!(dev_scope(&test_config(), Some("scope=drt2z")).ok(), Some(None));
+        assert_eq!(dev_scope(&test_config(), Some("scope=foobar")).ok(), Some(None));
+    }
+
+    #[test]
+    fn test_dev_scope_query_param() {
+        let config = RelayConfig {
+            dev_scope_query_param: true,
+            ..test_config()
+        };
+        assert_eq!(dev_scope(&config, Some("scope=DRT2Z")).ok(), Some(Some("drt2z".to_string())));
+        assert_eq!(dev_scope(&config, Some("x=1&scope=9q8yy")).ok(), Some(Some("9q8yy".to_string())));
+        assert_eq!(dev_scope(&config, Some("x=1")).ok(), Some(None));
+        assert_eq!(dev_scope(&config, None).ok(), Some(None));
+
+        let Err(response) = dev_scope(&config, Some("scope=foobar")) else {
+            panic!("invalid scope accepted");
+        };
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_scoped_headers_canonicalize_the_host() {
+        let factory = ScopedHandlerFactory::new((), 2);
+        let drt2z = Scope::named("drt2z").unwrap();
+        let headers_for = |host: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::HOST, HeaderValue::from_static(host));
+            headers
+        };
+
+        for host in ["drt2z.example.com", "DRT2Z.example.com:443", "drt2z.example.com."] {
+            let scoped = factory.scoped_headers(&headers_for(host), None);
+            assert_eq!(resolve_scope(&scoped, 2), drt2z, "{}", host);
+            assert_eq!(factory.resolve_scope(&headers_for(host), None), drt2z, "{}", host);
+        }
+
+        // The dev scope wins over the Host header
+        let scoped = factory.scoped_headers(&headers_for("example.com"), Some("9q8yy"));
+        assert_eq!(resolve_scope(&scoped, 2), Scope::named("9q8yy").unwrap());
+
+        // Root requests are passed on untouched
+        let root = headers_for("example.com:8080");
+        assert_eq!(factory.scoped_headers(&root, None), root);
+    }
+
+    #[tokio::test]
+    async fn test_dev_scope_info_page() {
+        let pages = InfoPages::new(&test_config());
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("localhost:8080"));
+
+        let html = body_string(root_info_page(&pages, &headers, Some("drt2z"), None)).await;
+        assert!(html.contains("drt2z Nostr Relay"));
+
+        let html = body_string(root_info_page(&pages, &headers, None, None)).await;
+        assert!(!html.contains("drt2z Nostr Relay"));
+    }
+
+    fn forced(app: Router, config: &RelayConfig) -> Router {
+        app.layer(middleware::from_fn_with_state(forced_host(config).unwrap(), force_host))
+    }
+
+    #[tokio::test]
+    async fn test_forced_scope_ignores_host() {
+        let config = RelayConfig {
+            forced_scope: Some("drt2z".to_string()),
+            ..test_config()
+        };
+        let pages = Arc::new(InfoPages::new(&config));
+        let app = forced(
+            Router::new().route("/", get(move |headers: HeaderMap| async move { root_info_page(&pages, &headers, None, None) })),
+            &config,
+        );
+        for host in ["example.com", "9q8yy.example.com", "10.0.0.5:8080"] {
+            let html = body_string(get(app.clone(), host, "/").await).await;
+            assert!(html.contains("drt2z Nostr Relay"), "{}", host);
+        }
+
+        // Root-only pages are gone even on the root domain
+        let response = get(forced(test_routes(config.clone()), &config), "example.com", "/trending").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let config = RelayConfig {
+            forced_scope: Some("root".to_string()),
+            ..test_config()
+        };
+        let response = get(forced(test_routes(config.clone()), &config), "drt2z.example.com", "/trending").await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_host_honored_without_forced_scope() {
+        assert!(forced_host(&test_config()).is_none());
+        let pages = InfoPages::new(&test_config());
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("9q8yy.example.com"));
+        let html = body_string(root_info_page(&pages, &headers, None, None)).await;
+        assert!(html.contains("9q8yy Nostr Relay"));
+        assert!(!html.contains("drt2z Nostr Relay"));
+    }
+
+    #[test]
+    fn test_forced_host_without_base_domain() {
+        let config = RelayConfig {
+            relay_url: "ws://localhost:8080".to_string(),
+            forced_scope: Some("drt2z".to_string()),
+            ..Default::default()
+        };
+        let host = forced_host(&config).unwrap();
+        let HostInfo { subdomain, .. } = crate::host_parsing::parse_host(host.to_str().unwrap(), config.base_domain_parts());
+        assert_eq!(subdomain.as_deref(), Some("drt2z"));
+        // Forced scopes win over `?scope=`
+        let config = RelayConfig { dev_scope_query_param: true, ..config };
+        assert_eq!(dev_scope(&config, Some("scope=9q8yy")).ok(), Some(None));
+    }
+
+    #[tokio::test]
+    async fn test_root_page_geoip_suggestion() {
+        let fixture = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/geoip-test.mmdb");
+        let pages = InfoPages::new(&test_config()).with_geoip(Arc::new(GeoIp::open(fixture, 5).unwrap()));
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("example.com"));
+
+        let response = root_info_page(&pages, &headers, None, Some("81.2.69.160".parse().unwrap()));
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "private, no-store");
+        assert!(body_string(response).await.contains("Your local cell is probably <strong>drt2z</strong>"));
+
+        // Misses get the shared root page
+        let response = root_info_page(&pages, &headers, None, Some("192.0.2.1".parse().unwrap()));
+        assert!(response.headers()[header::CACHE_CONTROL].to_str().unwrap().starts_with("public"));
+        assert!(!body_string(response).await.contains("Your local cell"));
+
+        // Cell pages never suggest anything
+        headers.insert(header::HOST, HeaderValue::from_static("9q8yy.example.com"));
+        let html = body_string(root_info_page(&pages, &headers, None, Some("81.2.69.160".parse().unwrap()))).await;
+        assert!(!html.contains("Your local cell"));
+    }
+
+    #[tokio::test]
+    async fn test_trending_page_only_on_root_domain() {
+        let response = get(test_routes(test_config()), "example.com", "/trending?window=24h").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(body_string(response).await.contains("Trending cells in the last 24 hours"));
+
+        let response = get(test_routes(test_config()), "example.com", "/trending?window=week").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = get(test_routes(test_config()), "drt2z.example.com", "/trending").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_existing_routes_take_precedence() {
+        let config = RelayConfig {
+            metrics_enabled: true,
+            ..test_config()
+        };
+
+        let response = get(test_routes(config.clone()), "example.com", "/health").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let health: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(health["status"], "ok");
+
+        let response = get(test_routes(config.clone()), "example.com", "/metrics").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = get(test_routes(config.clone()), "example.com", "/api/stats").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+
+        // Nested paths never reach the segment route
+        let response = get(test_routes(config), "example.com", "/assets/drt2z").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response.headers().get(header::LOCATION).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_health_reports_build_info() {
+        let response = get(test_routes(test_config()), "example.com", "/health").await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        let health: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(health["version"], build_info::VERSION);
+        assert_eq!(health["commit"], build_info::GIT_COMMIT);
+        assert!(health["uptime_secs"].is_u64());
+    }
+
+    #[tokio::test]
+    async fn test_health_reports_storage_pressure() {
+        let config = RelayConfig {
+            disk_watermark_percent: 90,
+            ..test_config()
+        };
+        let disk = DiskWatermark::with_provider(&config, crate::storage::FsDiskUsage);
+        disk.observe(97);
+        let response = get(test_routes_with_disk(config, disk), "example.com", "/health").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let health: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(health["status"], "degraded");
+    }
+
+    #[tokio::test]
+    async fn test_health_reports_repeated_write_failures() {
+        let config = RelayConfig {
+            storage_error_threshold: 2,
+            ..test_config()
+        };
+        let mut api_state = test_api_state(&config, DiskWatermark::disabled(), Arc::new(Maintenance::disabled()));
+        let failures = Arc::new(crate::storage::WriteFailures::new(&config));
+        api_state.write_failures = failures.clone();
+        let app = routes(&config, Arc::new(InfoPages::new(&config)), api_state);
+
+        let scope = nostr_lmdb::Scope::named("drt2z").unwrap();
+        let id = nostr_sdk::prelude::EventId::all_zeros();
+        let err = anyhow::anyhow!("Input/output error");
+        failures.record(&scope, id, &err);
+        let health: serde_json::Value =
+            serde_json::from_str(&body_string(get(app.clone(), "example.com", "/health").await).await).unwrap();
+        assert_eq!(health["status"], "ok");
+
+        failures.record(&scope, id, &err);
+        let health: serde_json::Value =
+            serde_json::from_str(&body_string(get(app, "example.com", "/health").await).await).unwrap();
+        assert_eq!(health["status"], "degraded");
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_degrades_health_and_shows_on_info_page() {
+        let maintenance = Arc::new(Maintenance::disabled());
+        let config = RelayConfig { path_routing: true, ..test_config() };
+        let app = test_routes_with(config, DiskWatermark::disabled(), maintenance.clone());
+        let page = body_string(get(app.clone(), "example.com", "/drt2z").await).await;
+        assert!(!page.contains("Down for maintenance"));
+
+        maintenance.set(true, Some("migrating storage".to_string())).unwrap();
+        let response = get(app.clone(), "example.com", "/health").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let health: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(health["status"], "degraded");
+        assert_eq!(health["maintenance"], "migrating storage");
+
+        // The cached page is re-rendered once the flag changes
+        let page = body_string(get(app, "example.com", "/drt2z").await).await;
+        assert!(page.contains("Down for maintenance"));
+        assert!(page.contains("migrating storage"));
+    }
+
+    #[tokio::test]
+    async fn test_read_only_shows_in_health_and_info_page_until_cleared() {
+        let maintenance = Arc::new(Maintenance::disabled().with_read_only(true));
+        let config = RelayConfig { path_routing: true, ..test_config() };
+        let app = test_routes_with(config, DiskWatermark::disabled(), maintenance.clone());
+        let health: serde_json::Value =
+            serde_json::from_str(&body_string(get(app.clone(), "example.com", "/health").await).await).unwrap();
+        assert_eq!(health["status"], "degraded");
+        assert_eq!(health["read_only"], true);
+        let page = body_string(get(app.clone(), "example.com", "/drt2z").await).await;
+        assert!(page.contains("<strong>Read-only:</strong>"));
+
+        // Maintenance takes over the banner while both are on
+        maintenance.set(true, Some("migrating storage".to_string())).unwrap();
+        let page = body_string(get(app.clone(), "example.com", "/drt2z").await).await;
+        assert!(page.contains("Down for maintenance"));
+        assert!(!page.contains("<strong>Read-only:</strong>"));
+        maintenance.set(false, None).unwrap();
+
+        maintenance.clear_read_only();
+        let health: serde_json::Value =
+            serde_json::from_str(&body_string(get(app.clone(), "example.com", "/health").await).await).unwrap();
+        assert_eq!(health["status"], "ok");
+        assert_eq!(health["read_only"], false);
+        let page = body_string(get(app, "example.com", "/drt2z").await).await;
+        assert!(!page.contains("<strong>Read-only:</strong>"));
+    }
+
+    #[tokio::test]
+    async fn test_version_is_plain_text() {
+        let response = get(test_routes(test_config()), "drt2z.example.com", "/version").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, build_info::version_string());
+    }
+
+    #[tokio::test]
+    async fn test_metrics_include_build_info() {
+        prometheus::install();
+        build_info::record_build_info_metric();
+        let config = RelayConfig {
+            metrics_enabled: true,
+            ..test_config()
+        };
+        let body = body_string(get(test_routes(config), "example.com", "/metrics").await).await;
+        let line = body.lines().find(|line| line.starts_with("relay_build_info{")).unwrap_or_else(|| panic!("{}", body));
+        assert!(line.contains(&format!(r#"version="{}""#, build_info::VERSION)), "{}", line);
+        assert!(line.contains(&format!(r#"commit="{}""#, build_info::GIT_COMMIT)), "{}", line);
+        assert!(line.ends_with(" 1"), "{}", line);
+    }
+
+    #[tokio::test]
+    async fn test_syndication_feeds_can_be_disabled() {
+        let response = get(test_routes(test_config()), "drt2z.example.com", "/feed.atom").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], syndication::ATOM_CONTENT_TYPE);
+
+        let config = RelayConfig {
+            syndication_feeds: false,
+            ..test_config()
+        };
+        let response = get(test_routes(config), "drt2z.example.com", "/feed.atom").await;
+        assert_ne!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_nip11_includes_version() {
+        let app = test_routes(RelayConfig { path_routing: true, ..test_config() });
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/drt2z")
+                    .header("host", "example.com")
+                    .header("accept", nip11::NIP11_CONTENT_TYPE)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let info: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(info["software"], build_info::SOFTWARE);
+        assert_eq!(info["version"], build_info::version_string());
+    }
+
+    #[tokio::test]
+    async fn test_missing_host_rejected_before_routing() {
+        let response = get_without_host(test_routes(test_config()), MissingHostPolicy::Reject, "/drt2z").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_string(response).await, "Host header required");
+    }
+
+    #[tokio::test]
+    async fn test_missing_host_served_as_root() {
+        // The geohash path redirect is built from the Host, so it shows
+        // which host the request was resolved against
+        let response = get_without_host(test_routes(test_config()), MissingHostPolicy::Root, "/drt2z").await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()[header::LOCATION], "https://drt2z.localhost/");
+
+        // An HTTP/2 style authority is used as the Host rather than root
+        let response = get_without_host(
+            test_routes(test_config()),
+            MissingHostPolicy::Reject,
+            "https://example.com/drt2z",
+        )
+        .await;
+        assert_eq!(response.headers()[header::LOCATION], "https://drt2z.example.com/");
+    }
+
+    #[tokio::test]
+    async fn test_info_page_rate_limited_per_client() {
+        let config = RelayConfig { path_routing: true, info_page_requests_per_minute: 3, ..test_config() };
+        let app = test_routes(config);
+        let request = |uri: &str, peer: &str, forwarded_for: Option<&str>| {
+            let mut request = Request::builder().uri(uri).header("host", "example.com");
+            if let Some(client) = forwarded_for {
+                request = request.header("x-forwarded-for", client);
+            }
+            request
+                .extension(ConnectInfo(peer.parse::<SocketAddr>().unwrap()))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for _ in 0..3 {
+            let response = app.clone().oneshot(request("/drt2z", "203.0.113.1:5000", None)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = app.clone().oneshot(request("/drt2z", "203.0.113.1:5001", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers()[header::RETRY_AFTER].to_str().unwrap().parse().unwrap();
+        assert!((1..=20).contains(&retry_after));
+
+        // Health checks, NIP-11 and other clients are unaffected
+        let response = app.clone().oneshot(request("/health", "203.0.113.1:5002", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut nip11 = request("/drt2z", "203.0.113.1:5003", None);
+        nip11.headers_mut().insert(header::ACCEPT, HeaderValue::from_static(nip11::NIP11_CONTENT_TYPE));
+        assert_eq!(app.clone().oneshot(nip11).await.unwrap().status(), StatusCode::OK);
+        let response = app.clone().oneshot(request("/drt2z", "203.0.113.2:5000", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Behind a local proxy, clients are told apart by X-Forwarded-For,
+        // and a forged header from anyone else is ignored
+        let response = app
+            .clone()
+            .oneshot(request("/drt2z", "127.0.0.1:6000", Some("198.51.100.7")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .clone()
+            .oneshot(request("/drt2z", "203.0.113.1:5004", Some("198.51.100.8")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
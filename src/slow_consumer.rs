@@ -0,0 +1,117 @@
+//! Slow-consumer detection
+//!
+//! relay_builder queues outgoing frames per connection and a writer task
+//! drains them into the socket. A subscriber that stops reading (congested
+//! mobile link, stalled client) lets that queue grow without bound.
+//! `SlowConsumerMiddleware` inspects the queue depth on every outbound
+//! message and, once it exceeds the configured budget, sends a final NOTICE
+//! and closes the connection.
+
+use nostr_sdk::prelude::*;
+use relay_builder::{NostrMiddleware, OutboundContext};
+use tracing::warn;
+use crate::processor::ConnectionState;
+use crate::reject::RejectReason;
+
+/// Outgoing queue limits for one connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutboundBudget {
+    pub max_messages: usize,
+    pub max_bytes: usize,
+}
+
+impl OutboundBudget {
+    /// Whether `queued` messages of roughly `avg_bytes` each exceed the budget
+    ///
+    /// Zero disables the corresponding limit.
+    pub fn exceeded(&self, queued: usize, avg_bytes: usize) -> bool {
+        (self.max_messages > 0 && queued > self.max_messages)
+            || (self.max_bytes > 0 && queued.saturating_mul(avg_bytes) > self.max_bytes)
+    }
+}
+
+/// Running average of outbound message sizes, kept in `ConnectionState`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OutboundSizes {
+    count: u64,
+    total_bytes: u64,
+}
+
+impl OutboundSizes {
+    pub fn record(&mut self, bytes: usize) {
+        self.count += 1;
+        self.total_bytes += bytes as u64;
+    }
+
+    pub fn average(&self) -> usize {
+        self.total_bytes.checked_div(self.count).unwrap_or(0) as usize
+    }
+}
+
+/// Closes connections whose outgoing queue outgrows the budget
+#[derive(Debug, Clone)]
+pub struct SlowConsumerMiddleware {
+    budget: OutboundBudget,
+}
+
+impl SlowConsumerMiddleware {
+    pub fn new(budget: OutboundBudget) -> Self {
+        Self { budget }
+    }
+}
+
+impl NostrMiddleware<ConnectionState> for SlowConsumerMiddleware {
+    async fn process_outbound(&self, ctx: OutboundContext<'_, ConnectionState>) -> Result<(), anyhow::Error> {
+        let Some(message) = &ctx.message else {
+            return Ok(());
+        };
+
+        let avg_bytes = {
+            let mut state = ctx.state.write();
+            state.custom.outbound_sizes.record(message.as_json().len());
+            state.custom.outbound_sizes.average()
+        };
+        let queued = ctx.sender.len();
+        if !self.budget.exceeded(queued, avg_bytes) {
+            return Ok(());
+        }
+
+        warn!(
+            "Disconnecting slow consumer {} ({} messages queued, ~{} bytes each)",
+            ctx.connection_id, queued, avg_bytes
+        );
+        metrics::counter!("relay_slow_consumer_disconnects_total").increment(1);
+
+        // Drop the frame that pushed us over and say why before closing
+        *ctx.message = None;
+        ctx.sender.send_bypass(RelayMessage::notice(RejectReason::SlowConsumer.to_string()));
+        ctx.state.read().connection_token.cancel();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_limits() {
+        let budget = OutboundBudget { max_messages: 100, max_bytes: 10_000 };
+        assert!(!budget.exceeded(100, 50));
+        assert!(budget.exceeded(101, 50));
+        // Few but large messages trip the byte limit
+        assert!(budget.exceeded(20, 1_000));
+
+        let unlimited = OutboundBudget { max_messages: 0, max_bytes: 0 };
+        assert!(!unlimited.exceeded(usize::MAX, usize::MAX));
+    }
+
+    #[test]
+    fn test_average_size() {
+        let mut sizes = OutboundSizes::default();
+        assert_eq!(sizes.average(), 0);
+        sizes.record(100);
+        sizes.record(300);
+        assert_eq!(sizes.average(), 200);
+    }
+}
@@ -0,0 +1,268 @@
+//! Server-Sent Events feed of a scope
+//!
+//! `GET /feed` on a geohash subdomain streams the cell's newly stored events
+//! as `event: nostr` messages whose data is the event JSON, for dashboards
+//! and signage that can't speak the websocket protocol. `?kinds=1,20000`
+//! narrows the feed. On connect the newest `feed_replay_events` stored
+//! events are replayed, oldest first, before live events follow from
+//! `LiveEvents`.
+//!
+//! Feeds are unauthenticated, so events pass through the scope policy's
+//! `visibility` as they would for an unauthenticated websocket.
+
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event as SseEvent, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::get,
+    Router,
+};
+use futures::stream::{self, Stream, StreamExt};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+use crate::config::RelayConfig;
+use crate::geohash_utils::is_served_geohash_subdomain;
+use crate::host_parsing::host_info;
+use crate::live::{LiveEvents, StoredEvent};
+use crate::scope_policy::{DefaultScopePolicy, ScopePolicy};
+use crate::store::ScopeStore;
+
+/// Parses a `kinds` query value such as `1,20000`
+pub fn parse_kinds(value: &str) -> Option<Vec<Kind>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|kind| !kind.is_empty())
+        .map(|kind| kind.parse::<u16>().ok().map(Kind::from))
+        .collect()
+}
+
+/// Shared state for `/feed`
+pub struct SseFeed {
+    config: Arc<RelayConfig>,
+    store: Arc<dyn ScopeStore>,
+    live: Arc<LiveEvents>,
+    policy: Arc<dyn ScopePolicy>,
+    /// Open feeds per client IP
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl SseFeed {
+    pub fn new(
+        config: Arc<RelayConfig>,
+        store: Arc<dyn ScopeStore>,
+        live: Arc<LiveEvents>,
+    ) -> Self {
+        Self {
+            policy: Arc::new(DefaultScopePolicy),
+            config,
+            store,
+            live,
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Filters feeds with `policy` instead of the default one
+    pub fn with_policy(mut self, policy: Arc<dyn ScopePolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Reserves a feed slot for `ip`, released when the guard drops
+    fn admit(self: &Arc<Self>, ip: IpAddr) -> Option<FeedSlot> {
+        let mut open = self.open.lock();
+        let count = open.entry(ip).or_insert(0);
+        let max = self.config.feed_max_connections_per_ip;
+        if max > 0 && *count >= max {
+            return None;
+        }
+        *count += 1;
+        metrics::gauge!("relay_sse_feeds").increment(1.0);
+        Some(FeedSlot { feed: self.clone(), ip })
+    }
+
+    fn visible(&self, event: &Event, scope: &Scope) -> bool {
+        self.policy.visibility(event, scope, None)
+    }
+
+    /// The newest stored events in `scope`, oldest first
+    async fn replay(&self, scope: &Scope, kinds: &[Kind]) -> Vec<Event> {
+        if self.config.feed_replay_events == 0 {
+            return Vec::new();
+        }
+        let mut filter = Filter::new().limit(self.config.feed_replay_events);
+        if !kinds.is_empty() {
+            filter = filter.kinds(kinds.iter().copied());
+        }
+        match self.store.query(scope, filter).await {
+            Ok(mut events) => {
+                events.reverse();
+                events
+            }
+            Err(e) => {
+                warn!("Failed to load feed replay: {}", e);
+                Vec::new()
+            }
+        }
+    }
+}
+
+/// One open feed, counted against its IP
+struct FeedSlot {
+    feed: Arc<SseFeed>,
+    ip: IpAddr,
+}
+
+impl Drop for FeedSlot {
+    fn drop(&mut self) {
+        let mut open = self.feed.open.lock();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+        metrics::gauge!("relay_sse_feeds").decrement(1.0);
+    }
+}
+
+/// Everything a live feed needs between events
+struct LiveFeed {
+    slot: FeedSlot,
+    receiver: broadcast::Receiver<Arc<StoredEvent>>,
+    scope: Scope,
+    kinds: Vec<Kind>,
+    /// Replayed events, which may also arrive live
+    replayed: HashSet<EventId>,
+}
+
+impl LiveFeed {
+    async fn next_event(&mut self) -> Option<Event> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(stored) => {
+                    let event = &stored.event;
+                    if stored.scope != self.scope
+                        || (!self.kinds.is_empty() && !self.kinds.contains(&event.kind))
+                        || self.replayed.contains(&event.id)
+                        || !self.slot.feed.visible(event, &self.scope)
+                    {
+                        continue;
+                    }
+                    return Some(event.clone());
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    metrics::counter!("relay_sse_lagged_events_total").increment(missed);
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+fn sse_event(event: &Event) -> Result<SseEvent, Infallible> {
+    Ok(SseEvent::default().event("nostr").id(event.id.to_hex()).data(event.as_json()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FeedQuery {
+    kinds: Option<String>,
+}
+
+async fn feed_handler(
+    State(feed): State<Arc<SseFeed>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<FeedQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let subdomain = host_info(&headers, feed.config.base_domain_parts())
+        .subdomain
+        .filter(|subdomain| is_served_geohash_subdomain(subdomain, &feed.config.allowed_precisions));
+    let Some(scope) = subdomain.and_then(|subdomain| Scope::named(&subdomain.to_lowercase()).ok()) else {
+        return (StatusCode::NOT_FOUND, "feeds are served on geohash subdomains").into_response();
+    };
+    let Some(kinds) = parse_kinds(query.kinds.as_deref().unwrap_or_default()) else {
+        return (StatusCode::BAD_REQUEST, "kinds must be comma-separated numbers").into_response();
+    };
+    let Some(slot) = feed.admit(addr.ip()) else {
+        metrics::counter!("relay_sse_feeds_refused_total").increment(1);
+        return (StatusCode::TOO_MANY_REQUESTS, "too many open feeds from this address").into_response();
+    };
+
+    // Subscribe before replaying so nothing stored in between is missed
+    let receiver = feed.live.subscribe();
+    let replay: Vec<Event> = feed
+        .replay(&scope, &kinds)
+        .await
+        .into_iter()
+        .filter(|event| feed.visible(event, &scope))
+        .collect();
+    let live = LiveFeed {
+        slot,
+        receiver,
+        scope,
+        kinds,
+        replayed: replay.iter().map(|event| event.id).collect(),
+    };
+
+    Sse::new(feed_stream(replay, live)).keep_alive(KeepAlive::default()).into_response()
+}
+
+fn feed_stream(replay: Vec<Event>, live: LiveFeed) -> impl Stream<Item = Result<SseEvent, Infallible>> {
+    let replayed = stream::iter(replay.into_iter().map(|event| sse_event(&event)));
+    let live = stream::unfold(live, |mut live| async move {
+        let event = live.next_event().await?;
+        Some((sse_event(&event), live))
+    });
+    replayed.chain(live)
+}
+
+pub fn router(feed: Arc<SseFeed>) -> Router {
+    Router::new().route("/feed", get(feed_handler)).with_state(feed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_kinds() {
+        assert_eq!(parse_kinds("1,20000"), Some(vec![Kind::TextNote, Kind::from(20000)]));
+        assert_eq!(parse_kinds(" 1 , "), Some(vec![Kind::TextNote]));
+        assert_eq!(parse_kinds(""), Some(Vec::new()));
+        assert_eq!(parse_kinds("1,note"), None);
+        assert_eq!(parse_kinds("70000"), None);
+    }
+
+    #[test]
+    fn test_per_ip_slots_are_released() {
+        let config = RelayConfig {
+            feed_max_connections_per_ip: 1,
+            ..Default::default()
+        };
+        let feed = Arc::new(SseFeed::new(
+            Arc::new(config),
+            Arc::new(crate::store::MemoryStore::new()),
+            Arc::new(LiveEvents::new()),
+        ));
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let other: IpAddr = "203.0.113.8".parse().unwrap();
+
+        let slot = feed.admit(ip).unwrap();
+        assert!(feed.admit(ip).is_none());
+        assert!(feed.admit(other).is_some());
+        drop(slot);
+        assert!(feed.admit(ip).is_some());
+    }
+}
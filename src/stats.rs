@@ -0,0 +1,273 @@
+//! Per-scope statistics
+//!
+//! Aggregates are computed by a periodic background task and cached, so the
+//! stats endpoint never triggers storage scans on request.
+
+use anyhow::Result;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
+use crate::connections::ConnectionRegistry;
+use crate::store::{scope_label, ScopeStore};
+use crate::wot::WotStats;
+
+/// Upper bound on events sampled per scope for pubkey/kind breakdowns
+const SAMPLE_LIMIT: usize = 10_000;
+
+/// Number of kinds reported in `top_kinds`
+const TOP_KINDS: usize = 10;
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KindCount {
+    pub kind: u16,
+    pub count: usize,
+}
+
+/// Aggregates computed for one scope
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ScopeAggregates {
+    pub stored_events: usize,
+    pub events_last_hour: usize,
+    pub events_last_24h: usize,
+    pub distinct_pubkeys_last_hour: usize,
+    pub distinct_pubkeys_24h: usize,
+    pub top_kinds: Vec<KindCount>,
+    /// `created_at` of the newest stored event
+    pub last_event_at: Option<u64>,
+}
+
+/// Stats document returned by `/api/stats`
+#[derive(Debug, Clone, Serialize)]
+pub struct ScopeStats {
+    pub scope: String,
+    pub stored_events: usize,
+    pub events_last_hour: usize,
+    pub events_last_24h: usize,
+    pub distinct_pubkeys_24h: usize,
+    pub active_connections: usize,
+    pub top_kinds: Vec<KindCount>,
+    /// Leading zero bits event ids currently need here (0 when PoW is off)
+    pub pow_difficulty: u8,
+    /// Web-of-trust set, when write gating is on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wot: Option<WotStats>,
+    /// Unix timestamp of the aggregate computation, null before the first run
+    pub computed_at: Option<u64>,
+}
+
+/// Cache of the latest aggregates for every scope
+#[derive(Debug, Default)]
+pub struct StatsCache {
+    scopes: RwLock<HashMap<String, ScopeAggregates>>,
+    computed_at: RwLock<Option<u64>>,
+}
+
+impl StatsCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces all cached aggregates with a fresh computation
+    pub fn replace(&self, scopes: HashMap<String, ScopeAggregates>, computed_at: u64) {
+        *self.scopes.write() = scopes;
+        *self.computed_at.write() = Some(computed_at);
+    }
+
+    pub fn computed_at(&self) -> Option<u64> {
+        *self.computed_at.read()
+    }
+
+    pub fn aggregates(&self, scope: &Scope) -> Option<ScopeAggregates> {
+        self.scopes.read().get(&scope_label(scope)).cloned()
+    }
+
+    /// All cached aggregates keyed by scope label
+    pub fn all(&self) -> HashMap<String, ScopeAggregates> {
+        self.scopes.read().clone()
+    }
+
+    /// Builds the stats document for a scope, combining cached aggregates
+    /// with the live connection count
+    pub fn stats_for(&self, scope: &Scope, connections: &ConnectionRegistry) -> ScopeStats {
+        let aggregates = self.aggregates(scope).unwrap_or(ScopeAggregates {
+            stored_events: 0,
+            events_last_hour: 0,
+            events_last_24h: 0,
+            distinct_pubkeys_last_hour: 0,
+            distinct_pubkeys_24h: 0,
+            top_kinds: Vec::new(),
+            last_event_at: None,
+        });
+        ScopeStats {
+            scope: scope_label(scope),
+            stored_events: aggregates.stored_events,
+            events_last_hour: aggregates.events_last_hour,
+            events_last_24h: aggregates.events_last_24h,
+            distinct_pubkeys_24h: aggregates.distinct_pubkeys_24h,
+            active_connections: connections.active(scope),
+            top_kinds: aggregates.top_kinds,
+            pow_difficulty: 0,
+            wot: None,
+            computed_at: self.computed_at(),
+        }
+    }
+}
+
+/// Computes aggregates for exactly one scope (named scopes are never
+/// included in root's numbers)
+pub async fn compute_scope_aggregates(
+    store: &dyn ScopeStore,
+    scope: &Scope,
+    now: Timestamp,
+) -> Result<ScopeAggregates> {
+    let hour_ago = Timestamp::from(now.as_u64().saturating_sub(HOUR));
+    let day_ago = Timestamp::from(now.as_u64().saturating_sub(DAY));
+
+    let stored_events = store.count(scope, Filter::new()).await?;
+    let last_event_at = store
+        .query(scope, Filter::new().limit(1))
+        .await?
+        .first()
+        .map(|e| e.created_at.as_u64());
+    let events_last_hour = store.count(scope, Filter::new().since(hour_ago)).await?;
+    let events_last_24h = store.count(scope, Filter::new().since(day_ago)).await?;
+
+    let recent = store
+        .query(scope, Filter::new().since(day_ago).limit(SAMPLE_LIMIT))
+        .await?;
+
+    let distinct_pubkeys_last_hour = recent
+        .iter()
+        .filter(|e| e.created_at >= hour_ago)
+        .map(|e| e.pubkey)
+        .collect::<HashSet<_>>()
+        .len();
+    let distinct_pubkeys_24h = recent.iter().map(|e| e.pubkey).collect::<HashSet<_>>().len();
+
+    let mut kinds: HashMap<u16, usize> = HashMap::new();
+    for event in &recent {
+        *kinds.entry(event.kind.as_u16()).or_insert(0) += 1;
+    }
+    let mut top_kinds: Vec<KindCount> = kinds
+        .into_iter()
+        .map(|(kind, count)| KindCount { kind, count })
+        .collect();
+    top_kinds.sort_by(|a, b| b.count.cmp(&a.count).then(a.kind.cmp(&b.kind)));
+    top_kinds.truncate(TOP_KINDS);
+
+    Ok(ScopeAggregates {
+        stored_events,
+        events_last_hour,
+        events_last_24h,
+        distinct_pubkeys_last_hour,
+        distinct_pubkeys_24h,
+        top_kinds,
+        last_event_at,
+    })
+}
+
+/// Recomputes aggregates for every scope and stores them in the cache
+pub async fn refresh(store: &dyn ScopeStore, cache: &StatsCache) -> Result<()> {
+    let now = Timestamp::now();
+    let mut computed = HashMap::new();
+
+    for scope in store.scopes().await? {
+        match compute_scope_aggregates(store, &scope, now).await {
+            Ok(aggregates) => {
+                computed.insert(scope_label(&scope), aggregates);
+            }
+            Err(e) => warn!("Failed to compute stats for {}: {}", scope_label(&scope), e),
+        }
+    }
+
+    debug!("Computed stats for {} scopes", computed.len());
+    cache.replace(computed, now.as_u64());
+    Ok(())
+}
+
+/// Spawns the periodic aggregation task
+pub fn spawn_stats_task(
+    store: Arc<dyn ScopeStore>,
+    cache: Arc<StatsCache>,
+    interval: Duration,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = refresh(store.as_ref(), &cache).await {
+                warn!("Stats refresh failed: {}", e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    async fn event_at(keys: &Keys, kind: u16, created_at: u64) -> Event {
+        EventBuilder::new(Kind::from(kind), "stats test")
+            .custom_created_at(Timestamp::from(created_at))
+            .sign(keys)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_aggregates_respect_windows_and_scopes() {
+        let store = MemoryStore::new();
+        let now = Timestamp::now().as_u64();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let alice = Keys::generate();
+        let bob = Keys::generate();
+
+        store.insert(&drt2z, event_at(&alice, 1, now - 60).await);
+        store.insert(&drt2z, event_at(&bob, 20000, now - 2 * HOUR).await);
+        store.insert(&drt2z, event_at(&bob, 1, now - 2 * DAY).await);
+        store.insert(&Scope::Default, event_at(&alice, 0, now).await);
+
+        let stats = compute_scope_aggregates(&store, &drt2z, Timestamp::from(now)).await.unwrap();
+        assert_eq!(stats.stored_events, 3);
+        assert_eq!(stats.events_last_hour, 1);
+        assert_eq!(stats.events_last_24h, 2);
+        assert_eq!(stats.distinct_pubkeys_last_hour, 1);
+        assert_eq!(stats.distinct_pubkeys_24h, 2);
+        assert_eq!(stats.top_kinds.len(), 2);
+        assert_eq!(stats.last_event_at, Some(now - 60));
+
+        // Root never includes named scopes
+        let root = compute_scope_aggregates(&store, &Scope::Default, Timestamp::from(now)).await.unwrap();
+        assert_eq!(root.stored_events, 1);
+        assert_eq!(root.top_kinds, vec![KindCount { kind: 0, count: 1 }]);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_populates_cache_with_freshness() {
+        let store = MemoryStore::new();
+        let drt2z = Scope::named("drt2z").unwrap();
+        store.insert(&drt2z, event_at(&Keys::generate(), 1, Timestamp::now().as_u64()).await);
+
+        let cache = StatsCache::new();
+        let registry = ConnectionRegistry::new();
+        assert_eq!(cache.stats_for(&drt2z, &registry).computed_at, None);
+
+        refresh(&store, &cache).await.unwrap();
+        registry.connected(&drt2z);
+
+        let stats = cache.stats_for(&drt2z, &registry);
+        assert_eq!(stats.scope, "drt2z");
+        assert_eq!(stats.stored_events, 1);
+        assert_eq!(stats.active_connections, 1);
+        assert!(stats.computed_at.is_some());
+    }
+}
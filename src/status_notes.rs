@@ -0,0 +1,245 @@
+//! Scheduled status notes in active cells
+//!
+//! With `status_notes_interval_hours` set, the relay posts a heartbeat into
+//! every cell that saw at least `status_notes_min_events` events in the last
+//! 24 hours, e.g. "42 notes from 17 people here in the last 24h". Each note
+//! is an event of `status_notes_kind` signed with the relay key, carrying a
+//! `g` tag for the cell, with content rendered from `status_notes_template`
+//! and the latest stats aggregates. Notes go through `publish_internal`, so
+//! they skip client write policies but are routed like any other event.
+//!
+//! At most `status_notes_max_cells` cells, the busiest first, get a note per
+//! cycle. Replicas post nothing; they get the leader's notes.
+
+use anyhow::Result;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::StoreCommand;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+use crate::config::RelayConfig;
+use crate::geohash_utils::is_valid_geohash;
+use crate::processor::GeohashedEventProcessor;
+use crate::scope_policy::{DefaultScopePolicy, ScopePolicy};
+use crate::stats::{ScopeAggregates, StatsCache};
+use crate::store::ScopeStore;
+
+/// Default `status_notes_template`
+pub const DEFAULT_STATUS_TEMPLATE: &str = "{events} notes from {people} people here in the last 24h";
+
+/// `template` with `{cell}`, `{events}`, `{people}` and `{stored}` filled in
+/// from `cell`'s aggregates
+pub fn render_status(template: &str, cell: &str, aggregates: &ScopeAggregates) -> String {
+    template
+        .replace("{cell}", cell)
+        .replace("{events}", &aggregates.events_last_24h.to_string())
+        .replace("{people}", &aggregates.distinct_pubkeys_24h.to_string())
+        .replace("{stored}", &aggregates.stored_events.to_string())
+}
+
+/// Cells with at least `min_events` events in the last 24 hours, busiest
+/// first, at most `max_cells` of them
+pub fn active_cells(stats: &StatsCache, min_events: usize, max_cells: usize) -> Vec<(String, ScopeAggregates)> {
+    let mut cells: Vec<(String, ScopeAggregates)> = stats
+        .all()
+        .into_iter()
+        .filter(|(label, aggregates)| is_valid_geohash(label) && aggregates.events_last_24h >= min_events)
+        .collect();
+    cells.sort_by(|(a_label, a), (b_label, b)| b.events_last_24h.cmp(&a.events_last_24h).then_with(|| a_label.cmp(b_label)));
+    cells.truncate(max_cells);
+    cells
+}
+
+/// Posts status notes into active cells
+pub struct StatusNotes<P = DefaultScopePolicy> {
+    processor: GeohashedEventProcessor<P>,
+    keys: Keys,
+    store: Arc<dyn ScopeStore>,
+    stats: Arc<StatsCache>,
+    kind: Kind,
+    template: String,
+    min_events: usize,
+    max_cells: usize,
+    interval: Duration,
+}
+
+impl<P: ScopePolicy> StatusNotes<P> {
+    pub fn new(
+        processor: GeohashedEventProcessor<P>,
+        keys: Keys,
+        store: Arc<dyn ScopeStore>,
+        stats: Arc<StatsCache>,
+        config: &RelayConfig,
+    ) -> Self {
+        Self {
+            processor,
+            keys,
+            store,
+            stats,
+            kind: Kind::from(config.status_notes_kind),
+            template: config.status_notes_template.clone(),
+            min_events: config.status_notes_min_events,
+            max_cells: config.status_notes_max_cells,
+            interval: Duration::from_secs(config.status_notes_interval_hours * 60 * 60),
+        }
+    }
+
+    /// The configured publisher; `None` unless `status_notes_interval_hours`
+    /// is set, or on a replica
+    pub fn for_config(
+        config: &RelayConfig,
+        processor: GeohashedEventProcessor<P>,
+        keys: Keys,
+        store: Arc<dyn ScopeStore>,
+        stats: Arc<StatsCache>,
+    ) -> Option<Self> {
+        (config.status_notes_interval_hours > 0 && config.replicate_from.is_none())
+            .then(|| Self::new(processor, keys, store, stats, config))
+    }
+
+    /// Signs the status note for `cell`
+    pub async fn note(&self, cell: &str, aggregates: &ScopeAggregates) -> Result<Event> {
+        let event = EventBuilder::new(self.kind, render_status(&self.template, cell, aggregates))
+            .tag(Tag::custom(TagKind::Custom("g".into()), [cell.to_string()]))
+            .sign(&self.keys)
+            .await?;
+        Ok(event)
+    }
+
+    /// Posts one round of notes, returning those stored
+    pub async fn publish_cycle(&self) -> Result<Vec<Event>> {
+        let mut published = Vec::new();
+        for (cell, aggregates) in active_cells(&self.stats, self.min_events, self.max_cells) {
+            let note = self.note(&cell, &aggregates).await?;
+            let commands = match self
+                .processor
+                .publish_internal(note.clone(), self.keys.public_key(), Scope::named(&cell)?)
+                .await
+            {
+                Ok(commands) => commands,
+                Err(e) => {
+                    warn!("Status note for {} was refused: {}", cell, e);
+                    continue;
+                }
+            };
+            for command in commands {
+                if let StoreCommand::SaveSignedEvent(event, target, _) = command {
+                    self.store.save(&target, *event).await?;
+                }
+            }
+            published.push(note);
+        }
+        metrics::counter!("relay_status_notes_published_total").increment(published.len() as u64);
+        Ok(published)
+    }
+}
+
+/// Posts a round of status notes every interval until the relay shuts
+/// down; the first round waits a full interval, so the stats have been
+/// computed by then
+pub fn spawn_status_notes<P: ScopePolicy>(notes: Arc<StatusNotes<P>>) {
+    tokio::spawn(async move {
+        let start = tokio::time::Instant::now() + notes.interval;
+        let mut ticker = tokio::time::interval_at(start, notes.interval);
+        loop {
+            ticker.tick().await;
+            match notes.publish_cycle().await {
+                Ok(published) => info!("Posted {} status notes", published.len()),
+                Err(e) => warn!("Failed to post status notes: {:#}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use std::collections::HashMap;
+
+    fn aggregates(events_last_24h: usize, distinct_pubkeys_24h: usize) -> ScopeAggregates {
+        ScopeAggregates {
+            stored_events: events_last_24h * 3,
+            events_last_hour: 0,
+            events_last_24h,
+            distinct_pubkeys_last_hour: 0,
+            distinct_pubkeys_24h,
+            top_kinds: Vec::new(),
+            last_event_at: None,
+        }
+    }
+
+    fn stats() -> Arc<StatsCache> {
+        let stats = Arc::new(StatsCache::new());
+        stats.replace(
+            HashMap::from([
+                ("drt2z".to_string(), aggregates(42, 17)),
+                ("9q8yy".to_string(), aggregates(12, 4)),
+                ("u4pru".to_string(), aggregates(30, 9)),
+                ("gcpvj".to_string(), aggregates(9, 3)),
+                ("root".to_string(), aggregates(500, 80)),
+            ]),
+            1_700_000_000,
+        );
+        stats
+    }
+
+    fn notes(store: Arc<MemoryStore>, keys: &Keys, max_cells: usize) -> StatusNotes {
+        let config = RelayConfig {
+            status_notes_interval_hours: 6,
+            status_notes_min_events: 10,
+            status_notes_max_cells: max_cells,
+            ..Default::default()
+        };
+        StatusNotes::new(GeohashedEventProcessor::new(), keys.clone(), store, stats(), &config)
+    }
+
+    #[test]
+    fn test_render_status() {
+        assert_eq!(
+            render_status(DEFAULT_STATUS_TEMPLATE, "drt2z", &aggregates(42, 17)),
+            "42 notes from 17 people here in the last 24h"
+        );
+        assert_eq!(render_status("{cell}: {stored} stored", "drt2z", &aggregates(42, 17)), "drt2z: 126 stored");
+    }
+
+    #[test]
+    fn test_active_cells_respect_threshold_and_cap() {
+        let labels = |cells: Vec<(String, ScopeAggregates)>| cells.into_iter().map(|(label, _)| label).collect::<Vec<_>>();
+        // Root is never a cell, and gcpvj is under the threshold
+        assert_eq!(labels(active_cells(&stats(), 10, 10)), ["drt2z", "u4pru", "9q8yy"]);
+        assert_eq!(labels(active_cells(&stats(), 10, 2)), ["drt2z", "u4pru"]);
+        assert_eq!(labels(active_cells(&stats(), 31, 10)), ["drt2z"]);
+    }
+
+    #[tokio::test]
+    async fn test_cycle_posts_valid_notes_into_their_cells() {
+        let store = Arc::new(MemoryStore::new());
+        let keys = Keys::generate();
+        let published = notes(store.clone(), &keys, 2).publish_cycle().await.unwrap();
+        assert_eq!(published.len(), 2);
+
+        let drt2z = Scope::named("drt2z").unwrap();
+        let stored = store.query(&drt2z, Filter::new()).await.unwrap();
+        assert_eq!(stored.len(), 1);
+        let note = &stored[0];
+        assert!(note.verify().is_ok());
+        assert_eq!(note.pubkey, keys.public_key());
+        assert_eq!(note.kind, Kind::TextNote);
+        assert_eq!(note.content, "42 notes from 17 people here in the last 24h");
+        let g: Vec<&str> = note
+            .tags
+            .iter()
+            .filter(|tag| tag.as_slice().first().map(String::as_str) == Some("g"))
+            .filter_map(|tag| tag.content())
+            .collect();
+        assert_eq!(g, ["drt2z"]);
+
+        // Over the cap and under the threshold get nothing
+        assert_eq!(store.count(&Scope::named("u4pru").unwrap(), Filter::new()).await.unwrap(), 1);
+        assert_eq!(store.count(&Scope::named("9q8yy").unwrap(), Filter::new()).await.unwrap(), 0);
+        assert_eq!(store.count(&Scope::named("gcpvj").unwrap(), Filter::new()).await.unwrap(), 0);
+        assert_eq!(store.count(&Scope::Default, Filter::new()).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,536 @@
+//! LMDB map-size monitoring and disk-full handling
+//!
+//! LMDB reserves a fixed-size map up front; once it's full every write fails
+//! with `MDB_MAP_FULL`. `StorageMonitor` periodically compares the data file
+//! against the configured map size, exports the ratio as a gauge and warns
+//! as it crosses 80/90/95%. Above `storage_read_only_percent`, or after a
+//! write actually fails with map-full, the relay goes read-only: EVENTs are
+//! rejected with `RejectReason::StorageFull` until an operator grows the
+//! map and restarts.
+//!
+//! Separately, `DiskWatermark` watches the filesystem holding the database.
+//! While it is fuller than `disk_watermark_percent` the relay is read-only
+//! with `RejectReason::StoragePressure`, and it
+//! recovers on its own once space is freed.
+//!
+//! Any other failed write is answered with `RejectReason::StorageFailure`
+//! and counted in `WriteFailures`; `storage_error_threshold` failures within
+//! `storage_error_window_secs` report the relay as degraded in `/health`.
+
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use relay_builder::{InboundContext, InboundProcessor, NostrMiddleware};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+use crate::config::RelayConfig;
+use crate::processor::ConnectionState;
+use crate::reject::RejectReason;
+use crate::store::scope_label;
+
+/// Points below the watermark usage must drop before writes resume, so a
+/// disk hovering at the threshold doesn't flap
+const WATERMARK_HYSTERESIS: u8 = 2;
+
+/// Usage levels that trigger a warning log when first crossed
+const WARNING_LEVELS: [u8; 3] = [80, 90, 95];
+
+/// Whether an error (or anything in its source chain) is LMDB's map-full
+pub fn is_map_full(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        let message = cause.to_string();
+        message.contains("MDB_MAP_FULL") || message.contains("MapFull") || message.contains("map full")
+    })
+}
+
+/// Whether an error came from the database or the disk under it, rather
+/// than from a rejection somewhere in the chain
+pub fn is_storage_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| cause.is::<DatabaseError>() || cause.is::<std::io::Error>())
+}
+
+/// Percentage of the map in use, capped at 100
+pub fn usage_percent(used_bytes: u64, map_size: u64) -> u8 {
+    if map_size == 0 {
+        return 0;
+    }
+    (used_bytes.saturating_mul(100) / map_size).min(100) as u8
+}
+
+/// Tracks map usage and whether the relay has gone read-only
+#[derive(Debug)]
+pub struct StorageMonitor {
+    data_file: PathBuf,
+    map_size: u64,
+    /// 0 disables the automatic read-only switch
+    read_only_percent: u8,
+    /// Highest warning level already logged
+    warned_level: AtomicU8,
+    read_only: AtomicBool,
+}
+
+impl StorageMonitor {
+    pub fn new(config: &RelayConfig) -> Self {
+        Self {
+            data_file: Path::new(&config.database_path).join("data.mdb"),
+            map_size: config.lmdb_map_size as u64,
+            read_only_percent: config.storage_read_only_percent,
+            warned_level: AtomicU8::new(0),
+            read_only: AtomicBool::new(false),
+        }
+    }
+
+    /// Monitor that never reports usage, for tests and tooling
+    pub fn disabled() -> Self {
+        Self {
+            data_file: PathBuf::new(),
+            map_size: 0,
+            read_only_percent: 0,
+            warned_level: AtomicU8::new(0),
+            read_only: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Stops accepting events; there is no way back short of a restart
+    pub fn enter_read_only(&self, reason: &str) {
+        if !self.read_only.swap(true, Ordering::Relaxed) {
+            error!("Storage is full, relay is now read-only: {}", reason);
+            metrics::gauge!("relay_storage_read_only").set(1.0);
+        }
+    }
+
+    /// Records a usage sample, logging warnings and switching to read-only
+    pub fn observe(&self, used_bytes: u64) -> u8 {
+        let percent = usage_percent(used_bytes, self.map_size);
+        metrics::gauge!("relay_storage_used_ratio").set(percent as f64 / 100.0);
+
+        if let Some(level) = WARNING_LEVELS.iter().rev().find(|level| percent >= **level) {
+            if self.warned_level.fetch_max(*level, Ordering::Relaxed) < *level {
+                warn!(
+                    "LMDB map is {}% full ({} of {} bytes); raise LMDB_MAP_SIZE",
+                    percent, used_bytes, self.map_size
+                );
+            }
+        }
+
+        if self.read_only_percent > 0 && percent >= self.read_only_percent {
+            self.enter_read_only(&format!("map usage {}% >= {}%", percent, self.read_only_percent));
+        }
+        percent
+    }
+
+    /// Samples the data file size
+    ///
+    /// LMDB grows data.mdb as pages are written, so the file size tracks
+    /// the used part of the map closely enough for alerting.
+    pub fn check(&self) -> Option<u8> {
+        if self.map_size == 0 {
+            return None;
+        }
+        match std::fs::metadata(&self.data_file) {
+            Ok(meta) => Some(self.observe(meta.len())),
+            Err(e) => {
+                warn!("Failed to stat {}: {}", self.data_file.display(), e);
+                None
+            }
+        }
+    }
+}
+
+/// Spawns the periodic usage check
+pub fn spawn_storage_monitor(monitor: Arc<StorageMonitor>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            monitor.check();
+        }
+    });
+}
+
+/// Reports how full the filesystem containing a path is
+pub trait DiskUsageProvider: Send + Sync + 'static {
+    /// Used space as a percentage of the filesystem's capacity
+    fn used_percent(&self, path: &Path) -> std::io::Result<u8>;
+}
+
+/// `DiskUsageProvider` backed by the real filesystem
+#[derive(Debug, Default)]
+pub struct FsDiskUsage;
+
+impl DiskUsageProvider for FsDiskUsage {
+    fn used_percent(&self, path: &Path) -> std::io::Result<u8> {
+        let total = fs2::total_space(path)?;
+        let available = fs2::available_space(path)?;
+        Ok(usage_percent(total.saturating_sub(available), total))
+    }
+}
+
+/// Flips the relay read-only while the database's filesystem is too full
+pub struct DiskWatermark {
+    path: PathBuf,
+    /// 0 disables the watermark
+    watermark_percent: u8,
+    provider: Box<dyn DiskUsageProvider>,
+    read_only: AtomicBool,
+}
+
+impl std::fmt::Debug for DiskWatermark {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DiskWatermark")
+            .field("path", &self.path)
+            .field("watermark_percent", &self.watermark_percent)
+            .field("read_only", &self.is_read_only())
+            .finish()
+    }
+}
+
+impl DiskWatermark {
+    pub fn new(config: &RelayConfig) -> Self {
+        Self::with_provider(config, FsDiskUsage)
+    }
+
+    pub fn with_provider(config: &RelayConfig, provider: impl DiskUsageProvider) -> Self {
+        Self {
+            path: PathBuf::from(&config.database_path),
+            watermark_percent: config.disk_watermark_percent,
+            provider: Box::new(provider),
+            read_only: AtomicBool::new(false),
+        }
+    }
+
+    /// Watermark that never trips, for tests and tooling
+    pub fn disabled() -> Self {
+        Self {
+            path: PathBuf::new(),
+            watermark_percent: 0,
+            provider: Box::new(FsDiskUsage),
+            read_only: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Records a usage sample, entering or leaving read-only mode
+    pub fn observe(&self, percent: u8) {
+        metrics::gauge!("relay_disk_used_ratio").set(percent as f64 / 100.0);
+        let was_read_only = self.is_read_only();
+        let read_only = if was_read_only {
+            percent + WATERMARK_HYSTERESIS > self.watermark_percent
+        } else {
+            percent >= self.watermark_percent
+        };
+        if read_only == was_read_only {
+            return;
+        }
+        self.read_only.store(read_only, Ordering::Relaxed);
+        metrics::gauge!("relay_disk_read_only").set(if read_only { 1.0 } else { 0.0 });
+        if read_only {
+            error!(
+                "Disk holding {} is {}% full (watermark {}%), rejecting events until space is freed",
+                self.path.display(),
+                percent,
+                self.watermark_percent
+            );
+        } else {
+            info!("Disk holding {} is back to {}% full, accepting events again", self.path.display(), percent);
+        }
+    }
+
+    /// Samples the filesystem
+    pub fn check(&self) -> Option<u8> {
+        if self.watermark_percent == 0 {
+            return None;
+        }
+        match self.provider.used_percent(&self.path) {
+            Ok(percent) => {
+                self.observe(percent);
+                Some(percent)
+            }
+            Err(e) => {
+                warn!("Failed to read disk usage for {}: {}", self.path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+/// Spawns the periodic disk usage check
+pub fn spawn_disk_watermark(watermark: Arc<DiskWatermark>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            watermark.check();
+        }
+    });
+}
+
+/// Recent write failures other than map-full, for the health check
+#[derive(Debug)]
+pub struct WriteFailures {
+    /// 0 never reports degraded
+    threshold: usize,
+    window: Duration,
+    /// At most `threshold` failure times, oldest first
+    recent: Mutex<VecDeque<Instant>>,
+}
+
+impl WriteFailures {
+    pub fn new(config: &RelayConfig) -> Self {
+        Self {
+            threshold: config.storage_error_threshold as usize,
+            window: Duration::from_secs(config.storage_error_window_secs),
+            recent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Counts failures without ever degrading, for tests and tooling
+    pub fn disabled() -> Self {
+        Self {
+            threshold: 0,
+            window: Duration::ZERO,
+            recent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Logs and counts a failed write of `event_id` into `scope`
+    pub fn record(&self, scope: &Scope, event_id: EventId, err: &anyhow::Error) {
+        error!("Failed to store event {} in {}: {:#}", event_id, scope_label(scope), err);
+        metrics::counter!("relay_storage_write_errors_total").increment(1);
+        self.record_at(Instant::now());
+    }
+
+    fn record_at(&self, now: Instant) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut recent = self.recent.lock();
+        recent.push_back(now);
+        while recent.len() > self.threshold {
+            recent.pop_front();
+        }
+    }
+
+    /// Whether `threshold` writes failed within the last window
+    pub fn is_degraded(&self) -> bool {
+        self.is_degraded_at(Instant::now())
+    }
+
+    fn is_degraded_at(&self, now: Instant) -> bool {
+        let recent = self.recent.lock();
+        self.threshold > 0
+            && recent.len() >= self.threshold
+            && recent.front().is_some_and(|oldest| now.duration_since(*oldest) <= self.window)
+    }
+}
+
+/// Turns failed writes into an OK false instead of a disconnect
+///
+/// Only errors relay_builder returns from `ctx.next()` get here, and only
+/// those `is_storage_error` recognizes are answered and counted; rejections
+/// from the processor and the rest of the inner chain go on to
+/// `ErrorHandlingMiddleware` as before.
+#[derive(Debug, Clone)]
+pub struct StorageFullMiddleware {
+    monitor: Arc<StorageMonitor>,
+    failures: Arc<WriteFailures>,
+}
+
+impl StorageFullMiddleware {
+    pub fn new(monitor: Arc<StorageMonitor>, failures: Arc<WriteFailures>) -> Self {
+        Self { monitor, failures }
+    }
+}
+
+impl NostrMiddleware<ConnectionState> for StorageFullMiddleware {
+    async fn process_inbound<Next>(&self, ctx: InboundContext<'_, ConnectionState, Next>) -> Result<(), anyhow::Error>
+    where
+        Next: InboundProcessor<ConnectionState>,
+    {
+        let event_id = match &ctx.message {
+            Some(ClientMessage::Event(event)) => Some(event.id),
+            _ => None,
+        };
+        let Some(event_id) = event_id else {
+            return ctx.next().await;
+        };
+
+        match ctx.next().await {
+            Err(e) if is_map_full(&e) => {
+                self.monitor.enter_read_only(&e.to_string());
+                ctx.send_message(RelayMessage::ok(event_id, false, RejectReason::StorageFull.to_string()))?;
+                Ok(())
+            }
+            Err(e) if is_storage_error(&e) => {
+                let scope = ctx.state.read().subdomain.clone();
+                self.failures.record(&scope, event_id, &e);
+                ctx.send_message(RelayMessage::ok(event_id, false, RejectReason::StorageFailure.to_string()))?;
+                Ok(())
+            }
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor(map_size: u64, read_only_percent: u8) -> StorageMonitor {
+        StorageMonitor {
+            map_size,
+            read_only_percent,
+            ..StorageMonitor::disabled()
+        }
+    }
+
+    #[test]
+    fn test_usage_percent() {
+        assert_eq!(usage_percent(0, 1000), 0);
+        assert_eq!(usage_percent(805, 1000), 80);
+        assert_eq!(usage_percent(2000, 1000), 100);
+        assert_eq!(usage_percent(10, 0), 0);
+    }
+
+    #[test]
+    fn test_read_only_threshold() {
+        let storage = monitor(1000, 95);
+        storage.observe(900);
+        assert!(!storage.is_read_only());
+        storage.observe(960);
+        assert!(storage.is_read_only());
+
+        // Disabled threshold never flips on its own
+        let storage = monitor(1000, 0);
+        storage.observe(1000);
+        assert!(!storage.is_read_only());
+    }
+
+    #[test]
+    fn test_warning_levels_logged_once() {
+        let storage = monitor(1000, 0);
+        storage.observe(850);
+        assert_eq!(storage.warned_level.load(Ordering::Relaxed), 80);
+        storage.observe(960);
+        assert_eq!(storage.warned_level.load(Ordering::Relaxed), 95);
+        // Dropping back down doesn't reset the level
+        storage.observe(100);
+        assert_eq!(storage.warned_level.load(Ordering::Relaxed), 95);
+    }
+
+    /// Reports whatever percentage the test sets
+    #[derive(Clone, Default)]
+    struct FakeDiskUsage(Arc<AtomicU8>);
+
+    impl DiskUsageProvider for FakeDiskUsage {
+        fn used_percent(&self, _path: &Path) -> std::io::Result<u8> {
+            Ok(self.0.load(Ordering::Relaxed))
+        }
+    }
+
+    #[test]
+    fn test_disk_watermark_trips_and_recovers() {
+        let config = RelayConfig {
+            disk_watermark_percent: 90,
+            ..Default::default()
+        };
+        let disk = FakeDiskUsage::default();
+        let watermark = DiskWatermark::with_provider(&config, disk.clone());
+
+        disk.0.store(50, Ordering::Relaxed);
+        assert_eq!(watermark.check(), Some(50));
+        assert!(!watermark.is_read_only());
+
+        disk.0.store(92, Ordering::Relaxed);
+        watermark.check();
+        assert!(watermark.is_read_only());
+
+        // Stays read-only until usage drops clear of the watermark
+        disk.0.store(89, Ordering::Relaxed);
+        watermark.check();
+        assert!(watermark.is_read_only());
+        disk.0.store(80, Ordering::Relaxed);
+        watermark.check();
+        assert!(!watermark.is_read_only());
+    }
+
+    #[test]
+    fn test_disabled_disk_watermark_never_samples() {
+        let config = RelayConfig {
+            disk_watermark_percent: 0,
+            ..Default::default()
+        };
+        let disk = FakeDiskUsage::default();
+        disk.0.store(100, Ordering::Relaxed);
+        let watermark = DiskWatermark::with_provider(&config, disk);
+        assert_eq!(watermark.check(), None);
+        assert!(!watermark.is_read_only());
+    }
+
+    #[test]
+    fn test_is_map_full() {
+        let err = anyhow::anyhow!("MDB_MAP_FULL: Environment mapsize limit reached")
+            .context("failed to save event");
+        assert!(is_map_full(&err));
+        assert!(!is_map_full(&anyhow::anyhow!("connection reset")));
+    }
+
+    fn failures(threshold: u32, window_secs: u64) -> WriteFailures {
+        WriteFailures::new(&RelayConfig {
+            storage_error_threshold: threshold,
+            storage_error_window_secs: window_secs,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_only_storage_errors_are_write_failures() {
+        let io = anyhow::Error::new(std::io::Error::other("Input/output error")).context("failed to save event");
+        assert!(is_storage_error(&io));
+        assert!(!is_map_full(&io));
+
+        let rejected = anyhow::Error::new(relay_builder::Error::restricted("blocked: not here"));
+        assert!(!is_storage_error(&rejected));
+        assert!(!is_storage_error(&anyhow::anyhow!("connection reset")));
+
+        let tracker = failures(1, 60);
+        tracker.record(&Scope::named("drt2z").unwrap(), EventId::all_zeros(), &io);
+        assert!(tracker.is_degraded());
+    }
+
+    #[test]
+    fn test_repeated_write_failures_degrade_within_window() {
+        let tracker = failures(3, 60);
+        let start = Instant::now();
+        tracker.record_at(start);
+        tracker.record_at(start + Duration::from_secs(10));
+        assert!(!tracker.is_degraded_at(start + Duration::from_secs(10)));
+        tracker.record_at(start + Duration::from_secs(20));
+        assert!(tracker.is_degraded_at(start + Duration::from_secs(20)));
+
+        // Recovers once the oldest of the last three ages out
+        assert!(tracker.is_degraded_at(start + Duration::from_secs(60)));
+        assert!(!tracker.is_degraded_at(start + Duration::from_secs(61)));
+
+        // Failures spread wider than the window never degrade
+        let tracker = failures(2, 60);
+        tracker.record_at(start);
+        tracker.record_at(start + Duration::from_secs(90));
+        assert!(!tracker.is_degraded_at(start + Duration::from_secs(90)));
+
+        let disabled = WriteFailures::disabled();
+        disabled.record_at(start);
+        assert!(!disabled.is_degraded_at(start));
+    }
+}
@@ -0,0 +1,333 @@
+//! Read access to the scoped event store for the HTTP layer
+//!
+//! relay_builder owns the client write path; the HTTP endpoints (stats,
+//! feeds, exports) mostly need to query and enumerate scopes, and the relay's
+//! own background tasks occasionally save events it signs itself.
+//! `ScopeStore` abstracts that so handlers can be tested against an in-memory
+//! store as well as a real LMDB database.
+
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::RwLock;
+use relay_builder::RelayDatabase;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use crate::config::{RelayConfig, StorageBackend};
+
+/// Directory under `database_path` for the memory backend's scratch database
+pub const MEMORY_DATABASE_DIR: &str = "memory";
+
+/// Label used for the root scope in APIs and logs
+pub const ROOT_SCOPE_LABEL: &str = "root";
+
+/// Human/API label for a scope ("root" for the default scope)
+pub fn scope_label(scope: &Scope) -> String {
+    match scope {
+        Scope::Named { name, .. } => name.clone(),
+        Scope::Default => ROOT_SCOPE_LABEL.to_string(),
+    }
+}
+
+/// Inverse of `scope_label`
+pub fn scope_from_label(label: &str) -> Option<Scope> {
+    if label == ROOT_SCOPE_LABEL {
+        Some(Scope::Default)
+    } else {
+        Scope::named(label).ok()
+    }
+}
+
+/// Query and enumeration access to scoped storage
+pub trait ScopeStore: Send + Sync + 'static {
+    /// Events matching `filter` in exactly `scope`
+    fn query(&self, scope: &Scope, filter: Filter) -> BoxFuture<'_, Result<Vec<Event>>>;
+
+    /// Number of events matching `filter` in exactly `scope`
+    fn count(&self, scope: &Scope, filter: Filter) -> BoxFuture<'_, Result<usize>>;
+
+    /// All scopes that have ever stored an event (including root)
+    fn scopes(&self) -> BoxFuture<'_, Result<Vec<Scope>>>;
+
+    /// Saves an event into `scope`, applying replaceable-event semantics
+    fn save(&self, scope: &Scope, event: Event) -> BoxFuture<'_, Result<()>>;
+
+    /// Removes one event from `scope`
+    fn delete(&self, scope: &Scope, id: EventId) -> BoxFuture<'_, Result<()>>;
+}
+
+/// `ScopeStore` backed by the relay's LMDB database
+#[derive(Clone)]
+pub struct LmdbStore {
+    database: Arc<RelayDatabase>,
+}
+
+/// Opens the relay database configured by `config`
+pub fn open_database(config: &RelayConfig) -> Result<Arc<RelayDatabase>> {
+    Ok(Arc::new(RelayDatabase::with_map_size(&config.database_path, config.lmdb_map_size)?))
+}
+
+/// Opens the database the relay serves from
+///
+/// relay_builder only takes its LMDB-backed `RelayDatabase`, so the memory
+/// backend is a scratch database that starts empty on every start and is
+/// kept small by `memory_backend::RingBuffers`.
+pub fn open_storage(config: &RelayConfig) -> Result<Arc<RelayDatabase>> {
+    match config.storage_backend {
+        StorageBackend::Lmdb => open_database(config),
+        StorageBackend::Memory => {
+            let path = Path::new(&config.database_path).join(MEMORY_DATABASE_DIR);
+            match std::fs::remove_dir_all(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("failed to clear {}", path.display())),
+            }
+            std::fs::create_dir_all(&path).with_context(|| format!("failed to create {}", path.display()))?;
+            Ok(Arc::new(RelayDatabase::with_map_size(&*path.to_string_lossy(), config.lmdb_map_size)?))
+        }
+    }
+}
+
+impl LmdbStore {
+    pub fn new(database: Arc<RelayDatabase>) -> Self {
+        Self { database }
+    }
+
+    pub fn database(&self) -> &Arc<RelayDatabase> {
+        &self.database
+    }
+}
+
+impl ScopeStore for LmdbStore {
+    fn query(&self, scope: &Scope, filter: Filter) -> BoxFuture<'_, Result<Vec<Event>>> {
+        let scope = scope.clone();
+        Box::pin(async move {
+            let events = self.database.query(vec![filter], &scope).await?;
+            Ok(events.into_iter().collect())
+        })
+    }
+
+    fn count(&self, scope: &Scope, filter: Filter) -> BoxFuture<'_, Result<usize>> {
+        let scope = scope.clone();
+        Box::pin(async move { Ok(self.database.count(vec![filter], &scope).await?) })
+    }
+
+    fn scopes(&self) -> BoxFuture<'_, Result<Vec<Scope>>> {
+        Box::pin(async move {
+            let mut scopes = self.database.list_scopes().await?;
+            if !scopes.contains(&Scope::Default) {
+                scopes.insert(0, Scope::Default);
+            }
+            Ok(scopes)
+        })
+    }
+
+    fn save(&self, scope: &Scope, event: Event) -> BoxFuture<'_, Result<()>> {
+        let scope = scope.clone();
+        Box::pin(async move {
+            self.database.save_event(&event, &scope).await?;
+            Ok(())
+        })
+    }
+
+    fn delete(&self, scope: &Scope, id: EventId) -> BoxFuture<'_, Result<()>> {
+        let scope = scope.clone();
+        Box::pin(async move {
+            self.database.delete(Filter::new().id(id), &scope).await?;
+            Ok(())
+        })
+    }
+}
+
+/// In-memory `ScopeStore`, used by tests and tooling
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    events: RwLock<HashMap<Scope, Vec<Event>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, scope: &Scope, event: Event) {
+        let mut events = self.events.write();
+        let scoped = events.entry(scope.clone()).or_default();
+        if scoped.iter().any(|e| e.id == event.id) {
+            return;
+        }
+        // Replacement and deletion only ever look at this scope, like LMDB
+        if event.kind.is_replaceable() || event.kind.is_addressable() {
+            let supersedes = |e: &Event| {
+                e.kind == event.kind
+                    && e.pubkey == event.pubkey
+                    && (event.kind.is_replaceable() || e.tags.identifier() == event.tags.identifier())
+            };
+            if scoped.iter().any(|e| supersedes(e) && e.created_at > event.created_at) {
+                return;
+            }
+            scoped.retain(|e| !supersedes(e));
+        }
+        if event.kind == Kind::EventDeletion {
+            let ids: Vec<&EventId> = event.tags.event_ids().collect();
+            let coordinates: Vec<&Coordinate> = event.tags.coordinates().collect();
+            let deleted = |e: &Event| {
+                e.pubkey == event.pubkey
+                    && (ids.contains(&&e.id)
+                        || coordinates.iter().any(|c| {
+                            c.kind == e.kind
+                                && c.public_key == e.pubkey
+                                && e.tags.identifier() == Some(c.identifier.as_str())
+                                && e.created_at <= event.created_at
+                        }))
+            };
+            scoped.retain(|e| !deleted(e));
+        }
+        scoped.push(event);
+    }
+
+    fn matching(&self, scope: &Scope, filter: &Filter) -> Vec<Event> {
+        let events = self.events.read();
+        let mut matched: Vec<Event> = events
+            .get(scope)
+            .map(|evs| evs.iter().filter(|e| filter.match_event(e)).cloned().collect())
+            .unwrap_or_default();
+        // Newest first, like LMDB queries
+        matched.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        if let Some(limit) = filter.limit {
+            matched.truncate(limit);
+        }
+        matched
+    }
+}
+
+impl ScopeStore for MemoryStore {
+    fn query(&self, scope: &Scope, filter: Filter) -> BoxFuture<'_, Result<Vec<Event>>> {
+        let events = self.matching(scope, &filter);
+        Box::pin(async move { Ok(events) })
+    }
+
+    fn count(&self, scope: &Scope, filter: Filter) -> BoxFuture<'_, Result<usize>> {
+        let count = self.matching(scope, &filter).len();
+        Box::pin(async move { Ok(count) })
+    }
+
+    fn scopes(&self) -> BoxFuture<'_, Result<Vec<Scope>>> {
+        let mut scopes: Vec<Scope> = self.events.read().keys().cloned().collect();
+        if !scopes.contains(&Scope::Default) {
+            scopes.insert(0, Scope::Default);
+        }
+        Box::pin(async move { Ok(scopes) })
+    }
+
+    fn save(&self, scope: &Scope, event: Event) -> BoxFuture<'_, Result<()>> {
+        self.insert(scope, event);
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn delete(&self, scope: &Scope, id: EventId) -> BoxFuture<'_, Result<()>> {
+        if let Some(scoped) = self.events.write().get_mut(scope) {
+            scoped.retain(|e| e.id != id);
+        }
+        Box::pin(async move { Ok(()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn note(content: &str) -> Event {
+        EventBuilder::text_note(content)
+            .sign(&Keys::generate())
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_scope_labels_round_trip() {
+        assert_eq!(scope_label(&Scope::Default), "root");
+        assert_eq!(scope_label(&Scope::named("drt2z").unwrap()), "drt2z");
+        assert_eq!(scope_from_label("root"), Some(Scope::Default));
+        assert_eq!(scope_from_label("drt2z"), Some(Scope::named("drt2z").unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_scopes_are_isolated() {
+        let store = MemoryStore::new();
+        let drt2z = Scope::named("drt2z").unwrap();
+        store.insert(&Scope::Default, note("root").await);
+        store.insert(&drt2z, note("cell").await);
+        store.insert(&drt2z, note("cell 2").await);
+
+        assert_eq!(store.count(&Scope::Default, Filter::new()).await.unwrap(), 1);
+        assert_eq!(store.count(&drt2z, Filter::new()).await.unwrap(), 2);
+        assert_eq!(store.query(&drt2z, Filter::new().limit(1)).await.unwrap().len(), 1);
+
+        let scopes = store.scopes().await.unwrap();
+        assert!(scopes.contains(&Scope::Default));
+        assert!(scopes.contains(&drt2z));
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_replaces_replaceable_events() {
+        let store = MemoryStore::new();
+        let keys = Keys::generate();
+        let older = EventBuilder::new(Kind::Metadata, "{}")
+            .custom_created_at(Timestamp::from(1_000))
+            .sign(&keys)
+            .await
+            .unwrap();
+        let newer = EventBuilder::new(Kind::Metadata, r#"{"name":"x"}"#)
+            .custom_created_at(Timestamp::from(2_000))
+            .sign(&keys)
+            .await
+            .unwrap();
+
+        store.save(&Scope::Default, newer.clone()).await.unwrap();
+        store.save(&Scope::Default, older).await.unwrap();
+
+        let stored = store.query(&Scope::Default, Filter::new().kind(Kind::Metadata)).await.unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].id, newer.id);
+    }
+
+    async fn article(keys: &Keys, content: &str, created_at: u64) -> Event {
+        EventBuilder::new(Kind::LongFormTextNote, content)
+            .tag(Tag::identifier("meetup"))
+            .custom_created_at(Timestamp::from(created_at))
+            .sign(keys)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_addressable_events_are_per_scope() {
+        let store = MemoryStore::new();
+        let keys = Keys::generate();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let sf = Scope::named("9q8yy").unwrap();
+        store.insert(&drt2z, article(&keys, "denver v1", 1_000).await);
+        store.insert(&sf, article(&keys, "la v1", 1_000).await);
+        store.insert(&drt2z, article(&keys, "denver v2", 2_000).await);
+
+        let contents = |scope: &Scope| {
+            let events = store.matching(scope, &Filter::new().kind(Kind::LongFormTextNote));
+            events.into_iter().map(|e| e.content).collect::<Vec<_>>()
+        };
+        assert_eq!(contents(&drt2z), vec!["denver v2"]);
+        assert_eq!(contents(&sf), vec!["la v1"]);
+
+        // Deleting the coordinate in one cell leaves the other's copy
+        let coordinate = Coordinate::new(Kind::LongFormTextNote, keys.public_key()).identifier("meetup");
+        let deletion = EventBuilder::delete(EventDeletionRequest::new().coordinate(coordinate))
+            .sign(&keys)
+            .await
+            .unwrap();
+        store.insert(&drt2z, deletion);
+        assert!(contents(&drt2z).is_empty());
+        assert_eq!(contents(&sf), vec!["la v1"]);
+    }
+}
@@ -0,0 +1,637 @@
+//! Database statistics and offline integrity checks
+//!
+//! Shared by the `/api/db` admin endpoint and the `verify` CLI subcommand.
+//! Everything goes through `ScopeStore`, so the same code runs against a
+//! live relay's database and one opened offline.
+
+use anyhow::{Context, Result};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tracing::info;
+use crate::geohash_utils::extract_geohash_tags;
+use crate::global_kinds::is_stored_in_root;
+use crate::storage::usage_percent;
+use crate::store::{scope_label, ScopeStore};
+
+/// Events fetched per page when walking a scope
+pub const PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ScopeEventCount {
+    pub scope: String,
+    pub events: usize,
+}
+
+/// Snapshot of the database for `/api/db`
+#[derive(Debug, Clone, Serialize)]
+pub struct DbStats {
+    pub size_on_disk: u64,
+    pub map_size: u64,
+    pub map_used_percent: u8,
+    pub named_scopes: usize,
+    pub total_events: usize,
+    pub top_scopes: Vec<ScopeEventCount>,
+    pub oldest_event: Option<u64>,
+    pub newest_event: Option<u64>,
+}
+
+/// Total size of the files in the database directory
+pub fn size_on_disk(database_path: &Path) -> u64 {
+    std::fs::read_dir(database_path)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.metadata().ok())
+                .filter(|meta| meta.is_file())
+                .map(|meta| meta.len())
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// Newest `created_at` in a scope
+pub async fn newest_created_at(store: &dyn ScopeStore, scope: &Scope) -> Result<Option<u64>> {
+    let events = store.query(scope, Filter::new().limit(1)).await?;
+    Ok(events.first().map(|e| e.created_at.as_u64()))
+}
+
+/// Oldest `created_at` in a scope
+///
+/// Queries only come back newest first, so this binary-searches on
+/// `until` with counts instead of reading the whole scope.
+async fn oldest_created_at(store: &dyn ScopeStore, scope: &Scope, newest: u64) -> Result<u64> {
+    let (mut low, mut high) = (0, newest);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        let count = store
+            .count(scope, Filter::new().until(Timestamp::from(mid)))
+            .await?;
+        if count > 0 {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    Ok(low)
+}
+
+/// Up to `count` of a scope's oldest events, oldest first
+///
+/// Binary-searches for the earliest `until` covering `count` events, like
+/// `oldest_created_at`, so only those events are read.
+pub async fn oldest_first(store: &dyn ScopeStore, scope: &Scope, count: usize) -> Result<Vec<Event>> {
+    let Some(newest) = newest_created_at(store, scope).await? else {
+        return Ok(Vec::new());
+    };
+    let (mut low, mut high) = (0, newest);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        let covered = store
+            .count(scope, Filter::new().until(Timestamp::from(mid)))
+            .await?;
+        if covered >= count {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    let mut events = store.query(scope, Filter::new().until(Timestamp::from(low))).await?;
+    events.sort_by(|a, b| a.created_at.cmp(&b.created_at).then(a.id.cmp(&b.id)));
+    events.truncate(count);
+    Ok(events)
+}
+
+/// Collects database statistics, listing the `top_n` largest scopes
+pub async fn db_stats(
+    store: &dyn ScopeStore,
+    database_path: &Path,
+    map_size: u64,
+    top_n: usize,
+) -> Result<DbStats> {
+    let size_on_disk = size_on_disk(database_path);
+    let scopes = store.scopes().await?;
+
+    let mut counts = Vec::new();
+    let mut oldest: Option<u64> = None;
+    let mut newest: Option<u64> = None;
+    for scope in &scopes {
+        let events = store.count(scope, Filter::new()).await?;
+        if let Some(scope_newest) = newest_created_at(store, scope).await? {
+            let scope_oldest = oldest_created_at(store, scope, scope_newest).await?;
+            oldest = Some(oldest.map_or(scope_oldest, |o| o.min(scope_oldest)));
+            newest = Some(newest.map_or(scope_newest, |n| n.max(scope_newest)));
+        }
+        counts.push(ScopeEventCount { scope: scope_label(scope), events });
+    }
+
+    let total_events = counts.iter().map(|c| c.events).sum();
+    counts.sort_by(|a, b| b.events.cmp(&a.events).then_with(|| a.scope.cmp(&b.scope)));
+    counts.truncate(top_n);
+
+    Ok(DbStats {
+        size_on_disk,
+        map_size,
+        map_used_percent: usage_percent(size_on_disk, map_size),
+        named_scopes: scopes.iter().filter(|s| matches!(s, Scope::Named { .. })).count(),
+        total_events,
+        top_scopes: counts,
+        oldest_event: oldest,
+        newest_event: newest,
+    })
+}
+
+/// Pages through a scope newest first
+///
+/// Pages are cut on `created_at`; ids already seen at the page boundary
+/// are skipped so events sharing a timestamp aren't returned twice. The
+/// position can be saved and restored to resume a long walk.
+#[derive(Debug)]
+pub struct ScopePager {
+    scope: Scope,
+    page_size: usize,
+    until: Option<Timestamp>,
+    since: Option<Timestamp>,
+    boundary_ids: HashSet<EventId>,
+    done: bool,
+}
+
+impl ScopePager {
+    pub fn new(scope: Scope, page_size: usize) -> Self {
+        Self {
+            scope,
+            page_size,
+            until: None,
+            since: None,
+            boundary_ids: HashSet::new(),
+            done: false,
+        }
+    }
+
+    /// Resumes a walk at a position previously returned by `position`
+    pub fn starting_at(mut self, until: Timestamp) -> Self {
+        self.until = Some(until);
+        self
+    }
+
+    /// Stops the walk at events older than `since`
+    pub fn since(mut self, since: Timestamp) -> Self {
+        self.since = Some(since);
+        self
+    }
+
+    /// `created_at` the next page starts from
+    pub fn position(&self) -> Option<Timestamp> {
+        self.until
+    }
+
+    /// Next page of unseen events, or None once the scope is exhausted
+    pub async fn next_page(&mut self, store: &dyn ScopeStore) -> Result<Option<Vec<Event>>> {
+        while !self.done {
+            let mut filter = Filter::new().limit(self.page_size);
+            if let Some(until) = self.until {
+                filter = filter.until(until);
+            }
+            if let Some(since) = self.since {
+                filter = filter.since(since);
+            }
+            let page = store.query(&self.scope, filter).await?;
+            let Some(last) = page.last().map(|e| e.created_at) else {
+                self.done = true;
+                break;
+            };
+            self.done = page.len() < self.page_size;
+
+            let mut next_boundary = HashSet::new();
+            let mut fresh = Vec::new();
+            for event in page {
+                if event.created_at == last {
+                    next_boundary.insert(event.id);
+                }
+                if !self.boundary_ids.contains(&event.id) {
+                    fresh.push(event);
+                }
+            }
+
+            if fresh.is_empty() {
+                // A whole page shares one timestamp; step past it
+                if last.as_u64() == 0 {
+                    self.done = true;
+                    break;
+                }
+                self.until = Some(Timestamp::from(last.as_u64() - 1));
+                self.boundary_ids.clear();
+                continue;
+            }
+
+            if self.until == Some(last) {
+                self.boundary_ids.extend(next_boundary);
+            } else {
+                self.boundary_ids = next_boundary;
+            }
+            self.until = Some(last);
+            return Ok(Some(fresh));
+        }
+        Ok(None)
+    }
+}
+
+/// Walks every event in a scope, newest first, one page at a time
+pub async fn for_each_event<F>(store: &dyn ScopeStore, scope: &Scope, page_size: usize, mut f: F) -> Result<usize>
+where
+    F: FnMut(Event) -> Result<()>,
+{
+    let mut pager = ScopePager::new(scope.clone(), page_size);
+    let mut visited = 0;
+    while let Some(page) = pager.next_page(store).await? {
+        visited += page.len();
+        for event in page {
+            f(event)?;
+        }
+    }
+    Ok(visited)
+}
+
+/// Cell named by an event's first valid g tag
+///
+/// Uses the same extraction and normalization as `handle_event`.
+pub fn geotagged_scope(event: &Event) -> Option<Scope> {
+    let tags: Vec<Vec<String>> = event.tags.iter().map(|tag| tag.clone().to_vec()).collect();
+    let geohash = extract_geohash_tags(&tags).into_iter().next()?;
+    Scope::named(&geohash).ok()
+}
+
+/// Scope runtime routing would have stored an event in, if it pins one
+///
+/// Mirrors `handle_event`: global kinds belong to root and geotagged
+/// events to their first geohash's cell. Untagged events may live in any
+/// scope they were posted to.
+pub fn routed_scope(event: &Event, global_kinds: &[u16]) -> Option<Scope> {
+    if is_stored_in_root(event.kind, global_kinds) {
+        return Some(Scope::Default);
+    }
+    geotagged_scope(event)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnomalyKind {
+    InvalidSignature,
+    MisScoped { expected: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Anomaly {
+    pub event_id: EventId,
+    pub scope: String,
+    pub kind: AnomalyKind,
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            AnomalyKind::InvalidSignature => {
+                write!(f, "{} in {}: invalid signature", self.event_id, self.scope)
+            }
+            AnomalyKind::MisScoped { expected } => {
+                write!(f, "{} in {}: routing places it in {}", self.event_id, self.scope, expected)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub scopes: usize,
+    pub events: usize,
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.anomalies.is_empty()
+    }
+}
+
+/// Re-verifies signatures and scope placement of every stored event
+pub async fn verify(store: &dyn ScopeStore, global_kinds: &[u16]) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    for scope in store.scopes().await? {
+        let label = scope_label(&scope);
+        let mut anomalies = Vec::new();
+        report.events += for_each_event(store, &scope, PAGE_SIZE, |event| {
+            if event.verify().is_err() {
+                anomalies.push(Anomaly {
+                    event_id: event.id,
+                    scope: label.clone(),
+                    kind: AnomalyKind::InvalidSignature,
+                });
+            }
+            if let Some(expected) = routed_scope(&event, global_kinds) {
+                if expected != scope {
+                    anomalies.push(Anomaly {
+                        event_id: event.id,
+                        scope: label.clone(),
+                        kind: AnomalyKind::MisScoped { expected: scope_label(&expected) },
+                    });
+                }
+            }
+            Ok(())
+        })
+        .await?;
+        report.scopes += 1;
+        report.anomalies.extend(anomalies);
+    }
+    Ok(report)
+}
+
+/// Options for `rescope`
+#[derive(Debug, Clone)]
+pub struct RescopeOptions {
+    /// Scope to scan
+    pub source: Scope,
+    /// Report moves without writing anything
+    pub dry_run: bool,
+    /// Where progress is checkpointed after each page (not used in dry runs)
+    pub checkpoint: Option<PathBuf>,
+    /// Continue from the position in `checkpoint`
+    pub resume: bool,
+    pub page_size: usize,
+}
+
+/// One event moved (or, in a dry run, that would be moved)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Move {
+    pub event_id: EventId,
+    pub from: String,
+    pub to: String,
+}
+
+impl fmt::Display for Move {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.event_id, self.from, self.to)
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RescopeReport {
+    pub scanned: usize,
+    pub moved: usize,
+}
+
+fn read_checkpoint(path: &Path) -> Result<Option<Timestamp>> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(Timestamp::from(
+            contents.trim().parse::<u64>().context("invalid rescope checkpoint")?,
+        ))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Moves geotagged events out of `options.source` into their cell
+///
+/// An event moves when its first valid g tag names a scope other than the
+/// source, exactly as `handle_event` would have routed it. Each event is
+/// written to the target before it's deleted from the source, so an
+/// interrupted run can leave a duplicate but never loses an event; the
+/// checkpoint records the scan position after every page.
+pub async fn rescope<F>(store: &dyn ScopeStore, options: &RescopeOptions, mut on_move: F) -> Result<RescopeReport>
+where
+    F: FnMut(&Move),
+{
+    let mut pager = ScopePager::new(options.source.clone(), options.page_size);
+    if options.resume {
+        if let Some(position) = options.checkpoint.as_deref().map(read_checkpoint).transpose()?.flatten() {
+            info!("Resuming rescope of {} at created_at {}", scope_label(&options.source), position);
+            pager = pager.starting_at(position);
+        }
+    }
+
+    let from = scope_label(&options.source);
+    let mut report = RescopeReport::default();
+    while let Some(page) = pager.next_page(store).await? {
+        report.scanned += page.len();
+        for event in page {
+            let Some(target) = geotagged_scope(&event) else {
+                continue;
+            };
+            if target == options.source {
+                continue;
+            }
+            let planned = Move {
+                event_id: event.id,
+                from: from.clone(),
+                to: scope_label(&target),
+            };
+            if !options.dry_run {
+                store.save(&target, event).await?;
+                store.delete(&options.source, planned.event_id).await?;
+            }
+            report.moved += 1;
+            on_move(&planned);
+        }
+
+        if !options.dry_run {
+            if let (Some(path), Some(position)) = (&options.checkpoint, pager.position()) {
+                std::fs::write(path, position.as_u64().to_string())?;
+            }
+        }
+        info!("Rescope {}: scanned {}, moved {}", from, report.scanned, report.moved);
+    }
+
+    // Finished: the next run should start from the top again
+    if !options.dry_run {
+        if let Some(path) = &options.checkpoint {
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Outcome of `import_events`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    pub imported: usize,
+    /// Events whose signature didn't verify, which are skipped
+    pub invalid: usize,
+}
+
+impl std::ops::AddAssign for ImportReport {
+    fn add_assign(&mut self, other: Self) {
+        self.imported += other.imported;
+        self.invalid += other.invalid;
+    }
+}
+
+/// Saves externally sourced events into `scope`, skipping bad signatures
+pub async fn import_events(store: &dyn ScopeStore, scope: &Scope, events: Vec<Event>) -> Result<ImportReport> {
+    let mut report = ImportReport::default();
+    for event in events {
+        if event.verify().is_err() {
+            report.invalid += 1;
+            continue;
+        }
+        store.save(scope, event).await?;
+        report.imported += 1;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::global_kinds::DEFAULT_GLOBAL_KINDS;
+    use crate::store::{LmdbStore, MemoryStore};
+    use relay_builder::RelayDatabase;
+    use std::sync::Arc;
+
+    async fn note_at(keys: &Keys, created_at: u64, tags: Vec<Tag>) -> Event {
+        EventBuilder::text_note("admin test")
+            .tags(tags)
+            .custom_created_at(Timestamp::from(created_at))
+            .sign(keys)
+            .await
+            .unwrap()
+    }
+
+    fn g(geohash: &str) -> Tag {
+        Tag::custom(TagKind::Custom("g".into()), vec![geohash.to_string()])
+    }
+
+    #[tokio::test]
+    async fn test_for_each_event_visits_everything_once() {
+        let store = MemoryStore::new();
+        let keys = Keys::generate();
+        // Several events share timestamps across page boundaries
+        for i in 0..25 {
+            store.insert(&Scope::Default, note_at(&keys, 1000 + i / 4, vec![]).await);
+        }
+        let mut ids = HashSet::new();
+        let visited = for_each_event(&store, &Scope::Default, 3, |event| {
+            assert!(ids.insert(event.id));
+            Ok(())
+        })
+        .await
+        .unwrap();
+        assert_eq!(visited, 25);
+    }
+
+    #[tokio::test]
+    async fn test_routed_scope() {
+        let keys = Keys::generate();
+        let drt2z = Scope::named("drt2z").unwrap();
+        assert_eq!(routed_scope(&note_at(&keys, 1, vec![g("DRT2Z")]).await, &[]), Some(drt2z));
+        assert_eq!(routed_scope(&note_at(&keys, 1, vec![]).await, &[]), None);
+        let profile = EventBuilder::new(Kind::Metadata, "{}").sign(&keys).await.unwrap();
+        assert_eq!(routed_scope(&profile, &DEFAULT_GLOBAL_KINDS), Some(Scope::Default));
+    }
+
+    #[tokio::test]
+    async fn test_verify_and_stats_on_seeded_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = Arc::new(RelayDatabase::new(dir.path()).unwrap());
+        let store = LmdbStore::new(database);
+        let keys = Keys::generate();
+        let drt2z = Scope::named("drt2z").unwrap();
+
+        store.save(&drt2z, note_at(&keys, 1000, vec![g("drt2z")]).await).await.unwrap();
+        store.save(&drt2z, note_at(&keys, 2000, vec![]).await).await.unwrap();
+        store.save(&Scope::Default, note_at(&keys, 1500, vec![]).await).await.unwrap();
+        // Stored by an older build in root despite its g tag
+        let misplaced = note_at(&keys, 3000, vec![g("9q8yy")]).await;
+        store.save(&Scope::Default, misplaced.clone()).await.unwrap();
+
+        let report = verify(&store, &DEFAULT_GLOBAL_KINDS).await.unwrap();
+        assert_eq!(report.events, 4);
+        assert_eq!(
+            report.anomalies,
+            vec![Anomaly {
+                event_id: misplaced.id,
+                scope: "root".to_string(),
+                kind: AnomalyKind::MisScoped { expected: "9q8yy".to_string() },
+            }]
+        );
+
+        let stats = db_stats(&store, dir.path(), 1 << 30, 10).await.unwrap();
+        assert_eq!(stats.total_events, 4);
+        assert_eq!(stats.named_scopes, 1);
+        assert_eq!(stats.oldest_event, Some(1000));
+        assert_eq!(stats.newest_event, Some(3000));
+        assert_eq!(stats.top_scopes[0].events, 2);
+        assert!(stats.size_on_disk > 0);
+    }
+
+    fn rescope_options(dry_run: bool) -> RescopeOptions {
+        RescopeOptions {
+            source: Scope::Default,
+            dry_run,
+            checkpoint: None,
+            resume: false,
+            page_size: 2,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rescope_moves_geotagged_events_out_of_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = Arc::new(RelayDatabase::new(dir.path()).unwrap());
+        let store = LmdbStore::new(database);
+        let keys = Keys::generate();
+        let drt2z = Scope::named("drt2z").unwrap();
+
+        let mut misplaced = Vec::new();
+        for i in 0..5 {
+            let event = note_at(&keys, 1000 + i, vec![g("DRT2Z"), g("9q8yy")]).await;
+            store.save(&Scope::Default, event.clone()).await.unwrap();
+            misplaced.push(event.id);
+        }
+        let untagged = note_at(&keys, 1002, vec![]).await;
+        store.save(&Scope::Default, untagged.clone()).await.unwrap();
+
+        // Dry run reports but changes nothing
+        let mut planned = Vec::new();
+        let report = rescope(&store, &rescope_options(true), |m| planned.push(m.clone())).await.unwrap();
+        assert_eq!(report, RescopeReport { scanned: 6, moved: 5 });
+        assert!(planned.iter().all(|m| m.from == "root" && m.to == "drt2z"));
+        assert_eq!(store.count(&Scope::Default, Filter::new()).await.unwrap(), 6);
+
+        let report = rescope(&store, &rescope_options(false), |_| {}).await.unwrap();
+        assert_eq!(report.moved, 5);
+
+        let root = store.query(&Scope::Default, Filter::new()).await.unwrap();
+        assert_eq!(root.iter().map(|e| e.id).collect::<Vec<_>>(), vec![untagged.id]);
+        let cell = store.query(&drt2z, Filter::new()).await.unwrap();
+        assert_eq!(cell.len(), 5);
+        assert!(cell.iter().all(|e| misplaced.contains(&e.id)));
+
+        // Nothing left to move
+        assert_eq!(rescope(&store, &rescope_options(false), |_| {}).await.unwrap().moved, 0);
+    }
+
+    #[tokio::test]
+    async fn test_rescope_resumes_from_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = MemoryStore::new();
+        let keys = Keys::generate();
+        for i in 0..4 {
+            store.insert(&Scope::Default, note_at(&keys, 1000 + i, vec![g("drt2z")]).await);
+        }
+
+        // A previous run got as far as created_at 1001
+        let checkpoint = dir.path().join("rescope-root.checkpoint");
+        std::fs::write(&checkpoint, "1001").unwrap();
+        let options = RescopeOptions {
+            checkpoint: Some(checkpoint.clone()),
+            resume: true,
+            ..rescope_options(false)
+        };
+        let report = rescope(&store, &options, |_| {}).await.unwrap();
+        assert_eq!(report.moved, 2);
+        assert_eq!(store.count(&Scope::Default, Filter::new()).await.unwrap(), 2);
+        assert!(!checkpoint.exists());
+    }
+}
@@ -0,0 +1,150 @@
+//! Per-connection subscription limits
+//!
+//! `SubscriptionLimitMiddleware` tracks each connection's open subscription
+//! ids in `ConnectionState` and answers a REQ that would open one more than
+//! `max_subscriptions_per_connection` with `CLOSED` and
+//! `RejectReason::TooManySubscriptions`. Ids leave the set on CLOSE and
+//! whenever the relay itself sends CLOSED.
+//!
+//! It also holds one of `max_concurrent_queries_per_connection` permits while
+//! a REQ's stored events are queried, so one client firing many heavy REQs
+//! queues behind itself instead of monopolizing the store.
+
+use nostr_sdk::prelude::*;
+use relay_builder::{InboundContext, InboundProcessor, NostrMiddleware, OutboundContext};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tracing::debug;
+use crate::processor::ConnectionState;
+use crate::reject::RejectReason;
+
+/// A connection's open subscriptions, kept in `ConnectionState`
+#[derive(Debug, Clone, Default)]
+pub struct OpenSubscriptions {
+    ids: HashSet<SubscriptionId>,
+    /// Created on the first REQ
+    queries: Option<Arc<Semaphore>>,
+}
+
+impl OpenSubscriptions {
+    /// Registers `id`, false if that would exceed `max` (0 disables)
+    ///
+    /// Reusing an open id replaces that subscription, so it always fits.
+    pub fn open(&mut self, id: &SubscriptionId, max: usize) -> bool {
+        if self.ids.contains(id) {
+            return true;
+        }
+        if max > 0 && self.ids.len() >= max {
+            return false;
+        }
+        self.ids.insert(id.clone());
+        true
+    }
+
+    pub fn close(&mut self, id: &SubscriptionId) {
+        self.ids.remove(id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    fn query_permits(&mut self, concurrency: usize) -> Arc<Semaphore> {
+        self.queries
+            .get_or_insert_with(|| Arc::new(Semaphore::new(concurrency.max(1))))
+            .clone()
+    }
+}
+
+/// Caps open subscriptions and concurrent REQ queries per connection
+#[derive(Debug, Clone)]
+pub struct SubscriptionLimitMiddleware {
+    max_subscriptions: usize,
+    max_concurrent_queries: usize,
+}
+
+impl SubscriptionLimitMiddleware {
+    pub fn new(max_subscriptions: usize, max_concurrent_queries: usize) -> Self {
+        Self {
+            max_subscriptions,
+            max_concurrent_queries,
+        }
+    }
+}
+
+impl NostrMiddleware<ConnectionState> for SubscriptionLimitMiddleware {
+    async fn process_inbound<Next>(&self, ctx: InboundContext<'_, ConnectionState, Next>) -> Result<(), anyhow::Error>
+    where
+        Next: InboundProcessor<ConnectionState>,
+    {
+        if let Some(ClientMessage::Close(subscription_id)) = &ctx.message {
+            ctx.state.write().custom.subscriptions.close(subscription_id);
+        }
+        let requested = match &ctx.message {
+            Some(ClientMessage::Req { subscription_id, .. }) => Some(SubscriptionId::clone(subscription_id)),
+            _ => None,
+        };
+        let Some(subscription_id) = requested else {
+            return ctx.next().await;
+        };
+
+        let (opened, permits) = {
+            let mut state = ctx.state.write();
+            let subscriptions = &mut state.custom.subscriptions;
+            (
+                subscriptions.open(&subscription_id, self.max_subscriptions),
+                subscriptions.query_permits(self.max_concurrent_queries),
+            )
+        };
+        if !opened {
+            debug!("Refusing subscription {}: connection is at its limit", subscription_id);
+            metrics::counter!("relay_subscriptions_rejected_total").increment(1);
+            let reason = RejectReason::TooManySubscriptions { max: self.max_subscriptions };
+            ctx.send_message(RelayMessage::closed(subscription_id, reason.to_string()))?;
+            return Ok(());
+        }
+
+        // Held until the stored events (and EOSE) have been sent
+        let _permit = permits.acquire_owned().await?;
+        ctx.next().await
+    }
+
+    async fn process_outbound(&self, ctx: OutboundContext<'_, ConnectionState>) -> Result<(), anyhow::Error> {
+        if let Some(RelayMessage::Closed { subscription_id, .. }) = &ctx.message {
+            ctx.state.write().custom.subscriptions.close(subscription_id);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_respects_the_limit() {
+        let mut subscriptions = OpenSubscriptions::default();
+        let a = SubscriptionId::new("a");
+        let b = SubscriptionId::new("b");
+        let c = SubscriptionId::new("c");
+
+        assert!(subscriptions.open(&a, 2));
+        assert!(subscriptions.open(&b, 2));
+        assert!(!subscriptions.open(&c, 2));
+        assert_eq!(subscriptions.len(), 2);
+
+        // Replacing an open subscription isn't a new one
+        assert!(subscriptions.open(&a, 2));
+
+        subscriptions.close(&a);
+        assert!(subscriptions.open(&c, 2));
+
+        // Zero disables the limit
+        assert!((0..100).all(|i| subscriptions.open(&SubscriptionId::new(i.to_string()), 0)));
+    }
+}
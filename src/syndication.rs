@@ -0,0 +1,495 @@
+//! JSON Feed and Atom feeds of recent notes per geohash
+//!
+//! `GET /feed.json` (JSON Feed 1.1) and `GET /feed.atom` on a geohash
+//! subdomain list the cell's latest text notes, so the cell can be followed
+//! in a feed reader and indexed. Note content is attacker-controlled: control
+//! characters are stripped, the Atom document is rendered by an escaping
+//! template and `content_html` is HTML-escaped. Rendered feeds are cached
+//! for `FEED_CACHE_TTL` per scope and domain.
+//!
+//! Disabled entirely with `SYNDICATION_FEEDS=false`.
+
+use askama::Template;
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use nostr::nips::nip19::ToBech32;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+use crate::config::RelayConfig;
+use crate::geohash_utils::is_geohash_subdomain;
+use crate::host_parsing::host_info;
+use crate::store::ScopeStore;
+
+/// Notes listed per feed
+pub const FEED_NOTE_LIMIT: usize = 50;
+
+/// How long a rendered feed is served before the scope is queried again
+pub const FEED_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// Rendered feeds kept at once
+const FEED_CACHE_CAPACITY: usize = 1024;
+
+/// Longest entry title taken from the note's first line
+const TITLE_CHARS: usize = 80;
+
+pub const JSON_FEED_CONTENT_TYPE: &str = "application/feed+json";
+pub const ATOM_CONTENT_TYPE: &str = "application/atom+xml; charset=utf-8";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FeedFormat {
+    Json,
+    Atom,
+}
+
+impl FeedFormat {
+    fn path(&self) -> &'static str {
+        match self {
+            FeedFormat::Json => "/feed.json",
+            FeedFormat::Atom => "/feed.atom",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            FeedFormat::Json => JSON_FEED_CONTENT_TYPE,
+            FeedFormat::Atom => ATOM_CONTENT_TYPE,
+        }
+    }
+}
+
+/// Removes control characters (except newlines and tabs), which are invalid
+/// in XML and confuse feed readers
+pub fn sanitize_text(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || matches!(c, '\n' | '\t'))
+        .collect()
+}
+
+/// Escapes text for use inside HTML
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Proleptic Gregorian date and time of a unix timestamp, plus the weekday
+/// (0 = Sunday)
+fn civil(secs: u64) -> (i64, u32, u32, u64, u64, u64, usize) {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+
+    // Howard Hinnant's civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let weekday = (days + 4).rem_euclid(7) as usize;
+    (year, month, day, rem / 3_600, rem % 3_600 / 60, rem % 60, weekday)
+}
+
+/// `2024-05-01T12:00:00Z`
+pub fn rfc3339(secs: u64) -> String {
+    let (year, month, day, hour, minute, second, _) = civil(secs);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hour, minute, second)
+}
+
+/// `Wed, 01 May 2024 12:00:00 GMT`, for `Last-Modified`
+pub fn http_date(secs: u64) -> String {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (year, month, day, hour, minute, second, weekday) = civil(secs);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        DAYS[weekday],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// URLs and wording shared by both formats
+struct FeedMeta {
+    title: String,
+    description: String,
+    home_page_url: String,
+    feed_url: String,
+}
+
+/// One note, sanitized and formatted for a feed
+struct FeedNote {
+    /// `nostr:note1...`
+    id: String,
+    author: String,
+    title: String,
+    content: String,
+    published: String,
+}
+
+impl FeedNote {
+    fn new(event: &Event) -> Self {
+        let content = sanitize_text(&event.content);
+        let first_line = content.lines().find(|line| !line.trim().is_empty()).unwrap_or_default().trim();
+        let mut title: String = first_line.chars().take(TITLE_CHARS).collect();
+        if first_line.chars().count() > TITLE_CHARS {
+            title.push('…');
+        }
+        Self {
+            id: format!("nostr:{}", event.id.to_bech32().unwrap_or_else(|_| event.id.to_hex())),
+            author: event.pubkey.to_bech32().unwrap_or_else(|_| event.pubkey.to_hex()),
+            title,
+            content,
+            published: rfc3339(event.created_at.as_u64()),
+        }
+    }
+}
+
+/// JSON Feed 1.1 document
+#[derive(Debug, Serialize)]
+pub struct JsonFeed {
+    pub version: &'static str,
+    pub title: String,
+    pub home_page_url: String,
+    pub feed_url: String,
+    pub description: String,
+    pub items: Vec<JsonFeedItem>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JsonFeedItem {
+    pub id: String,
+    pub title: String,
+    pub content_text: String,
+    pub content_html: String,
+    pub date_published: String,
+    pub authors: Vec<JsonFeedAuthor>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JsonFeedAuthor {
+    pub name: String,
+    pub url: String,
+}
+
+fn render_json(meta: FeedMeta, notes: Vec<FeedNote>) -> String {
+    let feed = JsonFeed {
+        version: "https://jsonfeed.org/version/1.1",
+        title: meta.title,
+        home_page_url: meta.home_page_url,
+        feed_url: meta.feed_url,
+        description: meta.description,
+        items: notes
+            .into_iter()
+            .map(|note| JsonFeedItem {
+                content_html: escape_html(&note.content).replace('\n', "<br>"),
+                authors: vec![JsonFeedAuthor {
+                    url: format!("nostr:{}", note.author),
+                    name: note.author,
+                }],
+                id: note.id,
+                title: note.title,
+                content_text: note.content,
+                date_published: note.published,
+            })
+            .collect(),
+    };
+    serde_json::to_string(&feed).expect("feed serialization is infallible")
+}
+
+/// Atom document; the template escapes every interpolated value
+#[derive(Template)]
+#[template(path = "feed.xml")]
+struct AtomFeed {
+    title: String,
+    description: String,
+    home_page_url: String,
+    feed_url: String,
+    updated: String,
+    entries: Vec<FeedNote>,
+}
+
+fn render_atom(meta: FeedMeta, notes: Vec<FeedNote>, updated: u64) -> String {
+    AtomFeed {
+        title: meta.title,
+        description: meta.description,
+        home_page_url: meta.home_page_url,
+        feed_url: meta.feed_url,
+        updated: rfc3339(updated),
+        entries: notes,
+    }
+    .render()
+    .expect("atom template rendering is infallible")
+}
+
+#[derive(Debug, Clone)]
+struct RenderedFeed {
+    body: Arc<str>,
+    /// Newest note's timestamp, if the feed has any
+    last_modified: Option<u64>,
+    rendered_at: Instant,
+}
+
+/// Renders and caches feeds for geohash cells
+pub struct SyndicationFeeds {
+    store: Arc<dyn ScopeStore>,
+    base_domain_parts: usize,
+    https: bool,
+    relay_name: Option<String>,
+    cache: Mutex<HashMap<(String, String, FeedFormat), RenderedFeed>>,
+}
+
+impl SyndicationFeeds {
+    pub fn new(config: &RelayConfig, store: Arc<dyn ScopeStore>) -> Self {
+        Self {
+            store,
+            base_domain_parts: config.base_domain_parts(),
+            https: !config.relay_url.starts_with("ws://"),
+            relay_name: config.relay_name.clone(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn render(&self, geohash: &str, domain: &str, format: FeedFormat) -> anyhow::Result<RenderedFeed> {
+        let key = (geohash.to_string(), domain.to_string(), format);
+        if let Some(feed) = self.cache.lock().get(&key) {
+            if feed.rendered_at.elapsed() < FEED_CACHE_TTL {
+                return Ok(feed.clone());
+            }
+        }
+
+        let scope = Scope::named(geohash)?;
+        let events = self
+            .store
+            .query(&scope, Filter::new().kind(Kind::TextNote).limit(FEED_NOTE_LIMIT))
+            .await?;
+        // Future-dated notes can't push Last-Modified past now
+        let now = now_secs();
+        let last_modified = events.iter().map(|e| e.created_at.as_u64().min(now)).max();
+
+        let scheme = if self.https { "https" } else { "http" };
+        let home_page_url = format!("{}://{}.{}/", scheme, geohash, domain);
+        let meta = FeedMeta {
+            title: match &self.relay_name {
+                Some(name) => format!("{} · {}", geohash, name),
+                None => format!("Notes in {}", geohash),
+            },
+            description: format!("Recent notes posted in geohash cell {}", geohash),
+            feed_url: format!("{}{}", home_page_url.trim_end_matches('/'), format.path()),
+            home_page_url,
+        };
+        let notes: Vec<FeedNote> = events.iter().map(FeedNote::new).collect();
+        let body = match format {
+            FeedFormat::Json => render_json(meta, notes),
+            FeedFormat::Atom => render_atom(meta, notes, last_modified.unwrap_or(now)),
+        };
+
+        let feed = RenderedFeed {
+            body: Arc::from(body),
+            last_modified,
+            rendered_at: Instant::now(),
+        };
+        let mut cache = self.cache.lock();
+        if cache.len() >= FEED_CACHE_CAPACITY {
+            cache.retain(|_, feed| feed.rendered_at.elapsed() < FEED_CACHE_TTL);
+            if cache.len() >= FEED_CACHE_CAPACITY {
+                cache.clear();
+            }
+        }
+        cache.insert(key, feed.clone());
+        Ok(feed)
+    }
+
+    async fn respond(&self, headers: &HeaderMap, format: FeedFormat) -> Response {
+        let host = host_info(headers, self.base_domain_parts);
+        let Some(geohash) = host.subdomain.filter(|s| is_geohash_subdomain(s)).map(|s| s.to_lowercase()) else {
+            return (StatusCode::NOT_FOUND, "feeds are served on geohash subdomains").into_response();
+        };
+        let feed = match self.render(&geohash, &host.domain, format).await {
+            Ok(feed) => feed,
+            Err(e) => {
+                warn!("Failed to render feed for {}: {}", geohash, e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+
+        let mut response = (
+            [
+                (header::CONTENT_TYPE, format.content_type().to_string()),
+                (header::CACHE_CONTROL, format!("public, max-age={}", FEED_CACHE_TTL.as_secs())),
+                (header::VARY, "host".to_string()),
+            ],
+            feed.body.to_string(),
+        )
+            .into_response();
+        if let Some(last_modified) = feed.last_modified {
+            if let Ok(value) = http_date(last_modified).parse() {
+                response.headers_mut().insert(header::LAST_MODIFIED, value);
+            }
+        }
+        response
+    }
+}
+
+async fn json_feed_handler(State(feeds): State<Arc<SyndicationFeeds>>, headers: HeaderMap) -> Response {
+    feeds.respond(&headers, FeedFormat::Json).await
+}
+
+async fn atom_feed_handler(State(feeds): State<Arc<SyndicationFeeds>>, headers: HeaderMap) -> Response {
+    feeds.respond(&headers, FeedFormat::Atom).await
+}
+
+pub fn router(feeds: Arc<SyndicationFeeds>) -> Router {
+    Router::new()
+        .route("/feed.json", get(json_feed_handler))
+        .route("/feed.atom", get(atom_feed_handler))
+        .with_state(feeds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use axum::{body::{to_bytes, Body}, http::Request};
+    use tower::ServiceExt;
+
+    const HOSTILE: &str = "<script>alert('x')</script> & \"quotes\"\u{0}\u{1b}]]>\nsecond line";
+
+    async fn app_with_notes(contents: &[&str]) -> (Router, Keys) {
+        let store = MemoryStore::new();
+        let keys = Keys::generate();
+        let cell = Scope::named("drt2z").unwrap();
+        for (i, content) in contents.iter().enumerate() {
+            let event = EventBuilder::text_note(*content)
+                .custom_created_at(Timestamp::from(1_714_564_800 + i as u64))
+                .sign(&keys)
+                .await
+                .unwrap();
+            store.insert(&cell, event);
+        }
+        // Other kinds aren't listed
+        let reaction = EventBuilder::new(Kind::Reaction, "+").sign(&keys).await.unwrap();
+        store.insert(&cell, reaction);
+
+        let config = RelayConfig {
+            relay_url: "wss://example.com".to_string(),
+            ..Default::default()
+        };
+        let feeds = SyndicationFeeds::new(&config, Arc::new(store));
+        (router(Arc::new(feeds)), keys)
+    }
+
+    async fn get(app: Router, host: &str, uri: &str) -> Response {
+        app.oneshot(Request::builder().uri(uri).header("host", host).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn body_string(response: Response) -> String {
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(1_714_564_800), "2024-05-01T12:00:00Z");
+        assert_eq!(rfc3339(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(http_date(1_714_564_800), "Wed, 01 May 2024 12:00:00 GMT");
+        assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+
+    #[tokio::test]
+    async fn test_json_feed_schema() {
+        let (app, keys) = app_with_notes(&["first note", "second note"]).await;
+        let response = get(app, "drt2z.example.com", "/feed.json").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], JSON_FEED_CONTENT_TYPE);
+        assert_eq!(response.headers()[header::LAST_MODIFIED], "Wed, 01 May 2024 12:00:01 GMT");
+
+        let feed: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(feed["version"], "https://jsonfeed.org/version/1.1");
+        assert_eq!(feed["home_page_url"], "https://drt2z.example.com/");
+        assert_eq!(feed["feed_url"], "https://drt2z.example.com/feed.json");
+        assert!(feed["title"].as_str().unwrap().contains("drt2z"));
+
+        let items = feed["items"].as_array().unwrap();
+        assert_eq!(items.len(), 2);
+        // Newest first
+        assert_eq!(items[0]["content_text"], "second note");
+        assert_eq!(items[0]["date_published"], "2024-05-01T12:00:01Z");
+        assert!(items[0]["id"].as_str().unwrap().starts_with("nostr:note1"));
+        let npub = keys.public_key().to_bech32().unwrap();
+        assert_eq!(items[0]["authors"][0]["name"], npub);
+    }
+
+    #[tokio::test]
+    async fn test_hostile_content_is_escaped() {
+        let (app, _) = app_with_notes(&[HOSTILE]).await;
+        let feed: serde_json::Value =
+            serde_json::from_str(&body_string(get(app.clone(), "drt2z.example.com", "/feed.json").await).await).unwrap();
+        let item = &feed["items"][0];
+        let text = item["content_text"].as_str().unwrap();
+        assert!(!text.contains('\u{0}') && !text.contains('\u{1b}'));
+        let html = item["content_html"].as_str().unwrap();
+        assert!(!html.contains("<script>"));
+        assert!(html.starts_with("&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt; &amp; &quot;quotes&quot;"));
+        assert!(html.ends_with("]]&gt;<br>second line"));
+
+        let response = get(app, "drt2z.example.com", "/feed.atom").await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], ATOM_CONTENT_TYPE);
+        let atom = body_string(response).await;
+        assert!(atom.starts_with("<?xml"));
+        assert!(!atom.contains("<script>"));
+        assert!(!atom.contains("]]>"));
+        assert!(!atom.contains('\u{0}') && !atom.contains('\u{1b}'));
+        assert!(atom.contains("&lt;script&gt;"));
+        assert!(atom.contains("<published>2024-05-01T12:00:00Z</published>"));
+    }
+
+    #[tokio::test]
+    async fn test_only_served_on_geohash_subdomains() {
+        let (app, _) = app_with_notes(&[]).await;
+        let response = get(app.clone(), "example.com", "/feed.json").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // An empty cell still has a valid feed, without Last-Modified
+        let response = get(app, "9q8yy.example.com", "/feed.atom").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(header::LAST_MODIFIED).is_none());
+        let atom = body_string(response).await;
+        assert!(atom.contains("9q8yy.example.com"));
+        assert!(!atom.contains("<entry>"));
+    }
+}
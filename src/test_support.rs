@@ -0,0 +1,46 @@
+//! Constructors for tests and benchmarks
+//!
+//! Builds processor inputs without a running relay or database. Events are
+//! signed synchronously so async signing doesn't dominate benchmark timings.
+
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::RwLock;
+use relay_builder::EventContext;
+use std::sync::Arc;
+use crate::processor::ConnectionState;
+
+/// Event context for a connection on `scope`, unauthenticated
+pub fn event_context(scope: Scope) -> EventContext {
+    EventContext {
+        relay_pubkey: Keys::generate().public_key(),
+        subdomain: Arc::new(scope),
+        authed_pubkey: None,
+    }
+}
+
+/// Fresh per-connection state
+pub fn connection_state() -> Arc<RwLock<ConnectionState>> {
+    Arc::new(RwLock::new(ConnectionState::default()))
+}
+
+/// A `["g", geohash]` tag
+pub fn geohash_tag(geohash: &str) -> Tag {
+    Tag::custom(TagKind::Custom("g".into()), vec![geohash.to_string()])
+}
+
+/// Text note carrying `tags`, signed synchronously
+pub fn signed_note(keys: &Keys, tags: Vec<Tag>) -> Event {
+    EventBuilder::text_note("test note")
+        .tags(tags)
+        .sign_with_keys(keys)
+        .expect("signing with local keys")
+}
+
+/// `count` raw g tags cycling through a few cells
+pub fn raw_geohash_tags(count: usize) -> Vec<Vec<String>> {
+    const CELLS: [&str; 4] = ["drt2z", "9q8yy", "u4pru", "gcpvj"];
+    (0..count)
+        .map(|i| vec!["g".to_string(), CELLS[i % CELLS.len()].to_string()])
+        .collect()
+}
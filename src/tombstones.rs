@@ -0,0 +1,355 @@
+//! Soft deletion for NIP-09 requests
+//!
+//! With `deletion_mode = tombstone`, a kind 5 request isn't handed to
+//! storage right away, since storage applies a request as it saves it and
+//! would delete what it names for good. The relay resolves
+//! its targets the way storage would (the named ids and coordinates in the
+//! request's scope, by its author, coordinates up to its `created_at`) and
+//! records a tombstone: who asked, when, in which scope and what it hid.
+//! Hidden events are left out of REQs (`can_see_event`), COUNTs and
+//! exports, so a moderation dispute can still see what went and when.
+//!
+//! An admin can restore a tombstone, bringing its events back and dropping
+//! the request, or purge it. Purging stores the request as hard deletion
+//! would have, so storage deletes its events for good and the request is
+//! served from then on. A purged tombstone stays as the record and keeps
+//! hiding its ids should anyone publish them again.
+//!
+//! Tombstones are kept in an `AppendLog` under `database_path`; a restore
+//! or purge appends the tombstone's new state.
+
+use anyhow::{Context, Result};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use crate::append_log::{AppendLog, LogEntry};
+use crate::config::{DeletionMode, RelayConfig};
+use crate::store::{scope_from_label, scope_label, ScopeStore};
+
+/// Tombstones, next to the database
+pub const TOMBSTONES_FILE: &str = "tombstones.jsonl";
+
+/// Where a tombstone stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TombstoneState {
+    /// Its events are stored but hidden
+    Hidden,
+    /// An admin brought its events back
+    Restored,
+    /// An admin deleted its events for good
+    Purged,
+}
+
+/// A deletion request and what it hid
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tombstone {
+    /// Id of the kind 5 request
+    pub id: EventId,
+    pub requested_by: PublicKey,
+    /// Scope label ("root" or the geohash)
+    pub scope: String,
+    /// When the relay received the request
+    pub received_at: u64,
+    /// Stored events the request applies to
+    pub hidden: Vec<EventId>,
+    pub state: TombstoneState,
+    /// The request as signed
+    pub request: Event,
+}
+
+impl Tombstone {
+    fn hides(&self) -> bool {
+        self.state != TombstoneState::Restored
+    }
+}
+
+/// Events in `scope` that `request` deletes under NIP-09: the ids it names
+/// and the addressable or replaceable events at the coordinates it names,
+/// up to its `created_at`, all by its author
+pub async fn targets(store: &dyn ScopeStore, scope: &Scope, request: &Event) -> Result<Vec<EventId>> {
+    let mut targets = Vec::new();
+    let ids: Vec<EventId> = request.tags.event_ids().copied().collect();
+    if !ids.is_empty() {
+        let filter = Filter::new().ids(ids).author(request.pubkey);
+        targets.extend(store.query(scope, filter).await?.into_iter().map(|event| event.id));
+    }
+    for coordinate in request.tags.coordinates().filter(|c| c.public_key == request.pubkey) {
+        let mut filter = Filter::new()
+            .kind(coordinate.kind)
+            .author(coordinate.public_key)
+            .until(request.created_at);
+        if !coordinate.identifier.is_empty() {
+            filter = filter.identifier(coordinate.identifier.clone());
+        }
+        for event in store.query(scope, filter).await? {
+            if !targets.contains(&event.id) {
+                targets.push(event.id);
+            }
+        }
+    }
+    Ok(targets)
+}
+
+/// Tombstones by request id, optionally backed by a file
+#[derive(Debug, Default)]
+pub struct Tombstones {
+    enabled: bool,
+    log: AppendLog<Tombstone>,
+    /// Hidden event ids, to the tombstone hiding them
+    hidden: RwLock<HashMap<EventId, EventId>>,
+}
+
+impl Tombstones {
+    /// Loads tombstones and indexes the ids they hide
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Ok(Self::with_log(AppendLog::open(path)?))
+    }
+
+    /// Tombstones under `database_path`, or none under hard deletion
+    pub fn for_config(config: &RelayConfig) -> Result<Self> {
+        match config.deletion_mode {
+            DeletionMode::Hard => Ok(Self::disabled()),
+            DeletionMode::Tombstone => Ok(Self::with_log(AppendLog::for_config(config, TOMBSTONES_FILE)?)),
+        }
+    }
+
+    fn with_log(log: AppendLog<Tombstone>) -> Self {
+        let tombstones = Self { enabled: true, log, ..Default::default() };
+        tombstones.reindex();
+        tombstones
+    }
+
+    /// Unpersisted tombstones, for tests
+    pub fn in_memory() -> Self {
+        Self { enabled: true, ..Default::default() }
+    }
+
+    /// Hard deletion: records and hides nothing
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Records `request`, received at `now` in `scope`, hiding `hidden`
+    pub fn record(&self, scope: &Scope, request: &Event, hidden: Vec<EventId>, now: u64) {
+        let tombstone = Tombstone {
+            id: request.id,
+            requested_by: request.pubkey,
+            scope: scope_label(scope),
+            received_at: now,
+            hidden,
+            state: TombstoneState::Hidden,
+            request: request.clone(),
+        };
+        {
+            let mut index = self.hidden.write();
+            for id in &tombstone.hidden {
+                index.insert(*id, tombstone.id);
+            }
+        }
+        self.log.insert(tombstone);
+    }
+
+    pub fn is_hidden(&self, id: &EventId) -> bool {
+        self.hidden.read().contains_key(id)
+    }
+
+    /// Id of the request hiding `id`, if any
+    pub fn hidden_by(&self, id: &EventId) -> Option<EventId> {
+        self.hidden.read().get(id).copied()
+    }
+
+    /// Every tombstone, most recent first
+    pub fn list(&self) -> Vec<Tombstone> {
+        let mut tombstones: Vec<Tombstone> = self.log.entries().values().cloned().collect();
+        tombstones.sort_by(|a, b| b.received_at.cmp(&a.received_at).then_with(|| a.id.cmp(&b.id)));
+        tombstones
+    }
+
+    /// Ids hidden in `scope`
+    pub fn hidden_in(&self, scope: &Scope) -> Vec<EventId> {
+        let label = scope_label(scope);
+        self.log
+            .entries()
+            .values()
+            .filter(|tombstone| tombstone.hides() && tombstone.scope == label)
+            .flat_map(|tombstone| tombstone.hidden.iter().copied())
+            .collect()
+    }
+
+    /// Hidden events in `scope` that `filter` matches, for counts taken
+    /// straight from the store
+    pub async fn hidden_matching(&self, store: &dyn ScopeStore, scope: &Scope, filter: &Filter) -> Result<usize> {
+        let ids = self.hidden_in(scope);
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let events = store.query(scope, Filter::new().ids(ids)).await?;
+        Ok(events.iter().filter(|event| filter.match_event(event)).count())
+    }
+
+    /// Brings a hidden tombstone's events back; `None` when there's no
+    /// such tombstone or it isn't hiding anything that's still stored
+    pub fn restore(&self, id: &EventId) -> Option<Tombstone> {
+        self.transition(id, TombstoneState::Restored)
+    }
+
+    /// Stores a hidden tombstone's request, deleting its events for good;
+    /// `None` when there's no such tombstone or it isn't hidden
+    pub async fn purge(&self, store: &dyn ScopeStore, id: &EventId) -> Result<Option<Tombstone>> {
+        let Some(tombstone) = self.log.get(id).filter(|t| t.state == TombstoneState::Hidden) else {
+            return Ok(None);
+        };
+        let scope = scope_from_label(&tombstone.scope).context("tombstone has an invalid scope")?;
+        store.save(&scope, tombstone.request.clone()).await?;
+        // In case the store doesn't apply deletion requests itself
+        for hidden in &tombstone.hidden {
+            store.delete(&scope, *hidden).await?;
+        }
+        Ok(self.transition(id, TombstoneState::Purged))
+    }
+
+    /// Moves a hidden tombstone to `state`, appending its new state
+    fn transition(&self, id: &EventId, state: TombstoneState) -> Option<Tombstone> {
+        let updated = self.log.update(*id, |old| {
+            old.filter(|tombstone| tombstone.state == TombstoneState::Hidden)
+                .map(|tombstone| Tombstone { state, ..tombstone.clone() })
+        })?;
+        self.reindex();
+        Some(updated)
+    }
+
+    /// Rebuilds the hidden index from the tombstones
+    fn reindex(&self) {
+        let index = self
+            .log
+            .entries()
+            .values()
+            .filter(|tombstone| tombstone.hides())
+            .flat_map(|tombstone| tombstone.hidden.iter().map(|hidden| (*hidden, tombstone.id)))
+            .collect();
+        *self.hidden.write() = index;
+    }
+}
+
+impl LogEntry for Tombstone {
+    type Key = EventId;
+
+    fn key(&self) -> EventId {
+        self.id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn note(keys: &Keys, content: &str) -> Event {
+        EventBuilder::text_note(content).sign_with_keys(keys).unwrap()
+    }
+
+    fn deletion(keys: &Keys, ids: &[EventId]) -> Event {
+        EventBuilder::delete(EventDeletionRequest::new().ids(ids.iter().copied()))
+            .sign_with_keys(keys)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_targets_are_the_authors_events_in_scope() {
+        let store = MemoryStore::new();
+        let drt2z = Scope::named("drt2z").unwrap();
+        let (alice, mallory) = (Keys::generate(), Keys::generate());
+        let (own, other, elsewhere) = (note(&alice, "mine"), note(&mallory, "theirs"), note(&alice, "root"));
+        store.insert(&drt2z, own.clone());
+        store.insert(&drt2z, other.clone());
+        store.insert(&Scope::Default, elsewhere.clone());
+
+        let request = deletion(&alice, &[own.id, other.id, elsewhere.id]);
+        assert_eq!(targets(&store, &drt2z, &request).await.unwrap(), vec![own.id]);
+    }
+
+    #[tokio::test]
+    async fn test_coordinates_hide_versions_up_to_the_request() {
+        let store = MemoryStore::new();
+        let keys = Keys::generate();
+        let article = EventBuilder::new(Kind::LongFormTextNote, "draft")
+            .tag(Tag::identifier("meetup"))
+            .custom_created_at(Timestamp::from(NOW - 10))
+            .sign_with_keys(&keys)
+            .unwrap();
+        store.insert(&Scope::Default, article.clone());
+        let coordinate = Coordinate::new(Kind::LongFormTextNote, keys.public_key()).identifier("meetup");
+        let request = EventBuilder::delete(EventDeletionRequest::new().coordinate(coordinate))
+            .custom_created_at(Timestamp::from(NOW))
+            .sign_with_keys(&keys)
+            .unwrap();
+        assert_eq!(targets(&store, &Scope::Default, &request).await.unwrap(), vec![article.id]);
+    }
+
+    #[tokio::test]
+    async fn test_restore_and_purge() {
+        let store = MemoryStore::new();
+        let keys = Keys::generate();
+        let (first, second) = (note(&keys, "first"), note(&keys, "second"));
+        store.insert(&Scope::Default, first.clone());
+        store.insert(&Scope::Default, second.clone());
+        let tombstones = Tombstones::in_memory();
+        let (delete_first, delete_second) = (deletion(&keys, &[first.id]), deletion(&keys, &[second.id]));
+        tombstones.record(&Scope::Default, &delete_first, vec![first.id], NOW);
+        tombstones.record(&Scope::Default, &delete_second, vec![second.id], NOW + 1);
+        assert!(tombstones.is_hidden(&first.id));
+        assert_eq!(tombstones.hidden_by(&second.id), Some(delete_second.id));
+        assert_eq!(tombstones.hidden_matching(&store, &Scope::Default, &Filter::new()).await.unwrap(), 2);
+
+        let restored = tombstones.restore(&delete_first.id).unwrap();
+        assert_eq!(restored.state, TombstoneState::Restored);
+        assert!(!tombstones.is_hidden(&first.id));
+        // Only hidden tombstones change state
+        assert!(tombstones.restore(&delete_first.id).is_none());
+        assert!(tombstones.purge(&store, &delete_first.id).await.unwrap().is_none());
+
+        let purged = tombstones.purge(&store, &delete_second.id).await.unwrap().unwrap();
+        assert_eq!(purged.state, TombstoneState::Purged);
+        assert!(tombstones.is_hidden(&second.id));
+        assert_eq!(store.count(&Scope::Default, Filter::new().kind(Kind::TextNote)).await.unwrap(), 1);
+        // The purged request is stored; the restored one never is
+        let stored = store.query(&Scope::Default, Filter::new().kind(Kind::EventDeletion)).await.unwrap();
+        assert_eq!(stored.iter().map(|e| e.id).collect::<Vec<_>>(), [delete_second.id]);
+        assert_eq!(tombstones.list().iter().map(|t| t.id).collect::<Vec<_>>(), [delete_second.id, delete_first.id]);
+    }
+
+    #[test]
+    fn test_tombstones_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(TOMBSTONES_FILE);
+        let keys = Keys::generate();
+        let (kept, restored) = (note(&keys, "kept"), note(&keys, "restored"));
+        let (delete_kept, delete_restored) = (deletion(&keys, &[kept.id]), deletion(&keys, &[restored.id]));
+
+        let tombstones = Tombstones::open(&path).unwrap();
+        tombstones.record(&Scope::Default, &delete_kept, vec![kept.id], NOW);
+        tombstones.record(&Scope::Default, &delete_restored, vec![restored.id], NOW);
+        tombstones.restore(&delete_restored.id).unwrap();
+        drop(tombstones);
+
+        let reopened = Tombstones::open(&path).unwrap();
+        assert!(reopened.is_hidden(&kept.id));
+        assert!(!reopened.is_hidden(&restored.id));
+        assert_eq!(reopened.list().len(), 2);
+    }
+
+    #[test]
+    fn test_disabled_under_hard_deletion() {
+        assert!(!Tombstones::for_config(&RelayConfig::default()).unwrap().is_enabled());
+    }
+}
@@ -0,0 +1,184 @@
+//! Most active geohash cells
+//!
+//! Ranks cells by accepted events in the last hour or day, using the
+//! aggregates the stats task already keeps, so listing them never touches
+//! storage. Cells with fewer than `trending_min_pubkeys` distinct authors
+//! in the window are left out: a cell with one poster would otherwise point
+//! at where that person is. Served as JSON by `GET /api/trending` and as
+//! HTML by `/trending` on the root domain.
+
+use serde::Serialize;
+use std::str::FromStr;
+use crate::config::RelayConfig;
+use crate::geohash_utils::is_valid_geohash;
+use crate::stats::{ScopeAggregates, StatsCache};
+
+/// Cells listed when no limit is requested
+pub const DEFAULT_TRENDING_LIMIT: usize = 20;
+/// Most cells one request may list
+pub const MAX_TRENDING_LIMIT: usize = 100;
+
+/// Activity window a ranking covers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TrendingWindow {
+    #[default]
+    Hour,
+    Day,
+}
+
+impl FromStr for TrendingWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "1h" => Ok(TrendingWindow::Hour),
+            "24h" | "1d" => Ok(TrendingWindow::Day),
+            other => anyhow::bail!("unknown window '{}' (expected 1h or 24h)", other),
+        }
+    }
+}
+
+impl TrendingWindow {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrendingWindow::Hour => "1h",
+            TrendingWindow::Day => "24h",
+        }
+    }
+
+    /// Accepted events and distinct authors of `aggregates` in this window
+    fn activity(&self, aggregates: &ScopeAggregates) -> (usize, usize) {
+        match self {
+            TrendingWindow::Hour => (aggregates.events_last_hour, aggregates.distinct_pubkeys_last_hour),
+            TrendingWindow::Day => (aggregates.events_last_24h, aggregates.distinct_pubkeys_24h),
+        }
+    }
+}
+
+/// One listed cell
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TrendingCell {
+    pub geohash: String,
+    pub relay_url: String,
+    pub events: usize,
+    pub distinct_pubkeys: usize,
+    /// Center of the cell
+    pub lat: f64,
+    pub lon: f64,
+}
+
+/// Body of `GET /api/trending`
+#[derive(Debug, Clone, Serialize)]
+pub struct TrendingReport {
+    pub window: &'static str,
+    pub min_pubkeys: usize,
+    pub cells: Vec<TrendingCell>,
+    /// Unix timestamp of the aggregates, null before the first run
+    pub computed_at: Option<u64>,
+}
+
+/// The `limit` busiest cells in `window`, most events first
+pub fn trending(stats: &StatsCache, config: &RelayConfig, window: TrendingWindow, limit: usize) -> TrendingReport {
+    let min_pubkeys = config.trending_min_pubkeys.max(1);
+    let mut cells: Vec<TrendingCell> = stats
+        .all()
+        .into_iter()
+        .filter(|(label, _)| is_valid_geohash(label))
+        .filter_map(|(geohash, aggregates)| {
+            let (events, distinct_pubkeys) = window.activity(&aggregates);
+            if events == 0 || distinct_pubkeys < min_pubkeys {
+                return None;
+            }
+            let (center, _, _) = geohash::decode(&geohash).ok()?;
+            Some(TrendingCell {
+                relay_url: config.relay_url_for(Some(&geohash)),
+                geohash,
+                events,
+                distinct_pubkeys,
+                lat: center.y,
+                lon: center.x,
+            })
+        })
+        .collect();
+    cells.sort_by(|a, b| {
+        b.events
+            .cmp(&a.events)
+            .then(b.distinct_pubkeys.cmp(&a.distinct_pubkeys))
+            .then(a.geohash.cmp(&b.geohash))
+    });
+    cells.truncate(limit.min(MAX_TRENDING_LIMIT));
+    TrendingReport {
+        window: window.as_str(),
+        min_pubkeys,
+        cells,
+        computed_at: stats.computed_at(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn aggregates(last_hour: (usize, usize), last_24h: (usize, usize)) -> ScopeAggregates {
+        ScopeAggregates {
+            stored_events: last_24h.0,
+            events_last_hour: last_hour.0,
+            events_last_24h: last_24h.0,
+            distinct_pubkeys_last_hour: last_hour.1,
+            distinct_pubkeys_24h: last_24h.1,
+            top_kinds: Vec::new(),
+            last_event_at: None,
+        }
+    }
+
+    fn seeded() -> StatsCache {
+        let cache = StatsCache::new();
+        cache.replace(
+            HashMap::from([
+                ("root".to_string(), aggregates((500, 50), (900, 80))),
+                ("drt2z".to_string(), aggregates((40, 5), (60, 9))),
+                ("9q8yy".to_string(), aggregates((90, 12), (95, 12))),
+                // One busy poster alone in a small cell
+                ("u33dc".to_string(), aggregates((300, 1), (300, 1))),
+                ("gcpvj".to_string(), aggregates((0, 0), (200, 20))),
+            ]),
+            1_700_000_000,
+        );
+        cache
+    }
+
+    #[test]
+    fn test_ranking_skips_root_and_quiet_cells() {
+        let report = trending(&seeded(), &RelayConfig::default(), TrendingWindow::Hour, DEFAULT_TRENDING_LIMIT);
+        let listed: Vec<_> = report.cells.iter().map(|c| c.geohash.as_str()).collect();
+        assert_eq!(listed, ["9q8yy", "drt2z"]);
+        assert_eq!(report.window, "1h");
+        assert_eq!(report.computed_at, Some(1_700_000_000));
+
+        let first = &report.cells[0];
+        assert_eq!((first.events, first.distinct_pubkeys), (90, 12));
+        assert_eq!(first.relay_url, RelayConfig::default().relay_url_for(Some("9q8yy")));
+        assert!((37.0..38.0).contains(&first.lat) && (-123.0..-122.0).contains(&first.lon));
+    }
+
+    #[test]
+    fn test_window_floor_and_limit() {
+        let report = trending(&seeded(), &RelayConfig::default(), TrendingWindow::Day, DEFAULT_TRENDING_LIMIT);
+        let listed: Vec<_> = report.cells.iter().map(|c| c.geohash.as_str()).collect();
+        assert_eq!(listed, ["gcpvj", "9q8yy", "drt2z"]);
+
+        let config = RelayConfig { trending_min_pubkeys: 10, ..RelayConfig::default() };
+        let report = trending(&seeded(), &config, TrendingWindow::Day, 1);
+        assert_eq!(report.cells.len(), 1);
+        assert_eq!(report.cells[0].geohash, "gcpvj");
+        assert_eq!(report.min_pubkeys, 10);
+    }
+
+    #[test]
+    fn test_window_parsing() {
+        assert_eq!("1h".parse::<TrendingWindow>().unwrap(), TrendingWindow::Hour);
+        assert_eq!("24H".parse::<TrendingWindow>().unwrap(), TrendingWindow::Day);
+        assert!("7d".parse::<TrendingWindow>().is_err());
+    }
+}
@@ -0,0 +1,137 @@
+//! Serving the relay on a Unix domain socket
+//!
+//! With `LISTEN=unix:/run/geohashed-relay.sock` the HTTP app is served on
+//! that socket instead of host/port, for a reverse proxy on the same
+//! machine. The socket file gets `unix_socket_mode` and, if set,
+//! `unix_socket_owner`; it is removed again on shutdown. A leftover file
+//! from a crashed relay is replaced, but one a running relay still answers
+//! on refuses the start.
+//!
+//! Unix peers have no IP address. Every connection is handed a loopback
+//! address with a port of its own, so `client_ip` reads X-Forwarded-For as
+//! it does for a proxy on localhost, and bookkeeping keyed by peer address
+//! still tells connections apart.
+
+use anyhow::{bail, Context, Result};
+use axum::extract::connect_info::{ConnectInfo, Connected};
+use axum::extract::Request;
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::serve::IncomingStream;
+use axum::Router;
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU16, Ordering};
+use tokio::net::UnixListener;
+use tracing::{info, warn};
+
+/// Ports handed to Unix connections, wrapping around
+static NEXT_PORT: AtomicU16 = AtomicU16::new(1);
+
+/// The loopback address a Unix connection is known by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnixPeer(pub SocketAddr);
+
+impl UnixPeer {
+    fn next() -> Self {
+        let port = match NEXT_PORT.fetch_add(1, Ordering::Relaxed) {
+            0 => NEXT_PORT.fetch_add(1, Ordering::Relaxed),
+            port => port,
+        };
+        Self(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+    }
+}
+
+impl Connected<IncomingStream<'_, UnixListener>> for UnixPeer {
+    fn connect_info(_stream: IncomingStream<'_, UnixListener>) -> Self {
+        Self::next()
+    }
+}
+
+/// Exposes a Unix connection's `UnixPeer` as the `ConnectInfo<SocketAddr>`
+/// the handlers extract
+async fn peer_as_socket_addr(mut request: Request, next: Next) -> Response {
+    if let Some(ConnectInfo(UnixPeer(addr))) = request.extensions().get::<ConnectInfo<UnixPeer>>().copied() {
+        request.extensions_mut().insert(ConnectInfo(addr));
+    }
+    next.run(request).await
+}
+
+/// A bound socket; its file is removed when dropped
+pub struct UnixSocket {
+    listener: UnixListener,
+    file: SocketFile,
+}
+
+impl UnixSocket {
+    pub fn path(&self) -> &Path {
+        &self.file.0
+    }
+}
+
+struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            warn!("Failed to remove socket {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// Binds `path` with `mode` and, if given, hands it to `owner` (uid, gid)
+///
+/// A stale socket file is replaced; one a process still accepts on is an
+/// error, as is any other kind of file.
+pub fn bind(path: impl AsRef<Path>, mode: u32, owner: Option<(u32, u32)>) -> Result<UnixSocket> {
+    let path = path.as_ref().to_path_buf();
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        use std::os::unix::fs::FileTypeExt;
+        if !metadata.file_type().is_socket() {
+            bail!("{} exists and is not a socket", path.display());
+        }
+        if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+            bail!("{} is in use by a running process", path.display());
+        }
+        info!("Removing stale socket {}", path.display());
+        std::fs::remove_file(&path).with_context(|| format!("failed to remove stale socket {}", path.display()))?;
+    }
+
+    let listener = UnixListener::bind(&path).with_context(|| format!("failed to bind {}", path.display()))?;
+    // From here on the file is cleaned up whatever fails
+    let socket = UnixSocket { listener, file: SocketFile(path) };
+    std::fs::set_permissions(socket.path(), std::fs::Permissions::from_mode(mode))
+        .with_context(|| format!("failed to set permissions on {}", socket.path().display()))?;
+    if let Some((uid, gid)) = owner {
+        std::os::unix::fs::chown(socket.path(), Some(uid), Some(gid))
+            .with_context(|| format!("failed to hand {} to {}:{}", socket.path().display(), uid, gid))?;
+    }
+    Ok(socket)
+}
+
+/// Serves `app` on `socket` until `shutdown` completes, then removes the
+/// socket file
+pub async fn serve(socket: UnixSocket, app: Router, shutdown: impl Future<Output = ()> + Send + 'static) -> Result<()> {
+    let UnixSocket { listener, file } = socket;
+    let app = app
+        .layer(middleware::from_fn(peer_as_socket_addr))
+        .into_make_service_with_connect_info::<UnixPeer>();
+    axum::serve(listener, app).with_graceful_shutdown(shutdown).await?;
+    drop(file);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unix_peers_are_distinct_loopback_addresses() {
+        let first = UnixPeer::next();
+        let second = UnixPeer::next();
+        assert!(first.0.ip().is_loopback());
+        assert_ne!(first, second);
+    }
+}
@@ -0,0 +1,415 @@
+//! Daily per-scope usage reports
+//!
+//! For operators who don't run Prometheus. `UsageTally` counts what the
+//! processor accepts (events and bytes) and rejects (by reason code) per
+//! scope. Once a day, at `usage_report_at` UTC, the tally is combined with
+//! the stats aggregates into a `UsageReport` covering the 24 hours that just
+//! ended. The report is POSTed as JSON to `usage_report_webhook` (signed
+//! like event webhooks when `usage_report_secret` is set), published into
+//! root as a relay-signed kind 30078 event with a `d` tag of
+//! `usage-report:<date>`, or both.
+//!
+//! The first boundary after startup only starts the clock, so a restart
+//! never sends a partial day twice.
+
+use anyhow::Result;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use relay_builder::StoreCommand;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+use crate::config::RelayConfig;
+use crate::processor::GeohashedEventProcessor;
+use crate::scope_policy::{DefaultScopePolicy, ScopePolicy};
+use crate::stats::StatsCache;
+use crate::store::{scope_label, ScopeStore};
+use crate::syndication::rfc3339;
+use crate::webhooks::{signature, SIGNATURE_HEADER};
+
+/// Application-specific data (NIP-78), addressable by date
+pub const USAGE_REPORT_KIND: u16 = 30078;
+/// Prefix of the report event's `d` tag
+pub const USAGE_REPORT_D_PREFIX: &str = "usage-report:";
+
+const DAY: u64 = 24 * 60 * 60;
+/// How often the schedule is checked
+const TICK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Whether `config` sends usage reports anywhere
+pub fn reports_enabled(config: &RelayConfig) -> bool {
+    config.usage_report_webhook.is_some() || config.usage_report_publish
+}
+
+/// Source of the current time, swapped for a fake in tests
+pub trait Clock: Send + Sync + 'static {
+    /// Seconds since the Unix epoch
+    fn now(&self) -> u64;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+    }
+}
+
+/// Counts for one scope since the last report
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DayUsage {
+    pub accepted_events: u64,
+    /// Serialized size of the accepted events
+    pub accepted_bytes: u64,
+    /// Rejections by `RejectReason` code
+    pub rejected: BTreeMap<String, u64>,
+}
+
+/// Accepted and rejected events per scope label, reset by every report
+#[derive(Debug, Default)]
+pub struct UsageTally {
+    enabled: bool,
+    scopes: Mutex<HashMap<String, DayUsage>>,
+}
+
+impl UsageTally {
+    pub fn new() -> Self {
+        Self {
+            enabled: true,
+            scopes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts nothing, for relays without reports
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    pub fn for_config(config: &RelayConfig) -> Self {
+        if reports_enabled(config) {
+            Self::new()
+        } else {
+            Self::disabled()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn record_accepted(&self, scope: &Scope, bytes: u64) {
+        if !self.enabled {
+            return;
+        }
+        let mut scopes = self.scopes.lock();
+        let usage = scopes.entry(scope_label(scope)).or_default();
+        usage.accepted_events += 1;
+        usage.accepted_bytes += bytes;
+    }
+
+    pub fn record_rejected(&self, scope: &Scope, code: &str) {
+        if !self.enabled {
+            return;
+        }
+        let mut scopes = self.scopes.lock();
+        *scopes.entry(scope_label(scope)).or_default().rejected.entry(code.to_string()).or_default() += 1;
+    }
+
+    /// Everything counted so far, starting over
+    pub fn take(&self) -> HashMap<String, DayUsage> {
+        std::mem::take(&mut *self.scopes.lock())
+    }
+}
+
+/// One scope's line in the report
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ScopeUsageReport {
+    #[serde(flatten)]
+    pub usage: DayUsage,
+    /// Events in the scope when the report was made
+    pub stored_events: usize,
+    /// From the stats aggregates
+    pub distinct_pubkeys_24h: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UsageReport {
+    /// UTC date the reported day started on
+    pub date: String,
+    pub period_start: u64,
+    pub period_end: u64,
+    pub scopes: BTreeMap<String, ScopeUsageReport>,
+}
+
+/// Combines a day's tally with the latest stats; scopes with neither
+/// accepted nor rejected events that day are left out
+pub fn build_report(period_end: u64, usage: HashMap<String, DayUsage>, stats: &StatsCache) -> UsageReport {
+    let aggregates = stats.all();
+    let scopes = usage
+        .into_iter()
+        .map(|(scope, usage)| {
+            let aggregates = aggregates.get(&scope);
+            let report = ScopeUsageReport {
+                usage,
+                stored_events: aggregates.map(|a| a.stored_events).unwrap_or_default(),
+                distinct_pubkeys_24h: aggregates.map(|a| a.distinct_pubkeys_24h).unwrap_or_default(),
+            };
+            (scope, report)
+        })
+        .collect();
+    let period_start = period_end.saturating_sub(DAY);
+    UsageReport {
+        date: rfc3339(period_start)[..10].to_string(),
+        period_start,
+        period_end,
+        scopes,
+    }
+}
+
+/// Daily boundaries at a fixed offset from UTC midnight
+#[derive(Debug, Clone)]
+pub struct ReportSchedule {
+    offset: u64,
+    last_boundary: Option<u64>,
+}
+
+impl ReportSchedule {
+    /// Reports `offset_secs` after every UTC midnight
+    pub fn new(offset_secs: u64) -> Self {
+        Self {
+            offset: offset_secs % DAY,
+            last_boundary: None,
+        }
+    }
+
+    /// The boundary crossed since the last poll, if any; the first poll
+    /// only notes where the clock is
+    pub fn poll(&mut self, now: u64) -> Option<u64> {
+        let boundary = now - (now + DAY - self.offset) % DAY;
+        match self.last_boundary.replace(boundary) {
+            Some(last) if boundary > last => Some(boundary),
+            _ => None,
+        }
+    }
+}
+
+/// Makes and delivers the daily report
+pub struct UsageReporter<P = DefaultScopePolicy> {
+    tally: Arc<UsageTally>,
+    stats: Arc<StatsCache>,
+    clock: Arc<dyn Clock>,
+    schedule: Mutex<ReportSchedule>,
+    webhook: Option<(String, Option<String>)>,
+    client: reqwest::Client,
+    /// Publishes the report into root when set
+    publisher: Option<(GeohashedEventProcessor<P>, Keys, Arc<dyn ScopeStore>)>,
+}
+
+impl<P: ScopePolicy> UsageReporter<P> {
+    pub fn new(tally: Arc<UsageTally>, stats: Arc<StatsCache>, offset_secs: u64, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            tally,
+            stats,
+            clock,
+            schedule: Mutex::new(ReportSchedule::new(offset_secs)),
+            webhook: None,
+            client: reqwest::Client::builder()
+                .user_agent(concat!("geohashed-relay/", env!("CARGO_PKG_VERSION")))
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("failed to build HTTP client"),
+            publisher: None,
+        }
+    }
+
+    /// The reporter `config` asks for, or `None` when reports are off
+    pub fn for_config(config: &RelayConfig, tally: Arc<UsageTally>, stats: Arc<StatsCache>) -> Option<Self> {
+        if !reports_enabled(config) {
+            return None;
+        }
+        let mut reporter = Self::new(tally, stats, config.usage_report_at, Arc::new(SystemClock));
+        if let Some(url) = &config.usage_report_webhook {
+            reporter = reporter.with_webhook(url.clone(), config.usage_report_secret.clone());
+        }
+        Some(reporter)
+    }
+
+    /// POSTs every report to `url`, signed with `secret` if given
+    pub fn with_webhook(mut self, url: String, secret: Option<String>) -> Self {
+        self.webhook = Some((url, secret));
+        self
+    }
+
+    /// Publishes every report into root, signed with `keys`
+    pub fn with_publisher(mut self, processor: GeohashedEventProcessor<P>, keys: Keys, store: Arc<dyn ScopeStore>) -> Self {
+        self.publisher = Some((processor, keys, store));
+        self
+    }
+
+    /// Reports if a day boundary has passed since the last tick
+    pub async fn tick(&self) -> Option<UsageReport> {
+        let boundary = self.schedule.lock().poll(self.clock.now())?;
+        let report = build_report(boundary, self.tally.take(), &self.stats);
+        info!("Usage report for {}: {} active scopes", report.date, report.scopes.len());
+        if let Some((url, secret)) = &self.webhook {
+            if let Err(e) = self.post(url, secret.as_deref(), &report).await {
+                warn!("Failed to POST usage report to {}: {:#}", url, e);
+            }
+        }
+        if let Some((processor, keys, store)) = &self.publisher {
+            if let Err(e) = publish(processor, keys, store.as_ref(), &report).await {
+                warn!("Failed to publish usage report: {:#}", e);
+            }
+        }
+        Some(report)
+    }
+
+    async fn post(&self, url: &str, secret: Option<&str>, report: &UsageReport) -> Result<()> {
+        let body = serde_json::to_string(report)?;
+        let mut request = self.client.post(url).header(reqwest::header::CONTENT_TYPE, "application/json");
+        if let Some(secret) = secret {
+            request = request.header(SIGNATURE_HEADER, format!("sha256={}", signature(secret, body.as_bytes())));
+        }
+        request.body(body).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Signs `report` and stores it in root
+async fn publish<P: ScopePolicy>(
+    processor: &GeohashedEventProcessor<P>,
+    keys: &Keys,
+    store: &dyn ScopeStore,
+    report: &UsageReport,
+) -> Result<()> {
+    let event = EventBuilder::new(Kind::from(USAGE_REPORT_KIND), serde_json::to_string(report)?)
+        .tag(Tag::identifier(format!("{}{}", USAGE_REPORT_D_PREFIX, report.date)))
+        .sign(keys)
+        .await?;
+    let commands = processor
+        .publish_internal(event, keys.public_key(), Scope::Default)
+        .await
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+    for command in commands {
+        if let StoreCommand::SaveSignedEvent(event, target, _) = command {
+            store.save(&target, *event).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks the schedule every minute until the relay shuts down
+pub fn spawn_usage_reports<P: ScopePolicy>(reporter: Arc<UsageReporter<P>>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(TICK_INTERVAL);
+        loop {
+            ticker.tick().await;
+            reporter.tick().await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::ScopeAggregates;
+    use crate::store::MemoryStore;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// 2024-05-01T00:00:00Z
+    const MAY_1: u64 = 1_714_521_600;
+
+    #[derive(Default)]
+    struct FakeClock(AtomicU64);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> u64 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    fn aggregates(stored_events: usize, distinct_pubkeys_24h: usize) -> ScopeAggregates {
+        ScopeAggregates {
+            stored_events,
+            events_last_hour: 0,
+            events_last_24h: 0,
+            distinct_pubkeys_last_hour: 0,
+            distinct_pubkeys_24h,
+            top_kinds: Vec::new(),
+            last_event_at: None,
+        }
+    }
+
+    #[test]
+    fn test_schedule_fires_once_per_boundary() {
+        // 06:00 UTC
+        let mut schedule = ReportSchedule::new(6 * 60 * 60);
+        assert_eq!(schedule.poll(MAY_1 + 3_600), None);
+        assert_eq!(schedule.poll(MAY_1 + 5 * 3_600), None);
+        assert_eq!(schedule.poll(MAY_1 + 6 * 3_600), Some(MAY_1 + 6 * 3_600));
+        assert_eq!(schedule.poll(MAY_1 + 7 * 3_600), None);
+        assert_eq!(schedule.poll(MAY_1 + DAY + 6 * 3_600 + 30), Some(MAY_1 + DAY + 6 * 3_600));
+
+        // Starting right after a boundary doesn't report the partial day
+        let mut schedule = ReportSchedule::new(0);
+        assert_eq!(schedule.poll(MAY_1 + 10), None);
+        assert_eq!(schedule.poll(MAY_1 + DAY - 1), None);
+        assert_eq!(schedule.poll(MAY_1 + DAY), Some(MAY_1 + DAY));
+    }
+
+    #[tokio::test]
+    async fn test_day_boundary_produces_one_report() {
+        let tally = Arc::new(UsageTally::new());
+        let stats = Arc::new(StatsCache::new());
+        stats.replace(HashMap::from([("drt2z".to_string(), aggregates(40, 7))]), MAY_1);
+        let clock = Arc::new(FakeClock(AtomicU64::new(MAY_1 + 60)));
+        let store: Arc<dyn ScopeStore> = Arc::new(MemoryStore::new());
+        let keys = Keys::generate();
+        let reporter = UsageReporter::new(tally.clone(), stats, 0, clock.clone()).with_publisher(
+            GeohashedEventProcessor::new(),
+            keys.clone(),
+            store.clone(),
+        );
+        assert!(reporter.tick().await.is_none());
+
+        let drt2z = Scope::named("drt2z").unwrap();
+        tally.record_accepted(&drt2z, 300);
+        tally.record_accepted(&drt2z, 200);
+        tally.record_rejected(&drt2z, "rate-limited");
+        tally.record_rejected(&drt2z, "rate-limited");
+        tally.record_rejected(&Scope::Default, "root-rejects-geotagged");
+
+        clock.0.store(MAY_1 + DAY - 1, Ordering::Relaxed);
+        assert!(reporter.tick().await.is_none());
+        clock.0.store(MAY_1 + DAY + 30, Ordering::Relaxed);
+        let report = reporter.tick().await.unwrap();
+        assert!(reporter.tick().await.is_none());
+
+        assert_eq!(report.date, "2024-05-01");
+        assert_eq!(report.scopes.len(), 2);
+        let cell = &report.scopes["drt2z"];
+        assert_eq!(cell.usage.accepted_events, 2);
+        assert_eq!(cell.usage.accepted_bytes, 500);
+        assert_eq!(cell.usage.rejected["rate-limited"], 2);
+        assert_eq!(cell.stored_events, 40);
+        assert_eq!(cell.distinct_pubkeys_24h, 7);
+        assert_eq!(report.scopes["root"].usage.rejected["root-rejects-geotagged"], 1);
+
+        let published = store
+            .query(&Scope::Default, Filter::new().kind(Kind::from(USAGE_REPORT_KIND)))
+            .await
+            .unwrap();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].pubkey, keys.public_key());
+        assert_eq!(published[0].tags.identifier(), Some("usage-report:2024-05-01"));
+        let body: serde_json::Value = serde_json::from_str(&published[0].content).unwrap();
+        assert_eq!(body["scopes"]["drt2z"]["rejected"]["rate-limited"], 2);
+
+        // The tally starts over for the next day
+        assert!(tally.take().is_empty());
+    }
+}
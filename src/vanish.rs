@@ -0,0 +1,361 @@
+//! NIP-62 requests to vanish
+//!
+//! A kind 62 event whose `relay` tag names this relay (its own URL, any
+//! cell under the base domain, or `ALL_RELAYS`) asks for everything its
+//! author stored here up to the request's `created_at` to go, along with
+//! the gift wraps addressed to them. A kind 5 deletion only reaches the
+//! scope it's posted to; a request to vanish covers every scope, so the
+//! vanish task enumerates them through `ScopeStore::scopes` and deletes the
+//! author's events in each. The request itself is never stored.
+//!
+//! Requests are kept in an `AppendLog` under `database_path`, also after
+//! their sweep: events dated before a request are refused for good,
+//! and `vanish_block_secs` refuses newer ones for a while too. A request
+//! whose sweep didn't finish is picked up again at startup.
+//!
+//! Each request recorded is also announced to `subscribe`rs, which is how a
+//! replication leader passes it on: followers record it in their own list,
+//! their vanish task clears their copy, and they skip streamed events the
+//! request covers.
+
+use anyhow::Result;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::{broadcast, Notify};
+use tracing::{info, warn};
+use crate::append_log::{AppendLog, LogEntry};
+use crate::config::RelayConfig;
+use crate::store::ScopeStore;
+
+/// Requests to vanish, next to the database
+pub const VANISH_FILE: &str = "vanished.jsonl";
+
+/// Kind of a request to vanish
+pub const REQUEST_TO_VANISH_KIND: u16 = 62;
+
+/// `relay` tag value addressing every relay
+pub const ALL_RELAYS: &str = "ALL_RELAYS";
+
+/// Announced requests buffered per subscriber
+const ANNOUNCE_BUFFER: usize = 64;
+
+fn url_host(url: &str) -> Option<String> {
+    let url = url::Url::parse(url).ok()?;
+    Some(url.host_str()?.to_lowercase())
+}
+
+/// Whether a request to vanish is addressed to this relay
+pub fn names_this_relay(event: &Event, config: &RelayConfig) -> bool {
+    let own = url_host(&config.relay_url);
+    let base = config.base_domain();
+    event
+        .tags
+        .iter()
+        .filter_map(|tag| match tag.as_slice() {
+            [name, value, ..] if name == "relay" => Some(value.as_str()),
+            _ => None,
+        })
+        .any(|value| {
+            if value == ALL_RELAYS {
+                return true;
+            }
+            let Some(host) = url_host(value) else {
+                return false;
+            };
+            own.as_deref() == Some(host.as_str())
+                || base.as_deref().is_some_and(|base| host == base || host.ends_with(&format!(".{}", base)))
+        })
+}
+
+/// One line of the log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    pubkey: PublicKey,
+    /// `created_at` of the latest request; older events are deleted and refused
+    until: u64,
+    /// When the relay received it, for `vanish_block_secs`
+    received_at: u64,
+    /// Whether every scope has been cleared up to `until`
+    swept: bool,
+}
+
+impl LogEntry for Entry {
+    type Key = PublicKey;
+
+    fn key(&self) -> PublicKey {
+        self.pubkey
+    }
+}
+
+/// Authors who asked to vanish, optionally backed by a file
+#[derive(Debug)]
+pub struct VanishList {
+    log: AppendLog<Entry>,
+    block_secs: u64,
+    /// Wakes the vanish task when a request arrives
+    wake: Notify,
+    /// `(pubkey, until)` of each request as it is recorded
+    announced: broadcast::Sender<(PublicKey, u64)>,
+}
+
+impl Default for VanishList {
+    fn default() -> Self {
+        Self {
+            log: AppendLog::default(),
+            block_secs: 0,
+            wake: Notify::new(),
+            announced: broadcast::channel(ANNOUNCE_BUFFER).0,
+        }
+    }
+}
+
+impl VanishList {
+    pub fn open(path: impl Into<PathBuf>, block_secs: u64) -> Result<Self> {
+        Ok(Self { log: AppendLog::open(path)?, block_secs, ..Default::default() })
+    }
+
+    /// Requests under `database_path`, blocking for `vanish_block_secs`
+    pub fn for_config(config: &RelayConfig) -> Result<Self> {
+        Ok(Self {
+            log: AppendLog::for_config(config, VANISH_FILE)?,
+            block_secs: config.vanish_block_secs,
+            ..Default::default()
+        })
+    }
+
+    /// A list that is never persisted, for tests and tooling
+    pub fn in_memory(block_secs: u64) -> Self {
+        Self { block_secs, ..Default::default() }
+    }
+
+    /// Notes that `pubkey` asked at `until` to vanish, wakes the vanish task
+    /// and announces the request
+    pub fn record(&self, pubkey: PublicKey, until: u64, now: u64) {
+        let recorded = self.log.update(pubkey, |old| {
+            let until = old.map_or(until, |entry| entry.until.max(until));
+            Some(Entry { pubkey, until, received_at: now, swept: false })
+        });
+        self.wake.notify_one();
+        if let Some(entry) = recorded {
+            // Nobody listening is fine
+            let _ = self.announced.send((pubkey, entry.until));
+        }
+    }
+
+    /// Requests as they are recorded from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<(PublicKey, u64)> {
+        self.announced.subscribe()
+    }
+
+    /// Every recorded request, with its `until`
+    pub fn requests(&self) -> Vec<(PublicKey, u64)> {
+        self.log.entries().values().map(|entry| (entry.pubkey, entry.until)).collect()
+    }
+
+    /// Whether a recorded request by `pubkey` reaches back to `created_at`
+    pub fn covers(&self, pubkey: &PublicKey, created_at: u64) -> bool {
+        self.log.entries().get(pubkey).is_some_and(|entry| created_at <= entry.until)
+    }
+
+    /// Whether `event` is one the vanish task deletes: by an author who
+    /// vanished after it, or a gift wrap addressed to one
+    pub fn erases(&self, event: &Event) -> bool {
+        let created_at = event.created_at.as_u64();
+        self.covers(&event.pubkey, created_at)
+            || (event.kind == Kind::GiftWrap && event.tags.public_keys().any(|pubkey| self.covers(pubkey, created_at)))
+    }
+
+    /// Whether an event by `pubkey` dated `created_at` is refused at `now`
+    pub fn refuses(&self, pubkey: &PublicKey, created_at: u64, now: u64) -> bool {
+        self.covers(pubkey, created_at)
+            || self.log.entries().get(pubkey).is_some_and(|entry| now < entry.received_at.saturating_add(self.block_secs))
+    }
+
+    /// Requests whose events may still be stored, with their `until`
+    pub fn pending(&self) -> Vec<(PublicKey, u64)> {
+        self.log
+            .entries()
+            .values()
+            .filter(|entry| !entry.swept)
+            .map(|entry| (entry.pubkey, entry.until))
+            .collect()
+    }
+
+    /// Marks `pubkey` swept up to `until`, unless a later request came in
+    /// meanwhile
+    fn mark_swept(&self, pubkey: &PublicKey, until: u64) {
+        self.log.update(*pubkey, |old| {
+            old.filter(|entry| entry.until == until).map(|entry| Entry { swept: true, ..entry.clone() })
+        });
+    }
+}
+
+/// Deletes `pubkey`'s events, and gift wraps addressed to them, dated up to
+/// `until` from every scope, returning how many went
+pub async fn vanish(store: &dyn ScopeStore, pubkey: PublicKey, until: u64) -> Result<usize> {
+    let until = Timestamp::from(until);
+    let filters = [
+        Filter::new().author(pubkey).until(until),
+        Filter::new().kind(Kind::GiftWrap).pubkey(pubkey).until(until),
+    ];
+    let mut deleted = 0;
+    for scope in store.scopes().await? {
+        for filter in &filters {
+            deleted += delete_matching(store, &scope, filter.clone()).await?;
+        }
+    }
+    Ok(deleted)
+}
+
+async fn delete_matching(store: &dyn ScopeStore, scope: &Scope, filter: Filter) -> Result<usize> {
+    let events = store.query(scope, filter).await?;
+    for event in &events {
+        store.delete(scope, event.id).await?;
+    }
+    Ok(events.len())
+}
+
+/// Clears every pending request, returning how many events went
+pub async fn sweep(store: &dyn ScopeStore, list: &VanishList) -> usize {
+    let mut total = 0;
+    for (pubkey, until) in list.pending() {
+        match vanish(store, pubkey, until).await {
+            Ok(deleted) => {
+                info!("Deleted {} events of {}, who asked to vanish", deleted, pubkey);
+                list.mark_swept(&pubkey, until);
+                total += deleted;
+            }
+            Err(e) => warn!("Failed to delete events of {}, who asked to vanish: {}", pubkey, e),
+        }
+    }
+    total
+}
+
+/// Sweeps at startup and whenever a request arrives
+pub fn spawn_vanish_task(store: Arc<dyn ScopeStore>, list: Arc<VanishList>) {
+    tokio::spawn(async move {
+        loop {
+            let deleted = sweep(store.as_ref(), &list).await;
+            metrics::counter!("relay_vanished_events_total").increment(deleted as u64);
+            list.wake.notified().await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn note(keys: &Keys, created_at: u64) -> Event {
+        EventBuilder::text_note("hello")
+            .custom_created_at(Timestamp::from(created_at))
+            .sign_with_keys(keys)
+            .unwrap()
+    }
+
+    fn request(keys: &Keys, relay: &str) -> Event {
+        EventBuilder::new(Kind::from(REQUEST_TO_VANISH_KIND), "")
+            .tag(Tag::parse(["relay", relay]).unwrap())
+            .sign_with_keys(keys)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_requests_name_this_relay() {
+        let config = RelayConfig { relay_url: "wss://hashstr.com".to_string(), ..Default::default() };
+        let keys = Keys::generate();
+        assert!(names_this_relay(&request(&keys, "wss://hashstr.com"), &config));
+        assert!(names_this_relay(&request(&keys, "wss://drt2z.hashstr.com/"), &config));
+        assert!(names_this_relay(&request(&keys, ALL_RELAYS), &config));
+        assert!(!names_this_relay(&request(&keys, "wss://relay.example.com"), &config));
+        assert!(!names_this_relay(&request(&keys, "wss://nothashstr.com"), &config));
+    }
+
+    #[tokio::test]
+    async fn test_vanish_clears_every_scope_of_one_author() {
+        let store = MemoryStore::new();
+        let (leaving, staying) = (Keys::generate(), Keys::generate());
+        let scopes = [Scope::Default, Scope::named("drt2z").unwrap(), Scope::named("9q8yy").unwrap()];
+        for scope in &scopes {
+            store.insert(scope, note(&leaving, NOW - 60));
+            store.insert(scope, note(&staying, NOW - 60));
+        }
+
+        let list = VanishList::in_memory(0);
+        list.record(leaving.public_key(), NOW, NOW);
+        assert_eq!(sweep(&store, &list).await, 3);
+        assert!(list.pending().is_empty());
+
+        for scope in &scopes {
+            let remaining = store.query(scope, Filter::new()).await.unwrap();
+            assert_eq!(remaining.len(), 1);
+            assert_eq!(remaining[0].pubkey, staying.public_key());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_events_after_the_request_survive() {
+        let store = MemoryStore::new();
+        let keys = Keys::generate();
+        let drt2z = Scope::named("drt2z").unwrap();
+        store.insert(&drt2z, note(&keys, NOW - 60));
+        store.insert(&drt2z, note(&keys, NOW + 60));
+
+        assert_eq!(vanish(&store, keys.public_key(), NOW).await.unwrap(), 1);
+        assert_eq!(store.count(&drt2z, Filter::new()).await.unwrap(), 1);
+    }
+
+    #[test]
+    fn test_refuses_older_events_and_blocks_for_a_while() {
+        let keys = Keys::generate();
+        let list = VanishList::in_memory(3_600);
+        list.record(keys.public_key(), NOW, NOW);
+
+        assert!(list.refuses(&keys.public_key(), NOW - 1, NOW + 7_200));
+        assert!(list.refuses(&keys.public_key(), NOW + 10, NOW + 10));
+        assert!(!list.refuses(&keys.public_key(), NOW + 7_200, NOW + 7_200));
+        assert!(!list.refuses(&Keys::generate().public_key(), NOW - 1, NOW));
+    }
+
+    #[test]
+    fn test_recorded_requests_are_announced_and_erase_gift_wraps() {
+        let (leaving, sender) = (Keys::generate(), Keys::generate());
+        let list = VanishList::in_memory(0);
+        let mut announced = list.subscribe();
+        list.record(leaving.public_key(), NOW, NOW);
+        assert_eq!(announced.try_recv().unwrap(), (leaving.public_key(), NOW));
+        assert_eq!(list.requests(), vec![(leaving.public_key(), NOW)]);
+
+        assert!(list.erases(&note(&leaving, NOW - 60)));
+        assert!(!list.erases(&note(&leaving, NOW + 60)));
+        assert!(!list.erases(&note(&sender, NOW - 60)));
+        let wrap = EventBuilder::new(Kind::GiftWrap, "sealed")
+            .tag(Tag::public_key(leaving.public_key()))
+            .custom_created_at(Timestamp::from(NOW - 60))
+            .sign_with_keys(&sender)
+            .unwrap();
+        assert!(list.erases(&wrap));
+    }
+
+    #[tokio::test]
+    async fn test_unswept_requests_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(VANISH_FILE);
+        let (swept, unswept) = (Keys::generate(), Keys::generate());
+        let list = VanishList::open(&path, 0).unwrap();
+        list.record(swept.public_key(), NOW, NOW);
+        assert_eq!(sweep(&MemoryStore::new(), &list).await, 0);
+        list.record(unswept.public_key(), NOW, NOW);
+        drop(list);
+
+        let reopened = VanishList::open(&path, 0).unwrap();
+        assert_eq!(reopened.pending(), vec![(unswept.public_key(), NOW)]);
+        assert!(reopened.refuses(&swept.public_key(), NOW - 1, NOW));
+    }
+}
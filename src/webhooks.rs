@@ -0,0 +1,369 @@
+//! Webhook notifications for newly stored events
+//!
+//! Each configured webhook names the scopes and kinds it cares about. Every
+//! event published on `LiveEvents` is handed to `WebhookDispatcher`, which
+//! POSTs the event JSON to every matching receiver with an
+//! `X-Webhook-Signature: sha256=<hex>` HMAC of the body.
+//!
+//! Deliveries go through a bounded queue and are retried with exponential
+//! backoff. Events that can't be queued or exhaust their attempts are
+//! dropped and counted in `relay_webhook_dead_letters_total`. Each webhook
+//! also has its own rate limit, so a flood in one cell can't overwhelm the
+//! receiver.
+
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use nostr::hashes::{hmac::{Hmac, HmacEngine}, sha256, Hash, HashEngine};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tracing::{debug, warn};
+use crate::config::{RelayConfig, WebhookConfig};
+use crate::live::LiveEvents;
+use crate::store::scope_label;
+
+/// Header carrying `sha256=<hex HMAC of the body>`
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+/// Header carrying the scope label the event was stored in
+pub const SCOPE_HEADER: &str = "X-Webhook-Scope";
+
+/// Deliveries waiting for a worker across all webhooks
+const QUEUE_CAPACITY: usize = 1_000;
+/// Deliveries in flight at once
+const MAX_CONCURRENT_DELIVERIES: usize = 16;
+
+/// Hex HMAC-SHA256 of `body` keyed with `secret`
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mut engine = HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+    engine.input(body);
+    Hmac::<sha256::Hash>::from_engine(engine).to_string()
+}
+
+/// Whether `scope` matches one of `patterns` (labels, with a trailing `*`
+/// matching by prefix); empty patterns match every scope
+pub fn scope_matches(patterns: &[String], scope: &str) -> bool {
+    patterns.is_empty()
+        || patterns.iter().any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => scope.starts_with(prefix),
+            None => pattern == scope,
+        })
+}
+
+impl WebhookConfig {
+    /// Whether an event of `kind` stored in `scope` should be delivered
+    pub fn matches(&self, scope: &str, kind: u16) -> bool {
+        scope_matches(&self.scopes, scope) && (self.kinds.is_empty() || self.kinds.contains(&kind))
+    }
+}
+
+/// How failed deliveries are retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn for_config(config: &RelayConfig) -> Self {
+        Self {
+            max_attempts: config.webhook_max_attempts.max(1),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+
+    /// Delay before retrying after `attempt` (1-based) failed
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+}
+
+struct Webhook {
+    config: WebhookConfig,
+    /// `None` when the webhook has no rate limit
+    limiter: Option<DefaultDirectRateLimiter>,
+}
+
+struct Delivery {
+    webhook: Arc<Webhook>,
+    scope: String,
+    body: Arc<String>,
+}
+
+/// Queues and delivers webhook notifications
+pub struct WebhookDispatcher {
+    webhooks: Vec<Arc<Webhook>>,
+    /// `None` when no webhooks are configured
+    sender: Option<mpsc::Sender<Delivery>>,
+}
+
+impl WebhookDispatcher {
+    /// Starts the delivery worker; must be called inside a tokio runtime
+    pub fn new(webhooks: Vec<WebhookConfig>, retry: RetryPolicy) -> Self {
+        let webhooks: Vec<Arc<Webhook>> = webhooks
+            .into_iter()
+            .map(|config| {
+                let limiter = NonZeroU32::new(config.events_per_minute)
+                    .map(|limit| RateLimiter::direct(Quota::per_minute(limit)));
+                Arc::new(Webhook { config, limiter })
+            })
+            .collect();
+        if webhooks.is_empty() {
+            return Self { webhooks, sender: None };
+        }
+
+        let client = reqwest::Client::builder()
+            .user_agent(concat!("geohashed-relay/", env!("CARGO_PKG_VERSION")))
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("failed to build HTTP client");
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(run_worker(client, receiver, retry));
+        Self { webhooks, sender: Some(sender) }
+    }
+
+    pub fn for_config(config: &RelayConfig) -> Self {
+        Self::new(config.webhooks.clone(), RetryPolicy::for_config(config))
+    }
+
+    /// Queues `event` for every matching webhook without waiting
+    pub fn dispatch(&self, event: &Event, scope: &Scope) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let scope = scope_label(scope);
+        let body = Arc::new(event.as_json());
+        for webhook in &self.webhooks {
+            if !webhook.config.matches(&scope, event.kind.as_u16()) {
+                continue;
+            }
+            if webhook.limiter.as_ref().is_some_and(|limiter| limiter.check().is_err()) {
+                debug!("Webhook {} over its rate limit, skipping {}", webhook.config.url, event.id);
+                metrics::counter!("relay_webhook_rate_limited_total").increment(1);
+                continue;
+            }
+            let delivery = Delivery {
+                webhook: webhook.clone(),
+                scope: scope.clone(),
+                body: body.clone(),
+            };
+            if sender.try_send(delivery).is_err() {
+                warn!("Webhook queue full, dropping {} for {}", event.id, webhook.config.url);
+                metrics::counter!("relay_webhook_dead_letters_total").increment(1);
+            }
+        }
+    }
+}
+
+async fn run_worker(client: reqwest::Client, mut receiver: mpsc::Receiver<Delivery>, retry: RetryPolicy) {
+    let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_DELIVERIES));
+    while let Some(delivery) = receiver.recv().await {
+        let Ok(permit) = permits.clone().acquire_owned().await else {
+            break;
+        };
+        let client = client.clone();
+        tokio::spawn(async move {
+            deliver(&client, &delivery, retry).await;
+            drop(permit);
+        });
+    }
+}
+
+async fn deliver(client: &reqwest::Client, delivery: &Delivery, retry: RetryPolicy) {
+    let config = &delivery.webhook.config;
+    let signature = format!("sha256={}", signature(&config.secret, delivery.body.as_bytes()));
+    for attempt in 1..=retry.max_attempts {
+        let result = client
+            .post(&config.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, &signature)
+            .header(SCOPE_HEADER, &delivery.scope)
+            .body(delivery.body.as_ref().clone())
+            .send()
+            .await;
+        match result {
+            Ok(response) if response.status().is_success() => {
+                metrics::counter!("relay_webhook_deliveries_total").increment(1);
+                return;
+            }
+            Ok(response) => debug!("Webhook {} returned {} (attempt {})", config.url, response.status(), attempt),
+            Err(e) => debug!("Webhook {} failed: {} (attempt {})", config.url, e, attempt),
+        }
+        if attempt < retry.max_attempts {
+            tokio::time::sleep(retry.backoff(attempt)).await;
+        }
+    }
+    warn!("Giving up on webhook {} after {} attempts", config.url, retry.max_attempts);
+    metrics::counter!("relay_webhook_dead_letters_total").increment(1);
+}
+
+/// Dispatches every event published on `live` until the relay shuts down
+pub fn spawn_listener(dispatcher: Arc<WebhookDispatcher>, live: &LiveEvents) {
+    if dispatcher.webhooks.is_empty() {
+        return;
+    }
+    let mut receiver = live.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(stored) => dispatcher.dispatch(&stored.event, &stored.scope),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Webhook listener fell behind, dropped {} events", missed);
+                    metrics::counter!("relay_webhook_dead_letters_total").increment(missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Bytes, extract::State, http::{HeaderMap, StatusCode}, routing::post, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    type Received = (HeaderMap, Bytes);
+
+    /// Local receiver that answers the first `failures` requests with a 500
+    async fn mock_server(failures: usize) -> (String, mpsc::UnboundedReceiver<Received>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(
+                    |State((tx, calls)): State<(mpsc::UnboundedSender<Received>, Arc<AtomicUsize>)>,
+                     headers: HeaderMap,
+                     body: Bytes| async move {
+                        if calls.fetch_add(1, Ordering::SeqCst) < failures {
+                            return StatusCode::INTERNAL_SERVER_ERROR;
+                        }
+                        tx.send((headers, body)).unwrap();
+                        StatusCode::OK
+                    },
+                ),
+            )
+            .with_state((tx, calls));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, rx)
+    }
+
+    fn webhook(url: &str, scopes: &[&str]) -> WebhookConfig {
+        WebhookConfig {
+            url: url.to_string(),
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+            kinds: Vec::new(),
+            secret: "s3cret".to_string(),
+            events_per_minute: 0,
+        }
+    }
+
+    fn fast_retry() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+        }
+    }
+
+    async fn next(rx: &mut mpsc::UnboundedReceiver<Received>) -> Received {
+        tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_signature_matches_rfc4231() {
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_matching() {
+        let hook = WebhookConfig {
+            kinds: vec![1],
+            ..webhook("http://localhost/hook", &["root", "9q*"])
+        };
+        assert!(hook.matches("root", 1));
+        assert!(hook.matches("9q8yy", 1));
+        assert!(!hook.matches("9q8yy", 20000));
+        assert!(!hook.matches("drt2z", 1));
+
+        let everything = webhook("http://localhost/hook", &[]);
+        assert!(everything.matches("drt2z", 20000));
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_cap() {
+        let retry = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+        };
+        assert_eq!(retry.backoff(1), Duration::from_secs(1));
+        assert_eq!(retry.backoff(3), Duration::from_secs(4));
+        assert_eq!(retry.backoff(4), Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_delivers_signed_event_filtered_by_scope_prefix() {
+        let (url, mut rx) = mock_server(0).await;
+        let dispatcher = WebhookDispatcher::new(vec![webhook(&url, &["9q*"])], fast_retry());
+        let keys = Keys::generate();
+        let outside = EventBuilder::text_note("elsewhere").sign(&keys).await.unwrap();
+        let inside = EventBuilder::text_note("in the bay").sign(&keys).await.unwrap();
+
+        dispatcher.dispatch(&outside, &Scope::named("drt2z").unwrap());
+        dispatcher.dispatch(&inside, &Scope::named("9q8yy").unwrap());
+
+        let (headers, body) = next(&mut rx).await;
+        let delivered = Event::from_json(&body).unwrap();
+        assert_eq!(delivered.id, inside.id);
+        assert_eq!(headers[SCOPE_HEADER], "9q8yy");
+        assert_eq!(
+            headers[SIGNATURE_HEADER].to_str().unwrap(),
+            format!("sha256={}", signature("s3cret", &body))
+        );
+        // The out-of-scope event never arrives
+        assert!(tokio::time::timeout(Duration::from_millis(200), rx.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retries_after_server_error() {
+        let (url, mut rx) = mock_server(2).await;
+        let dispatcher = WebhookDispatcher::new(vec![webhook(&url, &[])], fast_retry());
+        let event = EventBuilder::text_note("retry me").sign(&Keys::generate()).await.unwrap();
+
+        dispatcher.dispatch(&event, &Scope::Default);
+
+        let (headers, body) = next(&mut rx).await;
+        assert_eq!(Event::from_json(&body).unwrap().id, event.id);
+        assert_eq!(headers[SCOPE_HEADER], "root");
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_is_per_webhook() {
+        let (url, mut rx) = mock_server(0).await;
+        let limited = WebhookConfig {
+            events_per_minute: 1,
+            ..webhook(&url, &[])
+        };
+        let dispatcher = WebhookDispatcher::new(vec![limited], fast_retry());
+        let keys = Keys::generate();
+        for i in 0..3 {
+            let event = EventBuilder::text_note(format!("flood {}", i)).sign(&keys).await.unwrap();
+            dispatcher.dispatch(&event, &Scope::named("drt2z").unwrap());
+        }
+
+        next(&mut rx).await;
+        assert!(tokio::time::timeout(Duration::from_millis(200), rx.recv()).await.is_err());
+    }
+}
@@ -0,0 +1,278 @@
+//! Web-of-trust write gating
+//!
+//! Popular cells attract throwaway-key spam that no per-key limit catches.
+//! With `wot_seed_pubkeys` set, only the seeds and the keys within
+//! `wot_depth` follow hops of them may write: depth 1 trusts everyone a
+//! seed follows, depth 2 also everyone those follow. The set is built from
+//! kind 3 contact lists fetched from `wot_bootstrap_relays` (or the local
+//! store) and rebuilt every `wot_refresh_secs`; a failed rebuild keeps the
+//! previous set. Its size and age are reported in `/api/stats`.
+
+use anyhow::Result;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+use crate::config::RelayConfig;
+use crate::store::ScopeStore;
+
+/// Authors per contact list request
+const AUTHORS_PER_FETCH: usize = 500;
+
+/// How long a bootstrap relay fetch may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where contact lists come from
+pub trait FollowSource: Send + Sync + 'static {
+    /// Pubkeys followed by each of `authors` that has a contact list
+    fn follows(&self, authors: Vec<PublicKey>) -> impl Future<Output = Result<HashMap<PublicKey, Vec<PublicKey>>>> + Send;
+}
+
+/// Follows from the newest contact list of each author in `events`
+pub fn follows_from_contact_lists(events: impl IntoIterator<Item = Event>) -> HashMap<PublicKey, Vec<PublicKey>> {
+    let mut newest: HashMap<PublicKey, Event> = HashMap::new();
+    for event in events.into_iter().filter(|event| event.kind == Kind::ContactList) {
+        if !matches!(newest.get(&event.pubkey), Some(current) if current.created_at >= event.created_at) {
+            newest.insert(event.pubkey, event);
+        }
+    }
+    newest
+        .into_iter()
+        .map(|(author, event)| (author, event.tags.public_keys().copied().collect()))
+        .collect()
+}
+
+/// Contact lists stored in this relay's root scope
+pub struct StoreFollows {
+    store: Arc<dyn ScopeStore>,
+}
+
+impl StoreFollows {
+    pub fn new(store: Arc<dyn ScopeStore>) -> Self {
+        Self { store }
+    }
+}
+
+impl FollowSource for StoreFollows {
+    async fn follows(&self, authors: Vec<PublicKey>) -> Result<HashMap<PublicKey, Vec<PublicKey>>> {
+        let mut events = Vec::new();
+        for chunk in authors.chunks(AUTHORS_PER_FETCH) {
+            let filter = Filter::new().kind(Kind::ContactList).authors(chunk.iter().copied());
+            events.extend(self.store.query(&Scope::Default, filter).await?);
+        }
+        Ok(follows_from_contact_lists(events))
+    }
+}
+
+/// Contact lists fetched from other relays
+pub struct RelayFollows {
+    relays: Vec<String>,
+}
+
+impl RelayFollows {
+    pub fn new(relays: Vec<String>) -> Self {
+        Self { relays }
+    }
+}
+
+impl FollowSource for RelayFollows {
+    async fn follows(&self, authors: Vec<PublicKey>) -> Result<HashMap<PublicKey, Vec<PublicKey>>> {
+        let client = Client::default();
+        for relay in &self.relays {
+            client.add_relay(relay).await?;
+        }
+        client.connect().await;
+        let mut events = Vec::new();
+        for chunk in authors.chunks(AUTHORS_PER_FETCH) {
+            let filter = Filter::new().kind(Kind::ContactList).authors(chunk.iter().copied());
+            match client.fetch_events(filter, FETCH_TIMEOUT).await {
+                Ok(fetched) => events.extend(fetched),
+                Err(e) => {
+                    client.disconnect().await;
+                    return Err(e.into());
+                }
+            }
+        }
+        client.disconnect().await;
+        Ok(follows_from_contact_lists(events))
+    }
+}
+
+/// Size and age of the trusted set, for `/api/stats`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WotStats {
+    pub depth: u8,
+    pub trusted_pubkeys: usize,
+    /// Unix time of the last successful rebuild, null before the first
+    pub refreshed_at: Option<u64>,
+}
+
+/// Keys allowed to write while the web of trust is on
+#[derive(Debug, Default)]
+pub struct WebOfTrust {
+    seeds: Vec<PublicKey>,
+    depth: u8,
+    /// Seeds only until the first rebuild
+    trusted: RwLock<HashSet<PublicKey>>,
+    refreshed_at: RwLock<Option<u64>>,
+}
+
+impl WebOfTrust {
+    pub fn new(seeds: Vec<PublicKey>, depth: u8) -> Self {
+        Self {
+            trusted: RwLock::new(seeds.iter().copied().collect()),
+            seeds,
+            depth,
+            refreshed_at: RwLock::new(None),
+        }
+    }
+
+    pub fn for_config(config: &RelayConfig) -> Self {
+        let seeds = config
+            .wot_seed_pubkeys
+            .iter()
+            .filter_map(|seed| PublicKey::from_hex(seed).ok())
+            .collect();
+        Self::new(seeds, config.wot_depth)
+    }
+
+    /// Open to everyone, for tests and tooling
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.seeds.is_empty()
+    }
+
+    /// Whether `pubkey` may write; always true while disabled
+    pub fn allows(&self, pubkey: &PublicKey) -> bool {
+        !self.is_enabled() || self.trusted.read().contains(pubkey)
+    }
+
+    /// Rebuilds the trusted set from `source`; returns its size
+    pub async fn refresh(&self, source: &impl FollowSource) -> Result<usize> {
+        let mut trusted: HashSet<PublicKey> = self.seeds.iter().copied().collect();
+        let mut frontier = self.seeds.clone();
+        for _ in 0..self.depth {
+            if frontier.is_empty() {
+                break;
+            }
+            let follows = source.follows(frontier).await?;
+            frontier = follows
+                .into_values()
+                .flatten()
+                .filter(|pubkey| trusted.insert(*pubkey))
+                .collect();
+        }
+        let size = trusted.len();
+        *self.trusted.write() = trusted;
+        *self.refreshed_at.write() = Some(Timestamp::now().as_u64());
+        Ok(size)
+    }
+
+    /// `None` while disabled
+    pub fn report(&self) -> Option<WotStats> {
+        self.is_enabled().then(|| WotStats {
+            depth: self.depth,
+            trusted_pubkeys: self.trusted.read().len(),
+            refreshed_at: *self.refreshed_at.read(),
+        })
+    }
+}
+
+/// Builds the trusted set now and again every `interval`
+pub fn spawn_wot_task(wot: Arc<WebOfTrust>, source: impl FollowSource, interval: Duration) {
+    if !wot.is_enabled() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match wot.refresh(&source).await {
+                Ok(size) => info!("Web of trust rebuilt: {} trusted pubkeys", size),
+                Err(e) => warn!("Web of trust rebuild failed, keeping the previous set: {:#}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Follow graph held in memory
+    struct FakeGraph(HashMap<PublicKey, Vec<PublicKey>>);
+
+    impl FollowSource for FakeGraph {
+        async fn follows(&self, authors: Vec<PublicKey>) -> Result<HashMap<PublicKey, Vec<PublicKey>>> {
+            Ok(authors
+                .into_iter()
+                .filter_map(|author| self.0.get(&author).map(|follows| (author, follows.clone())))
+                .collect())
+        }
+    }
+
+    /// seed -> alice -> bob -> carol, and an unrelated spammer
+    fn graph() -> (FakeGraph, [PublicKey; 5]) {
+        let [seed, alice, bob, carol, spammer] = std::array::from_fn(|_| Keys::generate().public_key());
+        let graph = FakeGraph(HashMap::from([
+            (seed, vec![alice]),
+            (alice, vec![bob, seed]),
+            (bob, vec![carol]),
+            (spammer, vec![seed]),
+        ]));
+        (graph, [seed, alice, bob, carol, spammer])
+    }
+
+    #[tokio::test]
+    async fn test_depth_limits_the_trusted_set() {
+        let (graph, [seed, alice, bob, carol, spammer]) = graph();
+
+        let wot = WebOfTrust::new(vec![seed], 1);
+        assert_eq!(wot.refresh(&graph).await.unwrap(), 2);
+        assert!(wot.allows(&seed) && wot.allows(&alice));
+        assert!(!wot.allows(&bob) && !wot.allows(&spammer));
+
+        let wot = WebOfTrust::new(vec![seed], 2);
+        assert_eq!(wot.refresh(&graph).await.unwrap(), 3);
+        assert!(wot.allows(&bob));
+        assert!(!wot.allows(&carol) && !wot.allows(&spammer));
+        let report = wot.report().unwrap();
+        assert_eq!(report.trusted_pubkeys, 3);
+        assert!(report.refreshed_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_seeds_only_before_first_refresh_and_open_when_disabled() {
+        let (_, [seed, alice, ..]) = graph();
+        let wot = WebOfTrust::new(vec![seed], 1);
+        assert!(wot.allows(&seed) && !wot.allows(&alice));
+        assert_eq!(wot.report().unwrap().refreshed_at, None);
+
+        let open = WebOfTrust::disabled();
+        assert!(open.allows(&alice));
+        assert_eq!(open.report(), None);
+    }
+
+    #[tokio::test]
+    async fn test_newest_contact_list_wins() {
+        let keys = Keys::generate();
+        let (old, new) = (Keys::generate().public_key(), Keys::generate().public_key());
+        let list = |follow: PublicKey, at: u64| {
+            EventBuilder::new(Kind::ContactList, "")
+                .tag(Tag::public_key(follow))
+                .custom_created_at(Timestamp::from(at))
+                .sign_with_keys(&keys)
+                .unwrap()
+        };
+        let follows = follows_from_contact_lists([list(new, 2_000), list(old, 1_000)]);
+        assert_eq!(follows[&keys.public_key()], vec![new]);
+    }
+}
@@ -0,0 +1,142 @@
+/// Integration tests for addressable events (kind, pubkey, d tag) in
+/// several scopes
+///
+/// The same coordinate may exist in every scope. Replacement, deletion by
+/// `a` tag and lookups by coordinate must all stay inside the scope they
+/// happen in.
+
+mod common;
+
+use common::*;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde_json::json;
+
+const D_TAG: &str = "meetup";
+
+async fn article(keys: &Keys, content: &str, created_at: u64) -> Event {
+    EventBuilder::new(Kind::LongFormTextNote, content)
+        .tag(Tag::identifier(D_TAG))
+        .custom_created_at(Timestamp::from(created_at))
+        .sign(keys)
+        .await
+        .unwrap()
+}
+
+async fn publish_ok(client: &mut Client, event: &Event) {
+    publish(client, event).await;
+    let ok = next_message(client).await;
+    assert_eq!(ok[2], true, "{:?}", ok);
+}
+
+/// Contents of the scope's copies of the coordinate, fetched as a client
+/// would with an `a`-style filter
+async fn fetch_coordinate(client: &mut Client, keys: &Keys) -> Vec<String> {
+    req(
+        client,
+        "coordinate",
+        json!({"kinds": [30023], "authors": [keys.public_key().to_hex()], "#d": [D_TAG]}),
+    )
+    .await;
+    let messages = until_eose(client, "coordinate").await;
+    send(client, json!(["CLOSE", "coordinate"])).await;
+    messages
+        .iter()
+        .filter(|m| m[0] == "EVENT")
+        .map(|m| m[2]["content"].as_str().unwrap().to_string())
+        .collect()
+}
+
+async fn connect_to(relay: &TestRelay, host: &str) -> Client {
+    let mut client = relay.connect(host).await;
+    // Welcome notice
+    next_message(&mut client).await;
+    client
+}
+
+#[tokio::test]
+async fn test_each_scope_returns_only_its_own_version() {
+    let relay = start_relay().await;
+    let keys = Keys::generate();
+    let mut drt2z = connect_to(&relay, "drt2z.example.com").await;
+    let mut sf = connect_to(&relay, "9q8yy.example.com").await;
+
+    publish_ok(&mut drt2z, &article(&keys, "denver meetup", 1_000).await).await;
+    publish_ok(&mut sf, &article(&keys, "la meetup", 1_000).await).await;
+
+    assert_eq!(fetch_coordinate(&mut drt2z, &keys).await, vec!["denver meetup"]);
+    assert_eq!(fetch_coordinate(&mut sf, &keys).await, vec!["la meetup"]);
+
+    // A scope that never saw the coordinate has nothing for it
+    let mut root = connect_to(&relay, "example.com").await;
+    assert!(fetch_coordinate(&mut root, &keys).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_newer_version_only_replaces_within_its_scope() {
+    let relay = start_relay().await;
+    let keys = Keys::generate();
+    let mut drt2z = connect_to(&relay, "drt2z.example.com").await;
+    let mut sf = connect_to(&relay, "9q8yy.example.com").await;
+
+    publish_ok(&mut drt2z, &article(&keys, "denver v1", 1_000).await).await;
+    publish_ok(&mut sf, &article(&keys, "la v1", 1_000).await).await;
+    publish_ok(&mut drt2z, &article(&keys, "denver v2", 2_000).await).await;
+
+    assert_eq!(fetch_coordinate(&mut drt2z, &keys).await, vec!["denver v2"]);
+    assert_eq!(fetch_coordinate(&mut sf, &keys).await, vec!["la v1"]);
+
+    // An older version arriving later doesn't win either, in any scope
+    publish(&mut sf, &article(&keys, "la v0", 500).await).await;
+    next_message(&mut sf).await;
+    assert_eq!(fetch_coordinate(&mut sf, &keys).await, vec!["la v1"]);
+    assert_eq!(fetch_coordinate(&mut drt2z, &keys).await, vec!["denver v2"]);
+}
+
+#[tokio::test]
+async fn test_deletion_by_coordinate_stays_in_connection_scope() {
+    let relay = start_relay().await;
+    let keys = Keys::generate();
+    let mut drt2z = connect_to(&relay, "drt2z.example.com").await;
+    let mut sf = connect_to(&relay, "9q8yy.example.com").await;
+
+    publish_ok(&mut drt2z, &article(&keys, "denver meetup", 1_000).await).await;
+    publish_ok(&mut sf, &article(&keys, "la meetup", 1_000).await).await;
+
+    let coordinate = Coordinate::new(Kind::LongFormTextNote, keys.public_key()).identifier(D_TAG);
+    let deletion = EventBuilder::delete(EventDeletionRequest::new().coordinate(coordinate))
+        .sign(&keys)
+        .await
+        .unwrap();
+    publish_ok(&mut drt2z, &deletion).await;
+
+    assert!(fetch_coordinate(&mut drt2z, &keys).await.is_empty());
+    assert_eq!(fetch_coordinate(&mut sf, &keys).await, vec!["la meetup"]);
+
+    // The deletion itself is only stored where it was sent
+    let deletions = Filter::new().kind(Kind::EventDeletion);
+    let sf_scope = Scope::named("9q8yy").unwrap();
+    assert_eq!(relay.relay.store.count(&sf_scope, deletions.clone()).await.unwrap(), 0);
+    assert_eq!(relay.relay.store.count(&Scope::Default, deletions).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_deletion_stays_in_cell_even_when_listed_as_global_kind() {
+    let relay = start_relay_with(|config| config.global_kinds.push(5)).await;
+    let keys = Keys::generate();
+    let mut root = connect_to(&relay, "example.com").await;
+    let mut drt2z = connect_to(&relay, "drt2z.example.com").await;
+
+    publish_ok(&mut root, &article(&keys, "root meetup", 1_000).await).await;
+    publish_ok(&mut drt2z, &article(&keys, "denver meetup", 1_000).await).await;
+
+    let coordinate = Coordinate::new(Kind::LongFormTextNote, keys.public_key()).identifier(D_TAG);
+    let deletion = EventBuilder::delete(EventDeletionRequest::new().coordinate(coordinate))
+        .sign(&keys)
+        .await
+        .unwrap();
+    publish_ok(&mut drt2z, &deletion).await;
+
+    assert!(fetch_coordinate(&mut drt2z, &keys).await.is_empty());
+    assert_eq!(fetch_coordinate(&mut root, &keys).await, vec!["root meetup"]);
+}
@@ -0,0 +1,32 @@
+/// Integration tests for the per-IP cell spread limit behind a proxy
+
+mod common;
+
+use common::*;
+use geohashed_relay::reject::reason_code;
+use nostr_sdk::prelude::*;
+use serde_json::Value;
+
+async fn publish_from(relay: &TestRelay, cell: &str, client: &str, keys: &Keys) -> Value {
+    let mut connection = relay.connect_forwarded(&format!("{}.example.com", cell), client).await;
+    next_message(&mut connection).await;
+    let note = EventBuilder::text_note(format!("hello {}", cell)).sign(keys).await.unwrap();
+    publish(&mut connection, &note).await;
+    next_message(&mut connection).await
+}
+
+#[tokio::test]
+async fn test_cells_are_counted_per_forwarded_client() {
+    let relay = start_relay_with(|config| config.max_cells_per_ip_per_hour = 2).await;
+    let keys = Keys::generate();
+
+    // Every connection comes through the same (local) proxy
+    assert_eq!(publish_from(&relay, "9xj64", "198.51.100.7", &keys).await[2], true);
+    assert_eq!(publish_from(&relay, "9xj65", "198.51.100.7", &keys).await[2], true);
+    let refused = publish_from(&relay, "9xj66", "198.51.100.7", &keys).await;
+    assert_eq!(refused[2], false);
+    assert_eq!(reason_code(refused[3].as_str().unwrap()), Some("too-many-cells"));
+
+    // Another client behind it has its own allowance
+    assert_eq!(publish_from(&relay, "9xj66", "198.51.100.8", &keys).await[2], true);
+}
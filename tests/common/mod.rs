@@ -0,0 +1,153 @@
+//! Websocket integration harness
+//!
+//! Serves a fully built relay on an ephemeral port backed by a temporary
+//! database, and provides a minimal client that can connect with any Host
+//! header so scopes can be exercised without DNS.
+
+#![allow(dead_code)]
+
+use futures::{SinkExt, StreamExt};
+use geohashed_relay::config::RelayConfig;
+use geohashed_relay::relay::{build_relay, build_relay_with_policy, BuiltRelay};
+use geohashed_relay::scope_policy::ScopePolicy;
+use nostr_sdk::prelude::*;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{self, client::IntoClientRequest, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+pub type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// How long a client waits for the next relay message
+pub const MESSAGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A relay running on 127.0.0.1 with its own database
+pub struct TestRelay {
+    pub addr: SocketAddr,
+    pub relay: BuiltRelay,
+    _dir: TempDir,
+}
+
+/// Config suitable for tests: temporary storage, no outbound fetches
+pub fn test_config(dir: &TempDir) -> RelayConfig {
+    RelayConfig {
+        relay_url: "ws://example.com".to_string(),
+        database_path: dir.path().join("db").to_string_lossy().to_string(),
+        metrics_enabled: false,
+        preview_enabled: false,
+        self_publish: false,
+        stats_interval_secs: 3600,
+        // Don't let the host's disk decide whether writes are accepted
+        disk_watermark_percent: 0,
+        ..Default::default()
+    }
+}
+
+/// Starts a relay with the default test config
+pub async fn start_relay() -> TestRelay {
+    start_relay_with(|_| {}).await
+}
+
+/// Starts a relay after letting the caller adjust the test config
+pub async fn start_relay_with(configure: impl FnOnce(&mut RelayConfig)) -> TestRelay {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = test_config(&dir);
+    configure(&mut config);
+
+    let relay = build_relay(&config, Keys::generate()).await.unwrap();
+    serve(relay, dir).await
+}
+
+/// Starts a relay whose scopes are decided by `policy`
+pub async fn start_relay_with_policy<P: ScopePolicy + Clone>(policy: P) -> TestRelay {
+    let dir = tempfile::tempdir().unwrap();
+    let config = test_config(&dir);
+    let relay = build_relay_with_policy(&config, Keys::generate(), policy).await.unwrap();
+    serve(relay, dir).await
+}
+
+async fn serve(mut relay: BuiltRelay, dir: TempDir) -> TestRelay {
+    let app = std::mem::take(&mut relay.app);
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+            .unwrap();
+    });
+
+    TestRelay { addr, relay, _dir: dir }
+}
+
+impl TestRelay {
+    /// Opens a websocket as if connecting to `host` (e.g. "drt2z.example.com")
+    pub async fn connect(&self, host: &str) -> Client {
+        self.try_connect(host, "/").await.unwrap()
+    }
+
+    /// Opens a websocket to `path` (e.g. "/?scope=drt2z") with the given Host
+    pub async fn try_connect(&self, host: &str, path: &str) -> Result<Client, tungstenite::Error> {
+        let mut request = format!("ws://{}{}", self.addr, path).into_client_request().unwrap();
+        request.headers_mut().insert("host", host.parse().unwrap());
+        let (client, _) = connect_async(request).await?;
+        Ok(client)
+    }
+
+    /// Opens a websocket to `host` as `client` behind this (local) proxy
+    pub async fn connect_forwarded(&self, host: &str, client: &str) -> Client {
+        let mut request = format!("ws://{}/", self.addr).into_client_request().unwrap();
+        request.headers_mut().insert("host", host.parse().unwrap());
+        request.headers_mut().insert("x-forwarded-for", client.parse().unwrap());
+        let (client, _) = connect_async(request).await.unwrap();
+        client
+    }
+}
+
+/// Sends a raw JSON message
+pub async fn send(client: &mut Client, message: Value) {
+    client.send(Message::Text(message.to_string().into())).await.unwrap();
+}
+
+/// Sends a REQ for `filter` under `sub_id`
+pub async fn req(client: &mut Client, sub_id: &str, filter: Value) {
+    send(client, serde_json::json!(["REQ", sub_id, filter])).await;
+}
+
+/// Publishes an event
+pub async fn publish(client: &mut Client, event: &Event) {
+    send(client, serde_json::json!(["EVENT", event])).await;
+}
+
+/// Next relay message as JSON, panicking after `MESSAGE_TIMEOUT`
+pub async fn next_message(client: &mut Client) -> Value {
+    loop {
+        let message = tokio::time::timeout(MESSAGE_TIMEOUT, client.next())
+            .await
+            .expect("timed out waiting for relay message")
+            .expect("connection closed")
+            .unwrap();
+        if let Message::Text(text) = message {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+/// Collects messages until (and including) the EOSE for `sub_id`
+pub async fn until_eose(client: &mut Client, sub_id: &str) -> Vec<Value> {
+    let mut messages = Vec::new();
+    loop {
+        let message = next_message(client).await;
+        let done = message[0] == "EOSE" && message[1] == sub_id;
+        messages.push(message);
+        if done {
+            return messages;
+        }
+    }
+}
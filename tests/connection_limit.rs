@@ -0,0 +1,71 @@
+/// Integration tests for the global connection cap
+
+mod common;
+
+use common::*;
+use serde_json::json;
+use tokio_tungstenite::{connect_async, tungstenite::{client::IntoClientRequest, Error}};
+
+/// Attempts a websocket upgrade and returns the HTTP status on refusal
+async fn try_connect(relay: &TestRelay) -> Result<(), (u16, Option<String>)> {
+    let mut request = format!("ws://{}/", relay.addr).into_client_request().unwrap();
+    request.headers_mut().insert("host", "drt2z.example.com".parse().unwrap());
+    match connect_async(request).await {
+        Ok(_) => Ok(()),
+        Err(Error::Http(response)) => Err((
+            response.status().as_u16(),
+            response
+                .headers()
+                .get("retry-after")
+                .map(|v| v.to_str().unwrap().to_string()),
+        )),
+        Err(e) => panic!("unexpected error: {}", e),
+    }
+}
+
+#[tokio::test]
+async fn test_new_connections_refused_at_cap() {
+    let relay = start_relay_with(|config| {
+        config.max_connections = 2;
+        config.reserved_connections = 0;
+    })
+    .await;
+
+    // Waiting for the welcome NOTICE ensures each connection is registered
+    let mut first = relay.connect("drt2z.example.com").await;
+    next_message(&mut first).await;
+    let mut second = relay.connect("drt2z.example.com").await;
+    next_message(&mut second).await;
+
+    let (status, retry_after) = try_connect(&relay).await.unwrap_err();
+    assert_eq!(status, 503);
+    assert!(retry_after.is_some());
+
+    // Existing connections keep working
+    req(&mut first, "sub", json!({"limit": 1})).await;
+    let messages = until_eose(&mut first, "sub").await;
+    assert_eq!(messages.last().unwrap()[0], "EOSE");
+
+    // A slot frees up once someone leaves
+    drop(second);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert!(try_connect(&relay).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_localhost_gets_reserved_headroom() {
+    let relay = start_relay_with(|config| {
+        config.max_connections = 1;
+        config.reserved_connections = 1;
+    })
+    .await;
+
+    let mut first = relay.connect("drt2z.example.com").await;
+    next_message(&mut first).await;
+
+    // The test client connects from 127.0.0.1, which is trusted
+    let mut second = relay.connect("drt2z.example.com").await;
+    next_message(&mut second).await;
+
+    assert_eq!(try_connect(&relay).await.unwrap_err().0, 503);
+}
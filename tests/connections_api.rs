@@ -0,0 +1,72 @@
+/// Integration tests for listing live connections on the admin API
+
+mod common;
+
+use common::*;
+use nostr_sdk::prelude::*;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+const ADMIN_TOKEN: &str = "s3cret";
+
+async fn connections(relay: &TestRelay) -> Vec<Value> {
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/api/connections", relay.addr))
+        .bearer_auth(ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    serde_json::from_str(&response.text().await.unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn test_connections_reflect_a_live_connection() {
+    let relay = start_relay_with(|config| config.admin_token = Some(ADMIN_TOKEN.to_string())).await;
+    let unauthorized = reqwest::get(format!("http://{}/api/connections", relay.addr)).await.unwrap();
+    assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+
+    let mut client = relay.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+    let listed = connections(&relay).await;
+    assert_eq!(listed.len(), 1, "{:?}", listed);
+    assert_eq!(listed[0]["scope"], "drt2z");
+    assert_eq!(listed[0]["ip"], "127.0.0.1");
+    assert!(listed[0]["connected_at"].is_u64());
+    assert!(listed[0]["window_started_at"].is_null());
+
+    let keys = Keys::generate();
+    publish(&mut client, &EventBuilder::text_note("here").sign(&keys).await.unwrap()).await;
+    assert_eq!(next_message(&mut client).await[2], true);
+    req(&mut client, "feed", json!({ "kinds": [1] })).await;
+    until_eose(&mut client, "feed").await;
+
+    let listed = connections(&relay).await;
+    assert_eq!(listed[0]["accepted"], 1);
+    assert_eq!(listed[0]["rejected"], 0);
+    assert_eq!(listed[0]["subscriptions"], 1);
+    assert!(listed[0]["window_started_at"].is_u64());
+
+    // Closed connections drop off the list
+    drop(client);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert!(connections(&relay).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_read_only_connection_is_tracked_with_its_client_address() {
+    let relay = start_relay_with(|config| config.admin_token = Some(ADMIN_TOKEN.to_string())).await;
+    let mut client = relay.connect_forwarded("drt2z.example.com", "198.51.100.7").await;
+    next_message(&mut client).await;
+    // Only ever REQs, never an EVENT
+    req(&mut client, "feed", json!({ "kinds": [1] })).await;
+    until_eose(&mut client, "feed").await;
+
+    let listed = connections(&relay).await;
+    assert_eq!(listed.len(), 1, "{:?}", listed);
+    assert_eq!(listed[0]["scope"], "drt2z");
+    // The client behind the (local) proxy, not the proxy
+    assert_eq!(listed[0]["ip"], "198.51.100.7");
+    assert!(listed[0]["connected_at"].is_u64());
+    assert_eq!(listed[0]["subscriptions"], 1);
+}
@@ -0,0 +1,54 @@
+/// Integration tests for NIP-45 COUNT
+
+mod common;
+
+use common::*;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+async fn count(client: &mut Client, sub_id: &str, filter: Value) -> Value {
+    send(client, json!(["COUNT", sub_id, filter])).await;
+    next_message(client).await
+}
+
+#[tokio::test]
+async fn test_count_needs_read_auth() {
+    let relay = start_relay_with(|config| config.geohash_read_auth = true).await;
+    let note = EventBuilder::text_note("cell note").sign(&Keys::generate()).await.unwrap();
+    relay.relay.store.save(&Scope::named("drt2z").unwrap(), note).await.unwrap();
+
+    let mut client = relay.connect("drt2z.example.com").await;
+    loop {
+        if next_message(&mut client).await[0] == "AUTH" {
+            break;
+        }
+    }
+    let reply = count(&mut client, "count", json!({ "kinds": [1] })).await;
+    assert_eq!(reply[0], "CLOSED", "{:?}", reply);
+    assert!(reply[2].as_str().unwrap().starts_with("auth-required:"), "{:?}", reply);
+}
+
+#[tokio::test]
+async fn test_count_leaves_out_expired_events() {
+    let relay = start_relay().await;
+    let mut client = relay.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+
+    let expiration = Timestamp::from(Timestamp::now().as_u64() + 2);
+    let note = EventBuilder::text_note("gone soon")
+        .tag(Tag::expiration(expiration))
+        .sign(&Keys::generate())
+        .await
+        .unwrap();
+    publish(&mut client, &note).await;
+    let ok = next_message(&mut client).await;
+    assert_eq!(ok[2], true, "{:?}", ok);
+    let reply = count(&mut client, "before", json!({ "kinds": [1] })).await;
+    assert_eq!(reply[2]["count"], 1, "{:?}", reply);
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let reply = count(&mut client, "after", json!({ "kinds": [1] })).await;
+    assert_eq!(reply[2]["count"], 0, "{:?}", reply);
+}
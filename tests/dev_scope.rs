@@ -0,0 +1,54 @@
+/// Integration tests for picking the scope with `?scope=` in dev mode
+
+mod common;
+
+use common::*;
+use geohashed_relay::reject::reason_code;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+
+async fn publish_geotagged(client: &mut Client, geohash: &str) -> serde_json::Value {
+    let event = EventBuilder::text_note("hello")
+        .tags(vec![Tag::custom(TagKind::Custom("g".into()), vec![geohash.to_string()])])
+        .sign(&Keys::generate())
+        .await
+        .unwrap();
+    publish(client, &event).await;
+    next_message(client).await
+}
+
+#[tokio::test]
+async fn test_scope_param_ignored_when_disabled() {
+    let relay = start_relay().await;
+
+    let mut client = relay.try_connect("localhost", "/?scope=drt2z").await.unwrap();
+    next_message(&mut client).await;
+    let ok = publish_geotagged(&mut client, "drt2z").await;
+    assert_eq!(ok[2], false);
+    assert_eq!(reason_code(ok[3].as_str().unwrap()), Some("root-rejects-geotagged"));
+}
+
+#[tokio::test]
+async fn test_scope_param_selects_cell() {
+    let relay = start_relay_with(|config| config.dev_scope_query_param = true).await;
+
+    let mut client = relay.try_connect("localhost", "/?scope=drt2z").await.unwrap();
+    next_message(&mut client).await;
+    let ok = publish_geotagged(&mut client, "drt2z").await;
+    assert_eq!(ok[2], true, "{:?}", ok);
+
+    let stored = relay
+        .relay
+        .store
+        .query(&Scope::named("drt2z").unwrap(), Filter::new())
+        .await
+        .unwrap();
+    assert_eq!(stored.len(), 1);
+}
+
+#[tokio::test]
+async fn test_invalid_scope_param_rejected() {
+    let relay = start_relay_with(|config| config.dev_scope_query_param = true).await;
+
+    assert!(relay.try_connect("localhost", "/?scope=foobar").await.is_err());
+}
@@ -0,0 +1,46 @@
+/// Integration tests for republishing events the relay already stores
+
+mod common;
+
+use common::*;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+
+#[tokio::test]
+async fn test_second_publish_gets_duplicate_ok() {
+    let relay = start_relay().await;
+    let mut client = relay.connect("drt2z.example.com").await;
+    // Welcome notice
+    next_message(&mut client).await;
+
+    let event = EventBuilder::text_note("hello again").sign(&Keys::generate()).await.unwrap();
+    publish(&mut client, &event).await;
+    let first = next_message(&mut client).await;
+    assert_eq!(first[2], true, "{:?}", first);
+    assert!(!first[3].as_str().unwrap().starts_with("duplicate:"));
+
+    publish(&mut client, &event).await;
+    let second = next_message(&mut client).await;
+    assert_eq!(second[0], "OK");
+    assert_eq!(second[1], event.id.to_hex());
+    assert_eq!(second[2], true);
+    assert!(second[3].as_str().unwrap().starts_with("duplicate:"), "{:?}", second);
+
+    let stored = relay.relay.store.count(&Scope::named("drt2z").unwrap(), Filter::new().id(event.id)).await.unwrap();
+    assert_eq!(stored, 1);
+}
+
+#[tokio::test]
+async fn test_same_event_in_another_cell_is_not_a_duplicate() {
+    let relay = start_relay().await;
+    let event = EventBuilder::text_note("hello everywhere").sign(&Keys::generate()).await.unwrap();
+
+    for host in ["drt2z.example.com", "9q8yy.example.com"] {
+        let mut client = relay.connect(host).await;
+        next_message(&mut client).await;
+        publish(&mut client, &event).await;
+        let ok = next_message(&mut client).await;
+        assert_eq!(ok[2], true, "{:?}", ok);
+        assert!(!ok[3].as_str().unwrap().starts_with("duplicate:"), "{:?}", ok);
+    }
+}
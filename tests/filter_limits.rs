@@ -0,0 +1,82 @@
+/// Integration tests for clamped and defaulted REQ limits
+
+mod common;
+
+use common::*;
+use geohashed_relay::stats::ScopeAggregates;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+async fn seed(relay: &TestRelay, count: usize) {
+    let drt2z = Scope::named("drt2z").unwrap();
+    let keys = Keys::generate();
+    for i in 0..count {
+        let event = EventBuilder::text_note(format!("note {}", i)).sign(&keys).await.unwrap();
+        relay.relay.store.save(&drt2z, event).await.unwrap();
+    }
+}
+
+fn of_type<'a>(messages: &'a [Value], kind: &str) -> Vec<&'a Value> {
+    messages.iter().filter(|message| message[0] == kind).collect()
+}
+
+#[tokio::test]
+async fn test_limit_above_the_max_is_clamped_with_a_notice() {
+    let relay = start_relay_with(|config| config.max_limit_per_filter = 3).await;
+    seed(&relay, 5).await;
+    let mut client = relay.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+
+    req(&mut client, "big", json!({ "kinds": [1], "limit": 100000 })).await;
+    let messages = until_eose(&mut client, "big").await;
+    assert_eq!(of_type(&messages, "EVENT").len(), 3);
+    let notices = of_type(&messages, "NOTICE");
+    assert_eq!(notices.len(), 1, "{:?}", messages);
+    let notice = notices[0][1].as_str().unwrap();
+    assert!(notice.contains("subscription big"), "{}", notice);
+    assert!(notice.contains("limits above 3"), "{}", notice);
+
+    // Within the max nothing is said
+    req(&mut client, "small", json!({ "kinds": [1], "limit": 2 })).await;
+    let messages = until_eose(&mut client, "small").await;
+    assert_eq!(of_type(&messages, "EVENT").len(), 2);
+    assert!(of_type(&messages, "NOTICE").is_empty(), "{:?}", messages);
+}
+
+#[tokio::test]
+async fn test_missing_limit_is_defaulted_on_large_scopes() {
+    let relay = start_relay_with(|config| {
+        config.default_filter_limit = 2;
+        config.default_filter_limit_above_events = 4;
+    })
+    .await;
+    seed(&relay, 5).await;
+    let mut client = relay.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+
+    // Before the stats pass sizes the scope, everything is returned
+    req(&mut client, "before", json!({ "kinds": [1] })).await;
+    let messages = until_eose(&mut client, "before").await;
+    assert_eq!(of_type(&messages, "EVENT").len(), 5);
+    assert!(of_type(&messages, "NOTICE").is_empty(), "{:?}", messages);
+
+    let aggregates = ScopeAggregates {
+        stored_events: 5,
+        events_last_hour: 5,
+        events_last_24h: 5,
+        distinct_pubkeys_last_hour: 1,
+        distinct_pubkeys_24h: 1,
+        top_kinds: Vec::new(),
+        last_event_at: None,
+    };
+    relay.relay.stats.replace(HashMap::from([("drt2z".to_string(), aggregates)]), Timestamp::now().as_u64());
+
+    req(&mut client, "after", json!({ "kinds": [1] })).await;
+    let messages = until_eose(&mut client, "after").await;
+    assert_eq!(of_type(&messages, "EVENT").len(), 2);
+    let notices = of_type(&messages, "NOTICE");
+    assert_eq!(notices.len(), 1, "{:?}", messages);
+    assert!(notices[0][1].as_str().unwrap().contains("at most 2 events"), "{:?}", notices);
+}
@@ -0,0 +1,43 @@
+/// Integration tests for relay-side first-seen timestamps
+
+mod common;
+
+use common::*;
+use nostr_sdk::prelude::*;
+use reqwest::StatusCode;
+
+async fn meta(relay: &TestRelay, id: &EventId) -> (StatusCode, serde_json::Value) {
+    let response = reqwest::get(format!("http://{}/api/events/{}/meta", relay.addr, id.to_hex())).await.unwrap();
+    let status = response.status();
+    (status, serde_json::from_str(&response.text().await.unwrap()).unwrap_or(serde_json::Value::Null))
+}
+
+#[tokio::test]
+async fn test_first_seen_is_set_once() {
+    let relay = start_relay().await;
+    let mut client = relay.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+
+    // Claims to be from long ago; the relay records when it arrived
+    let event = EventBuilder::text_note("sync me")
+        .custom_created_at(Timestamp::from(1_000))
+        .sign(&Keys::generate())
+        .await
+        .unwrap();
+    assert_eq!(meta(&relay, &event.id).await.0, StatusCode::NOT_FOUND);
+
+    let before = Timestamp::now().as_u64();
+    publish(&mut client, &event).await;
+    assert_eq!(next_message(&mut client).await[2], true);
+    let (status, first) = meta(&relay, &event.id).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(first["scope"], "drt2z");
+    let received_at = first["received_at"].as_u64().unwrap();
+    assert!(received_at >= before && received_at <= Timestamp::now().as_u64());
+
+    // Republishing doesn't move it
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    publish(&mut client, &event).await;
+    next_message(&mut client).await;
+    assert_eq!(meta(&relay, &event.id).await.1, first);
+}
@@ -0,0 +1,96 @@
+#!/usr/bin/env python3
+"""Writes geoip-test.mmdb, the GeoIP fixture for the relay's tests.
+
+An IPv4-only MaxMind DB with one network, 81.2.69.0/24, located in Boston
+(geohash drt2z at precision 5). Every other address is a miss.
+"""
+
+import struct
+from pathlib import Path
+
+NETWORK = (bytes([81, 2, 69, 0]), 24)
+RECORD = {"location": {"latitude": 42.3601, "longitude": -71.0589}}
+
+
+class Uint:
+    """An unsigned integer of a specific MaxMind DB type"""
+
+    def __init__(self, type_id, value):
+        self.type_id = type_id
+        self.value = value
+
+
+def uint16(value):
+    return Uint(5, value)
+
+
+def uint32(value):
+    return Uint(6, value)
+
+
+def uint64(value):
+    return Uint(9, value)
+
+
+def control(type_id, size):
+    assert size < 29
+    if type_id <= 7:
+        return bytes([(type_id << 5) | size])
+    return bytes([size, type_id - 7])
+
+
+def encode(value):
+    if isinstance(value, dict):
+        out = control(7, len(value))
+        for key, item in value.items():
+            out += encode(key) + encode(item)
+        return out
+    if isinstance(value, list):
+        return control(11, len(value)) + b"".join(encode(item) for item in value)
+    if isinstance(value, str):
+        data = value.encode()
+        return control(2, len(data)) + data
+    if isinstance(value, float):
+        return control(3, 8) + struct.pack(">d", value)
+    if isinstance(value, Uint):
+        data = value.value.to_bytes(max(1, (value.value.bit_length() + 7) // 8), "big")
+        return control(value.type_id, len(data)) + data
+    raise TypeError(value)
+
+
+def main():
+    address, prefix = NETWORK
+    bits = [(address[i // 8] >> (7 - i % 8)) & 1 for i in range(prefix)]
+    node_count = prefix
+    data_pointer = node_count + 16  # the only record is at offset 0
+
+    tree = b""
+    for node, bit in enumerate(bits):
+        follow = data_pointer if node == prefix - 1 else node + 1
+        records = [node_count, node_count]
+        records[bit] = follow
+        tree += b"".join(record.to_bytes(3, "big") for record in records)
+
+    metadata = {
+        "binary_format_major_version": uint16(2),
+        "binary_format_minor_version": uint16(0),
+        "build_epoch": uint64(1700000000),
+        "database_type": "GeoIP2-City",
+        "description": {"en": "geohashed-relay test fixture"},
+        "ip_version": uint16(4),
+        "languages": ["en"],
+        "node_count": uint32(node_count),
+        "record_size": uint16(24),
+    }
+    contents = (
+        tree
+        + bytes(16)
+        + encode(RECORD)
+        + b"\xab\xcd\xefMaxMind.com"
+        + encode(metadata)
+    )
+    Path(__file__).with_name("geoip-test.mmdb").write_bytes(contents)
+
+
+if __name__ == "__main__":
+    main()
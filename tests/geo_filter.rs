@@ -0,0 +1,53 @@
+/// Integration tests for REQs with coordinates on the root relay
+
+mod common;
+
+use common::*;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde_json::json;
+
+async fn start() -> TestRelay {
+    start_relay_with(|config| {
+        config.geo_filter_extension = true;
+        config.geohash_read_auth = true;
+    })
+    .await
+}
+
+/// Messages until the AUTH challenge, which comes with the welcome
+async fn challenge(client: &mut Client) -> String {
+    loop {
+        let message = next_message(client).await;
+        if message[0] == "AUTH" {
+            return message[1].as_str().unwrap().to_string();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_coordinates_do_not_skip_the_cells_read_auth() {
+    let relay = start().await;
+    let keys = Keys::generate();
+    let note = EventBuilder::text_note("in 9q8yy").sign(&keys).await.unwrap();
+    relay.relay.store.save(&Scope::named("9q8yy").unwrap(), note.clone()).await.unwrap();
+    let filter = json!({ "kinds": [1], "#g": ["latlon:37.77,-122.41"] });
+
+    let mut client = relay.connect("example.com").await;
+    let nonce = challenge(&mut client).await;
+    req(&mut client, "before", filter.clone()).await;
+    let reply = next_message(&mut client).await;
+    assert_eq!(reply[0], "CLOSED", "{:?}", reply);
+    assert!(reply[2].as_str().unwrap().starts_with("auth-required:"), "{:?}", reply);
+
+    let relay_url = RelayUrl::parse("ws://example.com").unwrap();
+    let auth = EventBuilder::auth(nonce, relay_url).sign(&keys).await.unwrap();
+    send(&mut client, json!(["AUTH", auth])).await;
+    let ok = next_message(&mut client).await;
+    assert_eq!(ok[2], true, "{:?}", ok);
+
+    req(&mut client, "after", filter).await;
+    let messages = until_eose(&mut client, "after").await;
+    let ids: Vec<&str> = messages.iter().filter(|m| m[0] == "EVENT").map(|m| m[2]["id"].as_str().unwrap()).collect();
+    assert_eq!(ids, [note.id.to_hex()]);
+}
@@ -0,0 +1,79 @@
+/// Integration tests for reading root's global kinds from cells
+
+mod common;
+
+use common::*;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde_json::{json, Value};
+
+fn events(messages: &[Value]) -> Vec<String> {
+    messages
+        .iter()
+        .filter(|message| message[0] == "EVENT")
+        .map(|message| message[2]["content"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_root_profile_is_returned_on_a_cell_subscription() {
+    let relay = start_relay().await;
+    let keys = Keys::generate();
+    let profile = EventBuilder::new(Kind::Metadata, r#"{"name":"alice"}"#).sign(&keys).await.unwrap();
+    let root_note = EventBuilder::text_note("root note").sign(&keys).await.unwrap();
+    let cell_note = EventBuilder::text_note("cell note").sign(&keys).await.unwrap();
+    relay.relay.store.save(&Scope::Default, profile).await.unwrap();
+    relay.relay.store.save(&Scope::Default, root_note).await.unwrap();
+    relay.relay.store.save(&Scope::named("drt2z").unwrap(), cell_note).await.unwrap();
+
+    let mut client = relay.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+    let author = keys.public_key().to_hex();
+
+    req(&mut client, "profile", json!({ "kinds": [0], "authors": [author] })).await;
+    assert_eq!(events(&until_eose(&mut client, "profile").await), [r#"{"name":"alice"}"#]);
+
+    // Mixed kinds: root's profile and the cell's note, never root's note
+    req(&mut client, "mixed", json!({ "kinds": [0, 1], "authors": [author] })).await;
+    let mut contents = events(&until_eose(&mut client, "mixed").await);
+    contents.sort();
+    assert_eq!(contents, ["cell note", r#"{"name":"alice"}"#]);
+
+    // The limit covers both scopes together
+    req(&mut client, "limited", json!({ "kinds": [0, 1], "authors": [author], "limit": 1 })).await;
+    assert_eq!(events(&until_eose(&mut client, "limited").await).len(), 1);
+
+    // Without kinds, root still only contributes global kinds
+    req(&mut client, "any", json!({ "authors": [author] })).await;
+    let contents = events(&until_eose(&mut client, "any").await);
+    assert!(contents.contains(&"cell note".to_string()), "{:?}", contents);
+    assert!(!contents.contains(&"root note".to_string()), "{:?}", contents);
+}
+
+#[tokio::test]
+async fn test_refused_and_hidden_root_events_are_not_sent() {
+    let relay = start_relay().await;
+    let keys = Keys::generate();
+    let expired = EventBuilder::new(Kind::Metadata, r#"{"name":"gone"}"#)
+        .tag(Tag::expiration(Timestamp::from(Timestamp::now().as_u64() - 60)))
+        .sign(&keys)
+        .await
+        .unwrap();
+    let contacts = EventBuilder::new(Kind::ContactList, "").sign(&keys).await.unwrap();
+    relay.relay.store.save(&Scope::Default, expired).await.unwrap();
+    relay.relay.store.save(&Scope::Default, contacts).await.unwrap();
+
+    let mut client = relay.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+    let author = keys.public_key().to_hex();
+
+    // Past its NIP-40 expiration, as relay_builder would hide it
+    req(&mut client, "profile", json!({ "kinds": [0], "authors": [author] })).await;
+    assert!(events(&until_eose(&mut client, "profile").await).is_empty());
+
+    // Over the #g limit: refused before root's events go out
+    let cells: Vec<String> = "0123456789bcdefgh".chars().map(|c| format!("drt2{}", c)).collect();
+    req(&mut client, "too-many", json!({ "kinds": [3], "authors": [author], "#g": cells })).await;
+    let reply = next_message(&mut client).await;
+    assert_eq!(reply[0], "CLOSED", "{:?}", reply);
+}
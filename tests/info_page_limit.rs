@@ -0,0 +1,31 @@
+/// Integration tests for the per-IP info page rate limit
+
+mod common;
+
+use common::*;
+use reqwest::StatusCode;
+
+#[tokio::test]
+async fn test_info_page_flood_gets_429_but_websockets_still_connect() {
+    let relay = start_relay_with(|config| config.info_page_requests_per_minute = 5).await;
+    let http = reqwest::Client::new();
+
+    let mut statuses = Vec::new();
+    for _ in 0..20 {
+        let response = http.get(format!("http://{}/", relay.addr)).send().await.unwrap();
+        statuses.push(response.status());
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            assert!(response.headers().contains_key("retry-after"));
+        }
+    }
+    assert_eq!(statuses.iter().filter(|status| **status == StatusCode::OK).count(), 5);
+    assert_eq!(statuses.iter().filter(|status| **status == StatusCode::TOO_MANY_REQUESTS).count(), 15);
+
+    // Health checks and upgrades from the same address are not limited
+    let health = http.get(format!("http://{}/health", relay.addr)).send().await.unwrap();
+    assert_eq!(health.status(), StatusCode::OK);
+    for _ in 0..3 {
+        let mut client = relay.connect("drt2z.example.com").await;
+        assert_eq!(next_message(&mut client).await[0], "NOTICE");
+    }
+}
@@ -0,0 +1,60 @@
+/// Integration tests for the IP allowlist and denylist on websocket upgrades
+
+mod common;
+
+use common::*;
+use geohashed_relay::ip_filter::parse_cidr_list;
+use tokio_tungstenite::{connect_async, tungstenite::{client::IntoClientRequest, Error}};
+
+/// Attempts a websocket upgrade, optionally as a client behind this (local)
+/// proxy, and returns the HTTP status on refusal
+async fn try_connect(relay: &TestRelay, forwarded_for: Option<&str>) -> Result<(), u16> {
+    let mut request = format!("ws://{}/", relay.addr).into_client_request().unwrap();
+    request.headers_mut().insert("host", "drt2z.example.com".parse().unwrap());
+    if let Some(client) = forwarded_for {
+        request.headers_mut().insert("x-forwarded-for", client.parse().unwrap());
+    }
+    match connect_async(request).await {
+        Ok(_) => Ok(()),
+        Err(Error::Http(response)) => Err(response.status().as_u16()),
+        Err(e) => panic!("unexpected error: {}", e),
+    }
+}
+
+async fn relay_with_lists(allow: &str, deny: &str) -> TestRelay {
+    start_relay_with(|config| {
+        config.ip_allowlist = parse_cidr_list(allow).unwrap();
+        config.ip_denylist = parse_cidr_list(deny).unwrap();
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_no_lists_admit_everyone() {
+    let relay = relay_with_lists("", "").await;
+    assert_eq!(try_connect(&relay, None).await, Ok(()));
+    assert_eq!(try_connect(&relay, Some("203.0.113.9")).await, Ok(()));
+}
+
+#[tokio::test]
+async fn test_allowlist_refuses_others() {
+    let relay = relay_with_lists("10.0.0.0/8, 127.0.0.1", "").await;
+    assert_eq!(try_connect(&relay, None).await, Ok(()));
+    assert_eq!(try_connect(&relay, Some("10.1.2.3")).await, Ok(()));
+    assert_eq!(try_connect(&relay, Some("203.0.113.9")).await, Err(403));
+}
+
+#[tokio::test]
+async fn test_denylist_refuses_listed() {
+    let relay = relay_with_lists("", "203.0.113.0/24, 2001:db8::/32").await;
+    assert_eq!(try_connect(&relay, Some("203.0.113.9")).await, Err(403));
+    assert_eq!(try_connect(&relay, Some("2001:db8::1")).await, Err(403));
+    assert_eq!(try_connect(&relay, Some("198.51.100.1")).await, Ok(()));
+}
+
+#[tokio::test]
+async fn test_deny_wins_over_allow() {
+    let relay = relay_with_lists("10.0.0.0/8", "10.6.0.0/16").await;
+    assert_eq!(try_connect(&relay, Some("10.1.2.3")).await, Ok(()));
+    assert_eq!(try_connect(&relay, Some("10.6.0.1")).await, Err(403));
+}
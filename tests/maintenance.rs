@@ -0,0 +1,98 @@
+/// Integration tests for toggling maintenance and read-only mode on a running relay
+
+mod common;
+
+use common::*;
+use nostr_sdk::prelude::*;
+use reqwest::StatusCode;
+
+const ADMIN_TOKEN: &str = "s3cret";
+
+async fn set_maintenance(relay: &TestRelay, body: serde_json::Value) {
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/api/maintenance", relay.addr))
+        .bearer_auth(ADMIN_TOKEN)
+        .header("content-type", "application/json")
+        .body(body.to_string())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+async fn note(keys: &Keys) -> Event {
+    EventBuilder::text_note("during maintenance").sign(keys).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_maintenance_toggles_without_restart() {
+    let relay = start_relay_with(|config| config.admin_token = Some(ADMIN_TOKEN.to_string())).await;
+    let keys = Keys::generate();
+    let mut client = relay.connect("example.com").await;
+    next_message(&mut client).await;
+
+    let before = note(&keys).await;
+    publish(&mut client, &before).await;
+    assert_eq!(next_message(&mut client).await[2], true);
+
+    set_maintenance(&relay, serde_json::json!({ "enabled": true, "message": "migrating storage" })).await;
+    publish(&mut client, &note(&keys).await).await;
+    let ok = next_message(&mut client).await;
+    assert_eq!(ok[2], false);
+    assert_eq!(ok[3], "error: maintenance — migrating storage [maintenance]");
+
+    // Reads keep working on the same connection
+    req(&mut client, "reads", serde_json::json!({ "ids": [before.id.to_hex()] })).await;
+    let messages = until_eose(&mut client, "reads").await;
+    assert_eq!(messages[0][2]["id"], before.id.to_hex());
+
+    set_maintenance(&relay, serde_json::json!({ "enabled": false })).await;
+    publish(&mut client, &note(&keys).await).await;
+    assert_eq!(next_message(&mut client).await[2], true);
+}
+
+async fn clear_read_only(relay: &TestRelay) -> serde_json::Value {
+    let response = reqwest::Client::new()
+        .delete(format!("http://{}/api/read-only", relay.addr))
+        .bearer_auth(ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    serde_json::from_str(&response.text().await.unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn test_read_only_until_cleared() {
+    let relay = start_relay_with(|config| {
+        config.admin_token = Some(ADMIN_TOKEN.to_string());
+        config.read_only = true;
+    })
+    .await;
+    let keys = Keys::generate();
+    let mut client = relay.connect("example.com").await;
+    next_message(&mut client).await;
+
+    let refused = note(&keys).await;
+    publish(&mut client, &refused).await;
+    let ok = next_message(&mut client).await;
+    assert_eq!(ok[2], false);
+    assert_eq!(ok[3], "error: relay is read-only [read-only]");
+
+    // Subscriptions behave normally
+    req(&mut client, "reads", serde_json::json!({ "kinds": [1] })).await;
+    assert_eq!(until_eose(&mut client, "reads").await.len(), 1);
+
+    // Maintenance wins while both are on, and outlasts clearing read-only
+    set_maintenance(&relay, serde_json::json!({ "enabled": true, "message": "migrating storage" })).await;
+    publish(&mut client, &note(&keys).await).await;
+    assert_eq!(next_message(&mut client).await[3], "error: maintenance — migrating storage [maintenance]");
+    assert_eq!(clear_read_only(&relay).await["was_read_only"], true);
+    publish(&mut client, &note(&keys).await).await;
+    assert_eq!(next_message(&mut client).await[3], "error: maintenance — migrating storage [maintenance]");
+
+    set_maintenance(&relay, serde_json::json!({ "enabled": false })).await;
+    publish(&mut client, &refused).await;
+    assert_eq!(next_message(&mut client).await[2], true);
+    assert_eq!(clear_read_only(&relay).await["was_read_only"], false);
+}
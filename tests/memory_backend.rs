@@ -0,0 +1,68 @@
+/// Integration tests for the memory storage backend
+
+mod common;
+
+use common::*;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use geohashed_relay::config::StorageBackend;
+use std::time::Duration;
+
+const CAPACITY: usize = 3;
+
+#[tokio::test]
+async fn test_oldest_events_fall_out_when_the_ring_wraps() {
+    let relay = start_relay_with(|config| {
+        config.storage_backend = StorageBackend::Memory;
+        config.memory_events_per_scope = CAPACITY;
+    })
+    .await;
+    let drt2z = Scope::named("drt2z").unwrap();
+    let keys = Keys::generate();
+    let mut client = relay.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+
+    let mut published = Vec::new();
+    for i in 0..5u64 {
+        let event = EventBuilder::text_note(format!("pop-up {}", i))
+            .custom_created_at(Timestamp::from(1_700_000_000 + i))
+            .sign(&keys)
+            .await
+            .unwrap();
+        publish(&mut client, &event).await;
+        assert_eq!(next_message(&mut client).await[2], true);
+        published.push(event);
+    }
+
+    let store = relay.relay.store.clone();
+    for _ in 0..100 {
+        if store.count(&drt2z, Filter::new()).await.unwrap() == CAPACITY {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    req(&mut client, "recent", serde_json::json!({ "kinds": [1] })).await;
+    let messages = until_eose(&mut client, "recent").await;
+    let ids: Vec<String> = messages
+        .iter()
+        .filter(|message| message[0] == "EVENT")
+        .map(|message| message[2]["id"].as_str().unwrap().to_string())
+        .collect();
+    let newest: Vec<String> = published.iter().rev().take(CAPACITY).map(|event| event.id.to_hex()).collect();
+    assert_eq!(ids, newest);
+
+    // Live delivery is unaffected
+    req(&mut client, "live", serde_json::json!({ "kinds": [1], "limit": 0 })).await;
+    until_eose(&mut client, "live").await;
+    let live = EventBuilder::text_note("live").sign(&keys).await.unwrap();
+    publish(&mut client, &live).await;
+    let mut delivered = false;
+    for _ in 0..2 {
+        let message = next_message(&mut client).await;
+        if message[0] == "EVENT" && message[1] == "live" {
+            delivered = message[2]["id"] == live.id.to_hex();
+        }
+    }
+    assert!(delivered);
+}
@@ -0,0 +1,28 @@
+/// Integration tests for the Prometheus endpoint
+
+mod common;
+
+use common::*;
+use geohashed_relay::prometheus;
+use nostr_sdk::prelude::*;
+
+#[tokio::test]
+async fn test_metrics_report_accepted_events() {
+    prometheus::install();
+    let relay = start_relay_with(|config| config.metrics_enabled = true).await;
+    let mut client = relay.connect("example.com").await;
+    next_message(&mut client).await;
+
+    let note = EventBuilder::text_note("counted").sign(&Keys::generate()).await.unwrap();
+    publish(&mut client, &note).await;
+    let ok = next_message(&mut client).await;
+    assert_eq!(ok[2], true, "{:?}", ok);
+
+    let body = reqwest::get(format!("http://{}/metrics", relay.addr)).await.unwrap().text().await.unwrap();
+    let accepted = body
+        .lines()
+        .find(|line| line.starts_with("relay_events_accepted_total{") && line.contains(r#"kind="1""#))
+        .unwrap_or_else(|| panic!("no accepted count in {}", body));
+    let count: f64 = accepted.rsplit(' ').next().unwrap().parse().unwrap();
+    assert!(count >= 1.0, "{}", accepted);
+}
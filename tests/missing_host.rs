@@ -0,0 +1,65 @@
+/// Integration tests for websocket upgrades sent without a Host header
+///
+/// Regular clients always send one, so these speak HTTP/1.1 over a raw
+/// socket to leave it out.
+
+mod common;
+
+use common::*;
+use futures::{SinkExt, StreamExt};
+use geohashed_relay::config::MissingHostPolicy;
+use geohashed_relay::reject::reason_code;
+use nostr_sdk::prelude::*;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{tungstenite::protocol::Role, WebSocketStream};
+
+/// Sends an upgrade request without Host and returns the response head
+/// along with the socket, positioned at the start of the body or frames
+async fn upgrade_without_host(relay: &TestRelay) -> (String, TcpStream) {
+    let mut stream = TcpStream::connect(relay.addr).await.unwrap();
+    let request = "GET / HTTP/1.1\r\n\
+        Connection: Upgrade\r\n\
+        Upgrade: websocket\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+    (String::from_utf8(head).unwrap(), stream)
+}
+
+#[tokio::test]
+async fn test_upgrade_without_host_rejected() {
+    let relay = start_relay_with(|config| config.missing_host_policy = MissingHostPolicy::Reject).await;
+
+    let (head, _) = upgrade_without_host(&relay).await;
+    assert!(head.starts_with("HTTP/1.1 400"), "{}", head);
+}
+
+#[tokio::test]
+async fn test_upgrade_without_host_served_as_root() {
+    let relay = start_relay().await;
+
+    let (head, stream) = upgrade_without_host(&relay).await;
+    assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+
+    let mut client = WebSocketStream::from_raw_socket(stream, Role::Client, None).await;
+    // Welcome notice
+    client.next().await.unwrap().unwrap();
+
+    // Root refuses geotagged events, which shows where the connection landed
+    let event = EventBuilder::text_note("hello")
+        .tags(vec![Tag::custom(TagKind::Custom("g".into()), vec!["drt2z".to_string()])])
+        .sign(&Keys::generate())
+        .await
+        .unwrap();
+    client.send(json!(["EVENT", event]).to_string().into()).await.unwrap();
+    let ok: Value = serde_json::from_str(client.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+    assert_eq!(ok[2], false);
+    assert_eq!(reason_code(ok[3].as_str().unwrap()), Some("root-rejects-geotagged"));
+}
@@ -0,0 +1,93 @@
+/// Integration tests for switching built-in middleware off
+
+mod common;
+
+use common::*;
+use geohashed_relay::config::TtlMode;
+use geohashed_relay::relay::build_relay;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde_json::json;
+
+async fn expired_note(relay: &TestRelay) -> Event {
+    let past = Timestamp::from(Timestamp::now().as_u64() - 3600);
+    let event = EventBuilder::text_note("old news")
+        .tag(Tag::expiration(past))
+        .sign(&Keys::generate())
+        .await
+        .unwrap();
+    relay.relay.store.save(&Scope::named("drt2z").unwrap(), event.clone()).await.unwrap();
+    event
+}
+
+async fn returned_ids(relay: &TestRelay) -> Vec<String> {
+    let mut client = relay.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+    req(&mut client, "feed", json!({ "kinds": [1] })).await;
+    until_eose(&mut client, "feed")
+        .await
+        .into_iter()
+        .filter(|message| message[0] == "EVENT")
+        .map(|message| message[2]["id"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_expired_events_visible_only_without_nip40() {
+    let relay = start_relay().await;
+    expired_note(&relay).await;
+    assert!(returned_ids(&relay).await.is_empty());
+
+    let relay = start_relay_with(|config| config.enable_nip40_expiration = false).await;
+    let event = expired_note(&relay).await;
+    assert_eq!(returned_ids(&relay).await, [event.id.to_hex()]);
+}
+
+async fn accepted(relay: &TestRelay, count: usize) -> usize {
+    let keys = Keys::generate();
+    let mut client = relay.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+    let mut accepted = 0;
+    for i in 0..count {
+        let event = EventBuilder::text_note(format!("note {}", i)).sign(&keys).await.unwrap();
+        publish(&mut client, &event).await;
+        if next_message(&mut client).await[2] == true {
+            accepted += 1;
+        }
+    }
+    accepted
+}
+
+#[tokio::test]
+async fn test_rate_limit_can_be_switched_off() {
+    let relay = start_relay_with(|config| config.events_per_minute = 2).await;
+    assert_eq!(accepted(&relay, 4).await, 2);
+
+    let relay = start_relay_with(|config| {
+        config.events_per_minute = 2;
+        config.enable_rate_limit = false;
+    })
+    .await;
+    assert_eq!(accepted(&relay, 4).await, 4);
+}
+
+#[tokio::test]
+async fn test_other_parts_off_still_serve() {
+    let relay = start_relay_with(|config| {
+        config.enable_logger_middleware = false;
+        config.enable_error_handling = false;
+    })
+    .await;
+    assert_eq!(accepted(&relay, 1).await, 1);
+}
+
+#[tokio::test]
+async fn test_strict_ttl_without_nip40_refuses_to_start() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = test_config(&dir);
+    config.geohash_max_ttl_days = 7;
+    config.geohash_ttl_mode = TtlMode::Strict;
+    config.enable_nip40_expiration = false;
+    let err = build_relay(&config, Keys::generate()).await.err().expect("built an invalid chain");
+    assert!(err.to_string().contains("ENABLE_NIP40_EXPIRATION"), "{}", err);
+}
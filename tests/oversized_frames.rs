@@ -0,0 +1,57 @@
+/// Integration tests for the websocket message size cap
+
+mod common;
+
+use common::*;
+use futures::{SinkExt, StreamExt};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::Message;
+
+const MAX_EVENT_SIZE: usize = 1024;
+
+async fn small_relay() -> TestRelay {
+    start_relay_with(|config| config.max_event_size = MAX_EVENT_SIZE).await
+}
+
+/// Waits for the relay to end the connection, returning its close code
+async fn closed(client: &mut Client) -> Option<CloseCode> {
+    loop {
+        let message = tokio::time::timeout(MESSAGE_TIMEOUT, client.next())
+            .await
+            .expect("relay kept the connection open");
+        match message {
+            Some(Ok(Message::Close(frame))) => return frame.map(|frame| frame.code),
+            Some(Ok(_)) => continue,
+            Some(Err(_)) | None => return None,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_oversized_frame_closes_connection() {
+    let relay = small_relay().await;
+    let mut client = relay.connect("example.com").await;
+    next_message(&mut client).await;
+
+    let event = EventBuilder::text_note("x".repeat(64 * 1024)).sign(&Keys::generate()).await.unwrap();
+    // The relay may close before the whole frame is written
+    let _ = client.send(Message::Text(serde_json::json!(["EVENT", event]).to_string().into())).await;
+    assert_eq!(closed(&mut client).await, Some(CloseCode::Size));
+
+    let stored = relay.relay.store.query(&Scope::Default, Filter::new()).await.unwrap();
+    assert!(stored.is_empty());
+}
+
+#[tokio::test]
+async fn test_event_within_limit_is_accepted() {
+    let relay = small_relay().await;
+    let mut client = relay.connect("example.com").await;
+    next_message(&mut client).await;
+
+    let event = EventBuilder::text_note("x".repeat(MAX_EVENT_SIZE / 2)).sign(&Keys::generate()).await.unwrap();
+    publish(&mut client, &event).await;
+    let ok = next_message(&mut client).await;
+    assert_eq!(ok[2], true, "{:?}", ok);
+}
@@ -0,0 +1,108 @@
+/// Integration tests checking `/api/policy` against what the relay enforces
+
+mod common;
+
+use common::*;
+use geohashed_relay::config::TtlMode;
+use geohashed_relay::reject::reason_code;
+use nostr_sdk::prelude::*;
+use reqwest::{header, StatusCode};
+use serde_json::Value;
+
+async fn policy(relay: &TestRelay, host: &str, query: &str) -> (Value, String) {
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/api/policy{}", relay.addr, query))
+        .header(header::HOST, host)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag = response.headers()[header::ETAG].to_str().unwrap().to_string();
+    (serde_json::from_str(&response.text().await.unwrap()).unwrap(), etag)
+}
+
+async fn publish_ok(client: &mut Client, event: &Event) -> Value {
+    publish(client, event).await;
+    next_message(client).await
+}
+
+#[tokio::test]
+async fn test_policy_matches_enforced_kinds_and_rate_limit() {
+    let relay = start_relay_with(|config| {
+        config.geohash_allowed_kinds = Some(vec![1]);
+        config.scope_events_per_minute.insert("drt2z".to_string(), 2);
+    })
+    .await;
+    let (cell, etag) = policy(&relay, "drt2z.example.com", "").await;
+    assert_eq!(cell["scope"], "drt2z");
+    assert_eq!(cell["kinds"]["allowed"], serde_json::json!([1]));
+    assert_eq!(cell["writes"]["events_per_minute"], 2);
+    assert_eq!(etag, format!("\"{}\"", cell["policy_version"].as_str().unwrap()));
+    // The root domain can ask about the same cell
+    assert_eq!(policy(&relay, "example.com", "?scope=drt2z").await.0, cell);
+
+    let keys = Keys::generate();
+    let mut client = relay.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+    let reaction = EventBuilder::new(Kind::Reaction, "+").sign(&keys).await.unwrap();
+    let ok = publish_ok(&mut client, &reaction).await;
+    assert_eq!(reason_code(ok[3].as_str().unwrap()), Some("kind-not-allowed"));
+    for i in 0..2 {
+        let note = EventBuilder::text_note(format!("note {}", i)).sign(&keys).await.unwrap();
+        assert_eq!(publish_ok(&mut client, &note).await[2], true);
+    }
+    let note = EventBuilder::text_note("one too many").sign(&keys).await.unwrap();
+    let ok = publish_ok(&mut client, &note).await;
+    assert_eq!(reason_code(ok[3].as_str().unwrap()), Some("rate-limited"));
+
+    // Root takes every kind
+    let (root, _) = policy(&relay, "example.com", "").await;
+    assert!(root["kinds"]["allowed"].is_null());
+    let mut client = relay.connect("example.com").await;
+    next_message(&mut client).await;
+    assert_eq!(publish_ok(&mut client, &reaction).await[2], true);
+}
+
+#[tokio::test]
+async fn test_policy_matches_enforced_ttl() {
+    let relay = start_relay_with(|config| {
+        config.geohash_max_ttl_days = 1;
+        config.geohash_ttl_mode = TtlMode::Strict;
+    })
+    .await;
+    let (cell, _) = policy(&relay, "drt2z.example.com", "").await;
+    assert_eq!(cell["retention"]["max_ttl_secs"], 86_400);
+    assert_eq!(cell["retention"]["ttl_mode"], "strict");
+
+    let keys = Keys::generate();
+    let mut client = relay.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+    let note = EventBuilder::text_note("forever").sign(&keys).await.unwrap();
+    let ok = publish_ok(&mut client, &note).await;
+    assert_eq!(reason_code(ok[3].as_str().unwrap()), Some("expiration-required"));
+    let expires = Timestamp::now() + 3_600;
+    let note = EventBuilder::text_note("for an hour").tag(Tag::expiration(expires)).sign(&keys).await.unwrap();
+    assert_eq!(publish_ok(&mut client, &note).await[2], true);
+}
+
+#[tokio::test]
+async fn test_unchanged_policy_is_not_resent() {
+    let relay = start_relay().await;
+    let (_, etag) = policy(&relay, "drt2z.example.com", "").await;
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/api/policy", relay.addr))
+        .header(header::HOST, "drt2z.example.com")
+        .header(header::IF_NONE_MATCH, &etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/api/policy?scope=zzzz!", relay.addr))
+        .header(header::HOST, "example.com")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
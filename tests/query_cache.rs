@@ -0,0 +1,67 @@
+/// Integration tests for REQs answered from the query cache
+
+mod common;
+
+use common::*;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde_json::{json, Value};
+
+async fn start() -> TestRelay {
+    start_relay_with(|config| {
+        config.query_cache_size = 16;
+        config.query_cache_ttl_secs = 60;
+        config.geohash_read_auth = true;
+    })
+    .await
+}
+
+/// Messages until the AUTH challenge, which comes with the welcome
+async fn challenge(client: &mut Client) -> String {
+    loop {
+        let message = next_message(client).await;
+        if message[0] == "AUTH" {
+            return message[1].as_str().unwrap().to_string();
+        }
+    }
+}
+
+async fn authenticate(client: &mut Client, keys: &Keys, challenge: &str) {
+    let relay_url = RelayUrl::parse("ws://drt2z.example.com").unwrap();
+    let auth = EventBuilder::auth(challenge, relay_url).sign(keys).await.unwrap();
+    send(client, json!(["AUTH", auth])).await;
+    let ok = next_message(client).await;
+    assert_eq!(ok[2], true, "{:?}", ok);
+}
+
+fn event_ids(messages: &[Value]) -> Vec<String> {
+    messages
+        .iter()
+        .filter(|message| message[0] == "EVENT")
+        .map(|message| message[2]["id"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_warm_cache_does_not_skip_read_auth() {
+    let relay = start().await;
+    let keys = Keys::generate();
+    let note = EventBuilder::text_note("cell note").sign(&keys).await.unwrap();
+    relay.relay.store.save(&Scope::named("drt2z").unwrap(), note.clone()).await.unwrap();
+    let filter = json!({ "kinds": [1], "limit": 10 });
+
+    // An authenticated reader warms the cache
+    let mut reader = relay.connect("drt2z.example.com").await;
+    let nonce = challenge(&mut reader).await;
+    authenticate(&mut reader, &keys, &nonce).await;
+    req(&mut reader, "warm", filter.clone()).await;
+    assert_eq!(event_ids(&until_eose(&mut reader, "warm").await), [note.id.to_hex()]);
+
+    // Anyone else is still told to authenticate first
+    let mut stranger = relay.connect("drt2z.example.com").await;
+    challenge(&mut stranger).await;
+    req(&mut stranger, "cold", filter).await;
+    let reply = next_message(&mut stranger).await;
+    assert_eq!(reply[0], "CLOSED", "{:?}", reply);
+    assert!(reply[2].as_str().unwrap().starts_with("auth-required:"), "{:?}", reply);
+}
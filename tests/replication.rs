@@ -0,0 +1,162 @@
+/// Integration tests for leader-follower replication between two relays
+
+mod common;
+
+use common::*;
+use geohashed_relay::vanish::{ALL_RELAYS, REQUEST_TO_VANISH_KIND};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use reqwest::StatusCode;
+use std::time::Duration;
+
+const TOKEN: &str = "replication-s3cret";
+
+async fn note(keys: &Keys, geohash: &str) -> Event {
+    EventBuilder::new(Kind::TextNote, "replicate me")
+        .tags(vec![Tag::custom(TagKind::Custom("g".into()), vec![geohash.to_string()])])
+        .sign(keys)
+        .await
+        .unwrap()
+}
+
+async fn start_follower(leader: &TestRelay) -> TestRelay {
+    let leader_url = format!("http://{}", leader.addr);
+    start_relay_with(|config| {
+        config.replication_token = Some(TOKEN.to_string());
+        config.replicate_from = Some(leader_url);
+    })
+    .await
+}
+
+/// Waits until `relay` has stored `id` in `scope`
+async fn wait_for_event(relay: &TestRelay, scope: &Scope, id: EventId) {
+    for _ in 0..100 {
+        let found = relay.relay.store.query(scope, Filter::new().id(id)).await.unwrap();
+        if !found.is_empty() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("event {} never reached the follower", id);
+}
+
+async fn health(relay: &TestRelay) -> serde_json::Value {
+    let body = reqwest::get(format!("http://{}/health", relay.addr)).await.unwrap().text().await.unwrap();
+    serde_json::from_str(&body).unwrap()
+}
+
+#[tokio::test]
+async fn test_events_published_to_leader_reach_follower() {
+    let leader = start_relay_with(|config| config.replication_token = Some(TOKEN.to_string())).await;
+    let keys = Keys::generate();
+    let drt2z = Scope::named("drt2z").unwrap();
+    let mut client = leader.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+
+    // Stored before the follower exists, so it arrives through catch-up
+    let earlier = note(&keys, "drt2z").await;
+    publish(&mut client, &earlier).await;
+    assert_eq!(next_message(&mut client).await[2], true);
+
+    let follower = start_follower(&leader).await;
+    wait_for_event(&follower, &drt2z, earlier.id).await;
+
+    let live = note(&keys, "drt2z").await;
+    publish(&mut client, &live).await;
+    assert_eq!(next_message(&mut client).await[2], true);
+    wait_for_event(&follower, &drt2z, live.id).await;
+
+    // Clients of the follower can read it
+    let mut reader = follower.connect("drt2z.example.com").await;
+    next_message(&mut reader).await;
+    req(&mut reader, "replicated", serde_json::json!({ "ids": [live.id.to_hex()] })).await;
+    let messages = until_eose(&mut reader, "replicated").await;
+    assert_eq!(messages[0][0], "EVENT");
+    assert_eq!(messages[0][2]["id"], live.id.to_hex());
+
+    let replica = health(&follower).await;
+    assert_eq!(replica["status"], "ok");
+    assert_eq!(replica["replication"]["connected"], true);
+    assert!(replica["replication"]["lag_secs"].is_u64());
+    assert!(replica["replication"]["applied_events"].as_u64().unwrap() >= 2);
+    assert!(health(&leader).await.get("replication").is_none());
+}
+
+#[tokio::test]
+async fn test_follower_rejects_writes() {
+    let leader = start_relay_with(|config| config.replication_token = Some(TOKEN.to_string())).await;
+    let follower = start_follower(&leader).await;
+    let mut client = follower.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+
+    publish(&mut client, &note(&Keys::generate(), "drt2z").await).await;
+    let ok = next_message(&mut client).await;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().contains("this relay is a read-only replica"));
+}
+
+#[tokio::test]
+async fn test_stream_requires_token() {
+    let leader = start_relay_with(|config| config.replication_token = Some(TOKEN.to_string())).await;
+    let url = format!("http://{}/replication/stream", leader.addr);
+    let client = reqwest::Client::new();
+    let response = client
+        .post(&url)
+        .bearer_auth("wrong")
+        .header("content-type", "application/json")
+        .body("{}")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Not served at all without a token
+    let standalone = start_relay().await;
+    let response = client
+        .post(format!("http://{}/replication/stream", standalone.addr))
+        .bearer_auth(TOKEN)
+        .header("content-type", "application/json")
+        .body("{}")
+        .send()
+        .await
+        .unwrap();
+    assert_ne!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_requests_to_vanish_reach_follower() {
+    let leader = start_relay_with(|config| config.replication_token = Some(TOKEN.to_string())).await;
+    let (leaving, staying) = (Keys::generate(), Keys::generate());
+    let drt2z = Scope::named("drt2z").unwrap();
+    let mut client = leader.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+
+    let gone = note(&leaving, "drt2z").await;
+    let kept = note(&staying, "drt2z").await;
+    for event in [&gone, &kept] {
+        publish(&mut client, event).await;
+        assert_eq!(next_message(&mut client).await[2], true);
+    }
+    let follower = start_follower(&leader).await;
+    wait_for_event(&follower, &drt2z, gone.id).await;
+    wait_for_event(&follower, &drt2z, kept.id).await;
+
+    let request = EventBuilder::new(Kind::from(REQUEST_TO_VANISH_KIND), "")
+        .tag(Tag::parse(["relay", ALL_RELAYS]).unwrap())
+        .sign(&leaving)
+        .await
+        .unwrap();
+    publish(&mut client, &request).await;
+    assert_eq!(next_message(&mut client).await[2], true);
+
+    let store = follower.relay.store.clone();
+    let mut remaining = Vec::new();
+    for _ in 0..100 {
+        remaining = store.query(&drt2z, Filter::new()).await.unwrap();
+        if remaining.len() == 1 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(remaining.iter().map(|event| event.id).collect::<Vec<_>>(), vec![kept.id]);
+}
@@ -0,0 +1,87 @@
+/// Integration tests for relays built with their own scope policy
+
+mod common;
+
+use common::*;
+use geohashed_relay::reject::reason_code;
+use geohashed_relay::scope_policy::TeamScopePolicy;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde_json::{json, Value};
+
+async fn publish_note(client: &mut Client, event: &Event) -> Value {
+    publish(client, event).await;
+    next_message(client).await
+}
+
+async fn connect(relay: &TestRelay, host: &str) -> Client {
+    let mut client = relay.connect(host).await;
+    next_message(&mut client).await;
+    client
+}
+
+#[tokio::test]
+async fn test_team_subdomains_store_their_own_events() {
+    let relay = start_relay_with_policy(TeamScopePolicy::new(["acme"], 60)).await;
+    let keys = Keys::generate();
+    let note = EventBuilder::text_note("standup at ten").sign(&keys).await.unwrap();
+
+    let mut acme = connect(&relay, "acme.example.com").await;
+    let ok = publish_note(&mut acme, &note).await;
+    assert_eq!(ok[2], true, "{:?}", ok);
+    let acme_scope = Scope::named("acme").unwrap();
+    assert_eq!(relay.relay.store.count(&acme_scope, Filter::new()).await.unwrap(), 1);
+    assert_eq!(relay.relay.store.count(&Scope::Default, Filter::new()).await.unwrap(), 0);
+
+    req(&mut acme, "team", json!({ "kinds": [1] })).await;
+    let events: Vec<Value> = until_eose(&mut acme, "team").await.into_iter().filter(|m| m[0] == "EVENT").collect();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0][2]["id"], note.id.to_hex());
+
+    // Geotagged events still belong in their cell
+    let tagged = EventBuilder::text_note("coffee here")
+        .tag(Tag::custom(TagKind::Custom("g".into()), ["drt2z"]))
+        .sign(&keys)
+        .await
+        .unwrap();
+    let ok = publish_note(&mut acme, &tagged).await;
+    assert_eq!(ok[2], false, "{:?}", ok);
+    assert!(ok[3].as_str().unwrap().contains("drt2z"), "{:?}", ok);
+
+    // Cells work as they do without a policy
+    let mut cell = connect(&relay, "drt2z.example.com").await;
+    let ok = publish_note(&mut cell, &tagged).await;
+    assert_eq!(ok[2], true, "{:?}", ok);
+}
+
+#[tokio::test]
+async fn test_unknown_names_are_refused() {
+    let relay = start_relay_with_policy(TeamScopePolicy::new(["acme"], 60)).await;
+    let note = EventBuilder::text_note("hello").sign(&Keys::generate()).await.unwrap();
+
+    let mut globex = connect(&relay, "globex.example.com").await;
+    let ok = publish_note(&mut globex, &note).await;
+    assert_eq!(ok[2], false, "{:?}", ok);
+    assert_eq!(reason_code(ok[3].as_str().unwrap()), Some("invalid-subdomain"));
+
+    // The default policy refuses team names too
+    let relay = start_relay().await;
+    let mut acme = connect(&relay, "acme.example.com").await;
+    let ok = publish_note(&mut acme, &note).await;
+    assert_eq!(reason_code(ok[3].as_str().unwrap()), Some("invalid-subdomain"));
+}
+
+#[tokio::test]
+async fn test_team_scopes_have_their_own_rate_limit() {
+    let relay = start_relay_with_policy(TeamScopePolicy::new(["acme"], 2)).await;
+    let keys = Keys::generate();
+    let mut acme = connect(&relay, "acme.example.com").await;
+    for i in 0..2 {
+        let note = EventBuilder::text_note(format!("note {}", i)).sign(&keys).await.unwrap();
+        let ok = publish_note(&mut acme, &note).await;
+        assert_eq!(ok[2], true, "{:?}", ok);
+    }
+    let note = EventBuilder::text_note("one too many").sign(&keys).await.unwrap();
+    let ok = publish_note(&mut acme, &note).await;
+    assert_eq!(reason_code(ok[3].as_str().unwrap()), Some("rate-limited"));
+}
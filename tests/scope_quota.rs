@@ -0,0 +1,82 @@
+/// Integration tests for per-cell stored-event quotas
+
+mod common;
+
+use common::*;
+use geohashed_relay::config::QuotaPolicy;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde_json::json;
+use std::time::Duration;
+
+async fn publish_at(client: &mut Client, keys: &Keys, created_at: u64) -> serde_json::Value {
+    let event = EventBuilder::text_note(format!("note {}", created_at))
+        .custom_created_at(Timestamp::from(created_at))
+        .sign(keys)
+        .await
+        .unwrap();
+    publish(client, &event).await;
+    next_message(client).await
+}
+
+#[tokio::test]
+async fn test_full_cell_rejects_writes_but_serves_reads() {
+    let relay = start_relay_with(|config| {
+        config.max_events_per_scope = 2;
+        config.quota_policy = QuotaPolicy::Reject;
+    })
+    .await;
+    let keys = Keys::generate();
+
+    let mut client = relay.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+    for created_at in [1_000, 2_000] {
+        let ok = publish_at(&mut client, &keys, created_at).await;
+        assert_eq!(ok[2], true, "{:?}", ok);
+    }
+    let ok = publish_at(&mut client, &keys, 3_000).await;
+    assert_eq!(ok[2], false);
+    assert_eq!(ok[3], "error: this geohash cell is full [scope-full]");
+
+    req(&mut client, "all", json!({})).await;
+    let messages = until_eose(&mut client, "all").await;
+    assert_eq!(messages.len(), 3);
+
+    // Other cells have their own quota
+    let mut other = relay.connect("9q8yy.example.com").await;
+    next_message(&mut other).await;
+    let ok = publish_at(&mut other, &keys, 3_000).await;
+    assert_eq!(ok[2], true, "{:?}", ok);
+}
+
+#[tokio::test]
+async fn test_evict_oldest_keeps_cell_within_quota() {
+    let relay = start_relay_with(|config| {
+        config.max_events_per_scope = 2;
+        config.quota_policy = QuotaPolicy::EvictOldest;
+        config.quota_refresh_secs = 1;
+    })
+    .await;
+    let keys = Keys::generate();
+
+    let mut client = relay.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+    for created_at in [1_000, 2_000, 3_000, 4_000] {
+        let ok = publish_at(&mut client, &keys, created_at).await;
+        assert_eq!(ok[2], true, "{:?}", ok);
+    }
+
+    // Eviction runs in the background; the periodic recount catches up
+    // with any write that was still in flight
+    let scope = Scope::named("drt2z").unwrap();
+    let mut remaining = Vec::new();
+    for _ in 0..50 {
+        remaining = relay.relay.store.query(&scope, Filter::new()).await.unwrap();
+        if remaining.len() <= 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let timestamps: Vec<u64> = remaining.iter().map(|e| e.created_at.as_u64()).collect();
+    assert_eq!(timestamps, vec![4_000, 3_000]);
+}
@@ -0,0 +1,86 @@
+/// Integration tests for per-scope rate limiting
+
+mod common;
+
+use common::*;
+use geohashed_relay::reject::reason_code;
+use nostr_sdk::prelude::*;
+
+async fn publish_note(client: &mut Client, keys: &Keys, content: &str) -> serde_json::Value {
+    let event = EventBuilder::text_note(content).sign(keys).await.unwrap();
+    publish(client, &event).await;
+    next_message(client).await
+}
+
+#[tokio::test]
+async fn test_busy_cell_does_not_throttle_other_cells() {
+    let relay = start_relay_with(|config| {
+        config.events_per_minute = 3;
+    })
+    .await;
+    let keys = Keys::generate();
+
+    let mut busy = relay.connect("drt2z.example.com").await;
+    next_message(&mut busy).await;
+    for i in 0..3 {
+        let ok = publish_note(&mut busy, &keys, &format!("note {}", i)).await;
+        assert_eq!(ok[2], true, "{:?}", ok);
+    }
+    let ok = publish_note(&mut busy, &keys, "one too many").await;
+    assert_eq!(ok[0], "OK");
+    assert_eq!(ok[2], false);
+    let message = ok[3].as_str().unwrap();
+    assert!(message.starts_with("rate-limited:"));
+    assert_eq!(reason_code(message), Some("rate-limited"));
+
+    // A second connection to the same cell shares its budget
+    let mut same_cell = relay.connect("drt2z.example.com").await;
+    next_message(&mut same_cell).await;
+    let ok = publish_note(&mut same_cell, &keys, "still limited").await;
+    assert_eq!(ok[2], false);
+
+    // Other cells keep their own budget
+    let mut quiet = relay.connect("9q8yy.example.com").await;
+    next_message(&mut quiet).await;
+    let ok = publish_note(&mut quiet, &keys, "hello from 9q8yy").await;
+    assert_eq!(ok[2], true, "{:?}", ok);
+}
+
+#[tokio::test]
+async fn test_authenticated_pubkeys_get_their_own_budget() {
+    let relay = start_relay_with(|config| {
+        config.events_per_minute = 2;
+        config.authed_events_per_minute = 4;
+        // Cells send the AUTH challenge but still take anonymous writes
+        config.geohash_read_auth = true;
+    })
+    .await;
+    let keys = Keys::generate();
+
+    let mut client = relay.connect("drt2z.example.com").await;
+    let mut challenge = None;
+    while challenge.is_none() {
+        let message = next_message(&mut client).await;
+        if message[0] == "AUTH" {
+            challenge = message[1].as_str().map(str::to_string);
+        }
+    }
+    for i in 0..2 {
+        let ok = publish_note(&mut client, &keys, &format!("anonymous {}", i)).await;
+        assert_eq!(ok[2], true, "{:?}", ok);
+    }
+    let ok = publish_note(&mut client, &keys, "anonymous over budget").await;
+    assert_eq!(reason_code(ok[3].as_str().unwrap()), Some("rate-limited"));
+
+    // The same connection, once authenticated, spends its pubkey's budget
+    let relay_url = RelayUrl::parse("ws://drt2z.example.com").unwrap();
+    let auth = EventBuilder::auth(challenge.unwrap(), relay_url).sign(&keys).await.unwrap();
+    send(&mut client, serde_json::json!(["AUTH", auth])).await;
+    assert_eq!(next_message(&mut client).await[2], true);
+    for i in 0..4 {
+        let ok = publish_note(&mut client, &keys, &format!("authed {}", i)).await;
+        assert_eq!(ok[2], true, "{:?}", ok);
+    }
+    let ok = publish_note(&mut client, &keys, "authed over budget").await;
+    assert_eq!(reason_code(ok[3].as_str().unwrap()), Some("rate-limited"));
+}
@@ -0,0 +1,68 @@
+/// Integration tests for slow-consumer disconnects
+
+mod common;
+
+use common::*;
+use futures::StreamExt;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Reads everything the relay sends until it closes the connection
+async fn drain_until_closed(client: &mut Client) -> Vec<Value> {
+    let mut messages = Vec::new();
+    while let Ok(Some(Ok(message))) = tokio::time::timeout(MESSAGE_TIMEOUT, client.next()).await {
+        match message {
+            Message::Text(text) => messages.push(serde_json::from_str(&text).unwrap()),
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    messages
+}
+
+#[tokio::test]
+async fn test_stalled_client_is_disconnected() {
+    let relay = start_relay_with(|config| {
+        config.max_outbound_messages = 20;
+        config.max_outbound_bytes = 256 * 1024;
+    })
+    .await;
+
+    // Enough large events to fill the socket buffers many times over
+    let scope = Scope::named("drt2z").unwrap();
+    let keys = Keys::generate();
+    let padding = "x".repeat(32 * 1024);
+    for i in 0..400 {
+        let event = EventBuilder::text_note(format!("{} {}", i, padding))
+            .sign(&keys)
+            .await
+            .unwrap();
+        relay.relay.store.save(&scope, event).await.unwrap();
+    }
+
+    let mut stalled = relay.connect("drt2z.example.com").await;
+    next_message(&mut stalled).await;
+    req(&mut stalled, "flood", json!({"limit": 400})).await;
+
+    // A well-behaved client on the same cell is unaffected
+    let mut healthy = relay.connect("drt2z.example.com").await;
+    next_message(&mut healthy).await;
+    req(&mut healthy, "small", json!({"limit": 5})).await;
+    let messages = until_eose(&mut healthy, "small").await;
+    assert_eq!(messages.len(), 6);
+
+    // Only now does the stalled client start reading
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let messages = drain_until_closed(&mut stalled).await;
+    assert!(messages
+        .iter()
+        .any(|m| m[0] == "NOTICE" && m[1].as_str().unwrap().contains("not reading messages fast enough")));
+    assert!(!messages.iter().any(|m| m[0] == "EOSE"));
+
+    // The healthy connection is still open
+    req(&mut healthy, "again", json!({"limit": 1})).await;
+    assert_eq!(until_eose(&mut healthy, "again").await.last().unwrap()[0], "EOSE");
+}
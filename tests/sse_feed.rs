@@ -0,0 +1,130 @@
+/// Integration tests for the `/feed` Server-Sent Events stream
+
+mod common;
+
+use common::*;
+use nostr_sdk::prelude::*;
+use reqwest::StatusCode;
+
+/// Reads `data:` payloads of `event: nostr` messages from an SSE response
+struct SseReader {
+    response: reqwest::Response,
+    buffer: String,
+}
+
+impl SseReader {
+    async fn open(relay: &TestRelay, host: &str, path: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("http://{}{}", relay.addr, path))
+            .header("host", host)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    fn new(response: reqwest::Response) -> Self {
+        Self { response, buffer: String::new() }
+    }
+
+    async fn next_event(&mut self) -> Event {
+        loop {
+            if let Some(end) = self.buffer.find("\n\n") {
+                let message: String = self.buffer.drain(..end + 2).collect();
+                if !message.lines().any(|line| line == "event: nostr") {
+                    continue;
+                }
+                let data = message.lines().find_map(|line| line.strip_prefix("data: ")).unwrap();
+                return Event::from_json(data).unwrap();
+            }
+            let chunk = tokio::time::timeout(MESSAGE_TIMEOUT, self.response.chunk())
+                .await
+                .expect("timed out waiting for feed")
+                .unwrap()
+                .expect("feed closed");
+            self.buffer.push_str(&String::from_utf8_lossy(&chunk));
+        }
+    }
+}
+
+async fn note(keys: &Keys, kind: u16, geohash: &str) -> Event {
+    EventBuilder::new(Kind::from(kind), "on the feed")
+        .tags(vec![Tag::custom(TagKind::Custom("g".into()), vec![geohash.to_string()])])
+        .sign(keys)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_feed_replays_then_streams_published_events() {
+    let relay = start_relay().await;
+    let keys = Keys::generate();
+    let mut client = relay.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+
+    let earlier = note(&keys, 1, "drt2z").await;
+    publish(&mut client, &earlier).await;
+    assert_eq!(next_message(&mut client).await[2], true);
+
+    let response = SseReader::open(&relay, "drt2z.example.com", "/feed?kinds=1").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+    let mut feed = SseReader::new(response);
+    assert_eq!(feed.next_event().await.id, earlier.id);
+
+    // Filtered out by kinds, then one that matches
+    let ephemeral = note(&keys, 20000, "drt2z").await;
+    publish(&mut client, &ephemeral).await;
+    assert_eq!(next_message(&mut client).await[2], true);
+    let live = note(&keys, 1, "drt2z").await;
+    publish(&mut client, &live).await;
+    assert_eq!(next_message(&mut client).await[2], true);
+
+    assert_eq!(feed.next_event().await.id, live.id);
+}
+
+#[tokio::test]
+async fn test_feed_only_streams_its_own_scope() {
+    let relay = start_relay().await;
+    let keys = Keys::generate();
+    let mut feed = SseReader::new(SseReader::open(&relay, "drt2z.example.com", "/feed").await);
+
+    let mut other = relay.connect("9q8yy.example.com").await;
+    next_message(&mut other).await;
+    publish(&mut other, &note(&keys, 1, "9q8yy").await).await;
+    assert_eq!(next_message(&mut other).await[2], true);
+
+    let mut cell = relay.connect("drt2z.example.com").await;
+    next_message(&mut cell).await;
+    let event = note(&keys, 1, "drt2z").await;
+    publish(&mut cell, &event).await;
+    assert_eq!(next_message(&mut cell).await[2], true);
+
+    assert_eq!(feed.next_event().await.id, event.id);
+}
+
+#[tokio::test]
+async fn test_feed_limits() {
+    let relay = start_relay_with(|config| config.feed_max_connections_per_ip = 1).await;
+
+    let root = SseReader::open(&relay, "example.com", "/feed").await;
+    assert_eq!(root.status(), StatusCode::NOT_FOUND);
+    let bad_kinds = SseReader::open(&relay, "drt2z.example.com", "/feed?kinds=notes").await;
+    assert_eq!(bad_kinds.status(), StatusCode::BAD_REQUEST);
+
+    let first = SseReader::open(&relay, "drt2z.example.com", "/feed").await;
+    assert_eq!(first.status(), StatusCode::OK);
+    let second = SseReader::open(&relay, "9q8yy.example.com", "/feed").await;
+    assert_eq!(second.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    drop(first);
+    // The slot is released once the server notices the stream is gone
+    let mut status = StatusCode::TOO_MANY_REQUESTS;
+    for _ in 0..50 {
+        status = SseReader::open(&relay, "9q8yy.example.com", "/feed").await.status();
+        if status == StatusCode::OK {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert_eq!(status, StatusCode::OK);
+}
@@ -0,0 +1,73 @@
+/// Integration tests for map-full and failed-write handling
+
+mod common;
+
+use common::*;
+use geohashed_relay::reject::reason_code;
+use nostr_sdk::prelude::*;
+use serde_json::json;
+
+#[tokio::test]
+async fn test_full_map_returns_ok_false_and_keeps_connection() {
+    let relay = start_relay_with(|config| {
+        config.lmdb_map_size = 1024 * 1024; // 1MB
+        config.events_per_minute = 10_000;
+    })
+    .await;
+
+    let mut client = relay.connect("example.com").await;
+    next_message(&mut client).await;
+
+    // ~100KB events fill a 1MB map after a handful of writes
+    let keys = Keys::generate();
+    let padding = "x".repeat(100 * 1024);
+    let mut rejection = None;
+    for i in 0..50 {
+        let event = EventBuilder::text_note(format!("{} {}", i, padding))
+            .sign(&keys)
+            .await
+            .unwrap();
+        publish(&mut client, &event).await;
+        let ok = next_message(&mut client).await;
+        assert_eq!(ok[0], "OK");
+        if ok[2] == false {
+            rejection = Some(ok[3].as_str().unwrap().to_string());
+            break;
+        }
+    }
+    assert_eq!(rejection.as_deref(), Some("error: relay storage full [storage-full]"));
+
+    // Later events are refused up front with the same message
+    let event = EventBuilder::text_note("small").sign(&keys).await.unwrap();
+    publish(&mut client, &event).await;
+    let ok = next_message(&mut client).await;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().contains("relay storage full"));
+
+    // The connection survives and reads still work
+    req(&mut client, "sub", json!({"limit": 1})).await;
+    assert_eq!(until_eose(&mut client, "sub").await.last().unwrap()[0], "EOSE");
+}
+
+#[tokio::test]
+async fn test_rejections_are_not_counted_as_write_failures() {
+    let relay = start_relay_with(|config| config.storage_error_threshold = 1).await;
+
+    let mut client = relay.connect("example.com").await;
+    next_message(&mut client).await;
+
+    // Root refuses geotagged events in the processor, below this middleware
+    let event = EventBuilder::text_note("hello")
+        .tags(vec![Tag::custom(TagKind::Custom("g".into()), vec!["drt2z".to_string()])])
+        .sign(&Keys::generate())
+        .await
+        .unwrap();
+    publish(&mut client, &event).await;
+    let ok = next_message(&mut client).await;
+    assert_eq!(ok[2], false);
+    assert_eq!(reason_code(ok[3].as_str().unwrap()), Some("root-rejects-geotagged"));
+
+    let body = reqwest::get(format!("http://{}/health", relay.addr)).await.unwrap().text().await.unwrap();
+    let health: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(health["status"], "ok");
+}
@@ -0,0 +1,40 @@
+/// Integration tests for the per-connection subscription cap
+
+mod common;
+
+use common::*;
+use serde_json::json;
+
+const MAX_SUBSCRIPTIONS: usize = 3;
+
+#[tokio::test]
+async fn test_subscription_over_the_cap_is_closed() {
+    let relay = start_relay_with(|config| config.max_subscriptions_per_connection = MAX_SUBSCRIPTIONS).await;
+    let mut client = relay.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+
+    for i in 0..MAX_SUBSCRIPTIONS {
+        let sub_id = format!("sub-{}", i);
+        req(&mut client, &sub_id, json!({ "kinds": [1] })).await;
+        until_eose(&mut client, &sub_id).await;
+    }
+
+    req(&mut client, "one-too-many", json!({ "kinds": [1] })).await;
+    let closed = next_message(&mut client).await;
+    assert_eq!(closed[0], "CLOSED");
+    assert_eq!(closed[1], "one-too-many");
+    assert_eq!(
+        closed[2],
+        format!("restricted: too many subscriptions (max {}) [too-many-subscriptions]", MAX_SUBSCRIPTIONS)
+    );
+
+    // Replacing an open subscription is still allowed
+    req(&mut client, "sub-0", json!({ "kinds": [0] })).await;
+    until_eose(&mut client, "sub-0").await;
+
+    // Closing one frees a slot
+    send(&mut client, json!(["CLOSE", "sub-1"])).await;
+    req(&mut client, "after-close", json!({ "kinds": [1] })).await;
+    let messages = until_eose(&mut client, "after-close").await;
+    assert!(messages.iter().all(|message| message[0] != "CLOSED"));
+}
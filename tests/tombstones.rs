@@ -0,0 +1,159 @@
+/// Integration tests for kind 5 deletions in tombstone mode
+
+mod common;
+
+use common::*;
+use geohashed_relay::config::DeletionMode;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+const ADMIN_TOKEN: &str = "s3cret";
+
+async fn start() -> TestRelay {
+    start_relay_with(|config| {
+        config.admin_token = Some(ADMIN_TOKEN.to_string());
+        config.deletion_mode = DeletionMode::Tombstone;
+    })
+    .await
+}
+
+async fn connect(relay: &TestRelay) -> Client {
+    let mut client = relay.connect("drt2z.example.com").await;
+    next_message(&mut client).await;
+    client
+}
+
+async fn publish_ok(client: &mut Client, event: &Event) {
+    publish(client, event).await;
+    let ok = next_message(client).await;
+    assert_eq!(ok[2], true, "{:?}", ok);
+}
+
+/// A geotagged note and a deletion request for it, both published
+async fn publish_and_delete(client: &mut Client, keys: &Keys) -> (Event, Event) {
+    let note = EventBuilder::text_note("wrong cell, sorry")
+        .tag(Tag::custom(TagKind::Custom("g".into()), ["drt2z"]))
+        .sign(keys)
+        .await
+        .unwrap();
+    publish_ok(client, &note).await;
+    let deletion = EventBuilder::delete(EventDeletionRequest::new().id(note.id)).sign(keys).await.unwrap();
+    publish_ok(client, &deletion).await;
+    (note, deletion)
+}
+
+async fn visible(client: &mut Client, id: EventId) -> bool {
+    req(client, "lookup", json!({ "ids": [id.to_hex()] })).await;
+    until_eose(client, "lookup").await.iter().any(|m| m[0] == "EVENT")
+}
+
+async fn admin_post(relay: &TestRelay, path: &str) -> (StatusCode, Value) {
+    let response = reqwest::Client::new()
+        .post(format!("http://{}{}", relay.addr, path))
+        .bearer_auth(ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    let status = response.status();
+    (status, response.json().await.unwrap_or(Value::Null))
+}
+
+async fn export(relay: &TestRelay, query: &str) -> Vec<Value> {
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/api/scopes/drt2z/export{}", relay.addr, query))
+        .bearer_auth(ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.text().await.unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+}
+
+#[tokio::test]
+async fn test_deletion_hides_until_restored() {
+    let relay = start().await;
+    let keys = Keys::generate();
+    let mut client = connect(&relay).await;
+    let (note, deletion) = publish_and_delete(&mut client, &keys).await;
+
+    assert!(!visible(&mut client, note.id).await);
+    // Still stored, for the admin to decide on
+    let drt2z = Scope::named("drt2z").unwrap();
+    assert_eq!(relay.relay.store.count(&drt2z, Filter::new().id(note.id)).await.unwrap(), 1);
+    // The request too is held back until an admin settles it
+    assert_eq!(relay.relay.store.count(&drt2z, Filter::new().id(deletion.id)).await.unwrap(), 0);
+
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/api/tombstones", relay.addr))
+        .bearer_auth(ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    let tombstones: Value = response.json().await.unwrap();
+    assert_eq!(tombstones[0]["id"], deletion.id.to_hex());
+    assert_eq!(tombstones[0]["requested_by"], keys.public_key().to_hex());
+    assert_eq!(tombstones[0]["scope"], "drt2z");
+    assert_eq!(tombstones[0]["hidden"], json!([note.id.to_hex()]));
+    assert_eq!(tombstones[0]["state"], "hidden");
+
+    let (status, tombstone) = admin_post(&relay, &format!("/api/tombstones/{}/restore", deletion.id)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(tombstone["state"], "restored");
+    assert!(visible(&mut client, note.id).await);
+    let (status, _) = admin_post(&relay, &format!("/api/tombstones/{}/restore", deletion.id)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_purge_deletes_for_good() {
+    let relay = start().await;
+    let keys = Keys::generate();
+    let mut client = connect(&relay).await;
+    let (note, deletion) = publish_and_delete(&mut client, &keys).await;
+
+    let (status, tombstone) = admin_post(&relay, &format!("/api/tombstones/{}/purge", deletion.id)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(tombstone["state"], "purged");
+    let drt2z = Scope::named("drt2z").unwrap();
+    assert_eq!(relay.relay.store.count(&drt2z, Filter::new().id(note.id)).await.unwrap(), 0);
+    assert!(!visible(&mut client, note.id).await);
+    // Stored as under hard deletion, for clients and other relays to see
+    assert!(visible(&mut client, deletion.id).await);
+
+    // A purged tombstone can't be restored
+    let (status, _) = admin_post(&relay, &format!("/api/tombstones/{}/restore", deletion.id)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_export_leaves_out_deleted_events_unless_asked() {
+    let relay = start().await;
+    let keys = Keys::generate();
+    let mut client = connect(&relay).await;
+    let kept = EventBuilder::text_note("staying")
+        .tag(Tag::custom(TagKind::Custom("g".into()), ["drt2z"]))
+        .sign(&keys)
+        .await
+        .unwrap();
+    publish_ok(&mut client, &kept).await;
+    let (note, deletion) = publish_and_delete(&mut client, &keys).await;
+
+    let lines = export(&relay, "").await;
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0]["id"], kept.id.to_hex());
+
+    let lines = export(&relay, "?include_deleted=true").await;
+    assert_eq!(lines.len(), 2);
+    let deleted = lines.iter().find(|line| line["id"] == note.id.to_hex()).unwrap();
+    assert_eq!(deleted["tombstone"], deletion.id.to_hex());
+
+    // Deleted events are only ever exported to admins
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/api/scopes/drt2z/export?include_deleted=true", relay.addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
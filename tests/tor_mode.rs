@@ -0,0 +1,78 @@
+/// Integration tests for path-scoped websockets behind a Tor onion service
+
+mod common;
+
+use common::*;
+use geohashed_relay::config::RelayConfig;
+use geohashed_relay::reject::reason_code;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+
+const ONION: &str = "abcdef.onion";
+
+fn tor_mode(config: &mut RelayConfig) {
+    config.relay_url = format!("ws://{}", ONION);
+    config.tor_mode = true;
+    config.path_routing = true;
+}
+
+async fn publish_geotagged(client: &mut Client, geohash: &str) -> serde_json::Value {
+    let event = EventBuilder::text_note("hello")
+        .tags(vec![Tag::custom(TagKind::Custom("g".into()), vec![geohash.to_string()])])
+        .sign(&Keys::generate())
+        .await
+        .unwrap();
+    publish(client, &event).await;
+    next_message(client).await
+}
+
+#[test]
+fn test_tor_mode_binds_loopback() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = test_config(&dir);
+    tor_mode(&mut config);
+    assert!(config.bind_addr().ip().is_loopback());
+}
+
+#[tokio::test]
+async fn test_path_selects_cell() {
+    let relay = start_relay_with(tor_mode).await;
+
+    let mut client = relay.try_connect(ONION, "/drt2z").await.unwrap();
+    next_message(&mut client).await;
+    let ok = publish_geotagged(&mut client, "drt2z").await;
+    assert_eq!(ok[2], true, "{:?}", ok);
+
+    let stored = relay
+        .relay
+        .store
+        .query(&Scope::named("drt2z").unwrap(), Filter::new())
+        .await
+        .unwrap();
+    assert_eq!(stored.len(), 1);
+}
+
+#[tokio::test]
+async fn test_root_path_is_root_scope() {
+    let relay = start_relay_with(tor_mode).await;
+
+    let mut client = relay.try_connect(ONION, "/").await.unwrap();
+    next_message(&mut client).await;
+    let ok = publish_geotagged(&mut client, "drt2z").await;
+    assert_eq!(ok[2], false);
+    assert_eq!(reason_code(ok[3].as_str().unwrap()), Some("root-rejects-geotagged"));
+}
+
+#[tokio::test]
+async fn test_non_geohash_path_refuses_upgrade() {
+    let relay = start_relay_with(tor_mode).await;
+
+    assert!(relay.try_connect(ONION, "/team1").await.is_err());
+}
+
+#[tokio::test]
+async fn test_paths_are_not_websockets_without_tor_mode() {
+    let relay = start_relay().await;
+
+    assert!(relay.try_connect("example.com", "/drt2z").await.is_err());
+}
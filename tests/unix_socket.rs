@@ -0,0 +1,94 @@
+//! Integration tests for serving the relay on a Unix domain socket
+
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+use geohashed_relay::relay::build_relay;
+use geohashed_relay::unix_socket;
+use nostr_sdk::prelude::*;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::oneshot;
+
+/// Sends a raw HTTP/1.1 GET over the socket and returns the whole response
+async fn get(path: &Path, target: &str, headers: &[(&str, &str)]) -> String {
+    let mut stream = UnixStream::connect(path).await.unwrap();
+    let mut request = format!("GET {} HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n", target);
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(MESSAGE_TIMEOUT, stream.read_to_string(&mut response))
+        .await
+        .expect("timed out reading the response")
+        .unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_http_over_unix_socket() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = test_config(&dir);
+    // One page a minute: only an address per client keeps the second one apart
+    config.info_page_requests_per_minute = 1;
+    let mut relay = build_relay(&config, Keys::generate()).await.unwrap();
+    let app = std::mem::take(&mut relay.app);
+    let path = dir.path().join("relay.sock");
+
+    let socket = unix_socket::bind(&path, 0o600, None).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = tokio::spawn(unix_socket::serve(socket, app, async move {
+        stopped.await.ok();
+    }));
+
+    let health = get(&path, "/health", &[]).await;
+    assert!(health.starts_with("HTTP/1.1 200"), "{}", health);
+
+    // Client IPs come from the proxy's X-Forwarded-For
+    let first = get(&path, "/", &[("X-Forwarded-For", "203.0.113.1")]).await;
+    assert!(first.starts_with("HTTP/1.1 200"), "{}", first);
+    let second = get(&path, "/", &[("X-Forwarded-For", "203.0.113.2")]).await;
+    assert!(second.starts_with("HTTP/1.1 200"), "{}", second);
+    let repeat = get(&path, "/", &[("X-Forwarded-For", "203.0.113.1")]).await;
+    assert!(repeat.starts_with("HTTP/1.1 429"), "{}", repeat);
+
+    stop.send(()).unwrap();
+    server.await.unwrap().unwrap();
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn test_live_socket_refuses_a_second_relay() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("relay.sock");
+
+    let socket = unix_socket::bind(&path, 0o660, None).unwrap();
+    let err = unix_socket::bind(&path, 0o660, None).err().expect("bound a live socket twice");
+    assert!(format!("{:#}", err).contains("in use"), "{:#}", err);
+
+    // Dropped without serving, the file goes away with it
+    drop(socket);
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn test_stale_socket_is_replaced() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("relay.sock");
+    // Left behind by a process that no longer listens
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let socket = unix_socket::bind(&path, 0o660, None).unwrap();
+    assert_eq!(socket.path(), path);
+
+    std::fs::write(dir.path().join("plain"), "not a socket").unwrap();
+    assert!(unix_socket::bind(dir.path().join("plain"), 0o660, None).is_err());
+}
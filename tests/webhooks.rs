@@ -0,0 +1,81 @@
+/// Integration tests for webhook notifications of stored events
+
+mod common;
+
+use axum::{body::Bytes, extract::State, http::{HeaderMap, StatusCode}, routing::post, Router};
+use common::*;
+use geohashed_relay::config::WebhookConfig;
+use geohashed_relay::webhooks::{signature, SIGNATURE_HEADER};
+use nostr_sdk::prelude::*;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+async fn mock_receiver() -> (String, mpsc::UnboundedReceiver<(HeaderMap, Bytes)>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let app = Router::new()
+        .route(
+            "/hook",
+            post(
+                |State(tx): State<mpsc::UnboundedSender<(HeaderMap, Bytes)>>, headers: HeaderMap, body: Bytes| async move {
+                    tx.send((headers, body)).unwrap();
+                    StatusCode::NO_CONTENT
+                },
+            ),
+        )
+        .with_state(tx);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (url, rx)
+}
+
+fn geotagged(geohash: &str) -> EventBuilder {
+    EventBuilder::text_note(format!("hello {}", geohash))
+        .tags(vec![Tag::custom(TagKind::Custom("g".into()), vec![geohash.to_string()])])
+}
+
+#[tokio::test]
+async fn test_only_accepted_events_in_matching_scopes_are_delivered() {
+    let (url, mut received) = mock_receiver().await;
+    let relay = start_relay_with(|config| {
+        config.webhooks = vec![WebhookConfig {
+            url,
+            scopes: vec!["drt*".to_string()],
+            kinds: Vec::new(),
+            secret: "s3cret".to_string(),
+            events_per_minute: 0,
+        }];
+    })
+    .await;
+    let keys = Keys::generate();
+
+    // Rejected: geotagged event on root
+    let mut root = relay.connect("example.com").await;
+    next_message(&mut root).await;
+    publish(&mut root, &geotagged("drt2z").sign(&keys).await.unwrap()).await;
+    assert_eq!(next_message(&mut root).await[2], false);
+
+    // Accepted, but outside the webhook's scopes
+    let mut other = relay.connect("9q8yy.example.com").await;
+    next_message(&mut other).await;
+    publish(&mut other, &geotagged("9q8yy").sign(&keys).await.unwrap()).await;
+    assert_eq!(next_message(&mut other).await[2], true);
+
+    // Accepted and matching
+    let mut cell = relay.connect("drt2z.example.com").await;
+    next_message(&mut cell).await;
+    let event = geotagged("drt2z").sign(&keys).await.unwrap();
+    publish(&mut cell, &event).await;
+    assert_eq!(next_message(&mut cell).await[2], true);
+
+    let (headers, body) = tokio::time::timeout(Duration::from_secs(5), received.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(Event::from_json(&body).unwrap().id, event.id);
+    assert_eq!(
+        headers[SIGNATURE_HEADER].to_str().unwrap(),
+        format!("sha256={}", signature("s3cret", &body))
+    );
+    assert!(tokio::time::timeout(Duration::from_millis(300), received.recv()).await.is_err());
+}
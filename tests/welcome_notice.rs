@@ -0,0 +1,41 @@
+/// Integration tests for the welcome NOTICE sent on connect
+
+mod common;
+
+use common::*;
+use serde_json::json;
+
+#[tokio::test]
+async fn test_notice_arrives_before_subscription_data() {
+    let relay = start_relay().await;
+    let mut client = relay.connect("drt2z.example.com").await;
+    req(&mut client, "sub", json!({"limit": 10})).await;
+
+    let messages = until_eose(&mut client, "sub").await;
+    assert_eq!(messages[0][0], "NOTICE");
+    assert_eq!(
+        messages[0][1],
+        r#"Connected to drt2z scope — geotagged events must carry ["g","drt2z"]; untagged events are stored in this cell; 30 events/min limit"#
+    );
+}
+
+#[tokio::test]
+async fn test_root_connection_gets_root_variant() {
+    let relay = start_relay().await;
+    let mut client = relay.connect("example.com").await;
+
+    let notice = next_message(&mut client).await;
+    assert_eq!(notice[0], "NOTICE");
+    assert!(notice[1].as_str().unwrap().starts_with("Connected to root scope"));
+}
+
+#[tokio::test]
+async fn test_notice_can_be_suppressed() {
+    let relay = start_relay_with(|config| config.welcome_notice = false).await;
+    let mut client = relay.connect("drt2z.example.com").await;
+    req(&mut client, "sub", json!({"limit": 10})).await;
+
+    let messages = until_eose(&mut client, "sub").await;
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0][0], "EOSE");
+}